@@ -0,0 +1,50 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use crate::types::Problem;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Server responded with {}: {}", .0.status, .0.detail)]
+    Api(Problem),
+    #[error("Could not serialize events: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// Whether the same request may succeed later
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Error::Http(err) => err.is_connect() || err.is_timeout(),
+            Error::Api(problem) => problem.retriable,
+            Error::Url(_) | Error::Serde(_) => false,
+        }
+    }
+
+    /// Problem sent by the server, if it responded with an error
+    pub fn problem(&self) -> Option<&Problem> {
+        match self {
+            Error::Api(problem) => Some(problem),
+            _ => None,
+        }
+    }
+}
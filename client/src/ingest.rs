@@ -0,0 +1,118 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use serde_json::Value;
+
+use crate::{Client, Error, IngestSummary};
+
+const DEFAULT_MAX_EVENTS: usize = 1000;
+// well below the payload limit of the server
+const DEFAULT_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Sends the events pushed to a log stream in batches. A batch is sent once it has as many
+/// events or bytes as allowed, or when flushed. Events not sent yet are lost if the ingester
+/// is dropped without flushing.
+#[derive(Debug)]
+pub struct Ingester {
+    client: Client,
+    stream: String,
+    events: Vec<Value>,
+    // serialized size of the events
+    bytes: usize,
+    max_events: usize,
+    max_bytes: usize,
+}
+
+impl Ingester {
+    pub(crate) fn new(client: Client, stream: &str) -> Self {
+        Self {
+            client,
+            stream: stream.to_owned(),
+            events: Vec::new(),
+            bytes: 0,
+            max_events: DEFAULT_MAX_EVENTS,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+
+    pub fn max_events(mut self, max_events: usize) -> Self {
+        self.max_events = max_events.max(1);
+        self
+    }
+
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Events pushed and not sent yet
+    pub fn pending(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Add an event to the batch, sending the batch if it is full
+    pub async fn push(&mut self, event: Value) -> Result<(), Error> {
+        let size = serde_json::to_vec(&event)?.len();
+        if self.overflows(size) {
+            self.flush().await?;
+        }
+        self.events.push(event);
+        self.bytes += size;
+        if self.events.len() >= self.max_events {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send the events pushed so far. The events are kept for the next flush if they could
+    /// not be sent, events the server rejected are dropped.
+    pub async fn flush(&mut self) -> Result<IngestSummary, Error> {
+        if self.events.is_empty() {
+            return Ok(IngestSummary::default());
+        }
+        let summary = self.client.ingest(&self.stream, &self.events).await?;
+        self.events.clear();
+        self.bytes = 0;
+        Ok(summary)
+    }
+
+    // whether an event of this size does not fit in the batch, a batch has at least one event
+    fn overflows(&self, size: usize) -> bool {
+        !self.events.is_empty() && self.bytes + size > self.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Ingester;
+    use crate::Client;
+
+    #[test]
+    fn batch_overflows_beyond_max_bytes() {
+        let client = Client::new("http://localhost:8000", "admin", "admin").unwrap();
+        let mut ingester = Ingester::new(client, "app").max_bytes(100);
+        assert!(!ingester.overflows(500));
+
+        ingester.events.push(json!({"message": "disk full"}));
+        ingester.bytes = 60;
+        assert!(!ingester.overflows(40));
+        assert!(ingester.overflows(41));
+    }
+}
@@ -0,0 +1,473 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Async client of the Parseable http api.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), parseable_client::Error> {
+//! use parseable_client::{Client, QueryRequest};
+//! use serde_json::json;
+//!
+//! let client = Client::new("http://localhost:8000", "admin", "admin")?;
+//!
+//! let mut ingester = client.ingester("app");
+//! ingester.push(json!({"level": "error", "message": "disk full"})).await?;
+//! ingester.flush().await?;
+//!
+//! let records = client
+//!     .query(&QueryRequest::new("SELECT * FROM app WHERE level = 'error'", "10m"))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests failing with an error the server marks retriable, or failing to connect, are
+//! retried with exponential backoff, see [`Retry`].
+
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use url::Url;
+
+mod error;
+mod ingest;
+pub mod types;
+
+pub use error::Error;
+pub use ingest::Ingester;
+pub use types::{
+    Alert, AlertEvaluation, Alerts, HistoryParams, IngestSummary, LogStream, Problem, QueriedStats,
+    QueryRequest, RejectedEvent, StreamOptions,
+};
+
+const API_PATH: [&str; 2] = ["api", "v1"];
+
+const TIME_PARTITION_KEY: &str = "x-p-time-partition";
+const CUSTOM_PARTITION_KEY: &str = "x-p-custom-partition";
+const STATIC_SCHEMA_FLAG: &str = "x-p-static-schema-flag";
+
+/// Retries of requests which may succeed later
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// retries after the first attempt
+    pub retries: u32,
+    /// wait before the first retry, doubled for every next one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Retry {
+    /// No retries
+    pub fn never() -> Self {
+        Self {
+            retries: 0,
+            ..Default::default()
+        }
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    url: Url,
+    username: String,
+    password: String,
+    retry: Retry,
+}
+
+impl Client {
+    /// Client of the server at `url`, authenticating as a user with basic auth
+    pub fn new(
+        url: &str,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let url = Url::parse(url)?;
+        if url.cannot_be_a_base() {
+            return Err(url::ParseError::RelativeUrlWithCannotBeABaseBase.into());
+        }
+        Ok(Self {
+            http: reqwest::Client::new(),
+            url,
+            username: username.into(),
+            password: password.into(),
+            retry: Retry::default(),
+        })
+    }
+
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Send requests with a client of its own, e.g. with timeouts or certificates
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    // url of an api path, the segments are escaped
+    fn endpoint(&self, segments: &[&str]) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .expect("url is checked to be a base")
+            .pop_if_empty()
+            .extend(API_PATH)
+            .extend(segments);
+        url
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.http
+            .request(method, url)
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    /// Send a request, `request` builds it again for every attempt
+    async fn send<F>(&self, request: F) -> Result<Response, Error>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut retry = 0;
+        loop {
+            let result = match request().send().await {
+                Ok(res) => check(res).await,
+                Err(err) => Err(Error::Http(err)),
+            };
+            match result {
+                Err(err) if err.is_retriable() && retry < self.retry.retries => {
+                    tokio::time::sleep(self.retry.delay(retry)).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn call(
+        &self,
+        method: Method,
+        segments: &[&str],
+        body: Option<&Value>,
+    ) -> Result<Response, Error> {
+        let url = self.endpoint(segments);
+        self.send(|| {
+            let request = self.request(method.clone(), url.clone());
+            match body {
+                Some(body) => request.json(body),
+                None => request,
+            }
+        })
+        .await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, segments: &[&str]) -> Result<T, Error> {
+        let url = self.endpoint(segments);
+        let res = self.send(|| self.request(Method::GET, url.clone())).await?;
+        Ok(res.json().await?)
+    }
+
+    pub async fn list_streams(&self) -> Result<Vec<LogStream>, Error> {
+        self.get(&["logstream"]).await
+    }
+
+    pub async fn create_stream(&self, stream: &str, options: &StreamOptions) -> Result<(), Error> {
+        let url = self.endpoint(&["logstream", stream]);
+        self.send(|| {
+            let mut request = self.request(Method::PUT, url.clone());
+            if let Some(field) = &options.time_partition {
+                request = request.header(TIME_PARTITION_KEY, field);
+            }
+            if !options.custom_partition.is_empty() {
+                request = request.header(CUSTOM_PARTITION_KEY, options.custom_partition.join(","));
+            }
+            if let Some(schema) = &options.static_schema {
+                request = request.header(STATIC_SCHEMA_FLAG, "true").json(schema);
+            }
+            request
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Delete a log stream and all of its data
+    pub async fn delete_stream(&self, stream: &str) -> Result<(), Error> {
+        let url = self.endpoint(&["logstream", stream]);
+        self.send(|| self.request(Method::DELETE, url.clone()))
+            .await?;
+        Ok(())
+    }
+
+    /// Arrow schema of a log stream
+    pub async fn schema(&self, stream: &str) -> Result<Value, Error> {
+        self.get(&["logstream", stream, "schema"]).await
+    }
+
+    pub async fn stats(&self, stream: &str) -> Result<QueriedStats, Error> {
+        self.get(&["logstream", stream, "stats"]).await
+    }
+
+    /// Settings and first event of a log stream
+    pub async fn info(&self, stream: &str) -> Result<Value, Error> {
+        self.get(&["logstream", stream, "info"]).await
+    }
+
+    /// Send events to an existing log stream. Events may be ingested twice if the server
+    /// ingested them but the response was lost, and the request was retried. Events which
+    /// do not fit the stream are listed as rejected, the others are ingested.
+    pub async fn ingest(&self, stream: &str, events: &[Value]) -> Result<IngestSummary, Error> {
+        let url = self.endpoint(&["logstream", stream]);
+        let body = serde_json::to_vec(events)?;
+        let res = self
+            .send(|| {
+                self.request(Method::POST, url.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.clone())
+            })
+            .await?;
+        let summary = res.bytes().await?;
+        // older servers answer without a summary, they ingest all events or none
+        if summary.is_empty() {
+            return Ok(IngestSummary {
+                accepted: events.len(),
+                ..Default::default()
+            });
+        }
+        Ok(serde_json::from_slice(&summary)?)
+    }
+
+    /// Ingester of a log stream, sending events in batches
+    pub fn ingester(&self, stream: &str) -> Ingester {
+        Ingester::new(self.clone(), stream)
+    }
+
+    /// Records matching a query
+    pub async fn query(&self, query: &QueryRequest) -> Result<Vec<Map<String, Value>>, Error> {
+        let url = self.endpoint(&["query"]);
+        let res = self
+            .send(|| self.request(Method::POST, url.clone()).json(query))
+            .await?;
+        Ok(res.json().await?)
+    }
+
+    /// Alerts of a log stream, none if no alert was set yet
+    pub async fn alerts(&self, stream: &str) -> Result<Alerts, Error> {
+        match self.get(&["logstream", stream, "alert"]).await {
+            Err(Error::Api(problem)) if problem.code == "no_alerts" => Ok(Alerts::default()),
+            result => result,
+        }
+    }
+
+    /// Replace the alerts of a log stream
+    pub async fn put_alerts(&self, stream: &str, alerts: &Alerts) -> Result<(), Error> {
+        let url = self.endpoint(&["logstream", stream, "alert"]);
+        self.send(|| self.request(Method::PUT, url.clone()).json(alerts))
+            .await?;
+        Ok(())
+    }
+
+    /// Latest evaluations of an alert first
+    pub async fn alert_history(
+        &self,
+        id: &str,
+        params: &HistoryParams,
+    ) -> Result<Vec<AlertEvaluation>, Error> {
+        let url = self.endpoint(&["alerts", id, "history"]);
+        let res = self
+            .send(|| self.request(Method::GET, url.clone()).query(params))
+            .await?;
+        Ok(res.json().await?)
+    }
+
+    /// Convert the staged events of a log stream to parquet and upload them
+    pub async fn flush_stream(&self, stream: &str) -> Result<(), Error> {
+        self.call(Method::POST, &["logstream", stream, "flush"], None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<Value>, Error> {
+        self.get(&["user"]).await
+    }
+
+    /// Create a user authenticating with basic auth, its generated password is returned
+    pub async fn create_user(&self, username: &str, roles: &[String]) -> Result<String, Error> {
+        let roles = (!roles.is_empty()).then(|| Value::from(roles.to_vec()));
+        let res = self
+            .call(Method::POST, &["user", username], roles.as_ref())
+            .await?;
+        Ok(res.text().await?)
+    }
+
+    pub async fn delete_user(&self, username: &str) -> Result<(), Error> {
+        self.call(Method::DELETE, &["user", username], None).await?;
+        Ok(())
+    }
+
+    /// Replace the roles of a user
+    pub async fn set_user_roles(&self, username: &str, roles: &[String]) -> Result<(), Error> {
+        let roles = Value::from(roles.to_vec());
+        self.call(Method::PUT, &["user", username, "role"], Some(&roles))
+            .await?;
+        Ok(())
+    }
+
+    /// Generate a new password for a user
+    pub async fn reset_password(&self, username: &str) -> Result<String, Error> {
+        let res = self
+            .call(
+                Method::POST,
+                &["user", username, "generate-new-password"],
+                None,
+            )
+            .await?;
+        Ok(res.text().await?)
+    }
+
+    pub async fn list_roles(&self) -> Result<Vec<String>, Error> {
+        self.get(&["role"]).await
+    }
+
+    /// Privileges of a role
+    pub async fn role(&self, name: &str) -> Result<Value, Error> {
+        self.get(&["role", name]).await
+    }
+
+    /// Create a role or replace its privileges
+    pub async fn put_role(&self, name: &str, privileges: &Value) -> Result<(), Error> {
+        self.call(Method::PUT, &["role", name], Some(privileges))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_role(&self, name: &str) -> Result<(), Error> {
+        self.call(Method::DELETE, &["role", name], None).await?;
+        Ok(())
+    }
+
+    /// Ingesters of the cluster, from the query server
+    pub async fn cluster_info(&self) -> Result<Vec<Value>, Error> {
+        self.get(&["cluster", "info"]).await
+    }
+
+    /// Remove an ingester which is down from the cluster, by its id or its domain:port
+    pub async fn remove_ingester(&self, ingester: &str) -> Result<String, Error> {
+        let res = self
+            .call(Method::DELETE, &["cluster", ingester], None)
+            .await?;
+        Ok(res.text().await?)
+    }
+}
+
+// error of a response as the problem sent, or made up from its status and body
+async fn check(res: Response) -> Result<Response, Error> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let body = res.text().await?;
+    Err(Error::Api(problem(status, &body)))
+}
+
+fn problem(status: StatusCode, body: &str) -> Problem {
+    serde_json::from_str(body).unwrap_or_else(|_| Problem {
+        title: status.canonical_reason().unwrap_or_default().to_owned(),
+        status: status.as_u16(),
+        detail: body.to_owned(),
+        retriable: matches!(
+            status,
+            StatusCode::REQUEST_TIMEOUT
+                | StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::{problem, Client, Retry};
+
+    #[test]
+    fn endpoint_escapes_segments() {
+        let client = Client::new("https://logs.example.com/parseable/", "admin", "admin").unwrap();
+        assert_eq!(
+            client
+                .endpoint(&["logstream", "app logs", "stats"])
+                .as_str(),
+            "https://logs.example.com/parseable/api/v1/logstream/app%20logs/stats"
+        );
+
+        let client = Client::new("http://localhost:8000", "admin", "admin").unwrap();
+        assert_eq!(
+            client.endpoint(&["query"]).as_str(),
+            "http://localhost:8000/api/v1/query"
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let retry = Retry::default();
+        assert_eq!(retry.delay(0), Duration::from_millis(200));
+        assert_eq!(retry.delay(2), Duration::from_millis(800));
+        assert_eq!(retry.delay(10), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn problem_is_parsed_or_made_up() {
+        let parsed = problem(
+            StatusCode::NOT_FOUND,
+            r#"{"type":"about:blank","title":"Not Found","status":404,"detail":"Log stream app does not exist","code":"stream_not_found","retriable":false,"requestId":"01HRB8V7X2Q9J1Z3K4M5N6P7Q8"}"#,
+        );
+        assert_eq!(parsed.code, "stream_not_found");
+        assert_eq!(
+            parsed.request_id.as_deref(),
+            Some("01HRB8V7X2Q9J1Z3K4M5N6P7Q8")
+        );
+
+        let made_up = problem(StatusCode::BAD_GATEWAY, "upstream connect error");
+        assert_eq!(made_up.status, 502);
+        assert_eq!(made_up.detail, "upstream connect error");
+        assert!(made_up.retriable);
+    }
+}
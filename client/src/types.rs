@@ -0,0 +1,239 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Payloads of the api. Types the server sends as they are defined here are shared with it,
+//! so that a change to them changes the client too.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Log stream as listed by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LogStream {
+    pub name: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct QueriedStats {
+    pub stream: String,
+    pub time: DateTime<Utc>,
+    pub ingestion: IngestionStats,
+    pub storage: StorageStats,
+}
+
+impl QueriedStats {
+    pub fn new(
+        stream: &str,
+        time: DateTime<Utc>,
+        ingestion: IngestionStats,
+        storage: StorageStats,
+    ) -> Self {
+        Self {
+            stream: stream.to_string(),
+            time,
+            ingestion,
+            storage,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct IngestionStats {
+    pub count: u64,
+    /// e.g. `1024 Bytes`
+    pub size: String,
+    pub format: String,
+}
+
+impl IngestionStats {
+    pub fn new(count: u64, size: String, format: &str) -> Self {
+        Self {
+            count,
+            size,
+            format: format.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct StorageStats {
+    /// e.g. `1024 Bytes`
+    pub size: String,
+    pub format: String,
+}
+
+impl StorageStats {
+    pub fn new(size: String, format: &str) -> Self {
+        Self {
+            size,
+            format: format.to_string(),
+        }
+    }
+}
+
+/// Error sent by the server, as problem details (RFC 7807)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    #[serde(default)]
+    pub title: String,
+    pub status: u16,
+    #[serde(default)]
+    pub detail: String,
+    /// e.g. `stream_not_found`
+    #[serde(default)]
+    pub code: String,
+    /// whether the same request may succeed later
+    #[serde(default)]
+    pub retriable: bool,
+    pub request_id: Option<String>,
+}
+
+/// Events of an ingest request which were ingested, and why the others were rejected
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IngestSummary {
+    pub accepted: usize,
+    pub rejected: usize,
+    #[serde(default)]
+    pub errors: Vec<RejectedEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RejectedEvent {
+    /// position of the event in the request
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Options of a new log stream
+#[derive(Debug, Clone, Default)]
+pub struct StreamOptions {
+    /// field the events are partitioned by in time, instead of their arrival
+    pub time_partition: Option<String>,
+    /// fields the events are partitioned by
+    pub custom_partition: Vec<String>,
+    /// arrow schema of the stream, fields not in it are rejected
+    pub static_schema: Option<Value>,
+}
+
+/// SQL query on a log stream, in a time range
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryRequest {
+    pub query: String,
+    /// rfc3339 timestamp or a humantime duration before now, e.g. `10m`
+    pub start_time: String,
+    /// rfc3339 timestamp or `now`
+    pub end_time: String,
+    pub send_null: bool,
+}
+
+impl QueryRequest {
+    /// Query on the time range from `start_time` until now
+    pub fn new(query: impl Into<String>, start_time: impl Into<String>) -> Self {
+        Self {
+            query: query.into(),
+            start_time: start_time.into(),
+            end_time: "now".to_owned(),
+            send_null: false,
+        }
+    }
+
+    pub fn end_time(mut self, end_time: impl Into<String>) -> Self {
+        self.end_time = end_time.into();
+        self
+    }
+
+    /// Have fields which are null in the records
+    pub fn send_null(mut self, send_null: bool) -> Self {
+        self.send_null = send_null;
+        self
+    }
+}
+
+/// Alerts of a log stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alerts {
+    #[serde(default = "default_alerts_version")]
+    pub version: String,
+    pub alerts: Vec<Alert>,
+}
+
+fn default_alerts_version() -> String {
+    "v1".to_owned()
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self {
+            version: default_alerts_version(),
+            alerts: Vec::new(),
+        }
+    }
+}
+
+/// Alert on a log stream. The rule and the targets are validated by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    /// set by the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub message: String,
+    pub rule: Value,
+    pub targets: Vec<Value>,
+    /// other fields, e.g. whether the alert is silenced
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// Evaluation of an alert rule, from its history
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertEvaluation {
+    pub alert_id: String,
+    pub alert: String,
+    pub stream: String,
+    pub rule: String,
+    pub query: Option<String>,
+    pub events: Option<u64>,
+    pub observed: Option<String>,
+    pub threshold: String,
+    /// state of the alert after the evaluation, `Error` if it failed
+    pub decision: String,
+    pub error: Option<String>,
+    pub duration_ms: f64,
+    pub node: String,
+    /// when the alert was evaluated, as written by the stream
+    pub time: String,
+}
+
+/// Time range and number of evaluations in the history of an alert
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistoryParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
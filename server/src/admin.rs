@@ -0,0 +1,341 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! `parseable admin` commands, run instead of the server. They call the api of a running
+//! server, e.g.
+//!
+//! ```text
+//! parseable admin --url http://localhost:8000 users create alice --role reader
+//! ```
+//!
+//! or, below `parseable admin store`, work on the object store directly so that the server
+//! does not have to run. The store is configured with the same args and env vars as the server.
+//!
+//! ```text
+//! parseable admin store s3-store streams
+//! ```
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use parseable_client::Client;
+use serde_json::Value;
+
+use crate::handlers::http::cluster::remove_ingester_metadata;
+use crate::option::{store_commands, CONFIG};
+use crate::stats::Stats;
+
+pub const ADMIN: &str = "admin";
+pub const ADMIN_STORE: &str = "store";
+
+const URL: &str = "url";
+const USERNAME: &str = "username";
+const PASSWORD: &str = "password";
+
+fn stream_arg() -> Arg {
+    Arg::new("stream")
+        .value_name("STREAM")
+        .required(true)
+        .help("Name of the log stream")
+}
+
+fn name_arg(help: &'static str) -> Arg {
+    Arg::new("name")
+        .value_name("NAME")
+        .required(true)
+        .help(help)
+}
+
+fn ingester_arg() -> Arg {
+    Arg::new("ingester")
+        .value_name("INGESTER")
+        .required(true)
+        .help("Id of the ingester, or domain:port of ingesters registered before they had an id")
+}
+
+pub fn command() -> Command {
+    Command::new(ADMIN)
+        .about("Administer a running server, or its object store")
+        .subcommand_required(true)
+        .arg(
+            Arg::new(URL)
+                .long(URL)
+                .env("P_ADMIN_URL")
+                .value_name("URL")
+                .default_value("http://localhost:8000")
+                .help("Address of the server, the query server in a distributed deployment"),
+        )
+        .arg(
+            Arg::new(USERNAME)
+                .long(USERNAME)
+                .env("P_USERNAME")
+                .value_name("STRING")
+                .default_value("admin")
+                .help("Username of a user allowed to run the command"),
+        )
+        .arg(
+            Arg::new(PASSWORD)
+                .long(PASSWORD)
+                .env("P_PASSWORD")
+                .value_name("STRING")
+                .default_value("admin")
+                .hide_env_values(true)
+                .help("Password of the user"),
+        )
+        .subcommands([
+            Command::new("streams").about("List the log streams"),
+            Command::new("stats")
+                .about("Ingestion and storage stats of a log stream")
+                .arg(stream_arg()),
+            Command::new("flush")
+                .about("Convert the staged events of a log stream to parquet and upload them")
+                .arg(stream_arg()),
+            Command::new("users")
+                .about("Manage users")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("list").about("List the users"),
+                    Command::new("create")
+                        .about("Create a user, its password is printed")
+                        .arg(name_arg("Username"))
+                        .arg(
+                            Arg::new("role")
+                                .long("role")
+                                .value_name("ROLE")
+                                .action(ArgAction::Append)
+                                .help("Role of the user, can be repeated"),
+                        ),
+                    Command::new("delete")
+                        .about("Delete a user")
+                        .arg(name_arg("Username")),
+                    Command::new("set-roles")
+                        .about("Replace the roles of a user")
+                        .arg(name_arg("Username"))
+                        .arg(
+                            Arg::new("roles")
+                                .value_name("ROLE")
+                                .num_args(0..)
+                                .help("Roles of the user, none to remove all"),
+                        ),
+                    Command::new("reset-password")
+                        .about("Generate a new password for a user, it is printed")
+                        .arg(name_arg("Username")),
+                ]),
+            Command::new("roles")
+                .about("Manage roles")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("list").about("List the roles"),
+                    Command::new("get")
+                        .about("Privileges of a role")
+                        .arg(name_arg("Name of the role")),
+                    Command::new("put")
+                        .about("Create a role or replace its privileges")
+                        .arg(name_arg("Name of the role"))
+                        .arg(
+                            Arg::new("file")
+                                .long("file")
+                                .value_name("PATH")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .help("JSON file with the privileges of the role"),
+                        ),
+                    Command::new("delete")
+                        .about("Delete a role no user has")
+                        .arg(name_arg("Name of the role")),
+                ]),
+            Command::new("ingesters")
+                .about("Manage the ingesters of a distributed deployment")
+                .subcommand_required(true)
+                .subcommands([
+                    Command::new("list").about("List the ingesters and whether they are reachable"),
+                    Command::new("remove")
+                        .about("Remove an ingester which is down")
+                        .arg(ingester_arg()),
+                ]),
+            Command::new(ADMIN_STORE)
+                .about("Run commands on the object store, without a running server")
+                .subcommand_required(true)
+                .subcommands(store_commands().map(|store| {
+                    store.subcommand_required(true).subcommands([
+                        Command::new("streams").about("List the log streams"),
+                        Command::new("stats")
+                            .about("Stats of a log stream, summed over all ingesters")
+                            .arg(stream_arg()),
+                        Command::new("remove-ingester")
+                            .about("Remove an ingester which is down")
+                            .arg(ingester_arg()),
+                    ])
+                })),
+        ])
+}
+
+/// Run an admin command, `matches` are those of `parseable admin`
+pub async fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let (command, m) = matches.subcommand().expect("subcommand is required");
+    if command == ADMIN_STORE {
+        let (_, m) = m.subcommand().expect("store is required");
+        return run_on_store(m).await;
+    }
+
+    let client = Client::new(
+        matches.get_one::<String>(URL).expect("has default"),
+        matches.get_one::<String>(USERNAME).expect("has default"),
+        matches.get_one::<String>(PASSWORD).expect("has default"),
+    )?;
+    match (command, m.subcommand()) {
+        ("streams", _) => {
+            for stream in client.list_streams().await? {
+                println!("{}", stream.name);
+            }
+        }
+        ("stats", _) => print_json(&client.stats(arg(m, "stream")).await?)?,
+        ("flush", _) => {
+            let stream = arg(m, "stream");
+            client.flush_stream(stream).await?;
+            println!("Flushed {stream}");
+        }
+        ("users", Some(("list", _))) => print_json(&client.list_users().await?)?,
+        ("users", Some(("create", m))) => {
+            let roles: Vec<String> = m
+                .get_many::<String>("role")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            println!("{}", client.create_user(arg(m, "name"), &roles).await?);
+        }
+        ("users", Some(("delete", m))) => {
+            client.delete_user(arg(m, "name")).await?;
+            println!("Deleted user {}", arg(m, "name"));
+        }
+        ("users", Some(("set-roles", m))) => {
+            let roles: Vec<String> = m
+                .get_many::<String>("roles")
+                .unwrap_or_default()
+                .cloned()
+                .collect();
+            client.set_user_roles(arg(m, "name"), &roles).await?;
+            println!("Updated roles of {}", arg(m, "name"));
+        }
+        ("users", Some(("reset-password", m))) => {
+            println!("{}", client.reset_password(arg(m, "name")).await?);
+        }
+        ("roles", Some(("list", _))) => {
+            for role in client.list_roles().await? {
+                println!("{role}");
+            }
+        }
+        ("roles", Some(("get", m))) => print_json(&client.role(arg(m, "name")).await?)?,
+        ("roles", Some(("put", m))) => {
+            let path = m.get_one::<PathBuf>("file").expect("file is required");
+            let privileges: Value = serde_json::from_slice(
+                &std::fs::read(path).with_context(|| format!("could not read {path:?}"))?,
+            )
+            .with_context(|| format!("{path:?} is not valid json"))?;
+            client.put_role(arg(m, "name"), &privileges).await?;
+            println!("Updated role {}", arg(m, "name"));
+        }
+        ("roles", Some(("delete", m))) => {
+            client.delete_role(arg(m, "name")).await?;
+            println!("Deleted role {}", arg(m, "name"));
+        }
+        ("ingesters", Some(("list", _))) => print_json(&client.cluster_info().await?)?,
+        ("ingesters", Some(("remove", m))) => {
+            println!("{}", client.remove_ingester(arg(m, "ingester")).await?);
+        }
+        _ => unreachable!("subcommands are required"),
+    }
+    Ok(())
+}
+
+// commands on the object store configured by the `store` matches, through CONFIG
+async fn run_on_store(matches: &ArgMatches) -> anyhow::Result<()> {
+    let store = CONFIG.storage().get_object_store();
+    match matches.subcommand().expect("subcommand is required") {
+        ("streams", _) => {
+            for stream in store.list_streams().await? {
+                println!("{}", stream.name);
+            }
+        }
+        ("stats", m) => {
+            let stream = arg(m, "stream");
+            if !store.stream_exists(stream).await? {
+                anyhow::bail!("Log stream {stream} does not exist");
+            }
+            // every ingester keeps the stats of what it ingested in a metadata file of its own
+            let mut stats = Stats::default();
+            for path in store.get_stream_file_paths(stream).await? {
+                let metadata: Value = serde_json::from_slice(&store.get_object(&path).await?)?;
+                let written: Stats =
+                    serde_json::from_value(metadata["stats"].clone()).unwrap_or_default();
+                stats.events += written.events;
+                stats.ingestion += written.ingestion;
+                stats.storage += written.storage;
+            }
+            print_json(&stats)?;
+        }
+        ("remove-ingester", m) => {
+            let ingester = arg(m, "ingester").to_owned();
+            println!("{}", remove_ingester_metadata(ingester).await?);
+        }
+        _ => unreachable!("subcommands are required"),
+    }
+    Ok(())
+}
+
+fn arg<'a>(matches: &'a ArgMatches, id: &str) -> &'a str {
+    matches
+        .get_one::<String>(id)
+        .map(String::as_str)
+        .expect("arg is required")
+}
+
+fn print_json(value: &impl serde::Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::command;
+
+    #[test]
+    fn admin_commands_parse() {
+        command().debug_assert();
+
+        let matches = command()
+            .try_get_matches_from([
+                "admin",
+                "--url",
+                "http://query:8000",
+                "users",
+                "create",
+                "alice",
+                "--role",
+                "reader",
+                "--role",
+                "ingest",
+            ])
+            .unwrap();
+        let (_, users) = matches.subcommand().unwrap();
+        let (_, create) = users.subcommand().unwrap();
+        let roles: Vec<&String> = create.get_many("role").unwrap().collect();
+        assert_eq!(roles, ["reader", "ingest"]);
+    }
+}
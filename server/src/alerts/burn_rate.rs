@@ -0,0 +1,416 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Burn rate rules alert on the error budget of a service level objective. The ratio of failed
+//! events in a window, divided by the ratio the objective allows, is the rate the budget is
+//! spent at. An alert fires while both the long and the short window of any of its pairs burn
+//! at least at the pair's rate, and resolves when none does. The default pairs are those of the
+//! SRE workbook for a 30 day objective: 14.4 over 1h and 5m, and 6 over 6h and 30m.
+//!
+//! Unlike the other rules, these are not evaluated on ingested events but by querying the
+//! stream every minute.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+use chrono::{DateTime, Utc};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use serde_json::Value;
+
+use super::history::{self, Evaluation};
+use super::{AlertState, Rule};
+use crate::metadata::STREAM_INFO;
+use crate::query::{referenced_tables, QUERY_SESSION};
+use crate::utils::uid::Uid;
+
+/// Columns the message of a burn rate alert can refer to
+pub const MESSAGE_COLUMNS: &[&str] = &[
+    "long_window",
+    "short_window",
+    "long_burn_rate",
+    "short_burn_rate",
+    "threshold",
+];
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BurnRateRule {
+    #[serde(flatten)]
+    pub objective: ServiceLevelObjective,
+    #[serde(skip)]
+    firing: AtomicBool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceLevelObjective {
+    /// sql condition of failed events, e.g. `status >= 500`
+    pub error_condition: String,
+    /// sql condition of the events the objective is about, all events of the stream if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_condition: Option<String>,
+    /// percentage of events which should not fail, e.g. `99.9`
+    pub objective: f64,
+    #[serde(default = "default_windows")]
+    pub windows: Vec<BurnRateWindow>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BurnRateWindow {
+    #[serde(with = "humantime_serde")]
+    pub long: Duration,
+    #[serde(with = "humantime_serde")]
+    pub short: Duration,
+    pub burn_rate: f64,
+}
+
+fn default_windows() -> Vec<BurnRateWindow> {
+    vec![
+        BurnRateWindow {
+            long: Duration::from_secs(60 * 60),
+            short: Duration::from_secs(5 * 60),
+            burn_rate: 14.4,
+        },
+        BurnRateWindow {
+            long: Duration::from_secs(6 * 60 * 60),
+            short: Duration::from_secs(30 * 60),
+            burn_rate: 6.0,
+        },
+    ]
+}
+
+/// Burn rates measured for a pair of windows
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub window: BurnRateWindow,
+    pub long_burn_rate: f64,
+    pub short_burn_rate: f64,
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} over {} and {:.2} over {}",
+            self.long_burn_rate,
+            humantime::format_duration(self.window.long),
+            self.short_burn_rate,
+            humantime::format_duration(self.window.short)
+        )
+    }
+}
+
+impl Measurement {
+    pub fn is_burning(&self) -> bool {
+        self.long_burn_rate >= self.window.burn_rate
+            && self.short_burn_rate >= self.window.burn_rate
+    }
+
+    fn to_record_batch(&self) -> RecordBatch {
+        let window = |duration: Duration| -> ArrayRef {
+            let duration = humantime::format_duration(duration).to_string();
+            Arc::new(StringArray::from(vec![duration]))
+        };
+        let rate = |rate: f64| -> ArrayRef { Arc::new(Float64Array::from(vec![rate])) };
+        RecordBatch::try_from_iter([
+            ("long_window", window(self.window.long)),
+            ("short_window", window(self.window.short)),
+            ("long_burn_rate", rate(self.long_burn_rate)),
+            ("short_burn_rate", rate(self.short_burn_rate)),
+            ("threshold", rate(self.window.burn_rate)),
+        ])
+        .expect("columns have the same length")
+    }
+}
+
+impl BurnRateRule {
+    pub fn trigger_reason(&self) -> String {
+        format!(
+            "error budget of the {}% objective is burning too fast",
+            self.objective.objective
+        )
+    }
+
+    /// State of the alert after a measurement, firing while any pair of windows is burning
+    pub fn transition(&self, burning: bool) -> AlertState {
+        match (self.firing.swap(burning, Ordering::AcqRel), burning) {
+            (false, true) => AlertState::SetToFiring,
+            (true, true) => AlertState::Firing,
+            (true, false) => AlertState::Resolved,
+            (false, false) => AlertState::Listening,
+        }
+    }
+}
+
+impl ServiceLevelObjective {
+    fn ratio_sql(&self, stream_name: &str) -> String {
+        let filter = self
+            .total_condition
+            .as_ref()
+            .map(|condition| format!(" WHERE {condition}"))
+            .unwrap_or_default();
+        format!(
+            "SELECT COUNT(*) AS total, SUM(CASE WHEN {} THEN 1 ELSE 0 END) AS errors FROM \"{stream_name}\"{filter}",
+            self.error_condition
+        )
+    }
+
+    /// Whether the conditions are valid sql reading from the stream only
+    pub async fn valid_for_stream(&self, stream_name: &str) -> bool {
+        match QUERY_SESSION
+            .state()
+            .create_logical_plan(&self.ratio_sql(stream_name))
+            .await
+        {
+            Ok(plan) => referenced_tables(&plan) == [stream_name],
+            Err(_) => false,
+        }
+    }
+
+    /// Ratio of failed events in the window ending at `now`, none if there were no events
+    async fn error_ratio(
+        &self,
+        stream_name: &str,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Option<f64>> {
+        let query = crate::query::Query {
+            raw_logical_plan: QUERY_SESSION
+                .state()
+                .create_logical_plan(&self.ratio_sql(stream_name))
+                .await?,
+            start: now - chrono::Duration::from_std(window)?,
+            end: now,
+            filter_tag: None,
+            at: None,
+            raw: false,
+        };
+        let (records, _) = query.execute(stream_name.to_owned()).await?;
+        let rows = record_batches_to_json_rows(&records.iter().collect::<Vec<_>>())?;
+        let row = rows
+            .first()
+            .ok_or_else(|| anyhow!("ratio query returned no rows"))?;
+        let count = |name: &str| row.get(name).and_then(Value::as_f64).unwrap_or_default();
+
+        let total = count("total");
+        Ok((total > 0.0).then(|| count("errors") / total))
+    }
+
+    /// Burn rates of every pair of windows. Ratios are queried once per distinct window.
+    pub async fn measure(
+        &self,
+        stream_name: &str,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Measurement>> {
+        let mut ratios: HashMap<Duration, Option<f64>> = HashMap::new();
+        let mut measurements = Vec::with_capacity(self.windows.len());
+        for window in &self.windows {
+            for duration in [window.long, window.short] {
+                if let Entry::Vacant(entry) = ratios.entry(duration) {
+                    entry.insert(self.error_ratio(stream_name, duration, now).await?);
+                }
+            }
+            measurements.push(Measurement {
+                window: window.clone(),
+                long_burn_rate: self.burn_rate(ratios[&window.long]),
+                short_burn_rate: self.burn_rate(ratios[&window.short]),
+            });
+        }
+        Ok(measurements)
+    }
+
+    // burn rates of every pair of windows the alert fires at
+    fn threshold(&self) -> String {
+        self.windows
+            .iter()
+            .map(|window| {
+                format!(
+                    "{} over {} and {}",
+                    window.burn_rate,
+                    humantime::format_duration(window.long),
+                    humantime::format_duration(window.short)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn burn_rate(&self, error_ratio: Option<f64>) -> f64 {
+        let budget = 1.0 - self.objective / 100.0;
+        match error_ratio {
+            Some(ratio) if budget > 0.0 => ratio / budget,
+            _ => 0.0,
+        }
+    }
+}
+
+pub fn init_burn_rate_scheduler() {
+    log::info!("Setting up schedular for burn rate alerts");
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler.every(1.minutes()).run(evaluate_burn_rates);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+async fn evaluate_burn_rates() {
+    // objectives are copied out, the streams are not kept locked while querying
+    let objectives: Vec<(String, Uid, String, ServiceLevelObjective)> = STREAM_INFO
+        .read()
+        .expect("lock")
+        .iter()
+        .flat_map(|(stream_name, meta)| {
+            meta.alerts
+                .alerts
+                .iter()
+                .filter_map(|alert| match &alert.rule {
+                    Rule::BurnRate(rule) => Some((
+                        stream_name.clone(),
+                        alert.id,
+                        alert.name.clone(),
+                        rule.objective.clone(),
+                    )),
+                    _ => None,
+                })
+        })
+        .collect();
+
+    let now = Utc::now();
+    for (stream_name, id, name, objective) in objectives {
+        let started = Instant::now();
+        let measured = objective.measure(&stream_name, now).await;
+        let mut evaluation = Evaluation {
+            alert_id: id.to_string(),
+            alert: name,
+            stream: stream_name.clone(),
+            rule: "burnRate",
+            query: Some(objective.ratio_sql(&stream_name)),
+            events: None,
+            observed: None,
+            threshold: objective.threshold(),
+            decision: "Error".to_owned(),
+            error: None,
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        };
+        let measurements = match measured {
+            Ok(measurements) => measurements,
+            Err(err) => {
+                log::warn!("could not measure burn rate of alert {id} on {stream_name}: {err}");
+                evaluation.error = Some(err.to_string());
+                history::record(evaluation);
+                continue;
+            }
+        };
+        evaluation.observed = Some(
+            measurements
+                .iter()
+                .map(Measurement::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+        // the pair that is burning, or else the one closest to its threshold
+        let Some(measurement) = measurements
+            .iter()
+            .find(|measurement| measurement.is_burning())
+            .or_else(|| {
+                measurements.iter().max_by(|a, b| {
+                    (a.long_burn_rate / a.window.burn_rate)
+                        .total_cmp(&(b.long_burn_rate / b.window.burn_rate))
+                })
+            })
+        else {
+            continue;
+        };
+
+        let streams = STREAM_INFO.read().expect("lock");
+        let Some(alert) = streams
+            .get(&stream_name)
+            .and_then(|meta| meta.alerts.alerts.iter().find(|alert| alert.id == id))
+        else {
+            // the alert was changed while measuring
+            continue;
+        };
+        if let Rule::BurnRate(rule) = &alert.rule {
+            let state = rule.transition(measurement.is_burning());
+            evaluation.decision = state.to_string();
+            history::record(evaluation);
+            alert.notify(&stream_name, state, measurement.to_record_batch());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{BurnRateRule, BurnRateWindow, Measurement, ServiceLevelObjective};
+    use crate::alerts::AlertState;
+
+    fn objective() -> ServiceLevelObjective {
+        serde_json::from_str(r#"{"errorCondition": "status >= 500", "objective": 99.9}"#).unwrap()
+    }
+
+    #[test]
+    fn burn_rate_is_relative_to_budget() {
+        let objective = objective();
+        assert_eq!(objective.windows.len(), 2);
+        assert_eq!(objective.windows[0].long, Duration::from_secs(3600));
+
+        // 1.44% of events failing spends a 0.1% budget 14.4 times too fast
+        assert!((objective.burn_rate(Some(0.0144)) - 14.4).abs() < 1e-9);
+        assert_eq!(objective.burn_rate(None), 0.0);
+
+        let measurement = Measurement {
+            window: BurnRateWindow {
+                long: Duration::from_secs(3600),
+                short: Duration::from_secs(300),
+                burn_rate: 14.4,
+            },
+            long_burn_rate: 20.0,
+            short_burn_rate: 2.0,
+        };
+        // the long window alone does not fire, the error rate already went down
+        assert!(!measurement.is_burning());
+    }
+
+    #[test]
+    fn alert_fires_and_resolves() {
+        let rule = BurnRateRule {
+            objective: objective(),
+            firing: Default::default(),
+        };
+        assert_eq!(rule.transition(false), AlertState::Listening);
+        assert_eq!(rule.transition(true), AlertState::SetToFiring);
+        assert_eq!(rule.transition(true), AlertState::Firing);
+        assert_eq!(rule.transition(false), AlertState::Resolved);
+        assert_eq!(rule.transition(false), AlertState::Listening);
+    }
+}
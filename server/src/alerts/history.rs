@@ -0,0 +1,247 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Every evaluation of an alert rule is written to the internal `palerts` stream, with what was
+//! evaluated, the value observed, the threshold and the resulting state, so that it can be
+//! looked up why an alert did or did not fire.
+//!
+//! Rules on ingested events are evaluated once per batch of events, the evaluation records the
+//! event the state changed at, or the last one if it did not change.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use arrow_array::cast::as_string_array;
+use arrow_array::RecordBatch;
+use arrow_schema::DataType;
+use chrono::Utc;
+use clokwerk::{AsyncScheduler, TimeUnits};
+use datafusion::arrow::compute::kernels::cast;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use super::rule::ColumnRule;
+use super::{Alert, AlertState, Rule};
+use crate::handlers::http::ingest::{create_stream_if_not_exists, push_internal_events};
+use crate::option::{Mode, CONFIG};
+
+/// Internal stream the evaluations of alerts are written to
+pub const ALERT_HISTORY_STREAM_NAME: &str = "palerts";
+
+// evaluations recorded in memory are written to the stream at this interval
+const HISTORY_FLUSH_INTERVAL_SECS: u32 = 30;
+// evaluations recorded while the stream cannot be written to are dropped beyond this
+const MAX_PENDING_EVALUATIONS: usize = 10_000;
+
+#[derive(Debug, serde::Serialize)]
+pub struct Evaluation {
+    pub alert_id: String,
+    pub alert: String,
+    pub stream: String,
+    pub rule: &'static str,
+    /// query the rule was evaluated with, for rules evaluated by querying the stream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// number of ingested events the rule was evaluated on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed: Option<String>,
+    pub threshold: String,
+    /// state of the alert after the evaluation, `Error` if it failed
+    pub decision: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: f64,
+}
+
+impl Evaluation {
+    fn into_record(self, node: &str) -> Value {
+        let mut record = serde_json::to_value(self).expect("evaluation is serializable");
+        if let Value::Object(map) = &mut record {
+            map.insert("node".to_owned(), node.into());
+            map.insert("time".to_owned(), Utc::now().to_rfc3339().into());
+        }
+        record
+    }
+}
+
+pub fn rule_type(rule: &Rule) -> &'static str {
+    match rule {
+        Rule::Column(_) => "column",
+        Rule::Composite(_) => "composite",
+        Rule::BurnRate(_) => "burnRate",
+    }
+}
+
+/// Evaluation of a rule on a batch of ingested events, `states` are those of every event
+pub fn evaluated_on_events(
+    alert: &Alert,
+    stream_name: &str,
+    events: &RecordBatch,
+    states: &[AlertState],
+    duration: Duration,
+) -> Evaluation {
+    let index = states
+        .iter()
+        .position(|state| matches!(state, AlertState::SetToFiring | AlertState::Resolved))
+        .or(states.len().checked_sub(1));
+    let observed = match (&alert.rule, index) {
+        (Rule::Column(rule), Some(index)) => observed_value(rule, events, index),
+        _ => None,
+    };
+
+    Evaluation {
+        alert_id: alert.id.to_string(),
+        alert: alert.name.clone(),
+        stream: stream_name.to_owned(),
+        rule: rule_type(&alert.rule),
+        query: None,
+        events: Some(events.num_rows()),
+        observed,
+        threshold: alert.rule.trigger_reason(),
+        decision: index
+            .map(|index| states[index])
+            .unwrap_or_default()
+            .to_string(),
+        error: None,
+        duration_ms: duration.as_secs_f64() * 1000.0,
+    }
+}
+
+// value of the rule's column in an event, none if the event does not have it
+fn observed_value(rule: &ColumnRule, events: &RecordBatch, index: usize) -> Option<String> {
+    let column = events.column_by_name(rule.column())?;
+    if column.is_null(index) {
+        return None;
+    }
+    let column = cast(column, &DataType::Utf8).ok()?;
+    Some(as_string_array(&column).value(index).to_owned())
+}
+
+// evaluations since the last flush
+static PENDING: Lazy<Mutex<Vec<Value>>> = Lazy::new(Mutex::default);
+
+/// Record an evaluation, it is written to the stream with the next flush
+pub fn record(evaluation: Evaluation) {
+    // evaluations of alerts on the history itself would feed themselves
+    if evaluation.stream == ALERT_HISTORY_STREAM_NAME {
+        return;
+    }
+    let record = evaluation.into_record(&CONFIG.parseable.address);
+    let mut pending = PENDING.lock().unwrap();
+    if pending.len() < MAX_PENDING_EVALUATIONS {
+        pending.push(record);
+    }
+}
+
+/// Write the evaluations recorded since the last flush to the stream.
+/// Evaluations are kept for the next flush if they could not be written.
+pub async fn flush() {
+    let evaluations = std::mem::take(&mut *PENDING.lock().unwrap());
+    if evaluations.is_empty() {
+        return;
+    }
+
+    if let Err(err) =
+        push_internal_events(ALERT_HISTORY_STREAM_NAME, Value::Array(evaluations.clone())).await
+    {
+        log::warn!("could not write alert evaluations to {ALERT_HISTORY_STREAM_NAME}: {err}");
+        let mut pending = PENDING.lock().unwrap();
+        let room = MAX_PENDING_EVALUATIONS.saturating_sub(pending.len());
+        pending.splice(0..0, evaluations.into_iter().take(room));
+    }
+}
+
+/// Create the history stream if this node creates streams and flush evaluations at an interval
+pub async fn init() {
+    if CONFIG.parseable.mode != Mode::Ingest {
+        if let Err(err) = create_stream_if_not_exists(ALERT_HISTORY_STREAM_NAME).await {
+            log::warn!("could not create {ALERT_HISTORY_STREAM_NAME} stream: {err}");
+        }
+    }
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler
+        .every(HISTORY_FLUSH_INTERVAL_SECS.seconds())
+        .run(flush);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use arrow_array::{Int64Array, RecordBatch};
+
+    use super::evaluated_on_events;
+    use crate::alerts::{Alert, AlertState};
+
+    #[test]
+    fn evaluation_records_the_event_the_state_changed_at() {
+        let alert: Alert = serde_json::from_value(serde_json::json!({
+            "name": "5xx errors",
+            "message": "status was {status}",
+            "rule": {
+                "type": "column",
+                "config": {"column": "status", "operator": ">=", "value": 500, "repeats": 1}
+            },
+            "targets": [{"type": "webhook", "endpoint": "http://localhost:9000"}]
+        }))
+        .unwrap();
+        let events = RecordBatch::try_from_iter([(
+            "status",
+            Arc::new(Int64Array::from(vec![200, 502, 200])) as _,
+        )])
+        .unwrap();
+
+        let evaluation = evaluated_on_events(
+            &alert,
+            "nginx",
+            &events,
+            &[
+                AlertState::Listening,
+                AlertState::SetToFiring,
+                AlertState::Firing,
+            ],
+            Duration::from_micros(1500),
+        );
+        assert_eq!(evaluation.events, Some(3));
+        assert_eq!(evaluation.observed.as_deref(), Some("502"));
+        assert_eq!(evaluation.decision, "SetToFiring");
+        assert_eq!(evaluation.rule, "column");
+        assert_eq!(evaluation.duration_ms, 1.5);
+
+        let evaluation = evaluated_on_events(
+            &alert,
+            "nginx",
+            &events,
+            &[AlertState::Listening; 3],
+            Duration::ZERO,
+        );
+        assert_eq!(evaluation.observed.as_deref(), Some("200"));
+        assert_eq!(evaluation.decision, "Listening");
+    }
+}
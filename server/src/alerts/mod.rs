@@ -22,15 +22,23 @@ use arrow_schema::DataType;
 use async_trait::async_trait;
 use datafusion::arrow::compute::kernels::cast;
 use datafusion::arrow::datatypes::Schema;
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::fmt;
+use std::time::Instant;
 
+pub mod burn_rate;
+pub mod history;
 pub mod parser;
 pub mod rule;
+pub mod silence;
 pub mod target;
+pub mod template;
 
 use crate::metrics::ALERTS_STATES;
+use crate::monitor::{self, ServerEvent, PMETA_STREAM_NAME};
 use crate::utils::arrow::get_field;
 use crate::utils::uid;
 use crate::CONFIG;
@@ -67,28 +75,56 @@ pub struct Alert {
 
 impl Alert {
     pub fn check_alert(&self, stream_name: &str, events: RecordBatch) {
+        let started = Instant::now();
         let resolves = self.rule.resolves(events.clone());
+        // burn rate rules are recorded when they are measured
+        if !matches!(self.rule, Rule::BurnRate(_)) {
+            history::record(history::evaluated_on_events(
+                self,
+                stream_name,
+                &events,
+                &resolves,
+                started.elapsed(),
+            ));
+        }
 
         for (index, state) in resolves.into_iter().enumerate() {
-            match state {
-                AlertState::Listening | AlertState::Firing => (),
-                alert_state @ (AlertState::SetToFiring | AlertState::Resolved) => {
-                    let context = self.get_context(
-                        stream_name.to_owned(),
-                        alert_state,
-                        &self.rule,
-                        events.slice(index, 1),
+            self.notify(stream_name, state, events.slice(index, 1));
+        }
+    }
+
+    /// Record a change of the alert state and call its targets. The message is filled in from
+    /// the columns of `event_row`.
+    pub fn notify(&self, stream_name: &str, alert_state: AlertState, event_row: RecordBatch) {
+        match alert_state {
+            AlertState::Listening | AlertState::Firing => (),
+            alert_state @ (AlertState::SetToFiring | AlertState::Resolved) => {
+                let context =
+                    self.get_context(stream_name.to_owned(), alert_state, &self.rule, event_row);
+                ALERTS_STATES
+                    .with_label_values(&[
+                        context.stream.as_str(),
+                        context.alert_info.alert_name.as_str(),
+                        context.alert_info.alert_state.to_string().as_str(),
+                    ])
+                    .inc();
+                // state changes of alerts on pmeta would feed themselves
+                if stream_name != PMETA_STREAM_NAME {
+                    monitor::record(ServerEvent::AlertStateChanged {
+                        stream: context.stream.clone(),
+                        alert: context.alert_info.alert_name.clone(),
+                        state: context.alert_info.alert_state.to_string(),
+                    });
+                }
+                if silence::is_silenced(stream_name, &self.name) {
+                    log::info!(
+                        "alert {} on {stream_name} is silenced, targets are not called",
+                        self.name
                     );
-                    ALERTS_STATES
-                        .with_label_values(&[
-                            context.stream.as_str(),
-                            context.alert_info.alert_name.as_str(),
-                            context.alert_info.alert_state.to_string().as_str(),
-                        ])
-                        .inc();
-                    for target in &self.targets {
-                        target.call(context.clone());
-                    }
+                    return;
+                }
+                for target in &self.targets {
+                    target.call(context.clone());
                 }
             }
         }
@@ -108,11 +144,21 @@ impl Alert {
         );
         let deployment_id = storage::StorageMetadata::global().deployment_id;
         let deployment_mode = storage::StorageMetadata::global().mode.to_string();
+        let console_address = CONFIG
+            .parseable
+            .domain_address
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_else(|| deployment_instance.clone());
         let additional_labels =
             serde_json::to_value(rule).expect("rule is perfectly deserializable");
         let flatten_additional_labels =
             utils::json::flatten::flatten_with_parent_prefix(additional_labels, "rule", "_")
                 .expect("can be flattened");
+        let sample = record_batches_to_json_rows(&[&event_row]).unwrap_or_else(|err| {
+            log::warn!("could not convert the events of alert {}: {err}", self.name);
+            Vec::new()
+        });
         Context::new(
             stream_name,
             AlertInfo::new(
@@ -121,8 +167,14 @@ impl Alert {
                 rule.trigger_reason(),
                 alert_state,
             ),
-            DeploymentInfo::new(deployment_instance, deployment_id, deployment_mode),
+            DeploymentInfo::new(
+                deployment_instance,
+                deployment_id,
+                deployment_mode,
+                console_address,
+            ),
             flatten_additional_labels,
+            sample,
         )
     }
 }
@@ -175,6 +227,8 @@ pub struct Context {
     alert_info: AlertInfo,
     deployment_info: DeploymentInfo,
     additional_labels: serde_json::Value,
+    /// rows the alert was triggered by
+    sample: Vec<Map<String, Value>>,
 }
 
 impl Context {
@@ -183,12 +237,14 @@ impl Context {
         alert_info: AlertInfo,
         deployment_info: DeploymentInfo,
         additional_labels: serde_json::Value,
+        sample: Vec<Map<String, Value>>,
     ) -> Self {
         Self {
             stream,
             alert_info,
             deployment_info,
             additional_labels,
+            sample,
         }
     }
 
@@ -239,6 +295,8 @@ pub struct DeploymentInfo {
     deployment_instance: String,
     deployment_id: uid::Uid,
     deployment_mode: String,
+    /// address the console is served at, links in notifications point to it
+    console_address: String,
 }
 
 impl DeploymentInfo {
@@ -246,11 +304,13 @@ impl DeploymentInfo {
         deployment_instance: String,
         deployment_id: uid::Uid,
         deployment_mode: String,
+        console_address: String,
     ) -> Self {
         Self {
             deployment_instance,
             deployment_id,
             deployment_mode,
+            console_address,
         }
     }
 }
@@ -35,6 +35,7 @@ use self::base::{
     NumericRule, StringRule,
 };
 
+use super::burn_rate::BurnRateRule;
 use super::AlertState;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -44,6 +45,7 @@ pub enum Rule {
     Column(ColumnRule),
     #[serde(deserialize_with = "string_or_struct", serialize_with = "to_string")]
     Composite(CompositeRule),
+    BurnRate(BurnRateRule),
 }
 
 impl Rule {
@@ -61,6 +63,8 @@ impl Rule {
                     }
                 })
                 .collect(),
+            // evaluated by querying the stream, see `burn_rate`
+            Rule::BurnRate(_) => Vec::new(),
         }
     }
 
@@ -68,6 +72,8 @@ impl Rule {
         match self {
             Rule::Column(rule) => rule.valid_for_schema(schema),
             Rule::Composite(rule) => rule.valid_for_schema(schema),
+            // conditions are checked by planning their query, see `valid_for_stream`
+            Rule::BurnRate(_) => true,
         }
     }
 
@@ -75,6 +81,7 @@ impl Rule {
         match self {
             Rule::Column(rule) => rule.trigger_reason(),
             Rule::Composite(rule) => format!("matched rule {}", rule),
+            Rule::BurnRate(rule) => rule.trigger_reason(),
         }
     }
 }
@@ -87,6 +94,13 @@ pub enum ColumnRule {
 }
 
 impl ColumnRule {
+    pub fn column(&self) -> &str {
+        match self {
+            Self::ConsecutiveNumeric(rule) => &rule.base_rule.column,
+            Self::ConsecutiveString(rule) => &rule.base_rule.column,
+        }
+    }
+
     fn resolves(&self, event: RecordBatch) -> Vec<AlertState> {
         match self {
             Self::ConsecutiveNumeric(rule) => rule.resolves(event),
@@ -0,0 +1,255 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Silences keep alerts from calling their targets between a start and an end time, e.g.
+//! during planned maintenance. A silence applies to the alerts matched by all of its matchers.
+//! Alert states are still tracked while silenced.
+//!
+//! Silences are kept in storage and every node reloads them each minute, so that ingesters
+//! evaluating alerts see the silences created through the query server.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
+use relative_path::RelativePathBuf;
+use ulid::Ulid;
+
+use crate::option::CONFIG;
+use crate::storage::{ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+
+const SILENCES_DIRECTORY: &str = "silences";
+const SILENCE_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+static SILENCES: Lazy<RwLock<Vec<Silence>>> = Lazy::new(RwLock::default);
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Silence {
+    pub id: Ulid,
+    pub matchers: Vec<Matcher>,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    #[serde(default)]
+    pub comment: String,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Matcher {
+    pub name: MatcherName,
+    pub value: String,
+    /// value is a regex the whole name has to match
+    #[serde(default)]
+    pub is_regex: bool,
+    // regex of the value, compiled when it is first matched
+    #[serde(skip)]
+    compiled: OnceCell<Result<Regex, regex::Error>>,
+}
+
+/// What of an alert a matcher compares
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatcherName {
+    Stream,
+    Alert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SilenceStatus {
+    Pending,
+    Active,
+    Expired,
+}
+
+impl Matcher {
+    pub fn new(name: MatcherName, value: String, is_regex: bool) -> Self {
+        Self {
+            name,
+            value,
+            is_regex,
+            compiled: OnceCell::new(),
+        }
+    }
+
+    pub fn regex(&self) -> Result<&Regex, &regex::Error> {
+        self.compiled
+            .get_or_init(|| Regex::new(&format!("^(?:{})$", self.value)))
+            .as_ref()
+    }
+
+    fn matches(&self, stream_name: &str, alert_name: &str) -> bool {
+        let name = match self.name {
+            MatcherName::Stream => stream_name,
+            MatcherName::Alert => alert_name,
+        };
+        if self.is_regex {
+            self.regex().is_ok_and(|regex| regex.is_match(name))
+        } else {
+            self.value == name
+        }
+    }
+}
+
+impl Silence {
+    pub fn status(&self, now: DateTime<Utc>) -> SilenceStatus {
+        if now < self.starts_at {
+            SilenceStatus::Pending
+        } else if now < self.ends_at {
+            SilenceStatus::Active
+        } else {
+            SilenceStatus::Expired
+        }
+    }
+
+    pub fn matches(&self, stream_name: &str, alert_name: &str) -> bool {
+        self.matchers
+            .iter()
+            .all(|matcher| matcher.matches(stream_name, alert_name))
+    }
+}
+
+/// Ids of the silences active now for an alert
+pub fn silenced_by(stream_name: &str, alert_name: &str) -> Vec<Ulid> {
+    let now = Utc::now();
+    SILENCES
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|silence| {
+            silence.status(now) == SilenceStatus::Active && silence.matches(stream_name, alert_name)
+        })
+        .map(|silence| silence.id)
+        .collect()
+}
+
+pub fn is_silenced(stream_name: &str, alert_name: &str) -> bool {
+    !silenced_by(stream_name, alert_name).is_empty()
+}
+
+pub fn silences_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, SILENCES_DIRECTORY])
+}
+
+pub fn silence_path(id: Ulid) -> RelativePathBuf {
+    silences_path().join(format!("{id}.json"))
+}
+
+pub async fn list_silences() -> Result<Vec<Silence>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = match store
+        .get_objects(
+            Some(&silences_path()),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await
+    {
+        Ok(objects) => objects,
+        // nothing was saved yet
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    objects
+        .iter()
+        .map(|bytes| {
+            serde_json::from_slice(bytes)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))
+        })
+        .collect()
+}
+
+pub async fn put_silence(silence: Silence) -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let body = serde_json::to_vec(&silence)
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    store
+        .put_object(&silence_path(silence.id), body.into())
+        .await?;
+
+    let mut silences = SILENCES.write().unwrap();
+    silences.retain(|existing| existing.id != silence.id);
+    silences.push(silence);
+    Ok(())
+}
+
+pub async fn delete_silence(id: Ulid) -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    store.delete_object(&silence_path(id)).await?;
+
+    SILENCES.write().unwrap().retain(|silence| silence.id != id);
+    Ok(())
+}
+
+/// Load the silences and reload them every minute
+pub fn init() {
+    tokio::spawn(async {
+        loop {
+            match list_silences().await {
+                Ok(silences) => *SILENCES.write().unwrap() = silences,
+                Err(err) => log::warn!("could not load alert silences: {err}"),
+            }
+            tokio::time::sleep(SILENCE_RELOAD_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use ulid::Ulid;
+
+    use super::{Matcher, MatcherName, Silence, SilenceStatus};
+
+    #[test]
+    fn silence_matches_all_matchers() {
+        let starts_at = Utc.with_ymd_and_hms(2024, 3, 5, 22, 0, 0).unwrap();
+        let silence = Silence {
+            id: Ulid::new(),
+            matchers: vec![
+                Matcher::new(MatcherName::Stream, "nginx".to_string(), false),
+                Matcher::new(MatcherName::Alert, "5xx.*|latency".to_string(), true),
+            ],
+            starts_at,
+            ends_at: Utc.with_ymd_and_hms(2024, 3, 6, 2, 0, 0).unwrap(),
+            comment: "database upgrade".to_string(),
+            created_by: "alice".to_string(),
+            created_at: starts_at,
+        };
+
+        assert!(silence.matches("nginx", "5xx errors"));
+        assert!(silence.matches("nginx", "latency"));
+        assert!(!silence.matches("nginx", "high latency"));
+        assert!(!silence.matches("app", "latency"));
+
+        let at = |hour| Utc.with_ymd_and_hms(2024, 3, 5, hour, 0, 0).unwrap();
+        assert_eq!(silence.status(at(21)), SilenceStatus::Pending);
+        assert_eq!(silence.status(at(23)), SilenceStatus::Active);
+        assert_eq!(
+            silence.status(Utc.with_ymd_and_hms(2024, 3, 6, 2, 0, 0).unwrap()),
+            SilenceStatus::Expired
+        );
+    }
+}
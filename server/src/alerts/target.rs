@@ -29,6 +29,7 @@ use http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
 use humantime_serde::re::humantime;
 use reqwest::ClientBuilder;
 
+use crate::tls;
 use crate::utils::json;
 
 use super::{AlertState, CallableTarget, Context};
@@ -203,6 +204,14 @@ pub enum TargetType {
 }
 
 impl TargetType {
+    pub fn template(&self) -> Option<&String> {
+        match self {
+            TargetType::Slack(target) => target.template.as_ref(),
+            TargetType::Other(target) => target.template.as_ref(),
+            TargetType::AlertManager(target) => target.template.as_ref(),
+        }
+    }
+
     pub async fn call(&self, payload: &Context) {
         match self {
             TargetType::Slack(target) => target.call(payload).await,
@@ -213,12 +222,15 @@ impl TargetType {
 }
 
 fn default_client_builder() -> ClientBuilder {
-    ClientBuilder::new()
+    tls::client_builder(None)
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct SlackWebHook {
     endpoint: String,
+    /// handlebars template of the notification, see [`super::template`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
 }
 
 #[async_trait]
@@ -228,15 +240,14 @@ impl CallableTarget for SlackWebHook {
             .build()
             .expect("Client can be constructed on this system");
 
-        let alert = match payload.alert_info.alert_state {
-            AlertState::SetToFiring => {
-                serde_json::json!({ "text": payload.default_alert_string() })
-            }
-            AlertState::Resolved => {
-                serde_json::json!({ "text": payload.default_resolved_string() })
+        let text = payload.render(self.template.as_ref()).unwrap_or_else(|| {
+            match payload.alert_info.alert_state {
+                AlertState::SetToFiring => payload.default_alert_string(),
+                AlertState::Resolved => payload.default_resolved_string(),
+                _ => unreachable!(),
             }
-            _ => unreachable!(),
-        };
+        });
+        let alert = serde_json::json!({ "text": text });
 
         if let Err(e) = client.post(&self.endpoint).json(&alert).send().await {
             log::error!("Couldn't make call to webhook, error: {}", e)
@@ -252,6 +263,9 @@ pub struct OtherWebHook {
     headers: HashMap<String, String>,
     #[serde(default)]
     skip_tls_check: bool,
+    /// handlebars template of the notification, see [`super::template`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
 }
 
 #[async_trait]
@@ -266,11 +280,13 @@ impl CallableTarget for OtherWebHook {
             .build()
             .expect("Client can be constructed on this system");
 
-        let alert = match payload.alert_info.alert_state {
-            AlertState::SetToFiring => payload.default_alert_string(),
-            AlertState::Resolved => payload.default_resolved_string(),
-            _ => unreachable!(),
-        };
+        let alert = payload.render(self.template.as_ref()).unwrap_or_else(|| {
+            match payload.alert_info.alert_state {
+                AlertState::SetToFiring => payload.default_alert_string(),
+                AlertState::Resolved => payload.default_resolved_string(),
+                _ => unreachable!(),
+            }
+        });
 
         let request = client
             .post(&self.endpoint)
@@ -289,6 +305,9 @@ pub struct AlertManager {
     skip_tls_check: bool,
     #[serde(flatten)]
     auth: Option<Auth>,
+    /// handlebars template of the notification, see [`super::template`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
 }
 
 #[async_trait]
@@ -330,6 +349,10 @@ impl CallableTarget for AlertManager {
 
         let alert = &mut alerts[0];
 
+        if let Some(message) = payload.render(self.template.as_ref()) {
+            alert["annotations"]["message"] = message.into();
+        }
+
         alert["labels"].as_object_mut().expect("is object").extend(
             payload
                 .additional_labels
@@ -0,0 +1,145 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Targets can format their notification with a Handlebars template instead of the default
+//! text. A template sees
+//!
+//! - `alert`: `name`, `state` (`firing` or `resolved`), `message` and `reason`
+//! - `stream`
+//! - `labels`: the rule of the alert, flattened as for alertmanager
+//! - `deployment`: `instance`, `id` and `mode`
+//! - `sample`: the rows the alert was triggered by
+//! - `links`: `console` and `stream`, the logs of the stream in the console
+//!
+//! Values are not escaped, the templates are not rendered to html.
+
+use handlebars::{no_escape, Handlebars, RenderError, Template, TemplateError};
+use serde_json::{json, Value};
+
+use super::{AlertState, Context};
+
+#[allow(clippy::result_large_err)]
+pub fn validate(template: &str) -> Result<(), TemplateError> {
+    Template::compile(template).map(|_| ())
+}
+
+pub fn render(template: &str, context: &Context) -> Result<String, RenderError> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars.render_template(template, &data(context))
+}
+
+fn data(context: &Context) -> Value {
+    let state = match context.alert_info.alert_state {
+        AlertState::Resolved => "resolved",
+        _ => "firing",
+    };
+    let console = context
+        .deployment_info
+        .console_address
+        .trim_end_matches('/');
+    json!({
+        "alert": {
+            "name": context.alert_info.alert_name,
+            "state": state,
+            "message": context.alert_info.message,
+            "reason": context.alert_info.reason,
+        },
+        "stream": context.stream,
+        "labels": context.additional_labels,
+        "deployment": {
+            "instance": context.deployment_info.deployment_instance,
+            "id": context.deployment_info.deployment_id,
+            "mode": context.deployment_info.deployment_mode,
+        },
+        "sample": context.sample,
+        "links": {
+            "console": console,
+            "stream": format!("{console}/{}/logs", context.stream),
+        },
+    })
+}
+
+impl Context {
+    /// Notification text of a target with a template, none if the template fails to render
+    pub fn render(&self, template: Option<&String>) -> Option<String> {
+        let template = template?;
+        match render(template, self) {
+            Ok(text) => Some(text),
+            Err(err) => {
+                log::warn!(
+                    "could not render template of alert {} on {}, sending the default notification: {err}",
+                    self.alert_info.alert_name,
+                    self.stream
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{render, validate};
+    use crate::alerts::{AlertInfo, AlertState, Context, DeploymentInfo};
+    use crate::utils::uid;
+
+    #[test]
+    fn template_sees_alert_sample_and_links() {
+        let context = Context::new(
+            "nginx".to_string(),
+            AlertInfo::new(
+                "5xx errors".to_string(),
+                "status was 502".to_string(),
+                "status >= 500".to_string(),
+                AlertState::SetToFiring,
+            ),
+            DeploymentInfo::new(
+                "http://0.0.0.0:8000".to_string(),
+                uid::gen(),
+                "Standalone".to_string(),
+                "https://logs.example.com/".to_string(),
+            ),
+            json!({"rule_type": "column"}),
+            vec![json!({"status": 502, "path": "/api/<users>"})
+                .as_object()
+                .unwrap()
+                .clone()],
+        );
+
+        let text = render(
+            "[{{alert.state}}] {{alert.name}} on {{stream}} ({{labels.rule_type}})\n\
+             {{#each sample}}{{this.status}} {{this.path}}{{/each}}\n\
+             {{links.stream}}",
+            &context,
+        )
+        .unwrap();
+        assert_eq!(
+            text,
+            "[firing] 5xx errors on nginx (column)\n502 /api/<users>\nhttps://logs.example.com/nginx/logs"
+        );
+    }
+
+    #[test]
+    fn invalid_template_is_rejected() {
+        assert!(validate("{{alert.name}}").is_ok());
+        assert!(validate("{{#each sample}}{{this}}").is_err());
+    }
+}
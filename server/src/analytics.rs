@@ -18,10 +18,12 @@
  */
 
 use crate::about::{current, platform};
+use crate::handlers::http::cluster::node_client::CLIENT;
 use crate::handlers::http::cluster::utils::check_liveness;
 use crate::handlers::http::{base_path_without_preceding_slash, cluster};
 use crate::option::{Mode, CONFIG};
 use crate::storage;
+use crate::tls;
 use crate::{metadata, stats};
 
 use actix_web::{web, HttpRequest, Responder};
@@ -117,7 +119,9 @@ impl Report {
     }
 
     pub async fn send(&self) {
-        let client = reqwest::Client::new();
+        let client = tls::client_builder(None)
+            .build()
+            .expect("client can be built on this system");
         let _ = client.post(ANALYTICS_SERVER_URL).json(&self).send().await;
     }
 }
@@ -175,10 +179,11 @@ async fn fetch_ingesters_metrics() -> (u64, u64, usize, u64, u64, u64) {
             ))
             .expect("Should be a valid URL");
 
-            let resp = reqwest::Client::new()
+            let resp = CLIENT
                 .get(uri)
-                .header(header::AUTHORIZATION, im.token.clone())
+                .header(header::AUTHORIZATION, im.authorization())
                 .header(header::CONTENT_TYPE, "application/json")
+                .timeout(CONFIG.parseable.cluster_request_timeout)
                 .send()
                 .await
                 .unwrap(); // should respond
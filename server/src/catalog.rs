@@ -16,19 +16,34 @@
  *
  */
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, Utc};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use futures::TryStreamExt;
+use itertools::Itertools;
+use once_cell::sync::Lazy;
 use relative_path::RelativePathBuf;
+use tokio::sync::Mutex;
 
 use crate::{
     catalog::manifest::Manifest,
+    option::{Mode, CONFIG},
     query::PartialTimeFilter,
-    storage::{ObjectStorage, ObjectStorageError, MANIFEST_FILE},
+    storage::{
+        compression, ObjectStorage, ObjectStorageError, ObjectStoreFormat, MANIFEST_FILE,
+        STREAM_METADATA_FILE_NAME, STREAM_ROOT_DIRECTORY,
+    },
     utils::get_address,
 };
 
-use self::{column::Column, snapshot::ManifestItem};
+use self::{
+    column::Column,
+    snapshot::{ManifestItem, SnapshotAt},
+};
 
 pub mod column;
 pub mod manifest;
@@ -36,6 +51,13 @@ pub mod snapshot;
 
 pub use manifest::create_from_parquet_file;
 
+// directory of the snapshot log below the stream root directory
+pub(crate) const SNAPSHOT_LOG_DIR: &str = "snapshots";
+const SNAPSHOT_LOG_SUFFIX: &str = ".snapshot.json";
+
+// commits of a node are serialized so that every commit is based on the latest snapshot
+static COMMIT_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
 pub trait Snapshot {
     fn manifests(&self, time_predicates: &[PartialTimeFilter]) -> Vec<ManifestItem>;
 }
@@ -92,85 +114,37 @@ fn get_file_bounds(file: &manifest::File) -> (DateTime<Utc>, DateTime<Utc>) {
     }
 }
 
+/// Add uploaded files to the manifests of the stream and commit the result as a new snapshot.
+/// The files of a day are written as a new manifest rather than added to the manifest of
+/// the day, a manifest is only merged into the new one once it holds no more files than the
+/// new one, so that a file is rewritten a logarithmic number of times and a day is covered
+/// by a logarithmic number of manifests. Manifests are not changed once a snapshot refers to
+/// them, so that readers of an older snapshot keep a consistent view.
 pub async fn update_snapshot(
     storage: Arc<dyn ObjectStorage + Send>,
     stream_name: &str,
-    change: manifest::File,
+    changes: Vec<manifest::File>,
 ) -> Result<(), ObjectStorageError> {
-    // get current snapshot
-    let mut meta = storage.get_object_store_format(stream_name).await?;
-    let manifests = &mut meta.snapshot.manifest_list;
-
-    let (lower_bound, _) = get_file_bounds(&change);
-    let pos = manifests.iter().position(|item| {
-        item.time_lower_bound <= lower_bound && lower_bound < item.time_upper_bound
-    });
-
-    // if the mode in I.S. manifest needs to be created but it is not getting created because
-    // there is already a pos, to index into stream.json
-
-    // We update the manifest referenced by this position
-    // This updates an existing file so there is no need to create a snapshot entry.
-    if let Some(pos) = pos {
-        let info = &mut manifests[pos];
-        let path = partition_path(stream_name, info.time_lower_bound, info.time_upper_bound);
-
-        let mut ch = false;
-        for m in manifests.iter() {
-            let s = get_address();
-            let p = format!("{}.{}.{}", s.0, s.1, MANIFEST_FILE);
-            if m.manifest_path.contains(&p) {
-                ch = true;
-            }
-        }
-        if ch {
-            let Some(mut manifest) = storage.get_manifest(&path).await? else {
-                return Err(ObjectStorageError::UnhandledError(
-                    "Manifest found in snapshot but not in object-storage"
-                        .to_string()
-                        .into(),
-                ));
-            };
-            manifest.apply_change(change);
-            storage.put_manifest(&path, manifest).await?;
-        } else {
-            let lower_bound = lower_bound.date_naive().and_time(NaiveTime::MIN).and_utc();
-            let upper_bound = lower_bound
-                .date_naive()
-                .and_time(
-                    NaiveTime::from_num_seconds_from_midnight_opt(
-                        23 * 3600 + 59 * 60 + 59,
-                        999_999_999,
-                    )
-                    .unwrap(),
-                )
-                .and_utc();
+    if changes.is_empty() {
+        return Ok(());
+    }
 
-            let manifest = Manifest {
-                files: vec![change],
-                ..Manifest::default()
-            };
+    let _guard = COMMIT_LOCK.lock().await;
+    let mut snapshot = storage.get_object_store_format(stream_name).await?.snapshot;
+    let snapshot_id = snapshot.snapshot_id + 1;
+
+    let mut changes_by_day: BTreeMap<NaiveDate, Vec<manifest::File>> = BTreeMap::new();
+    for change in changes {
+        let (lower_bound, _) = get_file_bounds(&change);
+        changes_by_day
+            .entry(lower_bound.date_naive())
+            .or_default()
+            .push(change);
+    }
 
-            let addr = get_address();
-            let mainfest_file_name = format!("{}.{}.{}", addr.0, addr.1, MANIFEST_FILE);
-            let path =
-                partition_path(stream_name, lower_bound, upper_bound).join(&mainfest_file_name);
-            storage
-                .put_object(&path, serde_json::to_vec(&manifest).unwrap().into())
-                .await?;
-            let path = storage.absolute_url(&path);
-            let new_snapshot_entriy = snapshot::ManifestItem {
-                manifest_path: path.to_string(),
-                time_lower_bound: lower_bound,
-                time_upper_bound: upper_bound,
-            };
-            manifests.push(new_snapshot_entriy);
-            storage.put_snapshot(stream_name, meta.snapshot).await?;
-        }
-    } else {
-        let lower_bound = lower_bound.date_naive().and_time(NaiveTime::MIN).and_utc();
-        let upper_bound = lower_bound
-            .date_naive()
+    for (day, changes) in changes_by_day {
+        let lower_bound = day.and_time(NaiveTime::MIN).and_utc();
+        let upper_bound = day
             .and_time(
                 NaiveTime::from_num_seconds_from_midnight_opt(
                     23 * 3600 + 59 * 60 + 59,
@@ -180,28 +154,399 @@ pub async fn update_snapshot(
             )
             .and_utc();
 
-        let manifest = Manifest {
-            files: vec![change],
-            ..Manifest::default()
-        };
+        let mut manifest = Manifest::default();
+        for change in changes {
+            manifest.apply_change(change);
+        }
 
-        let addr = get_address();
-        let mainfest_file_name = format!("{}.{}.{}", addr.0, addr.1, MANIFEST_FILE);
-        let path = partition_path(stream_name, lower_bound, upper_bound).join(&mainfest_file_name);
+        // older manifests go first so that a file committed again replaces its older entry
+        let merged = manifests_to_merge(&snapshot.manifest_list, lower_bound, manifest.files.len());
+        if !merged.is_empty() {
+            let mut merged_manifest = Manifest::default();
+            for &pos in merged.iter().rev() {
+                let item = &snapshot.manifest_list[pos];
+                let Some(older) = read_manifest(&item.manifest_path).await? else {
+                    return Err(ObjectStorageError::UnhandledError(
+                        "Manifest found in snapshot but not in object-storage"
+                            .to_string()
+                            .into(),
+                    ));
+                };
+                for file in older.files {
+                    merged_manifest.apply_change(file);
+                }
+            }
+            for file in manifest.files {
+                merged_manifest.apply_change(file);
+            }
+            manifest = merged_manifest;
+            // positions are in descending order, removing one keeps the others valid
+            for pos in merged {
+                snapshot.manifest_list.remove(pos);
+            }
+        }
+
+        let path = partition_path(stream_name, lower_bound, upper_bound)
+            .join(manifest_file_name(snapshot_id));
         storage
             .put_object(&path, serde_json::to_vec(&manifest).unwrap().into())
             .await?;
-        let path = storage.absolute_url(&path);
-        let new_snapshot_entriy = snapshot::ManifestItem {
-            manifest_path: path.to_string(),
+        snapshot.manifest_list.push(snapshot::ManifestItem {
+            manifest_path: storage.absolute_url(&path).to_string(),
             time_lower_bound: lower_bound,
             time_upper_bound: upper_bound,
+            column_stats: manifest.column_stats(),
+            num_files: manifest.files.len(),
+        });
+    }
+
+    commit_snapshot(&*storage, stream_name, snapshot, snapshot_id).await
+}
+
+// Positions of the latest manifests of the day, in descending order, which are merged into a
+// new manifest of `num_files` files. A manifest is merged while it holds no more files than
+// the manifests merged so far, manifests of unknown size are never merged.
+fn manifests_to_merge(
+    manifest_list: &[ManifestItem],
+    lower_bound: DateTime<Utc>,
+    num_files: usize,
+) -> Vec<usize> {
+    let mut merged_files = num_files;
+    let mut merged = Vec::new();
+    for (pos, item) in manifest_list.iter().enumerate().rev() {
+        if !(item.time_lower_bound <= lower_bound && lower_bound < item.time_upper_bound) {
+            continue;
+        }
+        if item.num_files == 0 || item.num_files > merged_files {
+            break;
+        }
+        merged_files += item.num_files;
+        merged.push(pos);
+    }
+    merged
+}
+
+/// Replace files of the current snapshot of the stream and commit the result as a new snapshot.
+/// A file maps to the entry of the file replacing it, or to none if it is dropped. Files
+/// committed meanwhile are kept, the replaced files stay in storage for the older snapshots
+/// until they expire.
+pub async fn replace_files(
+    storage: Arc<dyn ObjectStorage + Send>,
+    stream_name: &str,
+    replacements: HashMap<String, Option<manifest::File>>,
+) -> Result<(), ObjectStorageError> {
+    if replacements.is_empty() {
+        return Ok(());
+    }
+
+    let _guard = COMMIT_LOCK.lock().await;
+    let mut snapshot = storage.get_object_store_format(stream_name).await?.snapshot;
+    let snapshot_id = snapshot.snapshot_id + 1;
+
+    for item in snapshot.manifest_list.iter_mut() {
+        let Some(mut manifest) = read_manifest(&item.manifest_path).await? else {
+            continue;
         };
-        manifests.push(new_snapshot_entriy);
-        storage.put_snapshot(stream_name, meta.snapshot).await?;
+        if !manifest
+            .files
+            .iter()
+            .any(|file| replacements.contains_key(&file.file_path))
+        {
+            continue;
+        }
+        manifest.files = manifest
+            .files
+            .into_iter()
+            .filter_map(|file| match replacements.get(&file.file_path) {
+                Some(replacement) => replacement.clone(),
+                None => Some(file),
+            })
+            .collect();
+
+        let path = partition_path(stream_name, item.time_lower_bound, item.time_upper_bound)
+            .join(manifest_file_name(snapshot_id));
+        storage
+            .put_object(&path, serde_json::to_vec(&manifest).unwrap().into())
+            .await?;
+        item.manifest_path = storage.absolute_url(&path).to_string();
+        item.column_stats = manifest.column_stats();
+        item.num_files = manifest.files.len();
     }
 
-    Ok(())
+    commit_snapshot(&*storage, stream_name, snapshot, snapshot_id).await
+}
+
+// The snapshot is recorded in the snapshot log before it becomes the current snapshot of the
+// stream, replacing the snapshot in stream.json is the single write which makes a commit visible
+async fn commit_snapshot(
+    storage: &(dyn ObjectStorage + Send),
+    stream_name: &str,
+    mut snapshot: snapshot::Snapshot,
+    snapshot_id: u64,
+) -> Result<(), ObjectStorageError> {
+    snapshot.version = snapshot::CURRENT_SNAPSHOT_VERSION.to_string();
+    snapshot.snapshot_id = snapshot_id;
+    snapshot.committed_at = Some(Utc::now());
+    let entry = SnapshotLogEntry {
+        node: node_id(),
+        snapshot: snapshot.clone(),
+    };
+    storage
+        .put_object(
+            &snapshot_log_path(stream_name, &entry.node, snapshot_id),
+            serde_json::to_vec(&entry).unwrap().into(),
+        )
+        .await?;
+    storage.put_snapshot(stream_name, snapshot).await
+}
+
+/// Snapshots of the stream in the snapshot log, committed by the node with the given id or by
+/// every node if none is given. Ordered by node and then by snapshot id.
+pub async fn list_snapshots(
+    storage: &(dyn ObjectStorage + Send),
+    stream_name: &str,
+    node: Option<String>,
+) -> Result<Vec<(String, snapshot::Snapshot)>, ObjectStorageError> {
+    let path = RelativePathBuf::from_iter([stream_name, STREAM_ROOT_DIRECTORY, SNAPSHOT_LOG_DIR]);
+    let filter = Box::new(move |file_name: String| {
+        file_name.ends_with(SNAPSHOT_LOG_SUFFIX)
+            && node
+                .as_ref()
+                .map_or(true, |node| file_name.starts_with(&format!("{node}.")))
+    });
+
+    let mut snapshots = Vec::new();
+    for bytes in storage.get_objects(Some(&path), filter).await? {
+        let entry: SnapshotLogEntry = serde_json::from_slice(&bytes)?;
+        snapshots.push((entry.node, entry.snapshot));
+    }
+    snapshots.sort_by(|(a, x), (b, y)| a.cmp(b).then(x.snapshot_id.cmp(&y.snapshot_id)));
+    Ok(snapshots)
+}
+
+/// Snapshot of the stream as of `at`, merged across the nodes which committed snapshots of it.
+/// A time selects the latest snapshot every node committed until then, snapshot ids are only
+/// meaningful while a single node commits snapshots of the stream.
+pub async fn snapshot_at(
+    storage: &(dyn ObjectStorage + Send),
+    stream_name: &str,
+    at: SnapshotAt,
+) -> Result<snapshot::Snapshot, ObjectStorageError> {
+    let snapshots = list_snapshots(storage, stream_name, None).await?;
+    let mut merged = snapshot::Snapshot::default();
+
+    match at {
+        SnapshotAt::Id(id) => {
+            if snapshots.iter().map(|(node, _)| node).unique().count() > 1 {
+                return Err(ObjectStorageError::Custom(format!(
+                    "snapshots of stream {stream_name} are committed by several nodes, query it at a time instead"
+                )));
+            }
+            let Some((_, snapshot)) = snapshots
+                .into_iter()
+                .find(|(_, snapshot)| snapshot.snapshot_id == id)
+            else {
+                return Err(ObjectStorageError::Custom(format!(
+                    "snapshot {id} of stream {stream_name} does not exist or has expired"
+                )));
+            };
+            merged.manifest_list = snapshot.manifest_list;
+        }
+        SnapshotAt::Time(time) => {
+            for (_, snapshots) in &snapshots.into_iter().group_by(|(node, _)| node.clone()) {
+                let latest = snapshots
+                    .map(|(_, snapshot)| snapshot)
+                    .filter(|snapshot| snapshot.committed_at.is_some_and(|at| at <= time))
+                    .last();
+                if let Some(snapshot) = latest {
+                    merged.manifest_list.extend(snapshot.manifest_list);
+                }
+            }
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Remove the snapshots this node committed more than `retention` ago, along with the manifests
+/// and data files no remaining snapshot refers to. The current snapshot is always kept.
+pub async fn expire_snapshots(
+    storage: Arc<dyn ObjectStorage + Send>,
+    stream_name: &str,
+    retention: Duration,
+) -> Result<usize, ObjectStorageError> {
+    let _guard = COMMIT_LOCK.lock().await;
+    let current = storage.get_object_store_format(stream_name).await?.snapshot;
+    let cutoff =
+        Utc::now() - chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::max_value());
+
+    let node = node_id();
+    let (expired, kept): (Vec<_>, Vec<_>) =
+        list_snapshots(&*storage, stream_name, Some(node.clone()))
+            .await?
+            .into_iter()
+            .map(|(_, snapshot)| snapshot)
+            .partition(|snapshot| {
+                snapshot.snapshot_id != current.snapshot_id
+                    && snapshot.committed_at.is_some_and(|at| at < cutoff)
+            });
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let referenced: HashSet<&str> = kept
+        .iter()
+        .chain([&current])
+        .flat_map(|snapshot| &snapshot.manifest_list)
+        .map(|item| item.manifest_path.as_str())
+        .collect();
+    let store = CONFIG
+        .storage()
+        .get_datafusion_object_store()
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    let mut deleted = HashSet::new();
+    // files of the removed manifests, e.g. replaced by a purge, and the days they are of
+    let mut unreferenced_files = HashSet::new();
+    let mut days = HashSet::new();
+    for snapshot in &expired {
+        for item in &snapshot.manifest_list {
+            let path = item.manifest_path.as_str();
+            if referenced.contains(path) || !deleted.insert(path) {
+                continue;
+            }
+            // manifests of dates removed by retention are already gone
+            if let Some(manifest) = read_manifest(path).await? {
+                unreferenced_files.extend(manifest.files.into_iter().map(|file| file.file_path));
+                days.insert(item.time_lower_bound);
+            }
+            delete_path(&*store, path).await?;
+        }
+        storage
+            .delete_object(&snapshot_log_path(stream_name, &node, snapshot.snapshot_id))
+            .await?;
+    }
+
+    // a file stays as long as a manifest of a remaining snapshot refers to it, manifests of
+    // other days do not refer to files of these days
+    let remaining: HashSet<&str> = kept
+        .iter()
+        .chain([&current])
+        .flat_map(|snapshot| &snapshot.manifest_list)
+        .filter(|item| days.contains(&item.time_lower_bound))
+        .map(|item| item.manifest_path.as_str())
+        .collect();
+    for path in remaining {
+        if let Some(manifest) = read_manifest(path).await? {
+            for file in &manifest.files {
+                unreferenced_files.remove(&file.file_path);
+            }
+        }
+    }
+    for path in &unreferenced_files {
+        delete_path(&*store, path).await?;
+    }
+
+    Ok(expired.len())
+}
+
+async fn delete_path(
+    store: &dyn object_store::ObjectStore,
+    path: &str,
+) -> Result<(), ObjectStorageError> {
+    let path = object_store::path::Path::parse(path)
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    match store.delete(&path).await {
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(crate) async fn read_manifest(
+    manifest_path: &str,
+) -> Result<Option<Manifest>, ObjectStorageError> {
+    let store = CONFIG
+        .storage()
+        .get_datafusion_object_store()
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    let path = object_store::path::Path::parse(manifest_path)
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    match compression::read(&*store, &path).await {
+        Ok(bytes) => Ok(Some(
+            serde_json::from_slice(&bytes).expect("manifest is valid json"),
+        )),
+        Err(object_store::Error::NotFound { .. }) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// snapshots and manifests are named after the node that commits them, nodes never share them
+fn node_id() -> String {
+    let addr = get_address();
+    format!("{}.{}", addr.0, addr.1)
+}
+
+fn manifest_file_name(snapshot_id: u64) -> String {
+    format!("{}.{snapshot_id:020}.{MANIFEST_FILE}", node_id())
+}
+
+fn snapshot_log_path(stream_name: &str, node: &str, snapshot_id: u64) -> RelativePathBuf {
+    RelativePathBuf::from_iter([
+        stream_name,
+        STREAM_ROOT_DIRECTORY,
+        SNAPSHOT_LOG_DIR,
+        &format!("{node}.{snapshot_id:020}{SNAPSHOT_LOG_SUFFIX}"),
+    ])
+}
+
+// entry of the snapshot log, the node is kept in the entry so that it is not parsed from the name
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotLogEntry {
+    node: String,
+    #[serde(flatten)]
+    snapshot: snapshot::Snapshot,
+}
+
+/// Manifests of the stream, including the ones of all ingesters in distributed mode
+pub async fn get_manifest_list(
+    storage: Arc<dyn ObjectStorage + Send>,
+    stream_name: &str,
+) -> Result<Vec<ManifestItem>, ObjectStorageError> {
+    let mut manifest_list = storage
+        .get_object_store_format(stream_name)
+        .await?
+        .snapshot
+        .manifest_list;
+
+    if CONFIG.parseable.mode == Mode::Query {
+        manifest_list.extend(ingester_manifests(&*storage, stream_name).await?);
+    }
+
+    Ok(manifest_list
+        .into_iter()
+        .unique_by(|item| item.manifest_path.clone())
+        .collect())
+}
+
+/// Manifests in the stream metadata every ingester keeps of the stream. A failed listing or an
+/// unreadable metadata object fails, rather than leaving out the manifests it has.
+pub async fn ingester_manifests(
+    storage: &dyn ObjectStorage,
+    stream_name: &str,
+) -> Result<Vec<ManifestItem>, ObjectStorageError> {
+    let path = RelativePathBuf::from_iter([stream_name, STREAM_ROOT_DIRECTORY]);
+    let mut ingester_metadata = storage.stream_objects(
+        Some(path),
+        Box::new(|file_name| {
+            file_name.starts_with(".ingester") && file_name.ends_with(STREAM_METADATA_FILE_NAME)
+        }),
+    );
+    let mut manifest_list = Vec::new();
+    while let Some((_, meta)) = ingester_metadata.try_next().await? {
+        let meta: ObjectStoreFormat = serde_json::from_slice(&meta)
+            .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+        manifest_list.extend(meta.snapshot.manifest_list);
+    }
+    Ok(manifest_list)
 }
 
 pub async fn remove_manifest_from_snapshot(
@@ -209,15 +554,16 @@ pub async fn remove_manifest_from_snapshot(
     stream_name: &str,
     dates: Vec<String>,
 ) -> Result<(), ObjectStorageError> {
-    // get current snapshot
-    let mut meta = storage.get_object_store_format(stream_name).await?;
-    let manifests = &mut meta.snapshot.manifest_list;
+    let _guard = COMMIT_LOCK.lock().await;
+    let mut snapshot = storage.get_object_store_format(stream_name).await?.snapshot;
+    let snapshot_id = snapshot.snapshot_id + 1;
 
     // Filter out items whose manifest_path contains any of the dates_to_delete
-    manifests.retain(|item| !dates.iter().any(|date| item.manifest_path.contains(date)));
+    snapshot
+        .manifest_list
+        .retain(|item| !dates.iter().any(|date| item.manifest_path.contains(date)));
 
-    storage.put_snapshot(stream_name, meta.snapshot).await?;
-    Ok(())
+    commit_snapshot(&*storage, stream_name, snapshot, snapshot_id).await
 }
 
 pub async fn get_first_event(
@@ -232,14 +578,7 @@ pub async fn get_first_event(
         return Err(ObjectStorageError::Custom("No manifest found".to_string()));
     }
 
-    let manifest = &manifests[0];
-
-    let path = partition_path(
-        stream_name,
-        manifest.time_lower_bound,
-        manifest.time_upper_bound,
-    );
-    let Some(manifest) = storage.get_manifest(&path).await? else {
+    let Some(manifest) = read_manifest(&manifests[0].manifest_path).await? else {
         return Err(ObjectStorageError::UnhandledError(
             "Manifest found in snapshot but not in object-storage"
                 .to_string()
@@ -270,3 +609,76 @@ fn partition_path(
         RelativePathBuf::from_iter([stream, &format!("date={}:{}", lower, upper)])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime};
+
+    use super::{manifests_to_merge, snapshot::ManifestItem};
+
+    fn day(day: u32) -> ManifestItem {
+        let date = NaiveDate::from_ymd_opt(2024, 1, day).unwrap();
+        ManifestItem {
+            manifest_path: String::new(),
+            time_lower_bound: date.and_time(NaiveTime::MIN).and_utc(),
+            time_upper_bound: date.and_hms_opt(23, 59, 59).unwrap().and_utc(),
+            column_stats: Default::default(),
+            num_files: 0,
+        }
+    }
+
+    // commits the given number of files on a day the way update_snapshot does and returns
+    // the number of files rewritten by merges
+    fn commit(manifest_list: &mut Vec<ManifestItem>, date: u32, num_files: usize) -> usize {
+        let lower_bound = day(date).time_lower_bound;
+        let merged = manifests_to_merge(manifest_list, lower_bound, num_files);
+        let mut total = num_files;
+        for pos in merged {
+            total += manifest_list.remove(pos).num_files;
+        }
+        manifest_list.push(ManifestItem {
+            num_files: total,
+            ..day(date)
+        });
+        total - num_files
+    }
+
+    #[test]
+    fn commits_of_a_day_are_merged_logarithmically() {
+        let mut manifest_list = vec![day(1)];
+        let mut rewritten = 0;
+        for _ in 0..1024 {
+            rewritten += commit(&mut manifest_list, 2, 1);
+            // a commit of another day is not merged into the manifests of the day
+            commit(&mut manifest_list, 3, 1);
+            let of_day = manifest_list
+                .iter()
+                .filter(|item| item.time_lower_bound == day(2).time_lower_bound)
+                .count();
+            assert!(of_day <= 11);
+        }
+        let of_day: Vec<_> = manifest_list
+            .iter()
+            .filter(|item| item.time_lower_bound == day(2).time_lower_bound)
+            .map(|item| item.num_files)
+            .collect();
+        assert_eq!(of_day, [1024]);
+        // a file is read again at most once per doubling of the manifest holding it
+        assert_eq!(rewritten, 1024 / 2 * 10);
+        // the manifest of an older version is never merged
+        assert_eq!(manifest_list[0], day(1));
+    }
+
+    #[test]
+    fn manifest_of_unknown_size_is_not_merged() {
+        let mut manifest_list = vec![day(2)];
+        assert_eq!(commit(&mut manifest_list, 2, 3), 0);
+        assert_eq!(commit(&mut manifest_list, 2, 3), 3);
+        assert_eq!(manifest_list.len(), 2);
+        assert_eq!(manifest_list[0], day(2));
+        assert_eq!(manifest_list[1].num_files, 6);
+        // a larger manifest is not merged into a smaller commit
+        assert_eq!(commit(&mut manifest_list, 2, 1), 0);
+        assert_eq!(manifest_list.len(), 3);
+    }
+}
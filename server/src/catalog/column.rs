@@ -22,25 +22,25 @@ use arrow_schema::DataType;
 use datafusion::scalar::ScalarValue;
 use parquet::file::statistics::Statistics;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct BoolType {
     pub min: bool,
     pub max: bool,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Float64Type {
     pub min: f64,
     pub max: f64,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Int64Type {
     pub min: i64,
     pub max: i64,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Utf8Type {
     pub min: String,
     pub max: String,
@@ -49,7 +49,7 @@ pub struct Utf8Type {
 // Typed statistics are typed variant of statistics
 // Currently all parquet types are casted down to these 4 types
 // Binary types are assumed to be of valid Utf8
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum TypedStatistics {
     Bool(BoolType),
     Int(Int64Type),
@@ -16,12 +16,15 @@
  *
  */
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::discriminant;
 
 use itertools::Itertools;
 use parquet::{file::reader::FileReader, format::SortingColumn};
+use sha2::{Digest, Sha256};
 
-use super::column::Column;
+use super::column::{Column, TypedStatistics};
+use crate::storage::staging::encryption;
 
 #[derive(
     Debug,
@@ -56,6 +59,9 @@ pub struct File {
     pub ingestion_size: u64,
     pub columns: Vec<Column>,
     pub sort_order_id: Vec<SortInfo>,
+    /// hex encoded SHA-256 of the file content, absent for files uploaded by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
 }
 
 /// A manifest file composed of multiple file entries.
@@ -86,21 +92,90 @@ impl Manifest {
             self.files.push(change)
         }
     }
+
+    /// Statistics of the columns across the files, for the columns every file has statistics of
+    pub fn column_stats(&self) -> BTreeMap<String, TypedStatistics> {
+        let mut files = self.files.iter();
+        let Some(first) = files.next() else {
+            return BTreeMap::new();
+        };
+        let mut stats: BTreeMap<String, TypedStatistics> = first
+            .columns
+            .iter()
+            .filter_map(|col| Some((col.name.clone(), col.stats.clone()?)))
+            .collect();
+        for file in files {
+            let columns: HashMap<&str, &TypedStatistics> = file
+                .columns
+                .iter()
+                .filter_map(|col| Some((col.name.as_str(), col.stats.as_ref()?)))
+                .collect();
+            stats = stats
+                .into_iter()
+                .filter_map(|(name, stats)| {
+                    let other = *columns.get(name.as_str())?;
+                    // a column whose type changed between files is not pruned on
+                    (discriminant(&stats) == discriminant(other))
+                        .then(|| (name, stats.update(other.clone())))
+                })
+                .collect();
+        }
+        stats
+    }
 }
 
 pub fn create_from_parquet_file(
     object_store_path: String,
     fs_file_path: &std::path::Path,
 ) -> anyhow::Result<File> {
+    // size and checksum are of the file as uploaded, decrypted if staging is encrypted
+    if encryption::enabled() {
+        let data = bytes::Bytes::from(encryption::read(fs_file_path)?);
+        return Ok(create_from_parquet_bytes(object_store_path, data)?);
+    }
+    create_from_plain_file(object_store_path, fs_file_path)
+}
+
+// the file is hashed and its metadata read without holding it in memory
+fn create_from_plain_file(
+    object_store_path: String,
+    fs_file_path: &std::path::Path,
+) -> anyhow::Result<File> {
+    let mut file = std::fs::File::open(fs_file_path)?;
+    let mut hasher = Sha256::new();
+    let file_size = std::io::copy(&mut file, &mut hasher)?;
+    Ok(create_from_parquet_reader(
+        object_store_path,
+        file,
+        file_size,
+        hex::encode(hasher.finalize()),
+    )?)
+}
+
+/// Manifest entry of a parquet file stored at `object_store_path` with the content `data`
+pub fn create_from_parquet_bytes(
+    object_store_path: String,
+    data: bytes::Bytes,
+) -> Result<File, parquet::errors::ParquetError> {
+    let file_size = data.len() as u64;
+    let checksum = checksum(&data);
+    create_from_parquet_reader(object_store_path, data, file_size, checksum)
+}
+
+fn create_from_parquet_reader<R: parquet::file::reader::ChunkReader + 'static>(
+    object_store_path: String,
+    reader: R,
+    file_size: u64,
+    checksum: String,
+) -> Result<File, parquet::errors::ParquetError> {
     let mut manifest_file = File {
         file_path: object_store_path,
+        file_size,
+        checksum: Some(checksum),
         ..File::default()
     };
 
-    let file = std::fs::File::open(fs_file_path)?;
-    manifest_file.file_size = file.metadata()?.len();
-
-    let file = parquet::file::serialized_reader::SerializedFileReader::new(file)?;
+    let file = parquet::file::serialized_reader::SerializedFileReader::new(reader)?;
     let file_meta = file.metadata().file_metadata();
     let row_groups = file.metadata().row_groups();
 
@@ -124,6 +199,10 @@ pub fn create_from_parquet_file(
     Ok(manifest_file)
 }
 
+pub fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
 fn sort_order(
     row_groups: &[parquet::file::metadata::RowGroupMetaData],
 ) -> Vec<Vec<(String, SortOrder)>> {
@@ -187,3 +266,45 @@ fn column_statistics(
     }
     columns
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow_array::{Int64Array, RecordBatch};
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+    use parquet::format::SortingColumn;
+
+    use super::{create_from_parquet_bytes, create_from_plain_file};
+
+    #[test]
+    fn file_entry_is_the_same_read_from_disk_or_memory() {
+        let batch = RecordBatch::try_from_iter([(
+            "p_timestamp",
+            Arc::new(Int64Array::from(vec![3, 2, 1])) as _,
+        )])
+        .unwrap();
+        let props = WriterProperties::builder()
+            .set_sorting_columns(Some(vec![SortingColumn::new(0, true, true)]))
+            .build();
+        let mut data = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut data, batch.schema(), Some(props)).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let path = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        std::fs::write(&path, &data).unwrap();
+        let from_disk = create_from_plain_file("app/a.parquet".to_string(), &path).unwrap();
+        let from_memory =
+            create_from_parquet_bytes("app/a.parquet".to_string(), data.into()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(from_disk.num_rows, 3);
+        assert!(from_disk.checksum.is_some());
+        assert_eq!(
+            serde_json::to_value(from_disk).unwrap(),
+            serde_json::to_value(from_memory).unwrap()
+        );
+    }
+}
@@ -16,16 +16,23 @@
  *
  */
 
-use std::ops::Bound;
+use std::{collections::BTreeMap, ops::Bound, str::FromStr};
 
 use chrono::{DateTime, Utc};
 
+use super::column::TypedStatistics;
 use crate::query::PartialTimeFilter;
 
-pub const CURRENT_SNAPSHOT_VERSION: &str = "v1";
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub const CURRENT_SNAPSHOT_VERSION: &str = "v2";
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Snapshot {
     pub version: String,
+    /// Sequence number of the commit which produced this snapshot, 0 for snapshots of older
+    /// versions which were changed in place
+    #[serde(default)]
+    pub snapshot_id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub committed_at: Option<DateTime<Utc>>,
     pub manifest_list: Vec<ManifestItem>,
 }
 
@@ -33,6 +40,8 @@ impl Default for Snapshot {
     fn default() -> Self {
         Self {
             version: CURRENT_SNAPSHOT_VERSION.to_string(),
+            snapshot_id: 0,
+            committed_at: None,
             manifest_list: Vec::default(),
         }
     }
@@ -71,9 +80,66 @@ impl super::Snapshot for Snapshot {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+/// Past state of a stream a query reads, a snapshot id or the time the state was current at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotAt {
+    Id(u64),
+    Time(DateTime<Utc>),
+}
+
+impl FromStr for SnapshotAt {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse() {
+            return Ok(Self::Id(id));
+        }
+        DateTime::parse_from_rfc3339(s)
+            .map(|time| Self::Time(time.into()))
+            .map_err(|_| format!("{s} is neither a snapshot id nor a rfc3339 timestamp"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ManifestItem {
     pub manifest_path: String,
     pub time_lower_bound: DateTime<Utc>,
     pub time_upper_bound: DateTime<Utc>,
+    /// Statistics of the columns across the files of the manifest, so that a query can skip
+    /// the manifest without reading it. Absent for manifests written by older versions.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub column_stats: BTreeMap<String, TypedStatistics>,
+    /// Number of files in the manifest, zero if unknown for manifests written by older versions
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub num_files: usize,
+}
+
+fn is_zero(num: &usize) -> bool {
+    *num == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Snapshot, SnapshotAt};
+
+    #[test]
+    fn unversioned_snapshot_is_read() {
+        let snapshot: Snapshot = serde_json::from_str(
+            r#"{"version":"v1","manifest_list":[{"manifest_path":"app/date=2024-01-01/manifest.json","time_lower_bound":"2024-01-01T00:00:00Z","time_upper_bound":"2024-01-01T23:59:59.999999999Z"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(snapshot.snapshot_id, 0);
+        assert!(snapshot.committed_at.is_none());
+        assert_eq!(snapshot.manifest_list.len(), 1);
+    }
+
+    #[test]
+    fn snapshot_at_parses_id_or_time() {
+        assert_eq!("42".parse(), Ok(SnapshotAt::Id(42)));
+        assert!(matches!(
+            "2024-01-01T10:00:00Z".parse(),
+            Ok(SnapshotAt::Time(_))
+        ));
+        assert!("yesterday".parse::<SnapshotAt>().is_err());
+    }
 }
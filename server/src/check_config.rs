@@ -0,0 +1,303 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! `parseable check-config`, run instead of the server to validate its configuration, e.g. in
+//! a CI pipeline. It takes the same args and env vars as the server.
+//!
+//! ```text
+//! parseable check-config --json s3-store
+//! ```
+
+use std::path::Path;
+
+use bytes::Bytes;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use relative_path::RelativePathBuf;
+
+use crate::option::{store_commands, Config};
+use crate::storage::{quirks, ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+
+pub const CHECK_CONFIG: &str = "check-config";
+
+// written, read and deleted again to probe the permissions on the object store
+const PROBE_FILE_NAME: &str = ".check-config.probe";
+
+pub fn command() -> Command {
+    Command::new(CHECK_CONFIG)
+        .about("Validate the configuration, object storage access and staging directory, without starting the server")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print the diagnostics as json"),
+        )
+        .subcommands(store_commands())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Diagnostic {
+    /// e.g. `storage.write`
+    pub check: &'static str,
+    pub status: Status,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(check: &'static str, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            check,
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn from_result<E: std::fmt::Display>(check: &'static str, result: Result<String, E>) -> Self {
+        match result {
+            Ok(message) => Self::new(check, Status::Ok, message),
+            Err(err) => Self::new(check, Status::Error, err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Report {
+    pub valid: bool,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        Self {
+            valid: diagnostics.iter().all(|d| d.status != Status::Error),
+            diagnostics,
+        }
+    }
+}
+
+/// Run `parseable check-config`, `matches` are those of the command. Fails if a check
+/// failed, so that the exit code tells whether the server can start with the configuration.
+pub async fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let (store, m) = matches.subcommand().expect("store is required");
+    let report = Report::new(check(store, m).await);
+
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for diagnostic in &report.diagnostics {
+            let status = match diagnostic.status {
+                Status::Ok => "ok",
+                Status::Warning => "warning",
+                Status::Error => "error",
+            };
+            println!(
+                "{status:<8} {:<18} {}",
+                diagnostic.check, diagnostic.message
+            );
+        }
+    }
+
+    if !report.valid {
+        anyhow::bail!("configuration is invalid");
+    }
+    Ok(())
+}
+
+async fn check(store: &str, m: &ArgMatches) -> Vec<Diagnostic> {
+    let config = match Config::from_store(store, m) {
+        Ok(config) => config,
+        Err(err) => return vec![Diagnostic::new("config", Status::Error, clap_message(&err))],
+    };
+
+    let mut diagnostics = vec![Diagnostic::new(
+        "config",
+        Status::Ok,
+        format!("{} mode on {}", config.parseable.mode.to_str(), store),
+    )];
+    if config.is_default_creds() {
+        diagnostics.push(Diagnostic::new(
+            "config.credentials",
+            Status::Warning,
+            "the default username and password are used",
+        ));
+    }
+
+    diagnostics.push(Diagnostic::from_result(
+        "staging",
+        check_writable(config.staging_dir()),
+    ));
+    if let Some(cache_dir) = config.cache_dir() {
+        diagnostics.push(Diagnostic::from_result("cache", check_writable(cache_dir)));
+    }
+
+    check_storage(&config, &mut diagnostics).await;
+    diagnostics
+}
+
+// first line of a clap error, without its `error: ` prefix
+fn clap_message(err: &clap::Error) -> String {
+    let message = err.to_string();
+    let line = message.lines().next().unwrap_or_default();
+    line.strip_prefix("error: ").unwrap_or(line).to_owned()
+}
+
+// create the directory if needed and write a file to it
+fn check_writable(dir: &Path) -> anyhow::Result<String> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(PROBE_FILE_NAME);
+    std::fs::write(&probe, b"probe")?;
+    std::fs::remove_file(&probe)?;
+    Ok(format!("{} is writable", dir.display()))
+}
+
+// probe listing, writing, reading and deleting objects, each needs a permission of its own
+async fn check_storage(config: &Config, diagnostics: &mut Vec<Diagnostic>) {
+    let endpoint = config.storage().get_endpoint();
+    let store = config.storage().get_object_store();
+
+    let listed = store
+        .list_streams()
+        .await
+        .map(|streams| format!("{} log streams in {endpoint}", streams.len()));
+    let reachable = listed.is_ok();
+    diagnostics.push(Diagnostic::from_result("storage.list", listed));
+    if !reachable {
+        return;
+    }
+
+    let probe = RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, PROBE_FILE_NAME]);
+    let content = Bytes::from_static(b"probe");
+    let written = store.put_object(&probe, content.clone()).await;
+    let writable = written.is_ok();
+    diagnostics.push(Diagnostic::from_result(
+        "storage.write",
+        written.map(|_| format!("wrote {probe}")),
+    ));
+    if !writable {
+        return;
+    }
+
+    let read = store.get_object(&probe).await.and_then(|bytes| {
+        if bytes == content {
+            Ok(format!("read {probe}"))
+        } else {
+            Err(ObjectStorageError::Custom(format!(
+                "{probe} was read with other content than it was written with"
+            )))
+        }
+    });
+    diagnostics.push(Diagnostic::from_result("storage.read", read));
+    diagnostics.push(Diagnostic::from_result(
+        "storage.delete",
+        store
+            .delete_object(&probe)
+            .await
+            .map(|_| format!("deleted {probe}")),
+    ));
+    check_quirks(config, diagnostics).await;
+
+    // the server refuses to start on data it does not recognize
+    diagnostics.push(Diagnostic::from_result(
+        "storage.data",
+        config
+            .validate_storage()
+            .await
+            .map(|_| "no stale data".to_owned()),
+    ));
+}
+
+// conformance of the store with the behavior of AWS S3 the server relies on
+async fn check_quirks(config: &Config, diagnostics: &mut Vec<Diagnostic>) {
+    let storage = config.storage();
+    let path = quirks::probe_path(&*storage.get_object_store());
+    let observed = match storage.get_datafusion_object_store() {
+        Ok(store) => quirks::probe(&*store, &path)
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let observed = match observed {
+        Ok(observed) => observed,
+        Err(err) => {
+            diagnostics.push(Diagnostic::new("storage.conformance", Status::Error, err));
+            return;
+        }
+    };
+
+    diagnostics.push(match observed.list_after_write_delay {
+        Some(delay) if delay.is_zero() => Diagnostic::new(
+            "storage.listing",
+            Status::Ok,
+            "new objects are listed right after they are written",
+        ),
+        Some(delay) => Diagnostic::new(
+            "storage.listing",
+            Status::Warning,
+            format!(
+                "a new object was listed {} after it was written",
+                humantime::format_duration(delay)
+            ),
+        ),
+        None => Diagnostic::new(
+            "storage.listing",
+            Status::Warning,
+            "a new object was not listed within 10s of being written",
+        ),
+    });
+
+    let quirks = storage.quirks().refine(&observed);
+    if !quirks.list_after_write_delay.is_zero() {
+        diagnostics.push(Diagnostic::new(
+            "storage.quirks",
+            Status::Ok,
+            format!(
+                "listings are assumed to lag up to {}",
+                humantime::format_duration(quirks.list_after_write_delay)
+            ),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Report, Status};
+
+    #[test]
+    fn report_is_valid_with_warnings() {
+        let report = Report::new(vec![
+            Diagnostic::new("config", Status::Ok, "All mode on s3-store"),
+            Diagnostic::new("config.credentials", Status::Warning, "default"),
+        ]);
+        assert!(report.valid);
+
+        let report = Report::new(vec![Diagnostic::new(
+            "staging",
+            Status::Error,
+            "Permission denied",
+        )]);
+        assert!(!report.valid);
+    }
+}
@@ -16,14 +16,18 @@
  *
  */
 
-use clap::{value_parser, Arg, ArgGroup, Command, FromArgMatches};
+use clap::{error::ErrorKind, value_parser, Arg, ArgGroup, Command, FromArgMatches};
 use std::path::PathBuf;
+use std::time::Duration;
 
 use url::Url;
 
 use crate::{
     oidc::{self, OpenidConfig},
-    option::{validation, Compression, Mode},
+    option::{validation, Compression, ConversionPriority, Mode},
+    s3_import::ImportMapping,
+    storage::staging::encryption::EncryptionKey,
+    tls,
 };
 
 #[derive(Debug, Default)]
@@ -34,6 +38,12 @@ pub struct Cli {
     /// The location of TLS Private Key file
     pub tls_key_path: Option<PathBuf>,
 
+    /// Cipher suites used for TLS, all supported ones if empty
+    pub tls_ciphers: Vec<String>,
+
+    /// Restrict TLS to FIPS approved cipher suites and key exchange groups
+    pub fips_mode: bool,
+
     /// The address on which the http server will listen.
     pub address: String,
 
@@ -81,9 +91,175 @@ pub struct Cli {
     /// Query memory limit in bytes
     pub query_memory_pool_size: Option<usize>,
 
+    /// Memory limit in bytes for a single query
+    pub query_memory_limit_per_query: Option<usize>,
+
+    /// Spill sorts and aggregations to disk when the memory limit is reached
+    pub query_spill_to_disk: bool,
+
+    /// Number of partitions a query scan is split into, 0 means number of cpus
+    pub query_target_partitions: usize,
+
+    /// Minimum bytes read by a single scan partition, small files are coalesced up to this size
+    pub query_min_scan_group_size: u64,
+
+    /// Maximum number of object store requests a single query can have in flight, 0 means no limit
+    pub query_max_object_store_requests: usize,
+
+    /// Byte ranges of a parquet file closer than this are fetched in a single request
+    pub query_read_coalesce_size: u64,
+
+    /// Bytes read from the end of a parquet file to fetch the footer in a single request
+    pub query_metadata_prefetch_size: u64,
+
+    /// Maximum number of queries running at once on this server, 0 means no limit
+    pub query_max_concurrent: usize,
+
+    /// Maximum number of queries a single user can run at once, 0 means no limit
+    pub query_max_concurrent_per_user: usize,
+
+    /// Maximum number of queries that can run at once on a single stream, 0 means no limit
+    pub query_max_concurrent_per_stream: usize,
+
+    /// Time a query waits in queue for a free slot before it is rejected
+    pub query_queue_timeout: Duration,
+
+    /// Serve the cpu profile and heap statistics endpoints
+    pub profiling: bool,
+
+    /// Queries running longer than this are written to the slow query stream
+    pub query_slow_threshold: Duration,
+
+    /// Snapshots of a stream older than this are expired, older states can no longer be queried
+    pub snapshot_retention: Duration,
+
     /// Parquet compression algorithm
     pub parquet_compression: Compression,
 
+    /// Write stream metadata and manifests compressed with zstd
+    pub compress_metadata: bool,
+
+    /// Parse ingested json with simd-json instead of serde_json
+    pub ingest_simd_json: bool,
+
+    /// Request bodies larger than this are spooled to disk and parsed incrementally
+    pub ingest_spool_threshold: u64,
+
+    /// Maximum size of an ingest request body
+    pub ingest_max_payload_size: u64,
+
+    /// Maximum size of a single ingested event, serialized as json
+    pub ingest_max_event_size: u64,
+
+    /// Maximum number of events in an ingest request
+    pub ingest_max_batch_events: usize,
+
+    /// Fewest rows of a stream buffered in memory before they are written to staging
+    pub staging_batch_min_rows: usize,
+
+    /// Most rows of a stream buffered in memory before they are written to staging
+    pub staging_batch_max_rows: usize,
+
+    /// Time events are buffered for at the current rate of their stream, and at most
+    pub staging_batch_latency: Duration,
+
+    /// Key staging files on the local disk are encrypted with
+    pub staging_encryption_key: Option<EncryptionKey>,
+
+    /// Number of streams converted from staging to parquet in parallel
+    pub conversion_concurrency: usize,
+
+    /// Priority of threads converting staging files to parquet
+    pub conversion_priority: ConversionPriority,
+
+    /// Number of parquet files uploaded to object storage in parallel
+    pub upload_concurrency: usize,
+
+    /// Average upload bandwidth in bytes per second, unlimited if not set
+    pub upload_bandwidth_limit: Option<u64>,
+
+    /// Streams searched by the correlation api, all streams if empty
+    pub correlation_streams: Vec<String>,
+
+    /// Column holding the id looked up by the correlation api
+    pub correlation_id_column: String,
+
+    /// SMTP server scheduled reports are sent through, reports are disabled if not set
+    pub smtp_host: Option<String>,
+
+    /// SMTP server port
+    pub smtp_port: u16,
+
+    /// SMTP username
+    pub smtp_username: Option<String>,
+
+    /// SMTP password
+    pub smtp_password: Option<String>,
+
+    /// Sender address of scheduled reports
+    pub smtp_from: Option<String>,
+
+    /// File with the settings applied on a configuration reload
+    pub config_file: Option<PathBuf>,
+
+    /// URLs stream lifecycle events are posted to
+    pub webhook_urls: Vec<Url>,
+
+    /// Key the webhook payloads are signed with
+    pub webhook_secret: Option<String>,
+
+    /// Access key id AWS services sign ingest requests with
+    pub aws_ingest_access_key_id: Option<String>,
+
+    /// Secret access key AWS services sign ingest requests with
+    pub aws_ingest_secret_access_key: Option<String>,
+
+    /// Access key Kinesis Firehose sends with deliveries to the ingest endpoint
+    pub aws_firehose_access_key: Option<String>,
+
+    /// SQS queue S3 event notifications of objects to import are read from
+    pub s3_import_queue_url: Option<Url>,
+
+    /// Streams and formats objects are imported as, by their bucket and prefix
+    pub s3_import_mappings: Vec<ImportMapping>,
+
+    /// Time given on shutdown to finish requests and flush staging
+    pub drain_timeout: Duration,
+
+    /// Ingesters without a heartbeat for this long are flagged as stale
+    pub ingester_stale_after: Duration,
+
+    /// Remove the metadata of stale ingesters
+    pub ingester_auto_remove: bool,
+
+    /// Number of peer ingesters holding a copy of events till they are uploaded, 0 disables replication
+    pub replication_factor: usize,
+
+    /// Key for the tokens nodes of a cluster authenticate their requests to each other with
+    pub cluster_secret: Option<String>,
+
+    /// Time a token issued to another node of the cluster is valid for
+    pub cluster_token_ttl: Duration,
+
+    /// Times a failed request to another node of the cluster is retried
+    pub cluster_request_retries: u32,
+
+    /// Time a single request to another node of the cluster may take
+    pub cluster_request_timeout: Duration,
+
+    /// Whether the metrics endpoint requires the scrape token or user credentials
+    pub metrics_auth: bool,
+
+    /// Bearer token for scraping the metrics endpoint without user credentials
+    pub metrics_token: Option<String>,
+
+    /// Streams given their own label in per stream metrics, and users in query metrics, the
+    /// rest share one label
+    pub metrics_stream_limit: usize,
+
+    /// Buckets other than the bucket of Parseable external tables may be read from
+    pub external_table_buckets: Vec<String>,
+
     /// Mode of operation
     pub mode: Mode,
 
@@ -95,6 +271,8 @@ impl Cli {
     // identifiers for arguments
     pub const TLS_CERT: &'static str = "tls-cert-path";
     pub const TLS_KEY: &'static str = "tls-key-path";
+    pub const TLS_CIPHERS: &'static str = "tls-ciphers";
+    pub const FIPS_MODE: &'static str = "fips-mode";
     pub const ADDRESS: &'static str = "address";
     pub const DOMAIN_URI: &'static str = "origin";
     pub const STAGING: &'static str = "local-staging-path";
@@ -112,8 +290,64 @@ impl Cli {
     pub const LIVETAIL_CAPACITY: &'static str = "livetail-capacity";
     // todo : what should this flag be
     pub const QUERY_MEM_POOL_SIZE: &'static str = "query-mempool-size";
+    pub const QUERY_MEM_LIMIT_PER_QUERY: &'static str = "query-mempool-size-per-query";
+    pub const QUERY_SPILL_TO_DISK: &'static str = "query-spill-to-disk";
+    pub const QUERY_TARGET_PARTITIONS: &'static str = "query-target-partitions";
+    pub const QUERY_MIN_SCAN_GROUP_SIZE: &'static str = "query-min-scan-group-size";
+    pub const QUERY_MAX_OBJECT_STORE_REQUESTS: &'static str = "query-max-object-store-requests";
+    pub const QUERY_READ_COALESCE_SIZE: &'static str = "query-read-coalesce-size";
+    pub const QUERY_METADATA_PREFETCH_SIZE: &'static str = "query-metadata-prefetch-size";
+    pub const QUERY_MAX_CONCURRENT: &'static str = "query-max-concurrent";
+    pub const QUERY_MAX_CONCURRENT_PER_USER: &'static str = "query-max-concurrent-per-user";
+    pub const QUERY_MAX_CONCURRENT_PER_STREAM: &'static str = "query-max-concurrent-per-stream";
+    pub const QUERY_QUEUE_TIMEOUT: &'static str = "query-queue-timeout";
+    pub const QUERY_SLOW_THRESHOLD: &'static str = "query-slow-threshold";
+    pub const SNAPSHOT_RETENTION: &'static str = "snapshot-retention";
+    pub const PROFILING: &'static str = "profiling";
     pub const ROW_GROUP_SIZE: &'static str = "row-group-size";
     pub const PARQUET_COMPRESSION_ALGO: &'static str = "compression-algo";
+    pub const COMPRESS_METADATA: &'static str = "compress-metadata";
+    pub const INGEST_SIMD_JSON: &'static str = "ingest-simd-json";
+    pub const INGEST_SPOOL_THRESHOLD: &'static str = "ingest-spool-threshold";
+    pub const INGEST_MAX_PAYLOAD_SIZE: &'static str = "ingest-max-payload-size";
+    pub const INGEST_MAX_EVENT_SIZE: &'static str = "ingest-max-event-size";
+    pub const INGEST_MAX_BATCH_EVENTS: &'static str = "ingest-max-batch-events";
+    pub const STAGING_BATCH_MIN_ROWS: &'static str = "staging-batch-min-rows";
+    pub const STAGING_BATCH_MAX_ROWS: &'static str = "staging-batch-max-rows";
+    pub const STAGING_BATCH_LATENCY: &'static str = "staging-batch-latency";
+    pub const STAGING_ENCRYPTION_KEY: &'static str = "staging-encryption-key";
+    pub const STAGING_ENCRYPTION_KEY_FILE: &'static str = "staging-encryption-key-file";
+    pub const CONVERSION_CONCURRENCY: &'static str = "conversion-concurrency";
+    pub const CONVERSION_PRIORITY: &'static str = "conversion-priority";
+    pub const UPLOAD_CONCURRENCY: &'static str = "upload-concurrency";
+    pub const UPLOAD_BANDWIDTH_LIMIT: &'static str = "upload-bandwidth-limit";
+    pub const CORRELATION_STREAMS: &'static str = "correlation-streams";
+    pub const CORRELATION_ID_COLUMN: &'static str = "correlation-id-column";
+    pub const SMTP_HOST: &'static str = "smtp-host";
+    pub const SMTP_PORT: &'static str = "smtp-port";
+    pub const SMTP_USERNAME: &'static str = "smtp-username";
+    pub const SMTP_PASSWORD: &'static str = "smtp-password";
+    pub const SMTP_FROM: &'static str = "smtp-from";
+    pub const CONFIG_FILE: &'static str = "config-file";
+    pub const WEBHOOK_URLS: &'static str = "webhook-urls";
+    pub const WEBHOOK_SECRET: &'static str = "webhook-secret";
+    pub const AWS_INGEST_ACCESS_KEY_ID: &'static str = "aws-ingest-access-key-id";
+    pub const AWS_INGEST_SECRET_ACCESS_KEY: &'static str = "aws-ingest-secret-access-key";
+    pub const AWS_FIREHOSE_ACCESS_KEY: &'static str = "aws-firehose-access-key";
+    pub const S3_IMPORT_QUEUE_URL: &'static str = "s3-import-queue-url";
+    pub const S3_IMPORT_MAPPINGS: &'static str = "s3-import-mappings";
+    pub const DRAIN_TIMEOUT: &'static str = "drain-timeout";
+    pub const INGESTER_STALE_AFTER: &'static str = "ingester-stale-after";
+    pub const INGESTER_AUTO_REMOVE: &'static str = "ingester-auto-remove";
+    pub const REPLICATION_FACTOR: &'static str = "replication-factor";
+    pub const CLUSTER_SECRET: &'static str = "cluster-secret";
+    pub const CLUSTER_TOKEN_TTL: &'static str = "cluster-token-ttl";
+    pub const CLUSTER_REQUEST_RETRIES: &'static str = "cluster-request-retries";
+    pub const CLUSTER_REQUEST_TIMEOUT: &'static str = "cluster-request-timeout";
+    pub const METRICS_AUTH: &'static str = "metrics-auth";
+    pub const METRICS_TOKEN: &'static str = "metrics-token";
+    pub const METRICS_STREAM_LIMIT: &'static str = "metrics-stream-limit";
+    pub const EXTERNAL_TABLE_BUCKETS: &'static str = "external-table-buckets";
     pub const MODE: &'static str = "mode";
     pub const INGESTOR_URL: &'static str = "ingestor-url";
     pub const DEFAULT_USERNAME: &'static str = "admin";
@@ -123,6 +357,18 @@ impl Cli {
         self.local_staging_path.join(stream_name)
     }
 
+    // address the oidc provider redirects back to
+    pub fn openid_origin(&self) -> oidc::Origin {
+        if let Some(url) = self.domain_address.clone() {
+            oidc::Origin::Production(url)
+        } else {
+            oidc::Origin::Local {
+                socket_addr: self.address.clone(),
+                https: self.tls_cert_path.is_some() && self.tls_key_path.is_some(),
+            }
+        }
+    }
+
     pub fn get_scheme(&self) -> String {
         if self.tls_cert_path.is_some() && self.tls_key_path.is_some() {
             return "https".to_string();
@@ -148,6 +394,26 @@ impl Cli {
                     .value_parser(validation::file_path)
                     .help("Local path on this device where private key file is located. Required to enable TLS"),
             )
+            .arg(
+                Arg::new(Self::TLS_CIPHERS)
+                    .long(Self::TLS_CIPHERS)
+                    .env("P_TLS_CIPHERS")
+                    .value_name("SUITE,..")
+                    .required(false)
+                    .value_delimiter(',')
+                    .value_parser(validation::cipher_suite)
+                    .help("TLS cipher suites of the server and of outgoing requests, e.g. TLS13_AES_256_GCM_SHA384. All supported suites if not set"),
+            )
+            .arg(
+                Arg::new(Self::FIPS_MODE)
+                    .long(Self::FIPS_MODE)
+                    .env("P_FIPS_MODE")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(value_parser!(bool))
+                    .help("Only use FIPS approved TLS cipher suites and key exchange groups for the server and outgoing requests, object storage has to be reached over https"),
+            )
             .arg(
                 Arg::new(Self::ADDRESS)
                     .long(Self::ADDRESS)
@@ -294,6 +560,145 @@ impl Cli {
                     .value_parser(value_parser!(u8))
                     .help("Set a fixed memory limit for query"),
             )
+            .arg(
+                Arg::new(Self::QUERY_MEM_LIMIT_PER_QUERY)
+                    .long(Self::QUERY_MEM_LIMIT_PER_QUERY)
+                    .env("P_QUERY_MEMORY_LIMIT_PER_QUERY")
+                    .value_name("Gib")
+                    .required(false)
+                    .value_parser(value_parser!(u8))
+                    .help("Set a fixed memory limit for a single query"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_SPILL_TO_DISK)
+                    .long(Self::QUERY_SPILL_TO_DISK)
+                    .env("P_QUERY_SPILL_TO_DISK")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("true")
+                    .value_parser(value_parser!(bool))
+                    .help("Spill sorts and aggregations to disk when query memory limit is reached"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_TARGET_PARTITIONS)
+                    .long(Self::QUERY_TARGET_PARTITIONS)
+                    .env("P_QUERY_TARGET_PARTITIONS")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("Number of partitions to split a query scan into, 0 uses the number of cpus"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_MIN_SCAN_GROUP_SIZE)
+                    .long(Self::QUERY_MIN_SCAN_GROUP_SIZE)
+                    .env("P_QUERY_MIN_SCAN_GROUP_SIZE")
+                    .value_name("size")
+                    .required(false)
+                    .default_value("64MiB")
+                    .value_parser(validation::human_size)
+                    .help("Minimum size of data read by a scan partition, smaller files are grouped together (e.g 64MiB)"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_MAX_OBJECT_STORE_REQUESTS)
+                    .long(Self::QUERY_MAX_OBJECT_STORE_REQUESTS)
+                    .env("P_QUERY_MAX_OBJECT_STORE_REQUESTS")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("32")
+                    .value_parser(value_parser!(usize))
+                    .help("Maximum number of object store requests a query can have in flight, 0 means no limit"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_READ_COALESCE_SIZE)
+                    .long(Self::QUERY_READ_COALESCE_SIZE)
+                    .env("P_QUERY_READ_COALESCE_SIZE")
+                    .value_name("size")
+                    .required(false)
+                    .default_value("1MiB")
+                    .value_parser(validation::human_size)
+                    .help("Byte ranges closer than this are merged into a single object store request (e.g 1MiB)"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_METADATA_PREFETCH_SIZE)
+                    .long(Self::QUERY_METADATA_PREFETCH_SIZE)
+                    .env("P_QUERY_METADATA_PREFETCH_SIZE")
+                    .value_name("size")
+                    .required(false)
+                    .default_value("512KiB")
+                    .value_parser(validation::human_size)
+                    .help("Bytes prefetched from the end of a parquet file to read its footer in a single request (e.g 512KiB)"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_MAX_CONCURRENT)
+                    .long(Self::QUERY_MAX_CONCURRENT)
+                    .env("P_QUERY_MAX_CONCURRENT")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("Maximum number of concurrent queries on this server, 0 disables the limit"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_MAX_CONCURRENT_PER_USER)
+                    .long(Self::QUERY_MAX_CONCURRENT_PER_USER)
+                    .env("P_QUERY_MAX_CONCURRENT_PER_USER")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("Maximum number of concurrent queries per user, 0 disables the limit"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_MAX_CONCURRENT_PER_STREAM)
+                    .long(Self::QUERY_MAX_CONCURRENT_PER_STREAM)
+                    .env("P_QUERY_MAX_CONCURRENT_PER_STREAM")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("Maximum number of concurrent queries per stream, 0 disables the limit"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_QUEUE_TIMEOUT)
+                    .long(Self::QUERY_QUEUE_TIMEOUT)
+                    .env("P_QUERY_QUEUE_TIMEOUT")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("30s")
+                    .value_parser(validation::duration)
+                    .help("Time a query waits for a free slot before it is rejected (e.g 30s, 1m)"),
+            )
+            .arg(
+                Arg::new(Self::QUERY_SLOW_THRESHOLD)
+                    .long(Self::QUERY_SLOW_THRESHOLD)
+                    .env("P_QUERY_SLOW_THRESHOLD")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("10s")
+                    .value_parser(validation::duration)
+                    .help("Queries running longer than this are logged to the pslowquery stream (e.g 10s, 1m)"),
+            )
+            .arg(
+                Arg::new(Self::SNAPSHOT_RETENTION)
+                    .long(Self::SNAPSHOT_RETENTION)
+                    .env("P_SNAPSHOT_RETENTION")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("7d")
+                    .value_parser(validation::duration)
+                    .help("Duration for which older snapshots of a stream are kept (e.g 1d, 7d)"),
+            )
+            .arg(
+                Arg::new(Self::PROFILING)
+                    .long(Self::PROFILING)
+                    .env("P_PROFILING")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(value_parser!(bool))
+                    .help("Serve cpu profiles and heap statistics under /debug/pprof, needs a build with the profiling feature"),
+            )
             .arg(
                 Arg::new(Self::ROW_GROUP_SIZE)
                     .long(Self::ROW_GROUP_SIZE)
@@ -341,7 +746,410 @@ impl Cli {
                         "lz4",
                         "zstd"])
                     .help("Parquet compression algorithm"),
-            ).group(
+            )
+            .arg(
+                Arg::new(Self::COMPRESS_METADATA)
+                    .long(Self::COMPRESS_METADATA)
+                    .env("P_COMPRESS_METADATA")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(value_parser!(bool))
+                    .help("Write stream metadata and manifests compressed with zstd, older versions can not read them"),
+            )
+            .arg(
+                Arg::new(Self::INGEST_SIMD_JSON)
+                    .long(Self::INGEST_SIMD_JSON)
+                    .env("P_INGEST_SIMD_JSON")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(value_parser!(bool))
+                    .help("Parse ingested json with simd-json, faster on CPUs with SIMD support"),
+            )
+            .arg(
+                Arg::new(Self::INGEST_SPOOL_THRESHOLD)
+                    .long(Self::INGEST_SPOOL_THRESHOLD)
+                    .env("P_INGEST_SPOOL_THRESHOLD")
+                    .value_name("size")
+                    .required(false)
+                    .default_value("4MiB")
+                    .value_parser(validation::human_size)
+                    .help("Request bodies larger than this are written to disk and parsed incrementally (e.g 4MiB)"),
+            )
+            .arg(
+                Arg::new(Self::INGEST_MAX_PAYLOAD_SIZE)
+                    .long(Self::INGEST_MAX_PAYLOAD_SIZE)
+                    .env("P_INGEST_MAX_PAYLOAD_SIZE")
+                    .value_name("size")
+                    .required(false)
+                    .default_value("10MiB")
+                    .value_parser(validation::human_size)
+                    .help("Maximum size of an ingest request body (e.g 10MiB)"),
+            )
+            .arg(
+                Arg::new(Self::INGEST_MAX_EVENT_SIZE)
+                    .long(Self::INGEST_MAX_EVENT_SIZE)
+                    .env("P_INGEST_MAX_EVENT_SIZE")
+                    .value_name("size")
+                    .required(false)
+                    .default_value("1MiB")
+                    .value_parser(validation::human_size)
+                    .help("Maximum size of a single event, larger events are rejected (e.g 1MiB)"),
+            )
+            .arg(
+                Arg::new(Self::INGEST_MAX_BATCH_EVENTS)
+                    .long(Self::INGEST_MAX_BATCH_EVENTS)
+                    .env("P_INGEST_MAX_BATCH_EVENTS")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("100000")
+                    .value_parser(value_parser!(usize))
+                    .help("Maximum number of events in an ingest request"),
+            )
+            .arg(
+                Arg::new(Self::STAGING_BATCH_MIN_ROWS)
+                    .long(Self::STAGING_BATCH_MIN_ROWS)
+                    .env("P_STAGING_BATCH_MIN_ROWS")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("1")
+                    .value_parser(value_parser!(usize))
+                    .help("Fewest rows of a stream buffered in memory before they are written to staging"),
+            )
+            .arg(
+                Arg::new(Self::STAGING_BATCH_MAX_ROWS)
+                    .long(Self::STAGING_BATCH_MAX_ROWS)
+                    .env("P_STAGING_BATCH_MAX_ROWS")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("16384")
+                    .value_parser(value_parser!(usize))
+                    .help("Most rows of a stream buffered in memory before they are written to staging"),
+            )
+            .arg(
+                Arg::new(Self::STAGING_BATCH_LATENCY)
+                    .long(Self::STAGING_BATCH_LATENCY)
+                    .env("P_STAGING_BATCH_LATENCY")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("1s")
+                    .value_parser(validation::duration)
+                    .help("Events are buffered for about this long at the current rate of their stream before they are written to staging, and never longer (e.g 500ms, 2s)"),
+            )
+            .arg(
+                Arg::new(Self::STAGING_ENCRYPTION_KEY)
+                    .long(Self::STAGING_ENCRYPTION_KEY)
+                    .env("P_STAGING_ENCRYPTION_KEY")
+                    .value_name("KEY")
+                    .required(false)
+                    .value_parser(validation::encryption_key)
+                    .help("256 bit key, in hex or base64, to encrypt staging files on the local disk with"),
+            )
+            .arg(
+                Arg::new(Self::STAGING_ENCRYPTION_KEY_FILE)
+                    .long(Self::STAGING_ENCRYPTION_KEY_FILE)
+                    .env("P_STAGING_ENCRYPTION_KEY_FILE")
+                    .value_name("PATH")
+                    .required(false)
+                    .conflicts_with(Self::STAGING_ENCRYPTION_KEY)
+                    .value_parser(validation::encryption_key_file)
+                    .help("File holding the key to encrypt staging files with, such as a secret mounted from a KMS"),
+            )
+            .arg(
+                Arg::new(Self::CONVERSION_CONCURRENCY)
+                    .long(Self::CONVERSION_CONCURRENCY)
+                    .env("P_CONVERSION_CONCURRENCY")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("1")
+                    .value_parser(value_parser!(usize))
+                    .help("Number of streams converted from staging to parquet in parallel"),
+            )
+            .arg(
+                Arg::new(Self::CONVERSION_PRIORITY)
+                    .long(Self::CONVERSION_PRIORITY)
+                    .env("P_CONVERSION_PRIORITY")
+                    .value_name("[LOW, NORMAL, HIGH]")
+                    .required(false)
+                    .default_value("normal")
+                    .value_parser(["low", "normal", "high"])
+                    .help("CPU priority of threads converting staging files to parquet"),
+            )
+            .arg(
+                Arg::new(Self::UPLOAD_CONCURRENCY)
+                    .long(Self::UPLOAD_CONCURRENCY)
+                    .env("P_UPLOAD_CONCURRENCY")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("1")
+                    .value_parser(value_parser!(usize))
+                    .help("Number of parquet files uploaded to object storage in parallel"),
+            )
+            .arg(
+                Arg::new(Self::UPLOAD_BANDWIDTH_LIMIT)
+                    .long(Self::UPLOAD_BANDWIDTH_LIMIT)
+                    .env("P_UPLOAD_BANDWIDTH_LIMIT")
+                    .value_name("size per second")
+                    .required(false)
+                    .value_parser(validation::human_size)
+                    .help("Average upload bandwidth to object storage per second, e.g. 20MiB. Unlimited if not set"),
+            )
+            .arg(
+                Arg::new(Self::CORRELATION_STREAMS)
+                    .long(Self::CORRELATION_STREAMS)
+                    .env("P_CORRELATION_STREAMS")
+                    .value_name("STREAM,..")
+                    .required(false)
+                    .value_delimiter(',')
+                    .help("Streams searched by the correlation api. All streams with the id column if not set"),
+            )
+            .arg(
+                Arg::new(Self::CORRELATION_ID_COLUMN)
+                    .long(Self::CORRELATION_ID_COLUMN)
+                    .env("P_CORRELATION_ID_COLUMN")
+                    .value_name("COLUMN")
+                    .required(false)
+                    .default_value("trace_id")
+                    .help("Column holding the id looked up by the correlation api"),
+            )
+            .arg(
+                Arg::new(Self::SMTP_HOST)
+                    .long(Self::SMTP_HOST)
+                    .env("P_SMTP_HOST")
+                    .value_name("HOST")
+                    .required(false)
+                    .help("SMTP server scheduled reports are sent through, reports are disabled if not set"),
+            )
+            .arg(
+                Arg::new(Self::SMTP_PORT)
+                    .long(Self::SMTP_PORT)
+                    .env("P_SMTP_PORT")
+                    .value_name("PORT")
+                    .default_value("587")
+                    .required(false)
+                    .value_parser(value_parser!(u16))
+                    .help("SMTP server port, connections are upgraded with STARTTLS"),
+            )
+            .arg(
+                Arg::new(Self::SMTP_USERNAME)
+                    .long(Self::SMTP_USERNAME)
+                    .env("P_SMTP_USERNAME")
+                    .value_name("STRING")
+                    .required(false)
+                    .help("Username for the SMTP server"),
+            )
+            .arg(
+                Arg::new(Self::SMTP_PASSWORD)
+                    .long(Self::SMTP_PASSWORD)
+                    .env("P_SMTP_PASSWORD")
+                    .value_name("STRING")
+                    .required(false)
+                    .help("Password for the SMTP server"),
+            )
+            .arg(
+                Arg::new(Self::SMTP_FROM)
+                    .long(Self::SMTP_FROM)
+                    .env("P_SMTP_FROM")
+                    .value_name("ADDRESS")
+                    .required(false)
+                    .help("Sender address of scheduled reports"),
+            )
+            .arg(
+                Arg::new(Self::CONFIG_FILE)
+                    .long(Self::CONFIG_FILE)
+                    .env("P_CONFIG_FILE")
+                    .value_name("PATH")
+                    .required(false)
+                    .value_parser(validation::file_path)
+                    .help("JSON file with log level, query limit and OIDC settings, applied on SIGHUP or a reload request"),
+            )
+            .arg(
+                Arg::new(Self::WEBHOOK_URLS)
+                    .long(Self::WEBHOOK_URLS)
+                    .env("P_WEBHOOK_URLS")
+                    .value_name("URL,..")
+                    .required(false)
+                    .value_delimiter(',')
+                    .value_parser(validation::url)
+                    .help("URLs stream lifecycle events are posted to"),
+            )
+            .arg(
+                Arg::new(Self::WEBHOOK_SECRET)
+                    .long(Self::WEBHOOK_SECRET)
+                    .env("P_WEBHOOK_SECRET")
+                    .value_name("STRING")
+                    .required(false)
+                    .help("Key for the HMAC-SHA256 signature sent with webhook payloads"),
+            )
+            .arg(
+                Arg::new(Self::AWS_INGEST_ACCESS_KEY_ID)
+                    .long(Self::AWS_INGEST_ACCESS_KEY_ID)
+                    .env("P_AWS_INGEST_ACCESS_KEY_ID")
+                    .value_name("STRING")
+                    .required(false)
+                    .requires(Self::AWS_INGEST_SECRET_ACCESS_KEY)
+                    .help("Access key id AWS services sign requests to /ingest/aws with"),
+            )
+            .arg(
+                Arg::new(Self::AWS_INGEST_SECRET_ACCESS_KEY)
+                    .long(Self::AWS_INGEST_SECRET_ACCESS_KEY)
+                    .env("P_AWS_INGEST_SECRET_ACCESS_KEY")
+                    .value_name("STRING")
+                    .required(false)
+                    .requires(Self::AWS_INGEST_ACCESS_KEY_ID)
+                    .help("Secret access key AWS services sign requests to /ingest/aws with"),
+            )
+            .arg(
+                Arg::new(Self::AWS_FIREHOSE_ACCESS_KEY)
+                    .long(Self::AWS_FIREHOSE_ACCESS_KEY)
+                    .env("P_AWS_FIREHOSE_ACCESS_KEY")
+                    .value_name("STRING")
+                    .required(false)
+                    .help("Access key of Kinesis Firehose HTTP endpoint deliveries to /ingest/aws"),
+            )
+            .arg(
+                Arg::new(Self::S3_IMPORT_QUEUE_URL)
+                    .long(Self::S3_IMPORT_QUEUE_URL)
+                    .env("P_S3_IMPORT_QUEUE_URL")
+                    .value_name("URL")
+                    .required(false)
+                    .requires(Self::S3_IMPORT_MAPPINGS)
+                    .value_parser(validation::url)
+                    .help("SQS queue receiving S3 event notifications of log files to import"),
+            )
+            .arg(
+                Arg::new(Self::S3_IMPORT_MAPPINGS)
+                    .long(Self::S3_IMPORT_MAPPINGS)
+                    .env("P_S3_IMPORT_MAPPINGS")
+                    .value_name("s3://BUCKET/PREFIX=STREAM:FORMAT,..")
+                    .required(false)
+                    .value_delimiter(',')
+                    .value_parser(validation::import_mapping)
+                    .help("Stream and format (alb, cloudtrail, vpcflow or json) of the imported objects under a prefix"),
+            )
+            .arg(
+                Arg::new(Self::DRAIN_TIMEOUT)
+                    .long(Self::DRAIN_TIMEOUT)
+                    .env("P_DRAIN_TIMEOUT")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("60s")
+                    .value_parser(validation::duration)
+                    .help("Time given on SIGTERM to finish requests in flight and upload staging data before exiting (e.g 60s, 2m)"),
+            )
+            .arg(
+                Arg::new(Self::INGESTER_STALE_AFTER)
+                    .long(Self::INGESTER_STALE_AFTER)
+                    .env("P_INGESTER_STALE_AFTER")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("10m")
+                    .value_parser(validation::duration)
+                    .help("Ingesters without a heartbeat for this long are flagged as stale by the query server (e.g 10m, 1h)"),
+            )
+            .arg(
+                Arg::new(Self::INGESTER_AUTO_REMOVE)
+                    .long(Self::INGESTER_AUTO_REMOVE)
+                    .env("P_INGESTER_AUTO_REMOVE")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(value_parser!(bool))
+                    .help("Remove the metadata of stale ingesters which are not reachable"),
+            )
+            .arg(
+                Arg::new(Self::REPLICATION_FACTOR)
+                    .long(Self::REPLICATION_FACTOR)
+                    .env("P_REPLICATION_FACTOR")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("0")
+                    .value_parser(value_parser!(usize))
+                    .help("Number of peer ingesters an event is copied to before it is acknowledged, 0 disables replication"),
+            )
+            .arg(
+                Arg::new(Self::METRICS_AUTH)
+                    .long(Self::METRICS_AUTH)
+                    .env("P_METRICS_AUTH")
+                    .value_name("BOOL")
+                    .required(false)
+                    .default_value("false")
+                    .value_parser(value_parser!(bool))
+                    .help("Require the metrics token or credentials of a user allowed to read metrics on the metrics endpoint"),
+            )
+            .arg(
+                Arg::new(Self::METRICS_TOKEN)
+                    .long(Self::METRICS_TOKEN)
+                    .env("P_METRICS_TOKEN")
+                    .value_name("STRING")
+                    .required(false)
+                    .help("Bearer token for scraping the metrics endpoint, set the same on every node of a cluster"),
+            )
+            .arg(
+                Arg::new(Self::METRICS_STREAM_LIMIT)
+                    .long(Self::METRICS_STREAM_LIMIT)
+                    .env("P_METRICS_STREAM_LIMIT")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("200")
+                    .value_parser(value_parser!(usize))
+                    .help("Number of streams labelled by name in per stream metrics, and of users in query metrics, further ones are counted as _other"),
+            )
+            .arg(
+                Arg::new(Self::EXTERNAL_TABLE_BUCKETS)
+                    .long(Self::EXTERNAL_TABLE_BUCKETS)
+                    .env("P_EXTERNAL_TABLE_BUCKETS")
+                    .value_name("BUCKET,..")
+                    .required(false)
+                    .value_delimiter(',')
+                    .help("Buckets external tables may be read from besides the bucket of Parseable. None if not set"),
+            )
+            .arg(
+                Arg::new(Self::CLUSTER_SECRET)
+                    .long(Self::CLUSTER_SECRET)
+                    .env("P_CLUSTER_SECRET")
+                    .value_name("STRING")
+                    .required(false)
+                    .help("Shared key for signing requests between nodes of the cluster, set the same on every node"),
+            )
+            .arg(
+                Arg::new(Self::CLUSTER_TOKEN_TTL)
+                    .long(Self::CLUSTER_TOKEN_TTL)
+                    .env("P_CLUSTER_TOKEN_TTL")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("5m")
+                    .value_parser(validation::duration)
+                    .help("Time a signed token of a node is valid for, tokens are renewed once half of it has passed (e.g 5m)"),
+            )
+            .arg(
+                Arg::new(Self::CLUSTER_REQUEST_RETRIES)
+                    .long(Self::CLUSTER_REQUEST_RETRIES)
+                    .env("P_CLUSTER_REQUEST_RETRIES")
+                    .value_name("NUMBER")
+                    .required(false)
+                    .default_value("3")
+                    .value_parser(value_parser!(u32))
+                    .help("Times a request to another node is retried after a connection error, timeout or server error"),
+            )
+            .arg(
+                Arg::new(Self::CLUSTER_REQUEST_TIMEOUT)
+                    .long(Self::CLUSTER_REQUEST_TIMEOUT)
+                    .env("P_CLUSTER_REQUEST_TIMEOUT")
+                    .value_name("DURATION")
+                    .required(false)
+                    .default_value("10s")
+                    .value_parser(validation::duration)
+                    .help("Time a single request to another node may take before it is retried (e.g 10s)"),
+            )
+            .group(
+                ArgGroup::new("smtp")
+                    .args([Self::SMTP_HOST, Self::SMTP_FROM])
+                    .requires_all([Self::SMTP_HOST, Self::SMTP_FROM])
+                    .multiple(true)
+            )
+            .group(
                 ArgGroup::new("oidc")
                     .args([Self::OPENID_CLIENT_ID, Self::OPENID_CLIENT_SECRET, Self::OPENID_ISSUER])
                     .requires_all([Self::OPENID_CLIENT_ID, Self::OPENID_CLIENT_SECRET, Self::OPENID_ISSUER])
@@ -361,6 +1169,26 @@ impl FromArgMatches for Cli {
         self.local_cache_path = m.get_one::<PathBuf>(Self::CACHE).cloned();
         self.tls_cert_path = m.get_one::<PathBuf>(Self::TLS_CERT).cloned();
         self.tls_key_path = m.get_one::<PathBuf>(Self::TLS_KEY).cloned();
+        self.tls_ciphers = m
+            .get_many::<String>(Self::TLS_CIPHERS)
+            .map(|ciphers| ciphers.cloned().collect())
+            .unwrap_or_default();
+        self.fips_mode = m
+            .get_one::<bool>(Self::FIPS_MODE)
+            .cloned()
+            .expect("default for fips mode");
+        if self.fips_mode {
+            if let Some(cipher) = self
+                .tls_ciphers
+                .iter()
+                .find(|cipher| !tls::FIPS_CIPHER_SUITES.contains(&cipher.as_str()))
+            {
+                return Err(clap::Error::raw(
+                    ErrorKind::ArgumentConflict,
+                    format!("{cipher} is not a FIPS approved cipher suite\n"),
+                ));
+            }
+        }
         self.domain_address = m.get_one::<Url>(Self::DOMAIN_URI).cloned();
 
         self.address = m
@@ -411,6 +1239,66 @@ impl FromArgMatches for Cli {
             .get_one::<u8>(Self::QUERY_MEM_POOL_SIZE)
             .cloned()
             .map(|gib| gib as usize * 1024usize.pow(3));
+        self.query_memory_limit_per_query = m
+            .get_one::<u8>(Self::QUERY_MEM_LIMIT_PER_QUERY)
+            .cloned()
+            .map(|gib| gib as usize * 1024usize.pow(3));
+        self.query_spill_to_disk = m
+            .get_one::<bool>(Self::QUERY_SPILL_TO_DISK)
+            .cloned()
+            .expect("default for query spill to disk");
+        self.query_target_partitions = match m
+            .get_one::<usize>(Self::QUERY_TARGET_PARTITIONS)
+            .cloned()
+            .expect("default for query target partitions")
+        {
+            0 => num_cpus::get(),
+            partitions => partitions,
+        };
+        self.query_min_scan_group_size = m
+            .get_one::<u64>(Self::QUERY_MIN_SCAN_GROUP_SIZE)
+            .cloned()
+            .expect("default for query min scan group size");
+        self.query_max_object_store_requests = m
+            .get_one::<usize>(Self::QUERY_MAX_OBJECT_STORE_REQUESTS)
+            .cloned()
+            .expect("default for query max object store requests");
+        self.query_read_coalesce_size = m
+            .get_one::<u64>(Self::QUERY_READ_COALESCE_SIZE)
+            .cloned()
+            .expect("default for query read coalesce size");
+        self.query_metadata_prefetch_size = m
+            .get_one::<u64>(Self::QUERY_METADATA_PREFETCH_SIZE)
+            .cloned()
+            .expect("default for query metadata prefetch size");
+        self.query_max_concurrent = m
+            .get_one::<usize>(Self::QUERY_MAX_CONCURRENT)
+            .cloned()
+            .expect("default for query max concurrent");
+        self.query_max_concurrent_per_user = m
+            .get_one::<usize>(Self::QUERY_MAX_CONCURRENT_PER_USER)
+            .cloned()
+            .expect("default for query max concurrent per user");
+        self.query_max_concurrent_per_stream = m
+            .get_one::<usize>(Self::QUERY_MAX_CONCURRENT_PER_STREAM)
+            .cloned()
+            .expect("default for query max concurrent per stream");
+        self.query_queue_timeout = m
+            .get_one::<Duration>(Self::QUERY_QUEUE_TIMEOUT)
+            .cloned()
+            .expect("default for query queue timeout");
+        self.query_slow_threshold = m
+            .get_one::<Duration>(Self::QUERY_SLOW_THRESHOLD)
+            .cloned()
+            .expect("default for query slow threshold");
+        self.snapshot_retention = m
+            .get_one::<Duration>(Self::SNAPSHOT_RETENTION)
+            .cloned()
+            .expect("default for snapshot retention");
+        self.profiling = m
+            .get_one::<bool>(Self::PROFILING)
+            .cloned()
+            .expect("default for profiling");
         self.row_group_size = m
             .get_one::<usize>(Self::ROW_GROUP_SIZE)
             .cloned()
@@ -429,28 +1317,155 @@ impl FromArgMatches for Cli {
             "zstd" => Compression::ZSTD,
             _ => unreachable!(),
         };
+        self.compress_metadata = m
+            .get_one::<bool>(Self::COMPRESS_METADATA)
+            .cloned()
+            .expect("default for compress metadata");
+        self.ingest_simd_json = m
+            .get_one::<bool>(Self::INGEST_SIMD_JSON)
+            .cloned()
+            .expect("default for ingest simd json");
+        self.ingest_spool_threshold = m
+            .get_one::<u64>(Self::INGEST_SPOOL_THRESHOLD)
+            .cloned()
+            .expect("default for ingest spool threshold");
+        self.ingest_max_payload_size = m
+            .get_one::<u64>(Self::INGEST_MAX_PAYLOAD_SIZE)
+            .cloned()
+            .expect("default for ingest max payload size");
+        self.ingest_max_event_size = m
+            .get_one::<u64>(Self::INGEST_MAX_EVENT_SIZE)
+            .cloned()
+            .expect("default for ingest max event size");
+        self.ingest_max_batch_events = m
+            .get_one::<usize>(Self::INGEST_MAX_BATCH_EVENTS)
+            .cloned()
+            .expect("default for ingest max batch events");
+        self.staging_batch_min_rows = m
+            .get_one::<usize>(Self::STAGING_BATCH_MIN_ROWS)
+            .cloned()
+            .expect("default for staging batch min rows");
+        self.staging_batch_max_rows = m
+            .get_one::<usize>(Self::STAGING_BATCH_MAX_ROWS)
+            .cloned()
+            .expect("default for staging batch max rows");
+        self.staging_batch_latency = m
+            .get_one::<Duration>(Self::STAGING_BATCH_LATENCY)
+            .cloned()
+            .expect("default for staging batch latency");
+        self.staging_encryption_key = m
+            .get_one::<EncryptionKey>(Self::STAGING_ENCRYPTION_KEY)
+            .or_else(|| m.get_one::<EncryptionKey>(Self::STAGING_ENCRYPTION_KEY_FILE))
+            .cloned();
+        self.conversion_concurrency = m
+            .get_one::<usize>(Self::CONVERSION_CONCURRENCY)
+            .cloned()
+            .expect("default for conversion concurrency");
+        self.conversion_priority = match m
+            .get_one::<String>(Self::CONVERSION_PRIORITY)
+            .expect("default for conversion priority")
+            .as_str()
+        {
+            "low" => ConversionPriority::Low,
+            "normal" => ConversionPriority::Normal,
+            "high" => ConversionPriority::High,
+            _ => unreachable!(),
+        };
+        self.upload_concurrency = m
+            .get_one::<usize>(Self::UPLOAD_CONCURRENCY)
+            .cloned()
+            .expect("default for upload concurrency");
+        self.upload_bandwidth_limit = m
+            .get_one::<u64>(Self::UPLOAD_BANDWIDTH_LIMIT)
+            .cloned()
+            .filter(|limit| *limit > 0);
+        self.correlation_streams = m
+            .get_many::<String>(Self::CORRELATION_STREAMS)
+            .map(|streams| streams.cloned().collect())
+            .unwrap_or_default();
+        self.correlation_id_column = m
+            .get_one::<String>(Self::CORRELATION_ID_COLUMN)
+            .cloned()
+            .expect("default for correlation id column");
+        self.smtp_host = m.get_one::<String>(Self::SMTP_HOST).cloned();
+        self.smtp_port = m
+            .get_one::<u16>(Self::SMTP_PORT)
+            .cloned()
+            .expect("default for smtp port");
+        self.smtp_username = m.get_one::<String>(Self::SMTP_USERNAME).cloned();
+        self.smtp_password = m.get_one::<String>(Self::SMTP_PASSWORD).cloned();
+        self.smtp_from = m.get_one::<String>(Self::SMTP_FROM).cloned();
+        self.config_file = m.get_one::<PathBuf>(Self::CONFIG_FILE).cloned();
+        self.webhook_urls = m
+            .get_many::<Url>(Self::WEBHOOK_URLS)
+            .map(|urls| urls.cloned().collect())
+            .unwrap_or_default();
+        self.webhook_secret = m.get_one::<String>(Self::WEBHOOK_SECRET).cloned();
+        self.aws_ingest_access_key_id =
+            m.get_one::<String>(Self::AWS_INGEST_ACCESS_KEY_ID).cloned();
+        self.aws_ingest_secret_access_key = m
+            .get_one::<String>(Self::AWS_INGEST_SECRET_ACCESS_KEY)
+            .cloned();
+        self.aws_firehose_access_key = m.get_one::<String>(Self::AWS_FIREHOSE_ACCESS_KEY).cloned();
+        self.s3_import_queue_url = m.get_one::<Url>(Self::S3_IMPORT_QUEUE_URL).cloned();
+        self.s3_import_mappings = m
+            .get_many::<ImportMapping>(Self::S3_IMPORT_MAPPINGS)
+            .map(|mappings| mappings.cloned().collect())
+            .unwrap_or_default();
+        self.drain_timeout = m
+            .get_one::<Duration>(Self::DRAIN_TIMEOUT)
+            .cloned()
+            .expect("default for drain timeout");
+        self.ingester_stale_after = m
+            .get_one::<Duration>(Self::INGESTER_STALE_AFTER)
+            .cloned()
+            .expect("default for ingester stale after");
+        self.ingester_auto_remove = m
+            .get_one::<bool>(Self::INGESTER_AUTO_REMOVE)
+            .cloned()
+            .expect("default for ingester auto remove");
+        self.replication_factor = m
+            .get_one::<usize>(Self::REPLICATION_FACTOR)
+            .cloned()
+            .expect("default for replication factor");
+        self.metrics_auth = m
+            .get_one::<bool>(Self::METRICS_AUTH)
+            .cloned()
+            .expect("default for metrics auth");
+        self.metrics_token = m.get_one::<String>(Self::METRICS_TOKEN).cloned();
+        self.metrics_stream_limit = m
+            .get_one::<usize>(Self::METRICS_STREAM_LIMIT)
+            .cloned()
+            .expect("default for metrics stream limit");
+        self.external_table_buckets = m
+            .get_many::<String>(Self::EXTERNAL_TABLE_BUCKETS)
+            .map(|buckets| buckets.cloned().collect())
+            .unwrap_or_default();
+        self.cluster_secret = m.get_one::<String>(Self::CLUSTER_SECRET).cloned();
+        self.cluster_token_ttl = m
+            .get_one::<Duration>(Self::CLUSTER_TOKEN_TTL)
+            .cloned()
+            .expect("default for cluster token ttl");
+        self.cluster_request_retries = m
+            .get_one::<u32>(Self::CLUSTER_REQUEST_RETRIES)
+            .cloned()
+            .expect("default for cluster request retries");
+        self.cluster_request_timeout = m
+            .get_one::<Duration>(Self::CLUSTER_REQUEST_TIMEOUT)
+            .cloned()
+            .expect("default for cluster request timeout");
 
         let openid_client_id = m.get_one::<String>(Self::OPENID_CLIENT_ID).cloned();
         let openid_client_secret = m.get_one::<String>(Self::OPENID_CLIENT_SECRET).cloned();
         let openid_issuer = m.get_one::<Url>(Self::OPENID_ISSUER).cloned();
 
         self.openid = match (openid_client_id, openid_client_secret, openid_issuer) {
-            (Some(id), Some(secret), Some(issuer)) => {
-                let origin = if let Some(url) = self.domain_address.clone() {
-                    oidc::Origin::Production(url)
-                } else {
-                    oidc::Origin::Local {
-                        socket_addr: self.address.clone(),
-                        https: self.tls_cert_path.is_some() && self.tls_key_path.is_some(),
-                    }
-                };
-                Some(OpenidConfig {
-                    id,
-                    secret,
-                    issuer,
-                    origin,
-                })
-            }
+            (Some(id), Some(secret), Some(issuer)) => Some(OpenidConfig {
+                id,
+                secret,
+                issuer,
+                origin: self.openid_origin(),
+            }),
             _ => None,
         };
 
@@ -0,0 +1,279 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Detections evaluate a Sigma rule over a stream at an interval. Each run covers the window
+//! since the previous one, and every matching event is written to the detections stream.
+
+pub mod sigma;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use relative_path::RelativePathBuf;
+use serde_json::{json, Value};
+use ulid::Ulid;
+
+use crate::handlers::http::ingest::push_internal_events;
+use crate::handlers::http::query::authorize_query;
+use crate::option::CONFIG;
+use crate::query::QUERY_SESSION;
+use crate::rbac::Users;
+use crate::storage::{ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+
+use self::sigma::SigmaRule;
+
+/// Internal stream the events matched by detections are written to
+pub const DETECTIONS_STREAM_NAME: &str = "pdetections";
+
+const DETECTIONS_DIRECTORY: &str = "detections";
+// events a single run writes at most, so that a noisy rule does not flood the stream
+const MAX_DETECTIONS_PER_RUN: usize = 1000;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Detection {
+    pub id: Ulid,
+    /// title of the rule
+    pub title: String,
+    /// the Sigma rule, as yaml
+    pub rule: String,
+    pub stream: String,
+    /// time between runs, e.g. `5m`
+    pub interval: String,
+    /// columns of the stream fields of the rule are read from, by field name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub fields: HashMap<String, String>,
+    pub enabled: bool,
+    pub owner: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+impl Detection {
+    pub fn interval(&self) -> anyhow::Result<chrono::Duration> {
+        Ok(chrono::Duration::from_std(humantime::parse_duration(
+            &self.interval,
+        )?)?)
+    }
+
+    /// Whether the interval passed since the detection last ran
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        let Ok(interval) = self.interval() else {
+            return false;
+        };
+        self.enabled && self.last_run.unwrap_or(self.created_at) + interval <= now
+    }
+}
+
+pub fn detections_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, DETECTIONS_DIRECTORY])
+}
+
+pub fn detection_path(id: Ulid) -> RelativePathBuf {
+    detections_path().join(format!("{id}.json"))
+}
+
+pub async fn list_detections() -> Result<Vec<Detection>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = match store
+        .get_objects(
+            Some(&detections_path()),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await
+    {
+        Ok(objects) => objects,
+        // nothing was saved yet
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    objects
+        .iter()
+        .map(|bytes| {
+            serde_json::from_slice(bytes)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))
+        })
+        .collect()
+}
+
+pub async fn put_detection(detection: &Detection) -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let body = serde_json::to_vec(detection)
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    store
+        .put_object(&detection_path(detection.id), body.into())
+        .await
+}
+
+pub fn init_detection_scheduler() {
+    log::info!("Setting up schedular for detections");
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler.every(1.minutes()).run(run_due_detections);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+async fn run_due_detections() {
+    let detections = match list_detections().await {
+        Ok(detections) => detections,
+        Err(err) => {
+            log::warn!("could not load detections: {err}");
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    for mut detection in detections
+        .into_iter()
+        .filter(|detection| detection.is_due(now))
+    {
+        // the first run looks back a single interval
+        let start = match detection.last_run {
+            Some(last_run) => last_run,
+            None => {
+                now - detection
+                    .interval()
+                    .unwrap_or_else(|_| chrono::Duration::zero())
+            }
+        };
+        if let Err(err) = run_detection(&detection, start, now).await {
+            log::warn!(
+                "detection {} ({}) failed: {err}",
+                detection.title,
+                detection.id
+            );
+        }
+        // a failed run is not retried, the next one starts where it ended
+        detection.last_run = Some(now);
+        if let Err(err) = put_detection(&detection).await {
+            log::warn!("could not update detection {}: {err}", detection.id);
+        }
+    }
+}
+
+/// Evaluate the rule over events of the window and write the matches to the detections stream.
+/// Returns the number of matched events.
+pub async fn run_detection(
+    detection: &Detection,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> anyhow::Result<usize> {
+    let rule = SigmaRule::parse(&detection.rule)?;
+    let sql = rule.to_sql(&detection.stream, &detection.fields)?;
+    let mut query = crate::query::Query {
+        raw_logical_plan: QUERY_SESSION.state().create_logical_plan(&sql).await?,
+        start,
+        end,
+        filter_tag: None,
+        at: None,
+        raw: false,
+    };
+    let table_name = query
+        .table_name()
+        .ok_or_else(|| anyhow!("query does not read from a stream"))?;
+
+    // the rule runs with the permissions the owner has at this time
+    let tags = authorize_query(&Users.get_user_permissions(&detection.owner), &table_name)?;
+    if !tags.is_empty() {
+        query.filter_tag = Some(tags);
+    }
+
+    let (records, _) = query.execute(table_name).await?;
+    let rows = record_batches_to_json_rows(&records.iter().collect::<Vec<_>>())?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    if rows.len() > MAX_DETECTIONS_PER_RUN {
+        log::warn!(
+            "detection {} matched {} events, only the first {MAX_DETECTIONS_PER_RUN} are written",
+            detection.id,
+            rows.len()
+        );
+    }
+
+    let events: Vec<Value> = rows
+        .iter()
+        .take(MAX_DETECTIONS_PER_RUN)
+        .map(|row| {
+            json!({
+                "detection_id": detection.id.to_string(),
+                "rule_id": rule.id,
+                "title": rule.title,
+                "level": rule.level,
+                "tags": rule.tags.join(","),
+                "stream": detection.stream,
+                "window_start": start.to_rfc3339(),
+                "window_end": end.to_rfc3339(),
+                "event": Value::Object(row.clone()).to_string(),
+            })
+        })
+        .collect();
+    let matched = events.len();
+    push_internal_events(DETECTIONS_STREAM_NAME, Value::Array(events)).await?;
+
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use ulid::Ulid;
+
+    use super::Detection;
+
+    #[test]
+    fn detection_is_due_after_interval() {
+        let created_at = Utc.with_ymd_and_hms(2024, 3, 5, 7, 0, 0).unwrap();
+        let mut detection = Detection {
+            id: Ulid::new(),
+            title: "curl download".to_string(),
+            rule: "title: curl download".to_string(),
+            stream: "linux".to_string(),
+            interval: "5m".to_string(),
+            fields: Default::default(),
+            enabled: true,
+            owner: "alice".to_string(),
+            created_at,
+            updated_at: created_at,
+            last_run: None,
+        };
+        assert!(!detection.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 7, 4, 0).unwrap()));
+        assert!(detection.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 7, 5, 0).unwrap()));
+
+        detection.last_run = Some(Utc.with_ymd_and_hms(2024, 3, 5, 7, 5, 0).unwrap());
+        assert!(!detection.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 7, 9, 0).unwrap()));
+
+        detection.enabled = false;
+        assert!(!detection.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 0).unwrap()));
+    }
+}
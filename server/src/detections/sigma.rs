@@ -0,0 +1,542 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Sigma rules compiled to a SQL query over a stream.
+//!
+//! Every search identifier of the detection becomes a predicate: a map is the `AND` of its
+//! fields, a list of maps the `OR` of them and a list of values a keyword search in the
+//! `message` column. Values of a field are `OR`ed unless the `all` modifier is given. Strings
+//! are matched case insensitively, with `*` and `?` as wildcards. The condition combines the
+//! identifiers with `and`, `or`, `not`, parentheses and `1 of` / `all of` a pattern or `them`.
+//! Aggregations and correlations are not supported.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use serde_yaml::{Mapping, Value};
+
+// column keyword searches look into
+const KEYWORD_FIELD: &str = "message";
+
+// fields of the common sigma taxonomy, mapped to their ECS name
+const SIGMA_FIELDS: &[(&str, &str)] = &[
+    ("Image", "process.executable"),
+    ("OriginalFileName", "process.pe.original_file_name"),
+    ("CommandLine", "process.command_line"),
+    ("CurrentDirectory", "process.working_directory"),
+    ("ProcessId", "process.pid"),
+    ("ParentImage", "process.parent.executable"),
+    ("ParentCommandLine", "process.parent.command_line"),
+    ("ParentProcessId", "process.parent.pid"),
+    ("User", "user.name"),
+    ("TargetFilename", "file.path"),
+    ("SourceIp", "source.ip"),
+    ("SourcePort", "source.port"),
+    ("DestinationIp", "destination.ip"),
+    ("DestinationPort", "destination.port"),
+    ("DestinationHostname", "destination.domain"),
+    ("QueryName", "dns.question.name"),
+    ("ComputerName", "host.name"),
+    ("Computer", "host.name"),
+    ("EventID", "event.code"),
+    ("Provider_Name", "event.provider"),
+    ("c-uri", "url.original"),
+    ("c-useragent", "user_agent.original"),
+    ("cs-method", "http.request.method"),
+    ("sc-status", "http.response.status_code"),
+];
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct SigmaRule {
+    pub title: String,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub level: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub detection: Mapping,
+}
+
+impl SigmaRule {
+    pub fn parse(rule: &str) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_str(rule)?)
+    }
+
+    /// Query for the events of `stream` the rule matches. `fields` maps names used by the rule
+    /// to columns of the stream, in addition to the ECS names of sigma fields.
+    pub fn to_sql(&self, stream: &str, fields: &HashMap<String, String>) -> anyhow::Result<String> {
+        let mut selections = HashMap::new();
+        let mut condition = None;
+        for (name, value) in &self.detection {
+            let name = name
+                .as_str()
+                .ok_or_else(|| anyhow!("detection keys must be strings"))?;
+            match name {
+                "condition" => condition = Some(value),
+                "timeframe" => bail!("rules with a timeframe are not supported"),
+                name => {
+                    selections.insert(name.to_owned(), search(value, fields)?);
+                }
+            }
+        }
+
+        let condition = match condition {
+            Some(Value::String(condition)) => compile_condition(condition, &selections)?,
+            // a list of conditions matches if any of them does
+            Some(Value::Sequence(conditions)) => any(conditions
+                .iter()
+                .map(|condition| {
+                    let condition = condition
+                        .as_str()
+                        .ok_or_else(|| anyhow!("condition must be a string"))?;
+                    compile_condition(condition, &selections)
+                })
+                .collect::<anyhow::Result<_>>()?),
+            _ => bail!("detection has no condition"),
+        };
+
+        Ok(format!(
+            "SELECT * FROM {} WHERE {condition}",
+            quote_identifier(stream)
+        ))
+    }
+}
+
+fn search(value: &Value, fields: &HashMap<String, String>) -> anyhow::Result<String> {
+    match value {
+        Value::Mapping(map) => {
+            let predicates = map
+                .iter()
+                .map(|(field, values)| {
+                    let field = field
+                        .as_str()
+                        .ok_or_else(|| anyhow!("field names must be strings"))?;
+                    field_predicate(field, values, fields)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Ok(all(predicates))
+        }
+        Value::Sequence(items) if items.is_empty() => bail!("search identifier has no values"),
+        Value::Sequence(items) if items.iter().all(Value::is_mapping) => Ok(any(items
+            .iter()
+            .map(|item| search(item, fields))
+            .collect::<anyhow::Result<_>>()?)),
+        Value::Sequence(keywords) => Ok(any(keywords
+            .iter()
+            .map(keyword_predicate)
+            .collect::<anyhow::Result<_>>()?)),
+        keyword => keyword_predicate(keyword),
+    }
+}
+
+fn keyword_predicate(keyword: &Value) -> anyhow::Result<String> {
+    let keyword = scalar(keyword)?;
+    Ok(format!(
+        "{} ~* {}",
+        quote_identifier(KEYWORD_FIELD),
+        quote_literal(&wildcard_regex(&keyword, false))
+    ))
+}
+
+fn field_predicate(
+    key: &str,
+    values: &Value,
+    fields: &HashMap<String, String>,
+) -> anyhow::Result<String> {
+    let mut parts = key.split('|');
+    let field = parts.next().unwrap_or_default();
+    let modifiers: Vec<&str> = parts.collect();
+    let column = quote_identifier(
+        fields
+            .get(field)
+            .map(String::as_str)
+            .or_else(|| {
+                SIGMA_FIELDS
+                    .iter()
+                    .find(|(name, _)| *name == field)
+                    .map(|(_, column)| *column)
+            })
+            .unwrap_or(field),
+    );
+
+    let values = match values {
+        Value::Sequence(values) if values.is_empty() => bail!("field {field} has no values"),
+        Value::Sequence(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    let predicates = values
+        .into_iter()
+        .map(|value| value_predicate(&column, value, &modifiers))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(if modifiers.contains(&"all") {
+        all(predicates)
+    } else {
+        any(predicates)
+    })
+}
+
+fn value_predicate(column: &str, value: &Value, modifiers: &[&str]) -> anyhow::Result<String> {
+    let mut pattern = None;
+    let mut comparison = None;
+    for modifier in modifiers {
+        match *modifier {
+            "all" => {}
+            "contains" | "startswith" | "endswith" | "re" => pattern = Some(*modifier),
+            "gt" => comparison = Some(">"),
+            "gte" => comparison = Some(">="),
+            "lt" => comparison = Some("<"),
+            "lte" => comparison = Some("<="),
+            modifier => bail!("modifier {modifier} is not supported"),
+        }
+    }
+
+    if let Some(operator) = comparison {
+        let number = value
+            .as_f64()
+            .ok_or_else(|| anyhow!("{operator} needs a number"))?;
+        return Ok(format!("{column} {operator} {number}"));
+    }
+
+    let value = match (value, pattern) {
+        (Value::Null, None) => return Ok(format!("{column} IS NULL")),
+        (Value::Bool(value), None) => return Ok(format!("{column} = {value}")),
+        (Value::Number(value), None) => return Ok(format!("{column} = {value}")),
+        (value, _) => scalar(value)?,
+    };
+    let regex = match pattern {
+        Some("re") => return Ok(format!("{column} ~ {}", quote_literal(&value))),
+        Some("contains") => wildcard_regex(&value, false),
+        Some("startswith") => format!("^{}", wildcard_regex(&value, false)),
+        Some("endswith") => format!("{}$", wildcard_regex(&value, false)),
+        _ if !has_wildcard(&value) => {
+            return Ok(format!(
+                "lower({column}) = {}",
+                quote_literal(&unescape(&value).to_lowercase())
+            ))
+        }
+        _ => format!("^{}$", wildcard_regex(&value, false)),
+    };
+    Ok(format!("{column} ~* {}", quote_literal(&regex)))
+}
+
+fn scalar(value: &Value) -> anyhow::Result<String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Bool(value) => Ok(value.to_string()),
+        _ => bail!("expected a string or number, got {value:?}"),
+    }
+}
+
+fn has_wildcard(value: &str) -> bool {
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('*' | '?' | '\\')) => unescaped.extend(chars.next()),
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+// regex of a sigma value, `*` and `?` match any characters unless escaped with `\`
+fn wildcard_regex(value: &str, anchored: bool) -> String {
+    let mut regex = String::new();
+    let mut literal = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('*' | '?' | '\\')) => literal.extend(chars.next()),
+            ('*' | '?', _) => {
+                regex.push_str(&regex::escape(&std::mem::take(&mut literal)));
+                regex.push_str(if c == '*' { ".*" } else { "." });
+            }
+            (c, _) => literal.push(c),
+        }
+    }
+    regex.push_str(&regex::escape(&literal));
+    if anchored {
+        format!("^{regex}$")
+    } else {
+        regex
+    }
+}
+
+fn all(predicates: Vec<String>) -> String {
+    combine(predicates, " AND ")
+}
+
+fn any(predicates: Vec<String>) -> String {
+    combine(predicates, " OR ")
+}
+
+fn combine(mut predicates: Vec<String>, operator: &str) -> String {
+    if predicates.len() == 1 {
+        return predicates.remove(0);
+    }
+    format!("({})", predicates.join(operator))
+}
+
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    OneOf,
+    AllOf,
+    Name(String),
+}
+
+fn tokenize(condition: &str) -> anyhow::Result<Vec<Token>> {
+    let spaced = condition.replace('(', " ( ").replace(')', " ) ");
+    let mut words = spaced.split_whitespace().peekable();
+    let mut tokens = Vec::new();
+    while let Some(word) = words.next() {
+        let token = match word.to_lowercase().as_str() {
+            "(" => Token::Open,
+            ")" => Token::Close,
+            "and" => Token::And,
+            "or" => Token::Or,
+            "not" => Token::Not,
+            "|" => bail!("aggregations are not supported"),
+            quantifier @ ("1" | "any" | "all") if words.peek() == Some(&"of") => {
+                words.next();
+                if quantifier == "all" {
+                    Token::AllOf
+                } else {
+                    Token::OneOf
+                }
+            }
+            _ => Token::Name(word.to_owned()),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn compile_condition(
+    condition: &str,
+    selections: &HashMap<String, String>,
+) -> anyhow::Result<String> {
+    let tokens = tokenize(condition)?;
+    let mut parser = ConditionParser {
+        tokens: &tokens,
+        position: 0,
+        selections,
+    };
+    let sql = parser.or()?;
+    if parser.position != tokens.len() {
+        bail!("unexpected {:?} in condition", tokens[parser.position]);
+    }
+    Ok(sql)
+}
+
+struct ConditionParser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    selections: &'a HashMap<String, String>,
+}
+
+impl ConditionParser<'_> {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn accept(&mut self, token: &Token) -> bool {
+        let accepted = self.tokens.get(self.position) == Some(token);
+        if accepted {
+            self.position += 1;
+        }
+        accepted
+    }
+
+    fn or(&mut self) -> anyhow::Result<String> {
+        let mut predicates = vec![self.and()?];
+        while self.accept(&Token::Or) {
+            predicates.push(self.and()?);
+        }
+        Ok(any(predicates))
+    }
+
+    fn and(&mut self) -> anyhow::Result<String> {
+        let mut predicates = vec![self.not()?];
+        while self.accept(&Token::And) {
+            predicates.push(self.not()?);
+        }
+        Ok(all(predicates))
+    }
+
+    fn not(&mut self) -> anyhow::Result<String> {
+        if self.accept(&Token::Not) {
+            return Ok(format!("NOT {}", self.not()?));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> anyhow::Result<String> {
+        match self.next().cloned() {
+            Some(Token::Open) => {
+                let predicate = self.or()?;
+                if !self.accept(&Token::Close) {
+                    bail!("missing closing parenthesis in condition");
+                }
+                Ok(format!("({predicate})"))
+            }
+            Some(quantifier @ (Token::OneOf | Token::AllOf)) => {
+                let Some(Token::Name(pattern)) = self.next().cloned() else {
+                    bail!("expected a search identifier after of");
+                };
+                let predicates = self.matching(&pattern)?;
+                Ok(if quantifier == Token::AllOf {
+                    all(predicates)
+                } else {
+                    any(predicates)
+                })
+            }
+            Some(Token::Name(name)) => self
+                .selections
+                .get(&name)
+                .map(|predicate| predicate.to_owned())
+                .ok_or_else(|| anyhow!("condition refers to unknown search identifier {name}")),
+            Some(token) => bail!("unexpected {token:?} in condition"),
+            None => bail!("condition ends unexpectedly"),
+        }
+    }
+
+    // selections named by a pattern, `them` is every selection not starting with an underscore
+    fn matching(&self, pattern: &str) -> anyhow::Result<Vec<String>> {
+        let regex = if pattern == "them" {
+            regex::Regex::new("^[^_]")?
+        } else {
+            regex::Regex::new(&wildcard_regex(pattern, true))?
+        };
+        let mut names: Vec<&String> = self
+            .selections
+            .keys()
+            .filter(|name| regex.is_match(name))
+            .collect();
+        if names.is_empty() {
+            bail!("no search identifier matches {pattern}");
+        }
+        names.sort();
+        Ok(names
+            .into_iter()
+            .map(|name| self.selections[name].clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::SigmaRule;
+
+    #[test]
+    fn rule_is_compiled_to_sql() {
+        let rule = SigmaRule::parse(
+            r#"
+title: Suspicious Curl Download
+id: 8b6a1f4c-2c1e-4d3a-9f52-5d3b1f6e3a11
+level: medium
+logsource:
+    category: process_creation
+    product: linux
+detection:
+    selection:
+        Image|endswith: '/curl'
+        CommandLine|contains:
+            - ' -o '
+            - ' --output '
+    filter:
+        User: root
+    condition: selection and not filter
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(rule.level.as_deref(), Some("medium"));
+        assert_eq!(
+            rule.to_sql("linux", &HashMap::new()).unwrap(),
+            "SELECT * FROM \"linux\" WHERE ((\"process.executable\" ~* '/curl$' AND \
+             (\"process.command_line\" ~* ' \\-o ' OR \"process.command_line\" ~* ' \\-\\-output ')) \
+             AND NOT lower(\"user.name\") = 'root')"
+        );
+    }
+
+    #[test]
+    fn quantifiers_and_field_overrides() {
+        let rule = SigmaRule::parse(
+            r#"
+title: Failed logons
+detection:
+    selection_logon:
+        EventID: 4625
+    selection_user:
+        TargetUserName|all:
+            - 'adm*'
+    keywords:
+        - 'failure'
+    condition: 1 of selection_* and keywords
+"#,
+        )
+        .unwrap();
+        let fields = HashMap::from([("EventID".to_string(), "event_id".to_string())]);
+
+        assert_eq!(
+            rule.to_sql("windows", &fields).unwrap(),
+            "SELECT * FROM \"windows\" WHERE ((\"event_id\" = 4625 OR \"TargetUserName\" ~* '^adm.*$') \
+             AND \"message\" ~* 'failure')"
+        );
+        assert!(SigmaRule::parse(
+            "title: x\ndetection:\n  sel: {a: 1}\n  condition: sel | count() > 5"
+        )
+        .unwrap()
+        .to_sql("windows", &fields)
+        .is_err());
+    }
+}
@@ -28,11 +28,13 @@ use std::sync::Arc;
 
 use self::error::EventError;
 pub use self::writer::STREAM_WRITERS;
-use crate::metadata;
+use crate::{metadata, metering};
 
 pub const DEFAULT_TIMESTAMP_KEY: &str = "p_timestamp";
 pub const DEFAULT_TAGS_KEY: &str = "p_tags";
 pub const DEFAULT_METADATA_KEY: &str = "p_metadata";
+// fields of events which would grow a stream past its max columns are packed into this column
+pub const EXTRA_FIELDS_KEY: &str = "_extra";
 
 #[derive(Clone)]
 pub struct Event {
@@ -51,6 +53,7 @@ impl Event {
 
         if self.is_first_event {
             commit_schema(&self.stream_name, self.rb.schema())?;
+            crate::schema_registry::record_version(&self.stream_name).await;
         }
 
         Self::process_event(&self.stream_name, &key, self.rb.clone())?;
@@ -61,6 +64,7 @@ impl Event {
             self.origin_size,
             num_rows,
         )?;
+        metering::record_ingest(&self.stream_name, self.origin_size);
 
         crate::livetail::LIVETAIL.process(&self.stream_name, &self.rb);
 
@@ -27,6 +27,7 @@ use crate::utils::{self, arrow::get_field};
 
 use super::{DEFAULT_METADATA_KEY, DEFAULT_TAGS_KEY, DEFAULT_TIMESTAMP_KEY};
 
+pub mod arrow;
 pub mod json;
 
 type Tags = String;
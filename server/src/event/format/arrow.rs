@@ -0,0 +1,206 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::{collections::HashMap, io::Cursor, sync::Arc};
+
+use anyhow::anyhow;
+use arrow_array::{new_null_array, ArrayRef, RecordBatch};
+use arrow_ipc::reader::StreamReader;
+use arrow_schema::{DataType, Field, Fields, Schema};
+use arrow_select::concat::concat_batches;
+use bytes::Bytes;
+use itertools::Itertools;
+
+use super::{EventFormat, Metadata, Tags};
+
+// Event sent as an arrow IPC stream. Record batches are written to staging
+// as is, without going through json.
+pub struct Event {
+    pub data: RecordBatch,
+    pub tags: Tags,
+    pub metadata: Metadata,
+}
+
+impl Event {
+    // read all record batches from an arrow IPC stream into a single record batch
+    pub fn read_ipc_stream(body: Bytes) -> Result<RecordBatch, anyhow::Error> {
+        let reader = StreamReader::try_new(Cursor::new(body), None)?;
+        let schema = reader.schema();
+        let batches: Vec<RecordBatch> = reader.try_collect()?;
+        Ok(concat_batches(&schema, &batches)?)
+    }
+}
+
+impl EventFormat for Event {
+    type Data = RecordBatch;
+
+    fn to_data(
+        self,
+        schema: HashMap<String, Arc<Field>>,
+        time_partition: Option<String>,
+        static_schema_flag: Option<String>,
+    ) -> Result<(Self::Data, Vec<Arc<Field>>, bool, Tags, Metadata), anyhow::Error> {
+        let stream_schema = schema;
+        let batch_schema = self.data.schema();
+
+        if let Some(time_partition) = &time_partition {
+            let Ok(field) = batch_schema.field_with_name(time_partition) else {
+                return Err(anyhow!(
+                    "ingestion failed as field {} is not part of the log",
+                    time_partition
+                ));
+            };
+            if !matches!(field.data_type(), DataType::Timestamp(..) | DataType::Utf8) {
+                return Err(anyhow!(
+                    "field {} is not a timestamp or a string",
+                    time_partition
+                ));
+            }
+        }
+
+        // fields are sorted by name, same as events sent as json
+        let mut is_first = false;
+        let mut fields = Vec::with_capacity(batch_schema.fields().len());
+        for field in batch_schema
+            .fields()
+            .iter()
+            .sorted_by(|a, b| a.name().cmp(b.name()))
+        {
+            match stream_schema.get(field.name()) {
+                Some(stream_field) if stream_field.data_type() == field.data_type() => {
+                    fields.push(stream_field.clone())
+                }
+                Some(_) => {
+                    return Err(anyhow!(
+                        "Could not process this event due to mismatch in datatype of field {}",
+                        field.name()
+                    ))
+                }
+                None if static_schema_flag.is_some() => return Err(anyhow!("Schema mismatch")),
+                None => {
+                    is_first = true;
+                    fields.push(field.clone())
+                }
+            }
+        }
+
+        if is_first {
+            if let Err(err) = Schema::try_merge(vec![
+                Schema::new(stream_schema.values().cloned().collect::<Fields>()),
+                Schema::new(fields.clone()),
+            ]) {
+                return Err(anyhow!(
+                    "Could not merge schema of this event with that of the existing stream. {:?}",
+                    err
+                ));
+            }
+        }
+
+        Ok((self.data, fields, is_first, self.tags, self.metadata))
+    }
+
+    // Reorder the columns of the record batch to match the schema.
+    // Columns not present in the event are filled with nulls.
+    fn decode(data: Self::Data, schema: Arc<Schema>) -> Result<RecordBatch, anyhow::Error> {
+        let columns: Vec<ArrayRef> = schema
+            .fields()
+            .iter()
+            .map(|field| match data.column_by_name(field.name()) {
+                Some(column) => column.clone(),
+                None => new_null_array(field.data_type(), data.num_rows()),
+            })
+            .collect();
+
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use arrow_array::{Int64Array, RecordBatch, StringArray};
+    use arrow_ipc::writer::StreamWriter;
+    use arrow_schema::{DataType, Field, Schema};
+    use bytes::Bytes;
+
+    use super::Event;
+    use crate::event::format::EventFormat;
+
+    fn ipc_stream(batches: &[RecordBatch]) -> Bytes {
+        let mut buf = Vec::new();
+        let mut writer = StreamWriter::try_new(&mut buf, &batches[0].schema()).unwrap();
+        for batch in batches {
+            writer.write(batch).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        buf.into()
+    }
+
+    fn batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("b", DataType::Utf8, true),
+            Field::new("a", DataType::Int64, true),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["x", "y"])),
+                Arc::new(Int64Array::from(vec![1, 2])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn ipc_stream_to_recordbatch() {
+        let data = Event::read_ipc_stream(ipc_stream(&[batch(), batch()])).unwrap();
+        let event = Event {
+            data,
+            tags: String::default(),
+            metadata: String::default(),
+        };
+
+        let (rb, is_first) = event
+            .into_recordbatch(HashMap::default(), None, None)
+            .unwrap();
+
+        assert!(is_first);
+        assert_eq!(rb.num_rows(), 4);
+        // p_timestamp, a, b, p_tags, p_metadata
+        assert_eq!(rb.num_columns(), 5);
+        assert_eq!(rb.schema().field(1).name(), "a");
+        assert_eq!(rb.schema().field(2).name(), "b");
+    }
+
+    #[test]
+    fn mismatched_datatype_is_rejected() {
+        let schema = HashMap::from([(
+            "a".to_string(),
+            Arc::new(Field::new("a", DataType::Utf8, true)),
+        )]);
+        let event = Event {
+            data: batch(),
+            tags: String::default(),
+            metadata: String::default(),
+        };
+
+        assert!(event.into_recordbatch(schema, None, None).is_err());
+    }
+}
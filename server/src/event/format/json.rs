@@ -25,16 +25,25 @@ use arrow_json::reader::{infer_json_schema_from_iterator, ReaderBuilder};
 use arrow_schema::{DataType, Field, Fields, Schema};
 use datafusion::arrow::util::bit_util::round_upto_multiple_of_64;
 use itertools::Itertools;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::{collections::HashMap, sync::Arc};
 
 use super::{EventFormat, Metadata, Tags};
+use crate::event::{
+    DEFAULT_METADATA_KEY, DEFAULT_TAGS_KEY, DEFAULT_TIMESTAMP_KEY, EXTRA_FIELDS_KEY,
+};
 use crate::utils::{arrow::get_field, json::flatten_json_body};
 
+/// Smallest max columns of a stream, the columns added by the server, the column of packed
+/// fields and one field of the events
+pub const MIN_MAX_COLUMNS: usize = 5;
+
 pub struct Event {
     pub data: Value,
     pub tags: Tags,
     pub metadata: Metadata,
+    /// columns the schema of the stream can grow to, not enforced for a static schema
+    pub max_columns: Option<usize>,
 }
 
 impl EventFormat for Event {
@@ -48,18 +57,27 @@ impl EventFormat for Event {
         time_partition: Option<String>,
         static_schema_flag: Option<String>,
     ) -> Result<(Self::Data, Vec<Arc<Field>>, bool, Tags, Metadata), anyhow::Error> {
-        let data = flatten_json_body(self.data, time_partition)?;
+        let data = flatten_json_body(self.data, time_partition.clone())?;
         let stream_schema = schema;
 
         // incoming event may be a single json or a json array
         // but Data (type defined above) is a vector of json values
         // hence we need to convert the incoming event to a vector of json values
-        let value_arr = match data {
+        let mut value_arr = match data {
             Value::Array(arr) => arr,
             value @ Value::Object(_) => vec![value],
             _ => unreachable!("flatten would have failed beforehand"),
         };
 
+        if let (Some(max_columns), None) = (self.max_columns, &static_schema_flag) {
+            pack_extra_fields(
+                &mut value_arr,
+                &stream_schema,
+                max_columns,
+                time_partition.as_deref(),
+            );
+        }
+
         // collect all the keys from all the json objects in the request body
         let fields =
             collect_keys(value_arr.iter()).expect("fields can be collected from array of objects");
@@ -137,6 +155,90 @@ fn derive_arrow_schema(
     Ok(res)
 }
 
+// Fields new to the stream are given columns in the order of their names while the stream has
+// less than `max_columns` columns, the others are packed into a json object in the
+// EXTRA_FIELDS_KEY column. A field of that name sent with the event is merged into the object.
+// The time partition always gets a column.
+fn pack_extra_fields(
+    values: &mut [Value],
+    stream_schema: &HashMap<String, Arc<Field>>,
+    max_columns: usize,
+    time_partition: Option<&str>,
+) {
+    let Ok(keys) = collect_keys(values.iter()) else {
+        return;
+    };
+    let mut new_fields: Vec<&str> = keys
+        .into_iter()
+        .filter(|name| !stream_schema.contains_key(*name) && *name != EXTRA_FIELDS_KEY)
+        .collect();
+    // columns the server adds to the first event of a stream
+    let reserved = [
+        DEFAULT_TIMESTAMP_KEY,
+        DEFAULT_TAGS_KEY,
+        DEFAULT_METADATA_KEY,
+    ]
+    .iter()
+    .filter(|name| !stream_schema.contains_key(**name))
+    .count();
+    let mut room = max_columns.saturating_sub(stream_schema.len() + reserved);
+    if new_fields.len() <= room {
+        return;
+    }
+
+    if !stream_schema.contains_key(EXTRA_FIELDS_KEY) {
+        room = room.saturating_sub(1);
+    }
+    if let Some(pos) = time_partition
+        .and_then(|time_partition| new_fields.iter().position(|name| *name == time_partition))
+    {
+        new_fields.remove(pos);
+        room = room.saturating_sub(1);
+    }
+    let packed: Vec<String> = new_fields
+        .into_iter()
+        .skip(room)
+        .map(ToOwned::to_owned)
+        .collect();
+
+    for value in values {
+        let Value::Object(fields) = value else {
+            continue;
+        };
+        let mut extra: Map<String, Value> = packed
+            .iter()
+            .filter_map(|name| fields.remove_entry(name))
+            .collect();
+        if extra.is_empty() {
+            continue;
+        }
+        if let Some(sent) = fields.remove(EXTRA_FIELDS_KEY) {
+            merge_extra_fields(&mut extra, sent);
+        }
+        fields.insert(
+            EXTRA_FIELDS_KEY.to_owned(),
+            Value::String(Value::Object(extra).to_string()),
+        );
+    }
+}
+
+// Entries of a json object sent in EXTRA_FIELDS_KEY, e.g. by events packed before, are kept
+// next to the packed fields, any other value is kept under EXTRA_FIELDS_KEY. Packed fields
+// take precedence over entries of the same name.
+fn merge_extra_fields(extra: &mut Map<String, Value>, sent: Value) {
+    let sent = match sent {
+        Value::String(s) => match serde_json::from_str(&s) {
+            Ok(Value::Object(sent)) => sent,
+            _ => Map::from_iter([(EXTRA_FIELDS_KEY.to_owned(), Value::String(s))]),
+        },
+        Value::Null => return,
+        sent => Map::from_iter([(EXTRA_FIELDS_KEY.to_owned(), sent)]),
+    };
+    for (name, value) in sent {
+        extra.entry(name).or_insert(value);
+    }
+}
+
 fn collect_keys<'a>(values: impl Iterator<Item = &'a Value>) -> Result<Vec<&'a str>, ()> {
     let mut keys = Vec::new();
     for value in values {
@@ -17,15 +17,17 @@
  *
  */
 
+mod batching;
 mod file_writer;
 mod mem_writer;
 
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex, RwLock},
+    time::Instant,
 };
 
-use crate::utils;
+use crate::{option::CONFIG, storage::staging::StorageDir, utils};
 
 use self::{errors::StreamWriterError, file_writer::FileWriter, mem_writer::MemWriter};
 use arrow_array::{RecordBatch, TimestampMillisecondArray};
@@ -38,7 +40,7 @@ pub static STREAM_WRITERS: Lazy<WriterTable> = Lazy::new(WriterTable::default);
 
 #[derive(Default)]
 pub struct Writer {
-    pub mem: MemWriter<16384>,
+    pub mem: MemWriter,
     pub disk: FileWriter,
 }
 
@@ -56,8 +58,9 @@ impl Writer {
             &[Arc::new(get_timestamp_array(rb.num_rows()))],
         );
 
-        self.disk.push(stream_name, schema_key, &rb)?;
-        self.mem.push(schema_key, rb);
+        let batch_rows = batching::batch_rows(stream_name, rb.num_rows());
+        self.disk.push(stream_name, schema_key, &rb, batch_rows)?;
+        self.mem.push(schema_key, rb, batch_rows);
         Ok(())
     }
 }
@@ -104,6 +107,31 @@ impl WriterTable {
 
     pub fn delete_stream(&self, stream_name: &str) {
         self.write().unwrap().remove(stream_name);
+        batching::remove_stream(stream_name);
+    }
+
+    // close the writers of a stream and set its files of the current minute aside, so that all
+    // of its staging files are ready for conversion. Events arriving meanwhile wait for the
+    // table, then go to new files.
+    pub fn seal_stream(&self, stream_name: &str) {
+        let mut table = self.write().unwrap();
+        if let Some(writer) = table.remove(stream_name) {
+            writer.into_inner().unwrap().disk.close_all();
+        }
+        StorageDir::new(stream_name).seal_current_files();
+    }
+
+    // write the records buffered longer than the batch latency, events are otherwise only
+    // written by the next event of their stream
+    pub fn flush_pending(&self) {
+        let now = Instant::now();
+        for writer in self.read().unwrap().values() {
+            writer
+                .lock()
+                .unwrap()
+                .disk
+                .flush_due(CONFIG.parseable.staging_batch_latency, now);
+        }
     }
 
     pub fn unset_all(&self) {
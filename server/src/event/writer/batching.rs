@@ -0,0 +1,133 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::metrics;
+use crate::option::CONFIG;
+
+// the rate is measured over windows of at least this long
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+// weight of the latest window in the rate
+const RATE_SMOOTHING: f64 = 0.5;
+
+// kept outside of the writers, which are replaced every minute
+static INGEST_RATES: Lazy<Mutex<HashMap<String, IngestRate>>> = Lazy::new(Mutex::default);
+
+/// Rows of a stream to buffer in memory before writing them to staging, sized so that events
+/// are held for about `P_STAGING_BATCH_LATENCY` at the current rate of the stream. Streams with
+/// few events write each one right away, busy streams write larger batches.
+pub fn batch_rows(stream_name: &str, rows: usize) -> usize {
+    let now = Instant::now();
+    let rate = INGEST_RATES
+        .lock()
+        .unwrap()
+        .entry(stream_name.to_owned())
+        .or_insert_with(|| IngestRate::new(now))
+        .record(rows, now);
+
+    let target = target_rows(
+        rate,
+        CONFIG.parseable.staging_batch_latency,
+        CONFIG.parseable.staging_batch_min_rows,
+        CONFIG.parseable.staging_batch_max_rows,
+    );
+    if let Some(label) = metrics::stream_label(stream_name) {
+        metrics::STAGING_EVENT_RATE
+            .with_label_values(&[&label])
+            .set(rate);
+        metrics::STAGING_BATCH_ROWS
+            .with_label_values(&[&label])
+            .set(target as i64);
+    }
+    target
+}
+
+pub fn remove_stream(stream_name: &str) {
+    INGEST_RATES.lock().unwrap().remove(stream_name);
+}
+
+fn target_rows(rate: f64, latency: Duration, min_rows: usize, max_rows: usize) -> usize {
+    let min_rows = min_rows.max(1);
+    ((rate * latency.as_secs_f64()) as usize).clamp(min_rows, max_rows.max(min_rows))
+}
+
+/// Rows per second a stream receives, smoothed over windows
+#[derive(Debug)]
+struct IngestRate {
+    rate: f64,
+    window_start: Instant,
+    window_rows: usize,
+}
+
+impl IngestRate {
+    fn new(now: Instant) -> Self {
+        Self {
+            rate: 0.0,
+            window_start: now,
+            window_rows: 0,
+        }
+    }
+
+    fn record(&mut self, rows: usize, now: Instant) -> f64 {
+        self.window_rows += rows;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            let window_rate = self.window_rows as f64 / elapsed.as_secs_f64();
+            self.rate = RATE_SMOOTHING * window_rate + (1.0 - RATE_SMOOTHING) * self.rate;
+            self.window_start = now;
+            self.window_rows = 0;
+        }
+        self.rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{target_rows, IngestRate};
+
+    #[test]
+    fn rate_follows_the_stream() {
+        let start = Instant::now();
+        let mut rate = IngestRate::new(start);
+        for ms in 1..=1000 {
+            rate.record(10, start + Duration::from_millis(ms));
+        }
+        // half of the first window's 10000 rows per second
+        assert!((rate.rate - 5000.0).abs() < 1.0);
+
+        // an idle stream decays on its next event
+        let rate = rate.record(1, start + Duration::from_secs(11));
+        assert!(rate < 2600.0);
+    }
+
+    #[test]
+    fn target_is_bounded() {
+        let latency = Duration::from_secs(1);
+        assert_eq!(target_rows(0.2, latency, 1, 16384), 1);
+        assert_eq!(target_rows(500.0, latency, 1, 16384), 500);
+        assert_eq!(target_rows(1e6, latency, 1, 16384), 16384);
+        assert_eq!(target_rows(500.0, latency, 1000, 100), 1000);
+    }
+}
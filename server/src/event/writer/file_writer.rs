@@ -19,18 +19,63 @@
 
 use arrow_array::RecordBatch;
 use arrow_ipc::writer::StreamWriter;
+use arrow_select::concat::concat_batches;
 use derive_more::{Deref, DerefMut};
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use crate::storage::staging::StorageDir;
+use crate::option::CONFIG;
+
+use crate::storage::staging::{encryption::StagingFile, StorageDir};
 
 use super::errors::StreamWriterError;
 
 pub struct ArrowWriter {
     pub file_path: PathBuf,
-    pub writer: StreamWriter<File>,
+    pub writer: StreamWriter<StagingFile>,
+    // records not written yet, with the time the oldest of them arrived
+    pending: Vec<RecordBatch>,
+    pending_rows: usize,
+    pending_since: Option<Instant>,
+}
+
+impl ArrowWriter {
+    fn write(&mut self, record: &RecordBatch, batch_rows: usize) -> Result<(), StreamWriterError> {
+        self.pending_since.get_or_insert_with(Instant::now);
+        self.pending_rows += record.num_rows();
+        self.pending.push(record.clone());
+
+        if self.pending_rows >= batch_rows
+            || is_due(
+                self.pending_since,
+                CONFIG.parseable.staging_batch_latency,
+                Instant::now(),
+            )
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), StreamWriterError> {
+        let pending = std::mem::take(&mut self.pending);
+        self.pending_rows = 0;
+        self.pending_since = None;
+        match pending.as_slice() {
+            [] => {}
+            [record] => self.writer.write(record)?,
+            [first, ..] => self
+                .writer
+                .write(&concat_batches(&first.schema(), &pending)?)?,
+        }
+        Ok(())
+    }
+}
+
+// whether records pending since the given time have waited for the latency
+fn is_due(pending_since: Option<Instant>, latency: Duration, now: Instant) -> bool {
+    pending_since.is_some_and(|since| now.saturating_duration_since(since) >= latency)
 }
 
 #[derive(Deref, DerefMut, Default)]
@@ -43,14 +88,10 @@ impl FileWriter {
         stream_name: &str,
         schema_key: &str,
         record: &RecordBatch,
+        batch_rows: usize,
     ) -> Result<(), StreamWriterError> {
         match self.get_mut(schema_key) {
-            Some(writer) => {
-                writer
-                    .writer
-                    .write(record)
-                    .map_err(StreamWriterError::Writer)?;
-            }
+            Some(writer) => writer.write(record, batch_rows)?,
             // entry is not present thus we create it
             None => {
                 // this requires mutable borrow of the map so we drop this read lock and wait for write lock
@@ -60,6 +101,9 @@ impl FileWriter {
                     ArrowWriter {
                         file_path: path,
                         writer,
+                        pending: Vec::new(),
+                        pending_rows: 0,
+                        pending_since: None,
                     },
                 );
             }
@@ -68,8 +112,29 @@ impl FileWriter {
         Ok(())
     }
 
+    // write the records of streams which stopped receiving events before their batch filled up
+    pub fn flush_due(&mut self, latency: Duration, now: Instant) {
+        for writer in self.values_mut() {
+            if !is_due(writer.pending_since, latency, now) {
+                continue;
+            }
+            if let Err(err) = writer.flush() {
+                log::error!(
+                    "failed to write buffered events to {}: {err}",
+                    writer.file_path.display()
+                );
+            }
+        }
+    }
+
     pub fn close_all(self) {
         for mut writer in self.0.into_values() {
+            if let Err(err) = writer.flush() {
+                log::error!(
+                    "failed to write buffered events to {}: {err}",
+                    writer.file_path.display()
+                );
+            }
             _ = writer.writer.finish();
         }
     }
@@ -79,12 +144,12 @@ fn init_new_stream_writer_file(
     stream_name: &str,
     schema_key: &str,
     record: &RecordBatch,
-) -> Result<(PathBuf, StreamWriter<std::fs::File>), StreamWriterError> {
+) -> Result<(PathBuf, StreamWriter<StagingFile>), StreamWriterError> {
     let dir = StorageDir::new(stream_name);
     let path = dir.path_by_current_time(schema_key);
     std::fs::create_dir_all(dir.data_path)?;
 
-    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let file = StagingFile::append(&path)?;
 
     let mut stream_writer = StreamWriter::try_new(file, &record.schema())
         .expect("File and RecordBatch both are checked");
@@ -94,3 +159,24 @@ fn init_new_stream_writer_file(
         .map_err(StreamWriterError::Writer)?;
     Ok((path, stream_writer))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::is_due;
+
+    #[test]
+    fn pending_records_are_due_after_the_latency() {
+        let since = Instant::now();
+        let latency = Duration::from_millis(500);
+        assert!(!is_due(None, latency, since + latency));
+        assert!(!is_due(Some(since), latency, since));
+        assert!(!is_due(
+            Some(since),
+            latency,
+            since + Duration::from_millis(499)
+        ));
+        assert!(is_due(Some(since), latency, since + latency));
+    }
+}
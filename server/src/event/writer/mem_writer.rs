@@ -29,16 +29,17 @@ use crate::utils::arrow::adapt_batch;
 ///
 /// Any new schema is updated in the schema map.
 /// Recordbatches are pushed to mutable buffer first and then concated together and pushed to read buffer
+/// once it holds the batch size of the stream
 #[derive(Debug)]
-pub struct MemWriter<const N: usize> {
+pub struct MemWriter {
     schema: Schema,
     // for checking uniqueness of schema
     schema_map: HashSet<String>,
     read_buffer: Vec<RecordBatch>,
-    mutable_buffer: MutableBuffer<N>,
+    mutable_buffer: MutableBuffer,
 }
 
-impl<const N: usize> Default for MemWriter<N> {
+impl Default for MemWriter {
     fn default() -> Self {
         Self {
             schema: Schema::empty(),
@@ -49,14 +50,14 @@ impl<const N: usize> Default for MemWriter<N> {
     }
 }
 
-impl<const N: usize> MemWriter<N> {
-    pub fn push(&mut self, schema_key: &str, rb: RecordBatch) {
+impl MemWriter {
+    pub fn push(&mut self, schema_key: &str, rb: RecordBatch, batch_rows: usize) {
         if !self.schema_map.contains(schema_key) {
             self.schema_map.insert(schema_key.to_owned());
             self.schema = Schema::try_merge([self.schema.clone(), (*rb.schema()).clone()]).unwrap();
         }
 
-        if let Some(record) = self.mutable_buffer.push(rb) {
+        if let Some(record) = self.mutable_buffer.push(rb, batch_rows) {
             let record = concat_records(&Arc::new(self.schema.clone()), &record);
             self.read_buffer.push(record);
         }
@@ -83,15 +84,16 @@ fn concat_records(schema: &Arc<Schema>, record: &[RecordBatch]) -> RecordBatch {
 }
 
 #[derive(Debug, Default)]
-struct MutableBuffer<const N: usize> {
+struct MutableBuffer {
     pub inner: Vec<RecordBatch>,
     pub rows: usize,
 }
 
-impl<const N: usize> MutableBuffer<N> {
-    fn push(&mut self, rb: RecordBatch) -> Option<Vec<RecordBatch>> {
-        if self.rows + rb.num_rows() >= N {
-            let left = N - self.rows;
+impl MutableBuffer {
+    fn push(&mut self, rb: RecordBatch, batch_rows: usize) -> Option<Vec<RecordBatch>> {
+        if self.rows + rb.num_rows() >= batch_rows {
+            // the batch size may have shrunk below the rows already buffered
+            let left = batch_rows.saturating_sub(self.rows);
             let right = rb.num_rows() - left;
             let left_slice = rb.slice(0, left);
             let right_slice = if left < rb.num_rows() {
@@ -0,0 +1,372 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! External tables expose parquet or csv files that were not ingested by Parseable, such as a
+//! customer dimension table, so that they can be queried and joined with log streams in SQL.
+//! The files are read in place from a prefix of the object store. Delta Lake and Iceberg tables
+//! are read from the data files of their current version, found through their own metadata.
+
+mod delta;
+mod iceberg;
+mod lakehouse;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use arrow_schema::Schema;
+use chrono::{DateTime, Utc};
+use datafusion::datasource::file_format::csv::CsvFormat;
+use datafusion::datasource::file_format::parquet::ParquetFormat;
+use datafusion::datasource::file_format::FileFormat;
+use datafusion::datasource::listing::{
+    ListingOptions, ListingTable, ListingTableConfig, ListingTableUrl,
+};
+use datafusion::datasource::TableProvider;
+use datafusion::error::DataFusionError;
+use object_store::path::Path;
+use once_cell::sync::Lazy;
+use relative_path::{RelativePath, RelativePathBuf};
+use url::Url;
+
+use self::lakehouse::{LakehouseSnapshot, TableRoot};
+
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::query::QUERY_SESSION;
+use crate::storage::{ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+
+const EXTERNAL_TABLES_DIRECTORY: &str = "external_tables";
+
+// definitions as last read from or written to object storage
+static EXTERNAL_TABLES: Lazy<RwLock<HashMap<String, ExternalTable>>> = Lazy::new(RwLock::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalTableFormat {
+    Parquet,
+    Csv,
+    Delta,
+    Iceberg,
+}
+
+impl ExternalTableFormat {
+    fn is_lakehouse(self) -> bool {
+        matches!(self, Self::Delta | Self::Iceberg)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalTable {
+    pub name: String,
+    /// bucket the table is in, when it is not the bucket Parseable stores its data in,
+    /// one of the buckets external tables are allowed to be read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    /// prefix of the object store the files are listed under, the root directory of
+    /// delta and iceberg tables
+    pub location: String,
+    pub format: ExternalTableFormat,
+    /// whether csv files start with a header row
+    #[serde(default)]
+    pub has_header: bool,
+    /// schema inferred from the files when the table was created,
+    /// delta and iceberg tables are read with the schema of their current version
+    pub schema: Schema,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ExternalTable {
+    pub async fn table_provider(&self) -> Result<Arc<dyn TableProvider>, DataFusionError> {
+        // a stream created after the table may own the location
+        if self.bucket.is_none() {
+            check_location(&self.location, |name| STREAM_INFO.stream_exists(name))
+                .map_err(DataFusionError::Plan)?;
+        }
+        let root = table_root(self.bucket.as_deref(), &self.location)?;
+        if self.format.is_lakehouse() {
+            let snapshot = load_snapshot(&root, self.format).await?;
+            return Ok(Arc::new(snapshot.into_table(&root.url)?));
+        }
+
+        let config = ListingTableConfig::new(listing_url(&root)?)
+            .with_listing_options(listing_options(self.format, self.has_header))
+            .with_schema(Arc::new(self.schema.clone()));
+        Ok(Arc::new(ListingTable::try_new(config)?))
+    }
+}
+
+fn listing_options(format: ExternalTableFormat, has_header: bool) -> ListingOptions {
+    let (file_format, extension): (Arc<dyn FileFormat>, _) = match format {
+        ExternalTableFormat::Csv => (
+            Arc::new(CsvFormat::default().with_has_header(has_header)),
+            ".csv",
+        ),
+        _ => (
+            Arc::new(ParquetFormat::default().with_enable_pruning(Some(true))),
+            ".parquet",
+        ),
+    };
+    ListingOptions::new(file_format)
+        .with_file_extension(extension)
+        .with_collect_stat(true)
+}
+
+async fn load_snapshot(
+    root: &TableRoot,
+    format: ExternalTableFormat,
+) -> Result<LakehouseSnapshot, DataFusionError> {
+    match format {
+        ExternalTableFormat::Delta => delta::load_snapshot(root).await,
+        _ => iceberg::load_snapshot(root).await,
+    }
+}
+
+/// Check that a location in the bucket of Parseable is outside of the data and metadata of
+/// Parseable, which would otherwise be readable by everyone allowed to read the table
+pub fn check_location(location: &str, is_stream: impl Fn(&str) -> bool) -> Result<(), String> {
+    let location = location.trim_matches('/');
+    let first = location.split('/').next().unwrap_or_default();
+    // the root directory of Parseable and the other hidden directories it keeps state in
+    if first == PARSEABLE_ROOT_DIRECTORY || first.starts_with('.') {
+        return Err(format!("location {location} is reserved for Parseable"));
+    }
+    if is_stream(first) {
+        return Err(format!(
+            "location {location} is in the data of stream {first}"
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a bucket other than the bucket of Parseable is one external tables may be read
+/// from. The bucket of Parseable is only read through locations checked by [`check_location`].
+pub fn check_bucket(
+    bucket: &str,
+    allowed: &[String],
+    parseable_bucket: &str,
+) -> Result<(), String> {
+    if bucket == parseable_bucket || !allowed.iter().any(|allowed| allowed == bucket) {
+        return Err(format!(
+            "external tables cannot be read from bucket {bucket}"
+        ));
+    }
+    Ok(())
+}
+
+// tables in other buckets are read with the credentials of the storage of Parseable, the store
+// of the bucket is registered with the query session the first time it is used
+fn table_root(bucket: Option<&str>, location: &str) -> Result<TableRoot, DataFusionError> {
+    let location = location.trim_matches('/');
+    let runtime = QUERY_SESSION.runtime_env();
+    let (url, path) = match bucket {
+        None => {
+            let storage = CONFIG.storage().get_object_store();
+            let path = storage.absolute_url(RelativePath::new(location));
+            (storage.store_url(), path)
+        }
+        Some(bucket) => {
+            check_bucket(
+                bucket,
+                &CONFIG.parseable.external_table_buckets,
+                &CONFIG.storage().get_object_store().get_bucket_name(),
+            )
+            .map_err(DataFusionError::Plan)?;
+            let url = Url::parse(&format!("s3://{bucket}"))
+                .map_err(|err| DataFusionError::Plan(format!("invalid bucket {bucket}: {err}")))?;
+            if runtime.object_store_registry.get_store(&url).is_err() {
+                let store = CONFIG.storage().get_bucket_store(bucket).ok_or_else(|| {
+                    DataFusionError::Plan(
+                        "tables in other buckets are only supported with s3 storage".to_string(),
+                    )
+                })?;
+                runtime.register_object_store(&url, store);
+            }
+            (url, Path::from(location))
+        }
+    };
+    Ok(TableRoot {
+        store: runtime.object_store_registry.get_store(&url)?,
+        url,
+        path,
+    })
+}
+
+// the location is a directory, files directly under it and in nested prefixes are read
+fn listing_url(root: &TableRoot) -> Result<ListingTableUrl, DataFusionError> {
+    ListingTableUrl::parse(format!(
+        "{}/{}/",
+        root.url.as_str().trim_end_matches('/'),
+        root.path
+    ))
+}
+
+/// Infer the schema of the table, failing if it has no files
+pub async fn infer_schema(
+    bucket: Option<&str>,
+    location: &str,
+    format: ExternalTableFormat,
+    has_header: bool,
+) -> Result<Schema, DataFusionError> {
+    let root = table_root(bucket, location)?;
+    if format.is_lakehouse() {
+        return Ok(load_snapshot(&root, format).await?.table_schema());
+    }
+
+    let schema = listing_options(format, has_header)
+        .infer_schema(&QUERY_SESSION.state(), &listing_url(&root)?)
+        .await?;
+    if schema.fields().is_empty() {
+        return Err(DataFusionError::Plan(format!(
+            "no {format:?} files found under {location}"
+        )));
+    }
+    Ok(schema.as_ref().clone())
+}
+
+pub fn external_tables_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, EXTERNAL_TABLES_DIRECTORY])
+}
+
+pub fn external_table_path(name: &str) -> RelativePathBuf {
+    external_tables_path().join(format!("{name}.json"))
+}
+
+pub fn exists(name: &str) -> bool {
+    EXTERNAL_TABLES.read().unwrap().contains_key(name)
+}
+
+pub fn get(name: &str) -> Option<ExternalTable> {
+    EXTERNAL_TABLES.read().unwrap().get(name).cloned()
+}
+
+pub fn list() -> Vec<ExternalTable> {
+    let mut tables: Vec<_> = EXTERNAL_TABLES.read().unwrap().values().cloned().collect();
+    tables.sort_by(|a, b| a.name.cmp(&b.name));
+    tables
+}
+
+/// Populate the tables from object storage
+pub async fn load() -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = match store
+        .get_objects(
+            Some(&external_tables_path()),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await
+    {
+        Ok(objects) => objects,
+        // nothing was saved yet
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut tables = HashMap::new();
+    for bytes in objects {
+        let table: ExternalTable = serde_json::from_slice(&bytes)?;
+        tables.insert(table.name.clone(), table);
+    }
+    *EXTERNAL_TABLES.write().unwrap() = tables;
+    Ok(())
+}
+
+/// Read a table another query server may have created since the tables were loaded
+pub async fn fetch(name: &str) -> Result<Option<ExternalTable>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let table: ExternalTable = match store.get_object(&external_table_path(name)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(ObjectStorageError::NoSuchKey(_)) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    EXTERNAL_TABLES
+        .write()
+        .unwrap()
+        .insert(table.name.clone(), table.clone());
+    Ok(Some(table))
+}
+
+pub async fn put(table: ExternalTable) -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    store
+        .put_object(
+            &external_table_path(&table.name),
+            serde_json::to_vec(&table)?.into(),
+        )
+        .await?;
+    EXTERNAL_TABLES
+        .write()
+        .unwrap()
+        .insert(table.name.clone(), table);
+    Ok(())
+}
+
+pub async fn delete(name: &str) -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    store.delete_object(&external_table_path(name)).await?;
+    EXTERNAL_TABLES.write().unwrap().remove(name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_bucket, check_location};
+
+    #[test]
+    fn only_allowed_buckets_are_read() {
+        let allowed = ["lake".to_string(), "logs".to_string()];
+        assert!(check_bucket("lake", &allowed, "logs").is_ok());
+        assert!(check_bucket("other", &allowed, "logs").is_err());
+        // the bucket of parseable is not read past the location checks
+        assert!(check_bucket("logs", &allowed, "logs").is_err());
+        assert!(check_bucket("lake", &[], "logs").is_err());
+    }
+
+    #[test]
+    fn locations_in_parseable_data_are_rejected() {
+        let is_stream = |name: &str| name == "app";
+        for location in [
+            ".parseable",
+            "/.parseable/users/",
+            ".parseable/external_tables",
+            ".hidden/data",
+            "app",
+            "app/date=2024-01-01/",
+            "/app/",
+        ] {
+            assert!(
+                check_location(location, is_stream).is_err(),
+                "{location} is accepted"
+            );
+        }
+        for location in [
+            "lake/customers",
+            "customers/",
+            "application/data",
+            "lake/app",
+        ] {
+            assert!(
+                check_location(location, is_stream).is_ok(),
+                "{location} is rejected"
+            );
+        }
+    }
+}
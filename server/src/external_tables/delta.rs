@@ -0,0 +1,349 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Current version of a Delta Lake table, replayed from the latest checkpoint and the commits
+//! after it in the `_delta_log` directory.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use arrow_schema::{DataType, Field, Fields, Schema, TimeUnit};
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use datafusion::error::DataFusionError;
+use futures_util::TryStreamExt;
+use object_store::path::Path;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde_json::Value;
+
+use super::lakehouse::{
+    decimal_type, external, map_type, unsupported, DataFile, LakehouseSnapshot, TableRoot,
+};
+
+const DELTA_LOG_DIRECTORY: &str = "_delta_log";
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Action {
+    add: Option<Add>,
+    remove: Option<Remove>,
+    meta_data: Option<Metadata>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Add {
+    path: String,
+    size: u64,
+    #[serde(default)]
+    partition_values: HashMap<String, Option<String>>,
+    #[serde(default)]
+    deletion_vector: Option<Value>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Remove {
+    path: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    schema_string: String,
+    #[serde(default)]
+    partition_columns: Vec<String>,
+    #[serde(default)]
+    configuration: HashMap<String, Option<String>>,
+}
+
+#[derive(Default)]
+struct TableState {
+    files: HashMap<String, Add>,
+    metadata: Option<Metadata>,
+}
+
+impl TableState {
+    fn apply(&mut self, action: Action) -> Result<(), DataFusionError> {
+        if let Some(add) = action.add {
+            if add.deletion_vector.as_ref().is_some_and(|dv| !dv.is_null()) {
+                return Err(unsupported("delta tables with deletion vectors"));
+            }
+            self.files.insert(add.path.clone(), add);
+        }
+        if let Some(remove) = action.remove {
+            self.files.remove(&remove.path);
+        }
+        if let Some(metadata) = action.meta_data {
+            self.metadata = Some(metadata);
+        }
+        Ok(())
+    }
+}
+
+// version of a commit `{version:020}.json`, or of a checkpoint part
+// `{version:020}.checkpoint.parquet` / `{version:020}.checkpoint.{part:010}.{parts:010}.parquet`
+enum LogFile {
+    Commit(u64),
+    Checkpoint { version: u64, parts: usize },
+}
+
+fn parse_log_file(name: &str) -> Option<LogFile> {
+    let (version, rest) = name.split_once('.')?;
+    if version.len() != 20 {
+        return None;
+    }
+    let version = version.parse().ok()?;
+    match rest {
+        "json" => Some(LogFile::Commit(version)),
+        "checkpoint.parquet" => Some(LogFile::Checkpoint { version, parts: 1 }),
+        rest => {
+            let (_, parts) = rest
+                .strip_prefix("checkpoint.")?
+                .strip_suffix(".parquet")?
+                .split_once('.')?;
+            Some(LogFile::Checkpoint {
+                version,
+                parts: parts.parse().ok()?,
+            })
+        }
+    }
+}
+
+pub async fn load_snapshot(root: &TableRoot) -> Result<LakehouseSnapshot, DataFusionError> {
+    let log_dir = root.path.child(DELTA_LOG_DIRECTORY);
+    let objects: Vec<_> = root.store.list(Some(&log_dir)).await?.try_collect().await?;
+
+    let mut commits = BTreeMap::new();
+    let mut checkpoints: BTreeMap<u64, (usize, Vec<Path>)> = BTreeMap::new();
+    for object in objects {
+        match object.location.filename().and_then(parse_log_file) {
+            Some(LogFile::Commit(version)) => {
+                commits.insert(version, object.location);
+            }
+            Some(LogFile::Checkpoint { version, parts }) => {
+                let checkpoint = checkpoints.entry(version).or_insert((parts, Vec::new()));
+                checkpoint.1.push(object.location);
+            }
+            None => (),
+        }
+    }
+    // a checkpoint is usable once all of its parts are written
+    let checkpoint = checkpoints
+        .into_iter()
+        .rev()
+        .find(|(_, (parts, files))| *parts == files.len());
+
+    let mut state = TableState::default();
+    let mut version = match checkpoint {
+        Some((version, (_, mut files))) => {
+            files.sort();
+            for file in files {
+                read_checkpoint(root, &file, &mut state).await?;
+            }
+            Some(version)
+        }
+        None => None,
+    };
+    for (commit_version, path) in commits.range(version.map_or(0, |version| version + 1)..) {
+        let expected = version.map_or(0, |version| version + 1);
+        if *commit_version != expected {
+            return Err(DataFusionError::Plan(format!(
+                "delta log is missing the commit of version {expected}"
+            )));
+        }
+        let bytes = root.get(path).await?;
+        for line in bytes.split(|byte| *byte == b'\n') {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            state.apply(serde_json::from_slice(line).map_err(external)?)?;
+        }
+        version = Some(*commit_version);
+    }
+
+    let metadata = state
+        .metadata
+        .ok_or_else(|| DataFusionError::Plan("no delta table found".to_string()))?;
+    if metadata
+        .configuration
+        .get("delta.columnMapping.mode")
+        .is_some_and(|mode| mode.as_deref().is_some_and(|mode| mode != "none"))
+    {
+        return Err(unsupported("delta tables with column mapping"));
+    }
+
+    let schema: Value = serde_json::from_str(&metadata.schema_string).map_err(external)?;
+    let fields = struct_fields(&schema)?;
+    let (partition_columns, file_fields): (Vec<_>, Vec<_>) = fields
+        .iter()
+        .map(|field| field.as_ref().clone())
+        .partition(|field| metadata.partition_columns.contains(field.name()));
+    // partition columns are in the order the table was partitioned by
+    let partition_columns = metadata
+        .partition_columns
+        .iter()
+        .filter_map(|name| partition_columns.iter().find(|field| field.name() == name))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut files = Vec::with_capacity(state.files.len());
+    for (path, add) in state.files {
+        let mut partition_values = add.partition_values;
+        files.push(DataFile {
+            path: root.resolve(&path)?,
+            size: add.size,
+            partition_values: partition_columns
+                .iter()
+                .map(|field| partition_values.remove(field.name()).flatten())
+                .collect(),
+        });
+    }
+
+    Ok(LakehouseSnapshot {
+        schema: Schema::new(file_fields),
+        partition_columns,
+        files,
+    })
+}
+
+// checkpoints hold the actions of every commit up to their version as parquet columns
+async fn read_checkpoint(
+    root: &TableRoot,
+    path: &Path,
+    state: &mut TableState,
+) -> Result<(), DataFusionError> {
+    let bytes = root.get(path).await?;
+    let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()?;
+    for batch in reader {
+        for row in record_batches_to_json_rows(&[&batch?])? {
+            state.apply(serde_json::from_value(Value::Object(row)).map_err(external)?)?;
+        }
+    }
+    Ok(())
+}
+
+fn struct_fields(value: &Value) -> Result<Fields, DataFusionError> {
+    let fields = value
+        .get("fields")
+        .and_then(Value::as_array)
+        .ok_or_else(|| unsupported(format!("delta type {value}")))?;
+    fields
+        .iter()
+        .map(|field| {
+            let name = field
+                .get("name")
+                .and_then(Value::as_str)
+                .ok_or_else(|| unsupported(format!("delta field {field}")))?;
+            let nullable = field
+                .get("nullable")
+                .and_then(Value::as_bool)
+                .unwrap_or(true);
+            Ok(Field::new(name, data_type(&field["type"])?, nullable))
+        })
+        .collect()
+}
+
+fn data_type(value: &Value) -> Result<DataType, DataFusionError> {
+    let data_type = match value {
+        Value::String(name) => match name.as_str() {
+            "string" => DataType::Utf8,
+            "long" => DataType::Int64,
+            "integer" => DataType::Int32,
+            "short" => DataType::Int16,
+            "byte" => DataType::Int8,
+            "float" => DataType::Float32,
+            "double" => DataType::Float64,
+            "boolean" => DataType::Boolean,
+            "binary" => DataType::Binary,
+            "date" => DataType::Date32,
+            "timestamp" => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            "timestamp_ntz" => DataType::Timestamp(TimeUnit::Microsecond, None),
+            name => decimal_type(name).ok_or_else(|| unsupported(format!("delta type {name}")))?,
+        },
+        Value::Object(object) => match object.get("type").and_then(Value::as_str) {
+            Some("struct") => DataType::Struct(struct_fields(value)?),
+            Some("array") => {
+                let nullable = object
+                    .get("containsNull")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(true);
+                let element = Field::new("element", data_type(&object["elementType"])?, nullable);
+                DataType::List(Arc::new(element))
+            }
+            Some("map") => map_type(
+                data_type(&object["keyType"])?,
+                data_type(&object["valueType"])?,
+                object
+                    .get("valueContainsNull")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(true),
+            ),
+            _ => return Err(unsupported(format!("delta type {value}"))),
+        },
+        _ => return Err(unsupported(format!("delta type {value}"))),
+    };
+    Ok(data_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_schema::DataType;
+    use serde_json::json;
+
+    use super::{data_type, parse_log_file, LogFile};
+
+    #[test]
+    fn log_file_names() {
+        assert!(matches!(
+            parse_log_file("00000000000000000012.json"),
+            Some(LogFile::Commit(12))
+        ));
+        assert!(matches!(
+            parse_log_file("00000000000000000010.checkpoint.parquet"),
+            Some(LogFile::Checkpoint {
+                version: 10,
+                parts: 1
+            })
+        ));
+        assert!(matches!(
+            parse_log_file("00000000000000000010.checkpoint.0000000001.0000000003.parquet"),
+            Some(LogFile::Checkpoint {
+                version: 10,
+                parts: 3
+            })
+        ));
+        assert!(parse_log_file("_last_checkpoint").is_none());
+        assert!(parse_log_file("00000000000000000010.crc").is_none());
+    }
+
+    #[test]
+    fn delta_types() {
+        assert_eq!(data_type(&json!("long")).unwrap(), DataType::Int64);
+        assert_eq!(
+            data_type(&json!("decimal(10,2)")).unwrap(),
+            DataType::Decimal128(10, 2)
+        );
+        let list = data_type(&json!({
+            "type": "array",
+            "elementType": "string",
+            "containsNull": false
+        }))
+        .unwrap();
+        assert!(matches!(list, DataType::List(field) if !field.is_nullable()));
+        assert!(data_type(&json!("interval")).is_err());
+    }
+}
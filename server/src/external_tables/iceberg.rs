@@ -0,0 +1,320 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Current snapshot of an Iceberg table, read from the latest metadata file in its `metadata`
+//! directory and the avro manifest list and manifests of the snapshot.
+
+use std::sync::Arc;
+
+use arrow_schema::{DataType, Field, Fields, Schema, TimeUnit};
+use datafusion::error::DataFusionError;
+use futures_util::TryStreamExt;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use super::lakehouse::{
+    decimal_type, external, map_type, unsupported, DataFile, LakehouseSnapshot, TableRoot,
+};
+
+const METADATA_DIRECTORY: &str = "metadata";
+// status of a manifest entry whose file was deleted in the snapshot
+const ENTRY_DELETED: i32 = 2;
+// content of data files and manifests, the others hold row level deletes
+const CONTENT_DATA: i32 = 0;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TableMetadata {
+    #[serde(default)]
+    current_schema_id: Option<i32>,
+    #[serde(default)]
+    schemas: Vec<IcebergSchema>,
+    // format version 1 only has a single schema
+    #[serde(default)]
+    schema: Option<IcebergSchema>,
+    #[serde(default)]
+    current_snapshot_id: Option<i64>,
+    #[serde(default)]
+    snapshots: Vec<IcebergSnapshot>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct IcebergSchema {
+    #[serde(default)]
+    schema_id: i32,
+    fields: Vec<IcebergField>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct IcebergField {
+    name: String,
+    required: bool,
+    #[serde(rename = "type")]
+    field_type: Value,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct IcebergSnapshot {
+    snapshot_id: i64,
+    #[serde(default)]
+    manifest_list: Option<String>,
+    // format version 1 may list the manifests in the metadata instead
+    #[serde(default)]
+    manifests: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ManifestListEntry {
+    manifest_path: String,
+    #[serde(default)]
+    content: i32,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ManifestEntry {
+    status: i32,
+    data_file: IcebergDataFile,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct IcebergDataFile {
+    #[serde(default)]
+    content: i32,
+    file_path: String,
+    file_format: String,
+    file_size_in_bytes: i64,
+}
+
+// version of a metadata file, named either `v{version}.metadata.json`
+// or `{version:05}-{uuid}.metadata.json`
+fn metadata_version(name: &str) -> Option<u64> {
+    let name = name.strip_suffix(".metadata.json")?;
+    let version = match name.strip_prefix('v') {
+        Some(version) => version,
+        None => name.split_once('-')?.0,
+    };
+    version.parse().ok()
+}
+
+pub async fn load_snapshot(root: &TableRoot) -> Result<LakehouseSnapshot, DataFusionError> {
+    let metadata_dir = root.path.child(METADATA_DIRECTORY);
+    let objects: Vec<_> = root
+        .store
+        .list(Some(&metadata_dir))
+        .await?
+        .try_collect()
+        .await?;
+    let latest = objects
+        .into_iter()
+        .filter_map(|object| {
+            let version = object.location.filename().and_then(metadata_version)?;
+            Some((version, object.location))
+        })
+        .max_by_key(|(version, _)| *version)
+        .ok_or_else(|| DataFusionError::Plan("no iceberg table found".to_string()))?;
+    let metadata: TableMetadata =
+        serde_json::from_slice(&root.get(&latest.1).await?).map_err(external)?;
+
+    let schema = metadata
+        .schemas
+        .iter()
+        .find(|schema| Some(schema.schema_id) == metadata.current_schema_id)
+        .or(metadata.schema.as_ref())
+        .or(metadata.schemas.last())
+        .ok_or_else(|| DataFusionError::Plan("iceberg table has no schema".to_string()))?;
+    let schema = Schema::new(struct_fields(&schema.fields)?);
+
+    let snapshot = metadata.current_snapshot_id.and_then(|id| {
+        metadata
+            .snapshots
+            .iter()
+            .find(|snapshot| snapshot.snapshot_id == id)
+    });
+    let mut manifests = Vec::new();
+    if let Some(snapshot) = snapshot {
+        match &snapshot.manifest_list {
+            Some(manifest_list) => {
+                for entry in read_avro::<ManifestListEntry>(root, manifest_list).await? {
+                    if entry.content != CONTENT_DATA {
+                        return Err(unsupported("iceberg tables with row level deletes"));
+                    }
+                    manifests.push(entry.manifest_path);
+                }
+            }
+            None => manifests.extend(snapshot.manifests.iter().cloned()),
+        }
+    }
+
+    let mut files = Vec::new();
+    for manifest in manifests {
+        for entry in read_avro::<ManifestEntry>(root, &manifest).await? {
+            if entry.status == ENTRY_DELETED {
+                continue;
+            }
+            let file = entry.data_file;
+            if file.content != CONTENT_DATA {
+                return Err(unsupported("iceberg tables with row level deletes"));
+            }
+            if !file.file_format.eq_ignore_ascii_case("parquet") {
+                return Err(unsupported(format!(
+                    "iceberg data files in {} format",
+                    file.file_format
+                )));
+            }
+            files.push(DataFile {
+                path: root.resolve(&file.file_path)?,
+                size: file.file_size_in_bytes as u64,
+                partition_values: Vec::new(),
+            });
+        }
+    }
+
+    // partitions are hidden, partition columns are stored in the data files as well
+    Ok(LakehouseSnapshot {
+        schema,
+        partition_columns: Vec::new(),
+        files,
+    })
+}
+
+async fn read_avro<T: DeserializeOwned>(
+    root: &TableRoot,
+    file: &str,
+) -> Result<Vec<T>, DataFusionError> {
+    let bytes = root.get(&root.resolve(file)?).await?;
+    let reader = apache_avro::Reader::new(&bytes[..]).map_err(external)?;
+    reader
+        .map(|value| apache_avro::from_value(&value.map_err(external)?).map_err(external))
+        .collect()
+}
+
+fn struct_fields(fields: &[IcebergField]) -> Result<Fields, DataFusionError> {
+    fields
+        .iter()
+        .map(|field| {
+            Ok(Field::new(
+                &field.name,
+                data_type(&field.field_type)?,
+                !field.required,
+            ))
+        })
+        .collect()
+}
+
+fn data_type(value: &Value) -> Result<DataType, DataFusionError> {
+    let data_type = match value {
+        Value::String(name) => match name.as_str() {
+            "boolean" => DataType::Boolean,
+            "int" => DataType::Int32,
+            "long" => DataType::Int64,
+            "float" => DataType::Float32,
+            "double" => DataType::Float64,
+            "date" => DataType::Date32,
+            "time" => DataType::Time64(TimeUnit::Microsecond),
+            "timestamp" => DataType::Timestamp(TimeUnit::Microsecond, None),
+            "timestamptz" => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            "timestamp_ns" => DataType::Timestamp(TimeUnit::Nanosecond, None),
+            "timestamptz_ns" => DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())),
+            "string" => DataType::Utf8,
+            "uuid" => DataType::FixedSizeBinary(16),
+            "binary" => DataType::Binary,
+            name => match name
+                .strip_prefix("fixed[")
+                .and_then(|length| length.strip_suffix(']'))
+            {
+                Some(length) => DataType::FixedSizeBinary(
+                    length
+                        .parse()
+                        .map_err(|_| unsupported(format!("iceberg type {name}")))?,
+                ),
+                None => {
+                    decimal_type(name).ok_or_else(|| unsupported(format!("iceberg type {name}")))?
+                }
+            },
+        },
+        Value::Object(object) => match object.get("type").and_then(Value::as_str) {
+            Some("struct") => {
+                let fields: Vec<IcebergField> =
+                    serde_json::from_value(object["fields"].clone()).map_err(external)?;
+                DataType::Struct(struct_fields(&fields)?)
+            }
+            Some("list") => {
+                let required = object
+                    .get("element-required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+                let element = Field::new("element", data_type(&object["element"])?, !required);
+                DataType::List(Arc::new(element))
+            }
+            Some("map") => map_type(
+                data_type(&object["key"])?,
+                data_type(&object["value"])?,
+                !object
+                    .get("value-required")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            ),
+            _ => return Err(unsupported(format!("iceberg type {value}"))),
+        },
+        _ => return Err(unsupported(format!("iceberg type {value}"))),
+    };
+    Ok(data_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_schema::DataType;
+    use serde_json::json;
+
+    use super::{data_type, metadata_version};
+
+    #[test]
+    fn metadata_file_names() {
+        assert_eq!(metadata_version("v3.metadata.json"), Some(3));
+        assert_eq!(
+            metadata_version("00012-8b2e0a3c-4c1f-4a5e-9d1b-3f0e6a7c2d11.metadata.json"),
+            Some(12)
+        );
+        assert_eq!(metadata_version("version-hint.text"), None);
+        assert_eq!(metadata_version("snap-1-1-uuid.avro"), None);
+    }
+
+    #[test]
+    fn iceberg_types() {
+        assert_eq!(
+            data_type(&json!("fixed[16]")).unwrap(),
+            DataType::FixedSizeBinary(16)
+        );
+        assert_eq!(
+            data_type(&json!("decimal(9, 2)")).unwrap(),
+            DataType::Decimal128(9, 2)
+        );
+        let map = data_type(&json!({
+            "type": "map",
+            "key-id": 4,
+            "key": "string",
+            "value-id": 5,
+            "value": "long",
+            "value-required": true
+        }))
+        .unwrap();
+        assert!(matches!(map, DataType::Map(..)));
+    }
+}
@@ -0,0 +1,266 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Tables whose data files are tracked by a lakehouse format, Delta Lake or Iceberg, instead of
+//! being every file under a prefix.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use bytes::Bytes;
+use datafusion::common::{ScalarValue, ToDFSchema};
+use datafusion::datasource::file_format::parquet::ParquetFormat;
+use datafusion::datasource::file_format::FileFormat;
+use datafusion::datasource::listing::PartitionedFile;
+use datafusion::datasource::physical_plan::FileScanConfig;
+use datafusion::datasource::TableProvider;
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::SessionState;
+use datafusion::execution::object_store::ObjectStoreUrl;
+use datafusion::logical_expr::{Expr, TableProviderFilterPushDown, TableType};
+use datafusion::optimizer::utils::conjunction;
+use datafusion::physical_expr::create_physical_expr;
+use datafusion::physical_plan::{ExecutionPlan, Statistics};
+use object_store::path::Path;
+use object_store::ObjectStore;
+use url::Url;
+
+/// Directory of a table in an object store
+pub struct TableRoot {
+    pub url: Url,
+    pub store: Arc<dyn ObjectStore>,
+    pub path: Path,
+}
+
+impl TableRoot {
+    pub async fn get(&self, path: &Path) -> Result<Bytes, DataFusionError> {
+        Ok(self.store.get(path).await?.bytes().await?)
+    }
+
+    /// Path of a file referenced by the table metadata, either relative to the table root or
+    /// an absolute uri which has to be in the same bucket
+    pub fn resolve(&self, file: &str) -> Result<Path, DataFusionError> {
+        if !file.contains(':') {
+            let relative = Path::from_url_path(file).map_err(external)?;
+            return Ok(Path::from_iter(self.path.parts().chain(relative.parts())));
+        }
+
+        let url = Url::parse(file).map_err(external)?;
+        let same_store = match url.scheme() {
+            "file" => self.url.scheme() == "file",
+            "s3" | "s3a" | "s3n" => {
+                self.url.scheme() == "s3" && url.host_str() == self.url.host_str()
+            }
+            _ => false,
+        };
+        if !same_store {
+            return Err(DataFusionError::NotImplemented(format!(
+                "data file {file} is outside of the table's bucket"
+            )));
+        }
+        Path::from_url_path(url.path()).map_err(external)
+    }
+}
+
+pub fn external(err: impl std::error::Error + Send + Sync + 'static) -> DataFusionError {
+    DataFusionError::External(Box::new(err))
+}
+
+pub fn unsupported(what: impl std::fmt::Display) -> DataFusionError {
+    DataFusionError::NotImplemented(what.to_string())
+}
+
+/// Map type with the layout the parquet writers of Spark and Iceberg use
+pub fn map_type(key: DataType, value: DataType, value_nullable: bool) -> DataType {
+    let entries = Field::new(
+        "key_value",
+        DataType::Struct(
+            vec![
+                Field::new("key", key, false),
+                Field::new("value", value, value_nullable),
+            ]
+            .into(),
+        ),
+        false,
+    );
+    DataType::Map(Arc::new(entries), false)
+}
+
+/// Parse a decimal type written as `decimal(precision, scale)`
+pub fn decimal_type(name: &str) -> Option<DataType> {
+    let (precision, scale) = name
+        .strip_prefix("decimal(")?
+        .strip_suffix(')')?
+        .split_once(',')?;
+    Some(DataType::Decimal128(
+        precision.trim().parse().ok()?,
+        scale.trim().parse().ok()?,
+    ))
+}
+
+/// Data file of the current version of a table
+pub struct DataFile {
+    pub path: Path,
+    pub size: u64,
+    /// value of every partition column, as written in the table metadata
+    pub partition_values: Vec<Option<String>>,
+}
+
+/// Current version of a table
+pub struct LakehouseSnapshot {
+    /// columns stored in the data files
+    pub schema: Schema,
+    /// columns stored in the table metadata only, appended to the columns of the files
+    pub partition_columns: Vec<Field>,
+    pub files: Vec<DataFile>,
+}
+
+impl LakehouseSnapshot {
+    pub fn table_schema(&self) -> Schema {
+        let fields = self
+            .schema
+            .fields()
+            .iter()
+            .map(|field| field.as_ref().clone())
+            .chain(self.partition_columns.iter().cloned());
+        Schema::new(fields.collect::<Vec<_>>())
+    }
+
+    pub fn into_table(self, url: &Url) -> Result<LakehouseTable, DataFusionError> {
+        let table_schema = Arc::new(self.table_schema());
+        let mut files = Vec::with_capacity(self.files.len());
+        for file in self.files {
+            let mut partitioned_file = PartitionedFile::new(file.path.to_string(), file.size);
+            partitioned_file.object_meta.location = file.path;
+            partitioned_file.partition_values = self
+                .partition_columns
+                .iter()
+                .zip(file.partition_values)
+                .map(|(column, value)| match value {
+                    Some(value) => ScalarValue::try_from_string(value, column.data_type()),
+                    None => ScalarValue::try_from(column.data_type()),
+                })
+                .collect::<Result<_, _>>()?;
+            files.push(partitioned_file);
+        }
+
+        Ok(LakehouseTable {
+            url: ObjectStoreUrl::parse(url)?,
+            file_schema: Arc::new(self.schema),
+            partition_columns: self
+                .partition_columns
+                .iter()
+                .map(|column| (column.name().clone(), column.data_type().clone()))
+                .collect(),
+            table_schema,
+            files,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LakehouseTable {
+    url: ObjectStoreUrl,
+    file_schema: SchemaRef,
+    partition_columns: Vec<(String, DataType)>,
+    table_schema: SchemaRef,
+    files: Vec<PartitionedFile>,
+}
+
+#[async_trait::async_trait]
+impl TableProvider for LakehouseTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.table_schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        let target_partitions = state.config_options().execution.target_partitions.max(1);
+        let mut file_groups = vec![Vec::new(); target_partitions.min(self.files.len()).max(1)];
+        let group_count = file_groups.len();
+        for (index, file) in self.files.iter().enumerate() {
+            file_groups[index % group_count].push(file.clone());
+        }
+
+        // row groups are pruned with the filters on columns of the files,
+        // filters on partition columns are applied after the scan
+        let file_filters: Vec<Expr> = filters
+            .iter()
+            .filter(|filter| {
+                filter.to_columns().is_ok_and(|columns| {
+                    columns
+                        .iter()
+                        .all(|column| self.file_schema.field_with_name(&column.name).is_ok())
+                })
+            })
+            .cloned()
+            .collect();
+        let predicate = match conjunction(file_filters) {
+            Some(expr) => {
+                let df_schema = self.file_schema.as_ref().clone().to_dfschema()?;
+                Some(create_physical_expr(
+                    &expr,
+                    &df_schema,
+                    &self.file_schema,
+                    state.execution_props(),
+                )?)
+            }
+            None => None,
+        };
+
+        ParquetFormat::default()
+            .with_enable_pruning(Some(true))
+            .create_physical_plan(
+                state,
+                FileScanConfig {
+                    object_store_url: self.url.clone(),
+                    file_schema: self.file_schema.clone(),
+                    file_groups,
+                    statistics: Statistics::default(),
+                    projection: projection.cloned(),
+                    limit,
+                    output_ordering: Vec::new(),
+                    table_partition_cols: self.partition_columns.clone(),
+                    infinite_source: false,
+                },
+                predicate.as_ref(),
+            )
+            .await
+    }
+
+    fn supports_filter_pushdown(
+        &self,
+        _filter: &Expr,
+    ) -> Result<TableProviderFilterPushDown, DataFusionError> {
+        Ok(TableProviderFilterPushDown::Inexact)
+    }
+}
@@ -0,0 +1,475 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! `parseable fsck`, run instead of the server to check the objects of a log stream during
+//! incident recovery. Nothing is written, problems are printed with a plan to repair them.
+//!
+//! ```text
+//! parseable fsck --stream app s3-store
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use arrow_schema::Schema;
+use chrono::{DateTime, Duration, Utc};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use futures::TryStreamExt;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use parquet::file::footer::{decode_footer, decode_metadata};
+use relative_path::RelativePath;
+
+use crate::catalog::manifest::Manifest;
+use crate::option::{store_commands, CONFIG};
+use crate::storage::consistency::ORPHAN_GRACE_PERIOD_MINUTES;
+use crate::storage::{
+    compression, ObjectStoreFormat, SCHEMA_FILE_NAME, STREAM_METADATA_FILE_NAME,
+    STREAM_ROOT_DIRECTORY,
+};
+
+pub const FSCK: &str = "fsck";
+
+// length of the footer length and the magic bytes at the end of a parquet file
+const FOOTER_SIZE: usize = 8;
+
+pub fn command() -> Command {
+    Command::new(FSCK)
+        .about("Check the metadata, manifests and parquet files of a log stream in the object store, without a running server")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .value_name("STREAM")
+                .required(true)
+                .help("Name of the log stream"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .action(ArgAction::SetTrue)
+                .help("Print the report as json"),
+        )
+        .subcommands(store_commands())
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum Problem {
+    /// the metadata file of the stream itself, ingesters have their own
+    MissingStreamMetadata {
+        path: String,
+    },
+    InvalidStreamMetadata {
+        path: String,
+        error: String,
+    },
+    InvalidSchema {
+        path: String,
+        error: String,
+    },
+    MissingManifest {
+        path: String,
+        snapshot: String,
+    },
+    InvalidManifest {
+        path: String,
+        error: String,
+    },
+    MissingFile {
+        path: String,
+        manifest: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    SizeMismatch {
+        path: String,
+        manifest: String,
+        manifest_size: u64,
+        actual_size: u64,
+    },
+    CorruptFile {
+        path: String,
+        manifest: String,
+        error: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    RowCountMismatch {
+        path: String,
+        manifest: String,
+        manifest_rows: u64,
+        actual_rows: u64,
+    },
+    /// parquet file not referenced by any manifest
+    OrphanedFile {
+        path: String,
+    },
+}
+
+impl Problem {
+    /// Step of the repair plan for this problem
+    pub fn repair(&self) -> String {
+        match self {
+            Problem::MissingStreamMetadata { path } => format!(
+                "restore {path} from a backup, or copy the metadata file of an ingester of the stream to it"
+            ),
+            Problem::InvalidStreamMetadata { path, .. } => {
+                format!("restore {path} from a backup")
+            }
+            Problem::InvalidSchema { path, .. } => format!(
+                "restore {path} from a backup, or replace it with the schema of a parquet file of the stream"
+            ),
+            Problem::MissingManifest { path, snapshot } => format!(
+                "drop {path} from the snapshot in {snapshot}, the files it listed are no longer queried"
+            ),
+            Problem::InvalidManifest { path, .. } => format!(
+                "restore {path} from a backup, or drop it from the snapshots listing it"
+            ),
+            Problem::MissingFile { path, manifest } => {
+                format!("drop {path} from {manifest}")
+            }
+            Problem::SizeMismatch {
+                path,
+                manifest,
+                actual_size,
+                ..
+            } => format!("set the size of {path} in {manifest} to {actual_size}"),
+            Problem::CorruptFile { path, manifest, .. } => format!(
+                "move {path} out of the stream and drop it from {manifest}, its events are lost unless a copy exists"
+            ),
+            Problem::RowCountMismatch {
+                path,
+                manifest,
+                actual_rows,
+                ..
+            } => format!("set the number of rows of {path} in {manifest} to {actual_rows}"),
+            Problem::OrphanedFile { path } => format!(
+                "add {path} to the manifest of its date, or delete it if its events are in another file"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MissingStreamMetadata { path } => write!(f, "{path} does not exist"),
+            Problem::InvalidStreamMetadata { path, error }
+            | Problem::InvalidSchema { path, error }
+            | Problem::InvalidManifest { path, error } => write!(f, "{path} is invalid: {error}"),
+            Problem::MissingManifest { path, snapshot } => {
+                write!(f, "{path} is listed in {snapshot} but does not exist")
+            }
+            Problem::MissingFile { path, manifest } => {
+                write!(f, "{path} is listed in {manifest} but does not exist")
+            }
+            Problem::SizeMismatch {
+                path,
+                manifest_size,
+                actual_size,
+                ..
+            } => write!(
+                f,
+                "{path} has {actual_size} bytes, its manifest says {manifest_size}"
+            ),
+            Problem::CorruptFile { path, error, .. } => {
+                write!(f, "{path} has no valid parquet footer: {error}")
+            }
+            Problem::RowCountMismatch {
+                path,
+                manifest_rows,
+                actual_rows,
+                ..
+            } => write!(
+                f,
+                "{path} has {actual_rows} rows, its manifest says {manifest_rows}"
+            ),
+            Problem::OrphanedFile { path } => write!(f, "{path} is not listed in any manifest"),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckReport {
+    pub stream: String,
+    pub metadata_files: usize,
+    pub manifests: usize,
+    pub files: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Run `parseable fsck`, `matches` are those of the command. Fails if problems are found,
+/// so that the exit code tells whether the stream is consistent.
+pub async fn run(matches: &ArgMatches) -> anyhow::Result<()> {
+    let stream = matches
+        .get_one::<String>("stream")
+        .expect("stream is required");
+    let storage = CONFIG.storage();
+    let store = storage.get_datafusion_object_store()?;
+    let prefix = storage
+        .get_object_store()
+        .absolute_url(RelativePath::new(stream));
+
+    let report = check(store.as_ref(), stream, &prefix).await?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+
+    if !report.problems.is_empty() {
+        anyhow::bail!(
+            "found {} problems in log stream {stream}",
+            report.problems.len()
+        );
+    }
+    Ok(())
+}
+
+fn print_report(report: &FsckReport) {
+    println!(
+        "Checked {} metadata files, {} manifests and {} parquet files of log stream {}",
+        report.metadata_files, report.manifests, report.files, report.stream
+    );
+    if report.problems.is_empty() {
+        println!("No problems found");
+        return;
+    }
+
+    println!("\nProblems:");
+    for problem in &report.problems {
+        println!("  - {problem}");
+    }
+    println!("\nRepair plan, with the server stopped:");
+    for (step, problem) in report.problems.iter().enumerate() {
+        println!("  {}. {}", step + 1, problem.repair());
+    }
+}
+
+/// Check the objects of the stream below `prefix`, reading only the footers of parquet files
+pub async fn check(
+    store: &dyn ObjectStore,
+    stream: &str,
+    prefix: &Path,
+) -> anyhow::Result<FsckReport> {
+    let objects: Vec<_> = store.list(Some(prefix)).await?.try_collect().await?;
+    let sizes: HashMap<String, (u64, DateTime<Utc>)> = objects
+        .iter()
+        .map(|meta| {
+            (
+                meta.location.to_string(),
+                (meta.size as u64, meta.last_modified),
+            )
+        })
+        .collect();
+
+    let mut report = FsckReport {
+        stream: stream.to_owned(),
+        ..FsckReport::default()
+    };
+
+    let stream_json = prefix
+        .child(STREAM_ROOT_DIRECTORY)
+        .child(STREAM_METADATA_FILE_NAME)
+        .to_string();
+    if !sizes.contains_key(&stream_json) {
+        report
+            .problems
+            .push(Problem::MissingStreamMetadata { path: stream_json });
+    }
+
+    // manifests listed in the snapshots, with the first metadata file listing them
+    let mut snapshots = HashMap::new();
+    for meta in &objects {
+        let in_stream_root = meta
+            .location
+            .parts()
+            .any(|part| part.as_ref() == STREAM_ROOT_DIRECTORY);
+        let Some(name) = meta.location.filename().filter(|_| in_stream_root) else {
+            continue;
+        };
+        let path = meta.location.to_string();
+
+        if name.ends_with(STREAM_METADATA_FILE_NAME) {
+            report.metadata_files += 1;
+            let bytes = compression::read(store, &meta.location).await?;
+            match serde_json::from_slice::<ObjectStoreFormat>(&bytes) {
+                Ok(format) => {
+                    for item in format.snapshot.manifest_list {
+                        snapshots
+                            .entry(item.manifest_path)
+                            .or_insert_with(|| path.clone());
+                    }
+                }
+                Err(err) => report.problems.push(Problem::InvalidStreamMetadata {
+                    path,
+                    error: err.to_string(),
+                }),
+            }
+        } else if name.ends_with(SCHEMA_FILE_NAME) {
+            report.metadata_files += 1;
+            let bytes = store.get(&meta.location).await?.bytes().await?;
+            if let Err(err) = serde_json::from_slice::<Schema>(&bytes) {
+                report.problems.push(Problem::InvalidSchema {
+                    path,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    let mut referenced = HashSet::new();
+    let mut snapshots: Vec<_> = snapshots.into_iter().collect();
+    snapshots.sort();
+    for (manifest_path, snapshot) in snapshots {
+        if !sizes.contains_key(&manifest_path) {
+            report.problems.push(Problem::MissingManifest {
+                path: manifest_path,
+                snapshot,
+            });
+            continue;
+        }
+        let bytes = compression::read(store, &Path::parse(&manifest_path)?).await?;
+        let manifest: Manifest = match serde_json::from_slice(&bytes) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                report.problems.push(Problem::InvalidManifest {
+                    path: manifest_path,
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+        report.manifests += 1;
+
+        for file in manifest.files {
+            report.files += 1;
+            referenced.insert(file.file_path.clone());
+            let Some((size, _)) = sizes.get(&file.file_path) else {
+                report.problems.push(Problem::MissingFile {
+                    path: file.file_path,
+                    manifest: manifest_path.clone(),
+                });
+                continue;
+            };
+            if *size != file.file_size {
+                report.problems.push(Problem::SizeMismatch {
+                    path: file.file_path.clone(),
+                    manifest: manifest_path.clone(),
+                    manifest_size: file.file_size,
+                    actual_size: *size,
+                });
+            }
+
+            match read_row_count(store, &Path::parse(&file.file_path)?, *size as usize).await {
+                Ok(rows) if rows != file.num_rows => {
+                    report.problems.push(Problem::RowCountMismatch {
+                        path: file.file_path,
+                        manifest: manifest_path.clone(),
+                        manifest_rows: file.num_rows,
+                        actual_rows: rows,
+                    })
+                }
+                Ok(_) => (),
+                Err(err) => report.problems.push(Problem::CorruptFile {
+                    path: file.file_path,
+                    manifest: manifest_path.clone(),
+                    error: err.to_string(),
+                }),
+            }
+        }
+    }
+
+    let grace_cutoff = Utc::now() - Duration::minutes(ORPHAN_GRACE_PERIOD_MINUTES);
+    let mut orphaned: Vec<_> = sizes
+        .iter()
+        .filter(|(path, (_, last_modified))| {
+            path.ends_with(".parquet")
+                && !referenced.contains(*path)
+                && *last_modified < grace_cutoff
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    orphaned.sort();
+    report.problems.extend(
+        orphaned
+            .into_iter()
+            .map(|path| Problem::OrphanedFile { path }),
+    );
+
+    Ok(report)
+}
+
+// number of rows in the footer of a parquet file, without downloading the rest of it
+async fn read_row_count(store: &dyn ObjectStore, path: &Path, size: usize) -> anyhow::Result<u64> {
+    if size < FOOTER_SIZE {
+        anyhow::bail!("file has only {size} bytes");
+    }
+    let tail = store.get_range(path, size - FOOTER_SIZE..size).await?;
+    let tail: [u8; FOOTER_SIZE] = tail.as_ref().try_into()?;
+    let metadata_len = decode_footer(&tail)?;
+    if metadata_len > size - FOOTER_SIZE {
+        anyhow::bail!("footer of {metadata_len} bytes is larger than the file");
+    }
+    let start = size - FOOTER_SIZE - metadata_len;
+    let metadata = decode_metadata(&store.get_range(path, start..size - FOOTER_SIZE).await?)?;
+    Ok(metadata.file_metadata().num_rows() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow_array::{Int64Array, RecordBatch};
+    use arrow_schema::{DataType, Field, Schema};
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+    use parquet::arrow::ArrowWriter;
+
+    use super::read_row_count;
+
+    #[tokio::test]
+    async fn row_count_is_read_from_the_footer() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "status",
+            DataType::Int64,
+            true,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![200, 404, 500]))],
+        )
+        .unwrap();
+        let mut data = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut data, schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let store = InMemory::new();
+        let path = Path::from("app/date=2024-01-01/data.parquet");
+        store.put(&path, data.clone().into()).await.unwrap();
+        assert_eq!(read_row_count(&store, &path, data.len()).await.unwrap(), 3);
+
+        let truncated = Path::from("app/date=2024-01-01/truncated.parquet");
+        let len = data.len() - 20;
+        store
+            .put(&truncated, data[..len].to_vec().into())
+            .await
+            .unwrap();
+        assert!(read_row_count(&store, &truncated, len).await.is_err());
+    }
+}
@@ -21,11 +21,15 @@ pub mod livetail;
 
 const PREFIX_TAGS: &str = "x-p-tag-";
 const PREFIX_META: &str = "x-p-meta-";
+const TAGS_KEY: &str = "x-p-tags";
 const STREAM_NAME_HEADER_KEY: &str = "x-p-stream";
 const LOG_SOURCE_KEY: &str = "x-p-log-source";
 const TIME_PARTITION_KEY: &str = "x-p-time-partition";
+const CUSTOM_PARTITION_KEY: &str = "x-p-custom-partition";
 const STATIC_SCHEMA_FLAG: &str = "x-p-static-schema-flag";
+const TRANSACTION_ID_KEY: &str = "x-p-transaction-id";
 const AUTHORIZATION_KEY: &str = "authorization";
+const REQUEST_ID_KEY: &str = "x-request-id";
 const SEPARATOR: char = '^';
 
 const OIDC_SCOPE: &str = "openid profile email";
@@ -40,5 +44,16 @@ const LOG_SOURCE_KINESIS: &str = "kinesis";
 // specification as explained here https://opentelemetry.io/docs/specs/otel/logs/data-model/
 const LOG_SOURCE_OTEL: &str = "otel";
 
+// CloudWatch Logs subscription payloads, sent through Firehose or by a Lambda function
+const LOG_SOURCE_CLOUDWATCH: &str = "cloudwatch";
+
+// Windows Event Log events, as XML or as JSON dumped from EVTX files
+const LOG_SOURCE_WINDOWS_EVENT: &str = "windows-event";
+
+// security events, normalized to a common schema
+const LOG_SOURCE_AUDITD: &str = "auditd";
+const LOG_SOURCE_FALCO: &str = "falco";
+const LOG_SOURCE_TETRAGON: &str = "tetragon";
+
 // AWS Kinesis constants
 const KINESIS_COMMON_ATTRIBUTES_KEY: &str = "x-amz-firehose-common-attributes";
@@ -23,22 +23,40 @@ use serde_json::Value;
 
 use crate::option::CONFIG;
 
+use self::cluster::node_client::CLIENT;
 use self::{cluster::get_ingester_info, query::Query};
 
 pub(crate) mod about;
+pub(crate) mod alerts;
+pub(crate) mod backup;
+pub(crate) mod cloudwatch;
 pub mod cluster;
+pub(crate) mod dashboards;
+pub(crate) mod detections;
+pub(crate) mod external_tables;
+pub(crate) mod filters;
 pub(crate) mod health_check;
 pub(crate) mod ingest;
+mod journald;
 mod kinesis;
 pub(crate) mod llm;
 pub(crate) mod logstream;
 pub(crate) mod middleware;
 pub mod modal;
 pub(crate) mod oidc;
+pub(crate) mod openapi;
 mod otel;
+pub(crate) mod problem;
+pub(crate) mod profiling;
 pub(crate) mod query;
 pub(crate) mod rbac;
+pub(crate) mod reports;
 pub(crate) mod role;
+mod security;
+pub(crate) mod silences;
+mod spool;
+pub(crate) mod stream_templates;
+mod windows;
 
 pub const MAX_EVENT_PAYLOAD_SIZE: usize = 10485760;
 pub const API_BASE_PATH: &str = "api";
@@ -106,11 +124,12 @@ pub async fn send_query_request_to_ingester(query: &Query) -> anyhow::Result<Vec
             base_path_without_preceding_slash(),
             "query"
         );
-        let reqw = reqwest::Client::new()
+        let reqw = CLIENT
             .post(uri)
             .json(query)
-            .header(http::header::AUTHORIZATION, im.token.clone())
+            .header(http::header::AUTHORIZATION, im.authorization())
             .header(http::header::CONTENT_TYPE, "application/json")
+            .timeout(CONFIG.parseable.cluster_request_timeout)
             .send()
             .await;
 
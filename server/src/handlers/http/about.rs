@@ -48,6 +48,12 @@ use std::path::PathBuf;
 ///         "path": store_endpoint
 ///     }
 /// }
+#[utoipa::path(
+    get,
+    path = "/api/v1/about",
+    tag = "server",
+    responses((status = 200, description = "Version, mode and storage of the server", body = Object))
+)]
 pub async fn about() -> Json<serde_json::Value> {
     let meta = StorageMetadata::global();
 
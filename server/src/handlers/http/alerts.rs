@@ -0,0 +1,157 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, Responder};
+use chrono::{DateTime, Duration, Utc};
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use http::StatusCode;
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::alerts::history::ALERT_HISTORY_STREAM_NAME;
+use crate::event::commit_schema;
+use crate::handlers::http::fetch_schema;
+use crate::handlers::http::problem::Problem;
+use crate::handlers::http::query::authorize_query;
+use crate::metadata::STREAM_INFO;
+use crate::option::{Mode, CONFIG};
+use crate::query::QUERY_SESSION;
+use crate::rbac::Users;
+use crate::storage::object_storage::commit_schema_to_storage;
+use crate::utils::actix::extract_session_key_from_req;
+
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1000;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryParams {
+    /// a day before the end if not set
+    start: Option<DateTime<Utc>>,
+    /// now if not set
+    end: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+// Handler for GET /api/v1/alerts/{id}/history
+// latest evaluations of the alert first, of the streams the user is allowed to query
+pub async fn history(
+    req: HttpRequest,
+    id: web::Path<String>,
+    params: web::Query<HistoryParams>,
+) -> Result<impl Responder, AlertHistoryError> {
+    let id = Ulid::from_string(&id).map_err(|_| AlertHistoryError::InvalidId(id.into_inner()))?;
+    let end = params.end.unwrap_or_else(Utc::now);
+    let start = params.start.unwrap_or(end - Duration::days(1));
+    if start >= end {
+        return Err(AlertHistoryError::InvalidRange);
+    }
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .min(MAX_HISTORY_LIMIT);
+
+    if CONFIG.parseable.mode == Mode::Query {
+        // the evaluations are written by the ingesters
+        if let Ok(schema) = fetch_schema(ALERT_HISTORY_STREAM_NAME).await {
+            commit_schema_to_storage(ALERT_HISTORY_STREAM_NAME, schema.clone()).await?;
+            commit_schema(ALERT_HISTORY_STREAM_NAME, Arc::new(schema))?;
+        }
+    }
+    // nothing was evaluated yet
+    if !STREAM_INFO.stream_exists(ALERT_HISTORY_STREAM_NAME)
+        || STREAM_INFO
+            .schema(ALERT_HISTORY_STREAM_NAME)?
+            .field_with_name("alert_id")
+            .is_err()
+    {
+        return Ok(web::Json(Vec::new()));
+    }
+
+    let sql = format!(
+        "SELECT * FROM {ALERT_HISTORY_STREAM_NAME} WHERE alert_id = '{id}' ORDER BY p_timestamp DESC LIMIT {limit}"
+    );
+    let query = crate::query::Query {
+        raw_logical_plan: QUERY_SESSION.state().create_logical_plan(&sql).await?,
+        start,
+        end,
+        filter_tag: None,
+        at: None,
+        raw: false,
+    };
+    let (records, _) = query
+        .execute(ALERT_HISTORY_STREAM_NAME.to_owned())
+        .await
+        .map_err(|err| AlertHistoryError::Query(err.to_string()))?;
+    let rows = record_batches_to_json_rows(&records.iter().collect::<Vec<_>>())
+        .map_err(|err| AlertHistoryError::Query(err.to_string()))?;
+
+    let key = extract_session_key_from_req(&req).map_err(|_| AlertHistoryError::Unauthorized)?;
+    let permissions = Users.get_permissions(&key);
+    let evaluations: Vec<Value> = rows
+        .into_iter()
+        .filter(|row| {
+            row.get("stream")
+                .and_then(Value::as_str)
+                .is_some_and(|stream| authorize_query(&permissions, stream).is_ok())
+        })
+        .map(Value::Object)
+        .collect();
+
+    Ok(web::Json(evaluations))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AlertHistoryError {
+    #[error("Invalid alert id {0}")]
+    InvalidId(String),
+    #[error("Start of the history has to be before its end")]
+    InvalidRange,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Could not query the alert history: {0}")]
+    Query(String),
+    #[error("Could not plan the alert history query: {0}")]
+    Datafusion(#[from] datafusion::error::DataFusionError),
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] crate::storage::ObjectStorageError),
+    #[error("{0}")]
+    Metadata(#[from] crate::metadata::error::stream_info::MetadataError),
+    #[error("{0}")]
+    Event(#[from] crate::event::error::EventError),
+}
+
+impl actix_web::ResponseError for AlertHistoryError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::InvalidId(_) | Self::InvalidRange => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::FORBIDDEN,
+            Self::Query(_)
+            | Self::Datafusion(_)
+            | Self::ObjectStorage(_)
+            | Self::Metadata(_)
+            | Self::Event(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
@@ -0,0 +1,433 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Backups of the metadata of the deployment: users, roles, dashboards, filters, alerts,
+//! the ingester registry and everything else under `.parseable`, and the metadata and
+//! schemas of every log stream. Data files, their manifests and the snapshot log are not
+//! backed up, and restoring stream metadata keeps the current snapshot and stats of the stream.
+
+use actix_web::{web, HttpRequest, Responder};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use http::StatusCode;
+use relative_path::{RelativePath, RelativePathBuf};
+
+use crate::catalog::SNAPSHOT_LOG_DIR;
+use crate::handlers::http::problem::Problem;
+use crate::migration::migration_backups_path;
+use crate::option::CONFIG;
+use crate::storage::object_storage::alert_json_path;
+use crate::storage::{
+    ObjectStorageError, ObjectStoreFormat, PARSEABLE_ROOT_DIRECTORY, STREAM_METADATA_FILE_NAME,
+    STREAM_ROOT_DIRECTORY,
+};
+use crate::utils::actix::request_username;
+
+const BACKUPS_DIRECTORY: &str = "backups";
+const CURRENT_BACKUP_VERSION: &str = "v1";
+
+/// A backup, written as a single object
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataBackup {
+    pub version: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: String,
+    pub objects: Vec<BackupObject>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BackupObject {
+    /// path relative to the root of the object store
+    pub path: String,
+    /// base64 encoded content
+    pub content: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupSummary {
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupListing {
+    pub name: String,
+    pub size: usize,
+    pub last_modified: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreQuery {
+    /// list the objects which would be restored, without writing them
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreSummary {
+    pub name: String,
+    pub dry_run: bool,
+    pub objects: Vec<String>,
+}
+
+fn backups_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, BACKUPS_DIRECTORY])
+}
+
+fn backup_path(name: &str) -> Result<RelativePathBuf, BackupError> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(BackupError::NotFound(name.to_owned()));
+    }
+    Ok(backups_path().join(format!("{name}.json")))
+}
+
+// the snapshot log of a stream, which only ever grows with its commits
+fn is_snapshot_log(path: &RelativePath) -> bool {
+    let mut components = path.components().map(|component| component.as_str());
+    components.any(|component| component == STREAM_ROOT_DIRECTORY)
+        && components.next() == Some(SNAPSHOT_LOG_DIR)
+}
+
+// metadata of a stream, written by the querier or by an ingester
+fn is_stream_metadata(path: &RelativePath) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.ends_with(STREAM_METADATA_FILE_NAME))
+        && path
+            .components()
+            .any(|component| component.as_str() == STREAM_ROOT_DIRECTORY)
+}
+
+// settings of the stream from the backup, with the snapshot and stats of the current metadata
+// so that data committed since the backup stays listed
+fn restore_stream_metadata(backup: &[u8], current: &[u8]) -> Result<Vec<u8>, serde_json::Error> {
+    let current: ObjectStoreFormat = serde_json::from_slice(current)?;
+    let mut restored: ObjectStoreFormat = serde_json::from_slice(backup)?;
+    restored.snapshot = current.snapshot;
+    restored.stats = current.stats;
+    restored.first_event_at = current.first_event_at;
+    serde_json::to_vec(&restored)
+}
+
+// every object below `prefix`, with its path relative to the root of the object store
+async fn list_objects(prefix: &RelativePath) -> Result<Vec<RelativePathBuf>, BackupError> {
+    let storage = CONFIG.storage();
+    let store = storage.get_datafusion_object_store()?;
+    let root = storage.get_object_store().absolute_url(prefix);
+
+    let objects: Vec<_> = match store.list(Some(&root)).await {
+        Ok(objects) => objects.try_collect().await?,
+        Err(object_store::Error::NotFound { .. }) => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(objects
+        .into_iter()
+        .filter_map(|meta| {
+            let parts = meta.location.prefix_match(&root)?;
+            Some(parts.fold(prefix.to_owned(), |path, part| path.join(part.as_ref())))
+        })
+        .collect())
+}
+
+// metadata objects of the deployment and of every log stream, earlier backups excluded
+async fn metadata_paths() -> Result<Vec<RelativePathBuf>, BackupError> {
+    let store = CONFIG.storage().get_object_store();
+    let backups = backups_path();
+    let migration_backups = migration_backups_path();
+
+    let mut paths: Vec<_> = list_objects(RelativePath::new(PARSEABLE_ROOT_DIRECTORY))
+        .await?
+        .into_iter()
+        .filter(|path| !path.starts_with(&backups) && !path.starts_with(&migration_backups))
+        .collect();
+    for stream in store.list_streams().await? {
+        paths.extend(
+            list_objects(&RelativePathBuf::from_iter([
+                stream.name.as_str(),
+                STREAM_ROOT_DIRECTORY,
+            ]))
+            .await?
+            .into_iter()
+            .filter(|path| !is_snapshot_log(path)),
+        );
+        paths.push(alert_json_path(&stream.name));
+    }
+    Ok(paths)
+}
+
+// Handler for POST /api/v1/admin/backup
+pub async fn create(req: HttpRequest) -> Result<impl Responder, BackupError> {
+    let store = CONFIG.storage().get_object_store();
+    let created_at = Utc::now();
+    let mut backup = MetadataBackup {
+        version: CURRENT_BACKUP_VERSION.to_owned(),
+        created_at,
+        created_by: request_username(&req),
+        objects: Vec::new(),
+    };
+
+    for path in metadata_paths().await? {
+        match store.get_object(&path).await {
+            Ok(content) => backup.objects.push(BackupObject {
+                path: path.to_string(),
+                content: STANDARD.encode(content),
+            }),
+            // streams without alerts, or objects deleted since they were listed
+            Err(ObjectStorageError::NoSuchKey(_)) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let name = created_at.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+    let content = serde_json::to_vec(&backup)?;
+    let summary = BackupSummary {
+        name: name.clone(),
+        created_at,
+        objects: backup.objects.len(),
+        bytes: content.len(),
+    };
+    store
+        .put_object(&backup_path(&name)?, Bytes::from(content))
+        .await?;
+
+    log::info!(
+        target: "audit",
+        "metadata backup {name} of {} objects created by {}",
+        summary.objects,
+        backup.created_by
+    );
+    Ok((web::Json(summary), StatusCode::CREATED))
+}
+
+// Handler for GET /api/v1/admin/backup
+pub async fn list() -> Result<impl Responder, BackupError> {
+    let storage = CONFIG.storage();
+    let store = storage.get_datafusion_object_store()?;
+    let prefix = storage.get_object_store().absolute_url(&backups_path());
+
+    let objects: Vec<_> = match store.list(Some(&prefix)).await {
+        Ok(objects) => objects.try_collect().await?,
+        Err(object_store::Error::NotFound { .. }) => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut backups: Vec<_> = objects
+        .into_iter()
+        .filter_map(|meta| {
+            let name = meta.location.filename()?.strip_suffix(".json")?.to_owned();
+            Some(BackupListing {
+                name,
+                size: meta.size,
+                last_modified: meta.last_modified,
+            })
+        })
+        .collect();
+    backups.sort_by(|a, b| b.name.cmp(&a.name));
+
+    Ok(web::Json(backups))
+}
+
+// Handler for POST /api/v1/admin/backup/{name}/restore
+// objects in the backup overwrite the current ones, objects created since are kept. Stream
+// metadata keeps its current snapshot and stats. Servers have to be restarted to load the
+// restored metadata.
+pub async fn restore(
+    req: HttpRequest,
+    name: web::Path<String>,
+    query: web::Query<RestoreQuery>,
+) -> Result<impl Responder, BackupError> {
+    let name = name.into_inner();
+    let store = CONFIG.storage().get_object_store();
+    let backup: MetadataBackup = match store.get_object(&backup_path(&name)?).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(ObjectStorageError::NoSuchKey(_)) => return Err(BackupError::NotFound(name)),
+        Err(err) => return Err(err.into()),
+    };
+    if backup.version != CURRENT_BACKUP_VERSION {
+        return Err(BackupError::UnsupportedVersion(backup.version));
+    }
+
+    // decode everything first, so that a corrupt backup restores nothing. Snapshot logs in
+    // backups taken before they were excluded are skipped.
+    let objects = backup
+        .objects
+        .into_iter()
+        .filter(|object| !is_snapshot_log(RelativePath::new(&object.path)))
+        .map(|object| {
+            let content = STANDARD
+                .decode(&object.content)
+                .map_err(|err| BackupError::Corrupt(format!("{}: {err}", object.path)))?;
+            Ok((RelativePathBuf::from(object.path), content))
+        })
+        .collect::<Result<Vec<_>, BackupError>>()?;
+
+    if !query.dry_run {
+        for (path, content) in &objects {
+            let content = if is_stream_metadata(path) {
+                match store.get_object(path).await {
+                    Ok(current) => restore_stream_metadata(content, &current)?,
+                    // streams deleted since the backup are restored as they were
+                    Err(ObjectStorageError::NoSuchKey(_)) => content.clone(),
+                    Err(err) => return Err(err.into()),
+                }
+            } else {
+                content.clone()
+            };
+            store.put_object(path, Bytes::from(content)).await?;
+        }
+        log::warn!(
+            target: "audit",
+            "metadata backup {name} restored by {}, restart the servers to load it",
+            request_username(&req)
+        );
+    }
+
+    Ok(web::Json(RestoreSummary {
+        name,
+        dry_run: query.dry_run,
+        objects: objects
+            .into_iter()
+            .map(|(path, _)| path.to_string())
+            .collect(),
+    }))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("Backup {0} not found")]
+    NotFound(String),
+    #[error("Backup has version {0}, which this server cannot restore")]
+    UnsupportedVersion(String),
+    #[error("Backup is corrupt: {0}")]
+    Corrupt(String),
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Failed to list objects: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[error("Failed to access storage: {0}")]
+    DataFusion(#[from] datafusion::error::DataFusionError),
+    #[error("Invalid backup: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for BackupError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::UnsupportedVersion(_) | Self::Corrupt(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::ObjectStorage(_)
+            | Self::ObjectStore(_)
+            | Self::DataFusion(_)
+            | Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use relative_path::RelativePath;
+
+    use super::{backup_path, is_snapshot_log, is_stream_metadata, restore_stream_metadata};
+    use crate::catalog::snapshot::Snapshot;
+    use crate::stats::Stats;
+    use crate::storage::ObjectStoreFormat;
+
+    #[test]
+    fn backup_names_stay_in_the_backups_directory() {
+        assert_eq!(
+            backup_path("20240101T120000.000Z").unwrap().as_str(),
+            ".parseable/backups/20240101T120000.000Z.json"
+        );
+        assert!(backup_path("../.parseable").is_err());
+        assert!(backup_path("..").is_err());
+        assert!(backup_path("").is_err());
+    }
+
+    #[test]
+    fn snapshot_logs_are_not_backed_up() {
+        assert!(is_snapshot_log(RelativePath::new(
+            "app/.stream/snapshots/node.00000000000000000003.snapshot.json"
+        )));
+        assert!(!is_snapshot_log(RelativePath::new(
+            "app/.stream/.stream.json"
+        )));
+        assert!(is_stream_metadata(RelativePath::new(
+            "app/.stream/.stream.json"
+        )));
+        assert!(is_stream_metadata(RelativePath::new(
+            "app/.stream/.ingester.abc.stream.json"
+        )));
+        assert!(!is_stream_metadata(RelativePath::new(
+            "app/.stream/.schema"
+        )));
+    }
+
+    #[test]
+    fn restore_keeps_commits_made_since_the_backup() {
+        let backup = ObjectStoreFormat {
+            cache_enabled: true,
+            stats: Stats {
+                events: 10,
+                ingestion: 100,
+                storage: 50,
+            },
+            snapshot: Snapshot {
+                snapshot_id: 2,
+                ..Snapshot::default()
+            },
+            ..ObjectStoreFormat::default()
+        };
+        let current = ObjectStoreFormat {
+            cache_enabled: false,
+            first_event_at: Some("2024-01-01T00:00:00+00:00".to_owned()),
+            stats: Stats {
+                events: 30,
+                ingestion: 300,
+                storage: 150,
+            },
+            snapshot: Snapshot {
+                snapshot_id: 7,
+                ..Snapshot::default()
+            },
+            ..ObjectStoreFormat::default()
+        };
+
+        let restored = restore_stream_metadata(
+            &serde_json::to_vec(&backup).unwrap(),
+            &serde_json::to_vec(&current).unwrap(),
+        )
+        .unwrap();
+        let restored: ObjectStoreFormat = serde_json::from_slice(&restored).unwrap();
+
+        assert!(restored.cache_enabled);
+        assert_eq!(restored.snapshot, current.snapshot);
+        assert_eq!(restored.stats, current.stats);
+        assert_eq!(restored.first_event_at, current.first_event_at);
+    }
+}
@@ -0,0 +1,235 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Payloads of CloudWatch Logs subscription filters.
+//!
+//! A subscription sends its log events gzipped, as the base64 encoded records of a Firehose
+//! delivery or in the `awslogs.data` field of the event a Lambda function is invoked with:
+//! ```json
+//! {
+//!     "messageType": "DATA_MESSAGE",
+//!     "owner": "123456789012",
+//!     "logGroup": "/aws/lambda/checkout",
+//!     "logStream": "2024/01/11/[$LATEST]5c2b5e7b",
+//!     "subscriptionFilters": ["all"],
+//!     "logEvents": [
+//!         { "id": "3693237296244627973", "timestamp": 1705026780451, "message": "START" }
+//!     ]
+//! }
+//! ```
+//! Every log event becomes an event with the owner, log group and log stream it came from.
+
+use std::io::Read;
+
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::MAX_EVENT_PAYLOAD_SIZE;
+
+// subscriptions are checked with a control message when they are created
+const DATA_MESSAGE: &str = "DATA_MESSAGE";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Subscription {
+    message_type: String,
+    owner: String,
+    log_group: String,
+    log_stream: String,
+    log_events: Vec<Map<String, Value>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Delivery {
+    records: Vec<Record>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Record {
+    data: String,
+}
+
+/// Log events of a subscription payload, sent as it is, through Firehose or by a Lambda function
+pub fn flatten_cloudwatch_logs(body: &[u8]) -> anyhow::Result<Vec<Value>> {
+    let body = decompress(body)?;
+    if let Some(events) = log_events(&body)? {
+        return Ok(events);
+    }
+
+    let delivery: Delivery = serde_json::from_slice(&body)
+        .map_err(|_| anyhow!("not a CloudWatch Logs subscription payload"))?;
+    let mut events = Vec::new();
+    for record in delivery.records {
+        let data = decompress(&STANDARD.decode(record.data)?)?;
+        events.extend(
+            log_events(&data)?
+                .ok_or_else(|| anyhow!("record is not a CloudWatch Logs subscription message"))?,
+        );
+    }
+    Ok(events)
+}
+
+/// Log events of a decompressed subscription message, or of the Lambda event wrapping one.
+/// None if `data` is neither.
+pub fn log_events(data: &[u8]) -> anyhow::Result<Option<Vec<Value>>> {
+    let Ok(value) = serde_json::from_slice::<Value>(data) else {
+        return Ok(None);
+    };
+
+    let subscription = if let Some(data) = value.pointer("/awslogs/data").and_then(Value::as_str) {
+        serde_json::from_slice(&decompress(&STANDARD.decode(data)?)?)?
+    } else if value.get("logEvents").is_some() {
+        serde_json::from_value(value)?
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(subscription_events(subscription)))
+}
+
+fn subscription_events(subscription: Subscription) -> Vec<Value> {
+    if subscription.message_type != DATA_MESSAGE {
+        return Vec::new();
+    }
+    subscription
+        .log_events
+        .into_iter()
+        .map(|mut event| {
+            event.insert(
+                "owner".to_owned(),
+                Value::String(subscription.owner.clone()),
+            );
+            event.insert(
+                "logGroup".to_owned(),
+                Value::String(subscription.log_group.clone()),
+            );
+            event.insert(
+                "logStream".to_owned(),
+                Value::String(subscription.log_stream.clone()),
+            );
+            Value::Object(event)
+        })
+        .collect()
+}
+
+/// Data unpacked if it is gzipped, as it is otherwise. Unpacked data larger than an event payload
+/// is rejected.
+pub fn decompress(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return Ok(data.to_vec());
+    }
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data)
+        .take(MAX_EVENT_PAYLOAD_SIZE as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > MAX_EVENT_PAYLOAD_SIZE {
+        return Err(anyhow!(
+            "unpacked payload is larger than {MAX_EVENT_PAYLOAD_SIZE} bytes"
+        ));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use flate2::{write::GzEncoder, Compression};
+    use serde_json::{json, Value};
+
+    use super::{decompress, flatten_cloudwatch_logs, MAX_EVENT_PAYLOAD_SIZE};
+
+    fn gzipped(value: &Value) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&serde_json::to_vec(value).unwrap())
+            .unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn subscription(message_type: &str) -> Value {
+        json!({
+            "messageType": message_type,
+            "owner": "123456789012",
+            "logGroup": "/aws/lambda/checkout",
+            "logStream": "stream",
+            "subscriptionFilters": ["all"],
+            "logEvents": [
+                { "id": "1", "timestamp": 1705026780451u64, "message": "first" },
+                { "id": "2", "timestamp": 1705026780452u64, "message": "second" },
+            ],
+        })
+    }
+
+    #[test]
+    fn lambda_event_is_unpacked() {
+        let data = STANDARD.encode(gzipped(&subscription("DATA_MESSAGE")));
+        let body = serde_json::to_vec(&json!({ "awslogs": { "data": data } })).unwrap();
+
+        let events = flatten_cloudwatch_logs(&body).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["message"], "second");
+        assert_eq!(events[1]["logGroup"], "/aws/lambda/checkout");
+    }
+
+    #[test]
+    fn firehose_records_are_unpacked() {
+        let records = [
+            subscription("DATA_MESSAGE"),
+            subscription("CONTROL_MESSAGE"),
+        ]
+        .iter()
+        .map(|message| json!({ "data": STANDARD.encode(gzipped(message)) }))
+        .collect::<Vec<_>>();
+        let body = serde_json::to_vec(&json!({
+            "requestId": "9b848d8a",
+            "timestamp": 1705026780451u64,
+            "records": records,
+        }))
+        .unwrap();
+
+        let events = flatten_cloudwatch_logs(&body).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["owner"], "123456789012");
+    }
+
+    #[test]
+    fn other_payload_is_rejected() {
+        assert!(flatten_cloudwatch_logs(br#"{"level": "info"}"#).is_err());
+    }
+
+    #[test]
+    fn unpacked_size_is_bounded() {
+        let compress = |data: &[u8]| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let data = vec![b'a'; MAX_EVENT_PAYLOAD_SIZE];
+        assert_eq!(decompress(&compress(&data)).unwrap().len(), data.len());
+
+        let bomb = vec![b'a'; MAX_EVENT_PAYLOAD_SIZE + 1];
+        assert!(decompress(&compress(&bomb)).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::{web, Responder};
+use chrono::{DateTime, Duration, Utc};
+use http::StatusCode;
+use object_store::path::Path;
+
+use crate::catalog::{self, manifest::Manifest};
+use crate::handlers::http::logstream::error::StreamError;
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::storage::compression;
+
+// ingestion rate is averaged over these many complete days
+const RATE_WINDOW_DAYS: i64 = 7;
+const DEFAULT_HORIZON_DAYS: u32 = 30;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CapacityQuery {
+    days: Option<u32>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamCapacity {
+    stream: String,
+    current_storage_bytes: u64,
+    daily_storage_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retention_days: Option<u32>,
+    projected_storage_bytes: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CapacityForecast {
+    generated_at: DateTime<Utc>,
+    rate_window_days: i64,
+    horizon_days: u32,
+    current_storage_bytes: u64,
+    projected_storage_bytes: u64,
+    streams: Vec<StreamCapacity>,
+}
+
+/// Storage expected after `horizon_days` given the current storage, the daily growth and the
+/// retention. With retention, storage converges to the data retained for `retention_days`.
+fn project_storage(
+    current: u64,
+    daily: u64,
+    retention_days: Option<u32>,
+    horizon_days: u32,
+) -> u64 {
+    let grown = current + daily * horizon_days as u64;
+    match retention_days {
+        Some(retention_days) => {
+            let steady = daily * retention_days as u64;
+            if horizon_days >= retention_days {
+                steady
+            } else {
+                grown.min(steady.max(current))
+            }
+        }
+        None => grown,
+    }
+}
+
+// GET "/cluster/capacity" ==> storage forecast of every stream
+pub async fn get_capacity(query: web::Query<CapacityQuery>) -> Result<impl Responder, StreamError> {
+    let horizon_days = query.days.unwrap_or(DEFAULT_HORIZON_DAYS);
+    let storage = CONFIG.storage();
+    let store = storage.get_object_store();
+    let object_store = storage
+        .get_datafusion_object_store()
+        .map_err(|err| StreamError::Anyhow(err.into()))?;
+
+    let now = Utc::now();
+    let today = now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let window_start = today - Duration::days(RATE_WINDOW_DAYS);
+
+    let mut streams = Vec::new();
+    for stream in STREAM_INFO.list_streams() {
+        let retention_days = store
+            .get_object_store_format(&stream)
+            .await?
+            .retention
+            .and_then(|retention| retention.delete_after_days());
+
+        let mut current = 0;
+        let mut window = 0;
+        for item in catalog::get_manifest_list(store.clone(), &stream).await? {
+            let path =
+                Path::parse(&item.manifest_path).map_err(|err| StreamError::Anyhow(err.into()))?;
+            let Ok(data) = compression::read(&*object_store, &path).await else {
+                log::warn!(
+                    "manifest {} of stream {stream} is missing",
+                    item.manifest_path
+                );
+                continue;
+            };
+            let manifest: Manifest = serde_json::from_slice(&data)?;
+            let size: u64 = manifest.files.iter().map(|file| file.file_size).sum();
+
+            current += size;
+            if item.time_lower_bound >= window_start && item.time_lower_bound < today {
+                window += size;
+            }
+        }
+
+        let daily = window / RATE_WINDOW_DAYS as u64;
+        streams.push(StreamCapacity {
+            projected_storage_bytes: project_storage(current, daily, retention_days, horizon_days),
+            stream,
+            current_storage_bytes: current,
+            daily_storage_bytes: daily,
+            retention_days,
+        });
+    }
+
+    let forecast = CapacityForecast {
+        generated_at: now,
+        rate_window_days: RATE_WINDOW_DAYS,
+        horizon_days,
+        current_storage_bytes: streams.iter().map(|s| s.current_storage_bytes).sum(),
+        projected_storage_bytes: streams.iter().map(|s| s.projected_storage_bytes).sum(),
+        streams,
+    };
+
+    Ok((web::Json(forecast), StatusCode::OK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::project_storage;
+
+    #[test]
+    fn projection_respects_retention() {
+        assert_eq!(project_storage(100, 10, None, 30), 400);
+        // grows until the retained window is full
+        assert_eq!(project_storage(100, 10, Some(30), 10), 200);
+        assert_eq!(project_storage(100, 10, Some(30), 25), 300);
+        assert_eq!(project_storage(100, 10, Some(30), 60), 300);
+        // older data beyond retention is deleted
+        assert_eq!(project_storage(1000, 10, Some(30), 60), 300);
+    }
+}
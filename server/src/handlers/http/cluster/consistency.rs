@@ -0,0 +1,231 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Compare what every ingester holds of each stream with the metadata in object storage.
+//!
+//! Ingesters keep their own copy of the stream settings and of the schema, both may fall behind
+//! when an ingester misses a sync. Settings are brought back in line by a re-sync, a field with
+//! another data type on an ingester than in storage needs the stream to be looked at by hand.
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, Responder};
+use arrow_schema::Schema;
+use http::StatusCode;
+
+use super::node_client::{self, CLIENT};
+use super::{compatible_ingesters, get_ingester_info, sync_stream_settings_with_ingesters, utils};
+use crate::handlers::http::base_path_without_preceding_slash;
+use crate::handlers::http::logstream::error::StreamError;
+use crate::handlers::http::modal::IngesterMetadata;
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::storage::StreamSettings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Remediation {
+    /// `POST /cluster/consistency/{logstream}/resync`
+    Resync,
+    Manual,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum Divergence {
+    Settings {
+        expected: StreamSettings,
+        found: StreamSettings,
+    },
+    FieldType {
+        field: String,
+        expected: String,
+        found: String,
+    },
+    Unreachable {
+        reason: String,
+    },
+}
+
+impl Divergence {
+    fn remediation(&self) -> Remediation {
+        match self {
+            Divergence::Settings { .. } => Remediation::Resync,
+            Divergence::FieldType { .. } | Divergence::Unreachable { .. } => Remediation::Manual,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamDivergence {
+    stream: String,
+    ingester_id: String,
+    domain_name: String,
+    #[serde(flatten)]
+    divergence: Divergence,
+    remediation: Remediation,
+}
+
+/// Fields typed differently on the ingester than in storage. Fields only one side has are
+/// expected, new fields reach storage with the next upload of the ingester.
+fn field_type_divergences(expected: &Schema, found: &Schema) -> Vec<Divergence> {
+    found
+        .fields()
+        .iter()
+        .filter_map(|field| {
+            let (_, stored) = expected.column_with_name(field.name())?;
+            (stored.data_type() != field.data_type()).then(|| Divergence::FieldType {
+                field: field.name().to_owned(),
+                expected: stored.data_type().to_string(),
+                found: field.data_type().to_string(),
+            })
+        })
+        .collect()
+}
+
+async fn fetch<T: serde::de::DeserializeOwned>(
+    ingester: &IngesterMetadata,
+    stream_name: &str,
+    path: &str,
+) -> Result<Option<T>, String> {
+    let url = format!(
+        "{}{}/logstream/{stream_name}/{path}",
+        ingester.domain_name,
+        base_path_without_preceding_slash()
+    );
+    let res = node_client::send(&ingester.domain_name, || {
+        CLIENT
+            .get(&url)
+            .header(header::AUTHORIZATION, ingester.authorization())
+    })
+    .await
+    .map_err(|err| err.to_string())?;
+
+    // streams are loaded by an ingester once it receives events for them
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let res = res.error_for_status().map_err(|err| err.to_string())?;
+    res.json().await.map(Some).map_err(|err| err.to_string())
+}
+
+async fn ingester_divergences(
+    ingester: &IngesterMetadata,
+    stream_name: &str,
+    schema: &Schema,
+    settings: &StreamSettings,
+) -> Vec<Divergence> {
+    let found_settings = match fetch::<StreamSettings>(ingester, stream_name, "settings").await {
+        Ok(Some(found)) => found,
+        Ok(None) => return Vec::new(),
+        Err(reason) => return vec![Divergence::Unreachable { reason }],
+    };
+    let found_schema = match fetch::<Schema>(ingester, stream_name, "schema").await {
+        Ok(found) => found.unwrap_or_else(Schema::empty),
+        Err(reason) => return vec![Divergence::Unreachable { reason }],
+    };
+
+    let mut divergences = field_type_divergences(schema, &found_schema);
+    if found_settings != *settings {
+        divergences.push(Divergence::Settings {
+            expected: settings.clone(),
+            found: found_settings,
+        });
+    }
+    divergences
+}
+
+// Handler for GET /api/v1/cluster/consistency
+// divergences of live ingesters from the stream metadata in storage
+pub async fn get_consistency() -> Result<impl Responder, StreamError> {
+    let ingesters: Vec<IngesterMetadata> =
+        compatible_ingesters(get_ingester_info().await.map_err(StreamError::Anyhow)?);
+    let mut live = Vec::new();
+    for ingester in ingesters {
+        if utils::check_liveness(&ingester.domain_name).await {
+            live.push(ingester);
+        }
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut report = Vec::new();
+    for stream_name in STREAM_INFO.list_streams() {
+        let schema = storage.get_schema(&stream_name).await?;
+        let settings = StreamSettings::of(&storage.get_stream_metadata(&stream_name).await?);
+
+        for ingester in &live {
+            for divergence in ingester_divergences(ingester, &stream_name, &schema, &settings).await
+            {
+                report.push(StreamDivergence {
+                    stream: stream_name.clone(),
+                    ingester_id: ingester.ingester_id.clone(),
+                    domain_name: ingester.domain_name.clone(),
+                    remediation: divergence.remediation(),
+                    divergence,
+                });
+            }
+        }
+    }
+
+    Ok(web::Json(report))
+}
+
+// Handler for POST /api/v1/cluster/consistency/{logstream}/resync
+// push the settings in storage to every live ingester again
+pub async fn resync_stream(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    if !STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    sync_stream_settings_with_ingesters(
+        &stream_name,
+        format!("re-sync settings of log stream {stream_name}"),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_schema::{DataType, Field, Schema};
+
+    use super::{field_type_divergences, Divergence};
+
+    #[test]
+    fn only_retyped_fields_diverge() {
+        let stored = Schema::new(vec![
+            Field::new("level", DataType::Utf8, true),
+            Field::new("status", DataType::Int64, true),
+        ]);
+        let found = Schema::new(vec![
+            Field::new("level", DataType::Utf8, true),
+            Field::new("status", DataType::Utf8, true),
+            Field::new("trace_id", DataType::Utf8, true),
+        ]);
+
+        assert_eq!(
+            field_type_divergences(&stored, &found),
+            vec![Divergence::FieldType {
+                field: "status".to_owned(),
+                expected: "Int64".to_owned(),
+                found: "Utf8".to_owned(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::{web, Responder};
+use chrono::Utc;
+use futures::future::join_all;
+
+use super::{get_ingester_info, is_stale, node_client, sharding};
+use crate::handlers::http::base_path_without_preceding_slash;
+use crate::handlers::http::logstream::error::StreamError;
+use crate::handlers::http::modal::IngesterMetadata;
+use crate::metrics::prom_utils::Metrics;
+use crate::option::CONFIG;
+
+// ingesters not answering within this time are left out
+const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestTarget {
+    ingester_id: String,
+    domain_name: String,
+    events_per_second: f64,
+    staging_utilization: f64,
+    /// 0 for an idle ingester with an empty staging disk, 1 for the busiest one with a full disk
+    load: f64,
+}
+
+/// Rank ingesters from the least to the most loaded. Load weighs the ingestion rate, relative to
+/// the busiest ingester, and the share of the staging disk in use equally.
+fn rank(ingesters: Vec<(IngesterMetadata, Metrics)>) -> Vec<IngestTarget> {
+    let max_rate = ingesters
+        .iter()
+        .map(|(_, metrics)| metrics.events_ingested_rate())
+        .fold(0.0, f64::max);
+
+    let mut targets: Vec<IngestTarget> = ingesters
+        .into_iter()
+        .map(|(ingester, metrics)| {
+            let rate = metrics.events_ingested_rate();
+            let staging = metrics.staging_disk_usage().clamp(0.0, 1.0);
+            let relative_rate = if max_rate > 0.0 { rate / max_rate } else { 0.0 };
+            IngestTarget {
+                ingester_id: ingester.ingester_id,
+                domain_name: ingester.domain_name,
+                events_per_second: rate,
+                staging_utilization: staging,
+                load: (relative_rate + staging) / 2.0,
+            }
+        })
+        .collect();
+
+    targets.sort_by(|a, b| a.load.total_cmp(&b.load));
+    targets
+}
+
+async fn fetch_metrics(ingester: &IngesterMetadata) -> Option<Metrics> {
+    let url = format!(
+        "{}{}/metrics",
+        ingester.domain_name,
+        base_path_without_preceding_slash()
+    );
+    let res = node_client::send(&ingester.domain_name, || {
+        node_client::CLIENT
+            .get(&url)
+            .header(header::AUTHORIZATION, ingester.metrics_authorization())
+            .timeout(METRICS_TIMEOUT)
+    })
+    .await
+    .ok()?;
+    if !res.status().is_success() {
+        return None;
+    }
+
+    let text = res.text().await.ok()?;
+    let samples = prometheus_parse::Scrape::parse(text.lines().map(|line| Ok(line.to_owned())))
+        .ok()?
+        .samples;
+    Some(Metrics::from_prometheus_samples(
+        samples,
+        ingester.domain_name.clone(),
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct IngestTargetsQuery {
+    /// only the owners of the shards of this stream, if it is sharded
+    stream: Option<String>,
+}
+
+// Handler for GET /api/v1/cluster/ingest-targets?stream={logstream}
+// live ingesters, least loaded first
+pub async fn get_ingest_targets(
+    query: web::Query<IngestTargetsQuery>,
+) -> Result<impl Responder, StreamError> {
+    let now = Utc::now();
+    let owners = query.stream.as_deref().and_then(sharding::owners);
+    let ingesters: Vec<IngesterMetadata> = get_ingester_info()
+        .await
+        .map_err(StreamError::Anyhow)?
+        .into_iter()
+        .filter(|ingester| !is_stale(ingester, now, CONFIG.parseable.ingester_stale_after))
+        .filter(|ingester| {
+            owners
+                .as_ref()
+                .map_or(true, |owners| owners.contains(&ingester.ingester_id))
+        })
+        .collect();
+
+    let metrics = join_all(ingesters.iter().map(fetch_metrics)).await;
+    let live = ingesters
+        .into_iter()
+        .zip(metrics)
+        .filter_map(|(ingester, metrics)| Some((ingester, metrics?)))
+        .collect();
+
+    Ok(web::Json(rank(live)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rank;
+    use crate::handlers::http::modal::IngesterMetadata;
+    use crate::metrics::prom_utils::Metrics;
+
+    fn ingester(id: &str, rate: f64, staging: f64) -> (IngesterMetadata, Metrics) {
+        let text = format!(
+            "# TYPE parseable_events_ingested_rate gauge\n\
+             parseable_events_ingested_rate {rate}\n\
+             # TYPE parseable_staging_disk_usage gauge\n\
+             parseable_staging_disk_usage {staging}\n"
+        );
+        let samples = prometheus_parse::Scrape::parse(text.lines().map(|line| Ok(line.to_owned())))
+            .unwrap()
+            .samples;
+        let metrics = Metrics::from_prometheus_samples(samples, id.to_owned());
+        let metadata = IngesterMetadata {
+            ingester_id: id.to_owned(),
+            ..Default::default()
+        };
+        (metadata, metrics)
+    }
+
+    #[test]
+    fn least_loaded_first() {
+        let targets = rank(vec![
+            ingester("busy", 1000.0, 0.2),
+            ingester("full", 100.0, 0.9),
+            ingester("idle", 0.0, 0.1),
+        ]);
+        let order: Vec<&str> = targets.iter().map(|t| t.ingester_id.as_str()).collect();
+        assert_eq!(order, ["idle", "full", "busy"]);
+        assert!((targets[2].load - 0.6).abs() < 1e-9);
+    }
+}
@@ -16,6 +16,13 @@
  *
  */
 
+pub mod capacity;
+pub mod consistency;
+pub mod ingest_targets;
+pub mod node_client;
+pub mod report;
+pub mod sharding;
+pub mod topology;
 pub mod utils;
 
 use crate::handlers::http::cluster::utils::{
@@ -23,91 +30,327 @@ use crate::handlers::http::cluster::utils::{
 };
 use crate::handlers::http::ingest::PostError;
 use crate::handlers::http::logstream::error::StreamError;
-use crate::handlers::{STATIC_SCHEMA_FLAG, TIME_PARTITION_KEY};
+use crate::handlers::{
+    CUSTOM_PARTITION_KEY, STATIC_SCHEMA_FLAG, STREAM_NAME_HEADER_KEY, TIME_PARTITION_KEY,
+    TRANSACTION_ID_KEY,
+};
 use crate::option::CONFIG;
+use crate::webhooks::{self, LifecycleEvent};
 
 use crate::metrics::prom_utils::Metrics;
-use crate::storage::object_storage::ingester_metadata_path;
-use crate::storage::{ObjectStorageError, STREAM_ROOT_DIRECTORY};
+use crate::storage::object_storage::{ingester_metadata_path, legacy_ingester_id};
+use crate::storage::{ObjectStorageError, StreamSettings, STREAM_ROOT_DIRECTORY};
 use crate::storage::{ObjectStoreFormat, PARSEABLE_ROOT_DIRECTORY};
 use actix_web::http::header;
 use actix_web::{HttpRequest, Responder};
 use bytes::Bytes;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use futures::Future;
+use futures::TryStreamExt;
 use http::StatusCode;
 use itertools::Itertools;
 use relative_path::RelativePathBuf;
 use serde_json::Value as JsonValue;
+use std::time::Duration;
 use url::Url;
 
 type IngesterMetadataArr = Vec<IngesterMetadata>;
 
+const STALE_CHECK_INTERVAL_MINUTES: u32 = 1;
+
+use self::node_client::{NodeRequestError, CLIENT};
+use self::report::ClusterOpReport;
 use self::utils::StorageStats;
 
 use super::base_path_without_preceding_slash;
 
-use super::modal::IngesterMetadata;
+use self::utils::VersionSkew;
+use super::modal::{IngesterMetadata, DEFAULT_VERSION};
+
+// ingesters writing another metadata format would misread the synced stream metadata
+fn compatible_ingesters(ingesters: IngesterMetadataArr) -> IngesterMetadataArr {
+    ingesters
+        .into_iter()
+        .filter(|ingester| {
+            let compatible = utils::has_compatible_metadata(ingester);
+            if !compatible {
+                log::error!(
+                    "not syncing stream to ingester {}, it writes metadata {} instead of {}",
+                    ingester.domain_name,
+                    ingester.version,
+                    DEFAULT_VERSION
+                );
+            }
+            compatible
+        })
+        .collect()
+}
+
+/// Creation of a stream prepared on every live ingester, each one activates the stream on
+/// commit. Ingesters not live during the preparation create the stream on its first event.
+pub struct PreparedStreamCreation {
+    transaction: String,
+    stream_name: String,
+    // ingesters which prepared the stream
+    ingesters: IngesterMetadataArr,
+    // ingesters which failed, they may still have prepared the stream before failing to respond
+    unconfirmed: IngesterMetadataArr,
+    report: ClusterOpReport,
+}
+
+fn stream_url(ingester: &IngesterMetadata, stream_name: &str, action: &str) -> String {
+    format!(
+        "{}{}/logstream/{}{}",
+        ingester.domain_name,
+        base_path_without_preceding_slash(),
+        stream_name,
+        action
+    )
+}
 
-// forward the request to all ingesters to keep them in sync
-#[allow(dead_code)]
-pub async fn sync_streams_with_ingesters(
+// first phase of creating a stream: every live ingester validates it without activating it,
+// so a stream is either created on all of them or on none
+pub async fn prepare_stream_with_ingesters(
     stream_name: &str,
     time_partition: &str,
+    custom_partition: &str,
     static_schema: &str,
     schema: Bytes,
-) -> Result<(), StreamError> {
+) -> Result<PreparedStreamCreation, StreamError> {
     let ingester_infos = get_ingester_info().await.map_err(|err| {
         log::error!("Fatal: failed to get ingester info: {:?}", err);
         StreamError::Anyhow(err)
     })?;
 
-    let mut errored = false;
-    for ingester in ingester_infos.iter() {
-        let url = format!(
-            "{}{}/logstream/{}",
-            ingester.domain_name,
-            base_path_without_preceding_slash(),
-            stream_name
+    let mut prepared = PreparedStreamCreation {
+        transaction: ulid::Ulid::new().to_string(),
+        stream_name: stream_name.to_owned(),
+        ingesters: Vec::new(),
+        unconfirmed: Vec::new(),
+        report: ClusterOpReport::new(format!("create log stream {stream_name}")),
+    };
+    for ingester in compatible_ingesters(ingester_infos) {
+        if !utils::check_liveness(&ingester.domain_name).await {
+            prepared.report.skipped(
+                &ingester.domain_name,
+                "ingester is not live, it creates the log stream on its first event",
+            );
+            continue;
+        }
+
+        let url = stream_url(&ingester, stream_name, "/prepare");
+        let res = node_client::send(&ingester.domain_name, || {
+            CLIENT
+                .put(&url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(TIME_PARTITION_KEY, time_partition)
+                .header(CUSTOM_PARTITION_KEY, custom_partition)
+                .header(STATIC_SCHEMA_FLAG, static_schema)
+                .header(TRANSACTION_ID_KEY, &prepared.transaction)
+                .header(header::AUTHORIZATION, ingester.authorization())
+                .body(schema.clone())
+        })
+        .await;
+
+        // outcomes of the ingesters which prepared the stream are reported on commit or abort
+        let mut outcome = ClusterOpReport::new("");
+        if outcome.record(&ingester.domain_name, res).await {
+            prepared.ingesters.push(ingester);
+            continue;
+        }
+        log::error!(
+            "failed to prepare log stream {stream_name} on ingester {}",
+            ingester.domain_name
         );
+        prepared.report.extend(outcome);
+        prepared.unconfirmed.push(ingester);
+        break;
+    }
 
-        match send_stream_sync_request(
-            &url,
-            ingester.clone(),
-            time_partition,
-            static_schema,
-            schema.clone(),
-        )
-        .await
-        {
-            Ok(_) => continue,
-            Err(_) => {
-                errored = true;
-                break;
+    Ok(prepared)
+}
+
+impl PreparedStreamCreation {
+    /// Whether every live ingester prepared the stream
+    pub fn is_ready(&self) -> bool {
+        self.report.is_success()
+    }
+
+    /// Second phase, every ingester activates the stream. If one of them fails to, the
+    /// creation is rolled back on the ingesters and the report of the failure is returned,
+    /// the caller rolls the stream back on this node.
+    pub async fn commit(self) -> Result<ClusterOpReport, ClusterOpReport> {
+        let transaction = self.transaction.clone();
+        let stream_name = self.stream_name.clone();
+        let commit = |ingester: &IngesterMetadata| {
+            let url = stream_url(ingester, &stream_name, "/commit");
+            let authorization = ingester.authorization();
+            let domain_name = ingester.domain_name.clone();
+            let transaction = transaction.clone();
+            async move {
+                // committing the transaction again has no further effect
+                node_client::send_retried(&domain_name, || {
+                    CLIENT
+                        .post(&url)
+                        .header(TRANSACTION_ID_KEY, &transaction)
+                        .header(header::AUTHORIZATION, &authorization)
+                })
+                .await
             }
-        }
+        };
+        let roll_back = |ingester: &IngesterMetadata, created: bool| {
+            // a created stream is dropped like a deleted one, a prepared one is aborted
+            let action = if created { "/staging" } else { "/prepare" };
+            let url = stream_url(ingester, &stream_name, action);
+            let authorization = ingester.authorization();
+            let domain_name = ingester.domain_name.clone();
+            let transaction = transaction.clone();
+            async move {
+                node_client::send(&domain_name, || {
+                    CLIENT
+                        .delete(&url)
+                        .header(TRANSACTION_ID_KEY, &transaction)
+                        .header(header::AUTHORIZATION, &authorization)
+                })
+                .await
+            }
+        };
+        self.commit_with(commit, roll_back).await
     }
 
-    if errored {
-        for ingester in ingester_infos {
-            let url = format!(
-                "{}{}/logstream/{}",
-                ingester.domain_name,
-                base_path_without_preceding_slash(),
-                stream_name
+    async fn commit_with<C, CF, R, RF>(
+        mut self,
+        commit: C,
+        roll_back: R,
+    ) -> Result<ClusterOpReport, ClusterOpReport>
+    where
+        C: Fn(&IngesterMetadata) -> CF,
+        CF: Future<Output = Result<reqwest::Response, NodeRequestError>>,
+        R: Fn(&IngesterMetadata, bool) -> RF,
+        RF: Future<Output = Result<reqwest::Response, NodeRequestError>>,
+    {
+        let mut failed = None;
+        for (index, ingester) in self.ingesters.iter().enumerate() {
+            let mut outcome = ClusterOpReport::new("");
+            if outcome
+                .record(&ingester.domain_name, commit(ingester).await)
+                .await
+            {
+                continue;
+            }
+            log::error!(
+                "failed to commit log stream {} on ingester {}, rolling back its creation",
+                self.stream_name,
+                ingester.domain_name
             );
+            self.report.extend(outcome);
+            failed = Some(index);
+            break;
+        }
+
+        let Some(failed) = failed else {
+            for ingester in &self.ingesters {
+                self.report.succeeded(&ingester.domain_name);
+            }
+            return Ok(self.report);
+        };
 
-            // roll back the stream creation
-            send_stream_rollback_request(&url, ingester.clone()).await?;
+        // the failed ingester may have created the stream before failing to respond
+        for (index, ingester) in self.ingesters.iter().enumerate() {
+            let created = index <= failed;
+            if index != failed {
+                self.report.failed(
+                    &ingester.domain_name,
+                    "log stream creation was rolled back",
+                    true,
+                );
+            }
+            let rolled_back = match roll_back(ingester, created).await {
+                Ok(res) => res.status().is_success(),
+                Err(_) => false,
+            };
+            if !rolled_back {
+                log::warn!(
+                    "failed to roll back log stream {} on ingester {}",
+                    self.stream_name,
+                    ingester.domain_name
+                );
+            }
         }
+        Err(self.report)
+    }
 
-        // this might be a bit too much
-        return Err(StreamError::Custom {
-            msg: "Failed to sync stream with ingesters".to_string(),
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-        });
+    // drop the prepared stream, ingesters missing the abort drop it once it expires
+    pub async fn abort(mut self, reason: &str) -> ClusterOpReport {
+        for ingester in &self.ingesters {
+            self.report.failed(&ingester.domain_name, reason, true);
+        }
+        for ingester in self.ingesters.iter().chain(&self.unconfirmed) {
+            let url = stream_url(ingester, &self.stream_name, "/prepare");
+            let res = node_client::send(&ingester.domain_name, || {
+                CLIENT
+                    .delete(&url)
+                    .header(TRANSACTION_ID_KEY, &self.transaction)
+                    .header(header::AUTHORIZATION, ingester.authorization())
+            })
+            .await;
+            if let Err(err) = res {
+                log::warn!(
+                    "failed to abort log stream {} on ingester {}: {err}",
+                    self.stream_name,
+                    ingester.domain_name
+                );
+            }
+        }
+        self.report
     }
+}
 
-    Ok(())
+// forward the settings of a stream to all live ingesters, they keep their own copy
+pub async fn sync_stream_settings_with_ingesters(
+    stream_name: &str,
+    operation: String,
+) -> Result<ClusterOpReport, StreamError> {
+    let meta = CONFIG
+        .storage()
+        .get_object_store()
+        .get_stream_metadata(stream_name)
+        .await?;
+    let settings = StreamSettings::of(&meta);
+
+    let ingester_infos = get_ingester_info().await.map_err(|err| {
+        log::error!("Fatal: failed to get ingester info: {:?}", err);
+        StreamError::Anyhow(err)
+    })?;
+
+    let mut report = ClusterOpReport::new(operation);
+    for ingester in compatible_ingesters(ingester_infos) {
+        if !utils::check_liveness(&ingester.domain_name).await {
+            report.skipped(
+                &ingester.domain_name,
+                "ingester is not live, it picks up the settings on restart",
+            );
+            continue;
+        }
+
+        let url = stream_url(&ingester, stream_name, "/settings");
+        let res = node_client::send(&ingester.domain_name, || {
+            CLIENT
+                .put(&url)
+                .header(header::AUTHORIZATION, ingester.authorization())
+                .json(&settings)
+        })
+        .await;
+        if !report.record(&ingester.domain_name, res).await {
+            log::error!(
+                "failed to forward stream settings to ingester: {}",
+                ingester.domain_name
+            );
+        }
+    }
+
+    Ok(report)
 }
 
 /// get the cumulative stats from all ingesters
@@ -144,97 +387,53 @@ pub async fn fetch_stats_from_ingesters(
     Ok(vec![qs])
 }
 
-#[allow(dead_code)]
-async fn send_stream_sync_request(
-    url: &str,
-    ingester: IngesterMetadata,
-    time_partition: &str,
-    static_schema: &str,
-    schema: Bytes,
-) -> Result<(), StreamError> {
-    if !utils::check_liveness(&ingester.domain_name).await {
-        return Ok(());
-    }
+// drop the staging data and schema of a deleted stream on all live ingesters, the shared data
+// is already gone
+pub async fn delete_stream_on_ingesters(
+    stream_name: &str,
+    operation: String,
+) -> Result<ClusterOpReport, StreamError> {
+    let ingester_infos = get_ingester_info().await.map_err(|err| {
+        log::error!("Fatal: failed to get ingester info: {:?}", err);
+        StreamError::Anyhow(err)
+    })?;
 
-    let client = reqwest::Client::new();
-    let res = client
-        .put(url)
-        .header(header::CONTENT_TYPE, "application/json")
-        .header(TIME_PARTITION_KEY, time_partition)
-        .header(STATIC_SCHEMA_FLAG, static_schema)
-        .header(header::AUTHORIZATION, ingester.token)
-        .body(schema)
-        .send()
-        .await
-        .map_err(|err| {
-            log::error!(
-                "Fatal: failed to forward create stream request to ingester: {}\n Error: {:?}",
-                ingester.domain_name,
-                err
+    let mut report = ClusterOpReport::new(operation);
+    for ingester in ingester_infos {
+        if !utils::check_liveness(&ingester.domain_name).await {
+            report.failed(
+                &ingester.domain_name,
+                "ingester is not live, its staging data of the log stream is left behind",
+                true,
             );
-            StreamError::Network(err)
-        })?;
-
-    if !res.status().is_success() {
-        log::error!(
-            "failed to forward create stream request to ingester: {}\nResponse Returned: {:?}",
-            ingester.domain_name,
-            res
-        );
-        return Err(StreamError::Network(res.error_for_status().unwrap_err()));
-    }
-
-    Ok(())
-}
-
-/// send a rollback request to all ingesters
-#[allow(dead_code)]
-async fn send_stream_rollback_request(
-    url: &str,
-    ingester: IngesterMetadata,
-) -> Result<(), StreamError> {
-    if !utils::check_liveness(&ingester.domain_name).await {
-        return Ok(());
-    }
+            continue;
+        }
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .delete(url)
-        .header(header::CONTENT_TYPE, "application/json")
-        .header(header::AUTHORIZATION, ingester.token)
-        .send()
-        .await
-        .map_err(|err| {
-            // log the error and return a custom error
+        let url = stream_url(&ingester, stream_name, "/staging");
+        let res = node_client::send(&ingester.domain_name, || {
+            CLIENT
+                .delete(&url)
+                .header(header::AUTHORIZATION, ingester.authorization())
+        })
+        .await;
+        if !report.record(&ingester.domain_name, res).await {
             log::error!(
-                "Fatal: failed to rollback stream creation: {}\n Error: {:?}",
-                ingester.domain_name,
-                err
+                "failed to drop staging data of log stream {stream_name} on ingester {}",
+                ingester.domain_name
             );
-            StreamError::Network(err)
-        })?;
-
-    // if the response is not successful, log the error and return a custom error
-    // this could be a bit too much, but we need to be sure it covers all cases
-    if !resp.status().is_success() {
-        log::error!(
-            "failed to rollback stream creation: {}\nResponse Returned: {:?}",
-            ingester.domain_name,
-            resp
-        );
-        return Err(StreamError::Custom {
-            msg: format!(
-                "failed to rollback stream creation: {}\nResponse Returned: {:?}",
-                ingester.domain_name,
-                resp.text().await.unwrap_or_default()
-            ),
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-        });
+        }
     }
 
-    Ok(())
+    Ok(report)
 }
 
+/// Ingesters of the cluster and whether they are reachable
+#[utoipa::path(
+    get,
+    path = "/api/v1/cluster/info",
+    tag = "cluster",
+    responses((status = 200, description = "Info of every ingester", body = Vec<utils::ClusterInfo>))
+)]
 pub async fn get_cluster_info() -> Result<impl Responder, StreamError> {
     let ingester_infos = get_ingester_info().await.map_err(|err| {
         log::error!("Fatal: failed to get ingester info: {:?}", err);
@@ -251,55 +450,85 @@ pub async fn get_cluster_info() -> Result<impl Responder, StreamError> {
         ))
         .expect("should always be a valid url");
 
-        let resp = reqwest::Client::new()
-            .get(uri)
-            .header(header::AUTHORIZATION, ingester.token.clone())
-            .header(header::CONTENT_TYPE, "application/json")
-            .send()
-            .await;
+        let resp = node_client::send(&ingester.domain_name, || {
+            CLIENT
+                .get(uri.clone())
+                .header(header::AUTHORIZATION, ingester.authorization())
+                .header(header::CONTENT_TYPE, "application/json")
+        })
+        .await;
+
+        let mut info = utils::ClusterInfo {
+            ingester_id: ingester.ingester_id.clone(),
+            domain_name: ingester.domain_name.clone(),
+            last_heartbeat: ingester.last_heartbeat,
+            stale: is_stale(&ingester, Utc::now(), CONFIG.parseable.ingester_stale_after),
+            storage_path: CONFIG.storage().get_endpoint(),
+            ..Default::default()
+        };
 
-        let (reachable, staging_path, error, status) = if let Ok(resp) = resp {
-            let status = Some(resp.status().to_string());
+        match resp {
+            Ok(resp) => {
+                info.status = Some(resp.status().to_string());
 
-            let resp_data = resp.bytes().await.map_err(|err| {
-                log::error!("Fatal: failed to parse ingester info to bytes: {:?}", err);
-                StreamError::Network(err)
-            })?;
+                let resp_data = resp.bytes().await.map_err(|err| {
+                    log::error!("Fatal: failed to parse ingester info to bytes: {:?}", err);
+                    StreamError::Network(err)
+                })?;
 
-            let sp = serde_json::from_slice::<JsonValue>(&resp_data)
-                .map_err(|err| {
+                let about = serde_json::from_slice::<JsonValue>(&resp_data).map_err(|err| {
                     log::error!("Fatal: failed to parse ingester info: {:?}", err);
                     StreamError::SerdeError(err)
-                })?
-                .get("staging")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string();
-
-            (true, sp, None, status)
-        } else {
-            (
-                false,
-                "".to_owned(),
-                resp.as_ref().err().map(|e| e.to_string()),
-                resp.unwrap_err().status().map(|s| s.to_string()),
-            )
-        };
+                })?;
+                let field = |name: &str| {
+                    about
+                        .get(name)
+                        .and_then(JsonValue::as_str)
+                        .map(str::to_owned)
+                };
+
+                info.reachable = true;
+                info.staging_path = field("staging").unwrap_or_default();
+                info.version = field("version");
+                info.commit = field("commit");
+
+                let skew = if utils::has_compatible_metadata(&ingester) {
+                    utils::version_skew(
+                        info.version.as_deref().unwrap_or_default(),
+                        info.commit.as_deref().unwrap_or_default(),
+                    )
+                } else {
+                    VersionSkew::Incompatible
+                };
+                if skew == VersionSkew::Incompatible {
+                    log::warn!(
+                        "ingester {} runs {} with metadata {}, incompatible with this node",
+                        ingester.domain_name,
+                        info.version.as_deref().unwrap_or("an unknown version"),
+                        ingester.version
+                    );
+                }
+                info.version_skew = Some(skew);
+            }
+            Err(err) => {
+                info.status = Some(err.status_code().to_string());
+                info.error = Some(err.to_string());
+            }
+        }
 
-        infos.push(utils::ClusterInfo::new(
-            &ingester.domain_name,
-            reachable,
-            staging_path,
-            CONFIG.storage().get_endpoint(),
-            error,
-            status,
-        ));
+        infos.push(info);
     }
 
     Ok(actix_web::HttpResponse::Ok().json(infos))
 }
 
+/// Ingestion and staging metrics of every ingester
+#[utoipa::path(
+    get,
+    path = "/api/v1/cluster/metrics",
+    tag = "cluster",
+    responses((status = 200, description = "Metrics of every ingester", body = Vec<crate::metrics::prom_utils::Metrics>))
+)]
 pub async fn get_cluster_metrics() -> Result<impl Responder, PostError> {
     let ingester_metadata = get_ingester_info().await.map_err(|err| {
         log::error!("Fatal: failed to get ingester info: {:?}", err);
@@ -316,11 +545,13 @@ pub async fn get_cluster_metrics() -> Result<impl Responder, PostError> {
         ))
         .unwrap();
 
-        let res = reqwest::Client::new()
-            .get(uri)
-            .header(header::CONTENT_TYPE, "application/json")
-            .send()
-            .await;
+        let res = node_client::send(&ingester.domain_name, || {
+            CLIENT
+                .get(uri.clone())
+                .header(header::AUTHORIZATION, ingester.metrics_authorization())
+                .header(header::CONTENT_TYPE, "application/json")
+        })
+        .await;
 
         if let Ok(res) = res {
             let text = res.text().await.map_err(PostError::NetworkError)?;
@@ -346,47 +577,94 @@ pub async fn get_cluster_metrics() -> Result<impl Responder, PostError> {
     Ok(actix_web::HttpResponse::Ok().json(dresses))
 }
 
-// update the .query.json file and return the new IngesterMetadataArr
+// ingest events through the first live ingester, the query server does not upload staging data
+pub async fn forward_events_to_ingester(stream_name: &str, body: Bytes) -> anyhow::Result<()> {
+    for ingester in get_ingester_info().await? {
+        if !check_liveness(&ingester.domain_name).await {
+            continue;
+        }
+
+        let url = format!(
+            "{}{}/ingest",
+            ingester.domain_name,
+            base_path_without_preceding_slash()
+        );
+        let res = node_client::send(&ingester.domain_name, || {
+            CLIENT
+                .post(&url)
+                .header(header::AUTHORIZATION, ingester.authorization())
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(STREAM_NAME_HEADER_KEY, stream_name)
+                .body(body.clone())
+        })
+        .await?;
+
+        if !res.status().is_success() {
+            anyhow::bail!(
+                "ingester {} returned {}: {}",
+                ingester.domain_name,
+                res.status(),
+                res.text().await.unwrap_or_default()
+            );
+        }
+        return Ok(());
+    }
+
+    anyhow::bail!("no live ingester to forward events to")
+}
+
+// metadata of every registered ingester, one entry per ingester id
 pub async fn get_ingester_info() -> anyhow::Result<IngesterMetadataArr> {
     let store = CONFIG.storage().get_object_store();
 
     let root_path = RelativePathBuf::from(PARSEABLE_ROOT_DIRECTORY);
     let arr = store
-        .get_objects(
-            Some(&root_path),
+        .stream_objects(
+            Some(root_path),
             Box::new(|file_name| file_name.starts_with("ingester")),
         )
-        .await?
-        .iter()
         // this unwrap will most definateley shoot me in the foot later
-        .map(|x| serde_json::from_slice::<IngesterMetadata>(x).unwrap_or_default())
-        .collect_vec();
+        .map_ok(|(_, x)| serde_json::from_slice::<IngesterMetadata>(&x).unwrap_or_default())
+        .try_collect()
+        .await?;
 
     Ok(arr)
 }
 
 pub async fn remove_ingester(req: HttpRequest) -> Result<impl Responder, PostError> {
-    let domain_name: String = req.match_info().get("ingester").unwrap().parse().unwrap();
-    let domain_name = to_url_string(domain_name);
+    let ingester: String = req.match_info().get("ingester").unwrap().parse().unwrap();
+    let msg = remove_ingester_metadata(ingester).await?;
+    Ok((msg, StatusCode::OK))
+}
+
+/// Remove the metadata of an ingester which is down, by its id or its domain:port
+pub async fn remove_ingester_metadata(ingester: String) -> Result<String, PostError> {
+    let known = get_ingester_info()
+        .await
+        .map_err(PostError::Invalid)?
+        .into_iter()
+        .find(|info| !info.ingester_id.is_empty() && info.ingester_id == ingester);
+    let (ingester_id, domain_name) = match known {
+        Some(info) => (info.ingester_id, info.domain_name),
+        // metadata written before ingesters had an id is removed by the ingester's address
+        None => {
+            let domain_name = to_url_string(ingester);
+            let url = Url::parse(&domain_name)
+                .map_err(|_| PostError::Invalid(anyhow::anyhow!("Node Not Found")))?;
+            let ingester_id = legacy_ingester_id(
+                url.host_str().unwrap_or_default(),
+                &url.port_or_known_default().unwrap_or_default().to_string(),
+            );
+            (ingester_id, domain_name)
+        }
+    };
 
     if check_liveness(&domain_name).await {
         return Err(PostError::Invalid(anyhow::anyhow!("Node Online")));
     }
 
-    let url = Url::parse(&domain_name).unwrap();
-    let ingester_meta_filename = ingester_metadata_path(
-        url.host_str().unwrap().to_owned(),
-        url.port().unwrap().to_string(),
-    )
-    .to_string();
-    let object_store = CONFIG.storage().get_object_store();
-    let msg = match object_store
-        .try_delete_ingester_meta(ingester_meta_filename)
-        .await
-    {
-        Ok(_) => {
-            format!("Node {} Removed Successfully", domain_name)
-        }
+    let msg = match delete_ingester_metadata(&ingester_id, &domain_name).await {
+        Ok(_) => format!("Node {} Removed Successfully", domain_name),
         Err(err) => {
             if matches!(err, ObjectStorageError::IoError(_)) {
                 format!("Node {} Not Found", domain_name)
@@ -397,5 +675,189 @@ pub async fn remove_ingester(req: HttpRequest) -> Result<impl Responder, PostErr
     };
 
     log::info!("{}", &msg);
-    Ok((msg, StatusCode::OK))
+    Ok(msg)
+}
+
+async fn delete_ingester_metadata(
+    ingester_id: &str,
+    domain_name: &str,
+) -> Result<(), ObjectStorageError> {
+    CONFIG
+        .storage()
+        .get_object_store()
+        .try_delete_ingester_meta(ingester_metadata_path(ingester_id).to_string())
+        .await?;
+    webhooks::notify(LifecycleEvent::IngesterLeft {
+        domain_name: domain_name.to_owned(),
+    });
+    Ok(())
+}
+
+// ingesters registered before heartbeats were introduced are never considered stale
+pub(crate) fn is_stale(
+    ingester: &IngesterMetadata,
+    now: DateTime<Utc>,
+    stale_after: Duration,
+) -> bool {
+    let stale_after =
+        chrono::Duration::from_std(stale_after).unwrap_or(chrono::Duration::max_value());
+    ingester
+        .last_heartbeat
+        .is_some_and(|heartbeat| now.signed_duration_since(heartbeat) > stale_after)
+}
+
+/// Check the ingester heartbeats on the query server, stale ingesters are logged and,
+/// if enabled, removed when they can not be reached either
+pub fn init_stale_ingester_scheduler() {
+    let mut scheduler = AsyncScheduler::new();
+    scheduler
+        .every(STALE_CHECK_INTERVAL_MINUTES.minutes())
+        .run(remove_stale_ingesters);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+async fn remove_stale_ingesters() {
+    let ingesters = match get_ingester_info().await {
+        Ok(ingesters) => ingesters,
+        Err(err) => {
+            log::warn!("could not check for stale ingesters: {err}");
+            return;
+        }
+    };
+
+    // shards are moved off stale ingesters whether or not they are removed
+    sharding::rebalance(&ingesters).await;
+
+    let now = Utc::now();
+    for ingester in ingesters
+        .into_iter()
+        .filter(|ingester| is_stale(ingester, now, CONFIG.parseable.ingester_stale_after))
+    {
+        log::warn!(
+            "ingester {} ({}) is stale, last heartbeat at {}",
+            ingester.ingester_id,
+            ingester.domain_name,
+            ingester.last_heartbeat.unwrap_or_default()
+        );
+        if !CONFIG.parseable.ingester_auto_remove {
+            continue;
+        }
+        // a reachable ingester only failed to write its heartbeat
+        if check_liveness(&ingester.domain_name).await {
+            continue;
+        }
+        match delete_ingester_metadata(&ingester.ingester_id, &ingester.domain_name).await {
+            Ok(()) => log::info!("removed stale ingester {}", ingester.domain_name),
+            Err(err) => log::error!(
+                "could not remove stale ingester {}: {err}",
+                ingester.domain_name
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use std::sync::Mutex;
+
+    use super::report::ClusterOpReport;
+    use super::{is_stale, PreparedStreamCreation};
+    use crate::handlers::http::modal::IngesterMetadata;
+
+    #[test]
+    fn stale_after_missed_heartbeats() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 5, 14, 0, 0).unwrap();
+        let stale_after = Duration::from_secs(600);
+        let ingester = |minutes_ago: Option<i64>| IngesterMetadata {
+            last_heartbeat: minutes_ago.map(|minutes| now - chrono::Duration::minutes(minutes)),
+            ..Default::default()
+        };
+
+        assert!(!is_stale(&ingester(Some(2)), now, stale_after));
+        assert!(is_stale(&ingester(Some(11)), now, stale_after));
+        // registered before heartbeats
+        assert!(!is_stale(&ingester(None), now, stale_after));
+    }
+
+    fn ingester(domain_name: &str) -> IngesterMetadata {
+        IngesterMetadata {
+            domain_name: domain_name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn response(status: u16) -> reqwest::Response {
+        http::Response::builder()
+            .status(status)
+            .body("")
+            .unwrap()
+            .into()
+    }
+
+    fn prepared(ingesters: &[&str]) -> PreparedStreamCreation {
+        PreparedStreamCreation {
+            transaction: "01HQ".to_owned(),
+            stream_name: "app".to_owned(),
+            ingesters: ingesters.iter().map(|name| ingester(name)).collect(),
+            unconfirmed: Vec::new(),
+            report: ClusterOpReport::new("create log stream app"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_commit_is_rolled_back_on_every_ingester() {
+        let rolled_back = Mutex::new(Vec::new());
+        let result = prepared(&["a", "b", "c"])
+            .commit_with(
+                |ingester| {
+                    let status = if ingester.domain_name == "b" {
+                        500
+                    } else {
+                        200
+                    };
+                    async move { Ok(response(status)) }
+                },
+                |ingester, created| {
+                    rolled_back
+                        .lock()
+                        .unwrap()
+                        .push((ingester.domain_name.clone(), created));
+                    async { Ok(response(200)) }
+                },
+            )
+            .await;
+
+        let report = result.unwrap_err();
+        assert!(!report.is_success());
+        assert_eq!(
+            *rolled_back.lock().unwrap(),
+            vec![
+                ("a".to_owned(), true),
+                ("b".to_owned(), true),
+                ("c".to_owned(), false)
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn commit_on_every_ingester_succeeds() {
+        let report = prepared(&["a", "b"])
+            .commit_with(
+                |_| async { Ok(response(200)) },
+                |_, _| async { panic!("nothing to roll back") },
+            )
+            .await
+            .unwrap();
+        assert!(report.is_success());
+    }
 }
@@ -0,0 +1,350 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Requests to other nodes of the cluster.
+//!
+//! Requests failing to connect or timing out are retried up to `P_CLUSTER_REQUEST_RETRIES` times
+//! with jittered exponential backoff, each attempt may take `P_CLUSTER_REQUEST_TIMEOUT` unless
+//! the request sets a timeout of its own. Only requests with idempotent methods are retried,
+//! unless sent with [`send_retried`], as a failed attempt may have reached the node. Responses
+//! are returned as is, whatever their status.
+//! A node failing [`FAILURE_THRESHOLD`] requests in a row is not contacted for [`OPEN_FOR`],
+//! requests to it fail right away instead of waiting on timeouts.
+//!
+//! All requests between nodes go through [`CLIENT`], so connections to a node are kept open and
+//! reused instead of doing a TCP and TLS handshake per request.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http::{Method, StatusCode};
+use once_cell::sync::Lazy;
+use rand::Rng;
+
+use crate::handlers::http::middleware::current_request_id;
+use crate::handlers::REQUEST_ID_KEY;
+use crate::metrics::{CLUSTER_REQUESTS, CLUSTER_REQUESTS_IN_FLIGHT, CLUSTER_REQUEST_TIME};
+use crate::option::CONFIG;
+use crate::tls;
+
+// consecutive failed requests after which a node is not contacted for a while
+const FAILURE_THRESHOLD: u32 = 5;
+const OPEN_FOR: Duration = Duration::from_secs(30);
+const BASE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// idle connections kept open to every node, and for how long
+const POOL_MAX_IDLE_PER_HOST: usize = 32;
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Client for requests to other nodes. Nodes serving TLS with the certificate of this node are
+/// trusted. The client has no timeout of its own, a timeout set on the client would cap requests
+/// which set a longer one, so requests sent with [`send`] get `P_CLUSTER_REQUEST_TIMEOUT` unless
+/// they set a timeout and other requests have to set one.
+pub static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    build_client(tls::client_builder(
+        CONFIG.parseable.tls_cert_path.as_deref(),
+    ))
+});
+
+fn build_client(builder: reqwest::ClientBuilder) -> reqwest::Client {
+    builder
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .build()
+        .expect("cluster client can be built")
+}
+
+// requests without a timeout of their own get the default one
+fn default_timeout(request: &mut reqwest::Request, timeout: Duration) {
+    request.timeout_mut().get_or_insert(timeout);
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn is_open(&self, now: Instant) -> bool {
+        self.open_until.is_some_and(|until| until > now)
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        if success {
+            *self = Breaker::default();
+            return;
+        }
+        self.failures += 1;
+        if self.failures >= FAILURE_THRESHOLD {
+            self.open_until = Some(now + OPEN_FOR);
+        }
+    }
+}
+
+// circuit breakers by node, keyed by domain name
+static BREAKERS: Lazy<Mutex<HashMap<String, Breaker>>> = Lazy::new(Mutex::default);
+
+fn is_open(node: &str) -> bool {
+    BREAKERS
+        .lock()
+        .unwrap()
+        .get(node)
+        .is_some_and(|breaker| breaker.is_open(Instant::now()))
+}
+
+fn record(node: &str, success: bool) {
+    BREAKERS
+        .lock()
+        .unwrap()
+        .entry(node.to_owned())
+        .or_default()
+        .record(success, Instant::now());
+}
+
+/// Upper bound of the wait before retry `attempt`, the actual wait is picked below it
+fn backoff_ceiling(attempt: u32) -> Duration {
+    BASE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+// the request may not have reached the node, or the node did not answer in time. Errors building
+// the request, e.g. a bad url, fail the same way on every attempt
+fn should_retry(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_timeout()
+}
+
+// sending these again has the same effect on the node as sending them once
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    )
+}
+
+async fn execute(node: &str, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+    let in_flight = CLUSTER_REQUESTS_IN_FLIGHT.with_label_values(&[node]);
+    in_flight.inc();
+    let timer = CLUSTER_REQUEST_TIME
+        .with_label_values(&[node])
+        .start_timer();
+    let result = CLIENT.execute(request).await;
+    timer.observe_duration();
+    in_flight.dec();
+
+    let outcome = match &result {
+        Ok(res) => res.status().as_u16().to_string(),
+        Err(err) if err.is_timeout() => "timeout".to_owned(),
+        Err(_) => "error".to_owned(),
+    };
+    CLUSTER_REQUESTS.with_label_values(&[node, &outcome]).inc();
+    result
+}
+
+/// Send a request to a node, `request` builds a new request with [`CLIENT`] for every attempt.
+/// After the last attempt its response is returned as is, whatever the status. Requests with
+/// methods which are not idempotent, e.g. POST, are sent once.
+pub async fn send<F>(node: &str, request: F) -> Result<reqwest::Response, NodeRequestError>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    send_attempts(node, false, request).await
+}
+
+/// Like [`send`], but failed requests are retried whatever their method. Only for requests the
+/// node handles the same when it gets them again, e.g. a POST carrying a transaction id.
+pub async fn send_retried<F>(node: &str, request: F) -> Result<reqwest::Response, NodeRequestError>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    send_attempts(node, true, request).await
+}
+
+async fn send_attempts<F>(
+    node: &str,
+    retry_any_method: bool,
+    request: F,
+) -> Result<reqwest::Response, NodeRequestError>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    if is_open(node) {
+        return Err(NodeRequestError::CircuitOpen(node.to_owned()));
+    }
+
+    let retries = CONFIG.parseable.cluster_request_retries;
+    let mut attempt = 0;
+    loop {
+        let mut builder = request();
+        // errors of the node can be correlated with the request they were made for
+        if let Some(id) = current_request_id() {
+            builder = builder.header(REQUEST_ID_KEY, id);
+        }
+        let mut request = builder.build()?;
+        default_timeout(&mut request, CONFIG.parseable.cluster_request_timeout);
+        let may_retry = retry_any_method || is_idempotent(request.method());
+
+        let result = execute(node, request).await;
+        let retryable = result.as_ref().is_err_and(should_retry);
+        let failed = match &result {
+            Ok(res) => res.status().is_server_error(),
+            Err(_) => true,
+        };
+        record(node, !failed);
+
+        if !retryable || !may_retry || attempt >= retries || is_open(node) {
+            return result.map_err(NodeRequestError::Request);
+        }
+
+        let ceiling = backoff_ceiling(attempt).as_millis() as u64;
+        let wait = rand::thread_rng().gen_range(0..=ceiling);
+        log::warn!("request to {node} failed, retrying in {wait}ms");
+        tokio::time::sleep(Duration::from_millis(wait)).await;
+        attempt += 1;
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NodeRequestError {
+    #[error("node {0} failed too many requests in a row, not contacting it for now")]
+    CircuitOpen(String),
+    #[error("{0}")]
+    Request(#[from] reqwest::Error),
+}
+
+impl NodeRequestError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            NodeRequestError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+            NodeRequestError::Request(err) if err.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            NodeRequestError::Request(err) => err.status().unwrap_or(StatusCode::BAD_GATEWAY),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http::Method;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::{
+        backoff_ceiling, build_client, default_timeout, is_idempotent, should_retry, Breaker,
+        FAILURE_THRESHOLD, MAX_BACKOFF, OPEN_FOR,
+    };
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record(false, now);
+        }
+        assert!(!breaker.is_open(now));
+
+        // a success in between starts the count over
+        breaker.record(true, now);
+        for _ in 1..FAILURE_THRESHOLD {
+            breaker.record(false, now);
+        }
+        assert!(!breaker.is_open(now));
+
+        breaker.record(false, now);
+        assert!(breaker.is_open(now));
+        assert!(!breaker.is_open(now + OPEN_FOR));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_ceiling() {
+        assert_eq!(backoff_ceiling(0), Duration::from_millis(100));
+        assert_eq!(backoff_ceiling(3), Duration::from_millis(800));
+        assert_eq!(backoff_ceiling(10), MAX_BACKOFF);
+        assert_eq!(backoff_ceiling(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn only_idempotent_methods_are_retried() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(is_idempotent(&Method::DELETE));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+
+    #[tokio::test]
+    async fn only_connect_errors_and_timeouts_are_retried() {
+        let client = reqwest::Client::new();
+
+        let err = client.get("not a url").build().unwrap_err();
+        assert!(!should_retry(&err));
+
+        // nothing listens on the port
+        let err = client.get("http://127.0.0.1:1").send().await.unwrap_err();
+        assert!(should_retry(&err));
+    }
+
+    // a node answering every request after `delay`
+    async fn slow_node(delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = socket
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                        .await;
+                });
+            }
+        });
+        format!("http://{addr}/")
+    }
+
+    #[tokio::test]
+    async fn requests_get_the_default_timeout_unless_they_set_one() {
+        let client = build_client(reqwest::Client::builder());
+        let url = slow_node(Duration::from_millis(200)).await;
+
+        let mut request = client.get(&url).build().unwrap();
+        default_timeout(&mut request, Duration::from_millis(50));
+        assert_eq!(request.timeout(), Some(&Duration::from_millis(50)));
+        let err = client.execute(request).await.unwrap_err();
+        assert!(err.is_timeout());
+
+        // a timeout longer than the default is not capped by the client
+        let mut request = client
+            .get(&url)
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        default_timeout(&mut request, Duration::from_millis(50));
+        assert_eq!(request.timeout(), Some(&Duration::from_secs(5)));
+        let res = client.execute(request).await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "ok");
+    }
+}
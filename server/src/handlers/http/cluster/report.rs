@@ -0,0 +1,168 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::body::BoxBody;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use http::StatusCode;
+
+use super::node_client::NodeRequestError;
+
+/// Outcome of an operation forwarded to every ingester, node by node
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterOpReport {
+    operation: String,
+    nodes: Vec<NodeOutcome>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeOutcome {
+    node: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// whether sending the same request again may succeed
+    retriable: bool,
+}
+
+impl ClusterOpReport {
+    pub fn new(operation: impl Into<String>) -> Self {
+        Self {
+            operation: operation.into(),
+            nodes: Vec::new(),
+        }
+    }
+
+    pub fn succeeded(&mut self, node: &str) {
+        self.push(node, true, None, false);
+    }
+
+    /// node left out of the operation, it catches up on its own
+    pub fn skipped(&mut self, node: &str, reason: impl Into<String>) {
+        self.push(node, true, Some(reason.into()), false);
+    }
+
+    pub fn failed(&mut self, node: &str, reason: impl Into<String>, retriable: bool) {
+        self.push(node, false, Some(reason.into()), retriable);
+    }
+
+    /// Add the outcomes of another report to this one
+    pub fn extend(&mut self, other: ClusterOpReport) {
+        self.nodes.extend(other.nodes);
+    }
+
+    fn push(&mut self, node: &str, success: bool, reason: Option<String>, retriable: bool) {
+        self.nodes.push(NodeOutcome {
+            node: node.to_owned(),
+            success,
+            reason,
+            retriable,
+        });
+    }
+
+    /// Record the outcome of a request to a node, returns whether it succeeded
+    pub async fn record(
+        &mut self,
+        node: &str,
+        res: Result<reqwest::Response, NodeRequestError>,
+    ) -> bool {
+        match res {
+            Ok(res) if res.status().is_success() => {
+                self.succeeded(node);
+                true
+            }
+            Ok(res) => {
+                let status = res.status();
+                let text = res.text().await.unwrap_or_default();
+                let retriable = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+                self.failed(node, format!("{status}: {text}"), retriable);
+                false
+            }
+            Err(err) => {
+                let retriable = match &err {
+                    NodeRequestError::CircuitOpen(_) => true,
+                    NodeRequestError::Request(err) => {
+                        err.is_timeout() || err.is_connect() || err.is_request()
+                    }
+                };
+                self.failed(node, err.to_string(), retriable);
+                false
+            }
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.nodes.iter().all(|outcome| outcome.success)
+    }
+
+    /// Status of an operation which could not be carried out because of the failed nodes
+    pub fn failure_status(&self) -> StatusCode {
+        let retriable = self
+            .nodes
+            .iter()
+            .filter(|outcome| !outcome.success)
+            .all(|outcome| outcome.retriable);
+        if retriable {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::BAD_REQUEST
+        }
+    }
+
+    /// Response for an operation which could not be carried out because of the failed nodes
+    pub fn into_failure_response(self) -> HttpResponse {
+        HttpResponse::build(self.failure_status()).json(self)
+    }
+}
+
+// 207 when the operation went through on some of the nodes only
+impl Responder for ClusterOpReport {
+    type Body = BoxBody;
+
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
+        let status = if self.is_success() {
+            StatusCode::OK
+        } else {
+            StatusCode::MULTI_STATUS
+        };
+        HttpResponse::build(status).json(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::ClusterOpReport;
+
+    #[test]
+    fn failures_decide_status() {
+        let mut report = ClusterOpReport::new("create log stream app");
+        report.succeeded("http://ingest-0:8000/");
+        report.skipped("http://ingest-1:8000/", "ingester is not live");
+        assert!(report.is_success());
+
+        report.failed("http://ingest-2:8000/", "timed out", true);
+        assert!(!report.is_success());
+        assert_eq!(report.failure_status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        report.failed("http://ingest-3:8000/", "400 Bad Request: invalid", false);
+        assert_eq!(report.failure_status(), StatusCode::BAD_REQUEST);
+    }
+}
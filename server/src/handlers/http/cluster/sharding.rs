@@ -0,0 +1,401 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Assignment of stream shards to ingesters.
+//!
+//! A hot stream can be split into shards, each owned by a different ingester. The ownership map is
+//! kept by the query server in object storage, clients pick the ingesters to send events of a
+//! stream to from it and queries only ask the owners for the events still in staging. Shards of
+//! ingesters which went stale are moved to live ingesters.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, Responder};
+use arrow_array::RecordBatch;
+use arrow_schema::Schema;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use http::StatusCode;
+use once_cell::sync::Lazy;
+
+use super::node_client::CLIENT;
+use super::{get_ingester_info, is_stale};
+use crate::event::format;
+use crate::handlers::http::base_path_without_preceding_slash;
+use crate::handlers::http::modal::IngesterMetadata;
+use crate::handlers::http::problem::Problem;
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::storage::object_storage::shard_map_path;
+use crate::storage::ObjectStorageError;
+use crate::utils::arrow::adapt_batch;
+
+// ingesters not answering within this time are left out of the query
+const STAGING_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShardMap {
+    /// incremented with every change of the assignment
+    #[serde(default)]
+    version: u64,
+    #[serde(default)]
+    streams: BTreeMap<String, StreamShards>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamShards {
+    /// id of the ingester owning each shard, a shard is identified by its position
+    owners: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShardRequest {
+    /// number of shards, 0 lets every ingester accept events of the stream again
+    shards: usize,
+}
+
+// map as last read from or written to object storage
+static SHARD_MAP: Lazy<RwLock<ShardMap>> = Lazy::new(RwLock::default);
+
+impl ShardMap {
+    // shards owned by each ingester, over all streams
+    fn shard_counts(&self) -> HashMap<&str, usize> {
+        let mut counts = HashMap::new();
+        for shards in self.streams.values() {
+            for owner in &shards.owners {
+                *counts.entry(owner.as_str()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Split a stream into `shards` shards owned by distinct live ingesters. Owners which are
+    /// still live keep their shards, new shards go to the ingesters owning the fewest shards.
+    fn assign(
+        &mut self,
+        stream_name: &str,
+        shards: usize,
+        live: &[String],
+    ) -> Result<bool, ShardError> {
+        if shards == 0 {
+            let removed = self.streams.remove(stream_name).is_some();
+            if removed {
+                self.version += 1;
+            }
+            return Ok(removed);
+        }
+        if shards > live.len() {
+            return Err(ShardError::NotEnoughIngesters {
+                shards,
+                live: live.len(),
+            });
+        }
+
+        let current = self
+            .streams
+            .get(stream_name)
+            .map(|stream| stream.owners.clone())
+            .unwrap_or_default();
+        let mut owners: Vec<String> = Vec::with_capacity(shards);
+        for owner in &current {
+            if owners.len() < shards && live.contains(owner) && !owners.contains(owner) {
+                owners.push(owner.clone());
+            }
+        }
+
+        let counts = self.shard_counts();
+        let mut candidates: Vec<&String> = live.iter().filter(|id| !owners.contains(id)).collect();
+        candidates.sort_by_key(|id| (counts.get(id.as_str()).copied().unwrap_or(0), *id));
+        owners.extend(candidates.into_iter().take(shards - owners.len()).cloned());
+
+        if owners == current {
+            return Ok(false);
+        }
+        self.streams
+            .insert(stream_name.to_owned(), StreamShards { owners });
+        self.version += 1;
+        Ok(true)
+    }
+
+    /// Move shards of ingesters which are no longer live, streams with more shards than
+    /// live ingesters are split over all of them
+    fn rebalance(&mut self, live: &[String]) -> bool {
+        let streams: Vec<(String, usize)> = self
+            .streams
+            .iter()
+            .map(|(name, shards)| (name.clone(), shards.owners.len()))
+            .collect();
+
+        let mut changed = false;
+        for (stream_name, shards) in streams {
+            if live.is_empty() {
+                break;
+            }
+            changed |= self
+                .assign(&stream_name, shards.min(live.len()), live)
+                .unwrap_or(false);
+        }
+        changed
+    }
+}
+
+/// Ingesters owning shards of the stream, `None` if the stream is not sharded
+pub fn owners(stream_name: &str) -> Option<Vec<String>> {
+    SHARD_MAP
+        .read()
+        .unwrap()
+        .streams
+        .get(stream_name)
+        .map(|shards| shards.owners.clone())
+}
+
+// ids of the registered ingesters with a recent heartbeat
+fn live_ingesters(ingesters: &[IngesterMetadata], now: DateTime<Utc>) -> Vec<String> {
+    ingesters
+        .iter()
+        .filter(|ingester| !ingester.ingester_id.is_empty())
+        .filter(|ingester| !is_stale(ingester, now, CONFIG.parseable.ingester_stale_after))
+        .map(|ingester| ingester.ingester_id.clone())
+        .collect()
+}
+
+async fn read_shard_map() -> Result<ShardMap, ObjectStorageError> {
+    match CONFIG
+        .storage()
+        .get_object_store()
+        .get_object(&shard_map_path())
+        .await
+    {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err))),
+        Err(ObjectStorageError::NoSuchKey(_)) => Ok(ShardMap::default()),
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(ShardMap::default())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn write_shard_map(map: &ShardMap) -> Result<(), ObjectStorageError> {
+    let body = serde_json::to_vec(map).expect("shard map serializes");
+    CONFIG
+        .storage()
+        .get_object_store()
+        .put_object(&shard_map_path(), body.into())
+        .await?;
+    *SHARD_MAP.write().unwrap() = map.clone();
+    Ok(())
+}
+
+/// Read the ownership map on start of the query server
+pub async fn load() {
+    match read_shard_map().await {
+        Ok(map) => *SHARD_MAP.write().unwrap() = map,
+        Err(err) => log::warn!("could not load the stream shard map: {err}"),
+    }
+}
+
+/// Move shards away from ingesters which went stale
+pub async fn rebalance(ingesters: &[IngesterMetadata]) {
+    let live = live_ingesters(ingesters, Utc::now());
+    let mut map = match read_shard_map().await {
+        Ok(map) => map,
+        Err(err) => {
+            log::warn!("could not rebalance stream shards: {err}");
+            return;
+        }
+    };
+    if !map.rebalance(&live) {
+        return;
+    }
+    match write_shard_map(&map).await {
+        Ok(()) => log::info!("stream shards rebalanced, version {}", map.version),
+        Err(err) => log::error!("could not store the rebalanced stream shards: {err}"),
+    }
+}
+
+// Handler for GET /api/v1/cluster/shards
+pub async fn get_shards() -> Result<impl Responder, ShardError> {
+    let map = read_shard_map().await?;
+    *SHARD_MAP.write().unwrap() = map.clone();
+    Ok(web::Json(map))
+}
+
+// Handler for PUT /api/v1/cluster/shards/{logstream}
+// split a stream over the given number of ingesters
+pub async fn put_shards(
+    req: HttpRequest,
+    body: web::Json<ShardRequest>,
+) -> Result<impl Responder, ShardError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    if !STREAM_INFO.stream_exists(&stream_name) {
+        return Err(ShardError::StreamNotFound(stream_name));
+    }
+
+    let ingesters = get_ingester_info().await.map_err(ShardError::Anyhow)?;
+    let live = live_ingesters(&ingesters, Utc::now());
+    let mut map = read_shard_map().await?;
+    if map.assign(&stream_name, body.shards, &live)? {
+        write_shard_map(&map).await?;
+        log::info!(
+            "stream {stream_name} split into {} shards, version {}",
+            body.shards,
+            map.version
+        );
+    }
+
+    Ok(web::Json(
+        map.streams.get(&stream_name).cloned().unwrap_or_default(),
+    ))
+}
+
+async fn fetch_staging(
+    ingester: &IngesterMetadata,
+    stream_name: &str,
+) -> Result<RecordBatch, anyhow::Error> {
+    let body = CLIENT
+        .get(format!(
+            "{}{}/logstream/{stream_name}/staging",
+            ingester.domain_name,
+            base_path_without_preceding_slash()
+        ))
+        .header(header::AUTHORIZATION, ingester.authorization())
+        .timeout(STAGING_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    format::arrow::Event::read_ipc_stream(body)
+}
+
+// a batch can only be adapted if the types of its fields agree with the stream schema
+fn fits_schema(schema: &Schema, rb: &RecordBatch) -> bool {
+    rb.schema().fields().iter().all(|field| {
+        schema
+            .field_with_name(field.name())
+            .is_ok_and(|table_field| table_field.data_type() == field.data_type())
+    })
+}
+
+/// Events of the stream still in the staging of the ingesters. Every ingester is asked, even
+/// for a sharded stream, since ingesters which owned a shard before it was moved keep its
+/// events in staging until they are uploaded.
+pub async fn staging_records(stream_name: &str, schema: &Arc<Schema>) -> Vec<RecordBatch> {
+    let ingesters = match get_ingester_info().await {
+        Ok(ingesters) => ingesters,
+        Err(err) => {
+            log::warn!("could not list ingesters to query staging of {stream_name}: {err}");
+            return Vec::new();
+        }
+    };
+    let now = Utc::now();
+    let ingesters: Vec<IngesterMetadata> = ingesters
+        .into_iter()
+        .filter(|ingester| !is_stale(ingester, now, CONFIG.parseable.ingester_stale_after))
+        .collect();
+
+    let results = join_all(
+        ingesters
+            .iter()
+            .map(|ingester| fetch_staging(ingester, stream_name)),
+    )
+    .await;
+
+    let mut records = Vec::new();
+    for (ingester, result) in ingesters.iter().zip(results) {
+        match result {
+            Ok(rb) if rb.num_rows() == 0 => {}
+            Ok(rb) if fits_schema(schema, &rb) => records.push(adapt_batch(schema, &rb)),
+            Ok(_) => log::warn!(
+                "staging of {stream_name} on {} does not match the stream schema",
+                ingester.domain_name
+            ),
+            Err(err) => log::warn!(
+                "could not query staging of {stream_name} on {}: {err}",
+                ingester.domain_name
+            ),
+        }
+    }
+    records
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShardError {
+    #[error("Log stream {0} does not exist")]
+    StreamNotFound(String),
+    #[error("{shards} shards need as many live ingesters, only {live} are live")]
+    NotEnoughIngesters { shards: usize, live: usize },
+    #[error("Storage Error {0}")]
+    Storage(#[from] ObjectStorageError),
+    #[error("Error: {0}")]
+    Anyhow(anyhow::Error),
+}
+
+impl actix_web::ResponseError for ShardError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::StreamNotFound(_) => StatusCode::NOT_FOUND,
+            Self::NotEnoughIngesters { .. } => StatusCode::BAD_REQUEST,
+            Self::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardMap;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn shards_spread_and_move_off_lost_ingesters() {
+        let live = ids(&["a", "b", "c"]);
+        let mut map = ShardMap::default();
+
+        assert!(map.assign("hot", 2, &live).unwrap());
+        assert_eq!(map.streams["hot"].owners, ids(&["a", "b"]));
+        // the least loaded ingester gets the next stream
+        assert!(map.assign("warm", 1, &live).unwrap());
+        assert_eq!(map.streams["warm"].owners, ids(&["c"]));
+        // nothing changes for the same assignment
+        assert!(!map.assign("hot", 2, &live).unwrap());
+        assert!(map.assign("hot", 4, &live).is_err());
+
+        // "a" is lost, its shard moves while "b" keeps its own
+        assert!(map.rebalance(&ids(&["b", "c"])));
+        assert_eq!(map.streams["hot"].owners, ids(&["b", "c"]));
+        assert_eq!(map.version, 3);
+
+        assert!(map.assign("hot", 0, &live).unwrap());
+        assert!(!map.streams.contains_key("hot"));
+    }
+}
@@ -0,0 +1,375 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::{web, Responder};
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use serde_json::Value;
+use url::Url;
+
+use super::node_client::CLIENT;
+use super::{get_ingester_info, is_stale};
+use crate::about;
+use crate::handlers::http::base_path_without_preceding_slash;
+use crate::handlers::http::logstream::error::StreamError;
+use crate::handlers::http::modal::IngesterMetadata;
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::storage::LogStream;
+
+// ingesters not answering within this time are shown as unreachable
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+const STORAGE_NODE_ID: &str = "storage";
+const QUERY_NODE_ID: &str = "query";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    Query,
+    Ingester,
+    Storage,
+    Stream,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeDetails {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    staging: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reachable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_heartbeat: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Node {
+    id: String,
+    kind: NodeKind,
+    label: String,
+    #[serde(flatten)]
+    details: NodeDetails,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    /// query node fetching live data and stats from an ingester
+    Queries,
+    /// node reading from object storage
+    Reads,
+    /// ingester uploading staging data to object storage
+    Uploads,
+    /// ingester accepting events of a stream
+    Ingests,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Edge {
+    from: String,
+    to: String,
+    kind: EdgeKind,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Topology {
+    generated_at: DateTime<Utc>,
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+// what an ingester reports about itself, absent when it can not be reached
+struct IngesterState {
+    version: Option<String>,
+    commit: Option<String>,
+    staging: Option<String>,
+    streams: Vec<String>,
+}
+
+fn stream_node_id(stream: &str) -> String {
+    format!("stream:{stream}")
+}
+
+fn ingester_node_id(ingester: &IngesterMetadata) -> String {
+    // ingesters registered before they had an id are identified by their address
+    if ingester.ingester_id.is_empty() {
+        format!("ingester:{}", ingester.domain_name)
+    } else {
+        format!("ingester:{}", ingester.ingester_id)
+    }
+}
+
+async fn ingester_state(ingester: &IngesterMetadata) -> Option<IngesterState> {
+    let get = |path: &str| {
+        CLIENT
+            .get(format!(
+                "{}{}/{path}",
+                ingester.domain_name,
+                base_path_without_preceding_slash()
+            ))
+            .timeout(NODE_TIMEOUT)
+            .header(header::AUTHORIZATION, ingester.authorization())
+            .send()
+    };
+
+    let about: Value = get("about").await.ok()?.json().await.ok()?;
+    let streams: Vec<LogStream> = match get("logstream").await {
+        Ok(res) => res.json().await.unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    let field = |name: &str| about.get(name).and_then(Value::as_str).map(str::to_owned);
+
+    Some(IngesterState {
+        version: field("version"),
+        commit: field("commit"),
+        staging: field("staging"),
+        streams: streams.into_iter().map(|stream| stream.name).collect(),
+    })
+}
+
+// the query node and object storage
+fn local_nodes() -> Vec<Node> {
+    let current = about::current();
+    vec![
+        Node {
+            id: QUERY_NODE_ID.to_owned(),
+            kind: NodeKind::Query,
+            label: CONFIG.parseable.address.clone(),
+            details: NodeDetails {
+                domain_name: CONFIG.parseable.domain_address.as_ref().map(Url::to_string),
+                version: Some(format!("v{}", current.released_version)),
+                commit: Some(current.commit_hash),
+                reachable: Some(true),
+                ..Default::default()
+            },
+        },
+        Node {
+            id: STORAGE_NODE_ID.to_owned(),
+            kind: NodeKind::Storage,
+            label: CONFIG.get_storage_mode_string().to_owned(),
+            details: NodeDetails {
+                domain_name: Some(CONFIG.storage().get_endpoint()),
+                ..Default::default()
+            },
+        },
+    ]
+}
+
+fn build(
+    mut nodes: Vec<Node>,
+    ingesters: Vec<(IngesterMetadata, Option<IngesterState>)>,
+    query_streams: Vec<String>,
+    stale_after: Duration,
+    now: DateTime<Utc>,
+) -> Topology {
+    let mut edges = vec![Edge {
+        from: QUERY_NODE_ID.to_owned(),
+        to: STORAGE_NODE_ID.to_owned(),
+        kind: EdgeKind::Reads,
+    }];
+    let mut streams: BTreeSet<String> = query_streams.into_iter().collect();
+
+    for (ingester, state) in ingesters {
+        let id = ingester_node_id(&ingester);
+        edges.push(Edge {
+            from: QUERY_NODE_ID.to_owned(),
+            to: id.clone(),
+            kind: EdgeKind::Queries,
+        });
+        edges.push(Edge {
+            from: id.clone(),
+            to: STORAGE_NODE_ID.to_owned(),
+            kind: EdgeKind::Uploads,
+        });
+
+        let mut details = NodeDetails {
+            domain_name: Some(ingester.domain_name.clone()),
+            reachable: Some(state.is_some()),
+            stale: Some(is_stale(&ingester, now, stale_after)),
+            last_heartbeat: ingester.last_heartbeat,
+            ..Default::default()
+        };
+        if let Some(state) = state {
+            details.version = state.version;
+            details.commit = state.commit;
+            details.staging = state.staging;
+            for stream in state.streams {
+                edges.push(Edge {
+                    from: id.clone(),
+                    to: stream_node_id(&stream),
+                    kind: EdgeKind::Ingests,
+                });
+                streams.insert(stream);
+            }
+        }
+
+        nodes.push(Node {
+            id,
+            kind: NodeKind::Ingester,
+            label: ingester.domain_name,
+            details,
+        });
+    }
+
+    nodes.extend(streams.into_iter().map(|stream| Node {
+        id: stream_node_id(&stream),
+        kind: NodeKind::Stream,
+        label: stream,
+        details: NodeDetails::default(),
+    }));
+
+    Topology {
+        generated_at: now,
+        nodes,
+        edges,
+    }
+}
+
+// Handler for GET /api/v1/cluster/topology
+// graph of the query node, the ingesters, object storage and the streams each ingester accepts
+pub async fn get_topology() -> Result<impl Responder, StreamError> {
+    let ingesters = get_ingester_info().await.map_err(StreamError::Anyhow)?;
+    let states = join_all(ingesters.iter().map(ingester_state)).await;
+
+    Ok(web::Json(build(
+        local_nodes(),
+        ingesters.into_iter().zip(states).collect(),
+        STREAM_INFO.list_streams(),
+        CONFIG.parseable.ingester_stale_after,
+        Utc::now(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use super::{
+        build, IngesterState, Node, NodeDetails, NodeKind, QUERY_NODE_ID, STORAGE_NODE_ID,
+    };
+    use crate::handlers::http::modal::IngesterMetadata;
+
+    fn ingester(id: &str, domain_name: &str, last_heartbeat_secs: i64) -> IngesterMetadata {
+        let mut ingester = IngesterMetadata::new(
+            "8000".to_owned(),
+            domain_name.to_owned(),
+            "v1.0.0".to_owned(),
+            "bucket".to_owned(),
+            "admin",
+            "admin",
+            id.to_owned(),
+        );
+        ingester.last_heartbeat = Some(Utc.timestamp_opt(last_heartbeat_secs, 0).unwrap());
+        ingester
+    }
+
+    #[test]
+    fn topology_links_ingesters_storage_and_streams() {
+        let local = vec![
+            Node {
+                id: QUERY_NODE_ID.to_owned(),
+                kind: NodeKind::Query,
+                label: "0.0.0.0:8000".to_owned(),
+                details: NodeDetails::default(),
+            },
+            Node {
+                id: STORAGE_NODE_ID.to_owned(),
+                kind: NodeKind::Storage,
+                label: "s3".to_owned(),
+                details: NodeDetails::default(),
+            },
+        ];
+        let reachable = IngesterState {
+            version: Some("v1.0.0".to_owned()),
+            commit: None,
+            staging: Some("/staging".to_owned()),
+            streams: vec!["web".to_owned()],
+        };
+        let ingesters = vec![
+            (ingester("a", "http://a:8000/", 95), Some(reachable)),
+            (ingester("", "http://b:8000/", 0), None),
+        ];
+
+        let topology = build(
+            local,
+            ingesters,
+            vec!["app".to_owned()],
+            Duration::from_secs(60),
+            Utc.timestamp_opt(100, 0).unwrap(),
+        );
+        let topology = serde_json::to_value(topology).unwrap();
+
+        assert_eq!(
+            topology["nodes"],
+            json!([
+                {"id": "query", "kind": "query", "label": "0.0.0.0:8000"},
+                {"id": "storage", "kind": "storage", "label": "s3"},
+                {
+                    "id": "ingester:a",
+                    "kind": "ingester",
+                    "label": "http://a:8000/",
+                    "domainName": "http://a:8000/",
+                    "version": "v1.0.0",
+                    "staging": "/staging",
+                    "reachable": true,
+                    "stale": false,
+                    "lastHeartbeat": "1970-01-01T00:01:35Z"
+                },
+                {
+                    "id": "ingester:http://b:8000/",
+                    "kind": "ingester",
+                    "label": "http://b:8000/",
+                    "domainName": "http://b:8000/",
+                    "reachable": false,
+                    "stale": true,
+                    "lastHeartbeat": "1970-01-01T00:00:00Z"
+                },
+                {"id": "stream:app", "kind": "stream", "label": "app"},
+                {"id": "stream:web", "kind": "stream", "label": "web"}
+            ])
+        );
+        assert_eq!(
+            topology["edges"],
+            json!([
+                {"from": "query", "to": "storage", "kind": "reads"},
+                {"from": "query", "to": "ingester:a", "kind": "queries"},
+                {"from": "ingester:a", "to": "storage", "kind": "uploads"},
+                {"from": "ingester:a", "to": "stream:web", "kind": "ingests"},
+                {"from": "query", "to": "ingester:http://b:8000/", "kind": "queries"},
+                {"from": "ingester:http://b:8000/", "to": "storage", "kind": "uploads"}
+            ])
+        );
+    }
+}
@@ -16,99 +16,85 @@
  *
  */
 
-use crate::handlers::http::{logstream::error::StreamError, modal::IngesterMetadata};
+use super::node_client::CLIENT;
+use crate::about;
+use crate::handlers::http::{
+    logstream::error::StreamError,
+    modal::{IngesterMetadata, DEFAULT_VERSION},
+};
+use crate::option::CONFIG;
 use actix_web::http::header;
 use chrono::{DateTime, Utc};
 use http::StatusCode;
 use itertools::Itertools;
 use reqwest::Response;
+use semver::Version;
 use serde::{Deserialize, Serialize};
 use url::Url;
+use utoipa::ToSchema;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct QueriedStats {
-    pub stream: String,
-    pub time: DateTime<Utc>,
-    pub ingestion: IngestionStats,
-    pub storage: StorageStats,
-}
-
-impl QueriedStats {
-    pub fn new(
-        stream: &str,
-        time: DateTime<Utc>,
-        ingestion: IngestionStats,
-        storage: StorageStats,
-    ) -> Self {
-        Self {
-            stream: stream.to_string(),
-            time,
-            ingestion,
-            storage,
-        }
-    }
-}
+// stats are sent as they are read by the client
+pub use parseable_client::types::{IngestionStats, QueriedStats, StorageStats};
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
 pub struct ClusterInfo {
-    domain_name: String,
-    reachable: bool,
-    staging_path: String,
-    storage_path: String,
-    error: Option<String>,  // error message if the ingester is not reachable
-    status: Option<String>, // status message if the ingester is reachable
+    pub ingester_id: String,
+    pub domain_name: String,
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    pub stale: bool, // no heartbeat within the stale period
+    pub reachable: bool,
+    pub staging_path: String,
+    pub storage_path: String,
+    pub version: Option<String>, // release of the ingester if it is reachable
+    pub commit: Option<String>,
+    pub version_skew: Option<VersionSkew>, // unknown if the ingester is not reachable
+    pub error: Option<String>,             // error message if the ingester is not reachable
+    pub status: Option<String>,            // status message if the ingester is reachable
 }
 
-impl ClusterInfo {
-    pub fn new(
-        domain_name: &str,
-        reachable: bool,
-        staging_path: String,
-        storage_path: String,
-        error: Option<String>,
-        status: Option<String>,
-    ) -> Self {
-        Self {
-            domain_name: domain_name.to_string(),
-            reachable,
-            staging_path,
-            storage_path,
-            error,
-            status,
-        }
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionSkew {
+    /// same release and commit as the query node
+    None,
+    /// another patch release or commit, the nodes work together
+    Compatible,
+    /// another major or minor release, or another metadata format
+    Incompatible,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct IngestionStats {
-    pub count: u64,
-    pub size: String,
-    pub format: String,
+/// Skew of an ingester running `version` at `commit` against the query node
+pub fn version_skew(version: &str, commit: &str) -> VersionSkew {
+    let current = about::current();
+    skew_between(
+        &current.released_version,
+        &current.commit_hash,
+        version,
+        commit,
+    )
 }
 
-impl IngestionStats {
-    pub fn new(count: u64, size: String, format: &str) -> Self {
-        Self {
-            count,
-            size,
-            format: format.to_string(),
-        }
+fn skew_between(
+    current: &Version,
+    current_commit: &str,
+    version: &str,
+    commit: &str,
+) -> VersionSkew {
+    let Ok(version) = Version::parse(version.trim_start_matches('v')) else {
+        return VersionSkew::Incompatible;
+    };
+    if version.major != current.major || version.minor != current.minor {
+        VersionSkew::Incompatible
+    } else if version != *current || commit != current_commit {
+        VersionSkew::Compatible
+    } else {
+        VersionSkew::None
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct StorageStats {
-    pub size: String,
-    pub format: String,
-}
-
-impl StorageStats {
-    pub fn new(size: String, format: &str) -> Self {
-        Self {
-            size,
-            format: format.to_string(),
-        }
-    }
+/// Streams are only synced to ingesters writing the metadata format of the query node
+pub fn has_compatible_metadata(ingester: &IngesterMetadata) -> bool {
+    ingester.version == DEFAULT_VERSION
 }
 
 pub fn merge_quried_stats(stats: Vec<QueriedStats>) -> QueriedStats {
@@ -188,9 +174,10 @@ pub async fn check_liveness(domain_name: &str) -> bool {
         }
     };
 
-    let reqw = reqwest::Client::new()
+    let reqw = CLIENT
         .get(uri)
         .header(header::CONTENT_TYPE, "application/json")
+        .timeout(CONFIG.parseable.cluster_request_timeout)
         .send()
         .await;
 
@@ -208,11 +195,11 @@ pub async fn send_stats_request(
         return Ok(None);
     }
 
-    let client = reqwest::Client::new();
-    let res = client
+    let res = CLIENT
         .get(url)
         .header(header::CONTENT_TYPE, "application/json")
-        .header(header::AUTHORIZATION, ingester.token)
+        .header(header::AUTHORIZATION, ingester.authorization())
+        .timeout(CONFIG.parseable.cluster_request_timeout)
         .send()
         .await
         .map_err(|err| {
@@ -267,3 +254,23 @@ pub fn to_url_string(str: String) -> String {
 
     format!("http://{}/", str)
 }
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use super::{skew_between, VersionSkew};
+
+    #[test]
+    fn skew_by_release() {
+        let current = Version::new(1, 2, 3);
+        let skew = |version, commit| skew_between(&current, "abc123", version, commit);
+
+        assert_eq!(skew("v1.2.3", "abc123"), VersionSkew::None);
+        assert_eq!(skew("v1.2.3", "def456"), VersionSkew::Compatible);
+        assert_eq!(skew("v1.2.0", "def456"), VersionSkew::Compatible);
+        assert_eq!(skew("v1.3.0", "def456"), VersionSkew::Incompatible);
+        assert_eq!(skew("v2.2.3", "abc123"), VersionSkew::Incompatible);
+        assert_eq!(skew("", ""), VersionSkew::Incompatible);
+    }
+}
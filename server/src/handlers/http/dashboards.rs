@@ -0,0 +1,363 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::HashSet;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use relative_path::RelativePathBuf;
+use ulid::Ulid;
+
+use crate::handlers::http::problem::Problem;
+use crate::option::CONFIG;
+use crate::storage::{ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+use crate::utils::actix::request_username;
+
+const DASHBOARDS_DIRECTORY: &str = "dashboards";
+// previous versions of a dashboard are kept under .history/<id>/<version>.json
+const HISTORY_DIRECTORY: &str = ".history";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PanelLayout {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Panel {
+    pub id: String,
+    pub title: String,
+    /// sql of the query backing this panel
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visualization: Option<String>,
+    pub layout: PanelLayout,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dashboard {
+    pub id: Ulid,
+    pub version: u64,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub panels: Vec<Panel>,
+    pub owner: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub updated_by: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DashboardRequest {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    panels: Vec<Panel>,
+    /// version the update is based on, rejected if the dashboard has changed since
+    #[serde(default)]
+    version: Option<u64>,
+}
+
+impl DashboardRequest {
+    fn validate(&self) -> Result<(), DashboardError> {
+        if self.name.trim().is_empty() {
+            return Err(DashboardError::Invalid("name cannot be empty".to_string()));
+        }
+        let mut ids = HashSet::new();
+        for panel in &self.panels {
+            if !ids.insert(&panel.id) {
+                return Err(DashboardError::Invalid(format!(
+                    "duplicate panel id {}",
+                    panel.id
+                )));
+            }
+            if panel.query.trim().is_empty() {
+                return Err(DashboardError::Invalid(format!(
+                    "panel {} has no query",
+                    panel.id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn dashboards_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, DASHBOARDS_DIRECTORY])
+}
+
+fn dashboard_path(id: Ulid) -> RelativePathBuf {
+    dashboards_path().join(format!("{id}.json"))
+}
+
+fn history_path(id: Ulid) -> RelativePathBuf {
+    dashboards_path()
+        .join(HISTORY_DIRECTORY)
+        .join(id.to_string())
+}
+
+fn parse_id(id: &str) -> Result<Ulid, DashboardError> {
+    Ulid::from_string(id).map_err(|_| DashboardError::NotFound(id.to_owned()))
+}
+
+// listing a prefix which was never written to fails on local storage
+fn empty_if_not_found<T: Default>(
+    res: Result<T, ObjectStorageError>,
+) -> Result<T, ObjectStorageError> {
+    match res {
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Ok(T::default())
+        }
+        res => res,
+    }
+}
+
+async fn get_dashboard(id: Ulid) -> Result<Dashboard, DashboardError> {
+    let store = CONFIG.storage().get_object_store();
+    match store.get_object(&dashboard_path(id)).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(ObjectStorageError::NoSuchKey(_)) => Err(DashboardError::NotFound(id.to_string())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn put_dashboard(dashboard: &Dashboard) -> Result<(), DashboardError> {
+    let store = CONFIG.storage().get_object_store();
+    store
+        .put_object(
+            &dashboard_path(dashboard.id),
+            serde_json::to_vec(dashboard)?.into(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Handler for GET /api/v1/dashboards
+pub async fn list() -> Result<impl Responder, DashboardError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = empty_if_not_found(
+        store
+            .get_objects(
+                Some(&dashboards_path()),
+                Box::new(|file_name| {
+                    file_name
+                        .strip_suffix(".json")
+                        .is_some_and(|id| Ulid::from_string(id).is_ok())
+                }),
+            )
+            .await,
+    )?;
+
+    let mut dashboards = objects
+        .iter()
+        .map(|bytes| serde_json::from_slice::<Dashboard>(bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+    dashboards.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(web::Json(dashboards))
+}
+
+// Handler for POST /api/v1/dashboards
+pub async fn post(
+    req: HttpRequest,
+    body: web::Json<DashboardRequest>,
+) -> Result<impl Responder, DashboardError> {
+    let body = body.into_inner();
+    body.validate()?;
+
+    let username = request_username(&req);
+    let now = Utc::now();
+    let dashboard = Dashboard {
+        id: Ulid::new(),
+        version: 1,
+        name: body.name,
+        description: body.description,
+        panels: body.panels,
+        owner: username.clone(),
+        created_at: now,
+        updated_at: now,
+        updated_by: username,
+    };
+    put_dashboard(&dashboard).await?;
+
+    Ok((web::Json(dashboard), StatusCode::CREATED))
+}
+
+// Handler for GET /api/v1/dashboards/{id}
+pub async fn get(id: web::Path<String>) -> Result<impl Responder, DashboardError> {
+    let dashboard = get_dashboard(parse_id(&id)?).await?;
+    Ok(web::Json(dashboard))
+}
+
+// Handler for PUT /api/v1/dashboards/{id}
+// the replaced definition is kept as a previous version
+pub async fn put(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<DashboardRequest>,
+) -> Result<impl Responder, DashboardError> {
+    let id = parse_id(&id)?;
+    let body = body.into_inner();
+    body.validate()?;
+
+    let current = get_dashboard(id).await?;
+    if body
+        .version
+        .is_some_and(|version| version != current.version)
+    {
+        return Err(DashboardError::VersionConflict(current.version));
+    }
+
+    let store = CONFIG.storage().get_object_store();
+    store
+        .put_object(
+            &history_path(id).join(format!("{}.json", current.version)),
+            serde_json::to_vec(&current)?.into(),
+        )
+        .await?;
+
+    let dashboard = Dashboard {
+        version: current.version + 1,
+        name: body.name,
+        description: body.description,
+        panels: body.panels,
+        updated_at: Utc::now(),
+        updated_by: request_username(&req),
+        ..current
+    };
+    put_dashboard(&dashboard).await?;
+
+    Ok(web::Json(dashboard))
+}
+
+// Handler for DELETE /api/v1/dashboards/{id}
+pub async fn delete(id: web::Path<String>) -> Result<impl Responder, DashboardError> {
+    let id = parse_id(&id)?;
+    get_dashboard(id).await?;
+
+    let store = CONFIG.storage().get_object_store();
+    store.delete_object(&dashboard_path(id)).await?;
+    empty_if_not_found(store.delete_prefix(&history_path(id)).await)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Handler for GET /api/v1/dashboards/{id}/versions
+// previous versions of the dashboard, oldest first
+pub async fn list_versions(id: web::Path<String>) -> Result<impl Responder, DashboardError> {
+    let id = parse_id(&id)?;
+    get_dashboard(id).await?;
+
+    let store = CONFIG.storage().get_object_store();
+    let objects = empty_if_not_found(
+        store
+            .get_objects(
+                Some(&history_path(id)),
+                Box::new(|file_name| file_name.ends_with(".json")),
+            )
+            .await,
+    )?;
+
+    let mut versions = objects
+        .iter()
+        .map(|bytes| serde_json::from_slice::<Dashboard>(bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+    versions.sort_by_key(|dashboard| dashboard.version);
+
+    Ok(web::Json(versions))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DashboardError {
+    #[error("Dashboard {0} not found")]
+    NotFound(String),
+    #[error("Invalid dashboard: {0}")]
+    Invalid(String),
+    #[error("Dashboard was modified, current version is {0}")]
+    VersionConflict(u64),
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Invalid dashboard definition in storage: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for DashboardError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::VersionConflict(_) => StatusCode::CONFLICT,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DashboardRequest, Panel, PanelLayout};
+
+    fn panel(id: &str, query: &str) -> Panel {
+        Panel {
+            id: id.to_string(),
+            title: id.to_string(),
+            query: query.to_string(),
+            visualization: None,
+            layout: PanelLayout {
+                x: 0,
+                y: 0,
+                w: 4,
+                h: 4,
+            },
+        }
+    }
+
+    #[test]
+    fn dashboard_validation() {
+        let request = |name: &str, panels| DashboardRequest {
+            name: name.to_string(),
+            description: None,
+            panels,
+            version: None,
+        };
+
+        assert!(request("errors", vec![panel("a", "select 1")])
+            .validate()
+            .is_ok());
+        assert!(request(" ", vec![]).validate().is_err());
+        assert!(request("errors", vec![panel("a", "")]).validate().is_err());
+        assert!(request(
+            "errors",
+            vec![panel("a", "select 1"), panel("a", "select 2")]
+        )
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,214 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::HashMap;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use http::StatusCode;
+use ulid::Ulid;
+
+use crate::detections::sigma::SigmaRule;
+use crate::detections::{self, detection_path, put_detection, Detection};
+use crate::handlers::http::problem::Problem;
+use crate::handlers::http::query::authorize_query;
+use crate::option::CONFIG;
+use crate::query::QUERY_SESSION;
+use crate::rbac::Users;
+use crate::storage::ObjectStorageError;
+use crate::utils::actix::{extract_session_key_from_req, request_username};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DetectionRequest {
+    rule: String,
+    stream: String,
+    interval: String,
+    #[serde(default)]
+    fields: HashMap<String, String>,
+    #[serde(default = "enabled")]
+    enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl DetectionRequest {
+    // the rule is checked to compile, and its stream against the permissions of the user
+    async fn validate(&self, req: &HttpRequest) -> Result<SigmaRule, DetectionError> {
+        let rule =
+            SigmaRule::parse(&self.rule).map_err(|err| DetectionError::Invalid(err.to_string()))?;
+        let sql = rule
+            .to_sql(&self.stream, &self.fields)
+            .map_err(|err| DetectionError::Invalid(err.to_string()))?;
+        humantime::parse_duration(&self.interval)
+            .map_err(|err| DetectionError::Invalid(format!("invalid interval: {err}")))?;
+
+        QUERY_SESSION
+            .state()
+            .create_logical_plan(&sql)
+            .await
+            .map_err(|err| DetectionError::Invalid(err.to_string()))?;
+
+        let key = extract_session_key_from_req(req).map_err(|_| DetectionError::Unauthorized)?;
+        authorize_query(&Users.get_permissions(&key), &self.stream)
+            .map_err(|_| DetectionError::Unauthorized)?;
+        Ok(rule)
+    }
+}
+
+// detections are visible to the users allowed to query their stream
+fn can_access(req: &HttpRequest, detection: &Detection) -> bool {
+    extract_session_key_from_req(req)
+        .is_ok_and(|key| authorize_query(&Users.get_permissions(&key), &detection.stream).is_ok())
+}
+
+async fn get_detection(req: &HttpRequest, id: &str) -> Result<Detection, DetectionError> {
+    let not_found = || DetectionError::NotFound(id.to_owned());
+    let id = Ulid::from_string(id).map_err(|_| not_found())?;
+
+    let store = CONFIG.storage().get_object_store();
+    let detection: Detection = match store.get_object(&detection_path(id)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(ObjectStorageError::NoSuchKey(_)) => return Err(not_found()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !can_access(req, &detection) {
+        return Err(not_found());
+    }
+    Ok(detection)
+}
+
+// Handler for GET /api/v1/detections
+pub async fn list(req: HttpRequest) -> Result<impl Responder, DetectionError> {
+    let mut detections: Vec<Detection> = detections::list_detections()
+        .await?
+        .into_iter()
+        .filter(|detection| can_access(&req, detection))
+        .collect();
+    detections.sort_by(|a, b| a.title.cmp(&b.title));
+
+    Ok(web::Json(detections))
+}
+
+// Handler for POST /api/v1/detections
+pub async fn post(
+    req: HttpRequest,
+    body: web::Json<DetectionRequest>,
+) -> Result<impl Responder, DetectionError> {
+    let body = body.into_inner();
+    let rule = body.validate(&req).await?;
+
+    let now = Utc::now();
+    let detection = Detection {
+        id: Ulid::new(),
+        title: rule.title,
+        rule: body.rule,
+        stream: body.stream,
+        interval: body.interval,
+        fields: body.fields,
+        enabled: body.enabled,
+        owner: request_username(&req),
+        created_at: now,
+        updated_at: now,
+        last_run: None,
+    };
+    put_detection(&detection).await?;
+
+    Ok((web::Json(detection), StatusCode::CREATED))
+}
+
+// Handler for GET /api/v1/detections/{id}
+pub async fn get(
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<impl Responder, DetectionError> {
+    Ok(web::Json(get_detection(&req, &id).await?))
+}
+
+// Handler for PUT /api/v1/detections/{id}
+// the detection runs with the permissions of the user who updated it last
+pub async fn put(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<DetectionRequest>,
+) -> Result<impl Responder, DetectionError> {
+    let current = get_detection(&req, &id).await?;
+    let body = body.into_inner();
+    let rule = body.validate(&req).await?;
+
+    let detection = Detection {
+        title: rule.title,
+        rule: body.rule,
+        stream: body.stream,
+        interval: body.interval,
+        fields: body.fields,
+        enabled: body.enabled,
+        owner: request_username(&req),
+        updated_at: Utc::now(),
+        ..current
+    };
+    put_detection(&detection).await?;
+
+    Ok(web::Json(detection))
+}
+
+// Handler for DELETE /api/v1/detections/{id}
+pub async fn delete(
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<impl Responder, DetectionError> {
+    let detection = get_detection(&req, &id).await?;
+
+    let store = CONFIG.storage().get_object_store();
+    store.delete_object(&detection_path(detection.id)).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DetectionError {
+    #[error("Detection {0} not found")]
+    NotFound(String),
+    #[error("Invalid detection: {0}")]
+    Invalid(String),
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Invalid detection definition in storage: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for DetectionError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::FORBIDDEN,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
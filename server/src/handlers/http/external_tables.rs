@@ -0,0 +1,212 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use datafusion::error::DataFusionError;
+use http::StatusCode;
+
+use crate::external_tables::{self, ExternalTable, ExternalTableFormat};
+use crate::handlers::http::problem::Problem;
+use crate::handlers::http::query::can_read_external_table;
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::rbac::Users;
+use crate::storage::ObjectStorageError;
+use crate::utils::actix::{extract_session_key_from_req, request_username};
+use crate::validator::{self, error::StreamNameValidationError};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalTableRequest {
+    name: String,
+    #[serde(default)]
+    bucket: Option<String>,
+    location: String,
+    format: ExternalTableFormat,
+    #[serde(default = "default_has_header")]
+    has_header: bool,
+}
+
+fn default_has_header() -> bool {
+    true
+}
+
+impl ExternalTableRequest {
+    fn validate(&self) -> Result<(), ExternalTableError> {
+        // tables share the namespace of streams in queries
+        validator::stream_name(&self.name)?;
+        if STREAM_INFO.stream_exists(&self.name) {
+            return Err(ExternalTableError::Invalid(format!(
+                "a stream named {} already exists",
+                self.name
+            )));
+        }
+        let location = self.location.trim_matches('/');
+        if location.is_empty() {
+            return Err(ExternalTableError::Invalid(
+                "location cannot be empty".to_string(),
+            ));
+        }
+        if location.split('/').any(|segment| segment == "..") {
+            return Err(ExternalTableError::Invalid(format!(
+                "invalid location {}",
+                self.location
+            )));
+        }
+        if let Some(bucket) = &self.bucket {
+            if bucket.is_empty() || bucket.contains('/') {
+                return Err(ExternalTableError::Invalid(format!(
+                    "invalid bucket {bucket}"
+                )));
+            }
+            external_tables::check_bucket(
+                bucket,
+                &CONFIG.parseable.external_table_buckets,
+                &CONFIG.storage().get_object_store().get_bucket_name(),
+            )
+            .map_err(ExternalTableError::Invalid)?;
+        }
+        Ok(())
+    }
+}
+
+// a table is only visible to the users allowed to read it
+fn can_read(req: &HttpRequest, name: &str) -> bool {
+    extract_session_key_from_req(req)
+        .is_ok_and(|key| can_read_external_table(&Users.get_permissions(&key), name))
+}
+
+async fn get_table(req: &HttpRequest, name: &str) -> Result<ExternalTable, ExternalTableError> {
+    if !can_read(req, name) {
+        return Err(ExternalTableError::NotFound(name.to_owned()));
+    }
+    match external_tables::get(name) {
+        Some(table) => Ok(table),
+        None => external_tables::fetch(name)
+            .await?
+            .ok_or_else(|| ExternalTableError::NotFound(name.to_owned())),
+    }
+}
+
+// Handler for GET /api/v1/external-tables
+pub async fn list(req: HttpRequest) -> Result<impl Responder, ExternalTableError> {
+    external_tables::load().await?;
+    let tables: Vec<_> = external_tables::list()
+        .into_iter()
+        .filter(|table| can_read(&req, &table.name))
+        .collect();
+    Ok(web::Json(tables))
+}
+
+// Handler for POST /api/v1/external-tables
+// the schema is inferred from the files under the location, or read from the metadata of
+// delta and iceberg tables
+pub async fn post(
+    req: HttpRequest,
+    body: web::Json<ExternalTableRequest>,
+) -> Result<impl Responder, ExternalTableError> {
+    let body = body.into_inner();
+    body.validate()?;
+    if external_tables::fetch(&body.name).await?.is_some() {
+        return Err(ExternalTableError::AlreadyExists(body.name));
+    }
+
+    let location = body.location.trim_matches('/').to_owned();
+    if body.bucket.is_none() {
+        // streams this server has not loaded yet are listed from storage
+        let streams = CONFIG.storage().get_object_store().list_streams().await?;
+        external_tables::check_location(&location, |name| {
+            STREAM_INFO.stream_exists(name) || streams.iter().any(|stream| stream.name == name)
+        })
+        .map_err(ExternalTableError::Invalid)?;
+    }
+    let schema = external_tables::infer_schema(
+        body.bucket.as_deref(),
+        &location,
+        body.format,
+        body.has_header,
+    )
+    .await
+    .map_err(ExternalTableError::Schema)?;
+    let table = ExternalTable {
+        name: body.name,
+        bucket: body.bucket,
+        location,
+        format: body.format,
+        has_header: body.has_header,
+        schema,
+        created_by: request_username(&req),
+        created_at: Utc::now(),
+    };
+    external_tables::put(table.clone()).await?;
+
+    Ok((web::Json(table), StatusCode::CREATED))
+}
+
+// Handler for GET /api/v1/external-tables/{name}
+pub async fn get(
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> Result<impl Responder, ExternalTableError> {
+    Ok(web::Json(get_table(&req, &name).await?))
+}
+
+// Handler for DELETE /api/v1/external-tables/{name}
+// only the definition is removed, the files are left in place
+pub async fn delete(
+    req: HttpRequest,
+    name: web::Path<String>,
+) -> Result<impl Responder, ExternalTableError> {
+    let table = get_table(&req, &name).await?;
+    external_tables::delete(&table.name).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExternalTableError {
+    #[error("External table {0} not found")]
+    NotFound(String),
+    #[error("External table {0} already exists")]
+    AlreadyExists(String),
+    #[error("Invalid external table: {0}")]
+    Invalid(String),
+    #[error("Invalid external table name: {0}")]
+    Name(#[from] StreamNameValidationError),
+    #[error("Could not read the files of the external table: {0}")]
+    Schema(DataFusionError),
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+}
+
+impl actix_web::ResponseError for ExternalTableError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::AlreadyExists(_) => StatusCode::CONFLICT,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Name(_) => StatusCode::BAD_REQUEST,
+            Self::Schema(_) => StatusCode::BAD_REQUEST,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
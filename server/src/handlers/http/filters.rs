@@ -0,0 +1,345 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use relative_path::RelativePathBuf;
+use ulid::Ulid;
+
+use crate::handlers::http::problem::Problem;
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+use crate::rbac::role::Action;
+use crate::rbac::{self, Users};
+use crate::storage::purge::validate_predicate;
+use crate::storage::{ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+use crate::utils::actix::{extract_session_key_from_req, request_username};
+
+const FILTERS_DIRECTORY: &str = "filters";
+// shares a filter with every user who can query its stream
+const SHARE_WITH_ALL: &str = "*";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SortKey {
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Filter {
+    pub id: Ulid,
+    pub stream: String,
+    pub name: String,
+    /// sql expression the records are filtered with, e.g. `level = 'error'`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub sort: Vec<SortKey>,
+    /// users the filter is shared with, `*` shares it with everyone
+    #[serde(default)]
+    pub shared_with: Vec<String>,
+    pub owner: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Filter {
+    fn is_visible_to(&self, username: &str) -> bool {
+        self.owner == username
+            || self
+                .shared_with
+                .iter()
+                .any(|user| user == username || user == SHARE_WITH_ALL)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterRequest {
+    stream: String,
+    name: String,
+    #[serde(default)]
+    filter: Option<String>,
+    #[serde(default)]
+    columns: Vec<String>,
+    #[serde(default)]
+    sort: Vec<SortKey>,
+    #[serde(default)]
+    shared_with: Vec<String>,
+}
+
+impl FilterRequest {
+    fn validate(&self) -> Result<(), FilterError> {
+        if self.name.trim().is_empty() {
+            return Err(FilterError::Invalid("name cannot be empty".to_string()));
+        }
+        if !STREAM_INFO.stream_exists(&self.stream) {
+            return Err(FilterError::StreamNotFound(self.stream.clone()));
+        }
+        if let Some(filter) = &self.filter {
+            validate_predicate(filter).map_err(|err| FilterError::Invalid(err.to_string()))?;
+        }
+        if self.sort.iter().any(|key| key.column.trim().is_empty()) {
+            return Err(FilterError::Invalid(
+                "sort column cannot be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ListQuery {
+    stream: Option<String>,
+}
+
+fn filters_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, FILTERS_DIRECTORY])
+}
+
+fn filter_path(id: Ulid) -> RelativePathBuf {
+    filters_path().join(format!("{id}.json"))
+}
+
+// filters are only accessible to users who can query their stream
+fn can_query(req: &HttpRequest, stream: &str) -> bool {
+    extract_session_key_from_req(req).is_ok_and(|key| {
+        matches!(
+            Users.authorize(key, Action::Query, Some(stream), None),
+            rbac::Response::Authorized
+        )
+    })
+}
+
+async fn get_filter(req: &HttpRequest, id: &str) -> Result<Filter, FilterError> {
+    let not_found = || FilterError::NotFound(id.to_owned());
+    let id = Ulid::from_string(id).map_err(|_| not_found())?;
+
+    let store = CONFIG.storage().get_object_store();
+    let filter: Filter = match store.get_object(&filter_path(id)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(ObjectStorageError::NoSuchKey(_)) => return Err(not_found()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !filter.is_visible_to(&request_username(req)) || !can_query(req, &filter.stream) {
+        return Err(not_found());
+    }
+    Ok(filter)
+}
+
+async fn put_filter(filter: &Filter) -> Result<(), FilterError> {
+    let store = CONFIG.storage().get_object_store();
+    store
+        .put_object(&filter_path(filter.id), serde_json::to_vec(filter)?.into())
+        .await?;
+    Ok(())
+}
+
+// Handler for GET /api/v1/filters?stream={stream}
+// filters owned by or shared with the user
+pub async fn list(
+    req: HttpRequest,
+    query: web::Query<ListQuery>,
+) -> Result<impl Responder, FilterError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = match store
+        .get_objects(
+            Some(&filters_path()),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await
+    {
+        Ok(objects) => objects,
+        // nothing was saved yet
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let username = request_username(&req);
+    let mut filters = Vec::new();
+    for bytes in objects {
+        let filter: Filter = serde_json::from_slice(&bytes)?;
+        if query
+            .stream
+            .as_ref()
+            .is_some_and(|stream| *stream != filter.stream)
+        {
+            continue;
+        }
+        if filter.is_visible_to(&username) && can_query(&req, &filter.stream) {
+            filters.push(filter);
+        }
+    }
+    filters.sort_by(|a, b| (&a.stream, &a.name).cmp(&(&b.stream, &b.name)));
+
+    Ok(web::Json(filters))
+}
+
+// Handler for POST /api/v1/filters
+pub async fn post(
+    req: HttpRequest,
+    body: web::Json<FilterRequest>,
+) -> Result<impl Responder, FilterError> {
+    let body = body.into_inner();
+    body.validate()?;
+    if !can_query(&req, &body.stream) {
+        return Err(FilterError::Unauthorized);
+    }
+
+    let now = Utc::now();
+    let filter = Filter {
+        id: Ulid::new(),
+        stream: body.stream,
+        name: body.name,
+        filter: body.filter,
+        columns: body.columns,
+        sort: body.sort,
+        shared_with: body.shared_with,
+        owner: request_username(&req),
+        created_at: now,
+        updated_at: now,
+    };
+    put_filter(&filter).await?;
+
+    Ok((web::Json(filter), StatusCode::CREATED))
+}
+
+// Handler for GET /api/v1/filters/{id}
+pub async fn get(req: HttpRequest, id: web::Path<String>) -> Result<impl Responder, FilterError> {
+    Ok(web::Json(get_filter(&req, &id).await?))
+}
+
+// Handler for PUT /api/v1/filters/{id}
+// only the owner can modify a filter
+pub async fn put(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<FilterRequest>,
+) -> Result<impl Responder, FilterError> {
+    let current = get_filter(&req, &id).await?;
+    if current.owner != request_username(&req) {
+        return Err(FilterError::Unauthorized);
+    }
+    let body = body.into_inner();
+    body.validate()?;
+    if !can_query(&req, &body.stream) {
+        return Err(FilterError::Unauthorized);
+    }
+
+    let filter = Filter {
+        stream: body.stream,
+        name: body.name,
+        filter: body.filter,
+        columns: body.columns,
+        sort: body.sort,
+        shared_with: body.shared_with,
+        updated_at: Utc::now(),
+        ..current
+    };
+    put_filter(&filter).await?;
+
+    Ok(web::Json(filter))
+}
+
+// Handler for DELETE /api/v1/filters/{id}
+// only the owner can delete a filter
+pub async fn delete(
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<impl Responder, FilterError> {
+    let filter = get_filter(&req, &id).await?;
+    if filter.owner != request_username(&req) {
+        return Err(FilterError::Unauthorized);
+    }
+
+    let store = CONFIG.storage().get_object_store();
+    store.delete_object(&filter_path(filter.id)).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FilterError {
+    #[error("Filter {0} not found")]
+    NotFound(String),
+    #[error("Stream {0} not found")]
+    StreamNotFound(String),
+    #[error("Invalid filter: {0}")]
+    Invalid(String),
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Invalid filter definition in storage: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for FilterError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::StreamNotFound(_) => StatusCode::BAD_REQUEST,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::FORBIDDEN,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use ulid::Ulid;
+
+    use super::Filter;
+
+    #[test]
+    fn filter_visibility() {
+        let filter = |shared_with: &[&str]| Filter {
+            id: Ulid::new(),
+            stream: "app".to_string(),
+            name: "errors".to_string(),
+            filter: Some("level = 'error'".to_string()),
+            columns: vec![],
+            sort: vec![],
+            shared_with: shared_with.iter().map(|user| user.to_string()).collect(),
+            owner: "alice".to_string(),
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+
+        assert!(filter(&[]).is_visible_to("alice"));
+        assert!(!filter(&[]).is_visible_to("bob"));
+        assert!(filter(&["bob"]).is_visible_to("bob"));
+        assert!(!filter(&["bob"]).is_visible_to("carol"));
+        assert!(filter(&["*"]).is_visible_to("carol"));
+    }
+}
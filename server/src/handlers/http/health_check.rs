@@ -16,19 +16,270 @@
  *
  */
 
+use std::path::Path;
+use std::time::{Duration, Instant};
+
 use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use sysinfo::{DiskExt, System, SystemExt};
+use tokio::sync::Mutex;
+
+use crate::handlers::http::cluster::{self, utils::check_liveness};
+use crate::option::{Mode, CONFIG};
+use crate::shutdown;
+
+// object storage and ingesters not answering within this time are considered down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// readiness fails when less than this share of the staging disk is free
+const MIN_STAGING_FREE_PERCENT: f64 = 5.0;
+// the ingesters are checked at most once in this time, however often readiness is probed
+const CLUSTER_CHECK_TTL: Duration = Duration::from_secs(30);
+
+static CLUSTER_CHECK: Lazy<Mutex<Option<(Instant, Component)>>> = Lazy::new(Mutex::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, serde::Serialize, utoipa::ToSchema)]
+pub struct Component {
+    #[schema(value_type = String)]
+    name: &'static str,
+    status: Status,
+    // probes are not authenticated, details of a failure are only logged
+    #[serde(skip)]
+    detail: Option<String>,
+}
+
+impl Component {
+    fn new(name: &'static str, up: bool, detail: String) -> Self {
+        Self {
+            name,
+            status: if up { Status::Up } else { Status::Down },
+            detail: Some(detail),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct Health {
+    status: Status,
+    components: Vec<Component>,
+}
+
+impl Health {
+    fn new(components: Vec<Component>) -> Self {
+        let status = if components.iter().all(|c| c.status == Status::Up) {
+            Status::Up
+        } else {
+            Status::Down
+        };
+        Self { status, components }
+    }
 
-use crate::option::CONFIG;
+    fn into_response(self) -> HttpResponse {
+        let status = match self.status {
+            Status::Up => StatusCode::OK,
+            Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        for component in &self.components {
+            if let (Status::Down, Some(detail)) = (component.status, &component.detail) {
+                log::warn!("readiness check of {} failed: {detail}", component.name);
+            }
+        }
+        HttpResponse::build(status).json(self)
+    }
+}
 
+// liveness deliberately does not depend on other services,
+// an object storage outage should not get every pod restarted
+#[utoipa::path(
+    get,
+    path = "/api/v1/liveness",
+    tag = "server",
+    security(()),
+    responses((status = 200, description = "Server is running", body = Health))
+)]
 pub async fn liveness() -> HttpResponse {
-    HttpResponse::new(StatusCode::OK)
+    Health::new(vec![Component {
+        name: "server",
+        status: Status::Up,
+        detail: None,
+    }])
+    .into_response()
 }
 
+/// Whether the server can take requests, with the state of the services it depends on
+#[utoipa::path(
+    get,
+    path = "/api/v1/readiness",
+    tag = "server",
+    security(()),
+    responses(
+        (status = 200, description = "Server is ready", body = Health),
+        (status = 503, description = "Server or a service it depends on is down", body = Health),
+    )
+)]
 pub async fn readiness() -> HttpResponse {
-    if CONFIG.storage().get_object_store().check().await.is_ok() {
-        return HttpResponse::new(StatusCode::OK);
+    if shutdown::is_draining() {
+        let server = Component::new("server", false, "shutting down".to_string());
+        return Health::new(vec![server]).into_response();
+    }
+
+    let mut components = vec![object_storage().await];
+    if CONFIG.parseable.mode == Mode::Query {
+        components.push(cluster_metadata().await);
+    } else {
+        components.push(staging_disk());
+    }
+
+    Health::new(components).into_response()
+}
+
+async fn object_storage() -> Component {
+    let start = Instant::now();
+    let res =
+        tokio::time::timeout(CHECK_TIMEOUT, CONFIG.storage().get_object_store().check()).await;
+    match res {
+        Ok(Ok(())) => Component::new(
+            "objectStorage",
+            true,
+            format!("reachable in {}ms", start.elapsed().as_millis()),
+        ),
+        Ok(Err(err)) => Component::new("objectStorage", false, err.to_string()),
+        Err(_) => Component::new(
+            "objectStorage",
+            false,
+            format!("no response in {}s", CHECK_TIMEOUT.as_secs()),
+        ),
     }
+}
 
-    HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE)
+/// Available and total bytes of the disk holding staging
+pub(crate) fn staging_disk_space() -> Option<(u64, u64)> {
+    let staging = CONFIG.staging_dir();
+    let staging = staging.canonicalize().unwrap_or_else(|_| staging.clone());
+
+    let mut system = System::new();
+    system.refresh_disks_list();
+    mount_of(&staging, &system).map(|disk| (disk.available_space(), disk.total_space()))
+}
+
+fn staging_disk() -> Component {
+    let Some((free, total)) = staging_disk_space() else {
+        return Component::new("stagingDisk", false, "disk not found".to_string());
+    };
+
+    let free_percent = if total == 0 {
+        0.0
+    } else {
+        free as f64 * 100.0 / total as f64
+    };
+    Component::new(
+        "stagingDisk",
+        free_percent >= MIN_STAGING_FREE_PERCENT,
+        format!("{free} of {total} bytes free ({free_percent:.1}%)"),
+    )
+}
+
+// the disk mounted closest to the path
+fn mount_of<'a>(path: &Path, system: &'a System) -> Option<&'a sysinfo::Disk> {
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())
+}
+
+// the result is shared by the probes within the ttl, concurrent probes wait for one check
+async fn cluster_metadata() -> Component {
+    let mut cached = CLUSTER_CHECK.lock().await;
+    if let Some((checked_at, component)) = cached.as_ref() {
+        if checked_at.elapsed() < CLUSTER_CHECK_TTL {
+            return component.clone();
+        }
+    }
+    let component = check_cluster_metadata().await;
+    *cached = Some((Instant::now(), component.clone()));
+    component
+}
+
+// the query node learns of ingesters from their metadata in object storage
+async fn check_cluster_metadata() -> Component {
+    let ingesters = match cluster::get_ingester_info().await {
+        Ok(ingesters) => ingesters,
+        Err(err) => {
+            return Component::new(
+                "clusterMetadata",
+                false,
+                format!("ingester metadata could not be read: {err}"),
+            )
+        }
+    };
+
+    let live = join_all(ingesters.iter().map(|ingester| async {
+        tokio::time::timeout(CHECK_TIMEOUT, check_liveness(&ingester.domain_name))
+            .await
+            .unwrap_or(false)
+    }))
+    .await;
+    let stale: Vec<&str> = ingesters
+        .iter()
+        .zip(live)
+        .filter(|(_, live)| !live)
+        .map(|(ingester, _)| ingester.domain_name.as_str())
+        .collect();
+
+    // unreachable ingesters are reported, but do not take the query node out of service
+    let mut detail = format!(
+        "{} of {} ingesters live",
+        ingesters.len() - stale.len(),
+        ingesters.len()
+    );
+    if !stale.is_empty() {
+        detail.push_str(&format!(", unreachable: {}", stale.join(", ")));
+    }
+    Component::new("clusterMetadata", true, detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::MessageBody;
+
+    use super::{Component, Health, Status};
+
+    #[test]
+    fn health_is_down_if_any_component_is_down() {
+        let health = Health::new(vec![
+            Component::new("objectStorage", true, String::new()),
+            Component::new("stagingDisk", true, String::new()),
+        ]);
+        assert_eq!(health.status, Status::Up);
+
+        let health = Health::new(vec![
+            Component::new("objectStorage", false, String::new()),
+            Component::new("stagingDisk", true, String::new()),
+        ]);
+        assert_eq!(health.status, Status::Down);
+    }
+
+    #[test]
+    fn failure_details_are_not_exposed() {
+        let health = Health::new(vec![Component::new(
+            "objectStorage",
+            false,
+            "dns error: failed to lookup address bucket.s3.internal".to_string(),
+        )]);
+        let body = health.into_response().into_body().try_into_bytes().unwrap();
+
+        assert_eq!(
+            body,
+            r#"{"status":"down","components":[{"name":"objectStorage","status":"down"}]}"#
+        );
+    }
 }
@@ -17,31 +17,62 @@
  */
 
 use super::logstream::error::CreateStreamError;
-use super::{kinesis, otel};
+use super::security::{auditd, falco, tetragon};
+use super::spool::{self, SpoolError, SpoolFile, SpooledBody};
+use super::{cloudwatch, cluster, journald, kinesis, otel, windows, MAX_EVENT_PAYLOAD_SIZE};
 use crate::event::{
     self,
     error::EventError,
     format::{self, EventFormat},
 };
+use crate::handlers::http::problem::Problem;
 use crate::handlers::{
-    LOG_SOURCE_KEY, LOG_SOURCE_KINESIS, LOG_SOURCE_OTEL, PREFIX_META, PREFIX_TAGS, SEPARATOR,
-    STREAM_NAME_HEADER_KEY,
+    LOG_SOURCE_AUDITD, LOG_SOURCE_CLOUDWATCH, LOG_SOURCE_FALCO, LOG_SOURCE_KEY, LOG_SOURCE_KINESIS,
+    LOG_SOURCE_OTEL, LOG_SOURCE_TETRAGON, LOG_SOURCE_WINDOWS_EVENT, PREFIX_META, PREFIX_TAGS,
+    SEPARATOR, STREAM_NAME_HEADER_KEY, TAGS_KEY,
 };
 use crate::metadata::{self, STREAM_INFO};
+use crate::metrics::{self, EVENTS_PARSE_FAILED, EVENTS_SPOOL_FAILED, INGEST_LIMIT_EXCEEDED};
 use crate::option::{Mode, CONFIG};
-use crate::storage::{LogStream, ObjectStorageError};
-use crate::utils::header_parsing::{collect_labelled_headers, ParseHeaderError};
-use actix_web::{http::header::ContentType, HttpRequest, HttpResponse};
-use arrow_schema::{Field, Schema};
+use crate::replication::{self, ReplicationError};
+use crate::schema_registry::{Compatibility, IncompatibleSchema};
+use crate::shutdown;
+use crate::storage::{FieldMapping, LogStream, ObjectStorageError};
+use crate::utils::header_parsing::{collect_labelled_headers, collect_tags, ParseHeaderError};
+use crate::utils::json;
+use crate::utils::sigv4::{self, SigV4Error};
+use actix_web::{web, HttpRequest, HttpResponse};
+use arrow_array::{ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
 use bytes::Bytes;
+use futures::StreamExt;
 use http::StatusCode;
 use serde_json::Value;
 use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use tokio::sync::mpsc;
 // Handler for POST /api/v1/ingest
 // ingests events by extracting stream name from header
 // creates if stream does not exist
-pub async fn ingest(req: HttpRequest, body: Bytes) -> Result<HttpResponse, PostError> {
+#[utoipa::path(
+    post,
+    path = "/api/v1/ingest",
+    tag = "ingest",
+    params(
+        ("X-P-Stream" = String, Header, description = "Log stream the events are sent to, created if it does not exist"),
+        ("X-P-Log-Source" = Option<String>, Header, description = "Format of the events, e.g. `kinesis` or `otel`"),
+        ("X-P-Tags" = Option<String>, Header, description = "Comma separated `name=value` tags set on every event, the stream has to allow them"),
+    ),
+    request_body(content = Object, description = "An event or an array of events"),
+    responses(
+        (status = 200, description = "Events were ingested, except those listed as rejected", body = IngestSummary),
+        (status = 400, description = "None of the events could be parsed", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 413, description = "Payload is too large", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 503, description = "Server is shutting down", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn ingest(req: HttpRequest, payload: web::Payload) -> Result<HttpResponse, PostError> {
+    reject_if_draining()?;
     if let Some((_, stream_name)) = req
         .headers()
         .iter()
@@ -50,103 +81,792 @@ pub async fn ingest(req: HttpRequest, body: Bytes) -> Result<HttpResponse, PostE
         let stream_name = stream_name.to_str().unwrap().to_owned();
         create_stream_if_not_exists(&stream_name).await?;
 
-        flatten_and_push_logs(req, body, stream_name).await?;
-        Ok(HttpResponse::Ok().finish())
+        let body = spool::spool_payload(payload)
+            .await
+            .map_err(|err| count_rejection(&stream_name, err.into()))?;
+        flatten_and_push_logs(req, body, stream_name)
+            .await?
+            .into_response()
     } else {
         Err(PostError::Header(ParseHeaderError::MissingStreamName))
     }
 }
 
+// Handler for POST /api/v1/ingest/arrow
+// ingests record batches sent as an arrow IPC stream by extracting stream name from header
+// creates if stream does not exist
+pub async fn ingest_arrow(req: HttpRequest, body: Bytes) -> Result<HttpResponse, PostError> {
+    reject_if_draining()?;
+    let Some(stream_name) = req.headers().get(STREAM_NAME_HEADER_KEY) else {
+        return Err(PostError::Header(ParseHeaderError::MissingStreamName));
+    };
+    let stream_name = stream_name.to_str().unwrap().to_owned();
+    create_stream_if_not_exists(&stream_name).await?;
+
+    let size = body.len();
+    let tags = event_tags(&req, &stream_name)?;
+    let (schema, time_partition, static_schema_flag) = stream_schema_info(&stream_name)?;
+    let compatibility = STREAM_INFO
+        .get_schema_compatibility(&stream_name)
+        .unwrap_or_default();
+    let (rb, is_first_event) = count_parse_failure(&stream_name, || {
+        let data = format::arrow::Event::read_ipc_stream(body)?;
+        let event = format::arrow::Event {
+            data: add_tag_columns(data, &tags).map_err(anyhow::Error::from)?,
+            tags: collect_labelled_headers(&req, PREFIX_TAGS, SEPARATOR)?,
+            metadata: collect_labelled_headers(&req, PREFIX_META, SEPARATOR)?,
+        };
+        let stream_schema = schema.clone();
+        let (rb, is_first_event) =
+            event.into_recordbatch(schema, time_partition, static_schema_flag)?;
+        check_compatibility(compatibility, &stream_schema, &rb, is_first_event)?;
+        Ok((rb, is_first_event))
+    })?;
+
+    process_event(event::Event {
+        rb,
+        stream_name,
+        // stream stats are tracked under the json format
+        origin_format: "json",
+        origin_size: size as u64,
+        is_first_event,
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Handler for POST /api/v1/ingest/journald/{logstream}/upload
+// ingests entries of the journal export format streamed by systemd-journal-upload
+// entries are pushed as they are completed, uploads following the journal never end
+// creates if stream does not exist
+pub async fn ingest_journald(
+    req: HttpRequest,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, PostError> {
+    reject_if_draining()?;
+    let stream_name = req.match_info().get("logstream").unwrap().to_owned();
+    create_stream_if_not_exists(&stream_name).await?;
+
+    let mut parser = journald::JournalParser::default();
+    let mut unaccounted = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(SpoolError::Payload)?;
+        unaccounted += chunk.len();
+        let (entries, size) = count_parse_failure(&stream_name, || Ok(parser.push(&chunk)?))?;
+        if parser.pending() > MAX_EVENT_PAYLOAD_SIZE {
+            return Err(SpoolError::Overflow(MAX_EVENT_PAYLOAD_SIZE).into());
+        }
+        if !entries.is_empty() {
+            push_events(&stream_name, Value::Array(entries), size).await?;
+            unaccounted -= size;
+        }
+    }
+
+    let last = count_parse_failure(&stream_name, || Ok(parser.finish()?))?;
+    if let Some(entry) = last {
+        push_events(&stream_name, Value::Array(vec![entry]), unaccounted).await?;
+    }
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Handler for POST /api/v1/ingest/aws/{logstream}
+// ingests events delivered by AWS services, which are authenticated by their signature
+// Firehose deliveries and the CloudWatch Logs subscriptions sent through them are unpacked
+// creates if stream does not exist
+pub async fn ingest_aws(req: HttpRequest, body: Bytes) -> Result<HttpResponse, PostError> {
+    reject_if_draining()?;
+    sigv4::verify(&req, &body)?;
+    let stream_name = req.match_info().get("logstream").unwrap().to_owned();
+    create_stream_if_not_exists(&stream_name).await?;
+
+    let delivery = kinesis::unpack_aws_delivery(&body)?;
+    if !delivery.events.is_empty() {
+        let events = serde_json::to_vec(&delivery.events)?;
+        push_logs(stream_name, req, events.into()).await?;
+    }
+
+    // firehose takes a delivery as done once its request id is returned
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "requestId": delivery.request_id,
+        "timestamp": chrono::Utc::now().timestamp_millis(),
+    })))
+}
+
+// requests with events that cannot be parsed are counted by stream
+fn count_parse_failure<T>(
+    stream_name: &str,
+    parse: impl FnOnce() -> Result<T, PostError>,
+) -> Result<T, PostError> {
+    parse().map_err(|err| count_rejection(stream_name, err))
+}
+
+// count a rejected request or event by stream, under the limit it exceeded if any
+fn count_rejection(stream_name: &str, err: PostError) -> PostError {
+    let label = metrics::stream_counter_label(stream_name);
+    match (&err, err.exceeded_limit()) {
+        (_, Some(limit)) => INGEST_LIMIT_EXCEEDED
+            .with_label_values(&[&label, limit])
+            .inc(),
+        // the disk failed, not the events
+        (PostError::Spool(SpoolError::Io(_)), None) => {
+            EVENTS_SPOOL_FAILED.with_label_values(&[&label]).inc()
+        }
+        (_, None) => EVENTS_PARSE_FAILED.with_label_values(&[&label]).inc(),
+    }
+    err
+}
+
+// events are only staged once peers hold a copy, so a failed replication is not acknowledged
+async fn process_event(event: event::Event) -> Result<(), PostError> {
+    replication::replicate(&event.stream_name, &event.rb).await?;
+    event.process().await?;
+    Ok(())
+}
+
+// events accepted after the final flush of staging would be stranded till the next start
+fn reject_if_draining() -> Result<(), PostError> {
+    if shutdown::is_draining() {
+        return Err(PostError::ShuttingDown);
+    }
+    Ok(())
+}
+
 async fn flatten_and_push_logs(
     req: HttpRequest,
-    body: Bytes,
+    body: SpooledBody,
     stream_name: String,
-) -> Result<(), PostError> {
+) -> Result<IngestSummary, PostError> {
     //flatten logs
     if let Some((_, log_source)) = req.headers().iter().find(|&(key, _)| key == LOG_SOURCE_KEY) {
+        let body = body.into_bytes().await.map_err(SpoolError::Io)?;
         let mut json: Vec<BTreeMap<String, Value>> = Vec::new();
         let log_source: String = log_source.to_str().unwrap().to_owned();
+        let mut summary = IngestSummary::default();
         match log_source.as_str() {
             LOG_SOURCE_KINESIS => json = kinesis::flatten_kinesis_logs(&body),
             LOG_SOURCE_OTEL => json = otel::flatten_otel_logs(&body),
+            // events of these sources are pushed together
+            LOG_SOURCE_CLOUDWATCH => {
+                let events = cloudwatch::flatten_cloudwatch_logs(&body)?;
+                summary = push_all_logs(&stream_name, &req, events).await?;
+            }
+            LOG_SOURCE_WINDOWS_EVENT => {
+                let events = windows::flatten_windows_events(&body)?;
+                summary = push_all_logs(&stream_name, &req, events).await?;
+            }
+            LOG_SOURCE_AUDITD => {
+                summary = push_all_logs(&stream_name, &req, auditd::normalize(&body)?).await?
+            }
+            LOG_SOURCE_FALCO => {
+                summary = push_all_logs(&stream_name, &req, falco::normalize(&body)?).await?
+            }
+            LOG_SOURCE_TETRAGON => {
+                summary = push_all_logs(&stream_name, &req, tetragon::normalize(&body)?).await?
+            }
             _ => {
                 log::warn!("Unknown log source: {}", log_source);
-                push_logs(stream_name.to_string(), req.clone(), body).await?;
+                summary = push_logs(stream_name.to_string(), req.clone(), body).await?;
             }
         }
-        for record in json.iter_mut() {
+        for (index, record) in json.iter_mut().enumerate() {
             let body: Bytes = serde_json::to_vec(record).unwrap().into();
-            push_logs(stream_name.to_string(), req.clone(), body).await?;
+            summary.merge(
+                push_logs(stream_name.to_string(), req.clone(), body).await?,
+                index,
+            );
         }
+        Ok(summary)
     } else {
-        push_logs(stream_name.to_string(), req, body).await?;
+        match body {
+            SpooledBody::Memory(body) => push_logs(stream_name, req, body).await,
+            SpooledBody::File(file) => push_spooled_logs(stream_name, req, file).await,
+        }
     }
-    Ok(())
 }
 
 // Handler for POST /api/v1/logstream/{logstream}
 // only ingests events into the specified logstream
 // fails if the logstream does not exist
-pub async fn post_event(req: HttpRequest, body: Bytes) -> Result<HttpResponse, PostError> {
+#[utoipa::path(
+    post,
+    path = "/api/v1/logstream/{logstream}",
+    tag = "ingest",
+    params(("logstream" = String, Path, description = "Name of the log stream")),
+    request_body(content = Object, description = "An event or an array of events"),
+    responses(
+        (status = 200, description = "Events were ingested, except those listed as rejected", body = IngestSummary),
+        (status = 400, description = "None of the events could be parsed", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Log stream does not exist", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 413, description = "Payload is too large", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn post_event(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, PostError> {
+    reject_if_draining()?;
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
 
-    flatten_and_push_logs(req, body, stream_name).await?;
-    Ok(HttpResponse::Ok().finish())
+    let body = spool::spool_payload(payload)
+        .await
+        .map_err(|err| count_rejection(&stream_name, err.into()))?;
+    flatten_and_push_logs(req, body, stream_name)
+        .await?
+        .into_response()
 }
 
-async fn push_logs(stream_name: String, req: HttpRequest, body: Bytes) -> Result<(), PostError> {
-    let (size, rb, is_first_event) = {
-        let hash_map = STREAM_INFO.read().unwrap();
-        let schema = hash_map
-            .get(&stream_name)
-            .ok_or(PostError::StreamNotFound(stream_name.clone()))?
-            .schema
-            .clone();
-        let time_partition = hash_map
-            .get(&stream_name)
-            .ok_or(PostError::StreamNotFound(stream_name.clone()))?
-            .time_partition
-            .clone();
-        let static_schema_flag = hash_map
-            .get(&stream_name)
-            .ok_or(PostError::StreamNotFound(stream_name.clone()))?
-            .static_schema_flag
-            .clone();
-
-        into_event_batch(req, body, schema, time_partition, static_schema_flag)?
+// push the events of a payload as one batch, payloads without events are ignored
+async fn push_all_logs(
+    stream_name: &str,
+    req: &HttpRequest,
+    events: Vec<Value>,
+) -> Result<IngestSummary, PostError> {
+    if events.is_empty() {
+        return Ok(IngestSummary::default());
+    }
+    let body = serde_json::to_vec(&events)?;
+    push_logs(stream_name.to_owned(), req.clone(), body.into()).await
+}
+
+async fn push_logs(
+    stream_name: String,
+    req: HttpRequest,
+    body: Bytes,
+) -> Result<IngestSummary, PostError> {
+    event_tags(&req, &stream_name)?;
+    let (schema, time_partition, static_schema_flag) = stream_schema_info(&stream_name)?;
+    let field_mapping = STREAM_INFO
+        .get_field_mapping(&stream_name)
+        .unwrap_or_default();
+    let batch = count_parse_failure(&stream_name, || {
+        into_event_batch(
+            req.clone(),
+            body.clone(),
+            BodyOptions::for_stream(&stream_name),
+            schema,
+            time_partition,
+            static_schema_flag,
+            field_mapping,
+        )
+    });
+    let (size, rb, is_first_event) = match batch {
+        Ok(batch) => batch,
+        Err(err @ PostError::TooManyEvents { .. }) => return Err(err),
+        // an array of events is pushed event by event, so that only the invalid ones are rejected
+        Err(err) => {
+            return match serde_json::from_slice(&body) {
+                Ok(Value::Array(events)) => {
+                    push_each_event(&stream_name, &req, events, body.len(), body.len()).await
+                }
+                _ => Err(err),
+            }
+        }
     };
 
-    event::Event {
+    let accepted = rb.num_rows();
+    process_event(event::Event {
         rb,
         stream_name,
         origin_format: "json",
         origin_size: size as u64,
         is_first_event,
-    }
-    .process()
+    })
     .await?;
 
-    Ok(())
+    Ok(IngestSummary::accepted(accepted))
+}
+
+// Push events of a batch which could not be converted as a whole one at a time, each is
+// converted against the schema the events before it left. Failing to stage an event fails
+// the request, events which do not fit the stream are rejected.
+async fn push_each_event(
+    stream_name: &str,
+    req: &HttpRequest,
+    events: Vec<Value>,
+    body_size: usize,
+    mut size: usize,
+) -> Result<IngestSummary, PostError> {
+    let options = BodyOptions::for_stream(stream_name);
+    let mut summary = IngestSummary::default();
+    for (index, event) in events.into_iter().enumerate() {
+        let (schema, time_partition, static_schema_flag) = stream_schema_info(stream_name)?;
+        let field_mapping = STREAM_INFO
+            .get_field_mapping(stream_name)
+            .unwrap_or_default();
+        let batch = options
+            .check_event_size(std::slice::from_ref(&event), body_size)
+            .and_then(|_| {
+                json_into_event_batch(
+                    req,
+                    event,
+                    schema,
+                    time_partition,
+                    static_schema_flag,
+                    field_mapping,
+                    &options,
+                )
+            });
+        match batch {
+            Ok((rb, is_first_event)) => {
+                process_event(event::Event {
+                    rb,
+                    stream_name: stream_name.to_owned(),
+                    origin_format: "json",
+                    // size of the whole batch is accounted with the first accepted event
+                    origin_size: std::mem::take(&mut size) as u64,
+                    is_first_event,
+                })
+                .await?;
+                summary.accepted += 1;
+            }
+            Err(err) => summary.reject(index, err),
+        }
+    }
+    Ok(summary)
 }
 
 fn into_event_batch(
     req: HttpRequest,
     body: Bytes,
+    options: BodyOptions,
     schema: HashMap<String, Arc<Field>>,
     time_partition: Option<String>,
     static_schema_flag: Option<String>,
+    field_mapping: FieldMapping,
 ) -> Result<(usize, arrow_array::RecordBatch, bool), PostError> {
-    let tags = collect_labelled_headers(&req, PREFIX_TAGS, SEPARATOR)?;
-    let metadata = collect_labelled_headers(&req, PREFIX_META, SEPARATOR)?;
     let size = body.len();
-    let body: Value = serde_json::from_slice(&body)?;
+    let body: Value = if options.simd_json {
+        json::from_slice_simd(&body)?
+    } else {
+        serde_json::from_slice(&body)?
+    };
+    let events = match &body {
+        Value::Array(events) => events.as_slice(),
+        event => std::slice::from_ref(event),
+    };
+    options.check_event_count(events.len())?;
+    options.check_event_size(events, size)?;
+    let (rb, is_first) = json_into_event_batch(
+        &req,
+        body,
+        schema,
+        time_partition,
+        static_schema_flag,
+        field_mapping,
+        &options,
+    )?;
+    Ok((size, rb, is_first))
+}
+
+fn json_into_event_batch(
+    req: &HttpRequest,
+    body: Value,
+    schema: HashMap<String, Arc<Field>>,
+    time_partition: Option<String>,
+    static_schema_flag: Option<String>,
+    field_mapping: FieldMapping,
+    options: &BodyOptions,
+) -> Result<(arrow_array::RecordBatch, bool), PostError> {
+    let tags = collect_labelled_headers(req, PREFIX_TAGS, SEPARATOR)?;
+    let metadata = collect_labelled_headers(req, PREFIX_META, SEPARATOR)?;
     let event = format::json::Event {
-        data: body,
+        data: add_tags(field_mapping.apply(body), &collect_tags(req, TAGS_KEY)?),
         tags,
         metadata,
+        max_columns: options.max_columns,
     };
+    let stream_schema = schema.clone();
     let (rb, is_first) = event.into_recordbatch(schema, time_partition, static_schema_flag)?;
-    Ok((size, rb, is_first))
+    check_compatibility(options.schema_compatibility, &stream_schema, &rb, is_first)?;
+    Ok((rb, is_first))
+}
+
+// events which add fields to the stream are checked against its schema compatibility
+fn check_compatibility(
+    compatibility: Compatibility,
+    stream_schema: &HashMap<String, Arc<Field>>,
+    rb: &RecordBatch,
+    is_first_event: bool,
+) -> Result<(), PostError> {
+    if is_first_event {
+        compatibility.check_event(stream_schema, &rb.schema())?;
+    }
+    Ok(())
+}
+
+// tags of the `X-P-Tags` headers of a request, each has to be allowed on the stream
+fn event_tags(req: &HttpRequest, stream_name: &str) -> Result<BTreeMap<String, String>, PostError> {
+    let tags = collect_tags(req, TAGS_KEY)?;
+    if tags.is_empty() {
+        return Ok(tags);
+    }
+    let allowed = STREAM_INFO
+        .get_allowed_tags(stream_name)
+        .map_err(|_| PostError::StreamNotFound(stream_name.to_owned()))?;
+    if let Some(name) = tags.keys().find(|name| !allowed.contains(name)) {
+        return Err(ParseHeaderError::TagNotAllowed(name.clone(), stream_name.to_owned()).into());
+    }
+    Ok(tags)
+}
+
+// tags are set on every event, replacing fields of the same name
+fn add_tags(body: Value, tags: &BTreeMap<String, String>) -> Value {
+    match body {
+        Value::Object(mut event) => {
+            for (name, value) in tags {
+                event.insert(name.clone(), Value::String(value.clone()));
+            }
+            Value::Object(event)
+        }
+        Value::Array(events) => Value::Array(
+            events
+                .into_iter()
+                .map(|event| add_tags(event, tags))
+                .collect(),
+        ),
+        body => body,
+    }
+}
+
+// tags become string columns of every row, replacing columns of the same name
+fn add_tag_columns(
+    rb: RecordBatch,
+    tags: &BTreeMap<String, String>,
+) -> Result<RecordBatch, ArrowError> {
+    if tags.is_empty() {
+        return Ok(rb);
+    }
+    let schema = rb.schema();
+    let (mut fields, mut columns): (Vec<Arc<Field>>, Vec<ArrayRef>) = schema
+        .fields()
+        .iter()
+        .zip(rb.columns())
+        .filter(|(field, _)| !tags.contains_key(field.name()))
+        .map(|(field, column)| (field.clone(), column.clone()))
+        .unzip();
+    for (name, value) in tags {
+        fields.push(Arc::new(Field::new(name, DataType::Utf8, true)));
+        columns.push(Arc::new(StringArray::from(vec![
+            value.as_str();
+            rb.num_rows()
+        ])));
+    }
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+/// How json bodies are parsed, and the limits their events are held to
+#[derive(Debug, Clone, Copy)]
+pub struct BodyOptions {
+    pub simd_json: bool,
+    pub max_event_size: usize,
+    pub max_batch_events: usize,
+    pub max_columns: Option<usize>,
+    pub schema_compatibility: Compatibility,
+}
+
+impl Default for BodyOptions {
+    // parsed with serde_json, without limits
+    fn default() -> Self {
+        Self {
+            simd_json: false,
+            max_event_size: usize::MAX,
+            max_batch_events: usize::MAX,
+            max_columns: None,
+            schema_compatibility: Compatibility::None,
+        }
+    }
+}
+
+impl BodyOptions {
+    fn for_stream(stream_name: &str) -> Self {
+        Self {
+            simd_json: CONFIG.parseable.ingest_simd_json,
+            max_event_size: CONFIG.parseable.ingest_max_event_size as usize,
+            max_batch_events: CONFIG.parseable.ingest_max_batch_events,
+            max_columns: STREAM_INFO.get_max_columns(stream_name).unwrap_or_default(),
+            schema_compatibility: STREAM_INFO
+                .get_schema_compatibility(stream_name)
+                .unwrap_or_default(),
+        }
+    }
+
+    // the limit on events per request is checked before any of them is converted
+    fn check_event_count(&self, count: usize) -> Result<(), PostError> {
+        if count > self.max_batch_events {
+            return Err(PostError::TooManyEvents {
+                count,
+                limit: self.max_batch_events,
+            });
+        }
+        Ok(())
+    }
+
+    // Events larger than the limit are rejected before they are converted. Bodies no larger
+    // than the limit can not hold such an event, so their events are not measured.
+    fn check_event_size(&self, events: &[Value], body_size: usize) -> Result<(), PostError> {
+        if body_size <= self.max_event_size {
+            return Ok(());
+        }
+        for event in events {
+            let size = json_size(event);
+            if size > self.max_event_size {
+                return Err(PostError::EventTooLarge {
+                    size,
+                    limit: self.max_event_size,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// size of a value serialized as json, without holding the serialization
+fn json_size(value: &Value) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    serde_json::to_writer(&mut counter, value).expect("json values can be serialized");
+    counter.0
+}
+
+// Push a body spooled to disk, parsing and pushing the events in chunks so that
+// the whole body is never held in memory. Chunks pushed before an error are kept.
+async fn push_spooled_logs(
+    stream_name: String,
+    req: HttpRequest,
+    file: SpoolFile,
+) -> Result<IngestSummary, PostError> {
+    event_tags(&req, &stream_name)?;
+    // events are counted before any of them is pushed, a request over the limit ingests none
+    let (count, file) =
+        tokio::task::spawn_blocking(move || (spool::count_json_events(&file), file))
+            .await
+            .map_err(|err| PostError::CustomError(err.to_string()))?;
+    let options = BodyOptions::for_stream(&stream_name);
+    count_parse_failure(&stream_name, || options.check_event_count(count?))?;
+
+    // size of the whole body is accounted with the first chunk
+    let body_size = file.size;
+    let mut size = file.size;
+    let mut summary = IngestSummary::default();
+    let (tx, mut rx) = mpsc::channel(1);
+    let reader = tokio::task::spawn_blocking(move || {
+        spool::read_json_chunks(&file, spool::SPOOL_BATCH_SIZE, tx)
+    });
+
+    while let Some(chunk) = rx.recv().await {
+        let (schema, time_partition, static_schema_flag) = stream_schema_info(&stream_name)?;
+        let field_mapping = STREAM_INFO
+            .get_field_mapping(&stream_name)
+            .unwrap_or_default();
+        let offset = summary.accepted + summary.rejected;
+        let batch = count_parse_failure(&stream_name, || {
+            options.check_event_size(&chunk, body_size)?;
+            json_into_event_batch(
+                &req,
+                Value::Array(chunk.clone()),
+                schema,
+                time_partition,
+                static_schema_flag,
+                field_mapping,
+                &options,
+            )
+        });
+        let (rb, is_first_event) = match batch {
+            Ok(batch) => batch,
+            Err(_) => {
+                let size = std::mem::take(&mut size);
+                let chunk_summary =
+                    push_each_event(&stream_name, &req, chunk, body_size, size).await?;
+                summary.merge(chunk_summary, offset);
+                continue;
+            }
+        };
+
+        let accepted = rb.num_rows();
+        process_event(event::Event {
+            rb,
+            stream_name: stream_name.clone(),
+            origin_format: "json",
+            origin_size: std::mem::take(&mut size) as u64,
+            is_first_event,
+        })
+        .await?;
+        summary.merge(IngestSummary::accepted(accepted), offset);
+    }
+
+    let read = reader
+        .await
+        .map_err(|err| PostError::CustomError(err.to_string()))?;
+    count_parse_failure(&stream_name, || Ok(read?))?;
+    Ok(summary)
+}
+
+/// Events of a request which were ingested, and why the others were rejected
+#[derive(Debug, Default, serde::Serialize, utoipa::ToSchema)]
+pub struct IngestSummary {
+    pub accepted: usize,
+    pub rejected: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<RejectedEvent>,
+    // error of the first rejected event, the request fails with it if no event was accepted
+    #[serde(skip)]
+    first_error: Option<PostError>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct RejectedEvent {
+    /// position of the event in the request
+    pub index: usize,
+    pub reason: String,
+}
+
+impl IngestSummary {
+    fn accepted(accepted: usize) -> Self {
+        Self {
+            accepted,
+            ..Default::default()
+        }
+    }
+
+    fn reject(&mut self, index: usize, err: PostError) {
+        self.rejected += 1;
+        self.errors.push(RejectedEvent {
+            index,
+            reason: err.to_string(),
+        });
+        if self.first_error.is_none() {
+            self.first_error = Some(err);
+        }
+    }
+
+    // add the summary of events which follow `offset` events of the same request
+    fn merge(&mut self, other: IngestSummary, offset: usize) {
+        self.accepted += other.accepted;
+        self.rejected += other.rejected;
+        self.errors
+            .extend(other.errors.into_iter().map(|rejected| RejectedEvent {
+                index: rejected.index + offset,
+                ..rejected
+            }));
+        if self.first_error.is_none() {
+            self.first_error = other.first_error;
+        }
+    }
+
+    // a request of which every event was rejected fails, as a request which can not be parsed
+    fn into_response(mut self) -> Result<HttpResponse, PostError> {
+        if self.accepted == 0 {
+            if let Some(err) = self.first_error.take() {
+                return Err(err);
+            }
+        }
+        Ok(HttpResponse::Ok().json(self))
+    }
+}
+
+/// Write events the server reads from another source to a stream, creating it on first use.
+/// They are ingested like posted events without tags or metadata.
+pub async fn push_events(stream_name: &str, records: Value, size: usize) -> Result<(), PostError> {
+    create_stream_if_not_exists(stream_name).await?;
+
+    let (schema, time_partition, static_schema_flag) = stream_schema_info(stream_name)?;
+    let field_mapping = STREAM_INFO
+        .get_field_mapping(stream_name)
+        .unwrap_or_default();
+    let options = BodyOptions::for_stream(stream_name);
+    let (rb, is_first_event) = count_parse_failure(stream_name, || {
+        let event = format::json::Event {
+            data: field_mapping.apply(records),
+            tags: String::default(),
+            metadata: String::default(),
+            max_columns: options.max_columns,
+        };
+        let stream_schema = schema.clone();
+        let (rb, is_first_event) =
+            event.into_recordbatch(schema, time_partition, static_schema_flag)?;
+        check_compatibility(
+            options.schema_compatibility,
+            &stream_schema,
+            &rb,
+            is_first_event,
+        )?;
+        Ok((rb, is_first_event))
+    })?;
+
+    process_event(event::Event {
+        rb,
+        stream_name: stream_name.to_owned(),
+        origin_format: "json",
+        origin_size: size as u64,
+        is_first_event,
+    })
+    .await
+}
+
+/// Write events the server generates itself to an internal stream, creating it on first use.
+/// The query server hands them to an ingester.
+pub async fn push_internal_events(stream_name: &str, records: Value) -> anyhow::Result<()> {
+    create_stream_if_not_exists(stream_name).await?;
+
+    if CONFIG.parseable.mode == Mode::Query {
+        let body = serde_json::to_vec(&records)?.into();
+        return cluster::forward_events_to_ingester(stream_name, body).await;
+    }
+
+    let schema = STREAM_INFO
+        .schema(stream_name)?
+        .fields()
+        .iter()
+        .map(|field| (field.name().clone(), field.clone()))
+        .collect();
+    let (rb, is_first_event) = format::json::Event {
+        data: records,
+        tags: String::default(),
+        metadata: String::default(),
+        max_columns: None,
+    }
+    .into_recordbatch(schema, None, None)?;
+
+    event::Event {
+        rb,
+        stream_name: stream_name.to_owned(),
+        origin_format: "json",
+        origin_size: 0,
+        is_first_event,
+    }
+    .process()
+    .await?;
+
+    Ok(())
+}
+
+// schema, time partition and static schema flag of the stream
+#[allow(clippy::type_complexity)]
+fn stream_schema_info(
+    stream_name: &str,
+) -> Result<(HashMap<String, Arc<Field>>, Option<String>, Option<String>), PostError> {
+    let hash_map = STREAM_INFO.read().unwrap();
+    let stream = hash_map
+        .get(stream_name)
+        .ok_or(PostError::StreamNotFound(stream_name.to_owned()))?;
+    Ok((
+        stream.schema.clone(),
+        stream.time_partition.clone(),
+        stream.static_schema_flag.clone(),
+    ))
 }
 
 // Check if the stream exists and create a new stream if doesn't exist
@@ -160,6 +880,7 @@ pub async fn create_stream_if_not_exists(stream_name: &str) -> Result<(), PostEr
                 stream_name.to_string(),
                 "",
                 "",
+                "",
                 Arc::new(Schema::empty()),
             )
             .await?;
@@ -212,6 +933,20 @@ pub enum PostError {
     NetworkError(#[from] reqwest::Error),
     #[error("ObjectStorageError: {0}")]
     ObjectStorageError(#[from] ObjectStorageError),
+    #[error("{0}")]
+    Spool(#[from] SpoolError),
+    #[error("Server is shutting down, send the events to another ingester")]
+    ShuttingDown,
+    #[error("{0}")]
+    Replication(#[from] ReplicationError),
+    #[error("{0}")]
+    Signature(#[from] SigV4Error),
+    #[error("Event of {size} bytes is larger than the limit of {limit} bytes")]
+    EventTooLarge { size: usize, limit: usize },
+    #[error("Request has {count} events, more than the limit of {limit} events")]
+    TooManyEvents { count: usize, limit: usize },
+    #[error("Incompatible schema change: {0}")]
+    IncompatibleSchema(#[from] IncompatibleSchema),
 }
 
 impl actix_web::ResponseError for PostError {
@@ -229,13 +964,69 @@ impl actix_web::ResponseError for PostError {
             PostError::CustomError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             PostError::NetworkError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             PostError::ObjectStorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            PostError::Spool(SpoolError::Overflow(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            PostError::Spool(SpoolError::Payload(_)) => StatusCode::BAD_REQUEST,
+            PostError::Spool(SpoolError::Io(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+            PostError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            PostError::Replication(err) => actix_web::ResponseError::status_code(err),
+            PostError::Signature(SigV4Error::NotConfigured) => StatusCode::NOT_FOUND,
+            PostError::Signature(SigV4Error::Malformed(_)) => StatusCode::BAD_REQUEST,
+            PostError::Signature(_) => StatusCode::FORBIDDEN,
+            PostError::EventTooLarge { .. } | PostError::TooManyEvents { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            PostError::IncompatibleSchema(_) => StatusCode::BAD_REQUEST,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        actix_web::HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
+        Problem::new(self.status_code(), self)
+            .code(self.error_code())
+            .retriable_if(matches!(
+                self,
+                PostError::NetworkError(_) | PostError::ObjectStorageError(_)
+            ))
+            .response()
+    }
+}
+
+impl PostError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            PostError::StreamNotFound(_) => "stream_not_found",
+            PostError::SerdeError(_) => "invalid_json",
+            PostError::Header(_) => "invalid_header",
+            PostError::Event(_) => "event_error",
+            PostError::Invalid(_) => "invalid_request",
+            PostError::CreateStream(CreateStreamError::StreamNameValidation(_)) => {
+                "invalid_stream_name"
+            }
+            PostError::CreateStream(_) => "stream_creation_failed",
+            PostError::CustomError(_) => "ingest_error",
+            PostError::NetworkError(_) => "network_error",
+            PostError::ObjectStorageError(_) => "storage_error",
+            PostError::Spool(SpoolError::Overflow(_)) => "payload_too_large",
+            PostError::Spool(SpoolError::Payload(_)) => "invalid_payload",
+            PostError::Spool(SpoolError::Io(_)) => "spool_error",
+            PostError::ShuttingDown => "shutting_down",
+            PostError::Replication(_) => "replication_error",
+            PostError::Signature(SigV4Error::NotConfigured) => "signature_not_configured",
+            PostError::Signature(SigV4Error::Malformed(_)) => "malformed_signature",
+            PostError::Signature(_) => "invalid_signature",
+            PostError::EventTooLarge { .. } => "event_too_large",
+            PostError::TooManyEvents { .. } => "too_many_events",
+            PostError::IncompatibleSchema(_) => "incompatible_schema",
+        }
+    }
+
+    // limit of the ingest limits metric exceeded by the request, if any
+    fn exceeded_limit(&self) -> Option<&'static str> {
+        match self {
+            PostError::Spool(SpoolError::Overflow(_)) => Some("body_size"),
+            PostError::EventTooLarge { .. } => Some("event_size"),
+            PostError::TooManyEvents { .. } => Some("batch_events"),
+            _ => None,
+        }
     }
 }
 
@@ -254,10 +1045,11 @@ mod tests {
 
     use crate::{
         event,
-        handlers::{PREFIX_META, PREFIX_TAGS},
+        handlers::{PREFIX_META, PREFIX_TAGS, TAGS_KEY},
+        storage::FieldMapping,
     };
 
-    use super::into_event_batch;
+    use super::{into_event_batch, BodyOptions, IngestSummary, PostError};
 
     trait TestExt {
         fn as_int64_arr(&self) -> &Int64Array;
@@ -299,9 +1091,11 @@ mod tests {
         let (size, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -334,6 +1128,176 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rejected_events_are_indexed_in_the_request() {
+        let mut summary = IngestSummary::accepted(2);
+        let mut chunk = IngestSummary::accepted(1);
+        chunk.reject(1, PostError::CustomError("invalid".to_owned()));
+        summary.merge(chunk, 2);
+
+        assert_eq!(summary.accepted, 3);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.errors[0].index, 3);
+        assert!(summary.into_response().is_ok());
+
+        let mut rejected = IngestSummary::default();
+        rejected.reject(0, PostError::CustomError("invalid".to_owned()));
+        assert!(rejected.into_response().is_err());
+    }
+
+    #[test]
+    fn events_over_the_limits_are_rejected() {
+        let json = json!([{"a": "x".repeat(100)}, {"a": "y"}]);
+        let body = Bytes::from(serde_json::to_vec(&json).unwrap());
+        let convert = |options| {
+            into_event_batch(
+                TestRequest::default().to_http_request(),
+                body.clone(),
+                options,
+                HashMap::default(),
+                None,
+                None,
+                FieldMapping::None,
+            )
+        };
+
+        let err = convert(BodyOptions {
+            max_event_size: 50,
+            ..BodyOptions::default()
+        })
+        .unwrap_err();
+        assert!(matches!(err, PostError::EventTooLarge { limit: 50, .. }));
+
+        let err = convert(BodyOptions {
+            max_batch_events: 1,
+            ..BodyOptions::default()
+        })
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            PostError::TooManyEvents { count: 2, limit: 1 }
+        ));
+
+        assert!(convert(BodyOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn fields_over_max_columns_are_packed() {
+        let json = json!([{"a": 1, "b": "x", "c": "y"}, {"a": 2, "d": 3}]);
+        let schema = fields_to_map(
+            [
+                Field::new(event::DEFAULT_TIMESTAMP_KEY, DataType::Utf8, true),
+                Field::new(event::DEFAULT_TAGS_KEY, DataType::Utf8, true),
+                Field::new(event::DEFAULT_METADATA_KEY, DataType::Utf8, true),
+                Field::new("a", DataType::Int64, true),
+            ]
+            .into_iter(),
+        );
+
+        let (_, rb, _) = into_event_batch(
+            TestRequest::default().to_http_request(),
+            Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions {
+                max_columns: Some(6),
+                ..BodyOptions::default()
+            },
+            schema,
+            None,
+            None,
+            FieldMapping::None,
+        )
+        .unwrap();
+
+        assert_eq!(rb.num_columns(), 6);
+        assert_eq!(
+            rb.column_by_name("b").unwrap().as_utf8_arr(),
+            &StringArray::from(vec![Some("x"), None])
+        );
+        assert_eq!(
+            rb.column_by_name(event::EXTRA_FIELDS_KEY)
+                .unwrap()
+                .as_utf8_arr(),
+            &StringArray::from(vec![r#"{"c":"y"}"#, r#"{"d":3}"#])
+        );
+        assert!(rb.column_by_name("c").is_none());
+    }
+
+    #[test]
+    fn sent_extra_fields_are_merged_when_packing() {
+        let json = json!([
+            {"a": 1, "c": "y", "_extra": r#"{"e":true}"#},
+            {"a": 2, "d": 3, "_extra": "note"},
+            {"a": 3, "_extra": "kept"},
+        ]);
+        let schema = fields_to_map(
+            [
+                Field::new(event::DEFAULT_TIMESTAMP_KEY, DataType::Utf8, true),
+                Field::new(event::DEFAULT_TAGS_KEY, DataType::Utf8, true),
+                Field::new(event::DEFAULT_METADATA_KEY, DataType::Utf8, true),
+                Field::new("a", DataType::Int64, true),
+            ]
+            .into_iter(),
+        );
+
+        let (_, rb, _) = into_event_batch(
+            TestRequest::default().to_http_request(),
+            Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions {
+                max_columns: Some(5),
+                ..BodyOptions::default()
+            },
+            schema,
+            None,
+            None,
+            FieldMapping::None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rb.column_by_name(event::EXTRA_FIELDS_KEY)
+                .unwrap()
+                .as_utf8_arr(),
+            &StringArray::from(vec![
+                r#"{"c":"y","e":true}"#,
+                r#"{"d":3,"_extra":"note"}"#,
+                "kept"
+            ])
+        );
+    }
+
+    #[test]
+    fn tags_are_set_on_every_event() {
+        let json = json!([
+            {"a": 1, "environment": "dev"},
+            {"a": 2},
+        ]);
+
+        let req = TestRequest::default()
+            .append_header((TAGS_KEY, "environment=prod,datacenter=eu-1"))
+            .to_http_request();
+
+        let (_, rb, _) = into_event_batch(
+            req,
+            Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
+            HashMap::default(),
+            None,
+            None,
+            FieldMapping::None,
+        )
+        .unwrap();
+
+        assert_eq!(rb.num_rows(), 2);
+        assert_eq!(
+            rb.column_by_name("environment").unwrap().as_utf8_arr(),
+            &StringArray::from_iter_values(["prod", "prod"])
+        );
+        assert_eq!(
+            rb.column_by_name("datacenter").unwrap().as_utf8_arr(),
+            &StringArray::from_iter_values(["eu-1", "eu-1"])
+        );
+    }
+
     #[test]
     fn basic_object_with_null_into_rb() {
         let json = json!({
@@ -347,9 +1311,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -386,9 +1352,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             schema,
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -425,9 +1393,11 @@ mod tests {
         assert!(into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             schema,
             None,
-            None
+            None,
+            FieldMapping::None,
         )
         .is_err());
     }
@@ -450,9 +1420,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             schema,
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -469,9 +1441,11 @@ mod tests {
         assert!(into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
-            None
+            None,
+            FieldMapping::None,
         )
         .is_err())
     }
@@ -499,9 +1473,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -554,9 +1530,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -609,9 +1587,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             schema,
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -656,9 +1636,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -708,9 +1690,11 @@ mod tests {
         assert!(into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             schema,
             None,
-            None
+            None,
+            FieldMapping::None,
         )
         .is_err());
     }
@@ -743,9 +1727,11 @@ mod tests {
         let (_, rb, _) = into_event_batch(
             req,
             Bytes::from(serde_json::to_vec(&json).unwrap()),
+            BodyOptions::default(),
             HashMap::default(),
             None,
             None,
+            FieldMapping::None,
         )
         .unwrap();
 
@@ -0,0 +1,253 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Journal export format, as sent by `systemd-journal-upload`.
+//!
+//! Entries are separated by an empty line. A field is written as `NAME=value\n`, or when its
+//! value is binary or spans lines, as `NAME\n` followed by the length of the value as a 64 bit
+//! little endian integer, the value and `\n`:
+//! ```text
+//! __CURSOR=s=739ad463348b4ceca5a9e69c95a3c93f;i=4ece7;b=6c7c6013a8914cbf9dc3ead3f2c9c78e
+//! __REALTIME_TIMESTAMP=1705026780451234
+//! _HOSTNAME=web-1
+//! PRIORITY=6
+//! MESSAGE=Started Session 42 of user root.
+//!
+//! ```
+//! Field names become columns in lower case without leading underscores, so `_HOSTNAME` is
+//! `hostname`. Trusted fields, which journald sets with a leading underscore, take precedence over
+//! user fields of the same column. `__REALTIME_TIMESTAMP` is written as an RFC 3339 timestamp.
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+
+const REALTIME_TIMESTAMP: &str = "__REALTIME_TIMESTAMP";
+
+/// Incremental parser of a journal export stream. Entries are returned once they are complete.
+#[derive(Debug, Default)]
+pub struct JournalParser {
+    buffer: Vec<u8>,
+    entry: Map<String, Value>,
+    // bytes the fields of the current entry were read from
+    entry_size: usize,
+}
+
+impl JournalParser {
+    /// Entries completed by `data`, with the number of bytes they were read from
+    pub fn push(&mut self, data: &[u8]) -> anyhow::Result<(Vec<Value>, usize)> {
+        self.buffer.extend_from_slice(data);
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        // position the last complete entry ended at
+        let mut entries_end = None;
+        while let Some(len) = self.buffer[pos..].iter().position(|&b| b == b'\n') {
+            let line = &self.buffer[pos..pos + len];
+            if line.is_empty() {
+                pos += 1;
+                entries_end = Some(pos);
+                if !self.entry.is_empty() {
+                    entries.push(Value::Object(std::mem::take(&mut self.entry)));
+                }
+                continue;
+            }
+
+            if let Some(eq) = line.iter().position(|&b| b == b'=') {
+                let value = String::from_utf8_lossy(&line[eq + 1..]).into_owned();
+                insert_field(&mut self.entry, &line[..eq], value)?;
+                pos += len + 1;
+                continue;
+            }
+
+            // binary field, the name is followed by the length of the value
+            let start = pos + len + 1;
+            let Some(size) = self.buffer.get(start..start + 8) else {
+                break;
+            };
+            let value_start = start + 8;
+            let value_end = usize::try_from(u64::from_le_bytes(size.try_into().unwrap()))
+                .ok()
+                .and_then(|size| value_start.checked_add(size))
+                .ok_or_else(|| anyhow!("binary field is too large"))?;
+            let Some(value) = self.buffer.get(value_start..value_end) else {
+                break;
+            };
+            match self.buffer.get(value_end) {
+                Some(b'\n') => {}
+                Some(_) => bail!("binary field is not terminated by a newline"),
+                None => break,
+            }
+            let value = String::from_utf8_lossy(value).into_owned();
+            insert_field(&mut self.entry, line, value)?;
+            pos = value_end + 1;
+        }
+
+        self.buffer.drain(..pos);
+        let size = match entries_end {
+            Some(end) => {
+                let size = self.entry_size + end;
+                self.entry_size = pos - end;
+                size
+            }
+            None => {
+                self.entry_size += pos;
+                0
+            }
+        };
+        Ok((entries, size))
+    }
+
+    /// Bytes of the entry which is not complete yet, its fields read so far and the bytes held back
+    pub fn pending(&self) -> usize {
+        self.entry_size + self.buffer.len()
+    }
+
+    /// Entry at the end of the stream, which need not be followed by an empty line
+    pub fn finish(mut self) -> anyhow::Result<Option<Value>> {
+        if !self.buffer.is_empty() {
+            // the last field may lack its newline as well
+            self.push(b"\n")?;
+            if !self.buffer.is_empty() {
+                bail!("journal export ends in the middle of a field");
+            }
+        }
+        Ok((!self.entry.is_empty()).then_some(Value::Object(self.entry)))
+    }
+}
+
+fn insert_field(entry: &mut Map<String, Value>, name: &[u8], value: String) -> anyhow::Result<()> {
+    let name = std::str::from_utf8(name).map_err(|_| anyhow!("field name is not utf-8"))?;
+    if name.is_empty() {
+        bail!("field without a name");
+    }
+
+    let value = if name == REALTIME_TIMESTAMP {
+        realtime_timestamp(&value).unwrap_or(Value::String(value))
+    } else {
+        Value::String(value)
+    };
+
+    let column = name.trim_start_matches('_').to_lowercase();
+    if name.starts_with('_') || !entry.contains_key(&column) {
+        entry.insert(column, value);
+    }
+    Ok(())
+}
+
+// microseconds since the epoch
+fn realtime_timestamp(value: &str) -> Option<Value> {
+    let micros = value.parse::<i64>().ok()?;
+    let time = NaiveDateTime::from_timestamp_opt(
+        micros.div_euclid(1_000_000),
+        micros.rem_euclid(1_000_000) as u32 * 1000,
+    )?;
+    let time = DateTime::<Utc>::from_naive_utc_and_offset(time, Utc);
+    Some(Value::String(
+        time.to_rfc3339_opts(SecondsFormat::Micros, true),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::JournalParser;
+
+    fn binary_field(name: &str, value: &[u8]) -> Vec<u8> {
+        let mut field = format!("{name}\n").into_bytes();
+        field.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        field.extend_from_slice(value);
+        field.push(b'\n');
+        field
+    }
+
+    #[test]
+    fn entries_are_mapped_to_columns() {
+        let mut export = b"__REALTIME_TIMESTAMP=1705026780451234\n_HOSTNAME=web-1\nHOSTNAME=spoofed\nPRIORITY=6\n".to_vec();
+        export.extend(binary_field("MESSAGE", b"first line\nsecond line"));
+        export.extend_from_slice(b"\nMESSAGE=next\n\n");
+
+        let (entries, read) = JournalParser::default().push(&export).unwrap();
+
+        assert_eq!(read, export.len());
+        assert_eq!(
+            entries,
+            vec![
+                json!({
+                    "realtime_timestamp": "2024-01-12T02:33:00.451234Z",
+                    "hostname": "web-1",
+                    "priority": "6",
+                    "message": "first line\nsecond line",
+                }),
+                json!({ "message": "next" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn entries_split_across_chunks_are_completed() {
+        let mut export = b"_PID=1\n".to_vec();
+        export.extend(binary_field("MESSAGE", b"split"));
+        export.extend_from_slice(b"\nMESSAGE=last");
+
+        let mut parser = JournalParser::default();
+        let mut entries = Vec::new();
+        for chunk in export.chunks(5) {
+            entries.extend(parser.push(chunk).unwrap().0);
+        }
+        entries.extend(parser.finish().unwrap());
+
+        assert_eq!(
+            entries,
+            vec![
+                json!({ "pid": "1", "message": "split" }),
+                json!({ "message": "last" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_binary_field_is_rejected() {
+        let export = binary_field("MESSAGE", b"truncated");
+        let mut parser = JournalParser::default();
+        parser.push(&export[..export.len() - 4]).unwrap();
+        assert!(parser.finish().is_err());
+    }
+
+    #[test]
+    fn oversized_binary_field_is_rejected() {
+        let mut export = b"MESSAGE\n".to_vec();
+        export.extend_from_slice(&u64::MAX.to_le_bytes());
+        export.extend_from_slice(b"value\n");
+        assert!(JournalParser::default().push(&export).is_err());
+    }
+
+    #[test]
+    fn fields_of_an_incomplete_entry_are_pending() {
+        let mut parser = JournalParser::default();
+        let (entries, _) = parser.push(b"_PID=1\nMESSAGE=long\nPRIO").unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(parser.pending(), b"_PID=1\nMESSAGE=long\nPRIO".len());
+
+        let (entries, read) = parser.push(b"RITY=6\n\n").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(read, b"_PID=1\nMESSAGE=long\nPRIORITY=6\n\n".len());
+        assert_eq!(parser.pending(), 0);
+    }
+}
@@ -16,13 +16,16 @@
  *
  */
 
+use anyhow::anyhow;
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::BTreeMap;
 use std::str;
 
+use super::cloudwatch;
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Message {
     #[serde(rename = "records")]
@@ -88,3 +91,102 @@ pub fn flatten_kinesis_logs(body: &Bytes) -> Vec<BTreeMap<String, Value>> {
     }
     vec_kinesis_json
 }
+
+/// Events of a request from an AWS service, with the id of the Firehose delivery it came in
+pub struct AwsDelivery {
+    pub request_id: Option<String>,
+    pub events: Vec<Value>,
+}
+
+// Unpacks the events of a request from an AWS service. Firehose deliveries are unpacked into
+// their records, which are decoded like in flatten_kinesis_logs and also may be gzipped or plain
+// text. Records of CloudWatch Logs subscriptions, also when forwarded by a Lambda function, are
+// unpacked into their log events. Any other body is taken as json events.
+pub fn unpack_aws_delivery(body: &[u8]) -> anyhow::Result<AwsDelivery> {
+    let body = cloudwatch::decompress(body)?;
+    if let Some(events) = cloudwatch::log_events(&body)? {
+        return Ok(AwsDelivery {
+            request_id: None,
+            events,
+        });
+    }
+
+    let body: Value = serde_json::from_slice(&body)?;
+    let Ok(message) = serde_json::from_value::<Message>(body.clone()) else {
+        let events = match body {
+            Value::Array(events) => events,
+            event => vec![event],
+        };
+        return Ok(AwsDelivery {
+            request_id: None,
+            events,
+        });
+    };
+
+    let mut events = Vec::new();
+    for record in message.records {
+        let data = cloudwatch::decompress(&STANDARD.decode(record.data)?)?;
+        if let Some(log_events) = cloudwatch::log_events(&data)? {
+            events.extend(log_events);
+            continue;
+        }
+
+        let mut event = match serde_json::from_slice(&data) {
+            Ok(Value::Object(event)) => event,
+            _ => {
+                let message = String::from_utf8(data)
+                    .map_err(|_| anyhow!("record is neither json nor text"))?;
+                Map::from_iter([("message".to_owned(), Value::String(message))])
+            }
+        };
+        event.insert(
+            "requestId".to_owned(),
+            Value::String(message.request_id.clone()),
+        );
+        event.insert(
+            "timestamp".to_owned(),
+            Value::String(message.timestamp.to_string()),
+        );
+        events.push(Value::Object(event));
+    }
+
+    Ok(AwsDelivery {
+        request_id: Some(message.request_id),
+        events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde_json::json;
+
+    use super::unpack_aws_delivery;
+
+    fn delivery(records: &[Vec<u8>]) -> Vec<u8> {
+        let records: Vec<_> = records
+            .iter()
+            .map(|data| json!({ "data": STANDARD.encode(data) }))
+            .collect();
+        serde_json::to_vec(&json!({
+            "requestId": "9b848d8a",
+            "timestamp": 1705026780451u64,
+            "records": records,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_are_unpacked() {
+        let delivery = unpack_aws_delivery(&delivery(&[
+            br#"{"level": "info"}"#.to_vec(),
+            b"plain line".to_vec(),
+        ]))
+        .unwrap();
+
+        assert_eq!(delivery.request_id.as_deref(), Some("9b848d8a"));
+        assert_eq!(delivery.events[0]["level"], "info");
+        assert_eq!(delivery.events[1]["message"], "plain line");
+        assert_eq!(delivery.events[1]["requestId"], "9b848d8a");
+    }
+}
@@ -16,15 +16,17 @@
  *
  */
 
-use actix_web::{http::header::ContentType, web, HttpResponse, Result};
+use actix_web::{web, HttpResponse, Result};
 use http::{header, StatusCode};
 use itertools::Itertools;
 use reqwest;
 use serde_json::{json, Value};
 
+use crate::handlers::http::problem::Problem;
 use crate::{
     metadata::{error::stream_info::MetadataError, STREAM_INFO},
     option::CONFIG,
+    tls,
 };
 
 const OPEN_AI_URL: &str = "https://api.openai.com/v1/chat/completions";
@@ -106,7 +108,9 @@ pub async fn make_llm_request(body: web::Json<AiPrompt>) -> Result<HttpResponse,
     let prompt = build_prompt(stream_name, &body.prompt, &schema_json);
     let body = build_request_body(prompt);
 
-    let client = reqwest::Client::new();
+    let client = tls::client_builder(None)
+        .build()
+        .expect("client can be built on this system");
     let response = client
         .post(OPEN_AI_URL)
         .header(header::CONTENT_TYPE, "application/json")
@@ -160,8 +164,6 @@ impl actix_web::ResponseError for LLMError {
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        actix_web::HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
+        Problem::new(self.status_code(), self).response()
     }
 }
@@ -17,27 +17,98 @@
  */
 
 use self::error::{CreateStreamError, StreamError};
-use crate::alerts::Alerts;
-use crate::handlers::{STATIC_SCHEMA_FLAG, TIME_PARTITION_KEY};
+use crate::alerts::{burn_rate, silence, Alerts, Rule};
+use crate::handlers::{
+    CUSTOM_PARTITION_KEY, STATIC_SCHEMA_FLAG, TIME_PARTITION_KEY, TRANSACTION_ID_KEY,
+};
 use crate::metadata::STREAM_INFO;
+use crate::metrics;
 use crate::option::{Mode, CONFIG};
+use crate::query::{default_filter, renamed_columns, QUERY_SESSION};
+use crate::schema_registry::Compatibility;
 use crate::static_schema::{convert_static_schema_to_arrow_schema, StaticSchema};
-use crate::storage::{retention::Retention, LogStream, StorageDir, StreamInfo};
-use crate::{catalog, event, stats};
+use crate::storage::archive::{archive_stream, Archive};
+use crate::storage::iceberg::{self, IcebergExport};
+use crate::storage::{consistency, purge};
+use crate::storage::{
+    retention::Retention, FieldMapping, LegalHold, LogStream, SortKey, StorageDir, StreamInfo,
+    StreamSettings,
+};
+use crate::sync::MIN_FLUSH_INTERVAL;
+use crate::utils::actix::request_username;
+use crate::utils::header_parsing::validate_tag_name;
+use crate::webhooks::{self, LifecycleEvent};
+use crate::{catalog, event, replication, schema_registry, stats};
 use crate::{metadata, validator};
 
-use super::cluster::fetch_stats_from_ingesters;
 use super::cluster::utils::{merge_quried_stats, IngestionStats, QueriedStats, StorageStats};
+use super::cluster::{
+    delete_stream_on_ingesters, fetch_stats_from_ingesters, prepare_stream_with_ingesters,
+    sync_stream_settings_with_ingesters,
+};
+use super::stream_templates::{get_template, StreamTemplate};
 use actix_web::http::StatusCode;
-use actix_web::{web, HttpRequest, Responder};
-use arrow_schema::{Field, Schema};
+use actix_web::{web, Either, HttpRequest, HttpResponse, Responder, ResponseError};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema};
 use bytes::Bytes;
 use chrono::Utc;
+use once_cell::sync::Lazy;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use ulid::Ulid;
+
+// more keys rarely help pruning and make every conversion slower
+const MAX_SORT_KEYS: usize = 8;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ConsistencyQuery {
+    #[serde(default)]
+    pub repair: bool,
+    #[serde(default)]
+    pub verify_checksums: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PurgeRequest {
+    pub predicate: String,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct PutStreamQuery {
+    /// name of the stream template the stream is created from
+    #[serde(default)]
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct LegalHoldRequest {
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// body of the flush interval api, no interval means the default interval is used
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct FlushInterval {
+    #[serde(default, with = "humantime_serde")]
+    pub interval: Option<Duration>,
+}
 
+/// Delete a log stream and its data
+#[utoipa::path(
+    delete,
+    path = "/api/v1/logstream/{logstream}",
+    tag = "logstream",
+    params(("logstream" = String, Path, description = "Name of the log stream")),
+    responses(
+        (status = 200, description = "Log stream was deleted"),
+        (status = 404, description = "Log stream does not exist", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Log stream is under legal hold", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn delete(req: HttpRequest) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
 
@@ -45,261 +116,1534 @@ pub async fn delete(req: HttpRequest) -> Result<impl Responder, StreamError> {
         return Err(StreamError::StreamNotFound(stream_name));
     }
 
+    if STREAM_INFO.legal_hold(&stream_name)?.is_some() {
+        return Err(StreamError::LegalHold(stream_name));
+    }
+
     let objectstore = CONFIG.storage().get_object_store();
+
+    // copy the data out before deleting anything, deletion is aborted if archival fails
+    let mut archived = String::new();
+    if let Some(archive) = objectstore
+        .get_object_store_format(&stream_name)
+        .await?
+        .archive
+    {
+        let report = archive_stream(&stream_name, &archive).await?;
+        log::info!("archived log stream {stream_name}: {report:?}");
+        archived = format!(
+            ", archived {} files ({} bytes) to {}",
+            report.files, report.bytes, report.destination
+        );
+    }
+
     objectstore.delete_stream(&stream_name).await?;
-    metadata::STREAM_INFO.delete_stream(&stream_name);
-    event::STREAM_WRITERS.delete_stream(&stream_name);
-    stats::delete_stats(&stream_name, "json").unwrap_or_else(|e| {
+    drop_local_stream(&stream_name).await?;
+
+    // ingesters delete their copy when the stream is deleted through the query server
+    if CONFIG.parseable.mode != Mode::Ingest {
+        webhooks::notify(LifecycleEvent::StreamDeleted {
+            stream: stream_name.clone(),
+        });
+    }
+
+    let msg = format!("log stream {stream_name} deleted{archived}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = delete_stream_on_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+// forget a stream and remove the staging data this node holds of it. The staging directory is
+// only removed for a valid name of a known stream, so that no other directory is removed
+async fn drop_local_stream(stream_name: &str) -> Result<(), StreamError> {
+    validator::stream_name(stream_name).map_err(CreateStreamError::from)?;
+    if !metadata::STREAM_INFO.stream_exists(stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name.to_owned()));
+    }
+
+    metadata::STREAM_INFO.delete_stream(stream_name);
+    event::STREAM_WRITERS.delete_stream(stream_name);
+    schema_registry::forget(stream_name).await;
+    stats::delete_stats(stream_name, "json").unwrap_or_else(|e| {
         log::warn!("failed to delete stats for stream {}: {:?}", stream_name, e)
     });
 
-    let stream_dir = StorageDir::new(&stream_name);
-    if fs::remove_dir_all(&stream_dir.data_path).is_err() {
+    let stream_dir = StorageDir::new(stream_name);
+    if stream_dir.data_path.exists() && fs::remove_dir_all(&stream_dir.data_path).is_err() {
         log::warn!(
             "failed to delete local data for stream {}. Clean {} manually",
             stream_name,
             stream_dir.data_path.to_string_lossy()
         )
     }
+    replication::drop_stream(stream_name);
+    metrics::remove_stream_label(stream_name);
+    Ok(())
+}
+
+// DELETE /logstream/{logstream}/staging, the query server deleted the stream and its shared
+// data, the ingester drops its staged events, copies kept for peers and in memory schema
+pub async fn delete_staging(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    validator::stream_name(&stream_name).map_err(CreateStreamError::from)?;
+    // ingesters load a stream once they receive events for it
+    if !STREAM_INFO.stream_exists(&stream_name) {
+        return Ok((
+            format!("log stream {stream_name} is not loaded on this ingester"),
+            StatusCode::OK,
+        ));
+    }
+    if STREAM_INFO.legal_hold(&stream_name)?.is_some() {
+        return Err(StreamError::LegalHold(stream_name));
+    }
+    drop_local_stream(&stream_name).await?;
+
+    Ok((
+        format!("dropped staging data of log stream {stream_name}"),
+        StatusCode::OK,
+    ))
+}
+
+pub async fn flush(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    if CONFIG.parseable.mode == Mode::Query {
+        return Err(StreamError::Custom {
+            msg: "Query server does not stage any data, flush the ingest servers instead"
+                .to_string(),
+            status: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    // files of the current minute are converted too, once set aside from new events
+    event::STREAM_WRITERS.seal_stream(&stream_name);
+    CONFIG
+        .storage()
+        .get_object_store()
+        .sync_streams(std::slice::from_ref(&stream_name))
+        .await?;
+
+    Ok((
+        format!("staging data of log stream {stream_name} flushed"),
+        StatusCode::OK,
+    ))
+}
+
+// events of the stream not converted yet, as an arrow IPC stream
+// the query server merges them with the data in object storage
+pub async fn get_staging(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let schema = STREAM_INFO.schema(&stream_name)?;
+    let records = event::STREAM_WRITERS
+        .recordbatches_cloned(&stream_name, &schema)
+        .unwrap_or_default();
+
+    let mut body = Vec::new();
+    {
+        let mut writer = StreamWriter::try_new(&mut body, &schema)
+            .map_err(|err| StreamError::Anyhow(err.into()))?;
+        for rb in &records {
+            writer
+                .write(rb)
+                .map_err(|err| StreamError::Anyhow(err.into()))?;
+        }
+        writer
+            .finish()
+            .map_err(|err| StreamError::Anyhow(err.into()))?;
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/vnd.apache.arrow.stream")
+        .body(body))
+}
+
+/// List the log streams
+#[utoipa::path(
+    get,
+    path = "/api/v1/logstream",
+    tag = "logstream",
+    responses((status = 200, description = "Log streams on the server", body = Vec<LogStream>))
+)]
+pub async fn list(_: HttpRequest) -> impl Responder {
+    let res: Vec<LogStream> = STREAM_INFO
+        .list_streams()
+        .into_iter()
+        .map(|stream| LogStream { name: stream })
+        .collect();
+
+    web::Json(res)
+}
+
+/// Arrow schema of a log stream
+#[utoipa::path(
+    get,
+    path = "/api/v1/logstream/{logstream}/schema",
+    tag = "logstream",
+    params(("logstream" = String, Path, description = "Name of the log stream")),
+    responses(
+        (status = 200, description = "Arrow schema of the log stream", body = Object),
+        (status = 404, description = "Log stream does not exist", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn schema(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let schema = STREAM_INFO.schema(&stream_name)?;
+    Ok((web::Json(schema), StatusCode::OK))
+}
+
+pub async fn get_schema_versions(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let versions = schema_registry::versions(&stream_name).await?;
+    Ok((web::Json(versions), StatusCode::OK))
+}
+
+pub async fn get_schema_compatibility(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let compatibility = STREAM_INFO.get_schema_compatibility(&stream_name)?;
+    Ok((web::Json(compatibility), StatusCode::OK))
+}
+
+// events are checked against the schema of the stream when it is set, fields added before
+// are kept
+pub async fn put_schema_compatibility(
+    req: HttpRequest,
+    body: web::Json<Compatibility>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let compatibility = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.schema_compatibility = compatibility;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_schema_compatibility(&stream_name, compatibility)?;
+
+    let msg = format!("set schema compatibility for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub async fn get_alert(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let alerts = metadata::STREAM_INFO
+        .read()
+        .expect(metadata::LOCK_EXPECT)
+        .get(&stream_name)
+        .map(|metadata| {
+            serde_json::to_value(&metadata.alerts).expect("alerts can serialize to valid json")
+        });
+
+    let mut alerts = match alerts {
+        Some(alerts) => alerts,
+        None => {
+            let alerts = CONFIG
+                .storage()
+                .get_object_store()
+                .get_alerts(&stream_name)
+                .await?;
+
+            if alerts.alerts.is_empty() {
+                return Err(StreamError::NoAlertsSet);
+            }
+
+            serde_json::to_value(alerts).expect("alerts can serialize to valid json")
+        }
+    };
+
+    // ids are kept, the history of an alert is looked up by its id
+    add_silences_to_alerts(&stream_name, &mut alerts);
+
+    Ok((web::Json(alerts), StatusCode::OK))
+}
+
+// a stream creation request, checked against the streams of this node
+struct NewStream {
+    name: String,
+    time_partition: String,
+    custom_partition: String,
+    static_schema_flag: String,
+    schema: Arc<Schema>,
+}
+
+impl NewStream {
+    // headers of the request take precedence over the template
+    fn parse(
+        req: &HttpRequest,
+        body: &Bytes,
+        template: Option<&StreamTemplate>,
+    ) -> Result<Self, StreamError> {
+        let header = |key: &str, default: Option<&str>| {
+            req.headers()
+                .get(key)
+                .map(|value| value.to_str().unwrap().to_owned())
+                .or_else(|| default.map(str::to_owned))
+                .unwrap_or_default()
+        };
+        let time_partition = header(
+            TIME_PARTITION_KEY,
+            template.and_then(|template| template.time_partition.as_deref()),
+        );
+        let custom_partition = header(
+            CUSTOM_PARTITION_KEY,
+            template.and_then(|template| template.custom_partition.as_deref()),
+        );
+        let static_schema_flag = header(
+            STATIC_SCHEMA_FLAG,
+            template.map(StreamTemplate::static_schema_flag),
+        );
+
+        let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+        validator::stream_name(&stream_name).map_err(CreateStreamError::from)?;
+        let mut schema = Arc::new(Schema::empty());
+        if metadata::STREAM_INFO.stream_exists(&stream_name) {
+            // Error if the log stream already exists
+            return Err(StreamError::Custom {
+                msg: format!(
+                    "logstream {stream_name} already exists, please create a new log stream with unique name"
+                ),
+                status: StatusCode::BAD_REQUEST,
+            });
+        }
+        // streams and external tables share the namespace of queries
+        if crate::external_tables::exists(&stream_name) {
+            return Err(StreamError::Custom {
+                msg: format!("an external table named {stream_name} already exists"),
+                status: StatusCode::BAD_REQUEST,
+            });
+        }
+
+        if !body.is_empty() && static_schema_flag == "true" {
+            let static_schema: StaticSchema = serde_json::from_slice(body).unwrap();
+            let parsed_schema = convert_static_schema_to_arrow_schema(static_schema);
+            if let Ok(parsed_schema) = parsed_schema {
+                schema = parsed_schema;
+            } else {
+                return Err(StreamError::Custom {
+                    msg: format!(
+                        "unable to commit static schema, logstream {stream_name} not created"
+                    ),
+                    status: StatusCode::BAD_REQUEST,
+                });
+            }
+        } else if body.is_empty() && static_schema_flag == "true" {
+            return Err(StreamError::Custom {
+                msg: format!(
+                    "please provide schema in the request body for static schema logstream {stream_name}"
+                ),
+                status: StatusCode::BAD_REQUEST,
+            });
+        }
+
+        if !custom_partition.is_empty() {
+            validate_custom_partition(
+                &custom_partition,
+                &time_partition,
+                &static_schema_flag,
+                &schema,
+            )
+            .map_err(|msg| StreamError::Custom {
+                msg,
+                status: StatusCode::BAD_REQUEST,
+            })?;
+        }
+
+        Ok(Self {
+            name: stream_name,
+            time_partition,
+            custom_partition,
+            static_schema_flag,
+            schema,
+        })
+    }
+
+    async fn create(self) -> Result<(), CreateStreamError> {
+        create_stream(
+            self.name,
+            &self.time_partition,
+            &self.custom_partition,
+            &self.static_schema_flag,
+            self.schema,
+        )
+        .await
+    }
+}
+
+// the custom partition column becomes part of object keys, so only plain column names are allowed
+pub(crate) fn validate_custom_partition(
+    column: &str,
+    time_partition: &str,
+    static_schema_flag: &str,
+    schema: &Schema,
+) -> Result<(), String> {
+    if !column
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(format!(
+            "custom partition {column} can only contain alphanumeric characters and underscores"
+        ));
+    }
+    if column == time_partition || column == event::DEFAULT_TIMESTAMP_KEY {
+        return Err(format!(
+            "custom partition {column} can not be the time partition of the log stream"
+        ));
+    }
+    if static_schema_flag == "true" && schema.field_with_name(column).is_err() {
+        return Err(format!(
+            "custom partition {column} is not a field of the static schema"
+        ));
+    }
+    Ok(())
+}
+
+/// Create a log stream, or update its static schema
+#[utoipa::path(
+    put,
+    path = "/api/v1/logstream/{logstream}",
+    tag = "logstream",
+    params(
+        ("logstream" = String, Path, description = "Name of the log stream"),
+        ("X-P-Time-Partition" = Option<String>, Header, description = "Field the events are partitioned by in time"),
+        ("X-P-Custom-Partition" = Option<String>, Header, description = "Comma separated fields the events are partitioned by"),
+        ("X-P-Static-Schema-Flag" = Option<String>, Header, description = "`true` if the body sets the schema of the stream"),
+        ("template" = Option<String>, Query, description = "Stream template the stream is created from, headers and body take precedence"),
+    ),
+    request_body(content = Option<Object>, description = "Static schema of the stream"),
+    responses(
+        (status = 200, description = "Log stream was created"),
+        (status = 400, description = "Invalid stream name or schema", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn put_stream(
+    req: HttpRequest,
+    query: web::Query<PutStreamQuery>,
+    body: Bytes,
+) -> Result<impl Responder, StreamError> {
+    let template = match &query.template {
+        Some(name) => Some(
+            get_template(name)
+                .await
+                .map_err(|err| StreamError::Custom {
+                    msg: err.to_string(),
+                    status: err.status_code(),
+                })?,
+        ),
+        None => None,
+    };
+    // the schema of the template, unless the request has one
+    let body = match template.as_ref().and_then(|t| t.static_schema.as_ref()) {
+        Some(schema) if body.is_empty() => Bytes::from(serde_json::to_vec(schema)?),
+        _ => body,
+    };
+    let stream = NewStream::parse(&req, &body, template.as_ref())?;
+    let stream_name = stream.name.clone();
+
+    // ingesters only activate the stream once it is created here too
+    if CONFIG.parseable.mode != Mode::Query {
+        stream.create().await?;
+        if let Some(template) = &template {
+            if let Err(err) = apply_template(&stream_name, template).await {
+                roll_back_creation(&stream_name).await;
+                return Err(err);
+            }
+        }
+        return Ok(Either::Left(("log stream created", StatusCode::OK)));
+    }
+
+    let prepared = prepare_stream_with_ingesters(
+        &stream.name,
+        &stream.time_partition,
+        &stream.custom_partition,
+        &stream.static_schema_flag,
+        body,
+    )
+    .await?;
+    if !prepared.is_ready() {
+        let report = prepared
+            .abort("log stream was rejected by another ingester")
+            .await;
+        return Ok(Either::Right(report.into_failure_response()));
+    }
+    if let Err(err) = stream.create().await {
+        prepared.abort("log stream could not be created").await;
+        return Err(err.into());
+    }
+    // the template is applied before the ingesters activate the stream, so that a stream is
+    // never left half configured
+    if let Some(template) = &template {
+        if let Err(err) = apply_template(&stream_name, template).await {
+            prepared.abort("stream template could not be applied").await;
+            roll_back_creation(&stream_name).await;
+            return Err(err);
+        }
+    }
+
+    let mut report = match prepared.commit().await {
+        Ok(report) => report,
+        Err(report) => {
+            // the ingesters dropped the stream again, so does this node
+            roll_back_creation(&stream_name).await;
+            return Ok(Either::Right(report.into_failure_response()));
+        }
+    };
+    // ingesters create the stream without the template, its settings are synced once they did
+    if let Some(template) = &template {
+        report.extend(
+            sync_stream_settings_with_ingesters(
+                &stream_name,
+                format!("apply stream template {}", template.name),
+            )
+            .await?,
+        );
+    }
+    Ok(Either::Right(report.respond_to(&req)))
+}
+
+// remove a stream whose creation failed part way, from the storage and this node
+async fn roll_back_creation(stream_name: &str) {
+    if let Err(err) = CONFIG
+        .storage()
+        .get_object_store()
+        .delete_stream(stream_name)
+        .await
+    {
+        log::error!("failed to roll back creation of log stream {stream_name}: {err}");
+    }
+    if let Err(err) = drop_local_stream(stream_name).await {
+        log::error!("failed to roll back creation of log stream {stream_name}: {err}");
+    }
+}
+
+// settings and alerts of the template, applied to the stream created from it
+async fn apply_template(stream_name: &str, template: &StreamTemplate) -> Result<(), StreamError> {
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(stream_name).await?;
+    if template.settings.apply_to(&mut stream_metadata) {
+        storage
+            .put_stream_manifest(stream_name, &stream_metadata)
+            .await?;
+    }
+    let settings = template.settings.clone();
+    STREAM_INFO.set_stream_cache(stream_name, settings.cache_enabled)?;
+    STREAM_INFO.set_flush_interval(stream_name, settings.flush_interval)?;
+    STREAM_INFO.set_sort_keys(stream_name, settings.sort_keys)?;
+    STREAM_INFO.set_field_mapping(stream_name, settings.field_mapping)?;
+    STREAM_INFO.set_allowed_tags(stream_name, settings.allowed_tags)?;
+    STREAM_INFO.set_max_columns(stream_name, settings.max_columns)?;
+    STREAM_INFO.set_schema_compatibility(stream_name, settings.schema_compatibility)?;
+    STREAM_INFO.set_renamed_columns(stream_name, settings.renamed_columns)?;
+    STREAM_INFO.set_default_filter(stream_name, settings.default_filter)?;
+
+    if let Some(alerts) = &template.alerts {
+        set_alerts(stream_name, alerts.clone()).await?;
+    }
+    Ok(())
+}
+
+// stream creations prepared by the query server, by stream name
+static PREPARED_STREAMS: Lazy<Mutex<HashMap<String, PreparedStream>>> = Lazy::new(Mutex::default);
+// prepared creations neither committed nor aborted are dropped after this long
+const PREPARED_STREAM_TTL: Duration = Duration::from_secs(60);
+
+struct PreparedStream {
+    transaction: String,
+    stream: NewStream,
+    prepared_at: Instant,
+}
+
+fn transaction_id(req: &HttpRequest) -> Result<String, StreamError> {
+    req.headers()
+        .get(TRANSACTION_ID_KEY)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .ok_or_else(|| StreamError::Custom {
+            msg: format!("missing header {TRANSACTION_ID_KEY}"),
+            status: StatusCode::BAD_REQUEST,
+        })
+}
+
+// PUT /logstream/{logstream}/prepare, first phase of creating a stream on every ingester
+pub async fn prepare_stream(req: HttpRequest, body: Bytes) -> Result<impl Responder, StreamError> {
+    let transaction = transaction_id(&req)?;
+    let stream = NewStream::parse(&req, &body, None)?;
+
+    let mut prepared = PREPARED_STREAMS.lock().unwrap();
+    prepared.retain(|_, pending| pending.prepared_at.elapsed() < PREPARED_STREAM_TTL);
+    if let Some(pending) = prepared.get(&stream.name) {
+        if pending.transaction != transaction {
+            return Err(StreamError::Custom {
+                msg: format!("logstream {} is already being created", stream.name),
+                status: StatusCode::CONFLICT,
+            });
+        }
+    }
+    prepared.insert(
+        stream.name.clone(),
+        PreparedStream {
+            transaction,
+            stream,
+            prepared_at: Instant::now(),
+        },
+    );
+
+    Ok(("log stream prepared", StatusCode::OK))
+}
+
+// POST /logstream/{logstream}/commit, activate a prepared stream
+pub async fn commit_stream(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let transaction = transaction_id(&req)?;
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let pending = {
+        let mut prepared = PREPARED_STREAMS.lock().unwrap();
+        match prepared.get(&stream_name) {
+            Some(pending) if pending.transaction == transaction => prepared.remove(&stream_name),
+            _ => None,
+        }
+    };
+    // a retried commit, or an event for the stream, finds the stream already created
+    if metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Ok(("log stream created", StatusCode::OK));
+    }
+    let Some(pending) = pending else {
+        return Err(StreamError::Custom {
+            msg: format!("no prepared creation of logstream {stream_name} for this transaction"),
+            status: StatusCode::CONFLICT,
+        });
+    };
+    pending.stream.create().await?;
+
+    Ok(("log stream created", StatusCode::OK))
+}
+
+// DELETE /logstream/{logstream}/prepare, drop a prepared stream
+pub async fn abort_stream(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let transaction = transaction_id(&req)?;
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    let mut prepared = PREPARED_STREAMS.lock().unwrap();
+    if prepared
+        .get(&stream_name)
+        .is_some_and(|pending| pending.transaction == transaction)
+    {
+        prepared.remove(&stream_name);
+    }
+
+    Ok(("log stream creation aborted", StatusCode::OK))
+}
+
+pub async fn put_alert(
+    req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    set_alerts(&stream_name, body.into_inner()).await?;
+
+    Ok((
+        format!("set alert configuration for log stream {stream_name}"),
+        StatusCode::OK,
+    ))
+}
+
+// validate and store the alerts of a stream
+async fn set_alerts(stream_name: &str, mut body: Value) -> Result<(), StreamError> {
+    let stream_name = stream_name.to_owned();
+    remove_id_from_alerts(&mut body);
+
+    let alerts: Alerts = match serde_json::from_value(body) {
+        Ok(alerts) => alerts,
+        Err(err) => {
+            return Err(StreamError::BadAlertJson {
+                stream: stream_name,
+                err,
+            })
+        }
+    };
+
+    validator::alert(&alerts)?;
+
+    if !STREAM_INFO.stream_initialized(&stream_name)? {
+        return Err(StreamError::UninitializedLogstream);
+    }
+
+    let schema = STREAM_INFO.schema(&stream_name)?;
+    for alert in &alerts.alerts {
+        if let Rule::BurnRate(rule) = &alert.rule {
+            if !rule.objective.valid_for_stream(&stream_name).await {
+                return Err(StreamError::InvalidAlert(alert.name.to_owned()));
+            }
+        }
+        for column in alert.message.extract_column_names() {
+            // messages of burn rate alerts describe the measured rates, not an event
+            let is_valid = match alert.rule {
+                Rule::BurnRate(_) => burn_rate::MESSAGE_COLUMNS.contains(&column),
+                _ => alert.message.valid(&schema, column),
+            };
+            if !is_valid {
+                return Err(StreamError::InvalidAlertMessage(
+                    alert.name.to_owned(),
+                    column.to_string(),
+                ));
+            }
+            if !alert.rule.valid_for_schema(&schema) {
+                return Err(StreamError::InvalidAlert(alert.name.to_owned()));
+            }
+        }
+    }
+
+    CONFIG
+        .storage()
+        .get_object_store()
+        .put_alerts(&stream_name, &alerts)
+        .await?;
+
+    metadata::STREAM_INFO
+        .set_alert(&stream_name, alerts)
+        .expect("alerts set on existing stream");
+
+    Ok(())
+}
+
+pub async fn get_retention(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let objectstore = CONFIG.storage().get_object_store();
+
+    if !objectstore.stream_exists(&stream_name).await? {
+        return Err(StreamError::StreamNotFound(stream_name.to_string()));
+    }
+
+    let retention = CONFIG
+        .storage()
+        .get_object_store()
+        .get_retention(&stream_name)
+        .await?;
+
+    Ok((web::Json(retention), StatusCode::OK))
+}
+
+pub async fn put_retention(
+    req: HttpRequest,
+    body: web::Json<serde_json::Value>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let body = body.into_inner();
+
+    let retention: Retention = match serde_json::from_value(body) {
+        Ok(retention) => retention,
+        Err(err) => return Err(StreamError::InvalidRetentionConfig(err)),
+    };
+
+    CONFIG
+        .storage()
+        .get_object_store()
+        .put_retention(&stream_name, &retention)
+        .await?;
+
+    let msg = format!("set retention configuration for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub async fn get_cache_enabled(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let cache_enabled = STREAM_INFO.cache_enabled(&stream_name)?;
+    Ok((web::Json(cache_enabled), StatusCode::OK))
+}
+
+pub async fn put_enable_cache(
+    req: HttpRequest,
+    body: web::Json<bool>,
+) -> Result<impl Responder, StreamError> {
+    let enable_cache = body.into_inner();
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let storage = CONFIG.storage().get_object_store();
+
+    if CONFIG.parseable.local_cache_path.is_none() {
+        return Err(StreamError::CacheNotEnabled(stream_name));
+    }
+
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.cache_enabled = enable_cache;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+
+    STREAM_INFO.set_stream_cache(&stream_name, enable_cache)?;
+
+    let msg = format!("Cache set to {enable_cache} for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub async fn get_flush_interval(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let interval = STREAM_INFO
+        .read()
+        .unwrap()
+        .get(&stream_name)
+        .and_then(|meta| meta.flush_interval);
+    Ok((web::Json(FlushInterval { interval }), StatusCode::OK))
+}
+
+pub async fn put_flush_interval(
+    req: HttpRequest,
+    body: web::Json<FlushInterval>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let flush_interval = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    if flush_interval
+        .interval
+        .is_some_and(|interval| interval < MIN_FLUSH_INTERVAL)
+    {
+        return Err(StreamError::Custom {
+            msg: format!(
+                "flush interval can not be smaller than {}",
+                humantime::format_duration(MIN_FLUSH_INTERVAL)
+            ),
+            status: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.flush_interval = flush_interval.interval;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_flush_interval(&stream_name, flush_interval.interval)?;
+
+    // ingesters keep their own copy of the stream metadata
+    let msg = format!("set flush interval for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub async fn get_sort_keys(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let sort_keys = STREAM_INFO.get_sort_keys(&stream_name)?;
+    Ok((web::Json(sort_keys), StatusCode::OK))
+}
+
+// sort keys apply to parquet files written after the change, an empty list restores time ordering
+pub async fn put_sort_keys(
+    req: HttpRequest,
+    body: web::Json<Vec<SortKey>>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let sort_keys = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    validate_sort_keys(&sort_keys).map_err(|msg| StreamError::Custom {
+        msg,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.sort_keys = sort_keys.clone();
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_sort_keys(&stream_name, sort_keys)?;
+
+    let msg = format!("set sort keys for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub(crate) fn validate_sort_keys(sort_keys: &[SortKey]) -> Result<(), String> {
+    if sort_keys.len() > MAX_SORT_KEYS {
+        return Err(format!(
+            "a log stream can have at most {MAX_SORT_KEYS} sort keys"
+        ));
+    }
+    let mut columns = HashSet::new();
+    for key in sort_keys {
+        if key.column.trim().is_empty() {
+            return Err("sort key column can not be empty".to_string());
+        }
+        if !columns.insert(key.column.as_str()) {
+            return Err(format!("column {} is used more than once", key.column));
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_field_mapping(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let field_mapping = STREAM_INFO.get_field_mapping(&stream_name)?;
+    Ok((web::Json(field_mapping), StatusCode::OK))
+}
+
+// the mapping applies to events ingested after the change, stored events keep their columns
+pub async fn put_field_mapping(
+    req: HttpRequest,
+    body: web::Json<FieldMapping>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let field_mapping = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.field_mapping = field_mapping;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_field_mapping(&stream_name, field_mapping)?;
+
+    let msg = format!("set field mapping for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub async fn get_allowed_tags(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let allowed_tags = STREAM_INFO.get_allowed_tags(&stream_name)?;
+    Ok((web::Json(allowed_tags), StatusCode::OK))
+}
+
+// tags are checked when events are ingested, columns of tags no longer allowed are kept
+pub async fn put_allowed_tags(
+    req: HttpRequest,
+    body: web::Json<Vec<String>>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let mut allowed_tags = body.into_inner();
+    allowed_tags.sort();
+    allowed_tags.dedup();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    validate_allowed_tags(&stream_name, &allowed_tags).map_err(|msg| StreamError::Custom {
+        msg,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.allowed_tags.clone_from(&allowed_tags);
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_allowed_tags(&stream_name, allowed_tags)?;
+
+    let msg = format!("set allowed tags for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+// tags become columns, on streams with a static schema they have to be string columns of it
+fn validate_allowed_tags(stream_name: &str, allowed_tags: &[String]) -> Result<(), String> {
+    let static_schema = STREAM_INFO
+        .get_static_schema_flag(stream_name)
+        .map_err(|err| err.to_string())?
+        .is_some();
+    let schema = STREAM_INFO
+        .schema(stream_name)
+        .map_err(|err| err.to_string())?;
+    for name in allowed_tags {
+        validate_tag_name(name).map_err(|err| err.to_string())?;
+        if static_schema
+            && !schema
+                .field_with_name(name)
+                .is_ok_and(|field| field.data_type() == &DataType::Utf8)
+        {
+            return Err(format!(
+                "tag {name} has to be a string column of the static schema"
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_max_columns(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let max_columns = STREAM_INFO.get_max_columns(&stream_name)?;
+    Ok((web::Json(max_columns), StatusCode::OK))
+}
+
+// a limit below the current number of columns keeps them, only new fields are packed.
+// `null` removes the limit.
+pub async fn put_max_columns(
+    req: HttpRequest,
+    body: web::Json<Option<usize>>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let max_columns = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    validate_max_columns(&stream_name, max_columns).map_err(|msg| StreamError::Custom {
+        msg,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.max_columns = max_columns;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_max_columns(&stream_name, max_columns)?;
+
+    let msg = format!("set max columns for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+fn validate_max_columns(stream_name: &str, max_columns: Option<usize>) -> Result<(), String> {
+    let Some(max_columns) = max_columns else {
+        return Ok(());
+    };
+    if STREAM_INFO
+        .get_static_schema_flag(stream_name)
+        .map_err(|err| err.to_string())?
+        .is_some()
+    {
+        return Err("the schema of a stream with a static schema does not grow".to_string());
+    }
+    if max_columns < event::format::json::MIN_MAX_COLUMNS {
+        return Err(format!(
+            "max columns can not be smaller than {}",
+            event::format::json::MIN_MAX_COLUMNS
+        ));
+    }
+    Ok(())
+}
+
+pub async fn get_renamed_columns(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let renames = STREAM_INFO.get_renamed_columns(&stream_name)?;
+    Ok((web::Json(renames), StatusCode::OK))
+}
+
+// body maps old names of columns to their current names, events are not changed. An empty
+// object removes the renames.
+pub async fn put_renamed_columns(
+    req: HttpRequest,
+    body: web::Json<BTreeMap<String, String>>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let renames = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    renamed_columns::validate(&renames).map_err(|msg| StreamError::Custom {
+        msg,
+        status: StatusCode::BAD_REQUEST,
+    })?;
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.renamed_columns.clone_from(&renames);
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_renamed_columns(&stream_name, renames)?;
+
+    let msg = format!("set renamed columns for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+pub async fn get_default_filter(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let default_filter = STREAM_INFO.get_default_filter(&stream_name)?;
+    Ok((web::Json(default_filter), StatusCode::OK))
+}
+
+// body is a predicate as in the `WHERE` clause of a query of the stream, e.g.
+// `"path != '/health'"`. `null` removes the filter.
+pub async fn put_default_filter(
+    req: HttpRequest,
+    body: web::Json<Option<String>>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let default_filter = body.into_inner();
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    if let Some(filter) = &default_filter {
+        default_filter::plan(&QUERY_SESSION.state(), &stream_name, filter)
+            .await
+            .map_err(|err| StreamError::Custom {
+                msg: format!("invalid default filter: {err}"),
+                status: StatusCode::BAD_REQUEST,
+            })?;
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.default_filter.clone_from(&default_filter);
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
+    STREAM_INFO.set_default_filter(&stream_name, default_filter)?;
+
+    let msg = format!("set default filter for log stream {stream_name}");
+    if CONFIG.parseable.mode == Mode::Query {
+        let report = sync_stream_settings_with_ingesters(&stream_name, msg).await?;
+        return Ok(Either::Right(report));
+    }
+
+    Ok(Either::Left((msg, StatusCode::OK)))
+}
+
+// settings of a stream this ingester acts on
+pub async fn get_settings(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut settings = StreamSettings::of(&storage.get_stream_metadata(&stream_name).await?);
+    // cache and flush interval are read from memory, not from the copy in storage
+    settings.cache_enabled = STREAM_INFO.cache_enabled(&stream_name)?;
+    settings.flush_interval = STREAM_INFO
+        .read()
+        .unwrap()
+        .get(&stream_name)
+        .and_then(|meta| meta.flush_interval);
+
+    Ok((web::Json(settings), StatusCode::OK))
+}
+
+// settings of a stream forwarded by the query server, applied to the copy of this ingester
+pub async fn put_settings(
+    req: HttpRequest,
+    body: web::Json<StreamSettings>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let settings = body.into_inner();
+
+    // streams this ingester has not seen yet are created from the shared metadata
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Ok((
+            format!("log stream {stream_name} is not loaded on this ingester"),
+            StatusCode::OK,
+        ));
+    }
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    if settings.apply_to(&mut stream_metadata) {
+        storage
+            .put_stream_manifest(&stream_name, &stream_metadata)
+            .await?;
+    }
+    STREAM_INFO.set_stream_cache(&stream_name, settings.cache_enabled)?;
+    STREAM_INFO.set_flush_interval(&stream_name, settings.flush_interval)?;
+    STREAM_INFO.set_sort_keys(&stream_name, settings.sort_keys)?;
+    STREAM_INFO.set_field_mapping(&stream_name, settings.field_mapping)?;
+    STREAM_INFO.set_allowed_tags(&stream_name, settings.allowed_tags)?;
+    STREAM_INFO.set_max_columns(&stream_name, settings.max_columns)?;
+    STREAM_INFO.set_schema_compatibility(&stream_name, settings.schema_compatibility)?;
+    STREAM_INFO.set_renamed_columns(&stream_name, settings.renamed_columns)?;
+    STREAM_INFO.set_default_filter(&stream_name, settings.default_filter)?;
 
-    Ok((format!("log stream {stream_name} deleted"), StatusCode::OK))
+    Ok((
+        format!("applied settings for log stream {stream_name}"),
+        StatusCode::OK,
+    ))
 }
 
-pub async fn list(_: HttpRequest) -> impl Responder {
-    let res: Vec<LogStream> = STREAM_INFO
-        .list_streams()
-        .into_iter()
-        .map(|stream| LogStream { name: stream })
-        .collect();
+pub async fn get_archive(req: HttpRequest) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
 
-    web::Json(res)
-}
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
 
-pub async fn schema(req: HttpRequest) -> Result<impl Responder, StreamError> {
-    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let schema = STREAM_INFO.schema(&stream_name)?;
-    Ok((web::Json(schema), StatusCode::OK))
+    let archive = CONFIG
+        .storage()
+        .get_object_store()
+        .get_object_store_format(&stream_name)
+        .await?
+        .archive;
+    Ok((web::Json(archive), StatusCode::OK))
 }
 
-pub async fn get_alert(req: HttpRequest) -> Result<impl Responder, StreamError> {
+// a null body disables archival for the stream
+pub async fn put_archive(
+    req: HttpRequest,
+    body: web::Json<Option<Archive>>,
+) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let archive = body.into_inner();
 
-    let alerts = metadata::STREAM_INFO
-        .read()
-        .expect(metadata::LOCK_EXPECT)
-        .get(&stream_name)
-        .map(|metadata| {
-            serde_json::to_value(&metadata.alerts).expect("alerts can serialize to valid json")
-        });
-
-    let mut alerts = match alerts {
-        Some(alerts) => alerts,
-        None => {
-            let alerts = CONFIG
-                .storage()
-                .get_object_store()
-                .get_alerts(&stream_name)
-                .await?;
-
-            if alerts.alerts.is_empty() {
-                return Err(StreamError::NoAlertsSet);
-            }
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
 
-            serde_json::to_value(alerts).expect("alerts can serialize to valid json")
-        }
-    };
+    if let Some(archive) = &archive {
+        archive.validate()?;
+    }
 
-    remove_id_from_alerts(&mut alerts);
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.archive = archive;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
 
-    Ok((web::Json(alerts), StatusCode::OK))
+    Ok((
+        format!("set archive destination for log stream {stream_name}"),
+        StatusCode::OK,
+    ))
 }
 
-pub async fn put_stream(req: HttpRequest, body: Bytes) -> Result<impl Responder, StreamError> {
-    let time_partition = if let Some((_, time_partition_name)) = req
-        .headers()
-        .iter()
-        .find(|&(key, _)| key == TIME_PARTITION_KEY)
-    {
-        time_partition_name.to_str().unwrap()
-    } else {
-        ""
-    };
-    let static_schema_flag = if let Some((_, static_schema_flag)) = req
-        .headers()
-        .iter()
-        .find(|&(key, _)| key == STATIC_SCHEMA_FLAG)
-    {
-        static_schema_flag.to_str().unwrap()
-    } else {
-        ""
-    };
-
+pub async fn get_iceberg_export(req: HttpRequest) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let mut schema = Arc::new(Schema::empty());
-    if metadata::STREAM_INFO.stream_exists(&stream_name) {
-        // Error if the log stream already exists
-        return Err(StreamError::Custom {
-            msg: format!(
-                "logstream {stream_name} already exists, please create a new log stream with unique name"
-            ),
-            status: StatusCode::BAD_REQUEST,
-        });
-    }
 
-    if !body.is_empty() && static_schema_flag == "true" {
-        let static_schema: StaticSchema = serde_json::from_slice(&body).unwrap();
-        let parsed_schema = convert_static_schema_to_arrow_schema(static_schema);
-        if let Ok(parsed_schema) = parsed_schema {
-            schema = parsed_schema;
-        } else {
-            return Err(StreamError::Custom {
-                msg: format!("unable to commit static schema, logstream {stream_name} not created"),
-                status: StatusCode::BAD_REQUEST,
-            });
-        }
-    } else if body.is_empty() && static_schema_flag == "true" {
-        return Err(StreamError::Custom {
-                    msg: format!(
-                        "please provide schema in the request body for static schema logstream {stream_name}"
-                    ),
-                    status: StatusCode::BAD_REQUEST,
-                });
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
     }
 
-    create_stream(stream_name, time_partition, static_schema_flag, schema).await?;
-
-    Ok(("log stream created", StatusCode::OK))
+    let enabled = CONFIG
+        .storage()
+        .get_object_store()
+        .get_object_store_format(&stream_name)
+        .await?
+        .iceberg_export;
+    let export = IcebergExport {
+        enabled,
+        location: enabled.then(|| iceberg::table_location(&stream_name)),
+    };
+    Ok((web::Json(export), StatusCode::OK))
 }
 
-pub async fn put_alert(
+// files already exported are left in place when the export is disabled
+pub async fn put_iceberg_export(
     req: HttpRequest,
-    body: web::Json<serde_json::Value>,
+    body: web::Json<IcebergExport>,
 ) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let enabled = body.into_inner().enabled;
 
-    let mut body = body.into_inner();
-    remove_id_from_alerts(&mut body);
-
-    let alerts: Alerts = match serde_json::from_value(body) {
-        Ok(alerts) => alerts,
-        Err(err) => {
-            return Err(StreamError::BadAlertJson {
-                stream: stream_name,
-                err,
-            })
-        }
-    };
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
 
-    validator::alert(&alerts)?;
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.iceberg_export = enabled;
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
+        .await?;
 
-    if !STREAM_INFO.stream_initialized(&stream_name)? {
-        return Err(StreamError::UninitializedLogstream);
+    if !enabled {
+        return Ok((
+            format!("disabled iceberg export for log stream {stream_name}"),
+            StatusCode::OK,
+        ));
     }
 
-    let schema = STREAM_INFO.schema(&stream_name)?;
-    for alert in &alerts.alerts {
-        for column in alert.message.extract_column_names() {
-            let is_valid = alert.message.valid(&schema, column);
-            if !is_valid {
-                return Err(StreamError::InvalidAlertMessage(
-                    alert.name.to_owned(),
-                    column.to_string(),
-                ));
-            }
-            if !alert.rule.valid_for_schema(&schema) {
-                return Err(StreamError::InvalidAlert(alert.name.to_owned()));
-            }
+    // the table is readable right away instead of after the next scheduled export
+    let stream = stream_name.clone();
+    tokio::spawn(async move {
+        if let Err(err) = iceberg::export_stream(&stream).await {
+            log::warn!("failed to export stream {stream} as an iceberg table: {err}");
         }
+    });
+    Ok((
+        format!(
+            "enabled iceberg export for log stream {stream_name} at {}",
+            iceberg::table_location(&stream_name)
+        ),
+        StatusCode::OK,
+    ))
+}
+
+pub async fn put_legal_hold(
+    req: HttpRequest,
+    body: Option<web::Json<LegalHoldRequest>>,
+) -> Result<impl Responder, StreamError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let reason = body.and_then(|body| body.into_inner().reason);
+
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
     }
 
-    CONFIG
-        .storage()
-        .get_object_store()
-        .put_alerts(&stream_name, &alerts)
+    let username = request_username(&req);
+    let legal_hold = LegalHold {
+        reason,
+        placed_by: username.clone(),
+        placed_at: Utc::now(),
+    };
+
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    stream_metadata.legal_hold = Some(legal_hold.clone());
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
         .await?;
+    STREAM_INFO.set_legal_hold(&stream_name, Some(legal_hold.clone()))?;
 
-    metadata::STREAM_INFO
-        .set_alert(&stream_name, alerts)
-        .expect("alerts set on existing stream");
+    log::info!(
+        target: "audit",
+        "legal hold placed on log stream {stream_name} by {username}, reason: {:?}",
+        legal_hold.reason
+    );
 
     Ok((
-        format!("set alert configuration for log stream {stream_name}"),
+        format!("legal hold placed on log stream {stream_name}"),
         StatusCode::OK,
     ))
 }
 
-pub async fn get_retention(req: HttpRequest) -> Result<impl Responder, StreamError> {
+pub async fn delete_legal_hold(req: HttpRequest) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let objectstore = CONFIG.storage().get_object_store();
 
-    if !objectstore.stream_exists(&stream_name).await? {
-        return Err(StreamError::StreamNotFound(stream_name.to_string()));
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
     }
 
-    let retention = CONFIG
-        .storage()
-        .get_object_store()
-        .get_retention(&stream_name)
+    let storage = CONFIG.storage().get_object_store();
+    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
+    let Some(legal_hold) = stream_metadata.legal_hold.take() else {
+        return Err(StreamError::Custom {
+            msg: format!("log stream {stream_name} is not under legal hold"),
+            status: StatusCode::NOT_FOUND,
+        });
+    };
+    storage
+        .put_stream_manifest(&stream_name, &stream_metadata)
         .await?;
+    STREAM_INFO.set_legal_hold(&stream_name, None)?;
+
+    log::info!(
+        target: "audit",
+        "legal hold on log stream {stream_name} lifted by {}, placed by {} at {}",
+        request_username(&req),
+        legal_hold.placed_by,
+        legal_hold.placed_at
+    );
 
-    Ok((web::Json(retention), StatusCode::OK))
+    Ok((
+        format!("legal hold lifted from log stream {stream_name}"),
+        StatusCode::OK,
+    ))
 }
 
-pub async fn put_retention(
+pub async fn purge(
     req: HttpRequest,
-    body: web::Json<serde_json::Value>,
+    body: web::Json<PurgeRequest>,
 ) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let body = body.into_inner();
+    let predicate = body.into_inner().predicate;
 
-    let retention: Retention = match serde_json::from_value(body) {
-        Ok(retention) => retention,
-        Err(err) => return Err(StreamError::InvalidRetentionConfig(err)),
-    };
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
+    }
 
-    CONFIG
+    if STREAM_INFO.legal_hold(&stream_name)?.is_some() {
+        return Err(StreamError::LegalHold(stream_name));
+    }
+
+    // the purged files are replaced in a snapshot of the node which committed them
+    if CONFIG.parseable.mode == Mode::Query {
+        return Err(StreamError::Custom {
+            msg: "Query server does not commit the files of ingesters, purge the log stream on the ingest servers instead"
+                .to_string(),
+            status: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    purge::validate_predicate(&predicate)?;
+
+    // data still in staging is not purged, it is picked up by the next purge once uploaded
+    let manifest_paths = CONFIG
         .storage()
         .get_object_store()
-        .put_retention(&stream_name, &retention)
-        .await?;
+        .get_object_store_format(&stream_name)
+        .await?
+        .snapshot
+        .manifest_list
+        .into_iter()
+        .map(|item| item.manifest_path)
+        .collect();
+
+    let id = purge::start_purge(&stream_name, predicate.clone(), manifest_paths)?;
+    log::info!(
+        target: "audit",
+        "purge {id} of log stream {stream_name} started by {} with predicate {predicate}",
+        request_username(&req)
+    );
 
     Ok((
-        format!("set retention configuration for log stream {stream_name}"),
-        StatusCode::OK,
+        web::Json(serde_json::json!({ "id": id })),
+        StatusCode::ACCEPTED,
     ))
 }
 
-pub async fn get_cache_enabled(req: HttpRequest) -> Result<impl Responder, StreamError> {
+pub async fn get_purge_status(req: HttpRequest) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let cache_enabled = STREAM_INFO.cache_enabled(&stream_name)?;
-    Ok((web::Json(cache_enabled), StatusCode::OK))
+    let id = req.match_info().get("id").unwrap();
+
+    let status = Ulid::from_string(id)
+        .ok()
+        .and_then(purge::purge_status)
+        .filter(|status| status.stream == stream_name)
+        .ok_or_else(|| StreamError::Custom {
+            msg: format!("purge {id} not found for log stream {stream_name}"),
+            status: StatusCode::NOT_FOUND,
+        })?;
+
+    Ok((web::Json(status), StatusCode::OK))
 }
 
-pub async fn put_enable_cache(
+pub async fn check_consistency(
     req: HttpRequest,
-    body: web::Json<bool>,
+    query: web::Query<ConsistencyQuery>,
 ) -> Result<impl Responder, StreamError> {
-    let enable_cache = body.into_inner();
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
-    let storage = CONFIG.storage().get_object_store();
 
-    if CONFIG.parseable.local_cache_path.is_none() {
-        return Err(StreamError::CacheNotEnabled(stream_name));
+    if !metadata::STREAM_INFO.stream_exists(&stream_name) {
+        return Err(StreamError::StreamNotFound(stream_name));
     }
 
-    let mut stream_metadata = storage.get_stream_metadata(&stream_name).await?;
-    stream_metadata.cache_enabled = enable_cache;
-    storage
-        .put_stream_manifest(&stream_name, &stream_metadata)
-        .await?;
+    let report =
+        consistency::check_stream(&stream_name, query.verify_checksums, query.repair).await?;
+    if report.repaired {
+        log::info!(
+            target: "audit",
+            "metadata of log stream {stream_name} repaired by {}: {report:?}",
+            request_username(&req)
+        );
+    }
 
-    STREAM_INFO.set_stream_cache(&stream_name, enable_cache)?;
-    Ok((
-        format!("Cache set to {enable_cache} for log stream {stream_name}"),
-        StatusCode::OK,
-    ))
+    Ok((web::Json(report), StatusCode::OK))
 }
 
+/// Ingestion and storage stats of a log stream
+#[utoipa::path(
+    get,
+    path = "/api/v1/logstream/{logstream}/stats",
+    tag = "logstream",
+    params(("logstream" = String, Path, description = "Name of the log stream")),
+    responses(
+        (status = 200, description = "Stats of the log stream", body = QueriedStats),
+        (status = 404, description = "Log stream does not exist", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn get_stats(req: HttpRequest) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
 
@@ -371,6 +1715,23 @@ pub fn first_event_at_empty(stream_name: &str) -> bool {
     true
 }
 
+// alerts silenced at this time list the silences
+fn add_silences_to_alerts(stream_name: &str, value: &mut Value) {
+    if let Some(Value::Array(alerts)) = value.get_mut("alerts") {
+        for alert in alerts.iter_mut().filter_map(Value::as_object_mut) {
+            let Some(name) = alert.get("name").and_then(Value::as_str) else {
+                continue;
+            };
+            let silenced_by: Vec<Value> = silence::silenced_by(stream_name, name)
+                .into_iter()
+                .map(|id| Value::String(id.to_string()))
+                .collect();
+            alert.insert("silenced".to_owned(), Value::Bool(!silenced_by.is_empty()));
+            alert.insert("silencedBy".to_owned(), Value::Array(silenced_by));
+        }
+    }
+}
+
 fn remove_id_from_alerts(value: &mut Value) {
     if let Some(Value::Array(alerts)) = value.get_mut("alerts") {
         alerts
@@ -385,6 +1746,7 @@ fn remove_id_from_alerts(value: &mut Value) {
 pub async fn create_stream(
     stream_name: String,
     time_partition: &str,
+    custom_partition: &str,
     static_schema_flag: &str,
     schema: Arc<Schema>,
 ) -> Result<(), CreateStreamError> {
@@ -397,6 +1759,7 @@ pub async fn create_stream(
         .create_stream(
             &stream_name,
             time_partition,
+            custom_partition,
             static_schema_flag,
             schema.clone(),
         )
@@ -426,13 +1789,31 @@ pub async fn create_stream(
         stream_name.to_string(),
         created_at,
         time_partition.to_string(),
+        custom_partition.to_string(),
         static_schema_flag.to_string(),
         static_schema,
     );
+    // ingesters create their copy of streams made through the query server
+    if CONFIG.parseable.mode != Mode::Ingest {
+        webhooks::notify(LifecycleEvent::StreamCreated {
+            stream: stream_name,
+        });
+    }
 
     Ok(())
 }
 
+/// Settings and first event of a log stream
+#[utoipa::path(
+    get,
+    path = "/api/v1/logstream/{logstream}/info",
+    tag = "logstream",
+    params(("logstream" = String, Path, description = "Name of the log stream")),
+    responses(
+        (status = 200, description = "Info of the log stream", body = StreamInfo),
+        (status = 404, description = "Log stream does not exist", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn get_stream_info(req: HttpRequest) -> Result<impl Responder, StreamError> {
     let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
     if !metadata::STREAM_INFO.stream_exists(&stream_name) {
@@ -462,8 +1843,18 @@ pub async fn get_stream_info(req: HttpRequest) -> Result<impl Responder, StreamE
         created_at: stream_meta.created_at.clone(),
         first_event_at: stream_meta.first_event_at.clone(),
         time_partition: stream_meta.time_partition.clone(),
+        custom_partition: stream_meta.custom_partition.clone(),
         cache_enabled: stream_meta.cache_enabled,
         static_schema_flag: stream_meta.static_schema_flag.clone(),
+        flush_interval: stream_meta.flush_interval,
+        sort_keys: stream_meta.sort_keys.clone(),
+        legal_hold: stream_meta.legal_hold.clone(),
+        field_mapping: stream_meta.field_mapping,
+        allowed_tags: stream_meta.allowed_tags.clone(),
+        max_columns: stream_meta.max_columns,
+        schema_compatibility: stream_meta.schema_compatibility,
+        renamed_columns: stream_meta.renamed_columns.clone(),
+        default_filter: stream_meta.default_filter.clone(),
     };
 
     // get the other info from
@@ -483,11 +1874,15 @@ fn classify_json_error(kind: serde_json::error::Category) -> StatusCode {
 
 pub mod error {
 
-    use actix_web::http::header::ContentType;
     use http::StatusCode;
 
     use crate::{
+        handlers::http::cluster::node_client::NodeRequestError,
+        handlers::http::problem::Problem,
         metadata::error::stream_info::MetadataError,
+        storage::archive::ArchiveError,
+        storage::consistency::ConsistencyError,
+        storage::purge::PurgeError,
         storage::ObjectStorageError,
         validator::error::{AlertValidationError, StreamNameValidationError},
     };
@@ -543,6 +1938,16 @@ pub mod error {
         Anyhow(#[from] anyhow::Error),
         #[error("Network Error: {0}")]
         Network(#[from] reqwest::Error),
+        #[error("Network Error: {0}")]
+        Node(#[from] NodeRequestError),
+        #[error("{0}")]
+        Archive(#[from] ArchiveError),
+        #[error("{0}")]
+        Purge(#[from] PurgeError),
+        #[error("Consistency check failed: {0}")]
+        Consistency(#[from] ConsistencyError),
+        #[error("Log stream {0} is under legal hold, the hold has to be lifted by an admin first")]
+        LegalHold(String),
         #[error("Could not deserialize into JSON object, {0}")]
         SerdeError(#[from] serde_json::Error),
     }
@@ -572,13 +1977,64 @@ pub mod error {
                 StreamError::Network(err) => {
                     err.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
                 }
+                StreamError::Node(err) => err.status_code(),
+                StreamError::Archive(ArchiveError::InvalidDestination(_)) => {
+                    StatusCode::BAD_REQUEST
+                }
+                StreamError::Archive(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                StreamError::LegalHold(_) => StatusCode::FORBIDDEN,
+                StreamError::Purge(PurgeError::InvalidPredicate(_)) => StatusCode::BAD_REQUEST,
+                StreamError::Purge(PurgeError::AlreadyRunning(_)) => StatusCode::CONFLICT,
+                StreamError::Purge(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                StreamError::Consistency(_) => StatusCode::INTERNAL_SERVER_ERROR,
             }
         }
 
         fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-            actix_web::HttpResponse::build(self.status_code())
-                .insert_header(ContentType::plaintext())
-                .body(self.to_string())
+            Problem::new(self.status_code(), self)
+                .code(self.error_code())
+                .retriable_if(matches!(
+                    self,
+                    StreamError::CreateStream(CreateStreamError::Storage { .. })
+                        | StreamError::Storage(_)
+                        | StreamError::Network(_)
+                ))
+                .response()
+        }
+    }
+
+    impl StreamError {
+        fn error_code(&self) -> &'static str {
+            match self {
+                StreamError::CreateStream(CreateStreamError::StreamNameValidation(_)) => {
+                    "invalid_stream_name"
+                }
+                StreamError::CreateStream(CreateStreamError::Storage { .. }) => "storage_error",
+                StreamError::StreamNotFound(_) => "stream_not_found",
+                StreamError::CacheNotEnabled(_) => "cache_not_enabled",
+                StreamError::UninitializedLogstream => "stream_not_initialized",
+                StreamError::Storage(_) => "storage_error",
+                StreamError::NoAlertsSet => "no_alerts",
+                StreamError::BadAlertJson { .. } => "invalid_alert_json",
+                StreamError::AlertValidation(_) => "invalid_alert",
+                StreamError::InvalidAlert(_) => "invalid_alert",
+                StreamError::InvalidAlertMessage(_, _) => "invalid_alert_message",
+                StreamError::InvalidRetentionConfig(_) => "invalid_retention",
+                StreamError::Custom { .. } => "stream_error",
+                StreamError::Anyhow(_) => "internal_error",
+                StreamError::Network(_) => "network_error",
+                StreamError::Node(_) => "node_error",
+                StreamError::Archive(ArchiveError::InvalidDestination(_)) => {
+                    "invalid_archive_destination"
+                }
+                StreamError::Archive(_) => "archive_error",
+                StreamError::Purge(PurgeError::InvalidPredicate(_)) => "invalid_purge_predicate",
+                StreamError::Purge(PurgeError::AlreadyRunning(_)) => "purge_running",
+                StreamError::Purge(_) => "purge_error",
+                StreamError::Consistency(_) => "consistency_error",
+                StreamError::LegalHold(_) => "legal_hold",
+                StreamError::SerdeError(_) => "invalid_json",
+            }
         }
     }
 
@@ -597,10 +2053,16 @@ pub mod error {
 
 #[cfg(test)]
 mod tests {
-    use crate::handlers::http::logstream::error::StreamError;
-    use crate::handlers::http::logstream::get_stats;
+    use crate::handlers::http::logstream::error::{CreateStreamError, StreamError};
+    use crate::handlers::http::logstream::{
+        delete, delete_staging, drop_local_stream, get_stats, purge, PurgeRequest,
+    };
+    use crate::metadata::{LogStreamMetadata, STREAM_INFO};
+    use crate::storage::LegalHold;
     use actix_web::test::TestRequest;
+    use actix_web::web;
     use anyhow::bail;
+    use chrono::Utc;
 
     #[actix_web::test]
     #[should_panic]
@@ -620,4 +2082,71 @@ mod tests {
             _ => bail!("expected StreamNotFound error"),
         }
     }
+
+    #[actix_web::test]
+    async fn legal_hold_blocks_deletion() -> anyhow::Result<()> {
+        let stream_name = "deletionlegalhold";
+        STREAM_INFO
+            .write()
+            .unwrap()
+            .insert(stream_name.to_string(), LogStreamMetadata::default());
+        let hold = LegalHold {
+            reason: Some("litigation".to_string()),
+            placed_by: "admin".to_string(),
+            placed_at: Utc::now(),
+        };
+        STREAM_INFO.set_legal_hold(stream_name, Some(hold))?;
+        let req = || {
+            TestRequest::default()
+                .param("logstream", stream_name)
+                .to_http_request()
+        };
+
+        let deleted = delete(req()).await;
+        let staging_dropped = delete_staging(req()).await;
+        let purged = purge(
+            req(),
+            web::Json(PurgeRequest {
+                predicate: "level = 'debug'".to_string(),
+            }),
+        )
+        .await;
+
+        assert!(STREAM_INFO.stream_exists(stream_name));
+        STREAM_INFO.delete_stream(stream_name);
+        for res in [deleted.err(), staging_dropped.err(), purged.err()] {
+            match res {
+                Some(StreamError::LegalHold(_)) => {}
+                _ => bail!("expected LegalHold error"),
+            }
+        }
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn staging_is_only_dropped_for_known_streams() {
+        let req = |stream_name: &str| {
+            TestRequest::default()
+                .param("logstream", stream_name.to_owned())
+                .to_http_request()
+        };
+
+        assert!(matches!(
+            delete_staging(req("../staging")).await,
+            Err(StreamError::CreateStream(
+                CreateStreamError::StreamNameValidation(_)
+            ))
+        ));
+        assert!(delete_staging(req("neverloaded")).await.is_ok());
+        assert!(matches!(
+            drop_local_stream("neverloaded").await,
+            Err(StreamError::StreamNotFound(_))
+        ));
+        assert!(matches!(
+            drop_local_stream("").await,
+            Err(StreamError::CreateStream(
+                CreateStreamError::StreamNameValidation(_)
+            ))
+        ));
+    }
 }
@@ -18,27 +18,41 @@
 */
 
 use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    error::{ErrorBadRequest, ErrorForbidden, ErrorUnauthorized},
-    http::header::{self, HeaderName},
+    error::{ErrorBadRequest, ErrorForbidden, ErrorUnauthorized, InternalError},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        Method, StatusCode,
+    },
+    middleware::Logger,
+    web::Bytes,
     Error, Route,
 };
 use futures_util::future::LocalBoxFuture;
+use ulid::Ulid;
 
 use crate::{
     handlers::{
+        http::{
+            base_path, metrics_path,
+            problem::{is_problem, Problem},
+        },
         AUTHORIZATION_KEY, KINESIS_COMMON_ATTRIBUTES_KEY, LOG_SOURCE_KEY, LOG_SOURCE_KINESIS,
-        STREAM_NAME_HEADER_KEY,
+        REQUEST_ID_KEY, STREAM_NAME_HEADER_KEY,
     },
     option::Mode,
+    shutdown::{InFlight, RequestKind},
 };
 use crate::{
     option::CONFIG,
     rbac::Users,
     rbac::{self, role::Action},
-    utils::actix::extract_session_key,
+    utils::{actix::extract_session_key, constant_time_eq},
 };
 
 use serde::{Deserialize, Serialize};
@@ -203,6 +217,265 @@ pub fn auth_user_context(
     creds.map(|key| Users.authorize(key, action, None, user))
 }
 
+// With P_METRICS_AUTH set, requests to the metrics endpoint need either the scrape token set
+// in P_METRICS_TOKEN or credentials of a user allowed to read metrics. The endpoint is open
+// otherwise, as scrapers set up for earlier versions send no credentials. Has to be wrapped
+// around the prometheus middleware, which answers these requests without calling the inner
+// services.
+pub struct MetricsAuth {
+    enforce: bool,
+    token: Option<String>,
+    path: String,
+}
+
+impl MetricsAuth {
+    pub fn from_config() -> Self {
+        Self {
+            enforce: CONFIG.parseable.metrics_auth,
+            token: CONFIG.parseable.metrics_token.clone(),
+            path: metrics_path(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for MetricsAuth
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = MetricsAuthMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(MetricsAuthMiddleware {
+            service,
+            enforce: self.enforce,
+            token: self.token.clone(),
+            path: self.path.clone(),
+        }))
+    }
+}
+
+pub struct MetricsAuthMiddleware<S> {
+    service: S,
+    enforce: bool,
+    token: Option<String>,
+    path: String,
+}
+
+impl<S, B> Service<ServiceRequest> for MetricsAuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        if !self.enforce || req.path() != self.path {
+            return Box::pin(self.service.call(req));
+        }
+
+        let auth_result = if has_metrics_token(&req, self.token.as_deref()) {
+            Ok(rbac::Response::Authorized)
+        } else {
+            auth_no_context(&mut req, Action::Metrics)
+        };
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            match auth_result? {
+                rbac::Response::UnAuthorized => return Err(
+                    ErrorForbidden("You don't have permission to access this resource. Please contact your administrator for assistance.")
+                ),
+                rbac::Response::ReloadRequired => return Err(
+                    ErrorUnauthorized("Your session has expired or is no longer valid. Please re-authenticate to access this resource.")
+                ),
+                _ => {}
+            }
+            fut.await
+        })
+    }
+}
+
+fn has_metrics_token(req: &ServiceRequest, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|bearer| constant_time_eq(bearer.as_bytes(), token.as_bytes()))
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Id of the request being handled by the current task, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+// ids sent by clients are used if they are short and printable
+fn valid_request_id(id: &str) -> bool {
+    (1..=128).contains(&id.len()) && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+fn request_kind(req: &ServiceRequest) -> RequestKind {
+    let base_path = base_path();
+    let mut segments = req
+        .path()
+        .strip_prefix(base_path.as_str())
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty());
+    match (segments.next(), segments.next(), segments.next()) {
+        (Some("ingest"), ..) => RequestKind::Ingest,
+        // POST "/logstream/{logstream}" ingests into the stream
+        (Some("logstream"), Some(_), None) if req.method() == Method::POST => RequestKind::Ingest,
+        (Some("query"), ..) => RequestKind::Query,
+        (Some("cluster"), ..) => RequestKind::Cluster,
+        _ => RequestKind::Other,
+    }
+}
+
+/// Access log of the requests, the default format of actix with the id of the request
+pub fn request_logger() -> Logger {
+    Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{x-request-id}o"#)
+}
+
+// Assigns every request an id, the one in the X-Request-Id header if the client sent one.
+// The id is returned in the X-Request-Id header of the response, including error responses, and
+// added to what is logged while the request is handled. Requests are counted as in flight till
+// the body of their response is sent, for the drain on shutdown.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<InFlightBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<InFlightBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_KEY)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| valid_request_id(id))
+            .map(ToOwned::to_owned)
+            .unwrap_or_else(|| Ulid::new().to_string());
+        let in_flight = InFlight::start(request_kind(&req));
+        // the request itself can not be held on to, routing needs it to be unique
+        let method = req.method().clone();
+        let path = req.path().to_owned();
+
+        let fut = REQUEST_ID.sync_scope(id.clone(), || self.service.call(req));
+        Box::pin(REQUEST_ID.scope(id.clone(), async move {
+            let header = HeaderValue::from_str(&id).ok();
+            let mut res = match fut.await {
+                Ok(res) => match res.response().error() {
+                    // errors not sent as problems yet, e.g. of extractors
+                    Some(err) if !is_problem(res.response()) => {
+                        log_server_error(&method, &path, res.status(), err);
+                        let problem = Problem::new(res.status(), err).response();
+                        res.into_response(problem).map_into_right_body()
+                    }
+                    Some(err) => {
+                        log_server_error(&method, &path, res.status(), err);
+                        res.map_into_left_body()
+                    }
+                    None => res.map_into_left_body(),
+                },
+                // errors of inner middlewares, e.g. of authorization, are sent as the problem
+                // response built here so that the id can be set on it
+                Err(err) => {
+                    let status = err.as_response_error().status_code();
+                    log_server_error(&method, &path, status, &err);
+                    let mut problem = Problem::new(status, &err).response();
+                    if let Some(value) = header {
+                        problem
+                            .headers_mut()
+                            .insert(HeaderName::from_static(REQUEST_ID_KEY), value);
+                    }
+                    return Err(InternalError::from_response(err, problem).into());
+                }
+            };
+            if let Some(value) = header {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_KEY), value);
+            }
+            // streamed responses are still in flight till their body is sent
+            Ok(res.map_body(|_, body| InFlightBody {
+                body: body.boxed(),
+                _in_flight: in_flight,
+            }))
+        }))
+    }
+}
+
+/// Body of a response which keeps its request counted as in flight till it is sent or dropped
+pub struct InFlightBody {
+    body: BoxBody,
+    _in_flight: InFlight,
+}
+
+impl MessageBody for InFlightBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+fn log_server_error(method: &Method, path: &str, status: StatusCode, err: &Error) {
+    if status.is_server_error() {
+        log::warn!("{method} {path} failed with {status}: {err}");
+    }
+}
+
 // The credentials set in the env vars (P_USERNAME & P_PASSWORD) are treated
 // as root credentials. Any other user is not allowed to modify or delete
 // the root user. Deny request if username is same as username
@@ -358,3 +631,128 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::Service;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use futures_util::StreamExt;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::{MetricsAuth, RequestId, REQUEST_ID_KEY};
+    use crate::shutdown::{in_flight, RequestKind};
+
+    fn metrics_auth(enforce: bool) -> MetricsAuth {
+        MetricsAuth {
+            enforce,
+            token: Some("scrape-secret".to_owned()),
+            path: "/metrics".to_owned(),
+        }
+    }
+
+    async fn status(auth: MetricsAuth, req: TestRequest) -> StatusCode {
+        let app = init_service(
+            App::new()
+                .wrap(auth)
+                .route("/metrics", web::get().to(HttpResponse::Ok))
+                .route("/about", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        match app.call(req.to_request()).await {
+            Ok(res) => res.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn metrics_are_open_unless_auth_is_enabled() {
+        let req = TestRequest::get().uri("/metrics");
+        assert_eq!(status(metrics_auth(false), req).await, StatusCode::OK);
+
+        let req = TestRequest::get().uri("/metrics");
+        assert_eq!(
+            status(metrics_auth(true), req).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn scrape_token_grants_access_to_metrics_only() {
+        let bearer = || (header::AUTHORIZATION, "Bearer scrape-secret");
+
+        let req = TestRequest::get().uri("/metrics").insert_header(bearer());
+        assert_eq!(status(metrics_auth(true), req).await, StatusCode::OK);
+
+        // other paths are left to their own authorization
+        let req = TestRequest::get().uri("/about");
+        assert_eq!(status(metrics_auth(true), req).await, StatusCode::OK);
+
+        let mut auth = metrics_auth(true);
+        auth.token = None;
+        let req = TestRequest::get().uri("/metrics").insert_header(bearer());
+        assert_ne!(status(auth, req).await, StatusCode::OK);
+    }
+
+    fn queries_in_flight() -> usize {
+        in_flight()
+            .into_iter()
+            .find(|(kind, _)| *kind == RequestKind::Query)
+            .map_or(0, |(_, count)| count)
+    }
+
+    #[actix_web::test]
+    async fn request_is_in_flight_till_its_body_is_sent() {
+        let (tx, rx) = mpsc::unbounded_channel::<web::Bytes>();
+        let rx = std::sync::Arc::new(std::sync::Mutex::new(Some(rx)));
+        let app = init_service(
+            App::new()
+                .wrap(RequestId)
+                .route(
+                    "/api/v1/query",
+                    web::get().to(move || {
+                        let rx = rx.lock().unwrap().take().unwrap();
+                        async move {
+                            HttpResponse::Ok().streaming(
+                                UnboundedReceiverStream::new(rx).map(Ok::<_, actix_web::Error>),
+                            )
+                        }
+                    }),
+                )
+                .route(
+                    "/api/v1/query/pending",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().streaming(futures_util::stream::pending::<
+                            Result<web::Bytes, actix_web::Error>,
+                        >())
+                    }),
+                ),
+        )
+        .await;
+
+        let res = app
+            .call(TestRequest::get().uri("/api/v1/query").to_request())
+            .await
+            .unwrap();
+        // the handler returned, its response is still being streamed
+        assert!(res.headers().contains_key(REQUEST_ID_KEY));
+        assert_eq!(queries_in_flight(), 1);
+
+        tx.send(web::Bytes::from_static(b"rows")).unwrap();
+        drop(tx);
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(body, "rows");
+        assert_eq!(queries_in_flight(), 0);
+
+        // a response dropped before its body is sent, e.g. as the client went away
+        let res = app
+            .call(TestRequest::get().uri("/api/v1/query/pending").to_request())
+            .await
+            .unwrap();
+        assert_eq!(queries_in_flight(), 1);
+        drop(res);
+        assert_eq!(queries_in_flight(), 0);
+    }
+}
@@ -19,23 +19,28 @@
 use crate::analytics;
 use crate::banner;
 use crate::handlers::http::logstream;
-use crate::handlers::http::middleware::RouteExt;
+use crate::handlers::http::middleware::{request_logger, MetricsAuth, RequestId, RouteExt};
 use crate::handlers::http::MAX_EVENT_PAYLOAD_SIZE;
 use crate::localcache::LocalCacheManager;
 use crate::metadata;
+use crate::metering;
 use crate::metrics;
+use crate::migration;
+use crate::monitor;
 use crate::rbac;
 use crate::rbac::role::Action;
+use crate::replication;
+use crate::shutdown;
 use crate::storage;
-use crate::storage::object_storage::ingester_metadata_path;
 use crate::storage::object_storage::parseable_json_path;
+use crate::storage::object_storage::{ingester_metadata_path, legacy_ingester_id};
 use crate::storage::ObjectStorageError;
 use crate::sync;
+use crate::webhooks::{self, LifecycleEvent};
 
 use super::server::Server;
 use super::ssl_acceptor::get_ssl_acceptor;
 use super::IngesterMetadata;
-use super::OpenIdClient;
 use super::ParseableServer;
 use super::DEFAULT_VERSION;
 
@@ -45,8 +50,13 @@ use actix_web::{web, App, HttpServer};
 use actix_web_prometheus::PrometheusMetrics;
 use async_trait::async_trait;
 use base64::Engine;
+use chrono::Utc;
+use clokwerk::{AsyncScheduler, TimeUnits};
 use itertools::Itertools;
+use once_cell::sync::OnceCell;
 use relative_path::RelativePathBuf;
+use std::time::Duration;
+use ulid::Ulid;
 use url::Url;
 
 use crate::{
@@ -54,9 +64,66 @@ use crate::{
     option::CONFIG,
 };
 
+/// File in staging holding the id of the ingester
+const INGESTER_ID_FILE: &str = ".ingester_id";
+const HEARTBEAT_INTERVAL_MINUTES: u32 = 1;
+
+static INGESTER_ID: OnceCell<String> = OnceCell::new();
+// metadata registered on start, written again with every heartbeat
+static INGESTER_METADATA: OnceCell<IngesterMetadata> = OnceCell::new();
+
 #[derive(Default)]
 pub struct IngestServer;
 
+// the id is generated on the first start and kept in staging,
+// so the ingester keeps its identity when it restarts with another address
+pub fn get_ingester_id() -> std::io::Result<String> {
+    INGESTER_ID
+        .get_or_try_init(|| load_ingester_id(CONFIG.staging_dir()))
+        .cloned()
+}
+
+fn load_ingester_id(staging_dir: &std::path::Path) -> std::io::Result<String> {
+    let path = staging_dir.join(INGESTER_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(id) if !id.trim().is_empty() => Ok(id.trim().to_owned()),
+        Ok(_) => write_ingester_id(staging_dir),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => write_ingester_id(staging_dir),
+        Err(err) => Err(err),
+    }
+}
+
+// an entry removed as stale while this ingester was unreachable is written again
+async fn heartbeat() {
+    // a drained ingester deregisters itself and should stay deregistered
+    if shutdown::is_draining() {
+        return;
+    }
+    let Some(metadata) = INGESTER_METADATA.get() else {
+        return;
+    };
+    let mut metadata = metadata.clone();
+    metadata.last_heartbeat = Some(Utc::now());
+
+    let path = ingester_metadata_path(&metadata.ingester_id);
+    let body = serde_json::to_vec(&metadata).expect("ingester metadata serializes");
+    if let Err(err) = CONFIG
+        .storage()
+        .get_object_store()
+        .put_object(&path, body.into())
+        .await
+    {
+        log::warn!("could not update ingester heartbeat: {err}");
+    }
+}
+
+fn write_ingester_id(staging_dir: &std::path::Path) -> std::io::Result<String> {
+    let id = Ulid::new().to_string();
+    std::fs::create_dir_all(staging_dir)?;
+    std::fs::write(staging_dir.join(INGESTER_ID_FILE), &id)?;
+    Ok(id)
+}
+
 #[async_trait(?Send)]
 impl ParseableServer for IngestServer {
     // we dont need oidc client here its just here to satisfy the trait
@@ -78,23 +145,30 @@ impl ParseableServer for IngestServer {
         let create_app_fn = move || {
             App::new()
                 .wrap(prometheus.clone())
-                .configure(|config| IngestServer::configure_routes(config, None))
-                .wrap(actix_web::middleware::Logger::default())
+                .wrap(MetricsAuth::from_config())
+                .configure(IngestServer::configure_routes)
+                .wrap(RequestId)
+                .wrap(request_logger())
                 .wrap(actix_web::middleware::Compress::default())
                 .wrap(cross_origin_config())
         };
 
         // concurrent workers equal to number of logical cores
-        let http_server = HttpServer::new(create_app_fn).workers(num_cpus::get());
+        // shutdown signals are handled by the drain, which stops the server gracefully
+        let http_server = HttpServer::new(create_app_fn)
+            .workers(num_cpus::get())
+            .disable_signals()
+            .shutdown_timeout(CONFIG.parseable.drain_timeout.as_secs());
 
-        if let Some(config) = ssl {
+        let http_server = if let Some(config) = ssl {
             http_server
-                .bind_rustls(&CONFIG.parseable.address, config)?
+                .bind_rustls_021(&CONFIG.parseable.address, config)?
                 .run()
-                .await?;
         } else {
-            http_server.bind(&CONFIG.parseable.address)?.run().await?;
-        }
+            http_server.bind(&CONFIG.parseable.address)?.run()
+        };
+        shutdown::init_signal_handler(http_server.handle());
+        http_server.await?;
 
         Ok(())
     }
@@ -106,6 +180,16 @@ impl ParseableServer for IngestServer {
         self.check_querier_state().await?;
         // to get the .parseable.json file in staging
         self.validate_credentials().await?;
+        // ingesters do not migrate metadata, but must not run on metadata of a newer server
+        migration::ensure_supported_versions(&CONFIG).await?;
+        // stream files written before this ingester had an id are keyed by its address
+        let sock = Server::get_server_address();
+        migration::run_ingester_file_migration(
+            &*CONFIG.storage().get_object_store(),
+            &legacy_ingester_id(&sock.ip().to_string(), &sock.port().to_string()),
+            &get_ingester_id()?,
+        )
+        .await?;
 
         let metadata = storage::resolve_parseable_metadata().await?;
         banner::print(&CONFIG, &metadata).await;
@@ -130,21 +214,46 @@ impl ParseableServer for IngestServer {
 
 impl IngestServer {
     // configure the api routes
-    fn configure_routes(config: &mut web::ServiceConfig, _oidc_client: Option<OpenIdClient>) {
+    fn configure_routes(config: &mut web::ServiceConfig) {
         config
             .service(
                 // Base path "{url}/api/v1"
                 web::scope(&base_path())
                     .service(Server::get_query_factory())
                     .service(Server::get_ingest_factory())
+                    .service(Server::get_ingest_arrow_factory())
+                    .service(Server::get_ingest_aws_factory())
+                    .service(Server::get_ingest_journald_factory())
                     .service(Self::logstream_api())
                     .service(Server::get_about_factory())
-                    .service(Self::analytics_factory()),
+                    .service(Server::get_reload_factory())
+                    .service(Self::replicas_factory())
+                    .service(Self::analytics_factory())
+                    .configure(Server::configure_profiling),
             )
             .service(Server::get_liveness_factory())
             .service(Server::get_readiness_factory());
     }
 
+    fn replicas_factory() -> Scope {
+        web::scope("/replicas").service(
+            web::resource("/{origin}/{logstream}")
+                // POST "/replicas/{origin}/{logstream}" ==> Keep a copy of events staged on a peer ingester
+                .route(
+                    web::post()
+                        .to(replication::put_replica)
+                        .authorize(Action::ReplicateEvents),
+                )
+                // DELETE "/replicas/{origin}/{logstream}" ==> Drop copies the peer ingester has uploaded
+                .route(
+                    web::delete()
+                        .to(replication::trim_replicas)
+                        .authorize(Action::ReplicateEvents),
+                )
+                .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE)),
+        )
+    }
+
     fn analytics_factory() -> Scope {
         web::scope("/analytics").service(
             // GET "/analytics" ==> Get analytics data
@@ -181,6 +290,78 @@ impl IngestServer {
                             )
                             .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE)),
                     )
+                    .service(
+                        web::resource("/prepare")
+                            // PUT "/logstream/{logstream}/prepare" ==> Validate a log stream the query server is creating
+                            .route(
+                                web::put()
+                                    .to(logstream::prepare_stream)
+                                    .authorize_for_stream(Action::CreateStream),
+                            )
+                            // DELETE "/logstream/{logstream}/prepare" ==> Drop a prepared log stream
+                            .route(
+                                web::delete()
+                                    .to(logstream::abort_stream)
+                                    .authorize_for_stream(Action::CreateStream),
+                            )
+                            .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE)),
+                    )
+                    .service(
+                        // POST "/logstream/{logstream}/commit" ==> Create a prepared log stream
+                        web::resource("/commit").route(
+                            web::post()
+                                .to(logstream::commit_stream)
+                                .authorize_for_stream(Action::CreateStream),
+                        ),
+                    )
+                    .service(
+                        // POST "/logstream/{logstream}/flush" ==> Convert and upload staging data of given log stream
+                        web::resource("/flush").route(
+                            web::post()
+                                .to(logstream::flush)
+                                .authorize_for_stream(Action::FlushStream),
+                        ),
+                    )
+                    .service(
+                        web::resource("/settings")
+                            // GET "/logstream/{logstream}/settings" ==> Get stream settings this ingester acts on
+                            .route(
+                                web::get()
+                                    .to(logstream::get_settings)
+                                    .authorize_for_stream(Action::GetRetention),
+                            )
+                            // PUT "/logstream/{logstream}/settings" ==> Apply stream settings set through the query server
+                            .route(
+                                web::put()
+                                    .to(logstream::put_settings)
+                                    .authorize(Action::PutStreamSettings),
+                            ),
+                    )
+                    .service(
+                        // PUT "/logstream/{logstream}/flush-interval" ==> Set staging flush interval for given logstream
+                        // deprecated, query servers of earlier versions forward the flush interval here instead of to /settings
+                        web::resource("/flush-interval").route(
+                            web::put()
+                                .to(logstream::put_flush_interval)
+                                .authorize_for_stream(Action::PutFlushInterval),
+                        ),
+                    )
+                    .service(
+                        // POST "/logstream/{logstream}/purge" ==> Start deleting rows matching a predicate from the files this ingester committed
+                        web::resource("/purge").route(
+                            web::post()
+                                .to(logstream::purge)
+                                .authorize_for_stream(Action::Purge),
+                        ),
+                    )
+                    .service(
+                        // GET "/logstream/{logstream}/purge/{id}" ==> Get progress of a purge of given logstream
+                        web::resource("/purge/{id}").route(
+                            web::get()
+                                .to(logstream::get_purge_status)
+                                .authorize_for_stream(Action::Purge),
+                        ),
+                    )
                     .service(
                         // GET "/logstream/{logstream}/schema" ==> Get schema for given log stream
                         web::resource("/schema").route(
@@ -189,6 +370,29 @@ impl IngestServer {
                                 .authorize_for_stream(Action::GetSchema),
                         ),
                     )
+                    .service(
+                        // GET "/logstream/{logstream}/schema/versions" ==> Get the versions of the schema of given log stream
+                        web::resource("/schema/versions").route(
+                            web::get()
+                                .to(logstream::get_schema_versions)
+                                .authorize_for_stream(Action::GetSchema),
+                        ),
+                    )
+                    .service(
+                        web::resource("/staging")
+                            // GET "/logstream/{logstream}/staging" ==> Get events of given log stream not converted yet
+                            .route(
+                                web::get()
+                                    .to(logstream::get_staging)
+                                    .authorize_for_stream(Action::Query),
+                            )
+                            // DELETE "/logstream/{logstream}/staging" ==> Drop staging data of a log stream deleted through the query server
+                            .route(
+                                web::delete()
+                                    .to(logstream::delete_staging)
+                                    .authorize_for_stream(Action::DeleteStream),
+                            ),
+                    )
                     .service(
                         // GET "/logstream/{logstream}/stats" ==> Get stats for given log stream
                         web::resource("/stats").route(
@@ -200,20 +404,37 @@ impl IngestServer {
             )
     }
 
-    // create the ingester metadata and put the .ingester.json file in the object store
+    // create or update the ingester metadata in the .ingester.json file in the object store
     async fn set_ingester_metadata(&self) -> anyhow::Result<()> {
         let store = CONFIG.storage().get_object_store();
 
         let sock = Server::get_server_address();
-        let path = ingester_metadata_path(sock.ip().to_string(), sock.port().to_string());
-
-        if store.get_object(&path).await.is_ok() {
-            println!("Ingester metadata already exists");
-            return Ok(());
+        let ingester_id = get_ingester_id()?;
+        let path = ingester_metadata_path(&ingester_id);
+
+        // the metadata written before this ingester had an id is keyed by its address
+        let legacy_path = ingester_metadata_path(&legacy_ingester_id(
+            &sock.ip().to_string(),
+            &sock.port().to_string(),
+        ));
+        let registered = match store.get_object(&legacy_path).await {
+            Ok(_) => {
+                store
+                    .try_delete_ingester_meta(legacy_path.to_string())
+                    .await?;
+                true
+            }
+            Err(_) => false,
         };
 
+        let existing = store
+            .get_object(&path)
+            .await
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<IngesterMetadata>(&bytes).ok());
+
         let scheme = CONFIG.parseable.get_scheme();
-        let resource = IngesterMetadata::new(
+        let mut resource = IngesterMetadata::new(
             sock.port().to_string(),
             CONFIG
                 .parseable
@@ -227,18 +448,74 @@ impl IngestServer {
             store.get_bucket_name(),
             &CONFIG.parseable.username,
             &CONFIG.parseable.password,
+            ingester_id,
         );
 
+        // the address may have changed since the last start, the entry is updated in place
+        resource.last_heartbeat = Some(Utc::now());
+        // nodes sign their requests when the cluster has a secret, credentials are not shared
+        if CONFIG.parseable.cluster_secret.is_some() {
+            resource.token = String::new();
+        }
+        let _ = INGESTER_METADATA.set(resource.clone());
+
+        let domain_name = resource.domain_name.clone();
         let resource = serde_json::to_string(&resource)
             .unwrap()
             .try_into_bytes()
             .unwrap();
 
         store.put_object(&path, resource).await?;
+        if existing.is_none() && !registered {
+            webhooks::notify(LifecycleEvent::IngesterJoined { domain_name });
+        }
 
         Ok(())
     }
 
+    // keep the heartbeat in the ingester metadata current,
+    // the query server flags ingesters without a recent heartbeat as stale
+    fn init_heartbeat_scheduler(&self) {
+        let mut scheduler = AsyncScheduler::new();
+        scheduler
+            .every(HEARTBEAT_INTERVAL_MINUTES.minutes())
+            .run(heartbeat);
+
+        tokio::spawn(async move {
+            loop {
+                scheduler.run_pending().await;
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        });
+    }
+
+    // remove the .ingester.json file so the querier no longer expects this ingester
+    async fn remove_ingester_metadata(&self) {
+        let path = match get_ingester_id() {
+            Ok(ingester_id) => ingester_metadata_path(&ingester_id),
+            Err(err) => {
+                log::error!("could not deregister ingester: {err}");
+                return;
+            }
+        };
+
+        let store = CONFIG.storage().get_object_store();
+        let domain_name = match store.get_object(&path).await {
+            Ok(bytes) => serde_json::from_slice::<IngesterMetadata>(&bytes)
+                .map(|metadata| metadata.domain_name)
+                .unwrap_or_default(),
+            Err(_) => return,
+        };
+
+        match store.try_delete_ingester_meta(path.to_string()).await {
+            Ok(()) => {
+                log::info!("ingester {domain_name} deregistered");
+                webhooks::send(LifecycleEvent::IngesterLeft { domain_name }).await;
+            }
+            Err(err) => log::error!("could not deregister ingester: {err}"),
+        }
+    }
+
     // check for querier state. Is it there, or was it there in the past
     // this should happen before the set the ingester metadata
     async fn check_querier_state(&self) -> anyhow::Result<(), ObjectStorageError> {
@@ -273,9 +550,12 @@ impl IngestServer {
             .map(|x| serde_json::from_slice::<IngesterMetadata>(x).unwrap_or_default())
             .collect_vec();
 
-        if !ingester_metadata.is_empty() {
-            let check = ingester_metadata[0].token.clone();
-
+        // ingesters of a cluster with a secret do not store their credentials
+        if let Some(check) = ingester_metadata
+            .iter()
+            .map(|ingester| ingester.token.clone())
+            .find(|token| !token.is_empty())
+        {
             let token = base64::prelude::BASE64_STANDARD.encode(format!(
                 "{}:{}",
                 CONFIG.parseable.username, CONFIG.parseable.password
@@ -307,7 +587,21 @@ impl IngestServer {
             log::warn!("could not populate local metadata. {:?}", err);
         }
 
+        if let Err(err) = storage.abort_abandoned_uploads().await {
+            log::warn!("could not clean up abandoned uploads. {:?}", err);
+        }
+
         metrics::fetch_stats_from_storage().await;
+        metering::init_metering_scheduler();
+        monitor::init().await;
+        metrics::init_load_sampler();
+        self.init_heartbeat_scheduler();
+        crate::s3_import::init();
+        // alerts are evaluated on the ingested events
+        crate::alerts::silence::init();
+        crate::alerts::history::init().await;
+        // copies held for peers are staged here if their origin is lost, whatever the local factor
+        replication::init(get_ingester_id()?).await;
 
         let (localsync_handler, mut localsync_outbox, localsync_inbox) = sync::run_local_sync();
         let (mut remote_sync_handler, mut remote_sync_outbox, mut remote_sync_inbox) =
@@ -323,6 +617,10 @@ impl IngestServer {
                     localsync_inbox.send(()).unwrap_or(());
                     localsync_handler.join().unwrap_or(());
                     remote_sync_handler.join().unwrap_or(());
+                    // staging left behind is only uploaded by this ingester, so it stays registered
+                    if shutdown::is_draining() && shutdown::flush_staging().await {
+                        self.remove_ingester_metadata().await;
+                    }
                     return e
                 },
                 _ = &mut localsync_outbox => {
@@ -340,3 +638,30 @@ impl IngestServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ulid::Ulid;
+
+    use super::{load_ingester_id, INGESTER_ID_FILE};
+
+    #[test]
+    fn ingester_id_is_kept_across_restarts() {
+        let staging = std::env::temp_dir().join(Ulid::new().to_string());
+
+        let id = load_ingester_id(&staging).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(staging.join(INGESTER_ID_FILE)).unwrap(),
+            id
+        );
+        assert_eq!(load_ingester_id(&staging).unwrap(), id);
+
+        // an emptied file gets a new id
+        std::fs::write(staging.join(INGESTER_ID_FILE), "\n").unwrap();
+        let replaced = load_ingester_id(&staging).unwrap();
+        assert_ne!(replaced, id);
+        assert_eq!(load_ingester_id(&staging).unwrap(), replaced);
+
+        std::fs::remove_dir_all(staging).unwrap();
+    }
+}
@@ -21,17 +21,16 @@ pub mod query_server;
 pub mod server;
 pub mod ssl_acceptor;
 
-use std::sync::Arc;
-
 use actix_web_prometheus::PrometheusMetrics;
 use async_trait::async_trait;
-use openid::Discovered;
 
-use crate::oidc;
 use base64::Engine;
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
 use serde::Serialize;
-pub type OpenIdClient = Arc<openid::Client<Discovered, oidc::Claims>>;
+
+use crate::option::CONFIG;
+use crate::rbac::cluster_token;
 
 // to be decided on what the Default version should be
 pub const DEFAULT_VERSION: &str = "v3";
@@ -61,6 +60,12 @@ pub struct IngesterMetadata {
     pub domain_name: String,
     pub bucket_name: String,
     pub token: String,
+    /// Stable id of the ingester, empty for metadata written before ingesters had an id
+    #[serde(default)]
+    pub ingester_id: String,
+    /// Last time the ingester reported being up, absent for ingesters without heartbeats
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_heartbeat: Option<DateTime<Utc>>,
 }
 
 impl IngesterMetadata {
@@ -71,6 +76,7 @@ impl IngesterMetadata {
         bucket_name: String,
         username: &str,
         password: &str,
+        ingester_id: String,
     ) -> Self {
         let token = base64::prelude::BASE64_STANDARD.encode(format!("{}:{}", username, password));
 
@@ -82,6 +88,21 @@ impl IngesterMetadata {
             version,
             bucket_name,
             token,
+            ingester_id,
+            last_heartbeat: None,
+        }
+    }
+
+    /// Authorization header for requests to this ingester, a signed token if the cluster has a secret
+    pub fn authorization(&self) -> String {
+        cluster_token::authorization().unwrap_or_else(|| self.token.clone())
+    }
+
+    /// Authorization header for scraping the metrics of this ingester, the scrape token if one is set
+    pub fn metrics_authorization(&self) -> String {
+        match &CONFIG.parseable.metrics_token {
+            Some(token) => format!("Bearer {token}"),
+            None => self.authorization(),
         }
     }
 }
@@ -102,13 +123,21 @@ mod test {
             "somebucket".to_string(),
             "admin",
             "admin",
+            "01HQX5J2K8Z3QW6R9N4T7V2Y1M".to_string(),
         );
 
-        let rhs = serde_json::from_slice::<IngesterMetadata>(br#"{"version":"v3","port":"8000","domain_name":"https://localhost:8000","bucket_name":"somebucket","token":"Basic YWRtaW46YWRtaW4="}"#).unwrap();
+        let rhs = serde_json::from_slice::<IngesterMetadata>(br#"{"version":"v3","port":"8000","domain_name":"https://localhost:8000","bucket_name":"somebucket","token":"Basic YWRtaW46YWRtaW4=","ingester_id":"01HQX5J2K8Z3QW6R9N4T7V2Y1M"}"#).unwrap();
 
         assert_eq!(rhs, lhs);
     }
 
+    #[rstest]
+    fn test_deserialize_resource_without_id() {
+        let im = serde_json::from_slice::<IngesterMetadata>(br#"{"version":"v3","port":"8000","domain_name":"https://localhost:8000","bucket_name":"somebucket","token":"Basic YWRtaW46YWRtaW4="}"#).unwrap();
+
+        assert!(im.ingester_id.is_empty());
+    }
+
     #[rstest]
     fn test_serialize_resource() {
         let im = IngesterMetadata::new(
@@ -118,13 +147,14 @@ mod test {
             "somebucket".to_string(),
             "admin",
             "admin",
+            "01HQX5J2K8Z3QW6R9N4T7V2Y1M".to_string(),
         );
 
         let lhs = serde_json::to_string(&im)
             .unwrap()
             .try_into_bytes()
             .unwrap();
-        let rhs = br#"{"version":"v3","port":"8000","domain_name":"https://localhost:8000","bucket_name":"somebucket","token":"Basic YWRtaW46YWRtaW4="}"#
+        let rhs = br#"{"version":"v3","port":"8000","domain_name":"https://localhost:8000","bucket_name":"somebucket","token":"Basic YWRtaW46YWRtaW4=","ingester_id":"01HQX5J2K8Z3QW6R9N4T7V2Y1M"}"#
                 .try_into_bytes()
                 .unwrap();
 
@@ -17,22 +17,24 @@
  */
 
 use crate::handlers::http::cluster;
-use crate::handlers::http::middleware::RouteExt;
-use crate::handlers::http::{base_path, cross_origin_config, API_BASE_PATH, API_VERSION};
+use crate::handlers::http::middleware::{request_logger, MetricsAuth, RequestId, RouteExt};
+use crate::handlers::http::{base_path, cross_origin_config, oidc};
 
 use crate::rbac::role::Action;
-use crate::{analytics, banner, metadata, metrics, migration, rbac, storage};
+use crate::{
+    alerts, analytics, banner, detections, external_tables, metadata, metering, metrics, migration,
+    monitor, rbac, reports, storage,
+};
 use actix_web::web;
 use actix_web::web::ServiceConfig;
 use actix_web::{App, HttpServer};
 use async_trait::async_trait;
-use std::sync::Arc;
 
 use crate::option::CONFIG;
 
 use super::server::Server;
 use super::ssl_acceptor::get_ssl_acceptor;
-use super::{OpenIdClient, ParseableServer};
+use super::ParseableServer;
 
 #[derive(Default, Debug)]
 pub struct QueryServer;
@@ -44,16 +46,7 @@ impl ParseableServer for QueryServer {
         prometheus: actix_web_prometheus::PrometheusMetrics,
         oidc_client: Option<crate::oidc::OpenidConfig>,
     ) -> anyhow::Result<()> {
-        let oidc_client = match oidc_client {
-            Some(config) => {
-                let client = config
-                    .connect(&format!("{API_BASE_PATH}/{API_VERSION}/o/code"))
-                    .await?;
-                Some(Arc::new(client))
-            }
-
-            None => None,
-        };
+        oidc::set_oidc_config(oidc_client).await?;
 
         let ssl = get_ssl_acceptor(
             &CONFIG.parseable.tls_cert_path,
@@ -63,8 +56,10 @@ impl ParseableServer for QueryServer {
         let create_app_fn = move || {
             App::new()
                 .wrap(prometheus.clone())
-                .configure(|config| QueryServer::configure_routes(config, oidc_client.clone()))
-                .wrap(actix_web::middleware::Logger::default())
+                .wrap(MetricsAuth::from_config())
+                .configure(QueryServer::configure_routes)
+                .wrap(RequestId)
+                .wrap(request_logger())
                 .wrap(actix_web::middleware::Compress::default())
                 .wrap(cross_origin_config())
         };
@@ -73,7 +68,7 @@ impl ParseableServer for QueryServer {
         let http_server = HttpServer::new(create_app_fn).workers(num_cpus::get());
         if let Some(config) = ssl {
             http_server
-                .bind_rustls(&CONFIG.parseable.address, config)?
+                .bind_rustls_021(&CONFIG.parseable.address, config)?
                 .run()
                 .await?;
         } else {
@@ -111,21 +106,37 @@ impl ParseableServer for QueryServer {
 
 impl QueryServer {
     // configure the api routes
-    fn configure_routes(config: &mut ServiceConfig, oidc_client: Option<OpenIdClient>) {
+    fn configure_routes(config: &mut ServiceConfig) {
         config
             .service(
                 web::scope(&base_path())
                     // POST "/query" ==> Get results of the SQL query passed in request body
                     .service(Server::get_query_factory())
+                    .service(Server::get_query_batch_factory())
+                    .service(Server::get_active_queries_webscope())
+                    .service(Server::get_query_validate_factory())
+                    .service(Server::get_correlate_factory())
                     .service(Server::get_liveness_factory())
                     .service(Server::get_readiness_factory())
                     .service(Server::get_about_factory())
+                    .service(Server::get_openapi_factory())
+                    .service(Server::get_metering_factory())
                     .service(Server::get_logstream_webscope())
                     .service(Server::get_user_webscope())
                     .service(Server::get_llm_webscope())
-                    .service(Server::get_oauth_webscope(oidc_client))
+                    .service(Server::get_oauth_webscope())
                     .service(Server::get_user_role_webscope())
-                    .service(Self::get_cluster_info_web_scope()),
+                    .service(Server::get_dashboards_webscope())
+                    .service(Server::get_filters_webscope())
+                    .service(Server::get_reports_webscope())
+                    .service(Server::get_detections_webscope())
+                    .service(Server::get_alerts_webscope())
+                    .service(Server::get_external_tables_webscope())
+                    .service(Server::get_reload_factory())
+                    .service(Server::get_backup_webscope())
+                    .service(Server::get_stream_templates_webscope())
+                    .service(Self::get_cluster_info_web_scope())
+                    .configure(Server::configure_profiling),
             )
             .service(Server::get_generated());
     }
@@ -148,7 +159,64 @@ impl QueryServer {
                         .authorize(Action::ListClusterMetrics),
                 ),
             )
-            // DELETE "/cluster/{ingester_domain:port}" ==> Delete an ingester from the cluster
+            // GET "/cluster/capacity" ==> Get storage growth forecast of all streams
+            .service(Server::get_capacity_factory())
+            .service(
+                web::scope("/shards")
+                    // GET "/cluster/shards" ==> Get the assignment of stream shards to ingesters
+                    .service(
+                        web::resource("").route(
+                            web::get()
+                                .to(cluster::sharding::get_shards)
+                                .authorize(Action::ListCluster),
+                        ),
+                    )
+                    // PUT "/cluster/shards/{logstream}" ==> Split a stream over the given number of ingesters
+                    .service(
+                        web::resource("/{logstream}").route(
+                            web::put()
+                                .to(cluster::sharding::put_shards)
+                                .authorize(Action::AssignShards),
+                        ),
+                    ),
+            )
+            // GET "/cluster/topology" ==> Get the graph of cluster nodes, storage and streams
+            .service(
+                web::resource("/topology").route(
+                    web::get()
+                        .to(cluster::topology::get_topology)
+                        .authorize(Action::ListCluster),
+                ),
+            )
+            // GET "/cluster/ingest-targets?stream={logstream}" ==> Get live ingesters, least loaded first
+            .service(
+                web::resource("/ingest-targets").route(
+                    web::get()
+                        .to(cluster::ingest_targets::get_ingest_targets)
+                        .authorize(Action::ListCluster),
+                ),
+            )
+            .service(
+                web::scope("/consistency")
+                    // GET "/cluster/consistency" ==> Get divergences of ingesters from the stream metadata in storage
+                    .service(
+                        web::resource("").route(
+                            web::get()
+                                .to(cluster::consistency::get_consistency)
+                                .authorize(Action::ListCluster),
+                        ),
+                    )
+                    // POST "/cluster/consistency/{logstream}/resync" ==> Push stream settings to ingesters again
+                    .service(
+                        web::resource("/{logstream}/resync").route(
+                            web::post()
+                                .to(cluster::consistency::resync_stream)
+                                .authorize(Action::PutStreamSettings),
+                        ),
+                    ),
+            )
+            // DELETE "/cluster/{ingester_id}" ==> Delete an ingester from the cluster, ingesters registered
+            // before they had an id are deleted by their domain:port
             .service(
                 web::scope("/{ingester}").service(
                     web::resource("").route(
@@ -166,11 +234,16 @@ impl QueryServer {
         CONFIG.storage().register_store_metrics(&prometheus);
 
         migration::run_migration(&CONFIG).await?;
+        // consistency checks account for the quirks of the object storage
+        tokio::spawn(storage::quirks::init());
 
         let storage = CONFIG.storage().get_object_store();
         if let Err(e) = metadata::STREAM_INFO.load(&*storage).await {
             log::warn!("could not populate local metadata. {:?}", e);
         }
+        if let Err(e) = external_tables::load().await {
+            log::warn!("could not load external tables. {:?}", e);
+        }
 
         // track all parquet files already in the data directory
         storage::retention::load_retention_from_global();
@@ -183,6 +256,17 @@ impl QueryServer {
             analytics::init_analytics_scheduler();
         }
 
+        metering::init_metering_scheduler();
+        monitor::init().await;
+        reports::init_report_scheduler();
+        detections::init_detection_scheduler();
+        alerts::burn_rate::init_burn_rate_scheduler();
+        alerts::silence::init();
+        alerts::history::init().await;
+        storage::iceberg::init_export_scheduler();
+        cluster::sharding::load().await;
+        cluster::init_stale_ingester_scheduler();
+
         self.start(prometheus, CONFIG.parseable.openid.clone())
             .await?;
 
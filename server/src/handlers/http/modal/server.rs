@@ -21,19 +21,23 @@ use crate::banner;
 use crate::handlers;
 use crate::handlers::http::about;
 use crate::handlers::http::base_path;
+use crate::handlers::http::cluster;
 use crate::handlers::http::health_check;
 use crate::handlers::http::query;
-use crate::handlers::http::API_BASE_PATH;
-use crate::handlers::http::API_VERSION;
 use crate::localcache::LocalCacheManager;
 use crate::metadata;
+use crate::metering;
 use crate::metrics;
 use crate::migration;
+use crate::monitor;
 use crate::rbac;
+use crate::reload;
+use crate::shutdown;
 use crate::storage;
 use crate::sync;
+use crate::tls;
 use std::net::SocketAddr;
-use std::{fs::File, io::BufReader, sync::Arc};
+use std::{fs::File, io::BufReader};
 
 use actix_web::web::resource;
 use actix_web::Resource;
@@ -43,14 +47,16 @@ use actix_web_prometheus::PrometheusMetrics;
 use actix_web_static_files::ResourceFiles;
 use async_trait::async_trait;
 
-use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls::{Certificate, PrivateKey};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 
 use crate::{
     handlers::http::{
-        self, cross_origin_config, ingest, llm, logstream,
-        middleware::{DisAllowRootUser, RouteExt},
-        oidc, role, MAX_EVENT_PAYLOAD_SIZE,
+        self, alerts, backup, cross_origin_config, dashboards, detections, external_tables,
+        filters, ingest, llm, logstream,
+        middleware::{request_logger, DisAllowRootUser, MetricsAuth, RequestId, RouteExt},
+        oidc, openapi, profiling, reports, role, silences, stream_templates,
+        MAX_EVENT_PAYLOAD_SIZE,
     },
     option::CONFIG,
     rbac::role::Action,
@@ -58,7 +64,6 @@ use crate::{
 
 // use super::generate;
 use super::generate;
-use super::OpenIdClient;
 use super::ParseableServer;
 
 #[derive(Default)]
@@ -71,21 +76,15 @@ impl ParseableServer for Server {
         prometheus: PrometheusMetrics,
         oidc_client: Option<crate::oidc::OpenidConfig>,
     ) -> anyhow::Result<()> {
-        let oidc_client = match oidc_client {
-            Some(config) => {
-                let client = config
-                    .connect(&format!("{API_BASE_PATH}/{API_VERSION}/o/code"))
-                    .await?;
-                Some(Arc::new(client))
-            }
-            None => None,
-        };
+        oidc::set_oidc_config(oidc_client).await?;
 
         let create_app_fn = move || {
             App::new()
                 .wrap(prometheus.clone())
-                .configure(|cfg| Server::configure_routes(cfg, oidc_client.clone()))
-                .wrap(actix_web::middleware::Logger::default())
+                .wrap(MetricsAuth::from_config())
+                .configure(Server::configure_routes)
+                .wrap(RequestId)
+                .wrap(request_logger())
                 .wrap(actix_web::middleware::Compress::default())
                 .wrap(cross_origin_config())
         };
@@ -95,10 +94,8 @@ impl ParseableServer for Server {
             &CONFIG.parseable.tls_key_path,
         ) {
             (Some(cert), Some(key)) => {
-                // init server config builder with safe defaults
-                let config = ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth();
+                // init server config builder with the configured cipher suites
+                let config = tls::server_config_builder()?.with_no_client_auth();
 
                 // load TLS key/cert files
                 let cert_file = &mut BufReader::new(File::open(cert)?);
@@ -125,15 +122,20 @@ impl ParseableServer for Server {
         };
 
         // concurrent workers equal to number of cores on the cpu
-        let http_server = HttpServer::new(create_app_fn).workers(num_cpus::get());
-        if let Some(config) = ssl_acceptor {
+        // shutdown signals are handled by the drain, which stops the server gracefully
+        let http_server = HttpServer::new(create_app_fn)
+            .workers(num_cpus::get())
+            .disable_signals()
+            .shutdown_timeout(CONFIG.parseable.drain_timeout.as_secs());
+        let http_server = if let Some(config) = ssl_acceptor {
             http_server
-                .bind_rustls(&CONFIG.parseable.address, config)?
+                .bind_rustls_021(&CONFIG.parseable.address, config)?
                 .run()
-                .await?;
         } else {
-            http_server.bind(&CONFIG.parseable.address)?.run().await?;
-        }
+            http_server.bind(&CONFIG.parseable.address)?.run()
+        };
+        shutdown::init_signal_handler(http_server.handle());
+        http_server.await?;
 
         Ok(())
     }
@@ -157,29 +159,93 @@ impl ParseableServer for Server {
 }
 
 impl Server {
-    fn configure_routes(config: &mut web::ServiceConfig, oidc_client: Option<OpenIdClient>) {
+    fn configure_routes(config: &mut web::ServiceConfig) {
         // there might be a bug in the configure routes method
         config
             .service(
                 web::scope(&base_path())
                     // POST "/query" ==> Get results of the SQL query passed in request body
                     .service(Self::get_query_factory())
+                    .service(Self::get_query_batch_factory())
+                    .service(Self::get_active_queries_webscope())
+                    .service(Self::get_query_validate_factory())
+                    .service(Self::get_correlate_factory())
                     .service(Self::get_ingest_factory())
+                    .service(Self::get_ingest_arrow_factory())
+                    .service(Self::get_ingest_aws_factory())
+                    .service(Self::get_ingest_journald_factory())
+                    // GET "/cluster/capacity" ==> Get storage growth forecast of all streams
+                    .service(web::scope("/cluster").service(Self::get_capacity_factory()))
                     .service(Self::get_liveness_factory())
                     .service(Self::get_readiness_factory())
                     .service(Self::get_about_factory())
+                    .service(Self::get_openapi_factory())
+                    .service(Self::get_metering_factory())
                     .service(Self::get_logstream_webscope())
                     .service(Self::get_user_webscope())
                     .service(Self::get_llm_webscope())
-                    .service(Self::get_oauth_webscope(oidc_client))
-                    .service(Self::get_user_role_webscope()),
+                    .service(Self::get_oauth_webscope())
+                    .service(Self::get_user_role_webscope())
+                    .service(Self::get_dashboards_webscope())
+                    .service(Self::get_filters_webscope())
+                    .service(Self::get_reports_webscope())
+                    .service(Self::get_detections_webscope())
+                    .service(Self::get_alerts_webscope())
+                    .service(Self::get_external_tables_webscope())
+                    .service(Self::get_reload_factory())
+                    .service(Self::get_backup_webscope())
+                    .service(Self::get_stream_templates_webscope())
+                    .configure(Self::configure_profiling),
             )
             .service(Self::get_generated());
     }
 
     // get the query factory
     pub fn get_query_factory() -> Resource {
-        web::resource("/query").route(web::post().to(query::query).authorize(Action::Query))
+        web::resource("/query")
+            .route(web::post().to(query::query).authorize(Action::Query))
+            // GET "/query?q={sql}&from={start}&to={end}" ==> Run the SQL query passed in the url
+            .route(web::get().to(query::query_get).authorize(Action::Query))
+    }
+
+    // get the query validate factory
+    pub fn get_query_validate_factory() -> Resource {
+        // POST "/query/validate" ==> Parse, plan and authorize the SQL query passed in request body without running it
+        web::resource("/query/validate")
+            .route(web::post().to(query::validate).authorize(Action::Query))
+    }
+
+    // get the query batch factory
+    pub fn get_query_batch_factory() -> Resource {
+        // POST "/query/batch" ==> Run the SQL queries passed in request body and return the results of all of them
+        web::resource("/query/batch").route(web::post().to(query::batch).authorize(Action::Query))
+    }
+
+    // get the active queries webscope
+    pub fn get_active_queries_webscope() -> Scope {
+        web::scope("/query/active")
+            .service(
+                // GET "/query/active" ==> List the queries queued or running on this server
+                resource("").route(
+                    web::get()
+                        .to(query::list_active)
+                        .authorize(Action::ListActiveQueries),
+                ),
+            )
+            .service(
+                // DELETE "/query/active/{id}" ==> Kill a query queued or running on this server
+                resource("/{id}").route(
+                    web::delete()
+                        .to(query::kill_active)
+                        .authorize(Action::KillQuery),
+                ),
+            )
+    }
+
+    // get the correlate factory
+    pub fn get_correlate_factory() -> Resource {
+        // GET "/correlate?trace_id={id}" ==> Records with the id from all correlated streams ordered by time
+        web::resource("/correlate").route(web::get().to(query::correlate).authorize(Action::Query))
     }
 
     // get the logstream web scope
@@ -214,6 +280,22 @@ impl Server {
                             )
                             .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE)),
                     )
+                    .service(
+                        // POST "/logstream/{logstream}/flush" ==> Convert and upload staging data of given log stream
+                        web::resource("/flush").route(
+                            web::post()
+                                .to(logstream::flush)
+                                .authorize_for_stream(Action::FlushStream),
+                        ),
+                    )
+                    .service(
+                        // POST "/logstream/{logstream}/patterns" ==> Cluster events of given log stream by message template
+                        web::resource("/patterns").route(
+                            web::post()
+                                .to(query::patterns)
+                                .authorize_for_stream(Action::Query),
+                        ),
+                    )
                     .service(
                         // GET "/logstream/{logstream}/info" ==> Get info for given log stream
                         web::resource("/info").route(
@@ -245,6 +327,29 @@ impl Server {
                                 .authorize_for_stream(Action::GetSchema),
                         ),
                     )
+                    .service(
+                        // GET "/logstream/{logstream}/schema/versions" ==> Get the versions of the schema of given log stream
+                        web::resource("/schema/versions").route(
+                            web::get()
+                                .to(logstream::get_schema_versions)
+                                .authorize_for_stream(Action::GetSchema),
+                        ),
+                    )
+                    .service(
+                        web::resource("/schema/compatibility")
+                            // PUT "/logstream/{logstream}/schema/compatibility" ==> Set the changes allowed to the schema of given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_schema_compatibility)
+                                    .authorize_for_stream(Action::PutSchemaCompatibility),
+                            )
+                            // GET "/logstream/{logstream}/schema/compatibility" ==> Get schema compatibility for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_schema_compatibility)
+                                    .authorize_for_stream(Action::GetSchemaCompatibility),
+                            ),
+                    )
                     .service(
                         // GET "/logstream/{logstream}/stats" ==> Get stats for given log stream
                         web::resource("/stats").route(
@@ -268,6 +373,180 @@ impl Server {
                                     .authorize_for_stream(Action::GetRetention),
                             ),
                     )
+                    .service(
+                        web::resource("/flush-interval")
+                            // PUT "/logstream/{logstream}/flush-interval" ==> Set staging flush interval for given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_flush_interval)
+                                    .authorize_for_stream(Action::PutFlushInterval),
+                            )
+                            // GET "/logstream/{logstream}/flush-interval" ==> Get staging flush interval for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_flush_interval)
+                                    .authorize_for_stream(Action::GetFlushInterval),
+                            ),
+                    )
+                    .service(
+                        web::resource("/sort-keys")
+                            // PUT "/logstream/{logstream}/sort-keys" ==> Set columns parquet files of given logstream are sorted by
+                            .route(
+                                web::put()
+                                    .to(logstream::put_sort_keys)
+                                    .authorize_for_stream(Action::PutSortKeys),
+                            )
+                            // GET "/logstream/{logstream}/sort-keys" ==> Get sort keys for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_sort_keys)
+                                    .authorize_for_stream(Action::GetSortKeys),
+                            ),
+                    )
+                    .service(
+                        web::resource("/field-mapping")
+                            // PUT "/logstream/{logstream}/field-mapping" ==> Set mapping of event fields to a schema for given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_field_mapping)
+                                    .authorize_for_stream(Action::PutFieldMapping),
+                            )
+                            // GET "/logstream/{logstream}/field-mapping" ==> Get field mapping for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_field_mapping)
+                                    .authorize_for_stream(Action::GetFieldMapping),
+                            ),
+                    )
+                    .service(
+                        web::resource("/allowed-tags")
+                            // PUT "/logstream/{logstream}/allowed-tags" ==> Set tags events of given logstream can be sent with
+                            .route(
+                                web::put()
+                                    .to(logstream::put_allowed_tags)
+                                    .authorize_for_stream(Action::PutAllowedTags),
+                            )
+                            // GET "/logstream/{logstream}/allowed-tags" ==> Get allowed tags for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_allowed_tags)
+                                    .authorize_for_stream(Action::GetAllowedTags),
+                            ),
+                    )
+                    .service(
+                        web::resource("/max-columns")
+                            // PUT "/logstream/{logstream}/max-columns" ==> Set number of columns the schema of given logstream can grow to
+                            .route(
+                                web::put()
+                                    .to(logstream::put_max_columns)
+                                    .authorize_for_stream(Action::PutMaxColumns),
+                            )
+                            // GET "/logstream/{logstream}/max-columns" ==> Get max columns for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_max_columns)
+                                    .authorize_for_stream(Action::GetMaxColumns),
+                            ),
+                    )
+                    .service(
+                        web::resource("/renamed-columns")
+                            // PUT "/logstream/{logstream}/renamed-columns" ==> Set current names of renamed columns of given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_renamed_columns)
+                                    .authorize_for_stream(Action::PutRenamedColumns),
+                            )
+                            // GET "/logstream/{logstream}/renamed-columns" ==> Get renamed columns for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_renamed_columns)
+                                    .authorize_for_stream(Action::GetRenamedColumns),
+                            ),
+                    )
+                    .service(
+                        web::resource("/default-filter")
+                            // PUT "/logstream/{logstream}/default-filter" ==> Set predicate queries of given logstream are filtered with
+                            .route(
+                                web::put()
+                                    .to(logstream::put_default_filter)
+                                    .authorize_for_stream(Action::PutDefaultFilter),
+                            )
+                            // GET "/logstream/{logstream}/default-filter" ==> Get default filter for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_default_filter)
+                                    .authorize_for_stream(Action::GetDefaultFilter),
+                            ),
+                    )
+                    .service(
+                        web::resource("/archive")
+                            // PUT "/logstream/{logstream}/archive" ==> Set archive destination used on deletion of given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_archive)
+                                    .authorize_for_stream(Action::PutArchive),
+                            )
+                            // GET "/logstream/{logstream}/archive" ==> Get archive destination for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_archive)
+                                    .authorize_for_stream(Action::GetArchive),
+                            ),
+                    )
+                    .service(
+                        web::resource("/iceberg")
+                            // PUT "/logstream/{logstream}/iceberg" ==> Enable or disable iceberg metadata for given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_iceberg_export)
+                                    .authorize_for_stream(Action::PutIcebergExport),
+                            )
+                            // GET "/logstream/{logstream}/iceberg" ==> Get iceberg export setting and table location for given logstream
+                            .route(
+                                web::get()
+                                    .to(logstream::get_iceberg_export)
+                                    .authorize_for_stream(Action::GetIcebergExport),
+                            ),
+                    )
+                    .service(
+                        web::resource("/legal-hold")
+                            // PUT "/logstream/{logstream}/legal-hold" ==> Place legal hold on given logstream
+                            .route(
+                                web::put()
+                                    .to(logstream::put_legal_hold)
+                                    .authorize(Action::PutLegalHold),
+                            )
+                            // DELETE "/logstream/{logstream}/legal-hold" ==> Lift legal hold from given logstream
+                            .route(
+                                web::delete()
+                                    .to(logstream::delete_legal_hold)
+                                    .authorize(Action::DeleteLegalHold),
+                            ),
+                    )
+                    .service(
+                        // POST "/logstream/{logstream}/purge" ==> Start deleting rows matching a predicate from given logstream
+                        web::resource("/purge").route(
+                            web::post()
+                                .to(logstream::purge)
+                                .authorize_for_stream(Action::Purge),
+                        ),
+                    )
+                    .service(
+                        // GET "/logstream/{logstream}/purge/{id}" ==> Get progress of a purge of given logstream
+                        web::resource("/purge/{id}").route(
+                            web::get()
+                                .to(logstream::get_purge_status)
+                                .authorize_for_stream(Action::Purge),
+                        ),
+                    )
+                    .service(
+                        // POST "/logstream/{logstream}/consistency" ==> Check storage metadata and checksums of given logstream, optionally repair metadata
+                        web::resource("/consistency").route(
+                            web::post()
+                                .to(logstream::check_consistency)
+                                .authorize(Action::CheckConsistency),
+                        ),
+                    )
                     .service(
                         web::resource("/cache")
                             // PUT "/logstream/{logstream}/cache" ==> Set retention for given logstream
@@ -297,18 +576,322 @@ impl Server {
             .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE))
     }
 
+    // get the factory for the ingest route of systemd-journal-upload
+    pub fn get_ingest_journald_factory() -> Resource {
+        // POST "/ingest/journald/{logstream}/upload" ==> Ingest journal entries, the upload url
+        // is set to the path before "/upload", which systemd-journal-upload appends
+        web::resource("/ingest/journald/{logstream}/upload").route(
+            web::post()
+                .to(ingest::ingest_journald)
+                .authorize_for_stream(Action::Ingest),
+        )
+    }
+
+    // get the factory for the ingest route of AWS services
+    pub fn get_ingest_aws_factory() -> Resource {
+        // POST "/ingest/aws/{logstream}" ==> Ingest events delivered by AWS services, requests
+        // are authenticated by their SigV4 signature instead of parseable credentials
+        web::resource("/ingest/aws/{logstream}")
+            .route(web::post().to(ingest::ingest_aws))
+            .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE))
+    }
+
+    // get the factory for the arrow ingest route
+    pub fn get_ingest_arrow_factory() -> Resource {
+        // POST "/ingest/arrow" ==> Ingest record batches sent as an arrow IPC stream
+        web::resource("/ingest/arrow")
+            .route(
+                web::post()
+                    .to(ingest::ingest_arrow)
+                    .authorize_for_stream(Action::Ingest),
+            )
+            .app_data(web::PayloadConfig::default().limit(MAX_EVENT_PAYLOAD_SIZE))
+    }
+
+    // get the factory for the storage capacity forecast
+    pub fn get_capacity_factory() -> Resource {
+        web::resource("/capacity").route(
+            web::get()
+                .to(cluster::capacity::get_capacity)
+                .authorize(Action::GetClusterCapacity),
+        )
+    }
+
     // get the oauth webscope
-    pub fn get_oauth_webscope(oidc_client: Option<OpenIdClient>) -> Scope {
-        let oauth = web::scope("/o")
+    pub fn get_oauth_webscope() -> Scope {
+        web::scope("/o")
             .service(resource("/login").route(web::get().to(oidc::login)))
             .service(resource("/logout").route(web::get().to(oidc::logout)))
-            .service(resource("/code").route(web::get().to(oidc::reply_login)));
+            .service(resource("/code").route(web::get().to(oidc::reply_login)))
+    }
 
-        if let Some(client) = oidc_client {
-            oauth.app_data(web::Data::from(client))
-        } else {
-            oauth
-        }
+    // get the dashboards webscope
+    pub fn get_dashboards_webscope() -> Scope {
+        web::scope("/dashboards")
+            .service(
+                resource("")
+                    // GET "/dashboards" ==> List all dashboards
+                    .route(
+                        web::get()
+                            .to(dashboards::list)
+                            .authorize(Action::ListDashboard),
+                    )
+                    // POST "/dashboards" ==> Create a dashboard
+                    .route(
+                        web::post()
+                            .to(dashboards::post)
+                            .authorize(Action::CreateDashboard),
+                    ),
+            )
+            .service(
+                resource("/{id}")
+                    // GET "/dashboards/{id}" ==> Get a dashboard
+                    .route(
+                        web::get()
+                            .to(dashboards::get)
+                            .authorize(Action::GetDashboard),
+                    )
+                    // PUT "/dashboards/{id}" ==> Update a dashboard, keeping the previous version
+                    .route(
+                        web::put()
+                            .to(dashboards::put)
+                            .authorize(Action::UpdateDashboard),
+                    )
+                    // DELETE "/dashboards/{id}" ==> Delete a dashboard and its versions
+                    .route(
+                        web::delete()
+                            .to(dashboards::delete)
+                            .authorize(Action::DeleteDashboard),
+                    ),
+            )
+            .service(
+                // GET "/dashboards/{id}/versions" ==> List previous versions of a dashboard
+                resource("/{id}/versions").route(
+                    web::get()
+                        .to(dashboards::list_versions)
+                        .authorize(Action::GetDashboard),
+                ),
+            )
+    }
+
+    // get the metadata backup webscope
+    pub fn get_backup_webscope() -> Scope {
+        web::scope("/admin/backup")
+            .service(
+                resource("")
+                    // GET "/admin/backup" ==> List the metadata backups
+                    .route(web::get().to(backup::list).authorize(Action::Backup))
+                    // POST "/admin/backup" ==> Back up the metadata of the deployment and its log streams
+                    .route(web::post().to(backup::create).authorize(Action::Backup)),
+            )
+            .service(
+                // POST "/admin/backup/{name}/restore" ==> Restore the metadata from a backup
+                resource("/{name}/restore")
+                    .route(web::post().to(backup::restore).authorize(Action::Restore)),
+            )
+    }
+
+    // get the stream templates webscope
+    pub fn get_stream_templates_webscope() -> Scope {
+        web::scope("/stream-templates")
+            .service(
+                // GET "/stream-templates" ==> List the stream templates
+                resource("").route(
+                    web::get()
+                        .to(stream_templates::list)
+                        .authorize(Action::ListStreamTemplate),
+                ),
+            )
+            .service(
+                resource("/{name}")
+                    // GET "/stream-templates/{name}" ==> Get a stream template
+                    .route(
+                        web::get()
+                            .to(stream_templates::get)
+                            .authorize(Action::GetStreamTemplate),
+                    )
+                    // PUT "/stream-templates/{name}" ==> Create or replace a stream template
+                    .route(
+                        web::put()
+                            .to(stream_templates::put)
+                            .authorize(Action::PutStreamTemplate),
+                    )
+                    // DELETE "/stream-templates/{name}" ==> Delete a stream template
+                    .route(
+                        web::delete()
+                            .to(stream_templates::delete)
+                            .authorize(Action::DeleteStreamTemplate),
+                    ),
+            )
+    }
+
+    // get the filters webscope
+    pub fn get_filters_webscope() -> Scope {
+        web::scope("/filters")
+            .service(
+                resource("")
+                    // GET "/filters" ==> List filters owned by or shared with the user
+                    .route(web::get().to(filters::list).authorize(Action::ListFilter))
+                    // POST "/filters" ==> Save a filter
+                    .route(
+                        web::post()
+                            .to(filters::post)
+                            .authorize(Action::CreateFilter),
+                    ),
+            )
+            .service(
+                resource("/{id}")
+                    // GET "/filters/{id}" ==> Get a filter
+                    .route(web::get().to(filters::get).authorize(Action::GetFilter))
+                    // PUT "/filters/{id}" ==> Update a filter
+                    .route(web::put().to(filters::put).authorize(Action::UpdateFilter))
+                    // DELETE "/filters/{id}" ==> Delete a filter
+                    .route(
+                        web::delete()
+                            .to(filters::delete)
+                            .authorize(Action::DeleteFilter),
+                    ),
+            )
+    }
+
+    // get the external tables webscope
+    pub fn get_external_tables_webscope() -> Scope {
+        web::scope("/external-tables")
+            .service(
+                resource("")
+                    // GET "/external-tables" ==> List external tables
+                    .route(
+                        web::get()
+                            .to(external_tables::list)
+                            .authorize(Action::ListExternalTable),
+                    )
+                    // POST "/external-tables" ==> Register files in object storage as a table
+                    .route(
+                        web::post()
+                            .to(external_tables::post)
+                            .authorize(Action::CreateExternalTable),
+                    ),
+            )
+            .service(
+                resource("/{name}")
+                    // GET "/external-tables/{name}" ==> Get an external table
+                    .route(
+                        web::get()
+                            .to(external_tables::get)
+                            .authorize(Action::GetExternalTable),
+                    )
+                    // DELETE "/external-tables/{name}" ==> Delete an external table
+                    .route(
+                        web::delete()
+                            .to(external_tables::delete)
+                            .authorize(Action::DeleteExternalTable),
+                    ),
+            )
+    }
+
+    // get the reports webscope
+    pub fn get_reports_webscope() -> Scope {
+        web::scope("/reports")
+            .service(
+                resource("")
+                    // GET "/reports" ==> List reports owned by the user
+                    .route(web::get().to(reports::list).authorize(Action::ListReport))
+                    // POST "/reports" ==> Schedule a report
+                    .route(
+                        web::post()
+                            .to(reports::post)
+                            .authorize(Action::CreateReport),
+                    ),
+            )
+            .service(
+                resource("/{id}")
+                    // GET "/reports/{id}" ==> Get a report
+                    .route(web::get().to(reports::get).authorize(Action::GetReport))
+                    // PUT "/reports/{id}" ==> Update a report
+                    .route(web::put().to(reports::put).authorize(Action::UpdateReport))
+                    // DELETE "/reports/{id}" ==> Delete a report
+                    .route(
+                        web::delete()
+                            .to(reports::delete)
+                            .authorize(Action::DeleteReport),
+                    ),
+            )
+    }
+
+    // get the detections webscope
+    pub fn get_detections_webscope() -> Scope {
+        web::scope("/detections")
+            .service(
+                resource("")
+                    // GET "/detections" ==> List detections on streams the user can query
+                    .route(
+                        web::get()
+                            .to(detections::list)
+                            .authorize(Action::ListDetection),
+                    )
+                    // POST "/detections" ==> Schedule a Sigma rule over a stream
+                    .route(
+                        web::post()
+                            .to(detections::post)
+                            .authorize(Action::CreateDetection),
+                    ),
+            )
+            .service(
+                resource("/{id}")
+                    // GET "/detections/{id}" ==> Get a detection
+                    .route(
+                        web::get()
+                            .to(detections::get)
+                            .authorize(Action::GetDetection),
+                    )
+                    // PUT "/detections/{id}" ==> Update a detection
+                    .route(
+                        web::put()
+                            .to(detections::put)
+                            .authorize(Action::UpdateDetection),
+                    )
+                    // DELETE "/detections/{id}" ==> Delete a detection
+                    .route(
+                        web::delete()
+                            .to(detections::delete)
+                            .authorize(Action::DeleteDetection),
+                    ),
+            )
+    }
+
+    // get the alerts webscope
+    pub fn get_alerts_webscope() -> Scope {
+        web::scope("/alerts")
+            .service(
+                resource("/silences")
+                    // GET "/alerts/silences" ==> List silences of alerts
+                    .route(web::get().to(silences::list).authorize(Action::ListSilence))
+                    // POST "/alerts/silences" ==> Silence the alerts matched by the given matchers
+                    .route(
+                        web::post()
+                            .to(silences::post)
+                            .authorize(Action::CreateSilence),
+                    ),
+            )
+            .service(
+                resource("/silences/{id}")
+                    // GET "/alerts/silences/{id}" ==> Get a silence
+                    .route(web::get().to(silences::get).authorize(Action::GetSilence))
+                    // DELETE "/alerts/silences/{id}" ==> Delete a silence
+                    .route(
+                        web::delete()
+                            .to(silences::delete)
+                            .authorize(Action::DeleteSilence),
+                    ),
+            )
+            .service(
+                // GET "/alerts/{id}/history" ==> Get the latest evaluations of an alert
+                resource("/{id}/history").route(
+                    web::get()
+                        .to(alerts::history)
+                        .authorize(Action::GetAlertHistory),
+                ),
+            )
     }
 
     // get the role webscope
@@ -409,11 +992,61 @@ impl Server {
         web::resource("/readiness").route(web::get().to(health_check::readiness))
     }
 
+    // get the openapi factory
+    // GET "/openapi.json" ==> OpenAPI document of the api, public so that SDKs can be generated from it
+    pub fn get_openapi_factory() -> Resource {
+        web::resource("/openapi.json").route(web::get().to(openapi::openapi))
+    }
+
     // get the about factory
     pub fn get_about_factory() -> Resource {
         web::resource("/about").route(web::get().to(about::about).authorize(Action::GetAbout))
     }
 
+    // profiling routes are only served with P_PROFILING set
+    pub fn configure_profiling(config: &mut web::ServiceConfig) {
+        if !CONFIG.parseable.profiling {
+            return;
+        }
+        config.service(
+            web::scope("/debug/pprof")
+                // GET "/debug/pprof/profile" ==> Cpu profile of the server
+                .service(
+                    web::resource("/profile").route(
+                        web::get()
+                            .to(profiling::cpu_profile)
+                            .authorize(Action::Profile),
+                    ),
+                )
+                // GET "/debug/pprof/heap" ==> Heap statistics of the server
+                .service(
+                    web::resource("/heap").route(
+                        web::get()
+                            .to(profiling::heap_stats)
+                            .authorize(Action::Profile),
+                    ),
+                ),
+        );
+    }
+
+    // POST "/config/reload" ==> Reload the config file, log level, query limits, alerts and oidc
+    pub fn get_reload_factory() -> Resource {
+        web::resource("/config/reload").route(
+            web::post()
+                .to(reload::reload_config)
+                .authorize(Action::ReloadConfig),
+        )
+    }
+
+    // GET "/metering" ==> Export hourly ingested and scanned bytes of all streams
+    pub fn get_metering_factory() -> Resource {
+        web::resource("/metering").route(
+            web::get()
+                .to(metering::export)
+                .authorize(Action::ExportMetering),
+        )
+    }
+
     // GET "/" ==> Serve the static frontend directory
     pub fn get_generated() -> ResourceFiles {
         ResourceFiles::new("/", generate()).resolve_not_found_to_root()
@@ -430,11 +1063,20 @@ impl Server {
         CONFIG.storage().register_store_metrics(&prometheus);
 
         migration::run_migration(&CONFIG).await?;
+        // consistency checks account for the quirks of the object storage
+        tokio::spawn(storage::quirks::init());
 
         let storage = CONFIG.storage().get_object_store();
         if let Err(err) = metadata::STREAM_INFO.load(&*storage).await {
             log::warn!("could not populate local metadata. {:?}", err);
         }
+        if let Err(err) = crate::external_tables::load().await {
+            log::warn!("could not load external tables. {:?}", err);
+        }
+
+        if let Err(err) = storage.abort_abandoned_uploads().await {
+            log::warn!("could not clean up abandoned uploads. {:?}", err);
+        }
 
         storage::retention::load_retention_from_global();
         metrics::fetch_stats_from_storage().await;
@@ -447,6 +1089,16 @@ impl Server {
             analytics::init_analytics_scheduler();
         }
 
+        metering::init_metering_scheduler();
+        monitor::init().await;
+        crate::reports::init_report_scheduler();
+        crate::detections::init_detection_scheduler();
+        crate::alerts::burn_rate::init_burn_rate_scheduler();
+        crate::alerts::silence::init();
+        crate::alerts::history::init().await;
+        storage::iceberg::init_export_scheduler();
+        crate::s3_import::init();
+
         tokio::spawn(handlers::livetail::server());
 
         let app = self.start(prometheus, CONFIG.parseable.openid.clone());
@@ -460,6 +1112,9 @@ impl Server {
                     localsync_inbox.send(()).unwrap_or(());
                     localsync_handler.join().unwrap_or(());
                     remote_sync_handler.join().unwrap_or(());
+                    if shutdown::is_draining() {
+                        shutdown::flush_staging().await;
+                    }
                     return e
                 },
                 _ = &mut localsync_outbox => {
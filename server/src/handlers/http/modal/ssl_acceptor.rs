@@ -22,15 +22,15 @@ use itertools::Itertools;
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 
+use crate::tls;
+
 pub fn get_ssl_acceptor(
     tls_cert: &Option<PathBuf>,
     tls_key: &Option<PathBuf>,
 ) -> anyhow::Result<Option<ServerConfig>> {
     match (tls_cert, tls_key) {
         (Some(cert), Some(key)) => {
-            let server_config = ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth();
+            let server_config = tls::server_config_builder()?.with_no_client_auth();
 
             let cert_file = &mut BufReader::new(File::open(cert)?);
             let key_file = &mut BufReader::new(File::open(key)?);
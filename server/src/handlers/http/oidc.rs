@@ -16,23 +16,30 @@
  *
  */
 
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
 
 use actix_web::{
     cookie::{time, Cookie, SameSite},
-    http::header::{self, ContentType},
-    web::{self, Data},
-    HttpRequest, HttpResponse,
+    http::header,
+    web, HttpRequest, HttpResponse,
 };
 use http::StatusCode;
+use once_cell::sync::Lazy;
 use openid::{Options, Token, Userinfo};
 use serde::Deserialize;
 use ulid::Ulid;
 use url::Url;
 
+use crate::handlers::http::problem::Problem;
 use crate::{
-    handlers::{COOKIE_AGE_DAYS, OIDC_SCOPE, SESSION_COOKIE_NAME, USER_COOKIE_NAME},
-    oidc::{Claims, DiscoveredClient},
+    handlers::{
+        http::{API_BASE_PATH, API_VERSION},
+        COOKIE_AGE_DAYS, OIDC_SCOPE, SESSION_COOKIE_NAME, USER_COOKIE_NAME,
+    },
+    oidc::{Claims, DiscoveredClient, OpenidConfig},
     option::CONFIG,
     rbac::{
         map::{SessionKey, DEFAULT_ROLE},
@@ -43,6 +50,27 @@ use crate::{
     utils::actix::extract_session_key_from_req,
 };
 
+// client of the configured provider, replaced when the configuration is reloaded
+static OIDC_CLIENT: Lazy<RwLock<Option<Arc<DiscoveredClient>>>> = Lazy::new(RwLock::default);
+
+fn oidc_client() -> Option<Arc<DiscoveredClient>> {
+    OIDC_CLIENT.read().unwrap().clone()
+}
+
+/// Discover the provider and use it for logins from now on, `None` disables oidc logins
+pub async fn set_oidc_config(config: Option<OpenidConfig>) -> Result<(), openid::error::Error> {
+    let client = match config {
+        Some(config) => Some(Arc::new(
+            config
+                .connect(&format!("{API_BASE_PATH}/{API_VERSION}/o/code"))
+                .await?,
+        )),
+        None => None,
+    };
+    *OIDC_CLIENT.write().unwrap() = client;
+    Ok(())
+}
+
 /// Struct representing query params returned from oidc provider
 #[derive(Deserialize, Debug)]
 pub struct Login {
@@ -62,12 +90,12 @@ pub async fn login(
     req: HttpRequest,
     query: web::Query<RedirectAfterLogin>,
 ) -> Result<HttpResponse, OIDCError> {
-    let oidc_client = req.app_data::<Data<DiscoveredClient>>();
+    let oidc_client = oidc_client();
     let session_key = extract_session_key_from_req(&req).ok();
 
     let (session_key, oidc_client) = match (session_key, oidc_client) {
         (None, None) => return Ok(redirect_no_oauth_setup(query.redirect.clone())),
-        (None, Some(client)) => return Ok(redirect_to_oidc(query, client)),
+        (None, Some(client)) => return Ok(redirect_to_oidc(query, &client)),
         (Some(session_key), client) => (session_key, client),
     };
 
@@ -97,7 +125,7 @@ pub async fn login(
             } else {
                 Users.remove_session(&key);
                 if let Some(oidc_client) = oidc_client {
-                    redirect_to_oidc(query, oidc_client)
+                    redirect_to_oidc(query, &oidc_client)
                 } else {
                     redirect_to_client(query.redirect.as_str(), None)
                 }
@@ -108,7 +136,7 @@ pub async fn login(
 }
 
 pub async fn logout(req: HttpRequest, query: web::Query<RedirectAfterLogin>) -> HttpResponse {
-    let oidc_client = req.app_data::<Data<DiscoveredClient>>();
+    let oidc_client = oidc_client();
     let Some(session) = extract_session_key_from_req(&req).ok() else {
         return redirect_to_client(query.redirect.as_str(), None);
     };
@@ -128,11 +156,10 @@ pub async fn logout(req: HttpRequest, query: web::Query<RedirectAfterLogin>) ->
 
 /// Handler for code callback
 /// User should be redirected to page they were trying to access with cookie
-pub async fn reply_login(
-    oidc_client: Data<DiscoveredClient>,
-    login_query: web::Query<Login>,
-) -> Result<HttpResponse, OIDCError> {
-    let oidc_client = Data::into_inner(oidc_client);
+pub async fn reply_login(login_query: web::Query<Login>) -> Result<HttpResponse, OIDCError> {
+    let Some(oidc_client) = oidc_client() else {
+        return Err(OIDCError::BadRequest);
+    };
     let Ok((mut claims, user_info)): Result<(Claims, Userinfo), anyhow::Error> =
         request_token(oidc_client, &login_query).await
     else {
@@ -370,8 +397,6 @@ impl actix_web::ResponseError for OIDCError {
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        actix_web::HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
+        Problem::new(self.status_code(), self).response()
     }
 }
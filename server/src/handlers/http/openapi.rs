@@ -0,0 +1,167 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! OpenAPI 3 document of the http api, generated from the annotations on the handlers.
+//! Handlers are added to [`ApiDoc`] once annotated with `#[utoipa::path]`.
+
+use actix_web::HttpResponse;
+use once_cell::sync::Lazy;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use super::{about, cluster, health_check, ingest, logstream, problem, query};
+use crate::handlers::http::cluster::utils;
+use crate::handlers::SESSION_COOKIE_NAME;
+use crate::metrics::prom_utils::Metrics;
+use crate::storage;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Parseable",
+        description = "Log analytics on object storage, api of the standalone and query servers"
+    ),
+    paths(
+        query::query,
+        query::query_get,
+        query::batch,
+        query::validate,
+        logstream::list,
+        logstream::put_stream,
+        logstream::delete,
+        logstream::schema,
+        logstream::get_stats,
+        logstream::get_stream_info,
+        ingest::ingest,
+        ingest::post_event,
+        cluster::get_cluster_info,
+        cluster::get_cluster_metrics,
+        about::about,
+        health_check::liveness,
+        health_check::readiness,
+        openapi,
+    ),
+    components(schemas(
+        query::Query,
+        query::BatchQuery,
+        query::BatchResult,
+        query::ValidateQuery,
+        query::ValidatedStatement,
+        query::ValidationError,
+        query::ValidationResponse,
+        ingest::IngestSummary,
+        ingest::RejectedEvent,
+        storage::LogStream,
+        storage::StreamInfo,
+        storage::SortKey,
+        storage::FieldMapping,
+        storage::LegalHold,
+        utils::QueriedStats,
+        utils::IngestionStats,
+        utils::StorageStats,
+        utils::ClusterInfo,
+        utils::VersionSkew,
+        Metrics,
+        health_check::Health,
+        health_check::Component,
+        health_check::Status,
+        problem::Problem,
+    )),
+    modifiers(&AuthSchemes),
+    security(("basic_auth" = []), ("session" = [])),
+    tags(
+        (name = "query", description = "SQL queries on log streams"),
+        (name = "logstream", description = "Log streams, their schema and stats"),
+        (name = "ingest", description = "Sending events to log streams"),
+        (name = "cluster", description = "Ingesters of a distributed deployment"),
+        (name = "server", description = "Health and version of the server"),
+    )
+)]
+pub struct ApiDoc;
+
+// users authenticate with basic auth, or the session cookie set on login through the console
+struct AuthSchemes;
+
+impl Modify for AuthSchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "basic_auth",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(SESSION_COOKIE_NAME))),
+        );
+    }
+}
+
+static DOCUMENT: Lazy<String> = Lazy::new(|| {
+    ApiDoc::openapi()
+        .to_json()
+        .expect("openapi document is serializable")
+});
+
+/// OpenAPI document of this api
+#[utoipa::path(
+    get,
+    path = "/api/v1/openapi.json",
+    tag = "server",
+    security(()),
+    responses((status = 200, description = "OpenAPI 3 document of the api"))
+)]
+pub async fn openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(DOCUMENT.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use utoipa::OpenApi;
+
+    use super::ApiDoc;
+
+    #[test]
+    fn document_covers_payloads_and_auth() {
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        assert!(document["paths"]["/api/v1/query"]["post"].is_object());
+        assert!(document["paths"]["/api/v1/logstream/{logstream}/stats"]["get"].is_object());
+        for schema in [
+            "Query",
+            "QueriedStats",
+            "ClusterInfo",
+            "StreamInfo",
+            "Problem",
+        ] {
+            assert!(
+                document["components"]["schemas"][schema].is_object(),
+                "{schema} is missing"
+            );
+        }
+        assert_eq!(
+            document["components"]["securitySchemes"]["basic_auth"]["scheme"],
+            "basic"
+        );
+        assert_eq!(
+            document["components"]["securitySchemes"]["session"]["in"],
+            "cookie"
+        );
+    }
+}
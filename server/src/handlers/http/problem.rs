@@ -0,0 +1,156 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Errors of the http api are sent as problem details (RFC 7807), e.g.
+//!
+//! ```json
+//! {
+//!   "type": "about:blank",
+//!   "title": "Not Found",
+//!   "status": 404,
+//!   "detail": "Log stream app does not exist",
+//!   "code": "stream_not_found",
+//!   "retriable": false,
+//!   "requestId": "01HRB8V7X2Q9J1Z3K4M5N6P7Q8"
+//! }
+//! ```
+//!
+//! `code` tells errors apart without parsing `detail`, it is derived from the status if the
+//! error does not set one. `retriable` tells whether the same request may succeed later.
+
+use std::borrow::Cow;
+
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::HttpResponse;
+use http::StatusCode;
+
+use super::middleware::current_request_id;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(value_type = String)]
+    kind: &'static str,
+    #[schema(value_type = String)]
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[schema(value_type = String, example = "stream_not_found")]
+    code: Cow<'static, str>,
+    retriable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, detail: impl ToString) -> Self {
+        Self {
+            // no documentation of the problem type beyond its status
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: detail.to_string(),
+            code: status_code_name(status),
+            retriable: matches!(
+                status,
+                StatusCode::REQUEST_TIMEOUT
+                    | StatusCode::TOO_MANY_REQUESTS
+                    | StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            request_id: current_request_id(),
+        }
+    }
+
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.code = Cow::Borrowed(code);
+        self
+    }
+
+    /// Mark the problem retriable, for errors which may pass whatever their status, e.g. of storage
+    pub fn retriable_if(mut self, retriable: bool) -> Self {
+        self.retriable |= retriable;
+        self
+    }
+
+    pub fn response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        HttpResponse::build(status)
+            .insert_header((CONTENT_TYPE, PROBLEM_JSON))
+            .body(serde_json::to_string(&self).expect("problem is serializable"))
+    }
+}
+
+// e.g. `not_found` for 404
+fn status_code_name(status: StatusCode) -> Cow<'static, str> {
+    match status.canonical_reason() {
+        Some(reason) => Cow::Owned(
+            reason
+                .chars()
+                .filter_map(|c| match c {
+                    ' ' | '-' => Some('_'),
+                    c if c.is_ascii_alphanumeric() => Some(c.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        None => Cow::Owned(format!("http_{}", status.as_u16())),
+    }
+}
+
+/// Whether a response is a problem already
+pub fn is_problem<B>(response: &HttpResponse<B>) -> bool {
+    response.headers().get(CONTENT_TYPE) == Some(&HeaderValue::from_static(PROBLEM_JSON))
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use serde_json::json;
+
+    use super::Problem;
+
+    #[test]
+    fn problem_defaults_to_status() {
+        let problem = serde_json::to_value(Problem::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Server is shutting down",
+        ))
+        .unwrap();
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank",
+                "title": "Service Unavailable",
+                "status": 503,
+                "detail": "Server is shutting down",
+                "code": "service_unavailable",
+                "retriable": true,
+            })
+        );
+
+        let problem = Problem::new(StatusCode::NOT_FOUND, "Log stream app does not exist")
+            .code("stream_not_found");
+        assert_eq!(problem.code, "stream_not_found");
+        assert!(!problem.retriable);
+    }
+}
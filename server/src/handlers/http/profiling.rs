@@ -0,0 +1,303 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! CPU profiles and heap statistics of a running server.
+//!
+//! Profiling needs a build with the `profiling` feature on a unix platform, which samples stacks
+//! with pprof and allocates with jemalloc, and the routes are only served with `P_PROFILING` set.
+
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use http::StatusCode;
+
+use crate::handlers::http::problem::Problem;
+
+// profiles longer than this hold the profiler for too long
+const MAX_PROFILE_DURATION: Duration = Duration::from_secs(300);
+const DEFAULT_PROFILE_SECONDS: u64 = 30;
+const DEFAULT_FREQUENCY: i32 = 99;
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    /// protobuf understood by `go tool pprof`
+    #[default]
+    Pprof,
+    /// one line per stack, frames separated by `;` followed by the sample count
+    Collapsed,
+    Flamegraph,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ProfileQuery {
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    #[serde(default)]
+    format: ProfileFormat,
+}
+
+// GET "/debug/pprof/profile?seconds=30&frequency=99&format=pprof|collapsed|flamegraph"
+// ==> Sample the stacks of every thread for the given time
+pub async fn cpu_profile(query: web::Query<ProfileQuery>) -> Result<HttpResponse, ProfilingError> {
+    let ProfileQuery {
+        seconds,
+        frequency,
+        format,
+    } = query.into_inner();
+    let duration = Duration::from_secs(seconds.unwrap_or(DEFAULT_PROFILE_SECONDS));
+    if duration.is_zero() || duration > MAX_PROFILE_DURATION {
+        return Err(ProfilingError::InvalidDuration);
+    }
+    let frequency = frequency.unwrap_or(DEFAULT_FREQUENCY);
+    if frequency <= 0 {
+        return Err(ProfilingError::InvalidFrequency);
+    }
+
+    imp::cpu_profile(duration, frequency, format).await
+}
+
+// GET "/debug/pprof/heap" ==> Allocator statistics in bytes
+pub async fn heap_stats() -> Result<HttpResponse, ProfilingError> {
+    imp::heap_stats().map(|stats| HttpResponse::Ok().json(stats))
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeapStats {
+    /// bytes allocated by the server
+    allocated: usize,
+    /// bytes in pages holding allocations
+    active: usize,
+    /// bytes in physically resident pages mapped by the allocator
+    resident: usize,
+    mapped: usize,
+    retained: usize,
+    /// bytes of allocator metadata
+    metadata: usize,
+}
+
+#[cfg(all(unix, feature = "profiling"))]
+mod imp {
+    use std::time::Duration;
+
+    use actix_web::http::header::ContentType;
+    use actix_web::HttpResponse;
+    use itertools::Itertools;
+    use pprof::protos::Message;
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    use super::{HeapStats, ProfileFormat, ProfilingError};
+
+    pub async fn cpu_profile(
+        duration: Duration,
+        frequency: i32,
+        format: ProfileFormat,
+    ) -> Result<HttpResponse, ProfilingError> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(frequency)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|err| match err {
+                pprof::Error::Running => ProfilingError::Busy,
+                err => ProfilingError::Profiler(err.to_string()),
+            })?;
+        tokio::time::sleep(duration).await;
+        let report = guard
+            .report()
+            .build()
+            .map_err(|err| ProfilingError::Profiler(err.to_string()))?;
+
+        match format {
+            ProfileFormat::Pprof => {
+                let profile = report
+                    .pprof()
+                    .map_err(|err| ProfilingError::Profiler(err.to_string()))?;
+                let mut body = Vec::new();
+                profile
+                    .encode(&mut body)
+                    .map_err(|err| ProfilingError::Profiler(err.to_string()))?;
+                Ok(HttpResponse::Ok()
+                    .insert_header(ContentType::octet_stream())
+                    .body(body))
+            }
+            ProfileFormat::Collapsed => {
+                let body = report
+                    .data
+                    .iter()
+                    .map(|(frames, count)| {
+                        // root frame first, inlined symbols of a frame outermost first
+                        let stack = frames
+                            .frames
+                            .iter()
+                            .rev()
+                            .flat_map(|frame| frame.iter().rev())
+                            .map(|symbol| symbol.to_string())
+                            .join(";");
+                        format!("{};{stack} {count}", frames.thread_name)
+                    })
+                    .join("\n");
+                Ok(HttpResponse::Ok()
+                    .insert_header(ContentType::plaintext())
+                    .body(body))
+            }
+            ProfileFormat::Flamegraph => {
+                let mut body = Vec::new();
+                report
+                    .flamegraph(&mut body)
+                    .map_err(|err| ProfilingError::Profiler(err.to_string()))?;
+                Ok(HttpResponse::Ok()
+                    .insert_header(ContentType(mime::IMAGE_SVG))
+                    .body(body))
+            }
+        }
+    }
+
+    pub fn heap_stats() -> Result<HeapStats, ProfilingError> {
+        let to_error = |err: tikv_jemalloc_ctl::Error| ProfilingError::Allocator(err.to_string());
+        // statistics are cached by jemalloc till the epoch is advanced
+        epoch::advance().map_err(to_error)?;
+        Ok(HeapStats {
+            allocated: stats::allocated::read().map_err(to_error)?,
+            active: stats::active::read().map_err(to_error)?,
+            resident: stats::resident::read().map_err(to_error)?,
+            mapped: stats::mapped::read().map_err(to_error)?,
+            retained: stats::retained::read().map_err(to_error)?,
+            metadata: stats::metadata::read().map_err(to_error)?,
+        })
+    }
+}
+
+#[cfg(not(all(unix, feature = "profiling")))]
+mod imp {
+    use std::time::Duration;
+
+    use actix_web::HttpResponse;
+
+    use super::{HeapStats, ProfileFormat, ProfilingError};
+
+    pub async fn cpu_profile(
+        _: Duration,
+        _: i32,
+        _: ProfileFormat,
+    ) -> Result<HttpResponse, ProfilingError> {
+        Err(ProfilingError::NotBuilt)
+    }
+
+    pub fn heap_stats() -> Result<HeapStats, ProfilingError> {
+        Err(ProfilingError::NotBuilt)
+    }
+}
+
+// errors of the profiler are only raised by builds with the profiling feature, and only builds
+// without it raise `NotBuilt`
+#[allow(dead_code)]
+#[derive(Debug, thiserror::Error)]
+pub enum ProfilingError {
+    #[error("Profiling needs a unix build of the server with the profiling feature")]
+    NotBuilt,
+    #[error("Profile duration must be between 1 and {} seconds", MAX_PROFILE_DURATION.as_secs())]
+    InvalidDuration,
+    #[error("Sampling frequency must be positive")]
+    InvalidFrequency,
+    #[error("Another profile is being taken, try again once it is done")]
+    Busy,
+    #[error("Profiler failed: {0}")]
+    Profiler(String),
+    #[error("Could not read allocator statistics: {0}")]
+    Allocator(String),
+}
+
+impl actix_web::ResponseError for ProfilingError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProfilingError::NotBuilt => StatusCode::NOT_IMPLEMENTED,
+            ProfilingError::InvalidDuration | ProfilingError::InvalidFrequency => {
+                StatusCode::BAD_REQUEST
+            }
+            ProfilingError::Busy => StatusCode::CONFLICT,
+            ProfilingError::Profiler(_) | ProfilingError::Allocator(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web;
+    use http::StatusCode;
+
+    use super::{cpu_profile, heap_stats, ProfileFormat, ProfileQuery, ProfilingError};
+
+    fn query(seconds: u64, frequency: i32, format: ProfileFormat) -> web::Query<ProfileQuery> {
+        web::Query(ProfileQuery {
+            seconds: Some(seconds),
+            frequency: Some(frequency),
+            format,
+        })
+    }
+
+    #[actix_web::test]
+    async fn profile_parameters_are_validated() {
+        for (seconds, frequency) in [(0, 99), (301, 99), (1, 0)] {
+            let err = cpu_profile(query(seconds, frequency, ProfileFormat::Pprof))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                actix_web::ResponseError::status_code(&err),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[cfg(not(all(unix, feature = "profiling")))]
+    #[actix_web::test]
+    async fn builds_without_the_feature_do_not_profile() {
+        let profile = cpu_profile(query(1, 99, ProfileFormat::Pprof)).await;
+        assert!(matches!(profile, Err(ProfilingError::NotBuilt)));
+        assert!(matches!(heap_stats().await, Err(ProfilingError::NotBuilt)));
+    }
+
+    #[cfg(all(unix, feature = "profiling"))]
+    #[actix_web::test]
+    async fn profiles_and_heap_statistics_are_taken() {
+        let res = cpu_profile(query(1, 99, ProfileFormat::Collapsed))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // a second profile can not be taken while one runs
+        let (first, second) = futures::join!(
+            cpu_profile(query(1, 99, ProfileFormat::Pprof)),
+            cpu_profile(query(1, 99, ProfileFormat::Pprof))
+        );
+        assert!(first.is_ok() != second.is_ok());
+        assert!(matches!(
+            first.err().or(second.err()),
+            Some(ProfilingError::Busy)
+        ));
+
+        let res = heap_stats().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
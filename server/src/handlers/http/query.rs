@@ -16,37 +16,66 @@
  *
  */
 
-use actix_web::http::header::ContentType;
+use actix_web::http::header::{self, EntityTag, IfNoneMatch};
 use actix_web::web::{self, Json};
-use actix_web::{FromRequest, HttpRequest, Responder};
+use actix_web::{Either, FromRequest, HttpMessage, HttpRequest, HttpResponse, Responder};
 use chrono::{DateTime, Utc};
-use datafusion::common::tree_node::TreeNode;
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::SessionState;
+use datafusion::sql::parser::DFParser;
+use datafusion::sql::sqlparser::dialect::GenericDialect;
 use futures_util::Future;
 use http::StatusCode;
+use regex::Regex;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::event::error::EventError;
+use crate::external_tables;
 use crate::handlers::http::fetch_schema;
+use crate::handlers::http::problem::Problem;
+use crate::{catalog, stats};
 
-use crate::event::commit_schema;
-use crate::metrics::QUERY_EXECUTE_TIME;
+use crate::event::{commit_schema, DEFAULT_TIMESTAMP_KEY};
+use crate::metadata::STREAM_INFO;
 use crate::option::{Mode, CONFIG};
+use crate::query::active::{self, Killed};
+use crate::query::admission::{AdmissionError, QUERY_ADMISSION};
+use crate::query::comparison;
 use crate::query::error::ExecuteError;
-use crate::query::{TableScanVisitor, QUERY_SESSION};
+use crate::query::patterns::{Drain, Pattern};
+use crate::query::slow_log::{self, ExecutedQuery};
+use crate::query::{referenced_tables, renamed_columns, ScanStats, QUERY_SESSION};
 use crate::rbac::role::{Action, Permission};
 use crate::rbac::Users;
 use crate::response::QueryResponse;
 use crate::storage::object_storage::commit_schema_to_storage;
 use crate::storage::ObjectStorageError;
-use crate::utils::actix::extract_session_key_from_req;
+use crate::sync::DEFAULT_FLUSH_INTERVAL;
+use crate::utils::actix::{extract_session_key_from_req, request_username};
+use crate::STORAGE_UPLOAD_INTERVAL;
+
+// records of each stream are limited to these many in a correlation lookup
+const CORRELATION_RECORD_LIMIT: usize = 1000;
+// field added to correlated records to tell the stream they were found in
+const CORRELATION_STREAM_KEY: &str = "p_stream";
+
+// pattern mining looks at no more than these many events
+const PATTERN_EVENT_LIMIT: usize = 100_000;
+const PATTERN_SIMILARITY_THRESHOLD: f64 = 0.5;
+const PATTERN_SAMPLES: usize = 3;
+
+// queries of a batch run concurrently, more would crowd out other requests
+const MAX_BATCH_QUERIES: usize = 50;
 
 /// Query Request through http endpoint.
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Query {
     query: String,
@@ -54,23 +83,82 @@ pub struct Query {
     end_time: String,
     #[serde(default)]
     send_null: bool,
+    /// shift of the comparison window, e.g. `7d` compares with the same window a week earlier
+    #[serde(default)]
+    compare_offset: Option<String>,
+    /// query the stream as it was at a snapshot id or a rfc3339 timestamp
+    #[serde(default)]
+    at: Option<String>,
     #[serde(skip)]
     fields: bool,
     #[serde(skip)]
     filter_tags: Option<Vec<String>>,
+    /// skip the default filters of the streams
+    #[serde(skip)]
+    raw: bool,
 }
 
+/// Run a SQL query on a log stream
+#[utoipa::path(
+    post,
+    path = "/api/v1/query",
+    tag = "query",
+    request_body = Query,
+    params(
+        ("fields" = Option<bool>, Query, description = "Return the fields of the result along with the records"),
+        ("sendNull" = Option<bool>, Query, description = "Include fields which are null in the records"),
+        ("raw" = Option<bool>, Query, description = "Skip the default filter of the stream"),
+    ),
+    responses(
+        (status = 200, description = "Records matching the query", body = Vec<Object>),
+        (status = 400, description = "Invalid query or time range", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to query the stream", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 429, description = "Too many queries are running", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
 pub async fn query(req: HttpRequest, query_request: Query) -> Result<impl Responder, QueryError> {
+    let creds = extract_session_key_from_req(&req).expect("expects basic auth");
+    let permissions = Users.get_permissions(&creds);
+    let (query, table_name) = plan_query(&query_request, &permissions).await?;
+
+    // dashboards polling the same query get a 304 while the data it reads has not changed
+    let etag = result_etag(&query_request, &query, &table_name).await?;
+    if let Some(etag) = &etag {
+        if is_not_modified(req.get_header::<IfNoneMatch>(), etag) {
+            return Ok(Either::Left(
+                HttpResponse::NotModified()
+                    .insert_header(header::ETag(etag.clone()))
+                    .finish(),
+            ));
+        }
+    }
+
+    let username = Users.get_username_from_session(&creds).unwrap_or_default();
+    let response = run_query(&query_request, query, table_name, username).await?;
+
+    let mut response = web::Json(response).customize();
+    if let Some(etag) = etag {
+        response = response.insert_header(header::ETag(etag));
+    }
+    Ok(Either::Right(response))
+}
+
+// plans the query and checks the permissions on every table it reads, returns it with the
+// stream it reads
+async fn plan_query(
+    query_request: &Query,
+    permissions: &[Permission],
+) -> Result<(crate::query::Query, String), QueryError> {
     let session_state = QUERY_SESSION.state();
 
     // get the logical plan and extract the table name
-    let raw_logical_plan = session_state
-        .create_logical_plan(&query_request.query)
-        .await?;
-    // create a visitor to extract the table name
-    let mut visitor = TableScanVisitor::default();
-    let _ = raw_logical_plan.visit(&mut visitor);
-    let table_name = visitor.into_inner().pop().unwrap();
+    let raw_logical_plan =
+        renamed_columns::create_logical_plan(&session_state, &query_request.query).await?;
+    // the stream read by the query, external tables are not ingested so have no schema to fetch
+    let table_name = referenced_tables(&raw_logical_plan)
+        .into_iter()
+        .find(|table| !external_tables::exists(table))
+        .ok_or(QueryError::NoStream)?;
 
     if CONFIG.parseable.mode == Mode::Query {
         if let Ok(new_schema) = fetch_schema(&table_name).await {
@@ -82,65 +170,833 @@ pub async fn query(req: HttpRequest, query_request: Query) -> Result<impl Respon
         }
     }
 
-    let mut query = into_query(&query_request, &session_state).await?;
+    let mut query = into_query(query_request, &session_state).await?;
+
+    // check authorization of this query if it references physical table;
+    let tags = authorize_query(permissions, &table_name)?;
+    if !tags.is_empty() {
+        query.filter_tag = Some(tags)
+    }
+    for table in referenced_tables(&query.raw_logical_plan) {
+        if external_tables::exists(&table) {
+            authorize_query(permissions, &table)?;
+        }
+    }
+
+    Ok((query, table_name))
+}
+
+// executes the query once admitted, its records are returned as json. The query is listed
+// with the active queries till then.
+async fn run_query(
+    query_request: &Query,
+    query: crate::query::Query,
+    table_name: String,
+    username: String,
+) -> Result<Value, QueryError> {
+    let user = username.clone();
+    let stream = table_name.clone();
+    active::track(&user, &stream, &query_request.query, async move {
+        // wait for a free slot, held till the response is built
+        let _permit = QUERY_ADMISSION.admit(&username, &table_name).await?;
+
+        let time = Instant::now();
+        let window = (query.start, query.end);
+
+        let (response, stats, result_rows) = match &query_request.compare_offset {
+            Some(offset) => {
+                let offset = chrono::Duration::from_std(humantime::parse_duration(offset)?)?;
+                compare(query_request, query, table_name.clone(), offset).await?
+            }
+            None => {
+                let (records, fields, stats) = query.execute_with_stats(table_name.clone()).await?;
+                let rows = records.iter().map(|batch| batch.num_rows()).sum();
+                let response = QueryResponse {
+                    records,
+                    fields,
+                    fill_null: query_request.send_null,
+                    with_fields: query_request.fields,
+                }
+                .to_json();
+                (response, stats, rows)
+            }
+        };
+
+        slow_log::record(ExecutedQuery {
+            query: query_request.query.clone(),
+            stream: table_name,
+            user: username,
+            start_time: window.0,
+            end_time: window.1,
+            duration_ms: time.elapsed().as_millis() as u64,
+            scanned_files: stats.files,
+            scanned_bytes: stats.bytes,
+            result_rows: result_rows as u64,
+        });
+
+        Ok::<_, QueryError>(response)
+    })
+    .await?
+}
+
+/// Queries run in a single request, e.g. those of the panels of a dashboard
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub struct BatchQuery {
+    queries: Vec<Query>,
+}
+
+/// Records of a query of a batch, or the reason it failed
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct BatchResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    records: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Problem>,
+}
+
+// Handler for POST /api/v1/query/batch
+// queries run concurrently on the same session, each is planned, authorized and admitted as a
+// single query would be. A failed query does not fail the others, results are in the order of
+// the queries.
+#[utoipa::path(
+    post,
+    path = "/api/v1/query/batch",
+    tag = "query",
+    request_body = BatchQuery,
+    params(
+        ("fields" = Option<bool>, Query, description = "Return the fields of every result along with the records"),
+        ("sendNull" = Option<bool>, Query, description = "Include fields which are null in the records"),
+        ("raw" = Option<bool>, Query, description = "Skip the default filters of the streams"),
+    ),
+    responses(
+        (status = 200, description = "Records or error of every query", body = Vec<BatchResult>),
+        (status = 400, description = "Too many queries in the batch", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn batch(req: HttpRequest, body: Json<BatchQuery>) -> Result<impl Responder, QueryError> {
+    let params = web::Query::<HashMap<String, bool>>::from_query(req.query_string())
+        .map(|params| params.into_inner())
+        .unwrap_or_default();
 
     let creds = extract_session_key_from_req(&req).expect("expects basic auth");
     let permissions = Users.get_permissions(&creds);
+    let username = Users.get_username_from_session(&creds).unwrap_or_default();
 
-    // check authorization of this query if it references physical table;
-    let table_name = query.table_name();
-    if let Some(ref table) = table_name {
-        let mut authorized = false;
-        let mut tags = Vec::new();
-
-        // in permission check if user can run query on the stream.
-        // also while iterating add any filter tags for this stream
-        for permission in permissions {
-            match permission {
-                Permission::Stream(Action::All, _) => {
-                    authorized = true;
-                    break;
-                }
-                Permission::StreamWithTag(Action::Query, ref stream, tag)
-                    if stream == table || stream == "*" =>
-                {
-                    authorized = true;
-                    if let Some(tag) = tag {
-                        tags.push(tag)
-                    }
-                }
-                _ => (),
+    let results = run_batch(body.into_inner().queries, |mut query_request| {
+        query_request.fields = params.get("fields").cloned().unwrap_or(false);
+        query_request.send_null |= params.get("sendNull").cloned().unwrap_or(false);
+        query_request.raw = params.get("raw").cloned().unwrap_or(false);
+        let permissions = &permissions;
+        let username = username.clone();
+        async move {
+            let (query, table_name) = plan_query(&query_request, permissions).await?;
+            run_query(&query_request, query, table_name, username).await
+        }
+    })
+    .await?;
+    Ok(web::Json(results))
+}
+
+// runs the queries of a batch concurrently, with the result of each in the order of the queries
+async fn run_batch<F, Fut>(queries: Vec<Query>, run: F) -> Result<Vec<BatchResult>, QueryError>
+where
+    F: FnMut(Query) -> Fut,
+    Fut: Future<Output = Result<Value, QueryError>>,
+{
+    if queries.len() > MAX_BATCH_QUERIES {
+        return Err(QueryError::BatchTooLarge(queries.len()));
+    }
+    let results = futures::future::join_all(queries.into_iter().map(run)).await;
+
+    Ok(results
+        .into_iter()
+        .map(|result| match result {
+            Ok(records) => BatchResult {
+                records: Some(records),
+                error: None,
+            },
+            Err(err) => BatchResult {
+                records: None,
+                error: Some(err.problem()),
+            },
+        })
+        .collect())
+}
+
+// Handler for GET /api/v1/query/active
+// queries this server has queued or is running, with the bytes they have scanned so far
+pub async fn list_active() -> impl Responder {
+    web::Json(active::list())
+}
+
+// Handler for DELETE /api/v1/query/active/{id}
+pub async fn kill_active(
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<impl Responder, QueryError> {
+    let id = id.into_inner();
+    let query_id = id.parse().map_err(|_| QueryError::NotActive(id.clone()))?;
+    if !active::kill(query_id) {
+        return Err(QueryError::NotActive(id));
+    }
+
+    log::warn!(
+        target: "audit",
+        "query {id} killed by {}",
+        request_username(&req)
+    );
+    Ok((format!("killed query {id}"), StatusCode::OK))
+}
+
+/// Query request as the parameters of a GET request
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryParams {
+    q: String,
+    from: String,
+    #[serde(default = "default_query_end")]
+    to: String,
+    #[serde(default)]
+    send_null: bool,
+    #[serde(default)]
+    fields: bool,
+    #[serde(default)]
+    raw: bool,
+    #[serde(default)]
+    at: Option<String>,
+}
+
+fn default_query_end() -> String {
+    "now".to_string()
+}
+
+impl From<QueryParams> for Query {
+    fn from(params: QueryParams) -> Self {
+        Query {
+            query: params.q,
+            start_time: params.from,
+            end_time: params.to,
+            send_null: params.send_null,
+            compare_offset: None,
+            at: params.at,
+            fields: params.fields,
+            filter_tags: None,
+            raw: params.raw,
+        }
+    }
+}
+
+/// Run a SQL query on a log stream, passed in the url for clients which can not send a body
+#[utoipa::path(
+    get,
+    path = "/api/v1/query",
+    tag = "query",
+    params(
+        ("q" = String, Query, description = "SQL query"),
+        ("from" = String, Query, description = "Start of the time range, a rfc3339 timestamp or a duration before now, e.g. `1h`"),
+        ("to" = Option<String>, Query, description = "End of the time range, a rfc3339 timestamp or `now`, the default"),
+        ("fields" = Option<bool>, Query, description = "Return the fields of the result along with the records"),
+        ("sendNull" = Option<bool>, Query, description = "Include fields which are null in the records"),
+        ("raw" = Option<bool>, Query, description = "Skip the default filter of the stream"),
+        ("at" = Option<String>, Query, description = "Query the stream as it was at a snapshot id or a rfc3339 timestamp"),
+    ),
+    responses(
+        (status = 200, description = "Records matching the query", body = Vec<Object>),
+        (status = 304, description = "Records did not change since the request with the ETag in If-None-Match"),
+        (status = 400, description = "Invalid query or time range", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Not allowed to query the stream", body = crate::handlers::http::problem::Problem, content_type = "application/problem+json"),
+    )
+)]
+pub async fn query_get(
+    req: HttpRequest,
+    params: web::Query<QueryParams>,
+) -> Result<HttpResponse, QueryError> {
+    let mut response = query(req.clone(), params.into_inner().into())
+        .await?
+        .respond_to(&req)
+        .map_into_boxed_body();
+    // caches keep the records of each user apart and check their ETag before reusing them
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        header::HeaderValue::from_static("private, no-cache"),
+    );
+    response.headers_mut().insert(
+        header::VARY,
+        header::HeaderValue::from_static("Authorization, Cookie"),
+    );
+    Ok(response)
+}
+
+// Identifies the result of the query for as long as the data it reads does not change: the
+// request, the resolved time range and the manifests of the stream, plus the events this node
+// has ingested which may still be in staging. None if that can not be known without executing
+// the query. Windows relative to now resolve to a new range on every request.
+async fn result_etag(
+    query_request: &Query,
+    query: &crate::query::Query,
+    table_name: &str,
+) -> Result<Option<EntityTag>, QueryError> {
+    // external tables change without parseable knowing
+    if referenced_tables(&query.raw_logical_plan)
+        .iter()
+        .any(|table| external_tables::exists(table))
+    {
+        return Ok(None);
+    }
+
+    let staged_events = if CONFIG.parseable.mode == Mode::Query {
+        // events in the staging of ingesters are not known here, windows which may still have
+        // some are not tagged. late events of time partitioned streams can be staged for any window
+        if STREAM_INFO
+            .get_time_partition(table_name)
+            .map_or(true, |partition| partition.is_some())
+        {
+            return Ok(None);
+        }
+        let flush_interval = STREAM_INFO
+            .get_flush_interval(table_name)
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_FLUSH_INTERVAL);
+        if may_be_staged(query.end, flush_interval, Utc::now())? {
+            return Ok(None);
+        }
+        0
+    } else {
+        stats::get_current_stats(table_name, "json").map_or(0, |stats| stats.events)
+    };
+
+    let manifests = catalog::get_manifest_list(CONFIG.storage().get_object_store(), table_name)
+        .await?
+        .into_iter()
+        .map(|item| item.manifest_path)
+        .collect();
+
+    let key = ResultKey {
+        query: &query_request.query,
+        start: query.start,
+        end: query.end,
+        send_null: query_request.send_null,
+        fields: query_request.fields,
+        compare_offset: query_request.compare_offset.as_deref(),
+        at: query_request.at.as_deref(),
+        tags: query.filter_tag.as_deref(),
+        raw: query.raw,
+        default_filter: STREAM_INFO.get_default_filter(table_name).ok().flatten(),
+        manifests,
+        staged_events,
+    };
+    Ok(Some(key.etag()))
+}
+
+// Whether events of a window ending at `end` may still be in the staging of an ingester
+fn may_be_staged(
+    end: DateTime<Utc>,
+    flush_interval: Duration,
+    now: DateTime<Utc>,
+) -> Result<bool, QueryError> {
+    let staging_window = flush_interval + Duration::from_secs(STORAGE_UPLOAD_INTERVAL as u64);
+    Ok(end + chrono::Duration::from_std(staging_window)? > now)
+}
+
+// A cached result may be used when one of its tags matches, `*` matches any result
+fn is_not_modified(if_none_match: Option<IfNoneMatch>, etag: &EntityTag) -> bool {
+    match if_none_match {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => false,
+    }
+}
+
+// Everything the result of a query depends on, see result_etag
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResultKey<'a> {
+    query: &'a str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    send_null: bool,
+    fields: bool,
+    compare_offset: Option<&'a str>,
+    at: Option<&'a str>,
+    tags: Option<&'a [String]>,
+    raw: bool,
+    default_filter: Option<String>,
+    manifests: Vec<String>,
+    staged_events: u64,
+}
+
+impl ResultKey<'_> {
+    fn etag(mut self) -> EntityTag {
+        // the order manifests are listed in does not change the result
+        self.manifests.sort();
+        let key = serde_json::to_vec(&self).expect("result key is serializable");
+        let hash = xxhash_rust::xxh3::xxh3_64(&key);
+        EntityTag::new_strong(format!("{hash:016x}"))
+    }
+}
+
+// runs the query over its window and over the window shifted back by `offset`,
+// rows of both are joined with the comparison values in `<column>_previous`
+async fn compare(
+    query_request: &Query,
+    query: crate::query::Query,
+    table_name: String,
+    offset: chrono::Duration,
+) -> Result<(Value, ScanStats, usize), QueryError> {
+    let previous = crate::query::Query {
+        raw_logical_plan: query.raw_logical_plan.clone(),
+        start: query.start - offset,
+        end: query.end - offset,
+        filter_tag: query.filter_tag.clone(),
+        at: query.at,
+        raw: query.raw,
+    };
+    let ((current, _, current_stats), (previous, _, previous_stats)) = futures::future::try_join(
+        query.execute_with_stats(table_name.clone()),
+        previous.execute_with_stats(table_name),
+    )
+    .await?;
+    let stats = ScanStats {
+        files: current_stats.files + previous_stats.files,
+        bytes: current_stats.bytes + previous_stats.bytes,
+    };
+
+    let to_query_error = |err: ArrowError| QueryError::Datafusion(err.into());
+    let previous = previous
+        .iter()
+        .map(|batch| comparison::shift_timestamps(batch, offset))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(to_query_error)?;
+    let (fields, mut rows) = comparison::align(&current, &previous).map_err(to_query_error)?;
+
+    if query_request.send_null {
+        for row in &mut rows {
+            for field in &fields {
+                row.entry(field.clone()).or_insert(Value::Null);
             }
         }
+    }
+    let rows: Vec<Value> = rows.into_iter().map(Value::Object).collect();
+    let result_rows = rows.len();
+
+    let response = if query_request.fields {
+        serde_json::json!({
+            "fields": fields,
+            "records": rows,
+        })
+    } else {
+        Value::Array(rows)
+    };
+    Ok((response, stats, result_rows))
+}
+
+/// Query validation request through http endpoint.
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub struct ValidateQuery {
+    query: String,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct ValidatedStatement {
+    tables: Vec<String>,
+    fields: Vec<String>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct ValidationError {
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statement: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<u64>,
+}
+
+impl ValidationError {
+    fn new(statement: Option<usize>, message: String) -> Self {
+        // sqlparser reports the location of tokenizer and parser errors as
+        // "at Line: x, Column y", pick it up so editors can point at it
+        let position: Option<(u64, u64)> = Regex::new(r"Line: (\d+), Column:? (\d+)")
+            .expect("valid regex")
+            .captures(&message)
+            .and_then(|cap| Some((cap[1].parse().ok()?, cap[2].parse().ok()?)));
+
+        Self {
+            statement,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct ValidationResponse {
+    valid: bool,
+    statements: Vec<ValidatedStatement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ValidationError>,
+}
+
+impl ValidationResponse {
+    fn invalid(statements: Vec<ValidatedStatement>, error: ValidationError) -> Self {
+        Self {
+            valid: false,
+            statements,
+            error: Some(error),
+        }
+    }
+}
+
+// Handler for POST /api/v1/query/validate
+// parses, plans and authorizes every statement in the request without executing it
+#[utoipa::path(
+    post,
+    path = "/api/v1/query/validate",
+    tag = "query",
+    request_body = ValidateQuery,
+    responses(
+        (status = 200, description = "Whether every statement is valid, with the tables and fields it reads", body = ValidationResponse),
+    )
+)]
+pub async fn validate(
+    req: HttpRequest,
+    body: Json<ValidateQuery>,
+) -> Result<impl Responder, QueryError> {
+    let sql = body.into_inner().query;
+    if sql.trim().is_empty() {
+        return Err(QueryError::EmptyQuery);
+    }
+
+    let session_state = QUERY_SESSION.state();
+    let creds = extract_session_key_from_req(&req).expect("expects basic auth");
+    let permissions = Users.get_permissions(&creds);
 
-        if !authorized {
-            return Err(QueryError::Unauthorized);
+    let statements = match DFParser::parse_sql_with_dialect(&sql, &GenericDialect {}) {
+        Ok(statements) => statements,
+        Err(err) => {
+            let error = ValidationError::new(None, DataFusionError::SQL(err).to_string());
+            return Ok(web::Json(ValidationResponse::invalid(vec![], error)));
         }
+    };
+
+    let mut validated = Vec::with_capacity(statements.len());
+    for (index, mut statement) in statements.into_iter().enumerate() {
+        renamed_columns::rewrite_statement(&mut statement);
+        let plan = match session_state.statement_to_plan(statement).await {
+            Ok(plan) => plan,
+            Err(err) => {
+                let error = ValidationError::new(Some(index), err.to_string());
+                return Ok(web::Json(ValidationResponse::invalid(validated, error)));
+            }
+        };
 
-        if !tags.is_empty() {
-            query.filter_tag = Some(tags)
+        let tables = referenced_tables(&plan);
+        if let Some(table) = tables
+            .iter()
+            .find(|table| authorize_query(&permissions, table).is_err())
+        {
+            let error =
+                ValidationError::new(Some(index), format!("Unauthorized to query stream {table}"));
+            return Ok(web::Json(ValidationResponse::invalid(validated, error)));
         }
+
+        let fields = plan
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().to_owned())
+            .collect();
+        validated.push(ValidatedStatement { tables, fields });
+    }
+
+    Ok(web::Json(ValidationResponse {
+        valid: true,
+        statements: validated,
+        error: None,
+    }))
+}
+
+/// Correlation request through http endpoint.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CorrelateQuery {
+    #[serde(rename = "trace_id")]
+    trace_id: String,
+    #[serde(default = "default_correlation_start")]
+    start_time: String,
+    #[serde(default = "default_correlation_end")]
+    end_time: String,
+}
+
+fn default_correlation_start() -> String {
+    "1d".to_string()
+}
+
+fn default_correlation_end() -> String {
+    "now".to_string()
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CorrelateResponse {
+    streams: Vec<String>,
+    records: Vec<Map<String, Value>>,
+}
+
+// Handler for GET /api/v1/correlate?trace_id={id}
+// looks up the id in every correlated stream the user can query and
+// returns the matching records of all streams ordered by time
+pub async fn correlate(
+    req: HttpRequest,
+    query: web::Query<CorrelateQuery>,
+) -> Result<impl Responder, QueryError> {
+    let CorrelateQuery {
+        trace_id,
+        start_time,
+        end_time,
+    } = query.into_inner();
+    if trace_id.is_empty() {
+        return Err(QueryError::EmptyTraceId);
     }
+    let (start, end) = parse_time_range(&start_time, &end_time)?;
 
-    let time = Instant::now();
+    let creds = extract_session_key_from_req(&req).expect("expects basic auth");
+    let permissions = Users.get_permissions(&creds);
+    let username = Users.get_username_from_session(&creds).unwrap_or_default();
 
-    let (records, fields) = query.execute(table_name.clone().unwrap()).await?;
-    let response = QueryResponse {
+    let column = &CONFIG.parseable.correlation_id_column;
+    let streams = if CONFIG.parseable.correlation_streams.is_empty() {
+        STREAM_INFO.list_streams()
+    } else {
+        CONFIG.parseable.correlation_streams.clone()
+    };
+    let streams = correlated_streams(streams, column, &permissions);
+
+    let lookups = streams.iter().map(|(stream, tags)| {
+        correlate_stream(stream, tags, column, &trace_id, start, end, &username)
+    });
+    let records = merge_correlated(futures::future::try_join_all(lookups).await?);
+
+    Ok(web::Json(CorrelateResponse {
+        streams: streams.into_iter().map(|(stream, _)| stream).collect(),
         records,
-        fields,
-        fill_null: query_request.send_null,
-        with_fields: query_request.fields,
+    }))
+}
+
+// streams which have the id column and the user is allowed to query, with the tags the
+// records of each stream are filtered on
+fn correlated_streams(
+    streams: Vec<String>,
+    column: &str,
+    permissions: &[Permission],
+) -> Vec<(String, Vec<String>)> {
+    streams
+        .into_iter()
+        .filter_map(|stream| {
+            STREAM_INFO
+                .schema(&stream)
+                .ok()?
+                .field_with_name(column)
+                .ok()?;
+            let tags = authorize_query(permissions, &stream).ok()?;
+            Some((stream, tags))
+        })
+        .collect()
+}
+
+// records of all streams in time order, timestamps of all streams are formatted alike
+fn merge_correlated(records: Vec<Vec<Map<String, Value>>>) -> Vec<Map<String, Value>> {
+    let mut records: Vec<_> = records.into_iter().flatten().collect();
+    records.sort_by(|a, b| {
+        let time = |record: &Map<String, Value>| {
+            record
+                .get(DEFAULT_TIMESTAMP_KEY)
+                .and_then(Value::as_str)
+                .map(str::to_owned)
+        };
+        time(a).cmp(&time(b))
+    });
+    records
+}
+
+async fn correlate_stream(
+    stream: &str,
+    tags: &[String],
+    column: &str,
+    id: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    username: &str,
+) -> Result<Vec<Map<String, Value>>, QueryError> {
+    let _permit = QUERY_ADMISSION.admit(username, stream).await?;
+
+    let sql = format!(
+        "SELECT * FROM \"{stream}\" WHERE \"{column}\" = '{}' ORDER BY {DEFAULT_TIMESTAMP_KEY} LIMIT {CORRELATION_RECORD_LIMIT}",
+        id.replace('\'', "''")
+    );
+    let query = crate::query::Query {
+        raw_logical_plan: QUERY_SESSION.state().create_logical_plan(&sql).await?,
+        start,
+        end,
+        filter_tag: (!tags.is_empty()).then(|| tags.to_vec()),
+        at: None,
+        raw: false,
+    };
+    let (records, _) = query.execute(stream.to_owned()).await?;
+
+    let records: Vec<&RecordBatch> = records.iter().collect();
+    let mut records =
+        record_batches_to_json_rows(&records).map_err(|err| QueryError::Datafusion(err.into()))?;
+    for record in records.iter_mut() {
+        record.insert(
+            CORRELATION_STREAM_KEY.to_owned(),
+            Value::String(stream.to_owned()),
+        );
     }
-    .to_http();
+    Ok(records)
+}
+
+/// Pattern mining request through http endpoint.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatternsRequest {
+    start_time: String,
+    end_time: String,
+    #[serde(default = "default_pattern_column")]
+    column: String,
+    #[serde(default = "default_max_patterns")]
+    max_patterns: usize,
+}
+
+fn default_pattern_column() -> String {
+    "message".to_string()
+}
+
+fn default_max_patterns() -> usize {
+    50
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PatternsResponse {
+    events: usize,
+    patterns: Vec<Pattern>,
+}
 
-    if let Some(table) = table_name {
-        let time = time.elapsed().as_secs_f64();
-        QUERY_EXECUTE_TIME
-            .with_label_values(&[&table])
-            .observe(time);
+// Handler for POST /api/v1/logstream/{logstream}/patterns
+// clusters the values of a column in the time range by message template
+pub async fn patterns(
+    req: HttpRequest,
+    body: Json<PatternsRequest>,
+) -> Result<impl Responder, QueryError> {
+    let stream_name: String = req.match_info().get("logstream").unwrap().parse().unwrap();
+    let body = body.into_inner();
+    let (start, end) = parse_time_range(&body.start_time, &body.end_time)?;
+
+    let creds = extract_session_key_from_req(&req).expect("expects basic auth");
+    let permissions = Users.get_permissions(&creds);
+    let tags = authorize_query(&permissions, &stream_name)?;
+    let username = Users.get_username_from_session(&creds).unwrap_or_default();
+    let _permit = QUERY_ADMISSION.admit(&username, &stream_name).await?;
+
+    // the column is quoted into the query, so only names of the stream's fields are taken
+    let known = STREAM_INFO
+        .schema(&stream_name)
+        .is_ok_and(|schema| schema.field_with_name(&body.column).is_ok());
+    if !known {
+        return Err(QueryError::UnknownColumn(body.column));
     }
 
-    Ok(response)
+    let sql = format!(
+        "SELECT \"{column}\" FROM \"{stream_name}\" WHERE \"{column}\" IS NOT NULL LIMIT {PATTERN_EVENT_LIMIT}",
+        column = body.column
+    );
+    let query = crate::query::Query {
+        raw_logical_plan: QUERY_SESSION.state().create_logical_plan(&sql).await?,
+        start,
+        end,
+        filter_tag: (!tags.is_empty()).then_some(tags),
+        at: None,
+        raw: false,
+    };
+    let (records, _) = query.execute(stream_name).await?;
+
+    let records: Vec<&RecordBatch> = records.iter().collect();
+    let rows =
+        record_batches_to_json_rows(&records).map_err(|err| QueryError::Datafusion(err.into()))?;
+
+    let mut drain = Drain::new(PATTERN_SIMILARITY_THRESHOLD, PATTERN_SAMPLES);
+    for row in &rows {
+        match row.get(&body.column) {
+            Some(Value::String(message)) => drain.add(message),
+            Some(Value::Null) | None => (),
+            Some(value) => drain.add(&value.to_string()),
+        }
+    }
+    let mut patterns = drain.into_patterns();
+    patterns.truncate(body.max_patterns);
+
+    Ok(web::Json(PatternsResponse {
+        events: rows.len(),
+        patterns,
+    }))
+}
+
+/// Checks if the given permissions allow running a query on the table.
+/// Returns the tag filters that need to be applied for this table.
+pub(crate) fn authorize_query(
+    permissions: &[Permission],
+    table: &str,
+) -> Result<Vec<String>, QueryError> {
+    // external tables have no tags, they are readable by everyone allowed to read them
+    if external_tables::exists(table) {
+        return can_read_external_table(permissions, table)
+            .then(Vec::new)
+            .ok_or(QueryError::Unauthorized);
+    }
+
+    let mut authorized = false;
+    let mut tags = Vec::new();
+
+    // in permission check if user can run query on the stream.
+    // also while iterating add any filter tags for this stream
+    for permission in permissions {
+        match permission {
+            Permission::Stream(Action::All, _) => {
+                authorized = true;
+                break;
+            }
+            Permission::StreamWithTag(Action::Query, stream, tag)
+                if stream == table || stream == "*" =>
+            {
+                authorized = true;
+                if let Some(tag) = tag {
+                    tags.push(tag.to_owned())
+                }
+            }
+            _ => (),
+        }
+    }
+
+    if !authorized {
+        return Err(QueryError::Unauthorized);
+    }
+
+    Ok(tags)
+}
+
+/// Checks if the given permissions allow reading the external table, the tables share the
+/// namespace of streams so a table is granted to a role the way a stream is
+pub(crate) fn can_read_external_table(permissions: &[Permission], table: &str) -> bool {
+    permissions.iter().any(|permission| {
+        matches!(
+            permission,
+            Permission::Stream(Action::All | Action::GetExternalTable, stream)
+                if stream == table || stream == "*"
+        )
+    })
 }
 
 impl FromRequest for Query {
@@ -162,6 +1018,7 @@ impl FromRequest for Query {
             if !query.send_null {
                 query.send_null = params.get("sendNull").cloned().unwrap_or(false);
             }
+            query.raw = params.get("raw").cloned().unwrap_or(false);
 
             Ok(query)
         };
@@ -186,17 +1043,51 @@ async fn into_query(
         return Err(QueryError::EmptyEndTime);
     }
 
+    let (start, end) = parse_time_range(&query.start_time, &query.end_time)?;
+    let at = query
+        .at
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(QueryError::InvalidSnapshot)?;
+
+    Ok(crate::query::Query {
+        raw_logical_plan: renamed_columns::create_logical_plan(session_state, &query.query).await?,
+        start,
+        end,
+        filter_tag: query.filter_tags.clone(),
+        at,
+        raw: query.raw,
+    })
+}
+
+// start and end time are either rfc3339 timestamps, or a duration or timestamp and "now"
+fn parse_time_range(
+    start_time: &str,
+    end_time: &str,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), QueryError> {
+    time_range_at(start_time, end_time, Utc::now())
+}
+
+fn time_range_at(
+    start_time: &str,
+    end_time: &str,
+    now: DateTime<Utc>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), QueryError> {
     let start: DateTime<Utc>;
     let end: DateTime<Utc>;
 
-    if query.end_time == "now" {
-        end = Utc::now();
-        start = end - chrono::Duration::from_std(humantime::parse_duration(&query.start_time)?)?;
+    if end_time == "now" {
+        end = now;
+        start = match DateTime::parse_from_rfc3339(start_time) {
+            Ok(start) => start.into(),
+            Err(_) => end - chrono::Duration::from_std(humantime::parse_duration(start_time)?)?,
+        };
     } else {
-        start = DateTime::parse_from_rfc3339(&query.start_time)
+        start = DateTime::parse_from_rfc3339(start_time)
             .map_err(|_| QueryError::StartTimeParse)?
             .into();
-        end = DateTime::parse_from_rfc3339(&query.end_time)
+        end = DateTime::parse_from_rfc3339(end_time)
             .map_err(|_| QueryError::EndTimeParse)?
             .into();
     };
@@ -205,12 +1096,7 @@ async fn into_query(
         return Err(QueryError::StartTimeAfterEndTime);
     }
 
-    Ok(crate::query::Query {
-        raw_logical_plan: session_state.create_logical_plan(&query.query).await?,
-        start,
-        end,
-        filter_tag: query.filter_tags.clone(),
-    })
+    Ok((start, end))
 }
 
 /// unused for now, might need it in the future
@@ -243,6 +1129,9 @@ fn transform_query_for_ingester(query: &Query) -> Option<Query> {
         fields: false,
         filter_tags: query.filter_tags.clone(),
         send_null: query.send_null,
+        compare_offset: None,
+        at: None,
+        raw: query.raw,
         start_time: start_time.to_rfc3339(),
         end_time: end_time.to_rfc3339(),
     };
@@ -254,6 +1143,8 @@ fn transform_query_for_ingester(query: &Query) -> Option<Query> {
 pub enum QueryError {
     #[error("Query cannot be empty")]
     EmptyQuery,
+    #[error("trace_id cannot be empty")]
+    EmptyTraceId,
     #[error("Start time cannot be empty")]
     EmptyStartTime,
     #[error("End time cannot be empty")]
@@ -266,6 +1157,10 @@ pub enum QueryError {
     NotValidDuration(#[from] humantime::DurationError),
     #[error("Parsed duration out of range")]
     OutOfRange(#[from] chrono::OutOfRangeError),
+    #[error("Invalid snapshot: {0}")]
+    InvalidSnapshot(String),
+    #[error("Query must read from a log stream")]
+    NoStream,
     #[error("Start time cannot be greater than the end time")]
     StartTimeAfterEndTime,
     #[error("Unauthorized")]
@@ -278,19 +1173,387 @@ pub enum QueryError {
     ObjectStorage(#[from] ObjectStorageError),
     #[error("Evern Error: {0}")]
     EventError(#[from] EventError),
+    #[error("{0}")]
+    Admission(#[from] AdmissionError),
+    #[error("A batch can have at most {MAX_BATCH_QUERIES} queries, it has {0}")]
+    BatchTooLarge(usize),
+    #[error("{0}")]
+    Killed(#[from] Killed),
+    #[error("Query {0} is not running")]
+    NotActive(String),
+    #[error("Log stream has no column {0}")]
+    UnknownColumn(String),
 }
 
 impl actix_web::ResponseError for QueryError {
     fn status_code(&self) -> http::StatusCode {
         match self {
             QueryError::Execute(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            QueryError::Admission(_) => StatusCode::TOO_MANY_REQUESTS,
+            QueryError::Killed(_) => StatusCode::CONFLICT,
+            QueryError::NotActive(_) => StatusCode::NOT_FOUND,
             _ => StatusCode::BAD_REQUEST,
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        actix_web::HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
+        self.problem().response()
+    }
+}
+
+impl QueryError {
+    fn problem(&self) -> Problem {
+        Problem::new(actix_web::ResponseError::status_code(self), self)
+            .code(self.error_code())
+            .retriable_if(matches!(self, QueryError::ObjectStorage(_)))
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            QueryError::EmptyQuery => "empty_query",
+            QueryError::EmptyTraceId => "empty_trace_id",
+            QueryError::EmptyStartTime => "empty_start_time",
+            QueryError::EmptyEndTime => "empty_end_time",
+            QueryError::StartTimeParse => "invalid_start_time",
+            QueryError::EndTimeParse => "invalid_end_time",
+            QueryError::NotValidDuration(_) => "invalid_duration",
+            QueryError::OutOfRange(_) => "duration_out_of_range",
+            QueryError::InvalidSnapshot(_) => "invalid_snapshot",
+            QueryError::NoStream => "no_stream",
+            QueryError::StartTimeAfterEndTime => "start_after_end",
+            QueryError::Unauthorized => "unauthorized",
+            QueryError::Datafusion(_) => "invalid_query",
+            QueryError::Execute(_) => "execution_failed",
+            QueryError::ObjectStorage(_) => "storage_error",
+            QueryError::EventError(_) => "event_error",
+            QueryError::Admission(_) => "too_many_queries",
+            QueryError::BatchTooLarge(_) => "batch_too_large",
+            QueryError::Killed(_) => "query_killed",
+            QueryError::NotActive(_) => "query_not_active",
+            QueryError::UnknownColumn(_) => "unknown_column",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::datatypes::{DataType, Field};
+    use serde_json::{json, Map, Value};
+
+    use actix_web::http::header::{EntityTag, IfNoneMatch};
+    use chrono::{TimeZone, Utc};
+
+    use super::{
+        can_read_external_table, correlated_streams, is_not_modified, may_be_staged,
+        merge_correlated, run_batch, time_range_at, Query, QueryError, QueryParams, ResultKey,
+        ValidationError, MAX_BATCH_QUERIES,
+    };
+    use crate::metadata::{LogStreamMetadata, STREAM_INFO};
+    use crate::rbac::role::{model::DefaultPrivilege, Action, Permission, RoleBuilder};
+
+    #[test]
+    fn validation_error_with_position() {
+        let err = ValidationError::new(
+            Some(0),
+            "SQL error: ParserError(\"Expected end of statement, found: FORM at Line: 1, Column 10\")"
+                .to_string(),
+        );
+        assert_eq!(err.line, Some(1));
+        assert_eq!(err.column, Some(10));
+    }
+
+    #[test]
+    fn validation_error_without_position() {
+        let err = ValidationError::new(Some(1), "table 'app' not found".to_string());
+        assert_eq!(err.statement, Some(1));
+        assert_eq!(err.line, None);
+        assert_eq!(err.column, None);
+    }
+
+    #[test]
+    fn time_range_up_to_now_starts_at_a_timestamp_or_a_duration_ago() {
+        let now = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(
+            time_range_at("2024-01-01T00:00:00Z", "now", now).unwrap(),
+            (start, now)
+        );
+        assert_eq!(time_range_at("1d", "now", now).unwrap(), (start, now));
+        assert_eq!(
+            time_range_at("2024-01-01T00:00:00Z", "2024-01-02T00:00:00Z", now).unwrap(),
+            (start, now)
+        );
+
+        assert!(matches!(
+            time_range_at("2024-01-03T00:00:00Z", "now", now),
+            Err(QueryError::StartTimeAfterEndTime)
+        ));
+        assert!(time_range_at("yesterday", "now", now).is_err());
+        assert!(matches!(
+            time_range_at("1d", "2024-01-02T00:00:00Z", now),
+            Err(QueryError::StartTimeParse)
+        ));
+    }
+
+    #[test]
+    fn get_query_ends_now_by_default() {
+        let params = actix_web::web::Query::<QueryParams>::from_query(
+            "q=select%20*%20from%20app&from=2024-01-01T00:00:00Z",
+        )
+        .unwrap()
+        .into_inner();
+        let query = Query::from(params);
+        assert_eq!(query.start_time, "2024-01-01T00:00:00Z");
+        assert_eq!(query.end_time, "now");
+    }
+
+    fn batch_query(query: &str) -> Query {
+        serde_json::from_value(json!({"query": query, "startTime": "1h", "endTime": "now"}))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn batch_results_are_in_the_order_of_the_queries() {
+        let queries = ["slow", "", "fast"].map(batch_query).into();
+        let results = run_batch(queries, |query| async move {
+            // later queries finish first
+            let delay = match query.query.as_str() {
+                "slow" => 20,
+                _ => 0,
+            };
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+            if query.query.is_empty() {
+                return Err(QueryError::EmptyQuery);
+            }
+            Ok(json!([{ "query": query.query }]))
+        })
+        .await
+        .unwrap();
+
+        let results = serde_json::to_value(results).unwrap();
+        assert_eq!(results[0], json!({"records": [{"query": "slow"}]}));
+        assert_eq!(results[1]["error"]["code"], "empty_query");
+        assert!(results[1].get("records").is_none());
+        assert_eq!(results[2], json!({"records": [{"query": "fast"}]}));
+    }
+
+    #[tokio::test]
+    async fn batches_are_limited_in_size() {
+        let ran = std::sync::atomic::AtomicUsize::new(0);
+        let run = |_| {
+            ran.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            async { Ok(json!([])) }
+        };
+
+        let queries = (0..=MAX_BATCH_QUERIES).map(|_| batch_query("q")).collect();
+        assert!(matches!(
+            run_batch(queries, run).await,
+            Err(QueryError::BatchTooLarge(count)) if count == MAX_BATCH_QUERIES + 1
+        ));
+        assert_eq!(ran.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        let queries = (0..MAX_BATCH_QUERIES).map(|_| batch_query("q")).collect();
+        assert_eq!(
+            run_batch(queries, run).await.unwrap().len(),
+            MAX_BATCH_QUERIES
+        );
+        assert_eq!(
+            ran.load(std::sync::atomic::Ordering::Relaxed),
+            MAX_BATCH_QUERIES
+        );
+    }
+
+    fn result_key() -> ResultKey<'static> {
+        ResultKey {
+            query: "select * from app",
+            start: Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2024, 1, 1, 1, 0, 0).unwrap(),
+            send_null: false,
+            fields: false,
+            compare_offset: None,
+            at: None,
+            tags: None,
+            raw: false,
+            default_filter: None,
+            manifests: vec!["a.manifest.json".to_string(), "b.manifest.json".to_string()],
+            staged_events: 10,
+        }
+    }
+
+    #[test]
+    fn result_etag_changes_with_its_inputs() {
+        let etag = result_key().etag();
+        assert_eq!(result_key().etag(), etag);
+        // manifests listed in another order are the same data
+        let mut key = result_key();
+        key.manifests.reverse();
+        assert_eq!(key.etag(), etag);
+
+        let tags = ["team=payments".to_string()];
+        let changes: Vec<fn(&mut ResultKey<'static>)> = vec![
+            |key| key.query = "select count(*) from app",
+            |key| key.start = Utc.with_ymd_and_hms(2024, 1, 1, 0, 30, 0).unwrap(),
+            |key| key.end = Utc.with_ymd_and_hms(2024, 1, 1, 2, 0, 0).unwrap(),
+            |key| key.send_null = true,
+            |key| key.fields = true,
+            |key| key.compare_offset = Some("1d"),
+            |key| key.at = Some("42"),
+            |key| key.raw = true,
+            |key| key.default_filter = Some("level = 'error'".to_string()),
+            // a flush added a manifest
+            |key| key.manifests.push("c.manifest.json".to_string()),
+            // a merge replaced the manifests
+            |key| key.manifests = vec!["c.manifest.json".to_string()],
+            // events were ingested into staging
+            |key| key.staged_events += 1,
+        ];
+        for (i, change) in changes.into_iter().enumerate() {
+            let mut key = result_key();
+            change(&mut key);
+            assert_ne!(key.etag(), etag, "change {i} keeps the etag");
+        }
+        let mut key = result_key();
+        key.tags = Some(&tags);
+        assert_ne!(key.etag(), etag);
+    }
+
+    #[test]
+    fn matching_etag_is_not_modified() {
+        let etag = result_key().etag();
+        let other = EntityTag::new_strong("other".to_string());
+        assert!(!is_not_modified(None, &etag));
+        assert!(is_not_modified(Some(IfNoneMatch::Any), &etag));
+        assert!(is_not_modified(
+            Some(IfNoneMatch::Items(vec![other.clone(), etag.clone()])),
+            &etag
+        ));
+        // caches may weaken the tag
+        let weak = EntityTag::new_weak(etag.tag().to_string());
+        assert!(is_not_modified(Some(IfNoneMatch::Items(vec![weak])), &etag));
+        assert!(!is_not_modified(
+            Some(IfNoneMatch::Items(vec![other])),
+            &etag
+        ));
+    }
+
+    #[test]
+    fn recent_windows_may_be_staged() {
+        let end = Utc.with_ymd_and_hms(2024, 1, 1, 1, 0, 0).unwrap();
+        let flush_interval = std::time::Duration::from_secs(60);
+        let now = |secs| end + chrono::Duration::seconds(secs);
+        assert!(may_be_staged(end, flush_interval, now(0)).unwrap());
+        assert!(may_be_staged(end, flush_interval, now(60)).unwrap());
+        assert!(!may_be_staged(end, flush_interval, now(3600)).unwrap());
+    }
+
+    #[test]
+    fn external_tables_are_readable_per_table() {
+        let permissions = |privilege: DefaultPrivilege| RoleBuilder::from(&privilege).build();
+
+        let editor = permissions(DefaultPrivilege::Editor);
+        assert!(can_read_external_table(&editor, "customers"));
+        let admin = permissions(DefaultPrivilege::Admin);
+        assert!(can_read_external_table(&admin, "customers"));
+
+        let reader = permissions(DefaultPrivilege::Reader {
+            stream: "customers".to_string(),
+            tag: None,
+        });
+        assert!(can_read_external_table(&reader, "customers"));
+        assert!(!can_read_external_table(&reader, "invoices"));
+
+        // reading a stream does not grant the tables
+        let writer = permissions(DefaultPrivilege::Writer {
+            stream: "app".to_string(),
+        });
+        assert!(!can_read_external_table(&writer, "customers"));
+        let ingester = permissions(DefaultPrivilege::Ingester {
+            stream: "*".to_string(),
+        });
+        assert!(!can_read_external_table(&ingester, "customers"));
+    }
+
+    fn add_stream(name: &str, columns: &[&str]) {
+        let schema = columns
+            .iter()
+            .map(|column| {
+                let field = Field::new(*column, DataType::Utf8, true);
+                (column.to_string(), Arc::new(field))
+            })
+            .collect();
+        STREAM_INFO.write().unwrap().insert(
+            name.to_string(),
+            LogStreamMetadata {
+                schema,
+                ..LogStreamMetadata::default()
+            },
+        );
+    }
+
+    #[test]
+    fn correlation_looks_up_readable_streams_with_the_id_column() {
+        add_stream("correlate_frontend", &["trace_id", "message"]);
+        add_stream("correlate_backend", &["trace_id", "status"]);
+        add_stream("correlate_audit", &["message"]);
+        let streams = vec![
+            "correlate_frontend".to_string(),
+            "correlate_backend".to_string(),
+            "correlate_audit".to_string(),
+            "correlate_missing".to_string(),
+        ];
+
+        let admin = [Permission::Stream(Action::All, "*".to_string())];
+        let found: Vec<_> = correlated_streams(streams.clone(), "trace_id", &admin)
+            .into_iter()
+            .map(|(stream, _)| stream)
+            .collect();
+        assert_eq!(found, vec!["correlate_frontend", "correlate_backend"]);
+
+        // a reader of one stream only finds records of that stream, filtered on its tag
+        let reader = [Permission::StreamWithTag(
+            Action::Query,
+            "correlate_backend".to_string(),
+            Some("team=payments".to_string()),
+        )];
+        let found = correlated_streams(streams.clone(), "trace_id", &reader);
+        assert_eq!(
+            found,
+            vec![(
+                "correlate_backend".to_string(),
+                vec!["team=payments".to_string()]
+            )]
+        );
+
+        assert!(correlated_streams(streams, "trace_id", &[]).is_empty());
+
+        for stream in ["correlate_frontend", "correlate_backend", "correlate_audit"] {
+            STREAM_INFO.delete_stream(stream);
+        }
+    }
+
+    #[test]
+    fn correlated_records_are_merged_in_time_order() {
+        let record = |time: &str, stream: &str| -> Map<String, Value> {
+            serde_json::from_value(json!({"p_timestamp": time, "p_stream": stream})).unwrap()
+        };
+        let merged = merge_correlated(vec![
+            vec![
+                record("2024-01-01T10:00:01.000", "frontend"),
+                record("2024-01-01T10:00:04.000", "frontend"),
+            ],
+            vec![
+                record("2024-01-01T10:00:02.000", "backend"),
+                record("2024-01-01T10:00:03.000", "backend"),
+            ],
+        ]);
+
+        let streams: Vec<_> = merged
+            .iter()
+            .map(|record| record["p_stream"].as_str().unwrap())
+            .collect();
+        assert_eq!(streams, vec!["frontend", "backend", "backend", "frontend"]);
     }
 }
@@ -18,13 +18,15 @@
 
 use std::collections::{HashMap, HashSet};
 
+use crate::handlers::http::problem::Problem;
 use crate::{
+    metrics,
     option::CONFIG,
     rbac::{map::roles, role::model::DefaultPrivilege, user, Users},
     storage::{self, ObjectStorageError, StorageMetadata},
     validator::{self, error::UsernameValidationError},
 };
-use actix_web::{http::header::ContentType, web, Responder};
+use actix_web::{web, Responder};
 use http::StatusCode;
 use tokio::sync::Mutex;
 
@@ -153,6 +155,7 @@ pub async fn delete_user(username: web::Path<String>) -> Result<impl Responder,
     put_metadata(&metadata).await?;
     // update in mem table
     Users.delete_user(&username);
+    metrics::remove_user_label(&username);
     Ok(format!("deleted user: {username}"))
 }
 
@@ -229,8 +232,6 @@ impl actix_web::ResponseError for RBACError {
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        actix_web::HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
+        Problem::new(self.status_code(), self).response()
     }
 }
@@ -0,0 +1,223 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
+use http::StatusCode;
+use lettre::message::Mailbox;
+use ulid::Ulid;
+
+use crate::handlers::http::problem::Problem;
+use crate::handlers::http::query::authorize_query;
+use crate::option::CONFIG;
+use crate::query::{renamed_columns, QUERY_SESSION};
+use crate::rbac::Users;
+use crate::reports::{self, parse_schedule, put_report, report_path, Report};
+use crate::storage::ObjectStorageError;
+use crate::utils::actix::{extract_session_key_from_req, request_username};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportRequest {
+    name: String,
+    query: String,
+    time_range: String,
+    schedule: String,
+    recipients: Vec<String>,
+}
+
+impl ReportRequest {
+    // the query is checked against the permissions of the user saving the report
+    async fn validate(&self, req: &HttpRequest) -> Result<(), ReportError> {
+        if !reports::smtp_configured() {
+            return Err(ReportError::SmtpNotConfigured);
+        }
+        if self.name.trim().is_empty() {
+            return Err(ReportError::Invalid("name cannot be empty".to_string()));
+        }
+        parse_schedule(&self.schedule)
+            .map_err(|err| ReportError::Invalid(format!("invalid schedule: {err}")))?;
+        humantime::parse_duration(&self.time_range)
+            .map_err(|err| ReportError::Invalid(format!("invalid time range: {err}")))?;
+        if self.recipients.is_empty() {
+            return Err(ReportError::Invalid(
+                "recipients cannot be empty".to_string(),
+            ));
+        }
+        for recipient in &self.recipients {
+            recipient.parse::<Mailbox>().map_err(|_| {
+                ReportError::Invalid(format!("invalid recipient address {recipient}"))
+            })?;
+        }
+
+        let plan = renamed_columns::create_logical_plan(&QUERY_SESSION.state(), &self.query)
+            .await
+            .map_err(|err| ReportError::Invalid(err.to_string()))?;
+        let table_name = crate::query::Query {
+            raw_logical_plan: plan,
+            start: Utc::now(),
+            end: Utc::now(),
+            filter_tag: None,
+            at: None,
+            raw: false,
+        }
+        .table_name()
+        .ok_or_else(|| ReportError::Invalid("query does not read from a stream".to_string()))?;
+
+        let key = extract_session_key_from_req(req).map_err(|_| ReportError::Unauthorized)?;
+        authorize_query(&Users.get_permissions(&key), &table_name)
+            .map_err(|_| ReportError::Unauthorized)?;
+        Ok(())
+    }
+}
+
+// reports are only accessible to their owner, as they run with the owner's permissions
+async fn get_report(req: &HttpRequest, id: &str) -> Result<Report, ReportError> {
+    let not_found = || ReportError::NotFound(id.to_owned());
+    let id = Ulid::from_string(id).map_err(|_| not_found())?;
+
+    let store = CONFIG.storage().get_object_store();
+    let report: Report = match store.get_object(&report_path(id)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(ObjectStorageError::NoSuchKey(_)) => return Err(not_found()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if report.owner != request_username(req) {
+        return Err(not_found());
+    }
+    Ok(report)
+}
+
+// Handler for GET /api/v1/reports
+// reports owned by the user
+pub async fn list(req: HttpRequest) -> Result<impl Responder, ReportError> {
+    let username = request_username(&req);
+    let mut reports: Vec<Report> = reports::list_reports()
+        .await?
+        .into_iter()
+        .filter(|report| report.owner == username)
+        .collect();
+    reports.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(web::Json(reports))
+}
+
+// Handler for POST /api/v1/reports
+pub async fn post(
+    req: HttpRequest,
+    body: web::Json<ReportRequest>,
+) -> Result<impl Responder, ReportError> {
+    let body = body.into_inner();
+    body.validate(&req).await?;
+
+    let now = Utc::now();
+    let report = Report {
+        id: Ulid::new(),
+        name: body.name,
+        query: body.query,
+        time_range: body.time_range,
+        schedule: body.schedule,
+        recipients: body.recipients,
+        owner: request_username(&req),
+        created_at: now,
+        updated_at: now,
+        last_run: None,
+        last_failure: None,
+        last_error: None,
+        failures: 0,
+    };
+    put_report(&report).await?;
+
+    Ok((web::Json(report), StatusCode::CREATED))
+}
+
+// Handler for GET /api/v1/reports/{id}
+pub async fn get(req: HttpRequest, id: web::Path<String>) -> Result<impl Responder, ReportError> {
+    Ok(web::Json(get_report(&req, &id).await?))
+}
+
+// Handler for PUT /api/v1/reports/{id}
+pub async fn put(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<ReportRequest>,
+) -> Result<impl Responder, ReportError> {
+    let current = get_report(&req, &id).await?;
+    let body = body.into_inner();
+    body.validate(&req).await?;
+
+    let report = Report {
+        name: body.name,
+        query: body.query,
+        time_range: body.time_range,
+        schedule: body.schedule,
+        recipients: body.recipients,
+        updated_at: Utc::now(),
+        ..current
+    };
+    put_report(&report).await?;
+
+    Ok(web::Json(report))
+}
+
+// Handler for DELETE /api/v1/reports/{id}
+pub async fn delete(
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<impl Responder, ReportError> {
+    let report = get_report(&req, &id).await?;
+
+    let store = CONFIG.storage().get_object_store();
+    store.delete_object(&report_path(report.id)).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReportError {
+    #[error("Report {0} not found")]
+    NotFound(String),
+    #[error("Invalid report: {0}")]
+    Invalid(String),
+    #[error("Reports need an SMTP server, set P_SMTP_HOST and P_SMTP_FROM to enable them")]
+    SmtpNotConfigured,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Invalid report definition in storage: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for ReportError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::SmtpNotConfigured => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::FORBIDDEN,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
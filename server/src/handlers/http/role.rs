@@ -16,9 +16,10 @@
  *
  */
 
-use actix_web::{http::header::ContentType, web, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder};
 use http::StatusCode;
 
+use crate::handlers::http::problem::Problem;
 use crate::{
     option::CONFIG,
     rbac::{
@@ -129,8 +130,6 @@ impl actix_web::ResponseError for RoleError {
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        actix_web::HttpResponse::build(self.status_code())
-            .insert_header(ContentType::plaintext())
-            .body(self.to_string())
+        Problem::new(self.status_code(), self).response()
     }
 }
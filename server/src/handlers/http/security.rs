@@ -0,0 +1,107 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Security events of auditd, Falco and Tetragon, normalized to a common schema modelled on ECS
+//! so that detection queries work across sources.
+//!
+//! Fields are nested as in ECS and flattened with `_` on ingest, `process.executable` becomes the
+//! column `process_executable`:
+//! - `timestamp`, `message`, `tags`
+//! - `event`: `kind` (event or alert), `module` (the source), `id`, `category`, `type`, `action`,
+//!   `outcome` (success or failure), `severity` (0 emergency to 7 debug), `original`
+//! - `host.name`, `source.ip`
+//! - `process`: `pid`, `name`, `executable`, `command_line`, `working_directory`,
+//!   `parent.pid`, `parent.name`, `parent.executable`
+//! - `user`: `id`, `name`, `effective.id`, `audit.id`, `audit.name`
+//! - `file.path`, `rule.name`
+//! - `container.id`, `container.image.name`, `orchestrator.namespace`,
+//!   `orchestrator.resource.name`
+//!
+//! Fields get their type from the ECS mapping of [`ecs`], ids of users are strings and pids are
+//! integers whatever the source writes them as. Fields a source has no value for are left out.
+
+pub mod auditd;
+pub mod falco;
+pub mod tetragon;
+
+use anyhow::anyhow;
+use serde_json::Value;
+
+use crate::utils::json::ecs;
+
+// audit ids of processes which were not started by a login
+const UNSET_AUDIT_IDS: &[&str] = &["-1", "4294967295"];
+
+/// Values of a json array, a json object or newline delimited objects
+pub fn json_values(body: &[u8]) -> anyhow::Result<Vec<Value>> {
+    match serde_json::from_slice(body) {
+        Ok(Value::Array(values)) => Ok(values),
+        Ok(value) => Ok(vec![value]),
+        Err(_) => std::str::from_utf8(body)
+            .map_err(|_| anyhow!("payload is not utf-8 text"))?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(Into::into))
+            .collect(),
+    }
+}
+
+// drop the fields without value, and the objects left empty by it
+fn prune(value: Value) -> Option<Value> {
+    match value {
+        Value::Null => None,
+        Value::String(s) if s.is_empty() => None,
+        Value::Object(object) => {
+            let object: serde_json::Map<_, _> = object
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, prune(value)?)))
+                .collect();
+            (!object.is_empty()).then_some(Value::Object(object))
+        }
+        value => Some(value),
+    }
+}
+
+fn normalized(event: Value) -> Value {
+    prune(ecs::with_field_types(event)).unwrap_or_default()
+}
+
+fn integer(value: Option<&Value>) -> Option<i64> {
+    match value? {
+        Value::Number(number) => number.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn string(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+fn audit_id(value: Option<String>) -> Option<String> {
+    value.filter(|id| !UNSET_AUDIT_IDS.contains(&id.as_str()))
+}
+
+// last component of a path
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
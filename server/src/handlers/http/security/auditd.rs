@@ -0,0 +1,310 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Records of the Linux audit log, one per line as auditd writes them:
+//! ```text
+//! type=SYSCALL msg=audit(1705026780.451:2207): arch=c000003e syscall=59 success=yes exit=0 pid=4312 ppid=4300 auid=1000 uid=0 euid=0 comm="cat" exe="/usr/bin/cat" key="exec"
+//! type=EXECVE msg=audit(1705026780.451:2207): argc=2 a0="cat" a1="/etc/shadow"
+//! type=CWD msg=audit(1705026780.451:2207): cwd="/root"
+//! type=PATH msg=audit(1705026780.451:2207): item=0 name="/usr/bin/cat" inode=1234
+//! type=EOE msg=audit(1705026780.451:2207):
+//! ```
+//! Consecutive records with the same timestamp and serial are one event. Records of an event
+//! should be sent in the same request, as those split across requests become separate events.
+//! Logs enriched by auditd (`log_format = ENRICHED`) carry the names of users in upper case
+//! fields, which are used for `user.name` and `user.audit.name`.
+
+use anyhow::anyhow;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use super::{audit_id, basename, normalized};
+
+// fields which auditd writes hex encoded when they contain spaces or special characters
+const ENCODED_FIELDS: &[&str] = &["proctitle", "name", "comm", "exe", "cwd", "acct", "key"];
+// marker of record types which have no event of their own
+const END_OF_EVENT: &str = "EOE";
+const NULL: &str = "(null)";
+
+#[derive(Debug)]
+struct Record<'a> {
+    kind: String,
+    // timestamp and serial of the event, as in audit(1705026780.451:2207)
+    id: &'a str,
+    fields: Vec<(String, String, bool)>,
+    line: &'a str,
+}
+
+impl Record<'_> {
+    // value of a field, decoded if it was written hex encoded
+    fn get(&self, name: &str) -> Option<String> {
+        let (_, value, quoted) = self.fields.iter().find(|(key, ..)| key == name)?;
+        if value == "?" || value == NULL {
+            return None;
+        }
+        let encoded = !quoted
+            && (ENCODED_FIELDS.contains(&name) || is_argument(name))
+            && value.len() % 2 == 0
+            && value.bytes().all(|b| b.is_ascii_hexdigit());
+        if encoded {
+            let bytes = hex::decode(value).ok()?;
+            // arguments of the process title are separated by NUL
+            let decoded: Vec<u8> = bytes
+                .into_iter()
+                .map(|b| if b == 0 { b' ' } else { b })
+                .collect();
+            return Some(String::from_utf8_lossy(&decoded).trim_end().to_owned());
+        }
+        Some(value.clone())
+    }
+}
+
+// a0, a1, .. of EXECVE records
+fn is_argument(name: &str) -> bool {
+    name.strip_prefix('a')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Events of auditd records of a payload in the common security schema
+pub fn normalize(body: &[u8]) -> anyhow::Result<Vec<Value>> {
+    let body = std::str::from_utf8(body).map_err(|_| anyhow!("payload is not utf-8 text"))?;
+    let records = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(record)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut events = Vec::new();
+    let mut start = 0;
+    for end in 1..=records.len() {
+        if end == records.len() || records[end].id != records[start].id {
+            events.extend(event(&records[start..end]));
+            start = end;
+        }
+    }
+    Ok(events)
+}
+
+fn record(line: &str) -> anyhow::Result<Record<'_>> {
+    let mut kind = None;
+    let mut id = None;
+    let mut fields = Vec::new();
+    for (key, value, quote) in split_fields(line) {
+        match (key, quote) {
+            ("type", _) => kind = Some(value.to_owned()),
+            ("msg", None) if value.starts_with("audit(") => {
+                id = value
+                    .strip_prefix("audit(")
+                    .and_then(|value| value.trim_end_matches(':').strip_suffix(')'));
+            }
+            // user space messages carry their fields in a quoted msg
+            ("msg", Some('\'')) => fields
+                .extend(split_fields(value).map(|(key, value, quote)| {
+                    (key.to_owned(), value.to_owned(), quote.is_some())
+                })),
+            (key, quote) => fields.push((key.to_owned(), value.to_owned(), quote.is_some())),
+        }
+    }
+
+    Ok(Record {
+        kind: kind.ok_or_else(|| anyhow!("audit record without type: {line}"))?,
+        id: id.ok_or_else(|| anyhow!("audit record without msg=audit(..): {line}"))?,
+        fields,
+        line,
+    })
+}
+
+// key=value pairs separated by spaces, values may be quoted. Enriched logs separate the
+// interpreted fields with a group separator.
+fn split_fields(line: &str) -> impl Iterator<Item = (&str, &str, Option<char>)> {
+    let mut rest = line;
+    std::iter::from_fn(move || loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '\x1d');
+        if rest.is_empty() {
+            return None;
+        }
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace() || c == '\x1d')
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = &rest[key_end..];
+        let Some(value) = rest.strip_prefix('=') else {
+            // a word without value
+            continue;
+        };
+
+        let quote = value.chars().next().filter(|&c| c == '"' || c == '\'');
+        let (value, remainder) = match quote {
+            Some(quote) => {
+                let value = &value[1..];
+                let end = value.find(quote).unwrap_or(value.len());
+                (&value[..end], value.get(end + 1..).unwrap_or_default())
+            }
+            None => {
+                let end = value
+                    .find(|c: char| c.is_whitespace() || c == '\x1d')
+                    .unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+        rest = remainder;
+        return Some((key, value, quote));
+    })
+}
+
+fn event(records: &[Record]) -> Option<Value> {
+    let records: Vec<_> = records
+        .iter()
+        .filter(|record| record.kind != END_OF_EVENT)
+        .collect();
+    let primary = records.first()?;
+    let of_kind = |kind: &str| records.iter().find(|record| record.kind == kind);
+    let get = |name: &str| records.iter().find_map(|record| record.get(name));
+    let (serial, timestamp) = primary
+        .id
+        .split_once(':')
+        .map(|(time, serial)| (Some(serial), timestamp(time)))
+        .unwrap_or_default();
+
+    let execve = of_kind("EXECVE");
+    let command_line = execve
+        .map(|execve| {
+            let argc = execve
+                .get("argc")
+                .and_then(|argc| argc.parse::<usize>().ok())
+                .unwrap_or(0);
+            (0..argc)
+                .filter_map(|i| execve.get(&format!("a{i}")))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .or_else(|| of_kind("PROCTITLE")?.get("proctitle"));
+    let file = of_kind("PATH").and_then(|path| path.get("name"));
+    let executable = get("exe");
+
+    let (category, event_type) = match primary.kind.as_str() {
+        _ if execve.is_some() => ("process", Some("start")),
+        "USER_AUTH" | "USER_LOGIN" | "USER_ACCT" | "USER_ERR" => ("authentication", None),
+        "USER_START" | "USER_END" | "CRED_ACQ" | "CRED_DISP" | "CRED_REFR" | "LOGIN" => {
+            ("session", None)
+        }
+        "ADD_USER" | "DEL_USER" | "ADD_GROUP" | "DEL_GROUP" | "USER_MGMT" | "GRP_MGMT"
+        | "USER_CHAUTHTOK" | "ACCT_LOCK" | "ACCT_UNLOCK" => ("iam", None),
+        "AVC" | "SELINUX_ERR" | "APPARMOR_DENIED" | "ANOM_ABEND" => ("intrusion_detection", None),
+        _ if of_kind("SOCKADDR").is_some() => ("network", None),
+        _ if file.is_some() => ("file", None),
+        _ => ("host", None),
+    };
+    let outcome = match get("success").or_else(|| get("res")).as_deref() {
+        Some("yes" | "success" | "1") => Some("success"),
+        Some("no" | "failed" | "0") => Some("failure"),
+        _ => None,
+    };
+    // interpreted name of the syscall in enriched logs, its number otherwise
+    let action = match primary.kind.as_str() {
+        "SYSCALL" => get("SYSCALL").or_else(|| get("syscall")),
+        kind => Some(kind.to_lowercase()),
+    };
+    let original: Vec<_> = records.iter().map(|record| record.line).collect();
+
+    Some(normalized(json!({
+        "timestamp": timestamp,
+        "event": {
+            "kind": "event",
+            "module": "auditd",
+            "id": serial,
+            "category": category,
+            "type": event_type,
+            "action": action,
+            "outcome": outcome,
+            "original": original.join("\n"),
+        },
+        "rule": { "name": get("key") },
+        "host": { "name": get("node") },
+        "source": { "ip": get("addr") },
+        "process": {
+            "pid": get("pid"),
+            "name": get("comm").or_else(|| executable.as_deref().map(basename).map(str::to_owned)),
+            "executable": executable,
+            "command_line": command_line,
+            "working_directory": of_kind("CWD").and_then(|cwd| cwd.get("cwd")),
+            "parent": { "pid": get("ppid") },
+        },
+        "user": {
+            "id": get("uid"),
+            "name": get("UID").or_else(|| get("acct")),
+            "effective": { "id": get("euid") },
+            "audit": { "id": audit_id(get("auid")), "name": get("AUID") },
+        },
+        "file": { "path": file },
+    })))
+}
+
+// seconds with milliseconds since the epoch
+fn timestamp(time: &str) -> Option<String> {
+    let (secs, millis) = time.split_once('.').unwrap_or((time, "0"));
+    let time = NaiveDateTime::from_timestamp_opt(
+        secs.parse().ok()?,
+        millis.parse::<u32>().ok()? * 1_000_000,
+    )?;
+    Some(
+        DateTime::<Utc>::from_naive_utc_and_offset(time, Utc)
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+
+    #[test]
+    fn records_of_an_event_are_joined() {
+        let body = r#"type=SYSCALL msg=audit(1705026780.451:2207): arch=c000003e syscall=59 success=yes exit=0 a0=55d0 pid=4312 ppid=4300 auid=4294967295 uid=0 euid=0 comm="cat" exe="/usr/bin/cat" key="exec"SYSCALL=execve UID="root"
+type=EXECVE msg=audit(1705026780.451:2207): argc=2 a0="cat" a1=2F6574632F736861646F77
+type=CWD msg=audit(1705026780.451:2207): cwd="/root"
+type=PATH msg=audit(1705026780.451:2207): item=0 name="/usr/bin/cat" inode=1234
+type=EOE msg=audit(1705026780.451:2207):
+type=USER_LOGIN msg=audit(1705026790.001:2208): pid=4400 uid=0 auid=1000 ses=3 msg='op=login id=1000 exe="/usr/sbin/sshd" hostname=? addr=10.0.0.7 terminal=sshd res=failed'
+"#;
+
+        let events = normalize(body.replace("\"SYSCALL", "\"\x1dSYSCALL").as_bytes()).unwrap();
+
+        assert_eq!(events.len(), 2);
+        let exec = &events[0];
+        assert_eq!(exec["timestamp"], "2024-01-12T02:33:00.451Z");
+        assert_eq!(exec["event"]["id"], "2207");
+        assert_eq!(exec["event"]["category"], "process");
+        assert_eq!(exec["event"]["action"], "execve");
+        assert_eq!(exec["event"]["outcome"], "success");
+        assert_eq!(exec["process"]["pid"], 4312);
+        assert_eq!(exec["process"]["command_line"], "cat /etc/shadow");
+        assert_eq!(exec["process"]["working_directory"], "/root");
+        assert_eq!(exec["user"]["name"], "root");
+        assert_eq!(exec["file"]["path"], "/usr/bin/cat");
+        assert_eq!(exec["rule"]["name"], "exec");
+        assert!(exec["user"].get("audit").is_none());
+
+        let login = &events[1];
+        assert_eq!(login["event"]["category"], "authentication");
+        assert_eq!(login["event"]["action"], "user_login");
+        assert_eq!(login["event"]["outcome"], "failure");
+        assert_eq!(login["source"]["ip"], "10.0.0.7");
+        assert_eq!(login["process"]["executable"], "/usr/sbin/sshd");
+        assert_eq!(login["user"]["audit"]["id"], "1000");
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Alerts of Falco, as its json and http outputs write them:
+//! ```json
+//! {
+//!     "time": "2024-01-11T09:08:34.290318000Z",
+//!     "rule": "Read sensitive file untrusted",
+//!     "priority": "Warning",
+//!     "source": "syscall",
+//!     "hostname": "node-1",
+//!     "output": "Sensitive file opened for reading by non-trusted program ...",
+//!     "tags": ["filesystem", "mitre_credential_access"],
+//!     "output_fields": { "proc.name": "cat", "fd.name": "/etc/shadow", "user.uid": 0 }
+//! }
+//! ```
+
+use anyhow::anyhow;
+use serde_json::{json, Value};
+
+use super::{audit_id, json_values, normalized, string};
+
+// syslog severity of the priorities of falco rules
+const PRIORITIES: &[&str] = &[
+    "emergency",
+    "alert",
+    "critical",
+    "error",
+    "warning",
+    "notice",
+    "informational",
+    "debug",
+];
+
+/// Falco alerts of a payload in the common security schema
+pub fn normalize(body: &[u8]) -> anyhow::Result<Vec<Value>> {
+    json_values(body)?.iter().map(alert).collect()
+}
+
+fn alert(alert: &Value) -> anyhow::Result<Value> {
+    let rule = alert
+        .get("rule")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("not a Falco alert"))?;
+    let field = |name: &str| {
+        alert
+            .get("output_fields")
+            .and_then(|fields| fields.get(name))
+    };
+    let severity = alert
+        .get("priority")
+        .and_then(Value::as_str)
+        .and_then(|priority| {
+            let priority = priority.to_lowercase();
+            PRIORITIES.iter().position(|&name| {
+                name == priority || (name == "informational" && priority == "info")
+            })
+        });
+    // fd.name is the tuple of addresses for network descriptors
+    let file = string(field("fd.name")).filter(|name| name.starts_with('/'));
+    // events outside of containers are attributed to the container "host"
+    let container = string(field("container.id")).filter(|id| id != "host");
+
+    Ok(normalized(json!({
+        "timestamp": alert.get("time"),
+        "message": alert.get("output"),
+        "tags": alert.get("tags"),
+        "event": {
+            "kind": "alert",
+            "module": "falco",
+            "dataset": alert.get("source"),
+            "action": rule,
+            "severity": severity,
+            "original": alert.to_string(),
+        },
+        "rule": { "name": rule },
+        "host": { "name": alert.get("hostname") },
+        "process": {
+            "pid": field("proc.pid"),
+            "name": string(field("proc.name")),
+            "executable": string(field("proc.exepath")).or_else(|| string(field("proc.exe"))),
+            "command_line": string(field("proc.cmdline")),
+            "working_directory": string(field("proc.cwd")),
+            "parent": {
+                "pid": field("proc.ppid"),
+                "name": string(field("proc.pname")),
+            },
+        },
+        "user": {
+            "id": string(field("user.uid")),
+            "name": string(field("user.name")),
+            "audit": {
+                "id": audit_id(string(field("user.loginuid"))),
+                "name": string(field("user.loginname")),
+            },
+        },
+        "file": { "path": file },
+        "container": {
+            "id": container,
+            "image": { "name": string(field("container.image.repository")) },
+        },
+        "orchestrator": {
+            "namespace": string(field("k8s.ns.name")),
+            "resource": { "name": string(field("k8s.pod.name")) },
+        },
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::normalize;
+
+    #[test]
+    fn alert_is_normalized() {
+        let body = json!({
+            "time": "2024-01-11T09:08:34.290318000Z",
+            "rule": "Read sensitive file untrusted",
+            "priority": "Warning",
+            "source": "syscall",
+            "hostname": "node-1",
+            "output": "Sensitive file opened for reading",
+            "tags": ["filesystem"],
+            "output_fields": {
+                "container.id": "host",
+                "fd.name": "/etc/shadow",
+                "proc.cmdline": "cat /etc/shadow",
+                "proc.name": "cat",
+                "proc.pid": 4312,
+                "user.loginuid": -1,
+                "user.uid": 0,
+            },
+        })
+        .to_string();
+
+        let events = normalize(body.as_bytes()).unwrap();
+        let event = &events[0];
+
+        assert_eq!(event["event"]["severity"], 4);
+        assert_eq!(event["rule"]["name"], "Read sensitive file untrusted");
+        assert_eq!(event["process"]["pid"], 4312);
+        assert_eq!(event["user"]["id"], "0");
+        assert_eq!(event["file"]["path"], "/etc/shadow");
+        assert!(event.get("container").is_none());
+        assert!(event["user"].get("audit").is_none());
+    }
+}
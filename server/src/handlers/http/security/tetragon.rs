@@ -0,0 +1,235 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Events of Tetragon, as its json export writes them. Every event has a single field named by
+//! its type, holding the process it happened in and its parent:
+//! ```json
+//! {
+//!     "process_exec": {
+//!         "process": { "pid": 52699, "uid": 0, "binary": "/usr/bin/curl", "arguments": "https://ebpf.io" },
+//!         "parent": { "pid": 52688, "binary": "/bin/bash" }
+//!     },
+//!     "node_name": "node-1",
+//!     "time": "2024-01-11T09:08:34.290318Z"
+//! }
+//! ```
+
+use anyhow::anyhow;
+use serde_json::{json, Value};
+
+use super::{audit_id, basename, integer, json_values, normalized, string};
+
+// functions hooked by kprobes and lsm programs, by the category of what they act on
+const NETWORK_FUNCTIONS: &[&str] = &["tcp_", "udp_", "inet_", "sock_", "security_socket_"];
+const FILE_FUNCTIONS: &[&str] = &[
+    "security_file_",
+    "security_path_",
+    "security_inode_",
+    "fd_install",
+    "vfs_",
+    "do_sys_open",
+    "__x64_sys_open",
+    "__x64_sys_write",
+];
+
+/// Tetragon events of a payload in the common security schema
+pub fn normalize(body: &[u8]) -> anyhow::Result<Vec<Value>> {
+    json_values(body)?.iter().map(event).collect()
+}
+
+fn event(event: &Value) -> anyhow::Result<Value> {
+    let (kind, details) = event
+        .as_object()
+        .and_then(|event| {
+            event
+                .iter()
+                .find(|(name, value)| name.starts_with("process_") && value.is_object())
+        })
+        .ok_or_else(|| anyhow!("not a Tetragon event"))?;
+
+    let function = details.get("function_name").and_then(Value::as_str);
+    let (category, event_type, action) = match kind.as_str() {
+        "process_exec" => ("process", Some("start"), Some("exec".to_owned())),
+        "process_exit" => ("process", Some("end"), Some("exit".to_owned())),
+        "process_tracepoint" => {
+            let subsys = details.get("subsys").and_then(Value::as_str);
+            let name = details.get("event").and_then(Value::as_str);
+            let action = subsys
+                .zip(name)
+                .map(|(subsys, name)| format!("{subsys}/{name}"));
+            (function_category(name.unwrap_or_default()), None, action)
+        }
+        _ => (
+            function_category(function.unwrap_or_default()),
+            None,
+            function.map(str::to_owned),
+        ),
+    };
+    let outcome = if kind == "process_exit" {
+        let failed = integer(details.get("status")).is_some_and(|status| status != 0)
+            || string(details.get("signal")).is_some_and(|signal| !signal.is_empty());
+        Some(if failed { "failure" } else { "success" })
+    } else {
+        None
+    };
+
+    let process = details.get("process");
+    let parent = details.get("parent");
+    let executable = string(field(process, "/binary"));
+    let command_line = executable.as_ref().map(|binary| {
+        match string(field(process, "/arguments")).filter(|arguments| !arguments.is_empty()) {
+            Some(arguments) => format!("{binary} {arguments}"),
+            None => binary.clone(),
+        }
+    });
+    let parent_executable = string(field(parent, "/binary"));
+    // container ids are prefixed with their runtime, like containerd://
+    let container =
+        string(field(process, "/pod/container/id")).map(|id| match id.split_once("://") {
+            Some((_, id)) => id.to_owned(),
+            None => id,
+        });
+
+    Ok(normalized(json!({
+        "timestamp": event.get("time"),
+        "event": {
+            "kind": "event",
+            "module": "tetragon",
+            "category": category,
+            "type": event_type,
+            "action": action,
+            "outcome": outcome,
+            "original": event.to_string(),
+        },
+        "rule": { "name": string(details.get("policy_name")) },
+        "host": { "name": event.get("node_name") },
+        "process": {
+            "pid": field(process, "/pid"),
+            "name": executable.as_deref().map(basename),
+            "executable": executable,
+            "command_line": command_line,
+            "working_directory": string(field(process, "/cwd")),
+            "parent": {
+                "pid": field(parent, "/pid"),
+                "name": parent_executable.as_deref().map(basename),
+                "executable": parent_executable,
+            },
+        },
+        "user": {
+            "id": string(field(process, "/uid")),
+            "audit": { "id": audit_id(string(field(process, "/auid"))) },
+        },
+        "container": {
+            "id": container,
+            "image": { "name": string(field(process, "/pod/container/image/name")) },
+        },
+        "orchestrator": {
+            "namespace": string(field(process, "/pod/namespace")),
+            "resource": { "name": string(field(process, "/pod/name")) },
+        },
+    })))
+}
+
+fn field<'a>(value: Option<&'a Value>, pointer: &str) -> Option<&'a Value> {
+    value?.pointer(pointer)
+}
+
+fn function_category(function: &str) -> &'static str {
+    if NETWORK_FUNCTIONS
+        .iter()
+        .any(|prefix| function.starts_with(prefix))
+    {
+        "network"
+    } else if FILE_FUNCTIONS
+        .iter()
+        .any(|prefix| function.starts_with(prefix))
+    {
+        "file"
+    } else {
+        "process"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::normalize;
+
+    #[test]
+    fn exec_is_normalized() {
+        let body = json!({
+            "process_exec": {
+                "process": {
+                    "pid": 52699,
+                    "uid": 0,
+                    "auid": 4294967295u64,
+                    "cwd": "/",
+                    "binary": "/usr/bin/curl",
+                    "arguments": "https://ebpf.io",
+                    "pod": {
+                        "namespace": "default",
+                        "name": "xwing",
+                        "container": {
+                            "id": "containerd://0d6f2c1a",
+                            "image": { "name": "docker.io/tgraf/netperf:latest" },
+                        },
+                    },
+                },
+                "parent": { "pid": 52688, "binary": "/bin/bash" },
+            },
+            "node_name": "node-1",
+            "time": "2024-01-11T09:08:34.290318Z",
+        })
+        .to_string();
+
+        let events = normalize(body.as_bytes()).unwrap();
+        let event = &events[0];
+
+        assert_eq!(event["event"]["category"], "process");
+        assert_eq!(event["event"]["type"], "start");
+        assert_eq!(event["process"]["name"], "curl");
+        assert_eq!(
+            event["process"]["command_line"],
+            "/usr/bin/curl https://ebpf.io"
+        );
+        assert_eq!(event["process"]["parent"]["name"], "bash");
+        assert_eq!(event["user"]["id"], "0");
+        assert_eq!(event["container"]["id"], "0d6f2c1a");
+        assert_eq!(event["orchestrator"]["resource"]["name"], "xwing");
+    }
+
+    #[test]
+    fn kprobe_is_categorized_by_function() {
+        let body = json!({
+            "process_kprobe": {
+                "process": { "pid": 1, "binary": "/usr/bin/nc" },
+                "function_name": "tcp_connect",
+                "policy_name": "connect",
+            },
+            "node_name": "node-1",
+        })
+        .to_string();
+
+        let event = &normalize(body.as_bytes()).unwrap()[0];
+
+        assert_eq!(event["event"]["category"], "network");
+        assert_eq!(event["event"]["action"], "tcp_connect");
+        assert_eq!(event["rule"]["name"], "connect");
+    }
+}
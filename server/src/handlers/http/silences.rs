@@ -0,0 +1,272 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::cmp::Reverse;
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use ulid::Ulid;
+
+use crate::alerts::silence::{self, silence_path, Matcher, MatcherName, Silence, SilenceStatus};
+use crate::handlers::http::problem::Problem;
+use crate::option::CONFIG;
+use crate::rbac::role::{Action, Permission};
+use crate::rbac::Users;
+use crate::storage::ObjectStorageError;
+use crate::utils::actix::{extract_session_key_from_req, request_username};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SilenceRequest {
+    matchers: Vec<Matcher>,
+    /// now if not set
+    #[serde(default)]
+    starts_at: Option<DateTime<Utc>>,
+    ends_at: DateTime<Utc>,
+    #[serde(default)]
+    comment: String,
+}
+
+impl SilenceRequest {
+    fn validate(&self) -> Result<(), SilenceError> {
+        if self.matchers.is_empty() {
+            return Err(SilenceError::Invalid(
+                "a silence needs at least one matcher".to_string(),
+            ));
+        }
+        for matcher in self.matchers.iter().filter(|matcher| matcher.is_regex) {
+            matcher
+                .regex()
+                .map_err(|err| SilenceError::Invalid(format!("invalid matcher regex: {err}")))?;
+        }
+        if self.ends_at <= self.starts_at.unwrap_or_else(Utc::now) {
+            return Err(SilenceError::Invalid(
+                "a silence has to end after it starts".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SilenceResponse {
+    #[serde(flatten)]
+    silence: Silence,
+    status: SilenceStatus,
+}
+
+impl From<Silence> for SilenceResponse {
+    fn from(silence: Silence) -> Self {
+        let status = silence.status(Utc::now());
+        Self { silence, status }
+    }
+}
+
+async fn get_silence(id: &str) -> Result<Silence, SilenceError> {
+    let not_found = || SilenceError::NotFound(id.to_owned());
+    let id = Ulid::from_string(id).map_err(|_| not_found())?;
+
+    let store = CONFIG.storage().get_object_store();
+    match store.get_object(&silence_path(id)).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(ObjectStorageError::NoSuchKey(_)) => Err(not_found()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// A silence may only be changed by users allowed to for every stream it applies to. Silences
+// without a plain stream matcher apply to any stream, which needs the action on all streams.
+fn can_change(permissions: &[Permission], action: Action, matchers: &[Matcher]) -> bool {
+    let allowed = |stream: &str| {
+        permissions.iter().any(|permission| {
+            matches!(
+                permission,
+                Permission::Stream(granted, granted_stream)
+                    if (*granted == action || *granted == Action::All)
+                        && (granted_stream == stream || granted_stream == "*")
+            )
+        })
+    };
+    let streams: Vec<_> = matchers
+        .iter()
+        .filter(|matcher| matcher.name == MatcherName::Stream && !matcher.is_regex)
+        .map(|matcher| matcher.value.as_str())
+        .collect();
+    if streams.is_empty() {
+        allowed("*")
+    } else {
+        streams.into_iter().all(allowed)
+    }
+}
+
+fn authorize(req: &HttpRequest, action: Action, matchers: &[Matcher]) -> Result<(), SilenceError> {
+    let permissions = extract_session_key_from_req(req)
+        .map(|key| Users.get_permissions(&key))
+        .unwrap_or_default();
+    if can_change(&permissions, action, matchers) {
+        Ok(())
+    } else {
+        Err(SilenceError::Forbidden)
+    }
+}
+
+// Handler for GET /api/v1/alerts/silences
+pub async fn list() -> Result<impl Responder, SilenceError> {
+    let mut silences = silence::list_silences().await?;
+    silences.sort_by_key(|silence| Reverse(silence.starts_at));
+    let silences: Vec<SilenceResponse> = silences.into_iter().map(Into::into).collect();
+
+    Ok(web::Json(silences))
+}
+
+// Handler for POST /api/v1/alerts/silences
+pub async fn post(
+    req: HttpRequest,
+    body: web::Json<SilenceRequest>,
+) -> Result<impl Responder, SilenceError> {
+    let body = body.into_inner();
+    body.validate()?;
+    authorize(&req, Action::CreateSilence, &body.matchers)?;
+
+    let now = Utc::now();
+    let silence = Silence {
+        id: Ulid::new(),
+        matchers: body.matchers,
+        starts_at: body.starts_at.unwrap_or(now),
+        ends_at: body.ends_at,
+        comment: body.comment,
+        created_by: request_username(&req),
+        created_at: now,
+    };
+    silence::put_silence(silence.clone()).await?;
+
+    Ok((
+        web::Json(SilenceResponse::from(silence)),
+        StatusCode::CREATED,
+    ))
+}
+
+// Handler for GET /api/v1/alerts/silences/{id}
+pub async fn get(id: web::Path<String>) -> Result<impl Responder, SilenceError> {
+    Ok(web::Json(SilenceResponse::from(get_silence(&id).await?)))
+}
+
+// Handler for DELETE /api/v1/alerts/silences/{id}
+pub async fn delete(
+    req: HttpRequest,
+    id: web::Path<String>,
+) -> Result<impl Responder, SilenceError> {
+    let silence = get_silence(&id).await?;
+    authorize(&req, Action::DeleteSilence, &silence.matchers)?;
+    silence::delete_silence(silence.id).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SilenceError {
+    #[error("Silence {0} not found")]
+    NotFound(String),
+    #[error("Invalid silence: {0}")]
+    Invalid(String),
+    #[error("Not allowed to change silences of all the streams the silence applies to")]
+    Forbidden,
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Invalid silence definition in storage: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for SilenceError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alerts::silence::{Matcher, MatcherName};
+    use crate::rbac::role::{Action, Permission};
+
+    use super::can_change;
+
+    #[test]
+    fn silences_are_changed_for_allowed_streams_only() {
+        let writer = [Permission::Stream(
+            Action::CreateSilence,
+            "nginx".to_string(),
+        )];
+        let admin = [Permission::Stream(Action::All, "*".to_string())];
+        let stream = |value: &str, is_regex| {
+            vec![
+                Matcher::new(MatcherName::Stream, value.to_string(), is_regex),
+                Matcher::new(MatcherName::Alert, "latency".to_string(), false),
+            ]
+        };
+
+        assert!(can_change(
+            &writer,
+            Action::CreateSilence,
+            &stream("nginx", false)
+        ));
+        assert!(!can_change(
+            &writer,
+            Action::DeleteSilence,
+            &stream("nginx", false)
+        ));
+        assert!(!can_change(
+            &writer,
+            Action::CreateSilence,
+            &stream("app", false)
+        ));
+        // a regex or a missing stream matcher may match any stream
+        assert!(!can_change(
+            &writer,
+            Action::CreateSilence,
+            &stream("ngin.*", true)
+        ));
+        assert!(!can_change(
+            &writer,
+            Action::CreateSilence,
+            &stream("nginx", false)[1..]
+        ));
+
+        assert!(can_change(
+            &admin,
+            Action::CreateSilence,
+            &stream("ngin.*", true)
+        ));
+        assert!(can_change(
+            &admin,
+            Action::DeleteSilence,
+            &stream("nginx", false)[1..]
+        ));
+    }
+}
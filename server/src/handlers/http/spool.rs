@@ -0,0 +1,284 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+use actix_web::{error::PayloadError, web};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::option::CONFIG;
+use crate::storage::staging::encryption::{self, Encryptor};
+
+// number of events read from a spooled body before they are pushed
+pub const SPOOL_BATCH_SIZE: usize = 10_000;
+
+/// Request body which is kept in memory while small and written
+/// to a file in the staging directory once it grows large.
+pub enum SpooledBody {
+    Memory(Bytes),
+    File(SpoolFile),
+}
+
+impl SpooledBody {
+    // read the whole body in memory, for sources which can not be parsed incrementally
+    pub async fn into_bytes(self) -> io::Result<Bytes> {
+        match self {
+            SpooledBody::Memory(bytes) => Ok(bytes),
+            SpooledBody::File(file) => tokio::fs::read(&file.path)
+                .await
+                .and_then(encryption::decrypt)
+                .map(Bytes::from),
+        }
+    }
+}
+
+/// Spooled file, removed once dropped
+pub struct SpoolFile {
+    path: PathBuf,
+    pub size: usize,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::warn!("failed to remove spooled file {:?}: {}", self.path, err);
+        }
+    }
+}
+
+fn spool_dir() -> PathBuf {
+    CONFIG.staging_dir().join(".spool")
+}
+
+// Read the request payload, spilling it to disk once it is larger than the configured threshold
+pub async fn spool_payload(mut payload: web::Payload) -> Result<SpooledBody, SpoolError> {
+    let threshold = CONFIG.parseable.ingest_spool_threshold as usize;
+    let limit = CONFIG.parseable.ingest_max_payload_size as usize;
+
+    let mut buffer = BytesMut::new();
+    let mut spooled: Option<(SpoolFile, tokio::fs::File, Encryptor)> = None;
+    let mut size = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        if size > limit {
+            return Err(SpoolError::Overflow(limit));
+        }
+
+        match &mut spooled {
+            Some((_, file, encryptor)) => file.write_all(&encryptor.encrypt(&chunk)?).await?,
+            None if buffer.len() + chunk.len() > threshold => {
+                fs::create_dir_all(spool_dir())?;
+                let path = spool_dir().join(ulid::Ulid::new().to_string());
+                let mut file = tokio::fs::File::create(&path).await?;
+                // spool file takes care of cleanup from here on
+                let spool_file = SpoolFile { path, size: 0 };
+                // the body is encrypted like the rest of staging
+                let (mut encryptor, header) = Encryptor::new();
+                file.write_all(&header).await?;
+                file.write_all(&encryptor.encrypt(&buffer)?).await?;
+                file.write_all(&encryptor.encrypt(&chunk)?).await?;
+                buffer = BytesMut::new();
+                spooled = Some((spool_file, file, encryptor));
+            }
+            None => buffer.extend_from_slice(&chunk),
+        }
+    }
+
+    match spooled {
+        Some((mut spool_file, mut file, _)) => {
+            file.flush().await?;
+            spool_file.size = size;
+            Ok(SpooledBody::File(spool_file))
+        }
+        None => Ok(SpooledBody::Memory(buffer.freeze())),
+    }
+}
+
+/// Parse a spooled json array, sending events over the channel in chunks of `batch_size`.
+/// A single json object is sent as a chunk of one event. Parsing stops once the receiver is dropped.
+pub fn read_json_chunks(
+    file: &SpoolFile,
+    batch_size: usize,
+    tx: mpsc::Sender<Vec<Value>>,
+) -> Result<(), serde_json::Error> {
+    let reader = BufReader::new(encryption::open(&file.path).map_err(serde_json::Error::io)?);
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    ChunkedEvents { batch_size, tx }.deserialize(&mut deserializer)?;
+    deserializer.end()
+}
+
+/// Number of events in a spooled body, counted without holding them
+pub fn count_json_events(file: &SpoolFile) -> Result<usize, serde_json::Error> {
+    let reader = BufReader::new(encryption::open(&file.path).map_err(serde_json::Error::io)?);
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let count = serde::Deserializer::deserialize_any(&mut deserializer, EventCount)?;
+    deserializer.end()?;
+    Ok(count)
+}
+
+struct EventCount;
+
+impl<'de> Visitor<'de> for EventCount {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a json object or an array of json objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let mut count = 0;
+        while seq.next_element::<IgnoredAny>()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<usize, A::Error> {
+        IgnoredAny::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        Ok(1)
+    }
+}
+
+struct ChunkedEvents {
+    batch_size: usize,
+    tx: mpsc::Sender<Vec<Value>>,
+}
+
+impl ChunkedEvents {
+    fn send<E: serde::de::Error>(&self, chunk: Vec<Value>) -> Result<(), E> {
+        self.tx
+            .blocking_send(chunk)
+            .map_err(|_| E::custom("ingestion of the request body was aborted"))
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ChunkedEvents {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ChunkedEvents {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a json object or an array of json objects")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut chunk = Vec::with_capacity(self.batch_size);
+        while let Some(value) = seq.next_element::<Value>()? {
+            chunk.push(value);
+            if chunk.len() == self.batch_size {
+                self.send(std::mem::replace(
+                    &mut chunk,
+                    Vec::with_capacity(self.batch_size),
+                ))?;
+            }
+        }
+        if !chunk.is_empty() {
+            self.send(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<(), A::Error> {
+        let value = Value::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+        self.send(vec![value])
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpoolError {
+    #[error("Failed to read request body: {0}")]
+    Payload(#[from] PayloadError),
+    #[error("Failed to spool request body: {0}")]
+    Io(#[from] io::Error),
+    #[error("Request body is larger than the limit of {0} bytes")]
+    Overflow(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::{count_json_events, read_json_chunks, SpoolFile};
+
+    fn spool_file(content: &[u8]) -> SpoolFile {
+        let path = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(content)
+            .unwrap();
+        SpoolFile {
+            path,
+            size: content.len(),
+        }
+    }
+
+    #[test]
+    fn array_is_read_in_chunks() {
+        let file = spool_file(br#"[{"a": 1}, {"a": 2}, {"a": 3}]"#);
+        let (tx, mut rx) = mpsc::channel(10);
+
+        read_json_chunks(&file, 2, tx).unwrap();
+
+        assert_eq!(
+            rx.blocking_recv().unwrap(),
+            vec![json!({"a": 1}), json!({"a": 2})]
+        );
+        assert_eq!(rx.blocking_recv().unwrap(), vec![json!({"a": 3})]);
+        assert!(rx.blocking_recv().is_none());
+    }
+
+    #[test]
+    fn single_object_and_invalid_json() {
+        let file = spool_file(br#"{"a": {"b": true}}"#);
+        let (tx, mut rx) = mpsc::channel(10);
+        read_json_chunks(&file, 2, tx).unwrap();
+        assert_eq!(rx.blocking_recv().unwrap(), vec![json!({"a": {"b": true}})]);
+
+        let file = spool_file(br#"[{"a": 1}, {"a": "#);
+        let (tx, _rx) = mpsc::channel(10);
+        assert!(read_json_chunks(&file, 2, tx).is_err());
+    }
+
+    #[test]
+    fn events_are_counted() {
+        let file = spool_file(br#"[{"a": 1}, {"a": [1, 2]}, {"a": {"b": 3}}]"#);
+        assert_eq!(count_json_events(&file).unwrap(), 3);
+
+        let file = spool_file(br#"{"a": 1, "b": 2}"#);
+        assert_eq!(count_json_events(&file).unwrap(), 1);
+    }
+}
@@ -0,0 +1,294 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Named templates of log streams. `PUT /logstream/{logstream}?template={name}` creates a
+//! stream with the partitioning and schema of the template, then applies its settings and alerts.
+//! A stream the template can not be applied to is not created.
+//!
+//! Streams have no ingestion pipelines in this version, so templates carry none.
+
+use actix_web::{web, HttpRequest, Responder};
+use arrow_schema::Schema;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use relative_path::RelativePathBuf;
+use serde_json::Value;
+
+use crate::alerts::Alerts;
+use crate::event::format::json::MIN_MAX_COLUMNS;
+use crate::handlers::http::logstream::{validate_custom_partition, validate_sort_keys};
+use crate::handlers::http::problem::Problem;
+use crate::option::CONFIG;
+use crate::query::renamed_columns;
+use crate::static_schema::{convert_static_schema_to_arrow_schema, StaticSchema};
+use crate::storage::{ObjectStorageError, StreamSettings, PARSEABLE_ROOT_DIRECTORY};
+use crate::sync::MIN_FLUSH_INTERVAL;
+use crate::utils::actix::request_username;
+use crate::utils::header_parsing::validate_tag_name;
+use crate::validator;
+
+const STREAM_TEMPLATES_DIRECTORY: &str = "stream_templates";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamTemplate {
+    /// set from the path it is put at
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_partition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_partition: Option<String>,
+    /// static schema of the streams, as in the body of a stream creation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub static_schema: Option<Value>,
+    /// retention, caching, flush interval, sort keys and field mapping
+    #[serde(default)]
+    pub settings: StreamSettings,
+    /// alerts of the streams, they need a static schema to be validated against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<Value>,
+    #[serde(default)]
+    pub updated_by: String,
+    #[serde(default = "Utc::now")]
+    pub updated_at: DateTime<Utc>,
+}
+
+impl StreamTemplate {
+    fn validate(&self) -> Result<(), TemplateError> {
+        validator::stream_name(&self.name)
+            .map_err(|err| TemplateError::Invalid(err.to_string()))?;
+
+        let schema = match &self.static_schema {
+            Some(schema) => {
+                let schema: StaticSchema = serde_json::from_value(schema.clone())
+                    .map_err(|err| TemplateError::Invalid(format!("static schema: {err}")))?;
+                Some(
+                    convert_static_schema_to_arrow_schema(schema)
+                        .map_err(|err| TemplateError::Invalid(format!("static schema: {err}")))?,
+                )
+            }
+            None => None,
+        };
+
+        if let Some(custom_partition) = &self.custom_partition {
+            let empty = Schema::empty();
+            validate_custom_partition(
+                custom_partition,
+                self.time_partition.as_deref().unwrap_or_default(),
+                self.static_schema_flag(),
+                schema.as_deref().unwrap_or(&empty),
+            )
+            .map_err(TemplateError::Invalid)?;
+        }
+        validate_sort_keys(&self.settings.sort_keys).map_err(TemplateError::Invalid)?;
+        for name in &self.settings.allowed_tags {
+            validate_tag_name(name).map_err(|err| TemplateError::Invalid(err.to_string()))?;
+        }
+        if self
+            .settings
+            .max_columns
+            .is_some_and(|max_columns| max_columns < MIN_MAX_COLUMNS)
+        {
+            return Err(TemplateError::Invalid(format!(
+                "max columns can not be smaller than {MIN_MAX_COLUMNS}"
+            )));
+        }
+        renamed_columns::validate(&self.settings.renamed_columns)
+            .map_err(TemplateError::Invalid)?;
+        if self
+            .settings
+            .flush_interval
+            .is_some_and(|interval| interval < MIN_FLUSH_INTERVAL)
+        {
+            return Err(TemplateError::Invalid(format!(
+                "flush interval can not be smaller than {}",
+                humantime::format_duration(MIN_FLUSH_INTERVAL)
+            )));
+        }
+
+        if let Some(alerts) = &self.alerts {
+            if schema.is_none() {
+                return Err(TemplateError::Invalid(
+                    "alerts need a static schema to be validated against".to_string(),
+                ));
+            }
+            let alerts: Alerts = serde_json::from_value(alerts.clone())
+                .map_err(|err| TemplateError::Invalid(format!("alerts: {err}")))?;
+            validator::alert(&alerts).map_err(|err| TemplateError::Invalid(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// `true` if the template has a static schema, as in the header of a stream creation
+    pub fn static_schema_flag(&self) -> &'static str {
+        if self.static_schema.is_some() {
+            "true"
+        } else {
+            ""
+        }
+    }
+}
+
+fn templates_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, STREAM_TEMPLATES_DIRECTORY])
+}
+
+fn template_path(name: &str) -> RelativePathBuf {
+    templates_path().join(format!("{name}.json"))
+}
+
+/// Template of the given name
+pub async fn get_template(name: &str) -> Result<StreamTemplate, TemplateError> {
+    validator::stream_name(name).map_err(|_| TemplateError::NotFound(name.to_owned()))?;
+    let store = CONFIG.storage().get_object_store();
+    match store.get_object(&template_path(name)).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(ObjectStorageError::NoSuchKey(_)) => Err(TemplateError::NotFound(name.to_owned())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Handler for GET /api/v1/stream-templates
+pub async fn list() -> Result<impl Responder, TemplateError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = match store
+        .get_objects(
+            Some(&templates_path()),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await
+    {
+        Ok(objects) => objects,
+        // listing a prefix which was never written to fails on local storage
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut templates = objects
+        .iter()
+        .map(|bytes| serde_json::from_slice::<StreamTemplate>(bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(web::Json(templates))
+}
+
+// Handler for GET /api/v1/stream-templates/{name}
+pub async fn get(name: web::Path<String>) -> Result<impl Responder, TemplateError> {
+    Ok(web::Json(get_template(&name).await?))
+}
+
+// Handler for PUT /api/v1/stream-templates/{name}
+// streams created from an earlier version of the template keep their configuration
+pub async fn put(
+    req: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<StreamTemplate>,
+) -> Result<impl Responder, TemplateError> {
+    let template = StreamTemplate {
+        name: name.into_inner(),
+        updated_by: request_username(&req),
+        updated_at: Utc::now(),
+        ..body.into_inner()
+    };
+    template.validate()?;
+
+    CONFIG
+        .storage()
+        .get_object_store()
+        .put_object(
+            &template_path(&template.name),
+            serde_json::to_vec(&template)?.into(),
+        )
+        .await?;
+
+    Ok(web::Json(template))
+}
+
+// Handler for DELETE /api/v1/stream-templates/{name}
+pub async fn delete(name: web::Path<String>) -> Result<impl Responder, TemplateError> {
+    get_template(&name).await?;
+    CONFIG
+        .storage()
+        .get_object_store()
+        .delete_object(&template_path(&name))
+        .await?;
+
+    Ok((format!("deleted stream template {name}"), StatusCode::OK))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("Stream template {0} not found")]
+    NotFound(String),
+    #[error("Invalid stream template: {0}")]
+    Invalid(String),
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+    #[error("Invalid stream template in storage: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+impl actix_web::ResponseError for TemplateError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::ObjectStorage(_) | Self::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::StreamTemplate;
+
+    fn template(value: serde_json::Value) -> StreamTemplate {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn template_validation() {
+        let nginx = template(json!({
+            "name": "nginx",
+            "customPartition": "status",
+            "settings": {"sort-keys": [{"column": "status"}]}
+        }));
+        assert!(nginx.validate().is_ok());
+
+        let invalid_name = template(json!({"name": "Nginx Logs"}));
+        assert!(invalid_name.validate().is_err());
+
+        let alerts_without_schema = template(json!({
+            "name": "nginx",
+            "alerts": {"version": "v1", "alerts": []}
+        }));
+        assert!(alerts_without_schema.validate().is_err());
+    }
+}
@@ -0,0 +1,458 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Windows Event Log events, as XML or as the JSON EVTX files are dumped to.
+//!
+//! XML is one or more `<Event>` elements, on their own or inside a wrapping element:
+//! ```xml
+//! <Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event">
+//!   <System>
+//!     <Provider Name="Microsoft-Windows-Security-Auditing" Guid="{54849625-5478-4994-a5ba-3e3b0328c30d}"/>
+//!     <EventID>4624</EventID>
+//!     <Level>0</Level>
+//!     <TimeCreated SystemTime="2024-01-11T09:08:34.290318Z"/>
+//!     <Computer>dc01.example.com</Computer>
+//!   </System>
+//!   <EventData>
+//!     <Data Name="TargetUserName">alice</Data>
+//!     <Data Name="LogonType">3</Data>
+//!   </EventData>
+//! </Event>
+//! ```
+//! JSON is an array, an object or newline delimited objects of the form
+//! `{"Event": {"System": {..}, "EventData": {..}}}`, with attributes under `#attributes` and the
+//! text of an element with attributes under `#text`.
+//!
+//! The fields of `System` become typed columns such as `event_id`, `provider` and `level`. Fields
+//! of `EventData` or `UserData` are kept as strings under `event_data`, the same field of an event
+//! id is not always written with the same type.
+
+use anyhow::{anyhow, bail};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::{Map, Value};
+
+use super::security::json_values;
+
+const ATTRIBUTES: &str = "#attributes";
+const TEXT: &str = "#text";
+// elements nested deeper than this are rejected, events are only a few levels deep
+const MAX_DEPTH: usize = 32;
+
+/// Events of a Windows Event Log payload, XML or JSON
+pub fn flatten_windows_events(body: &[u8]) -> anyhow::Result<Vec<Value>> {
+    let body = std::str::from_utf8(body).map_err(|_| anyhow!("payload is not utf-8 text"))?;
+    let body = body.trim_start_matches('\u{feff}').trim();
+
+    let events = if body.starts_with('<') {
+        xml_events(body)?
+    } else {
+        json_events(body)?
+    };
+    events.iter().map(event_columns).collect()
+}
+
+fn json_events(body: &str) -> anyhow::Result<Vec<Value>> {
+    json_values(body.as_bytes())?
+        .into_iter()
+        .map(|mut value| match value.get_mut("Event").map(Value::take) {
+            Some(event) => Ok(event),
+            None if value.get("System").is_some() => Ok(value),
+            None => Err(anyhow!("not a Windows event")),
+        })
+        .collect()
+}
+
+// element of the XML payload, before it is converted to the JSON form of an event
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attributes: Map<String, Value>,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn new(start: &BytesStart) -> anyhow::Result<Self> {
+        let mut attributes = Map::new();
+        for attribute in start.attributes() {
+            let attribute = attribute?;
+            // namespace declarations are no fields of the event
+            if attribute.key.as_ref().starts_with(b"xmlns") {
+                continue;
+            }
+            let name = String::from_utf8_lossy(attribute.key.local_name().as_ref()).into_owned();
+            let value = attribute.unescape_value()?.into_owned();
+            attributes.insert(name, Value::String(value));
+        }
+
+        Ok(Self {
+            name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+            attributes,
+            ..Default::default()
+        })
+    }
+
+    // `<Data Name="x">v</Data>` is the field x, as EVTX dumps write it
+    fn into_json(self) -> Value {
+        if self.attributes.is_empty() && self.children.is_empty() {
+            return if self.text.is_empty() {
+                Value::Null
+            } else {
+                Value::String(self.text)
+            };
+        }
+
+        let mut object = Map::new();
+        if !self.attributes.is_empty() {
+            object.insert(ATTRIBUTES.to_owned(), Value::Object(self.attributes));
+        }
+        if !self.text.is_empty() {
+            object.insert(TEXT.to_owned(), Value::String(self.text));
+        }
+        for mut child in self.children {
+            let name = match child.attributes.remove("Name") {
+                Some(Value::String(name)) if child.name == "Data" => name,
+                Some(name) => {
+                    child.attributes.insert("Name".to_owned(), name);
+                    std::mem::take(&mut child.name)
+                }
+                None => std::mem::take(&mut child.name),
+            };
+            let value = child.into_json();
+            match object.get_mut(&name) {
+                Some(Value::Array(values)) => values.push(value),
+                Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+                None => {
+                    object.insert(name, value);
+                }
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+fn xml_events(body: &str) -> anyhow::Result<Vec<Value>> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+
+    let mut stack: Vec<Element> = Vec::new();
+    let mut roots = Vec::new();
+    loop {
+        let closed = match reader.read_event()? {
+            Event::Start(start) => {
+                if stack.len() == MAX_DEPTH {
+                    bail!("XML elements are nested deeper than {MAX_DEPTH} levels");
+                }
+                stack.push(Element::new(&start)?);
+                continue;
+            }
+            Event::Empty(start) => Element::new(&start)?,
+            Event::End(_) => stack.pop().ok_or_else(|| anyhow!("unexpected end tag"))?,
+            Event::Text(text) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&text.unescape()?);
+                }
+                continue;
+            }
+            Event::CData(data) => {
+                if let Some(element) = stack.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&data));
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(closed),
+            None => roots.push(closed),
+        }
+    }
+    if !stack.is_empty() {
+        bail!("XML payload ends inside of an element");
+    }
+
+    let mut events = Vec::new();
+    collect_events(roots, &mut events);
+    if events.is_empty() {
+        bail!("no Event element in XML payload");
+    }
+    Ok(events)
+}
+
+// events may be wrapped in an element like <Events>
+fn collect_events(elements: Vec<Element>, events: &mut Vec<Value>) {
+    for element in elements {
+        if element.name == "Event" {
+            events.push(element.into_json());
+        } else {
+            collect_events(element.children, events);
+        }
+    }
+}
+
+fn event_columns(event: &Value) -> anyhow::Result<Value> {
+    let system = event
+        .get("System")
+        .ok_or_else(|| anyhow!("Windows event without System element"))?;
+    let mut columns = Map::new();
+    let mut insert = |name: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            columns.insert(name.to_owned(), value);
+        }
+    };
+
+    let level = integer(system.get("Level"));
+    insert("event_id", integer(system.get("EventID")));
+    insert(
+        "provider",
+        string(attribute(system.get("Provider"), "Name")),
+    );
+    insert(
+        "provider_guid",
+        string(attribute(system.get("Provider"), "Guid")),
+    );
+    insert("level", level.clone());
+    insert(
+        "level_name",
+        string(event.pointer("/RenderingInfo/Level"))
+            .or_else(|| level_name(level.as_ref()?.as_i64()?)),
+    );
+    insert("task", integer(system.get("Task")));
+    insert("opcode", integer(system.get("Opcode")));
+    insert("keywords", string(system.get("Keywords")));
+    insert(
+        "time_created",
+        string(attribute(system.get("TimeCreated"), "SystemTime")),
+    );
+    insert("event_record_id", integer(system.get("EventRecordID")));
+    insert(
+        "activity_id",
+        string(attribute(system.get("Correlation"), "ActivityID")),
+    );
+    insert(
+        "process_id",
+        integer(attribute(system.get("Execution"), "ProcessID")),
+    );
+    insert(
+        "thread_id",
+        integer(attribute(system.get("Execution"), "ThreadID")),
+    );
+    insert("channel", string(system.get("Channel")));
+    insert("computer", string(system.get("Computer")));
+    insert(
+        "user_id",
+        string(attribute(system.get("Security"), "UserID")),
+    );
+    insert("message", string(event.pointer("/RenderingInfo/Message")));
+
+    // user data has a single element named by the event, holding the fields
+    let data = event.get("EventData").or_else(|| {
+        event
+            .get("UserData")?
+            .as_object()?
+            .iter()
+            .find(|(name, _)| *name != ATTRIBUTES)
+            .map(|(_, data)| data)
+    });
+    if let Some(data) = data.and_then(event_data) {
+        columns.insert("event_data".to_owned(), Value::Object(data));
+    }
+
+    Ok(Value::Object(columns))
+}
+
+fn event_data(data: &Value) -> Option<Map<String, Value>> {
+    let mut fields = Map::new();
+    for (name, value) in data.as_object()? {
+        if name == ATTRIBUTES {
+            continue;
+        }
+        // unnamed data elements are numbered as the insertion strings of the message
+        if name == "Data" {
+            let values = match value.get(TEXT).unwrap_or(value) {
+                Value::Array(values) => values.clone(),
+                value => vec![value.clone()],
+            };
+            for (i, value) in values.iter().enumerate() {
+                if let Some(value) = string(Some(value)) {
+                    fields.insert(format!("data_{}", i + 1), value);
+                }
+            }
+            continue;
+        }
+        if let Some(value) = string(Some(value)) {
+            fields.insert(name.clone(), value);
+        }
+    }
+    (!fields.is_empty()).then_some(fields)
+}
+
+fn attribute<'a>(element: Option<&'a Value>, name: &str) -> Option<&'a Value> {
+    element?.get(ATTRIBUTES)?.get(name)
+}
+
+// text of an element, which is under #text once the element has attributes
+fn text(value: Option<&Value>) -> Option<&Value> {
+    match value? {
+        Value::Object(object) => object.get(TEXT),
+        Value::Null => None,
+        value => Some(value),
+    }
+}
+
+fn integer(value: Option<&Value>) -> Option<Value> {
+    match text(value)? {
+        Value::Number(number) => Some(Value::Number(number.clone())),
+        Value::String(s) => s.trim().parse::<i64>().ok().map(Value::from),
+        _ => None,
+    }
+}
+
+fn string(value: Option<&Value>) -> Option<Value> {
+    match text(value)? {
+        Value::String(s) => Some(Value::String(s.clone())),
+        Value::Number(number) => Some(Value::String(number.to_string())),
+        Value::Bool(b) => Some(Value::String(b.to_string())),
+        value => Some(Value::String(value.to_string())),
+    }
+}
+
+fn level_name(level: i64) -> Option<Value> {
+    let name = match level {
+        0 | 4 => "Information",
+        1 => "Critical",
+        2 => "Error",
+        3 => "Warning",
+        5 => "Verbose",
+        _ => return None,
+    };
+    Some(Value::String(name.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::flatten_windows_events;
+
+    const LOGON: &str = r#"<Events>
+<Event xmlns="http://schemas.microsoft.com/win/2004/08/events/event">
+  <System>
+    <Provider Name="Microsoft-Windows-Security-Auditing" Guid="{54849625-5478-4994-a5ba-3e3b0328c30d}"/>
+    <EventID>4624</EventID>
+    <Version>2</Version>
+    <Level>0</Level>
+    <Task>12544</Task>
+    <Opcode>0</Opcode>
+    <Keywords>0x8020000000000000</Keywords>
+    <TimeCreated SystemTime="2024-01-11T09:08:34.290318Z"/>
+    <EventRecordID>27314</EventRecordID>
+    <Execution ProcessID="652" ThreadID="720"/>
+    <Channel>Security</Channel>
+    <Computer>dc01.example.com</Computer>
+    <Security/>
+  </System>
+  <EventData>
+    <Data Name="TargetUserName">alice</Data>
+    <Data Name="LogonType">3</Data>
+    <Data Name="IpAddress">10.0.0.7</Data>
+  </EventData>
+</Event>
+</Events>"#;
+
+    #[test]
+    fn xml_event_is_lifted_into_columns() {
+        let events = flatten_windows_events(LOGON.as_bytes()).unwrap();
+
+        assert_eq!(
+            events,
+            vec![json!({
+                "event_id": 4624,
+                "provider": "Microsoft-Windows-Security-Auditing",
+                "provider_guid": "{54849625-5478-4994-a5ba-3e3b0328c30d}",
+                "level": 0,
+                "level_name": "Information",
+                "task": 12544,
+                "opcode": 0,
+                "keywords": "0x8020000000000000",
+                "time_created": "2024-01-11T09:08:34.290318Z",
+                "event_record_id": 27314,
+                "process_id": 652,
+                "thread_id": 720,
+                "channel": "Security",
+                "computer": "dc01.example.com",
+                "event_data": {
+                    "TargetUserName": "alice",
+                    "LogonType": "3",
+                    "IpAddress": "10.0.0.7",
+                },
+            })]
+        );
+    }
+
+    #[test]
+    fn evtx_json_is_lifted_into_columns() {
+        let line = json!({
+            "Event": {
+                "#attributes": { "xmlns": "http://schemas.microsoft.com/win/2004/08/events/event" },
+                "System": {
+                    "Provider": { "#attributes": { "Name": "Service Control Manager" } },
+                    "EventID": { "#attributes": { "Qualifiers": 16384 }, "#text": 7036 },
+                    "Level": 4,
+                    "Channel": "System",
+                },
+                "EventData": { "Data": { "#text": ["Windows Update", "running"] } },
+            }
+        })
+        .to_string();
+        let body = format!("{line}\n{line}\n");
+
+        let events = flatten_windows_events(body.as_bytes()).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event_id"], 7036);
+        assert_eq!(events[0]["provider"], "Service Control Manager");
+        assert_eq!(events[0]["level_name"], "Information");
+        assert_eq!(
+            events[0]["event_data"],
+            json!({ "data_1": "Windows Update", "data_2": "running" })
+        );
+    }
+
+    #[test]
+    fn other_payload_is_rejected() {
+        assert!(flatten_windows_events(br#"{"level": "info"}"#).is_err());
+        assert!(flatten_windows_events(b"<Log><Entry/></Log>").is_err());
+    }
+
+    #[test]
+    fn deeply_nested_xml_is_rejected() {
+        // the event element and the nested elements below it
+        let event = |depth: usize| {
+            format!(
+                "<Event><System><EventID>1</EventID></System>{}{}</Event>",
+                "<UserData>".repeat(depth - 1),
+                "</UserData>".repeat(depth - 1)
+            )
+        };
+        assert!(flatten_windows_events(event(super::MAX_DEPTH).as_bytes()).is_ok());
+        assert!(flatten_windows_events(event(super::MAX_DEPTH + 1).as_bytes()).is_err());
+    }
+}
@@ -205,6 +205,28 @@ impl LocalCacheManager {
         Ok(())
     }
 
+    /// Remove the cached copies of the files stored at `keys`, so that data removed from the
+    /// store is not served from the cache anymore
+    pub async fn remove_from_cache(&self, stream: &str, keys: &[String]) -> Result<(), CacheError> {
+        let lock = self.semaphore.lock().await;
+        let mut cache = self.get_cache(stream).await?;
+        for key in keys {
+            let Some(cache_path) = cache.files.remove(key) else {
+                continue;
+            };
+            if let Ok(metadata) = fs::metadata(&cache_path).await {
+                cache.current_size = cache.current_size.saturating_sub(metadata.len());
+            }
+            match fs::remove_file(&cache_path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        self.put_cache(stream, &cache).await?;
+        drop(lock);
+        Ok(())
+    }
+
     pub async fn partition_on_cached<T>(
         &self,
         stream: &str,
@@ -17,29 +17,44 @@
  */
 
 mod about;
+mod admin;
 mod alerts;
 mod analytics;
 mod banner;
 mod catalog;
+mod check_config;
 mod cli;
+mod detections;
 mod event;
+mod external_tables;
+mod fsck;
 mod handlers;
 mod livetail;
 mod localcache;
 mod metadata;
+mod metering;
 mod metrics;
 mod migration;
+mod monitor;
 mod oidc;
 mod option;
 mod query;
 mod rbac;
+mod reload;
+mod replication;
+mod reports;
 mod response;
+mod s3_import;
+mod schema_registry;
+mod shutdown;
 mod static_schema;
 mod stats;
 mod storage;
 mod sync;
+mod tls;
 mod utils;
 mod validator;
+mod webhooks;
 
 use std::sync::Arc;
 
@@ -54,9 +69,23 @@ use crate::{
 };
 pub const STORAGE_UPLOAD_INTERVAL: u32 = 60;
 
+// heap statistics are read from jemalloc
+#[cfg(all(unix, feature = "profiling"))]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    // admin commands, fsck and check-config are run instead of the server
+    if let Some((command, matches)) = option::command_matches() {
+        return match command.as_str() {
+            fsck::FSCK => fsck::run(&matches).await,
+            check_config::CHECK_CONFIG => check_config::run(&matches).await,
+            _ => admin::run(&matches).await,
+        };
+    }
+
+    reload::init_logger();
 
     // these are empty ptrs so mem footprint should be minimal
     let server: Arc<dyn ParseableServer> = match CONFIG.parseable.mode {
@@ -67,6 +96,7 @@ async fn main() -> anyhow::Result<()> {
         Mode::All => Arc::new(Server),
     };
 
+    reload::init_sighup_handler();
     server.init().await?;
 
     Ok(())
@@ -21,12 +21,15 @@ use arrow_schema::{Field, Fields, Schema};
 use chrono::Local;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use crate::alerts::Alerts;
 use crate::metrics::{EVENTS_INGESTED, EVENTS_INGESTED_SIZE};
-use crate::storage::{LogStream, ObjectStorage, StorageDir};
+use crate::option::{Mode, CONFIG};
+use crate::schema_registry::Compatibility;
+use crate::storage::{FieldMapping, LegalHold, LogStream, ObjectStorage, SortKey, StorageDir};
 use crate::utils::arrow::MergedRecordReader;
 
 use self::error::stream_info::{CheckAlertError, LoadError, MetadataError};
@@ -47,7 +50,17 @@ pub struct LogStreamMetadata {
     pub created_at: String,
     pub first_event_at: Option<String>,
     pub time_partition: Option<String>,
+    pub custom_partition: Option<String>,
     pub static_schema_flag: Option<String>,
+    pub flush_interval: Option<Duration>,
+    pub sort_keys: Vec<SortKey>,
+    pub legal_hold: Option<LegalHold>,
+    pub field_mapping: FieldMapping,
+    pub allowed_tags: Vec<String>,
+    pub max_columns: Option<usize>,
+    pub schema_compatibility: Compatibility,
+    pub renamed_columns: BTreeMap<String, String>,
+    pub default_filter: Option<String>,
 }
 
 // It is very unlikely that panic will occur when dealing with metadata.
@@ -100,6 +113,33 @@ impl StreamInfo {
             .map(|metadata| metadata.time_partition.clone())
     }
 
+    pub fn get_custom_partition(&self, stream_name: &str) -> Result<Option<String>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.custom_partition.clone())
+    }
+
+    pub fn get_sort_keys(&self, stream_name: &str) -> Result<Vec<SortKey>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.sort_keys.clone())
+    }
+
+    pub fn set_sort_keys(
+        &self,
+        stream_name: &str,
+        sort_keys: Vec<SortKey>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.sort_keys = sort_keys;
+        Ok(())
+    }
+
     pub fn get_static_schema_flag(
         &self,
         stream_name: &str,
@@ -110,6 +150,152 @@ impl StreamInfo {
             .map(|metadata| metadata.static_schema_flag.clone())
     }
 
+    pub fn legal_hold(&self, stream_name: &str) -> Result<Option<LegalHold>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.legal_hold.clone())
+    }
+
+    pub fn set_legal_hold(
+        &self,
+        stream_name: &str,
+        legal_hold: Option<LegalHold>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.legal_hold = legal_hold;
+        Ok(())
+    }
+
+    pub fn get_field_mapping(&self, stream_name: &str) -> Result<FieldMapping, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.field_mapping)
+    }
+
+    pub fn set_field_mapping(
+        &self,
+        stream_name: &str,
+        field_mapping: FieldMapping,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.field_mapping = field_mapping;
+        Ok(())
+    }
+
+    pub fn get_allowed_tags(&self, stream_name: &str) -> Result<Vec<String>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.allowed_tags.clone())
+    }
+
+    pub fn set_allowed_tags(
+        &self,
+        stream_name: &str,
+        allowed_tags: Vec<String>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.allowed_tags = allowed_tags;
+        Ok(())
+    }
+
+    pub fn get_max_columns(&self, stream_name: &str) -> Result<Option<usize>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.max_columns)
+    }
+
+    pub fn set_max_columns(
+        &self,
+        stream_name: &str,
+        max_columns: Option<usize>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.max_columns = max_columns;
+        Ok(())
+    }
+
+    pub fn get_schema_compatibility(
+        &self,
+        stream_name: &str,
+    ) -> Result<Compatibility, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.schema_compatibility)
+    }
+
+    pub fn set_schema_compatibility(
+        &self,
+        stream_name: &str,
+        schema_compatibility: Compatibility,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.schema_compatibility = schema_compatibility;
+        Ok(())
+    }
+
+    pub fn get_renamed_columns(
+        &self,
+        stream_name: &str,
+    ) -> Result<BTreeMap<String, String>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.renamed_columns.clone())
+    }
+
+    pub fn set_renamed_columns(
+        &self,
+        stream_name: &str,
+        renamed_columns: BTreeMap<String, String>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.renamed_columns = renamed_columns;
+        Ok(())
+    }
+
+    pub fn get_default_filter(&self, stream_name: &str) -> Result<Option<String>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.default_filter.clone())
+    }
+
+    pub fn set_default_filter(
+        &self,
+        stream_name: &str,
+        default_filter: Option<String>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.default_filter = default_filter;
+        Ok(())
+    }
+
     pub fn set_stream_cache(&self, stream_name: &str, enable: bool) -> Result<(), MetadataError> {
         let mut map = self.write().expect(LOCK_EXPECT);
         let stream = map
@@ -119,6 +305,35 @@ impl StreamInfo {
         Ok(())
     }
 
+    pub fn get_flush_interval(&self, stream_name: &str) -> Result<Option<Duration>, MetadataError> {
+        let map = self.read().expect(LOCK_EXPECT);
+        map.get(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))
+            .map(|metadata| metadata.flush_interval)
+    }
+
+    pub fn set_flush_interval(
+        &self,
+        stream_name: &str,
+        flush_interval: Option<Duration>,
+    ) -> Result<(), MetadataError> {
+        let mut map = self.write().expect(LOCK_EXPECT);
+        let stream = map
+            .get_mut(stream_name)
+            .ok_or(MetadataError::StreamMetaNotFound(stream_name.to_string()))?;
+        stream.flush_interval = flush_interval;
+        Ok(())
+    }
+
+    // flush interval of every stream, None for streams using the default interval
+    pub fn flush_intervals(&self) -> Vec<(String, Option<Duration>)> {
+        self.read()
+            .expect(LOCK_EXPECT)
+            .iter()
+            .map(|(stream, metadata)| (stream.clone(), metadata.flush_interval))
+            .collect()
+    }
+
     pub fn schema(&self, stream_name: &str) -> Result<Arc<Schema>, MetadataError> {
         let map = self.read().expect(LOCK_EXPECT);
         let schema = map
@@ -166,6 +381,7 @@ impl StreamInfo {
         stream_name: String,
         created_at: String,
         time_partition: String,
+        custom_partition: String,
         static_schema_flag: String,
         static_schema: HashMap<String, Arc<Field>>,
     ) {
@@ -181,6 +397,11 @@ impl StreamInfo {
             } else {
                 Some(time_partition)
             },
+            custom_partition: if custom_partition.is_empty() {
+                None
+            } else {
+                Some(custom_partition)
+            },
             static_schema_flag: if static_schema_flag != "true" {
                 None
             } else {
@@ -220,7 +441,14 @@ impl StreamInfo {
     ) -> Result<(), LoadError> {
         let alerts = storage.get_alerts(&stream.name).await?;
         let schema = storage.get_schema_on_server_start(&stream.name).await?;
-        let meta = storage.get_stream_metadata(&stream.name).await?;
+        let mut meta = storage.get_stream_metadata(&stream.name).await?;
+        // settings changed while this ingester was down are only in the shared metadata
+        if CONFIG.parseable.mode == Mode::Ingest {
+            let settings = storage.get_stream_settings(&stream.name).await?;
+            if settings.apply_to(&mut meta) {
+                storage.put_stream_manifest(&stream.name, &meta).await?;
+            }
+        }
 
         let schema = update_schema_from_staging(&stream.name, schema);
         let schema = HashMap::from_iter(
@@ -237,7 +465,17 @@ impl StreamInfo {
             created_at: meta.created_at,
             first_event_at: meta.first_event_at,
             time_partition: meta.time_partition,
+            custom_partition: meta.custom_partition,
             static_schema_flag: meta.static_schema_flag,
+            flush_interval: meta.flush_interval,
+            sort_keys: meta.sort_keys,
+            legal_hold: meta.legal_hold,
+            field_mapping: meta.field_mapping,
+            allowed_tags: meta.allowed_tags,
+            max_columns: meta.max_columns,
+            schema_compatibility: meta.schema_compatibility,
+            renamed_columns: meta.renamed_columns,
+            default_filter: meta.default_filter,
         };
 
         let mut map = self.write().expect(LOCK_EXPECT);
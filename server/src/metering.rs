@@ -0,0 +1,248 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Timelike, Utc};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use http::StatusCode;
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::handlers::http::ingest::push_internal_events;
+use crate::handlers::http::query::QueryError;
+use crate::metadata::STREAM_INFO;
+use crate::monitor::{self, ServerEvent};
+use crate::query::QUERY_SESSION;
+
+/// Internal stream the hourly usage records are written to
+pub const METERING_STREAM_NAME: &str = "pmeter";
+
+// usage accumulated in memory is written to the metering stream at this interval
+const METERING_FLUSH_INTERVAL_MINUTES: u32 = 5;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Usage {
+    ingested_bytes: u64,
+    scanned_bytes: u64,
+}
+
+// usage by (hour, stream) since the last flush
+static USAGE: Lazy<Mutex<BTreeMap<(String, String), Usage>>> = Lazy::new(Mutex::default);
+
+fn hour_of(time: DateTime<Utc>) -> String {
+    time.with_minute(0)
+        .and_then(|time| time.with_second(0))
+        .expect("valid time")
+        .format("%Y-%m-%dT%H:00:00Z")
+        .to_string()
+}
+
+fn record(stream_name: &str, f: impl FnOnce(&mut Usage)) {
+    if stream_name == METERING_STREAM_NAME {
+        return;
+    }
+    let key = (hour_of(Utc::now()), stream_name.to_owned());
+    f(USAGE.lock().unwrap().entry(key).or_default())
+}
+
+/// Record bytes received by ingestion for a stream
+pub fn record_ingest(stream_name: &str, bytes: u64) {
+    record(stream_name, |usage| usage.ingested_bytes += bytes)
+}
+
+/// Record bytes of stored data read by a query on a stream
+pub fn record_scan(stream_name: &str, bytes: u64) {
+    record(stream_name, |usage| usage.scanned_bytes += bytes)
+}
+
+fn usage_records(usage: &BTreeMap<(String, String), Usage>) -> Vec<Value> {
+    usage
+        .iter()
+        .map(|((hour, stream), usage)| {
+            json!({
+                "hour": hour,
+                "stream": stream,
+                "ingested_bytes": usage.ingested_bytes,
+                "scanned_bytes": usage.scanned_bytes,
+            })
+        })
+        .collect()
+}
+
+/// Write the usage recorded since the last flush to the metering stream.
+/// Usage is kept for the next flush if it could not be written.
+pub async fn flush() {
+    let usage = std::mem::take(&mut *USAGE.lock().unwrap());
+    if usage.is_empty() {
+        return;
+    }
+
+    if let Err(err) = write_usage(&usage).await {
+        log::warn!("could not write usage to {METERING_STREAM_NAME}: {err}");
+        monitor::record(ServerEvent::error("write usage", &err));
+        let mut current = USAGE.lock().unwrap();
+        for (key, usage) in usage {
+            let entry = current.entry(key).or_default();
+            entry.ingested_bytes += usage.ingested_bytes;
+            entry.scanned_bytes += usage.scanned_bytes;
+        }
+    }
+}
+
+async fn write_usage(usage: &BTreeMap<(String, String), Usage>) -> anyhow::Result<()> {
+    push_internal_events(METERING_STREAM_NAME, Value::Array(usage_records(usage))).await
+}
+
+pub fn init_metering_scheduler() {
+    log::info!("Setting up schedular for usage metering");
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler
+        .every(METERING_FLUSH_INTERVAL_MINUTES.minutes())
+        .run(flush);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportQuery {
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+// GET "/metering?startTime=..&endTime=..&format=json|csv" ==> hourly usage of every stream
+pub async fn export(query: web::Query<ExportQuery>) -> Result<HttpResponse, QueryError> {
+    let ExportQuery {
+        start_time,
+        end_time,
+        format,
+    } = query.into_inner();
+    if start_time > end_time {
+        return Err(QueryError::StartTimeAfterEndTime);
+    }
+    if !STREAM_INFO.stream_exists(METERING_STREAM_NAME) {
+        return Ok(export_response(Vec::new(), format));
+    }
+
+    let sql = format!(
+        "SELECT hour, stream, SUM(ingested_bytes) AS ingested_bytes, SUM(scanned_bytes) AS scanned_bytes \
+         FROM {METERING_STREAM_NAME} WHERE hour >= '{}' AND hour < '{}' \
+         GROUP BY hour, stream ORDER BY hour, stream",
+        hour_of(start_time),
+        hour_of(end_time),
+    );
+    // records of an hour are written up to a flush interval after it ends
+    let query = crate::query::Query {
+        raw_logical_plan: QUERY_SESSION.state().create_logical_plan(&sql).await?,
+        start: start_time,
+        end: end_time
+            + chrono::Duration::hours(1)
+            + chrono::Duration::minutes(METERING_FLUSH_INTERVAL_MINUTES as i64),
+        filter_tag: None,
+        at: None,
+        raw: false,
+    };
+    let (records, _) = query.execute(METERING_STREAM_NAME.to_owned()).await?;
+    let records: Vec<_> = records.iter().collect();
+    let rows =
+        record_batches_to_json_rows(&records).map_err(|err| QueryError::Datafusion(err.into()))?;
+
+    Ok(export_response(rows, format))
+}
+
+fn export_response(
+    rows: Vec<serde_json::Map<String, Value>>,
+    format: ExportFormat,
+) -> HttpResponse {
+    match format {
+        ExportFormat::Json => HttpResponse::build(StatusCode::OK).json(rows),
+        ExportFormat::Csv => HttpResponse::build(StatusCode::OK)
+            .insert_header(ContentType(mime::TEXT_CSV))
+            .body(to_csv(&rows)),
+    }
+}
+
+fn to_csv(rows: &[serde_json::Map<String, Value>]) -> String {
+    let mut csv = String::from("hour,stream,ingested_bytes,scanned_bytes\n");
+    for row in rows {
+        let field = |name: &str| match row.get(name) {
+            Some(Value::String(value)) => format!("\"{}\"", value.replace('"', "\"\"")),
+            Some(Value::Null) | None => String::new(),
+            Some(value) => value.to_string(),
+        };
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            field("hour"),
+            field("stream"),
+            field("ingested_bytes"),
+            field("scanned_bytes")
+        ));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use super::{hour_of, to_csv};
+
+    #[test]
+    fn usage_is_bucketed_by_hour() {
+        let time = Utc.with_ymd_and_hms(2024, 3, 5, 14, 42, 17).unwrap();
+        assert_eq!(hour_of(time), "2024-03-05T14:00:00Z");
+    }
+
+    #[test]
+    fn csv_export() {
+        let row = json!({
+            "hour": "2024-03-05T14:00:00Z",
+            "stream": "app\"logs",
+            "ingested_bytes": 1024,
+            "scanned_bytes": 0
+        });
+        let csv = to_csv(&[row.as_object().unwrap().clone()]);
+        assert_eq!(
+            csv,
+            "hour,stream,ingested_bytes,scanned_bytes\n\"2024-03-05T14:00:00Z\",\"app\"\"logs\",1024,0\n"
+        );
+    }
+}
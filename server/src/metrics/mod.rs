@@ -20,12 +20,33 @@ pub mod prom_utils;
 pub mod storage;
 
 use actix_web_prometheus::{PrometheusMetrics, PrometheusMetricsBuilder};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use futures::TryStreamExt;
 use once_cell::sync::Lazy;
-use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+use prometheus::core::Collector;
+use prometheus::{
+    Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry,
+};
+use relative_path::RelativePathBuf;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::{handlers::http::metrics_path, metadata::STREAM_INFO, option::CONFIG};
+use crate::{
+    handlers::http::{health_check::staging_disk_space, metrics_path},
+    metadata::STREAM_INFO,
+    option::CONFIG,
+    stats::Stats,
+    storage::{object_storage::stream_json_file_name, STREAM_ROOT_DIRECTORY},
+};
 
 pub const METRICS_NAMESPACE: &str = env!("CARGO_PKG_NAME");
+const LOAD_SAMPLE_INTERVAL_SECS: u32 = 15;
+
+/// Values of the `limit` label of [`INGEST_LIMIT_EXCEEDED`]
+pub const INGEST_LIMITS: [&str; 3] = ["body_size", "event_size", "batch_events"];
 
 pub static EVENTS_INGESTED: Lazy<IntCounterVec> = Lazy::new(|| {
     IntCounterVec::new(
@@ -44,6 +65,97 @@ pub static EVENTS_INGESTED_SIZE: Lazy<IntGaugeVec> = Lazy::new(|| {
     .expect("metric can be created")
 });
 
+pub static EVENTS_PARSE_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "events_parse_failed",
+            "Requests rejected because their events could not be parsed",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static EVENTS_SPOOL_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "events_spool_failed",
+            "Requests failed because their body could not be spooled to or read from disk",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static INGEST_LIMIT_EXCEEDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "ingest_limit_exceeded",
+            "Ingest requests with a body, event count or event over the configured limits",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream", "limit"],
+    )
+    .expect("metric can be created")
+});
+
+pub static STAGING_SIZE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    IntGaugeVec::new(
+        Opts::new(
+            "staging_size",
+            "Bytes in staging waiting for conversion or upload",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static STAGING_EVENT_RATE: Lazy<GaugeVec> = Lazy::new(|| {
+    GaugeVec::new(
+        Opts::new(
+            "staging_event_rate",
+            "Events per second written to staging, as used to size its batches",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static STAGING_BATCH_ROWS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    IntGaugeVec::new(
+        Opts::new(
+            "staging_batch_rows",
+            "Rows buffered in memory before they are written to staging",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static EVENTS_INGESTED_RATE: Lazy<Gauge> = Lazy::new(|| {
+    Gauge::with_opts(
+        Opts::new(
+            "events_ingested_rate",
+            "Events ingested per second over the last sampling interval",
+        )
+        .namespace(METRICS_NAMESPACE),
+    )
+    .expect("metric can be created")
+});
+
+pub static STAGING_DISK_USAGE: Lazy<Gauge> = Lazy::new(|| {
+    Gauge::with_opts(
+        Opts::new("staging_disk_usage", "Share of the staging disk in use")
+            .namespace(METRICS_NAMESPACE),
+    )
+    .expect("metric can be created")
+});
+
 pub static STORAGE_SIZE: Lazy<IntGaugeVec> = Lazy::new(|| {
     IntGaugeVec::new(
         Opts::new("storage_size", "Storage size bytes").namespace(METRICS_NAMESPACE),
@@ -68,6 +180,40 @@ pub static QUERY_EXECUTE_TIME: Lazy<HistogramVec> = Lazy::new(|| {
     .expect("metric can be created")
 });
 
+pub static QUERY_SCANNED_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("query_scanned_bytes", "Parquet bytes read by queries")
+            .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static QUERY_SCANNED_FILES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("query_scanned_files", "Parquet files read by queries")
+            .namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static QUERY_RESULT_ROWS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("query_result_rows", "Rows returned by queries").namespace(METRICS_NAMESPACE),
+        &["stream"],
+    )
+    .expect("metric can be created")
+});
+
+pub static QUERIES_EXECUTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("queries_executed", "Queries executed by user").namespace(METRICS_NAMESPACE),
+        &["user"],
+    )
+    .expect("metric can be created")
+});
+
 pub static QUERY_CACHE_HIT: Lazy<IntCounterVec> = Lazy::new(|| {
     IntCounterVec::new(
         Opts::new("QUERY_CACHE_HIT", "Full Cache hit").namespace(METRICS_NAMESPACE),
@@ -76,6 +222,63 @@ pub static QUERY_CACHE_HIT: Lazy<IntCounterVec> = Lazy::new(|| {
     .expect("metric can be created")
 });
 
+pub static QUERIES_RUNNING: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::with_opts(
+        Opts::new("queries_running", "Queries currently executing").namespace(METRICS_NAMESPACE),
+    )
+    .expect("metric can be created")
+});
+
+pub static QUERIES_QUEUED: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::with_opts(
+        Opts::new("queries_queued", "Queries waiting for a free slot").namespace(METRICS_NAMESPACE),
+    )
+    .expect("metric can be created")
+});
+
+pub static QUERIES_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("queries_rejected", "Queries rejected by admission control")
+            .namespace(METRICS_NAMESPACE),
+        &["limit"],
+    )
+    .expect("metric can be created")
+});
+
+pub static CLUSTER_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "cluster_requests",
+            "Requests sent to other nodes by response status",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["node", "status"],
+    )
+    .expect("metric can be created")
+});
+
+// every request in flight holds a pooled connection to the node
+pub static CLUSTER_REQUESTS_IN_FLIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    IntGaugeVec::new(
+        Opts::new(
+            "cluster_requests_in_flight",
+            "Connections to other nodes in use by requests",
+        )
+        .namespace(METRICS_NAMESPACE),
+        &["node"],
+    )
+    .expect("metric can be created")
+});
+
+pub static CLUSTER_REQUEST_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+    HistogramVec::new(
+        HistogramOpts::new("cluster_request_time", "Time of requests to other nodes")
+            .namespace(METRICS_NAMESPACE),
+        &["node"],
+    )
+    .expect("metric can be created")
+});
+
 pub static ALERTS_STATES: Lazy<IntCounterVec> = Lazy::new(|| {
     IntCounterVec::new(
         Opts::new("alerts_states", "Alerts States").namespace(METRICS_NAMESPACE),
@@ -84,6 +287,88 @@ pub static ALERTS_STATES: Lazy<IntCounterVec> = Lazy::new(|| {
     .expect("metric can be created")
 });
 
+/// Label shared by the streams beyond `P_METRICS_STREAM_LIMIT` in counters
+pub const OTHER_STREAMS_LABEL: &str = "_other";
+
+// streams holding a label of their own
+static LABELLED_STREAMS: Lazy<Mutex<HashSet<String>>> = Lazy::new(Mutex::default);
+
+/// Label shared by the users beyond `P_METRICS_STREAM_LIMIT` in [`QUERIES_EXECUTED`]
+pub const OTHER_USERS_LABEL: &str = "_other";
+
+// users holding a label of their own
+static LABELLED_USERS: Lazy<Mutex<HashSet<String>>> = Lazy::new(Mutex::default);
+
+/// Label of a stream in the per stream metrics. The first `P_METRICS_STREAM_LIMIT` streams
+/// are labelled by name, so that a deployment with many streams does not blow up the number of
+/// series. Later streams have no label of their own, they are counted under
+/// [`OTHER_STREAMS_LABEL`] and have no gauges.
+///
+/// The events ingested, their size and the stored size of a stream are its stats, kept by
+/// stream name in [`EVENTS_INGESTED`], [`EVENTS_INGESTED_SIZE`] and the `data` series of
+/// [`STORAGE_SIZE`] whatever the limit.
+pub fn stream_label(stream: &str) -> Option<String> {
+    label_within(
+        &mut LABELLED_STREAMS.lock().unwrap(),
+        CONFIG.parseable.metrics_stream_limit,
+        stream,
+    )
+}
+
+/// Label of a stream in counters and histograms, [`OTHER_STREAMS_LABEL`] beyond the limit
+pub fn stream_counter_label(stream: &str) -> String {
+    stream_label(stream).unwrap_or_else(|| OTHER_STREAMS_LABEL.to_owned())
+}
+
+/// Label of a user in [`QUERIES_EXECUTED`]. As many users as streams are labelled by name,
+/// later users are counted under [`OTHER_USERS_LABEL`].
+pub fn user_label(user: &str) -> String {
+    label_within(
+        &mut LABELLED_USERS.lock().unwrap(),
+        CONFIG.parseable.metrics_stream_limit,
+        user,
+    )
+    .unwrap_or_else(|| OTHER_USERS_LABEL.to_owned())
+}
+
+fn label_within(labelled: &mut HashSet<String>, limit: usize, name: &str) -> Option<String> {
+    if labelled.contains(name) || labelled.len() < limit {
+        labelled.insert(name.to_owned());
+        return Some(name.to_owned());
+    }
+    None
+}
+
+/// Drop the series of a deleted user, freeing its label for another user
+pub fn remove_user_label(user: &str) {
+    if LABELLED_USERS.lock().unwrap().remove(user) {
+        let _ = QUERIES_EXECUTED.remove_label_values(&[user]);
+    }
+}
+
+/// Drop the series of a deleted stream, freeing its label for another stream
+pub fn remove_stream_label(stream: &str) {
+    if LABELLED_STREAMS.lock().unwrap().remove(stream) {
+        let _ = EVENTS_PARSE_FAILED.remove_label_values(&[stream]);
+        let _ = EVENTS_SPOOL_FAILED.remove_label_values(&[stream]);
+        for limit in INGEST_LIMITS {
+            let _ = INGEST_LIMIT_EXCEEDED.remove_label_values(&[stream, limit]);
+        }
+        let _ = STAGING_SIZE.remove_label_values(&[stream]);
+        let _ = STAGING_EVENT_RATE.remove_label_values(&[stream]);
+        let _ = STAGING_BATCH_ROWS.remove_label_values(&[stream]);
+        let _ = STAGING_FILES.remove_label_values(&[stream]);
+        for format in ["arrows", "parquet"] {
+            let _ = STORAGE_SIZE.remove_label_values(&["staging", stream, format]);
+        }
+        let _ = QUERY_EXECUTE_TIME.remove_label_values(&[stream]);
+        let _ = QUERY_SCANNED_BYTES.remove_label_values(&[stream]);
+        let _ = QUERY_SCANNED_FILES.remove_label_values(&[stream]);
+        let _ = QUERY_RESULT_ROWS.remove_label_values(&[stream]);
+        let _ = QUERY_CACHE_HIT.remove_label_values(&[stream]);
+    }
+}
+
 fn custom_metrics(registry: &Registry) {
     registry
         .register(Box::new(EVENTS_INGESTED.clone()))
@@ -91,6 +376,30 @@ fn custom_metrics(registry: &Registry) {
     registry
         .register(Box::new(EVENTS_INGESTED_SIZE.clone()))
         .expect("metric can be registered");
+    registry
+        .register(Box::new(EVENTS_PARSE_FAILED.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(EVENTS_SPOOL_FAILED.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(INGEST_LIMIT_EXCEEDED.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(STAGING_SIZE.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(STAGING_EVENT_RATE.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(STAGING_BATCH_ROWS.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(EVENTS_INGESTED_RATE.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(STAGING_DISK_USAGE.clone()))
+        .expect("metric can be registered");
     registry
         .register(Box::new(STORAGE_SIZE.clone()))
         .expect("metric can be registered");
@@ -100,9 +409,39 @@ fn custom_metrics(registry: &Registry) {
     registry
         .register(Box::new(QUERY_EXECUTE_TIME.clone()))
         .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERY_SCANNED_BYTES.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERY_SCANNED_FILES.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERY_RESULT_ROWS.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERIES_EXECUTED.clone()))
+        .expect("metric can be registered");
     registry
         .register(Box::new(QUERY_CACHE_HIT.clone()))
         .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERIES_RUNNING.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERIES_QUEUED.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(QUERIES_REJECTED.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(CLUSTER_REQUESTS.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(CLUSTER_REQUESTS_IN_FLIGHT.clone()))
+        .expect("metric can be registered");
+    registry
+        .register(Box::new(CLUSTER_REQUEST_TIME.clone()))
+        .expect("metric can be registered");
     registry
         .register(Box::new(ALERTS_STATES.clone()))
         .expect("metric can be registered");
@@ -134,23 +473,108 @@ fn prom_process_metrics(metrics: &PrometheusMetrics) {
 #[cfg(not(target_os = "linux"))]
 fn prom_process_metrics(_metrics: &PrometheusMetrics) {}
 
+/// Load the stats of every stream from the metadata this node writes, the metadata of the
+/// streams is listed concurrently
 pub async fn fetch_stats_from_storage() {
-    for stream_name in STREAM_INFO.list_streams() {
-        let stats = CONFIG
-            .storage()
-            .get_object_store()
-            .get_stats(&stream_name)
-            .await
-            .expect("stats are loaded properly");
+    let store = CONFIG.storage().get_object_store();
+    let prefixes = STREAM_INFO
+        .list_streams()
+        .iter()
+        .map(|stream_name| {
+            RelativePathBuf::from_iter([stream_name.as_str(), STREAM_ROOT_DIRECTORY])
+        })
+        .collect();
+    let file_name = stream_json_file_name();
+    let mut metadata = store.stream_objects_in(prefixes, Arc::new(move |name| name == file_name));
+
+    while let Some((path, bytes)) = metadata
+        .try_next()
+        .await
+        .expect("stats are loaded properly")
+    {
+        let Some(stream_name) = path.iter().next() else {
+            continue;
+        };
+        let stream_metadata: Value =
+            serde_json::from_slice(&bytes).expect("parseable config is valid json");
+        let stats: Stats =
+            serde_json::from_value(stream_metadata["stats"].clone()).unwrap_or_default();
 
         EVENTS_INGESTED
-            .with_label_values(&[&stream_name, "json"])
+            .with_label_values(&[stream_name, "json"])
             .inc_by(stats.events);
         EVENTS_INGESTED_SIZE
-            .with_label_values(&[&stream_name, "json"])
+            .with_label_values(&[stream_name, "json"])
             .set(stats.ingestion as i64);
         STORAGE_SIZE
-            .with_label_values(&["data", &stream_name, "parquet"])
+            .with_label_values(&["data", stream_name, "parquet"])
             .set(stats.storage as i64)
     }
 }
+
+fn total_events_ingested() -> u64 {
+    EVENTS_INGESTED
+        .collect()
+        .iter()
+        .flat_map(|family| family.get_metric())
+        .map(|metric| metric.get_counter().get_value() as u64)
+        .sum()
+}
+
+/// Sample the ingestion rate and the staging disk usage, the query server ranks ingesters by
+/// them when clients ask where to send events
+pub fn init_load_sampler() {
+    let mut scheduler = AsyncScheduler::new();
+    let mut last_total = total_events_ingested();
+    scheduler
+        .every(LOAD_SAMPLE_INTERVAL_SECS.seconds())
+        .run(move || {
+            let total = total_events_ingested();
+            // counters of deleted streams are removed, that is no negative rate
+            let rate = total.saturating_sub(last_total) as f64 / LOAD_SAMPLE_INTERVAL_SECS as f64;
+            EVENTS_INGESTED_RATE.set(rate);
+            last_total = total;
+
+            if let Some((available, size)) = staging_disk_space() {
+                if size > 0 {
+                    STAGING_DISK_USAGE.set(1.0 - available as f64 / size as f64);
+                }
+            }
+            async {}
+        });
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::label_within;
+
+    #[test]
+    fn streams_beyond_limit_have_no_label() {
+        let mut labelled = HashSet::new();
+        assert_eq!(
+            label_within(&mut labelled, 2, "app").as_deref(),
+            Some("app")
+        );
+        assert_eq!(
+            label_within(&mut labelled, 2, "web").as_deref(),
+            Some("web")
+        );
+        assert_eq!(label_within(&mut labelled, 2, "db"), None);
+        assert_eq!(
+            label_within(&mut labelled, 2, "app").as_deref(),
+            Some("app")
+        );
+
+        labelled.remove("web");
+        assert_eq!(label_within(&mut labelled, 2, "db").as_deref(), Some("db"));
+    }
+}
@@ -6,16 +6,19 @@ use serde_json::Value as JsonValue;
 
 use crate::handlers::http::modal::server::Server;
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, utoipa::ToSchema)]
 pub struct Metrics {
     address: String,
     parseable_events_ingested: f64, // all streams
+    parseable_events_ingested_rate: f64,
+    parseable_staging_disk_usage: f64,
     parseable_staging_files: f64,
     process_resident_memory_bytes: f64,
+    #[schema(inline)]
     parseable_storage_size: StorageMetrics,
 }
 
-#[derive(Debug, Serialize, Default, Clone)]
+#[derive(Debug, Serialize, Default, Clone, utoipa::ToSchema)]
 struct StorageMetrics {
     staging: f64,
     data: f64,
@@ -28,6 +31,8 @@ impl Default for Metrics {
         Metrics {
             address,
             parseable_events_ingested: 0.0,
+            parseable_events_ingested_rate: 0.0,
+            parseable_staging_disk_usage: 0.0,
             parseable_staging_files: 0.0,
             process_resident_memory_bytes: 0.0,
             parseable_storage_size: StorageMetrics::default(),
@@ -40,6 +45,8 @@ impl Metrics {
         Metrics {
             address,
             parseable_events_ingested: 0.0,
+            parseable_events_ingested_rate: 0.0,
+            parseable_staging_disk_usage: 0.0,
             parseable_staging_files: 0.0,
             process_resident_memory_bytes: 0.0,
             parseable_storage_size: StorageMetrics::default(),
@@ -48,6 +55,16 @@ impl Metrics {
 }
 
 impl Metrics {
+    /// Events per second the node ingested recently
+    pub fn events_ingested_rate(&self) -> f64 {
+        self.parseable_events_ingested_rate
+    }
+
+    /// Share of the staging disk in use
+    pub fn staging_disk_usage(&self) -> f64 {
+        self.parseable_staging_disk_usage
+    }
+
     pub fn from_prometheus_samples(samples: Vec<PromSample>, address: String) -> Self {
         let mut prom_dress = Metrics::new(address);
 
@@ -56,6 +73,14 @@ impl Metrics {
                 if let PromValue::Counter(val) = sample.value {
                     prom_dress.parseable_events_ingested += val;
                 }
+            } else if sample.metric == "parseable_events_ingested_rate" {
+                if let PromValue::Gauge(val) = sample.value {
+                    prom_dress.parseable_events_ingested_rate = val;
+                }
+            } else if sample.metric == "parseable_staging_disk_usage" {
+                if let PromValue::Gauge(val) = sample.value {
+                    prom_dress.parseable_staging_disk_usage = val;
+                }
             } else if sample.metric == "parseable_staging_files" {
                 if let PromValue::Gauge(val) = sample.value {
                     prom_dress.parseable_staging_files += val;
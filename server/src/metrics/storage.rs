@@ -51,7 +51,7 @@ pub mod localfs {
 pub mod s3 {
     use crate::{metrics::METRICS_NAMESPACE, storage::S3Config};
     use once_cell::sync::Lazy;
-    use prometheus::{HistogramOpts, HistogramVec};
+    use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts};
 
     use super::StorageMetrics;
 
@@ -73,6 +73,33 @@ pub mod s3 {
         .expect("metric can be created")
     });
 
+    // requests to the object store, by the layer sending them and the outcome, which is `ok`
+    // or the class of the error, e.g. `throttled`, `server_error` or `network`
+    pub static STORAGE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+        IntCounterVec::new(
+            Opts::new("s3_requests", "S3 requests by outcome").namespace(METRICS_NAMESPACE),
+            &["layer", "method", "outcome"],
+        )
+        .expect("metric can be created")
+    });
+
+    pub static STORAGE_REQUEST_TIME: Lazy<HistogramVec> = Lazy::new(|| {
+        HistogramVec::new(
+            HistogramOpts::new("s3_request_time", "S3 request latency by outcome")
+                .namespace(METRICS_NAMESPACE),
+            &["layer", "method", "outcome"],
+        )
+        .expect("metric can be created")
+    });
+
+    pub static STORAGE_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+        IntCounterVec::new(
+            Opts::new("s3_bytes", "Bytes read from and written to S3").namespace(METRICS_NAMESPACE),
+            &["layer", "direction"],
+        )
+        .expect("metric can be created")
+    });
+
     impl StorageMetrics for S3Config {
         fn register_metrics(&self, handler: &actix_web_prometheus::PrometheusMetrics) {
             handler
@@ -83,6 +110,18 @@ pub mod s3 {
                 .registry
                 .register(Box::new(QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME.clone()))
                 .expect("metric can be registered");
+            handler
+                .registry
+                .register(Box::new(STORAGE_REQUESTS.clone()))
+                .expect("metric can be registered");
+            handler
+                .registry
+                .register(Box::new(STORAGE_REQUEST_TIME.clone()))
+                .expect("metric can be registered");
+            handler
+                .registry
+                .register(Box::new(STORAGE_BYTES.clone()))
+                .expect("metric can be registered");
         }
     }
 }
@@ -25,42 +25,105 @@ use std::{fs::OpenOptions, sync::Arc};
 
 use bytes::Bytes;
 use itertools::Itertools;
-use relative_path::RelativePathBuf;
+use relative_path::{RelativePath, RelativePathBuf};
 use serde::Serialize;
 
 use crate::{
+    handlers::http::modal::DEFAULT_VERSION,
     option::Config,
     storage::{
-        object_storage::{parseable_json_path, stream_json_path},
-        ObjectStorage, ObjectStorageError, PARSEABLE_METADATA_FILE_NAME, PARSEABLE_ROOT_DIRECTORY,
-        SCHEMA_FILE_NAME, STREAM_ROOT_DIRECTORY,
+        object_storage::{ingester_file_prefix, parseable_json_path, stream_json_path},
+        ObjectStorage, ObjectStorageError, CURRENT_SCHEMA_VERSION,
+        CURRENT_STORAGE_METADATA_VERSION, PARSEABLE_METADATA_FILE_NAME, PARSEABLE_ROOT_DIRECTORY,
+        SCHEMA_FILE_NAME, STREAM_METADATA_FILE_NAME, STREAM_ROOT_DIRECTORY,
     },
 };
 
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("{path} has version {version}, newer than version {current} this server supports. Upgrade the server, or restore the metadata from before the upgrade")]
+    FutureVersion {
+        path: String,
+        version: String,
+        current: &'static str,
+    },
+}
+
+fn get_version(metadata: &serde_json::Value) -> Option<&str> {
+    metadata
+        .as_object()
+        .and_then(|meta| meta.get("version"))
+        .and_then(|version| version.as_str())
+}
+
+// number of a `vN` version
+fn version_number(version: &str) -> Option<u32> {
+    version.strip_prefix('v')?.parse().ok()
+}
+
+/// Fails if the metadata at `path` was written by a newer server. It would be misread, and
+/// overwritten without the fields this server does not know.
+fn ensure_known_version(
+    path: &impl ToString,
+    version: Option<&str>,
+    current: &'static str,
+) -> Result<(), MigrationError> {
+    let Some(version) = version else {
+        return Ok(());
+    };
+    match (version_number(version), version_number(current)) {
+        (Some(number), Some(current_number)) if number > current_number => {
+            Err(MigrationError::FutureVersion {
+                path: path.to_string(),
+                version: version.to_owned(),
+                current,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fail if the metadata of the deployment or of an ingester was written by a newer server
+pub async fn ensure_supported_versions(config: &Config) -> anyhow::Result<()> {
+    let object_store = config.storage().get_object_store();
+    if let Some(metadata) = get_storage_metadata(&*object_store).await? {
+        ensure_known_version(
+            &parseable_json_path(),
+            get_version(&metadata),
+            CURRENT_STORAGE_METADATA_VERSION,
+        )?;
+    }
+    for path in object_store.get_ingester_meta_file_paths().await? {
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&object_store.get_object(&path).await?)?;
+        ensure_known_version(&path, get_version(&metadata), DEFAULT_VERSION)?;
+    }
+    Ok(())
+}
+
 /// Migrate the metdata from v1 or v2 to v3
 /// This is a one time migration
 pub async fn run_metadata_migration(config: &Config) -> anyhow::Result<()> {
+    ensure_supported_versions(config).await?;
+
     let object_store = config.storage().get_object_store();
     let storage_metadata = get_storage_metadata(&*object_store).await?;
     let staging_metadata = get_staging_metadata(config)?;
 
-    fn get_version(metadata: &serde_json::Value) -> Option<&str> {
-        metadata
-            .as_object()
-            .and_then(|meta| meta.get("version"))
-            .and_then(|version| version.as_str())
-    }
-
     // if storage metadata is none do nothing
     if let Some(storage_metadata) = storage_metadata {
         match get_version(&storage_metadata) {
             Some("v1") => {
+                backup(&*object_store, &parseable_json_path(), "v1").await?;
                 let metadata = metadata_migration::v1_v3(storage_metadata);
                 put_remote_metadata(&*object_store, &metadata).await?;
+                prune_backups(&*object_store, &[&parseable_json_path()], "v1").await;
             }
             Some("v2") => {
+                backup(&*object_store, &parseable_json_path(), "v2").await?;
                 let metadata = metadata_migration::v2_v3(storage_metadata);
                 put_remote_metadata(&*object_store, &metadata).await?;
+                prune_backups(&*object_store, &[&parseable_json_path()], "v2").await;
             }
             Some("v3") => {
                 let mdata = metadata_migration::update_v3(storage_metadata);
@@ -72,14 +135,24 @@ pub async fn run_metadata_migration(config: &Config) -> anyhow::Result<()> {
 
     // if staging metadata is none do nothing
     if let Some(staging_metadata) = staging_metadata {
+        let path = parseable_json_path().to_path(config.staging_dir());
+        ensure_known_version(
+            &path.display(),
+            get_version(&staging_metadata),
+            CURRENT_STORAGE_METADATA_VERSION,
+        )?;
         match get_version(&staging_metadata) {
             Some("v1") => {
+                backup_staging(config, &path, "v1")?;
                 let metadata = metadata_migration::v1_v3(staging_metadata);
                 put_staging_metadata(config, &metadata)?;
+                prune_staging_backup(config, "v1");
             }
             Some("v2") => {
+                backup_staging(config, &path, "v2")?;
                 let metadata = metadata_migration::v2_v3(staging_metadata);
                 put_staging_metadata(config, &metadata)?;
+                prune_staging_backup(config, "v2");
             }
             Some("v3") => {
                 let mdata = metadata_migration::update_v3(staging_metadata);
@@ -111,37 +184,42 @@ async fn migration_stream(stream: &str, storage: &dyn ObjectStorage) -> anyhow::
     let stream_metadata: serde_json::Value =
         serde_json::from_slice(&stream_metadata).expect("stream.json is valid json");
 
-    let version = stream_metadata
-        .as_object()
-        .and_then(|meta| meta.get("version"))
-        .and_then(|version| version.as_str());
+    let version = get_version(&stream_metadata).map(str::to_owned);
+    ensure_known_version(&path, version.as_deref(), CURRENT_SCHEMA_VERSION)?;
 
-    match version {
+    // the originals are backed up before anything is written, and the stream metadata is
+    // written last as its version marks the migration done. An interrupted migration is run
+    // again on the next start, from the backups, which are removed once it is done.
+    match version.as_deref() {
         Some("v1") => {
-            let new_stream_metadata = stream_metadata_migration::v1_v3(stream_metadata);
-            storage
-                .put_object(&path, to_bytes(&new_stream_metadata))
-                .await?;
-
             let schema_path =
                 RelativePathBuf::from_iter([stream, STREAM_ROOT_DIRECTORY, SCHEMA_FILE_NAME]);
-            let schema = storage.get_object(&schema_path).await?;
+            backup(storage, &path, "v1").await?;
+            let schema = backup(storage, &schema_path, "v1").await?;
             let schema = serde_json::from_slice(&schema).ok();
             let map = schema_migration::v1_v3(schema)?;
             storage.put_object(&schema_path, to_bytes(&map)).await?;
-        }
-        Some("v2") => {
-            let new_stream_metadata = stream_metadata_migration::v2_v3(stream_metadata);
+
+            let new_stream_metadata = stream_metadata_migration::v1_v3(stream_metadata);
             storage
                 .put_object(&path, to_bytes(&new_stream_metadata))
                 .await?;
-
+            prune_backups(storage, &[&path, &schema_path], "v1").await;
+        }
+        Some("v2") => {
             let schema_path =
                 RelativePathBuf::from_iter([stream, STREAM_ROOT_DIRECTORY, SCHEMA_FILE_NAME]);
-            let schema = storage.get_object(&schema_path).await?;
+            backup(storage, &path, "v2").await?;
+            let schema = backup(storage, &schema_path, "v2").await?;
             let schema = serde_json::from_slice(&schema)?;
             let map = schema_migration::v2_v3(schema)?;
             storage.put_object(&schema_path, to_bytes(&map)).await?;
+
+            let new_stream_metadata = stream_metadata_migration::v2_v3(stream_metadata);
+            storage
+                .put_object(&path, to_bytes(&new_stream_metadata))
+                .await?;
+            prune_backups(storage, &[&path, &schema_path], "v2").await;
         }
         _ => (),
     }
@@ -149,6 +227,131 @@ async fn migration_stream(stream: &str, storage: &dyn ObjectStorage) -> anyhow::
     Ok(())
 }
 
+/// Rename the schema and stream metadata an ingester wrote under its address, before it had an
+/// id, to the names keyed by its id
+pub async fn run_ingester_file_migration(
+    storage: &dyn ObjectStorage,
+    legacy_id: &str,
+    ingester_id: &str,
+) -> anyhow::Result<()> {
+    for stream in storage.list_streams().await? {
+        migrate_ingester_files(storage, &stream.name, legacy_id, ingester_id).await?;
+    }
+    Ok(())
+}
+
+async fn migrate_ingester_files(
+    storage: &dyn ObjectStorage,
+    stream: &str,
+    legacy_id: &str,
+    ingester_id: &str,
+) -> anyhow::Result<()> {
+    for (legacy_path, path) in ingester_file_renames(stream, legacy_id, ingester_id) {
+        let bytes = match storage.get_object(&legacy_path).await {
+            Ok(bytes) => bytes,
+            Err(ObjectStorageError::NoSuchKey(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        // a file already keyed by the id was written later, the legacy one is outdated
+        match storage.get_object(&path).await {
+            Ok(_) => (),
+            Err(ObjectStorageError::NoSuchKey(_)) => storage.put_object(&path, bytes).await?,
+            Err(err) => return Err(err.into()),
+        }
+        storage.delete_object(&legacy_path).await?;
+        log::info!("renamed {legacy_path} to {path}");
+    }
+    Ok(())
+}
+
+// legacy and new path of each file an ingester writes for a stream
+fn ingester_file_renames(
+    stream: &str,
+    legacy_id: &str,
+    ingester_id: &str,
+) -> Vec<(RelativePathBuf, RelativePathBuf)> {
+    let path_of = |id: &str, file_name: &str| {
+        RelativePathBuf::from_iter([
+            stream,
+            STREAM_ROOT_DIRECTORY,
+            &format!("{}{file_name}", ingester_file_prefix(id)),
+        ])
+    };
+    [SCHEMA_FILE_NAME, STREAM_METADATA_FILE_NAME]
+        .into_iter()
+        .map(|file_name| {
+            (
+                path_of(legacy_id, file_name),
+                path_of(ingester_id, file_name),
+            )
+        })
+        .collect()
+}
+
+const MIGRATION_BACKUPS_DIRECTORY: &str = "migration-backups";
+
+/// Directory holding the backups taken before a migration, kept apart from the stream and
+/// ingester metadata so listings of those do not pick them up
+pub fn migration_backups_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, MIGRATION_BACKUPS_DIRECTORY])
+}
+
+// backup of an object before its migration from `version`, under the backups directory
+fn backup_path(path: &RelativePath, version: &str) -> RelativePathBuf {
+    migration_backups_path().join(format!("{path}.{version}.bak"))
+}
+
+/// Back up an object before migrating it from `version`, and return its original content.
+/// If a backup exists, a migration was interrupted after possibly overwriting the object,
+/// so the backup is the original.
+async fn backup(
+    storage: &dyn ObjectStorage,
+    path: &RelativePath,
+    version: &str,
+) -> anyhow::Result<Bytes> {
+    let backup_path = backup_path(path, version);
+    match storage.get_object(&backup_path).await {
+        Ok(original) => return Ok(original),
+        Err(ObjectStorageError::NoSuchKey(_)) => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    let original = storage.get_object(path).await?;
+    storage.put_object(&backup_path, original.clone()).await?;
+    log::info!("backed up {path} to {backup_path} before migrating it from {version}");
+    Ok(original)
+}
+
+// Remove the backups of migrated objects, once the migration is done
+async fn prune_backups(storage: &dyn ObjectStorage, paths: &[&RelativePath], version: &str) {
+    for path in paths {
+        let backup_path = backup_path(path, version);
+        if let Err(err) = storage.delete_object(&backup_path).await {
+            log::warn!("failed to remove the migration backup {backup_path}: {err}");
+        }
+    }
+}
+
+// Copy the staging metadata before migrating it from `version`
+fn backup_staging(config: &Config, path: &std::path::Path, version: &str) -> std::io::Result<()> {
+    let backup_path = backup_path(&parseable_json_path(), version).to_path(config.staging_dir());
+    if let Some(parent) = backup_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(path, backup_path)?;
+    Ok(())
+}
+
+fn prune_staging_backup(config: &Config, version: &str) {
+    let backup_path = backup_path(&parseable_json_path(), version).to_path(config.staging_dir());
+    if let Err(err) = std::fs::remove_file(&backup_path) {
+        log::warn!(
+            "failed to remove the migration backup {}: {err}",
+            backup_path.display()
+        );
+    }
+}
+
 #[inline(always)]
 fn to_bytes(any: &(impl ?Sized + Serialize)) -> Bytes {
     serde_json::to_vec(any)
@@ -297,3 +500,54 @@ async fn run_stream_files_migration(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use relative_path::RelativePath;
+
+    use super::{backup_path, ensure_known_version, ingester_file_renames};
+
+    #[test]
+    fn newer_versions_are_refused() {
+        let path = RelativePath::new("app/.stream/.stream.json");
+        assert!(ensure_known_version(&path, Some("v2"), "v3").is_ok());
+        assert!(ensure_known_version(&path, Some("v3"), "v3").is_ok());
+        assert!(ensure_known_version(&path, None, "v3").is_ok());
+        assert!(ensure_known_version(&path, Some("v4"), "v3").is_err());
+        assert!(ensure_known_version(&path, Some("v10"), "v3").is_err());
+    }
+
+    #[test]
+    fn backups_are_kept_apart_from_the_metadata() {
+        assert_eq!(
+            backup_path(RelativePath::new("app/.stream/.schema"), "v1").as_str(),
+            ".parseable/migration-backups/app/.stream/.schema.v1.bak"
+        );
+        assert_eq!(
+            backup_path(RelativePath::new(".parseable/.parseable.json"), "v2").as_str(),
+            ".parseable/migration-backups/.parseable/.parseable.json.v2.bak"
+        );
+    }
+
+    #[test]
+    fn legacy_ingester_files_are_renamed_by_id() {
+        let renames = ingester_file_renames("app", "10.0.0.1.8000", "01HQ");
+        let renames: Vec<_> = renames
+            .iter()
+            .map(|(legacy, path)| (legacy.as_str(), path.as_str()))
+            .collect();
+        assert_eq!(
+            renames,
+            [
+                (
+                    "app/.stream/.ingester.10.0.0.1.8000.schema",
+                    "app/.stream/.ingester.01HQ.schema"
+                ),
+                (
+                    "app/.stream/.ingester.10.0.0.1.8000.stream.json",
+                    "app/.stream/.ingester.01HQ.stream.json"
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Events of the server itself, written to the internal `pmeta` stream so that the history of
+//! startups, flushes, alert state changes and errors of every node can be looked at with SQL.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+use clokwerk::{AsyncScheduler, TimeUnits};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::about::current;
+use crate::handlers::http::ingest::{create_stream_if_not_exists, push_internal_events};
+use crate::option::{Mode, CONFIG};
+
+/// Internal stream the server writes its own events to
+pub const PMETA_STREAM_NAME: &str = "pmeta";
+
+// events recorded in memory are written to the pmeta stream at this interval
+const PMETA_FLUSH_INTERVAL_SECS: u32 = 30;
+// events recorded while the stream cannot be written to are dropped beyond this
+const MAX_PENDING_EVENTS: usize = 10_000;
+
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Startup {
+        version: String,
+        commit: String,
+    },
+    Shutdown {
+        signal: String,
+        in_flight: usize,
+    },
+    Flush {
+        streams: usize,
+        duration_ms: u64,
+    },
+    FlushFailed {
+        streams: usize,
+        error: String,
+    },
+    AlertStateChanged {
+        stream: String,
+        alert: String,
+        state: String,
+    },
+    Error {
+        context: String,
+        error: String,
+    },
+}
+
+impl ServerEvent {
+    pub fn startup() -> Self {
+        let about = current();
+        ServerEvent::Startup {
+            version: about.released_version.to_string(),
+            commit: about.commit_hash,
+        }
+    }
+
+    pub fn error(context: impl Into<String>, error: impl ToString) -> Self {
+        ServerEvent::Error {
+            context: context.into(),
+            error: error.to_string(),
+        }
+    }
+
+    fn level(&self) -> &'static str {
+        match self {
+            ServerEvent::FlushFailed { .. } | ServerEvent::Error { .. } => "error",
+            _ => "info",
+        }
+    }
+
+    fn into_record(self, node: &str, mode: &str) -> Value {
+        let level = self.level();
+        let mut record = serde_json::to_value(self).expect("server event is serializable");
+        if let Value::Object(map) = &mut record {
+            map.insert("level".to_owned(), level.into());
+            map.insert("node".to_owned(), node.into());
+            map.insert("mode".to_owned(), mode.into());
+            map.insert("time".to_owned(), Utc::now().to_rfc3339().into());
+        }
+        record
+    }
+}
+
+// events since the last flush
+static PENDING: Lazy<Mutex<Vec<Value>>> = Lazy::new(Mutex::default);
+
+/// Record an event of this server, it is written to the pmeta stream with the next flush
+pub fn record(event: ServerEvent) {
+    let record = event.into_record(
+        &CONFIG.parseable.address,
+        &CONFIG.parseable.mode.to_string(),
+    );
+    let mut pending = PENDING.lock().unwrap();
+    if pending.len() < MAX_PENDING_EVENTS {
+        pending.push(record);
+    }
+}
+
+/// Write the events recorded since the last flush to the pmeta stream.
+/// Events are kept for the next flush if they could not be written.
+pub async fn flush() {
+    let events = std::mem::take(&mut *PENDING.lock().unwrap());
+    if events.is_empty() {
+        return;
+    }
+
+    if let Err(err) = push_internal_events(PMETA_STREAM_NAME, Value::Array(events.clone())).await {
+        log::warn!("could not write server events to {PMETA_STREAM_NAME}: {err}");
+        let mut pending = PENDING.lock().unwrap();
+        let room = MAX_PENDING_EVENTS.saturating_sub(pending.len());
+        pending.splice(0..0, events.into_iter().take(room));
+    }
+}
+
+/// Create the pmeta stream if this node creates streams, record the startup and flush events
+/// at an interval. Ingesters write to the stream once the query server created it.
+pub async fn init() {
+    if CONFIG.parseable.mode != Mode::Ingest {
+        if let Err(err) = create_stream_if_not_exists(PMETA_STREAM_NAME).await {
+            log::warn!("could not create {PMETA_STREAM_NAME} stream: {err}");
+        }
+    }
+    record(ServerEvent::startup());
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler
+        .every(PMETA_FLUSH_INTERVAL_SECS.seconds())
+        .run(flush);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::ServerEvent;
+
+    #[test]
+    fn record_is_tagged_with_event_and_node() {
+        let record = ServerEvent::FlushFailed {
+            streams: 2,
+            error: "timed out".to_owned(),
+        }
+        .into_record("ingest-0:8000", "Ingest");
+
+        let mut record = record.as_object().unwrap().clone();
+        assert!(record.remove("time").is_some());
+        assert_eq!(
+            serde_json::Value::Object(record),
+            json!({
+                "event": "flush_failed",
+                "streams": 2,
+                "error": "timed out",
+                "level": "error",
+                "node": "ingest-0:8000",
+                "mode": "Ingest",
+            })
+        );
+    }
+}
@@ -21,6 +21,8 @@ use std::collections::HashMap;
 use openid::{Client, CompactJson, CustomClaims, Discovered, StandardClaims};
 use url::Url;
 
+use crate::tls;
+
 pub type DiscoveredClient = Client<Discovered, Claims>;
 
 // If domain is not configured then
@@ -62,8 +64,17 @@ impl OpenidConfig {
         };
 
         let redirect_uri = redirect_uri.join(redirect_to).expect("valid suffix");
-        DiscoveredClient::discover(self.id, self.secret, redirect_uri.to_string(), self.issuer)
-            .await
+        let http_client = tls::client_builder(None)
+            .build()
+            .expect("client can be built on this system");
+        DiscoveredClient::discover_with_client(
+            http_client,
+            self.id,
+            self.secret,
+            redirect_uri.to_string(),
+            self.issuer,
+        )
+        .await
     }
 }
 
@@ -17,7 +17,7 @@
  */
 
 use clap::error::ErrorKind;
-use clap::{command, Args, Command, FromArgMatches};
+use clap::{command, ArgMatches, Args, Command, FromArgMatches};
 
 use once_cell::sync::Lazy;
 use parquet::basic::{BrotliLevel, GzipLevel, ZstdLevel};
@@ -25,7 +25,10 @@ use std::env;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::admin::{self, ADMIN, ADMIN_STORE};
+use crate::check_config::{self, CHECK_CONFIG};
 use crate::cli::Cli;
+use crate::fsck::{self, FSCK};
 use crate::storage::object_storage::parseable_json_path;
 use crate::storage::{FSConfig, ObjectStorageError, ObjectStorageProvider, S3Config};
 pub const MIN_CACHE_SIZE_BYTES: u64 = 1000u64.pow(3); // 1 GiB
@@ -51,56 +54,76 @@ impl Config {
             .color(clap::ColorChoice::Always)
             .get_matches();
 
-        match cli.subcommand() {
-            Some(("local-store", m)) => {
-                let cli = match Cli::from_arg_matches(m) {
-                    Ok(cli) => cli,
-                    Err(err) => err.exit(),
-                };
-                let storage = match FSConfig::from_arg_matches(m) {
-                    Ok(storage) => storage,
-                    Err(err) => err.exit(),
-                };
+        let config = match cli.subcommand() {
+            // admin commands on the object store configure it as the server does
+            Some((ADMIN, m)) => match m
+                .subcommand_matches(ADMIN_STORE)
+                .and_then(|m| m.subcommand())
+            {
+                Some((store, m)) => Config::from_store(store, m),
+                None => unreachable!("admin commands without a store do not read the config"),
+            },
+            Some((FSCK | CHECK_CONFIG, m)) => match m.subcommand() {
+                Some((store, m)) => Config::from_store(store, m),
+                None => unreachable!("the command has a store"),
+            },
+            Some((store, m)) => Config::from_store(store, m),
+            None => unreachable!(),
+        };
+        config.unwrap_or_else(|err| err.exit())
+    }
+
+    /// Config of the `local-store` or `s3-store` command matched by `m`
+    pub fn from_store(store: &str, m: &ArgMatches) -> Result<Self, clap::Error> {
+        match store {
+            "local-store" => {
+                let cli = Cli::from_arg_matches(m)?;
+                let storage = FSConfig::from_arg_matches(m)?;
 
                 if cli.local_staging_path == storage.root {
-                    create_parseable_cli_command()
-                        .error(
-                            ErrorKind::ValueValidation,
-                            "Cannot use same path for storage and staging",
-                        )
-                        .exit()
+                    return Err(create_parseable_cli_command().error(
+                        ErrorKind::ValueValidation,
+                        "Cannot use same path for storage and staging",
+                    ));
                 }
 
                 if cli.local_cache_path.is_some() {
-                    create_parseable_cli_command()
-                        .error(
-                            ErrorKind::ValueValidation,
-                            "Cannot use cache with local-store subcommand.",
-                        )
-                        .exit()
+                    return Err(create_parseable_cli_command().error(
+                        ErrorKind::ValueValidation,
+                        "Cannot use cache with local-store subcommand.",
+                    ));
                 }
 
-                Config {
+                Ok(Config {
                     parseable: cli,
                     storage: Arc::new(storage),
                     storage_name: "drive",
-                }
+                })
             }
-            Some(("s3-store", m)) => {
-                let cli = match Cli::from_arg_matches(m) {
-                    Ok(cli) => cli,
-                    Err(err) => err.exit(),
-                };
-                let storage = match S3Config::from_arg_matches(m) {
-                    Ok(storage) => storage,
-                    Err(err) => err.exit(),
-                };
-
-                Config {
+            "s3-store" => {
+                let cli = Cli::from_arg_matches(m)?;
+                let storage = S3Config::from_arg_matches(m)?;
+
+                // with restricted cipher suites object store requests go through a proxy which
+                // always verifies certificates, in fips mode they also have to use tls
+                if (cli.fips_mode || !cli.tls_ciphers.is_empty()) && storage.skip_tls {
+                    return Err(create_parseable_cli_command().error(
+                        ErrorKind::ArgumentConflict,
+                        "P_S3_TLS_SKIP_VERIFY cannot be used with restricted cipher suites",
+                    ));
+                }
+                if cli.fips_mode && !storage.endpoint_url.starts_with("https://") {
+                    return Err(create_parseable_cli_command().error(
+                        ErrorKind::ValueValidation,
+                        "FIPS mode requires an https object storage endpoint",
+                    ));
+                }
+
+                Ok(Config {
                     parseable: cli,
                     storage: Arc::new(storage),
                     storage_name: "s3",
-                }
+                })
             }
             _ => unreachable!(),
         }
@@ -167,7 +190,8 @@ impl Config {
     }
 }
 
-fn create_parseable_cli_command() -> Command {
+/// Commands running on an object store, with the args of the server and of the store
+pub fn store_commands() -> [Command; 2] {
     let local = Cli::create_cli_command_with_clap("local-store");
     let local = <FSConfig as Args>::augment_args_for_update(local);
 
@@ -180,7 +204,10 @@ fn create_parseable_cli_command() -> Command {
         });
     let s3 = Cli::create_cli_command_with_clap("s3-store");
     let s3 = <S3Config as Args>::augment_args_for_update(s3);
+    [local, s3]
+}
 
+fn create_parseable_cli_command() -> Command {
     command!()
         .name("Parseable")
         .bin_name("parseable")
@@ -196,7 +223,22 @@ Join the community at https://logg.ing/community.
         "#,
         )
         .subcommand_required(true)
-        .subcommands([local, s3])
+        .subcommands(store_commands())
+        .subcommand(admin::command())
+        .subcommand(fsck::command())
+        .subcommand(check_config::command())
+}
+
+/// Name and matches of the command if the command line runs one instead of the server,
+/// e.g. `admin`
+pub fn command_matches() -> Option<(String, ArgMatches)> {
+    let command = env::args().nth(1)?;
+    if ![ADMIN, FSCK, CHECK_CONFIG].contains(&command.as_str()) {
+        return None;
+    }
+    create_parseable_cli_command()
+        .get_matches()
+        .remove_subcommand()
 }
 
 #[derive(Debug, Default, Eq, PartialEq)]
@@ -232,6 +274,15 @@ impl ToString for Mode {
     }
 }
 
+// priority of threads converting staging files to parquet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConversionPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 pub enum Compression {
@@ -265,11 +316,13 @@ pub mod validation {
         net::ToSocketAddrs,
         path::{Path, PathBuf},
         str::FromStr,
+        time::Duration,
     };
 
     use path_clean::PathClean;
 
     use crate::option::MIN_CACHE_SIZE_BYTES;
+    use crate::storage::staging::encryption::EncryptionKey;
     use human_size::{multiples, SpecificSize};
 
     pub fn file_path(s: &str) -> Result<PathBuf, String> {
@@ -314,6 +367,10 @@ pub mod validation {
         url::Url::parse(s).map_err(|_| "Invalid URL provided".to_string())
     }
 
+    pub fn duration(s: &str) -> Result<Duration, String> {
+        humantime::parse_duration(s).map_err(|_| "Invalid duration provided".to_string())
+    }
+
     fn human_size_to_bytes(s: &str) -> Result<u64, String> {
         fn parse_and_map<T: human_size::Multiple>(
             s: &str,
@@ -321,7 +378,9 @@ pub mod validation {
             SpecificSize::<T>::from_str(s).map(|x| x.to_bytes())
         }
 
-        let size = parse_and_map::<multiples::Mebibyte>(s)
+        let size = parse_and_map::<multiples::Kibibyte>(s)
+            .or(parse_and_map::<multiples::Kilobyte>(s))
+            .or(parse_and_map::<multiples::Mebibyte>(s))
             .or(parse_and_map::<multiples::Megabyte>(s))
             .or(parse_and_map::<multiples::Gigibyte>(s))
             .or(parse_and_map::<multiples::Gigabyte>(s))
@@ -329,13 +388,39 @@ pub mod validation {
             .or(parse_and_map::<multiples::Terabyte>(s))
             .map_err(|_| "Could not parse given size".to_string())?;
 
-        if size < MIN_CACHE_SIZE_BYTES {
-            return Err(
-                "Specified value of cache size is smaller than current minimum of 1GiB".to_string(),
-            );
+        Ok(size)
+    }
+
+    pub fn encryption_key(s: &str) -> Result<EncryptionKey, String> {
+        EncryptionKey::parse(s)
+    }
+
+    pub fn encryption_key_file(s: &str) -> Result<EncryptionKey, String> {
+        let key = std::fs::read_to_string(file_path(s)?)
+            .map_err(|err| format!("could not read key file: {err}"))?;
+        EncryptionKey::parse(&key)
+    }
+
+    pub fn import_mapping(s: &str) -> Result<crate::s3_import::ImportMapping, String> {
+        s.parse()
+    }
+
+    pub fn cipher_suite(s: &str) -> Result<String, String> {
+        let suite = s.trim().to_uppercase();
+        if crate::tls::cipher_suite_names().any(|name| name == suite) {
+            Ok(suite)
+        } else {
+            Err(format!(
+                "unknown cipher suite, supported are {}",
+                crate::tls::cipher_suite_names()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
         }
+    }
 
-        Ok(size)
+    pub fn human_size(s: &str) -> Result<u64, String> {
+        human_size_to_bytes(s)
     }
 
     pub fn cache_size(s: &str) -> Result<u64, String> {
@@ -16,20 +16,32 @@
  *
  */
 
+pub mod active;
+pub mod admission;
+pub mod comparison;
+pub mod default_filter;
 mod filter_optimizer;
 mod listing_table_builder;
+mod memory;
+pub mod patterns;
+pub mod renamed_columns;
+pub mod slow_log;
 mod stream_schema_provider;
 
 use chrono::{DateTime, Utc};
 use chrono::{NaiveDateTime, TimeZone};
 use datafusion::arrow::record_batch::RecordBatch;
 
-use datafusion::common::tree_node::{Transformed, TreeNode, TreeNodeVisitor, VisitRecursion};
+use datafusion::common::tree_node::{Transformed, TreeNode, VisitRecursion};
+use datafusion::datasource::physical_plan::ParquetExec;
 use datafusion::error::DataFusionError;
 use datafusion::execution::context::SessionState;
 use datafusion::execution::disk_manager::DiskManagerConfig;
+use datafusion::execution::memory_pool::{FairSpillPool, GreedyMemoryPool, MemoryPool};
 use datafusion::execution::runtime_env::RuntimeEnv;
-use datafusion::logical_expr::{Explain, Filter, LogicalPlan, PlanType, ToStringifiedPlan};
+use datafusion::execution::TaskContext;
+use datafusion::logical_expr::{expr, Explain, Filter, LogicalPlan, PlanType, ToStringifiedPlan};
+use datafusion::physical_plan::{accept, collect, ExecutionPlan, ExecutionPlanVisitor};
 use datafusion::prelude::*;
 use itertools::Itertools;
 use once_cell::sync::Lazy;
@@ -40,15 +52,45 @@ use std::sync::Arc;
 use sysinfo::{System, SystemExt};
 
 use self::error::ExecuteError;
-use self::stream_schema_provider::GlobalSchemaProvider;
+use self::memory::QueryMemoryPool;
 pub use self::stream_schema_provider::PartialTimeFilter;
+use self::stream_schema_provider::{GlobalSchemaProvider, TimeOrdered};
+use crate::catalog::snapshot::SnapshotAt;
 use crate::event;
+use crate::external_tables;
 use crate::option::CONFIG;
-use crate::storage::{ObjectStorageProvider, StorageDir};
+use crate::storage::{ObjectStorageProvider, ReadLayerRegistry, StorageDir};
 
 pub static QUERY_SESSION: Lazy<SessionContext> =
     Lazy::new(|| Query::create_session_context(CONFIG.storage()));
 
+/// Parquet files and bytes read by an executed query, from staging, cache and object storage
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScanStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl ExecutionPlanVisitor for ScanStats {
+    type Error = DataFusionError;
+
+    fn pre_visit(&mut self, plan: &dyn ExecutionPlan) -> Result<bool, Self::Error> {
+        if let Some(parquet) = plan.as_any().downcast_ref::<ParquetExec>() {
+            self.files += parquet
+                .base_config()
+                .file_groups
+                .iter()
+                .map(|group| group.len() as u64)
+                .sum::<u64>();
+            self.bytes += parquet
+                .metrics()
+                .and_then(|metrics| metrics.sum_by_name("bytes_scanned"))
+                .map_or(0, |bytes| bytes.as_usize() as u64);
+        }
+        Ok(true)
+    }
+}
+
 // A query request by client
 #[derive(Debug)]
 pub struct Query {
@@ -56,6 +98,10 @@ pub struct Query {
     pub start: DateTime<Utc>,
     pub end: DateTime<Utc>,
     pub filter_tag: Option<Vec<String>>,
+    /// past state of the stream to query instead of its current state
+    pub at: Option<SnapshotAt>,
+    /// skip the default filters of the streams
+    pub raw: bool,
 }
 
 impl Query {
@@ -63,9 +109,15 @@ impl Query {
     pub fn create_session_context(
         storage: Arc<dyn ObjectStorageProvider + Send>,
     ) -> SessionContext {
+        // spilling needs a disk manager to write temporary files to
+        let disk_manager = if CONFIG.parseable.query_spill_to_disk {
+            DiskManagerConfig::NewOs
+        } else {
+            DiskManagerConfig::Disabled
+        };
         let runtime_config = storage
             .get_datafusion_runtime()
-            .with_disk_manager(DiskManagerConfig::NewOs);
+            .with_disk_manager(disk_manager);
 
         let (pool_size, fraction) = match CONFIG.parseable.query_memory_pool_size {
             Some(size) => (size, 1.),
@@ -77,13 +129,25 @@ impl Query {
             }
         };
 
-        let runtime_config = runtime_config.with_memory_limit(pool_size, fraction);
+        // fair spill pool splits the memory between spilling operators such as
+        // sorts and aggregations, so that they spill to disk instead of failing
+        let pool_size = (pool_size as f64 * fraction) as usize;
+        let memory_pool: Arc<dyn MemoryPool> = if CONFIG.parseable.query_spill_to_disk {
+            Arc::new(FairSpillPool::new(pool_size))
+        } else {
+            Arc::new(GreedyMemoryPool::new(pool_size))
+        };
+        let runtime_config = runtime_config.with_memory_pool(memory_pool);
         let runtime = Arc::new(RuntimeEnv::new(runtime_config).unwrap());
 
-        let config = SessionConfig::default()
+        let mut config = SessionConfig::default()
+            .with_target_partitions(CONFIG.parseable.query_target_partitions)
             .with_parquet_pruning(true)
             .with_prefer_existing_sort(true)
             .with_round_robin_repartition(true);
+        // read parquet footer and metadata in a single request
+        config.options_mut().execution.parquet.metadata_size_hint =
+            Some(CONFIG.parseable.query_metadata_prefetch_size as usize);
 
         let state = SessionState::new_with_config_rt(config, runtime);
         let schema_provider = Arc::new(GlobalSchemaProvider {
@@ -106,12 +170,49 @@ impl Query {
         &self,
         stream_name: String,
     ) -> Result<(Vec<RecordBatch>, Vec<String>), ExecuteError> {
+        let (results, fields, _) = self.execute_with_stats(stream_name).await?;
+        Ok((results, fields))
+    }
+
+    /// Execute the query, also reporting how much stored data it read
+    pub async fn execute_with_stats(
+        &self,
+        stream_name: String,
+    ) -> Result<(Vec<RecordBatch>, Vec<String>, ScanStats), ExecuteError> {
         let store = CONFIG.storage().get_object_store();
         let object_store_format = store.get_object_store_format(&stream_name).await?;
         let time_partition = object_store_format.time_partition;
 
-        let df = QUERY_SESSION
-            .execute_logical_plan(self.final_logical_plan(&time_partition))
+        // the table provider reads the snapshot to query and whether the query is sorted by
+        // time from the session config
+        let time_column = time_partition
+            .as_deref()
+            .unwrap_or(event::DEFAULT_TIMESTAMP_KEY);
+        let time_ordered = sorted_by_time(&self.raw_logical_plan, time_column);
+        let session = if self.at.is_some() || time_ordered {
+            let state = QUERY_SESSION.state();
+            // the session shares the catalog of the server instead of creating a default one
+            let mut config = state
+                .config()
+                .clone()
+                .with_create_default_catalog_and_schema(false);
+            if let Some(at) = self.at {
+                config = config.with_extension(Arc::new(at));
+            }
+            if time_ordered {
+                config = config.with_extension(Arc::new(TimeOrdered));
+            }
+            SessionContext::new_with_state(SessionState::new_with_config_rt_and_catalog_list(
+                config,
+                state.runtime_env().clone(),
+                state.catalog_list(),
+            ))
+        } else {
+            QUERY_SESSION.clone()
+        };
+        let default_filters = self.default_filters(&session.state()).await;
+        let df = session
+            .execute_logical_plan(self.final_logical_plan(&time_partition, &default_filters))
             .await?;
 
         let fields = df
@@ -123,15 +224,33 @@ impl Query {
             .collect_vec();
 
         if fields.is_empty() {
-            return Ok((vec![], fields));
+            return Ok((vec![], fields, ScanStats::default()));
         }
 
-        let results = df.collect().await?;
-        Ok((results, fields))
+        let task_ctx = query_task_ctx(&session);
+        let plan = df.create_physical_plan().await?;
+        active::running(&plan);
+        let results = collect(plan.clone(), task_ctx).await?;
+
+        let mut stats = ScanStats::default();
+        let _ = accept(plan.as_ref(), &mut stats);
+        Ok((results, fields, stats))
+    }
+
+    /// Default filters of the streams the query reads, none for a query of raw events
+    async fn default_filters(&self, state: &SessionState) -> HashMap<String, Expr> {
+        if self.raw {
+            return HashMap::new();
+        }
+        default_filter::of_plan(state, &self.raw_logical_plan).await
     }
 
     /// return logical plan with all time filters applied through
-    fn final_logical_plan(&self, time_partition: &Option<String>) -> LogicalPlan {
+    fn final_logical_plan(
+        &self,
+        time_partition: &Option<String>,
+        default_filters: &HashMap<String, Expr>,
+    ) -> LogicalPlan {
         let filters = self.filter_tag.clone().and_then(tag_filter);
         // see https://github.com/apache/arrow-datafusion/pull/8400
         // this can be eliminated in later version of datafusion but with slight caveat
@@ -145,6 +264,7 @@ impl Query {
                     self.start.naive_utc(),
                     self.end.naive_utc(),
                     filters,
+                    default_filters,
                     time_partition,
                 );
                 LogicalPlan::Explain(Explain {
@@ -162,41 +282,99 @@ impl Query {
                 self.start.naive_utc(),
                 self.end.naive_utc(),
                 filters,
+                default_filters,
                 time_partition,
             ),
         }
     }
 
+    /// The stream the query reads from, external tables it is joined with are skipped
     pub fn table_name(&self) -> Option<String> {
-        let mut visitor = TableScanVisitor::default();
-        let _ = self.raw_logical_plan.visit(&mut visitor);
-        visitor.into_inner().pop()
+        referenced_tables(&self.raw_logical_plan)
+            .into_iter()
+            .find(|table| !external_tables::exists(table))
     }
 }
 
-#[derive(Debug, Default)]
-pub(crate) struct TableScanVisitor {
-    tables: Vec<String>,
+/// Task context for a single query which shares everything with the session
+/// except for the memory pool and object store requests, which are limited per query
+fn query_task_ctx(ctx: &SessionContext) -> Arc<TaskContext> {
+    let runtime = ctx.runtime_env();
+    let memory_pool: Arc<dyn MemoryPool> = match CONFIG.parseable.query_memory_limit_per_query {
+        Some(limit) => Arc::new(QueryMemoryPool::new(runtime.memory_pool.clone(), limit)),
+        None => runtime.memory_pool.clone(),
+    };
+    let object_store_registry = Arc::new(ReadLayerRegistry::new(
+        runtime.object_store_registry.clone(),
+        CONFIG.parseable.query_max_object_store_requests,
+        CONFIG.parseable.query_read_coalesce_size as usize,
+    ));
+
+    let runtime = RuntimeEnv {
+        memory_pool,
+        disk_manager: runtime.disk_manager.clone(),
+        cache_manager: runtime.cache_manager.clone(),
+        object_store_registry,
+    };
+
+    Arc::new(TaskContext::from(&ctx.state()).with_runtime(Arc::new(runtime)))
 }
 
-impl TableScanVisitor {
-    pub fn into_inner(self) -> Vec<String> {
-        self.tables
-    }
+/// Collects the names of all the physical tables referenced in the plan.
+/// The whole plan is walked so that joins, unions, tables referenced
+/// through CTEs and subqueries are all accounted for.
+pub(crate) fn referenced_tables(plan: &LogicalPlan) -> Vec<String> {
+    let mut tables = Vec::new();
+    collect_tables(plan, &mut tables);
+    tables
 }
 
-impl TreeNodeVisitor for TableScanVisitor {
-    type N = LogicalPlan;
+// subqueries of expressions, e.g. `IN (SELECT ..)`, are not inputs of the plan node they are in
+fn collect_tables(plan: &LogicalPlan, tables: &mut Vec<String>) {
+    let _ = plan.apply(&mut |node| {
+        if let LogicalPlan::TableScan(table) = node {
+            let name = table.table_name.table().to_string();
+            if !tables.contains(&name) {
+                tables.push(name);
+            }
+        }
+        for node_expr in node.expressions() {
+            let _ = node_expr.apply(&mut |e| {
+                match e {
+                    Expr::ScalarSubquery(subquery)
+                    | Expr::Exists(expr::Exists { subquery, .. })
+                    | Expr::InSubquery(expr::InSubquery { subquery, .. }) => {
+                        collect_tables(&subquery.subquery, tables)
+                    }
+                    _ => (),
+                }
+                Ok(VisitRecursion::Continue)
+            });
+        }
+        Ok(VisitRecursion::Continue)
+    });
+}
 
-    fn pre_visit(&mut self, node: &Self::N) -> Result<VisitRecursion, DataFusionError> {
-        match node {
-            LogicalPlan::TableScan(table) => {
-                self.tables.push(table.table_name.table().to_string());
-                Ok(VisitRecursion::Stop)
+// whether the query sorts by the time column, newest first, as stored events are ordered
+fn sorted_by_time(plan: &LogicalPlan, time_column: &str) -> bool {
+    let mut sorted = false;
+    let _ = plan.apply(&mut |node| {
+        if let LogicalPlan::Sort(sort) = node {
+            if let Some(Expr::Sort(expr::Sort {
+                expr,
+                asc: false,
+                nulls_first: true,
+            })) = sort.expr.first()
+            {
+                if matches!(expr.as_ref(), Expr::Column(column) if column.name == time_column) {
+                    sorted = true;
+                    return Ok(VisitRecursion::Stop);
+                }
             }
-            _ => Ok(VisitRecursion::Continue),
         }
-    }
+        Ok(VisitRecursion::Continue)
+    });
+    sorted
 }
 
 fn tag_filter(filters: Vec<String>) -> Option<Expr> {
@@ -214,9 +392,14 @@ fn transform(
     start_time: NaiveDateTime,
     end_time: NaiveDateTime,
     filters: Option<Expr>,
+    default_filters: &HashMap<String, Expr>,
     time_partition: &Option<String>,
 ) -> LogicalPlan {
     plan.transform(&|plan| match plan {
+        // external tables are not partitioned by time
+        LogicalPlan::TableScan(table) if external_tables::exists(table.table_name.table()) => {
+            Ok(Transformed::No(LogicalPlan::TableScan(table)))
+        }
         LogicalPlan::TableScan(table) => {
             let mut new_filters = vec![];
             if !table_contains_any_time_filters(&table, time_partition) {
@@ -260,6 +443,9 @@ fn transform(
             if let Some(tag_filters) = filters.clone() {
                 new_filters.push(tag_filters)
             }
+            if let Some(default_filter) = default_filters.get(table.table_name.table()) {
+                new_filters.push(default_filter.clone())
+            }
             let new_filter = new_filters.into_iter().reduce(and);
             if let Some(new_filter) = new_filter {
                 let filter =
@@ -397,10 +583,76 @@ pub mod error {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use arrow_schema::{DataType, Field, Schema};
+    use chrono::Utc;
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::{col, lit, SessionContext};
     use serde_json::json;
 
+    use crate::metadata::{LogStreamMetadata, STREAM_INFO};
     use crate::query::flatten_objects_for_count;
 
+    use super::{referenced_tables, Query};
+
+    #[tokio::test]
+    async fn tables_of_subqueries_are_referenced() {
+        let ctx = SessionContext::new();
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, true)]));
+        for table in ["a", "b", "c", "d"] {
+            let provider = MemTable::try_new(schema.clone(), vec![vec![]]).unwrap();
+            ctx.register_table(table, Arc::new(provider)).unwrap();
+        }
+
+        let plan = ctx
+            .state()
+            .create_logical_plan(
+                "WITH x AS (SELECT id FROM b) \
+                 SELECT id, (SELECT max(id) FROM d) FROM a \
+                 WHERE id IN (SELECT id FROM x) AND EXISTS (SELECT 1 FROM c)",
+            )
+            .await
+            .unwrap();
+        let mut tables = referenced_tables(&plan);
+        tables.sort();
+        assert_eq!(tables, ["a", "b", "c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn raw_queries_skip_default_filters() {
+        let stream = "default_filter_raw";
+        STREAM_INFO.write().unwrap().insert(
+            stream.to_string(),
+            LogStreamMetadata {
+                default_filter: Some("path <> '/health'".to_string()),
+                ..LogStreamMetadata::default()
+            },
+        );
+        let ctx = SessionContext::new();
+        let schema = Arc::new(Schema::new(vec![Field::new("path", DataType::Utf8, true)]));
+        let provider = MemTable::try_new(schema, vec![vec![]]).unwrap();
+        ctx.register_table(stream, Arc::new(provider)).unwrap();
+        let state = ctx.state();
+
+        let mut query = Query {
+            raw_logical_plan: state
+                .create_logical_plan(&format!("SELECT * FROM {stream}"))
+                .await
+                .unwrap(),
+            start: Utc::now(),
+            end: Utc::now(),
+            filter_tag: None,
+            at: None,
+            raw: false,
+        };
+        let filters = query.default_filters(&state).await;
+        assert_eq!(filters[stream], col("path").not_eq(lit("/health")));
+
+        query.raw = true;
+        assert!(query.default_filters(&state).await.is_empty());
+    }
+
     use super::time_from_path;
     use std::path::PathBuf;
 
@@ -0,0 +1,207 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Queries this server has queued for admission or is running. A query is tracked from the
+//! moment it asks for admission till its response is built. Killing a query drops its
+//! execution, which cancels the scans it is waiting on.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use datafusion::physical_plan::{accept, ExecutionPlan};
+use futures::future::{AbortHandle, Abortable};
+use once_cell::sync::Lazy;
+use ulid::Ulid;
+
+use super::ScanStats;
+
+static ACTIVE_QUERIES: Lazy<Mutex<HashMap<Ulid, Arc<ActiveQuery>>>> = Lazy::new(Mutex::default);
+
+tokio::task_local! {
+    static CURRENT: Arc<ActiveQuery>;
+}
+
+struct ActiveQuery {
+    id: Ulid,
+    user: String,
+    stream: String,
+    digest: String,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    running: AtomicBool,
+    // plans being executed, whose metrics tell the bytes scanned so far
+    plans: Mutex<Vec<Arc<dyn ExecutionPlan>>>,
+    abort: AbortHandle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryState {
+    Queued,
+    Running,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveQueryInfo {
+    pub id: Ulid,
+    pub user: String,
+    pub stream: String,
+    /// hash of the SQL, the same query sent again has the same digest
+    pub digest: String,
+    pub state: QueryState,
+    pub started_at: DateTime<Utc>,
+    pub elapsed_ms: u64,
+    pub scanned_bytes: u64,
+}
+
+/// The query was killed by an admin
+#[derive(Debug, thiserror::Error)]
+#[error("Query was killed")]
+pub struct Killed;
+
+// removes the query once it completes, fails or its request is dropped
+struct Registration(Ulid);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        ACTIVE_QUERIES.lock().expect("not poisoned").remove(&self.0);
+    }
+}
+
+/// Hash of a SQL query, as listed with active queries
+pub fn digest(sql: &str) -> String {
+    format!("{:016x}", xxhash_rust::xxh3::xxh3_64(sql.as_bytes()))
+}
+
+/// Run a query, `query` covers its admission and execution
+pub async fn track<T>(
+    user: &str,
+    stream: &str,
+    sql: &str,
+    query: impl Future<Output = T>,
+) -> Result<T, Killed> {
+    let (abort, abort_registration) = AbortHandle::new_pair();
+    let active = Arc::new(ActiveQuery {
+        id: Ulid::new(),
+        user: user.to_owned(),
+        stream: stream.to_owned(),
+        digest: digest(sql),
+        started_at: Utc::now(),
+        started: Instant::now(),
+        running: AtomicBool::new(false),
+        plans: Mutex::default(),
+        abort,
+    });
+    ACTIVE_QUERIES
+        .lock()
+        .expect("not poisoned")
+        .insert(active.id, active.clone());
+    let _registration = Registration(active.id);
+
+    Abortable::new(CURRENT.scope(active, query), abort_registration)
+        .await
+        .map_err(|_| Killed)
+}
+
+/// Mark the query of the current task as running the plan, it may run more than one
+pub fn running(plan: &Arc<dyn ExecutionPlan>) {
+    let _ = CURRENT.try_with(|active| {
+        active.running.store(true, Ordering::Relaxed);
+        active
+            .plans
+            .lock()
+            .expect("not poisoned")
+            .push(plan.clone());
+    });
+}
+
+/// Queries queued or running, the longest running first
+pub fn list() -> Vec<ActiveQueryInfo> {
+    let active: Vec<_> = ACTIVE_QUERIES
+        .lock()
+        .expect("not poisoned")
+        .values()
+        .cloned()
+        .collect();
+
+    let mut queries: Vec<_> = active
+        .iter()
+        .map(|active| {
+            let mut stats = ScanStats::default();
+            for plan in active.plans.lock().expect("not poisoned").iter() {
+                let _ = accept(plan.as_ref(), &mut stats);
+            }
+            ActiveQueryInfo {
+                id: active.id,
+                user: active.user.clone(),
+                stream: active.stream.clone(),
+                digest: active.digest.clone(),
+                state: if active.running.load(Ordering::Relaxed) {
+                    QueryState::Running
+                } else {
+                    QueryState::Queued
+                },
+                started_at: active.started_at,
+                elapsed_ms: active.started.elapsed().as_millis() as u64,
+                scanned_bytes: stats.bytes,
+            }
+        })
+        .collect();
+    queries.sort_by_key(|query| query.started_at);
+    queries
+}
+
+/// Kill a query, false if it is not queued or running
+pub fn kill(id: Ulid) -> bool {
+    match ACTIVE_QUERIES.lock().expect("not poisoned").get(&id) {
+        Some(active) => {
+            active.abort.abort();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{kill, list, track, QueryState};
+
+    #[tokio::test]
+    async fn killed_queries_stop() {
+        let query = tokio::spawn(track("admin", "app", "SELECT * FROM app", async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }));
+        tokio::task::yield_now().await;
+        while list().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let active = list();
+        assert_eq!(active[0].state, QueryState::Queued);
+        assert!(kill(active[0].id));
+        assert!(query.await.unwrap().is_err());
+        assert!(list().is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::metrics::{QUERIES_QUEUED, QUERIES_REJECTED, QUERIES_RUNNING};
+use crate::option::CONFIG;
+
+pub static QUERY_ADMISSION: Lazy<AdmissionController> = Lazy::new(|| {
+    AdmissionController::new(
+        CONFIG.parseable.query_max_concurrent,
+        CONFIG.parseable.query_max_concurrent_per_user,
+        CONFIG.parseable.query_max_concurrent_per_stream,
+        CONFIG.parseable.query_queue_timeout,
+    )
+});
+
+/// Admission control for queries. A query has to get a slot from the per user,
+/// per stream and global limits before it is allowed to execute. Queries wait
+/// in queue for at most `queue_timeout` before they are rejected.
+#[derive(Debug)]
+pub struct AdmissionController {
+    global: Arc<Slots>,
+    per_user: KeyedLimit,
+    per_stream: KeyedLimit,
+    queue_timeout: RwLock<Duration>,
+}
+
+// Slots of a limit whose size can change while slots are held. Slots held beyond a lowered
+// size are kept till released, new queries wait till fewer than the new size are held.
+// A size of 0 is no limit, the slots held are still counted.
+#[derive(Debug)]
+struct Slots {
+    state: Mutex<SlotState>,
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct SlotState {
+    size: usize,
+    held: usize,
+}
+
+impl Slots {
+    fn new(size: usize) -> Self {
+        Self {
+            state: Mutex::new(SlotState { size, held: 0 }),
+            released: Notify::new(),
+        }
+    }
+
+    fn resize(&self, size: usize) {
+        self.state.lock().expect("not poisoned").size = size;
+        self.released.notify_waiters();
+    }
+
+    async fn acquire(self: Arc<Self>) -> SlotPermit {
+        loop {
+            // registered before the slots are checked, so that a release in between is not missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut state = self.state.lock().expect("not poisoned");
+                if state.size == 0 || state.held < state.size {
+                    state.held += 1;
+                    return SlotPermit {
+                        slots: Arc::clone(&self),
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SlotPermit {
+    slots: Arc<Slots>,
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        self.slots.state.lock().expect("not poisoned").held -= 1;
+        self.slots.released.notify_waiters();
+    }
+}
+
+// slots are created lazily for every key that is seen, and dropped once idle
+#[derive(Debug)]
+struct KeyedLimit {
+    limit: AtomicUsize,
+    semaphores: Mutex<HashMap<String, Arc<Slots>>>,
+}
+
+impl KeyedLimit {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit),
+            semaphores: Mutex::default(),
+        }
+    }
+
+    fn semaphore(&self, key: &str) -> Arc<Slots> {
+        let mut semaphores = self.semaphores.lock().expect("not poisoned");
+        // permits and queued queries hold on to the slots, the map is the only owner of
+        // idle ones
+        semaphores.retain(|_, slots| Arc::strong_count(slots) > 1);
+        let limit = self.limit.load(Ordering::Relaxed);
+        let slots = semaphores
+            .entry(key.to_owned())
+            .or_insert_with(|| Arc::new(Slots::new(limit)));
+        Arc::clone(slots)
+    }
+
+    fn resize(&self, limit: usize) {
+        let semaphores = self.semaphores.lock().expect("not poisoned");
+        self.limit.store(limit, Ordering::Relaxed);
+        for slots in semaphores.values() {
+            slots.resize(limit);
+        }
+    }
+}
+
+impl AdmissionController {
+    /// a limit of 0 disables that limit
+    pub fn new(global: usize, per_user: usize, per_stream: usize, queue_timeout: Duration) -> Self {
+        Self {
+            global: Arc::new(Slots::new(global)),
+            per_user: KeyedLimit::new(per_user),
+            per_stream: KeyedLimit::new(per_stream),
+            queue_timeout: RwLock::new(queue_timeout),
+        }
+    }
+
+    /// Change the limits. Running queries count towards the new limits, if they are lowered
+    /// below the queries running no further query is admitted till enough of them finish.
+    pub fn set_limits(
+        &self,
+        global: usize,
+        per_user: usize,
+        per_stream: usize,
+        queue_timeout: Duration,
+    ) {
+        *self.queue_timeout.write().expect("not poisoned") = queue_timeout;
+        self.global.resize(global);
+        self.per_user.resize(per_user);
+        self.per_stream.resize(per_stream);
+    }
+
+    /// Wait for a free slot to run a query by `user` on `stream`.
+    /// The slot is held till the returned permit is dropped.
+    pub async fn admit(&self, user: &str, stream: &str) -> Result<QueryPermit, AdmissionError> {
+        // acquire the narrower limits first so that a user waiting on their
+        // own limit does not hold on to a slot of the global limit
+        let limits = [
+            ("user", self.per_user.semaphore(user)),
+            ("stream", self.per_stream.semaphore(stream)),
+            ("global", Arc::clone(&self.global)),
+        ];
+        let queue_timeout = *self.queue_timeout.read().expect("not poisoned");
+
+        let deadline = Instant::now() + queue_timeout;
+        let mut permits = Vec::with_capacity(limits.len());
+
+        QUERIES_QUEUED.inc();
+        for (limit, slots) in limits {
+            match tokio::time::timeout_at(deadline, slots.acquire()).await {
+                Ok(permit) => permits.push(permit),
+                Err(_) => {
+                    QUERIES_QUEUED.dec();
+                    QUERIES_REJECTED.with_label_values(&[limit]).inc();
+                    return Err(AdmissionError::Timeout(limit));
+                }
+            }
+        }
+        QUERIES_QUEUED.dec();
+
+        QUERIES_RUNNING.inc();
+        Ok(QueryPermit { _permits: permits })
+    }
+}
+
+/// Slot for a running query, released on drop
+#[derive(Debug)]
+pub struct QueryPermit {
+    _permits: Vec<SlotPermit>,
+}
+
+impl Drop for QueryPermit {
+    fn drop(&mut self) {
+        QUERIES_RUNNING.dec();
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdmissionError {
+    #[error("Too many concurrent queries, {0} limit reached. Please try again later")]
+    Timeout(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{AdmissionController, AdmissionError, KeyedLimit};
+
+    #[actix_web::test]
+    async fn rejects_when_user_limit_is_reached() {
+        let controller = AdmissionController::new(0, 1, 0, Duration::from_millis(10));
+
+        let _permit = controller.admit("alice", "app").await.unwrap();
+        assert!(matches!(
+            controller.admit("alice", "web").await,
+            Err(AdmissionError::Timeout("user"))
+        ));
+        assert!(controller.admit("bob", "app").await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn releases_slot_on_drop() {
+        let controller = AdmissionController::new(1, 0, 0, Duration::from_millis(10));
+
+        let permit = controller.admit("alice", "app").await.unwrap();
+        assert!(matches!(
+            controller.admit("bob", "web").await,
+            Err(AdmissionError::Timeout("global"))
+        ));
+        drop(permit);
+        assert!(controller.admit("bob", "web").await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn new_limits_apply_to_new_queries() {
+        let controller = AdmissionController::new(1, 0, 0, Duration::from_millis(10));
+
+        let _permit = controller.admit("alice", "app").await.unwrap();
+        controller.set_limits(2, 0, 0, Duration::from_millis(10));
+        // the running query counts towards the raised limit
+        let _second = controller.admit("bob", "web").await.unwrap();
+        assert!(matches!(
+            controller.admit("carol", "web").await,
+            Err(AdmissionError::Timeout("global"))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn lowered_limits_count_running_queries() {
+        let controller = AdmissionController::new(2, 0, 0, Duration::from_millis(10));
+
+        let first = controller.admit("alice", "app").await.unwrap();
+        let _second = controller.admit("bob", "app").await.unwrap();
+        controller.set_limits(1, 0, 0, Duration::from_millis(10));
+
+        // both queries keep running, a new one waits till only one query runs
+        drop(first);
+        assert!(matches!(
+            controller.admit("carol", "web").await,
+            Err(AdmissionError::Timeout("global"))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn enabled_limits_count_running_queries() {
+        let controller = AdmissionController::new(0, 0, 0, Duration::from_millis(10));
+
+        let _permit = controller.admit("alice", "app").await.unwrap();
+        controller.set_limits(0, 0, 1, Duration::from_millis(10));
+        assert!(matches!(
+            controller.admit("bob", "app").await,
+            Err(AdmissionError::Timeout("stream"))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn queued_query_is_admitted_once_a_slot_is_released() {
+        let controller = Arc::new(AdmissionController::new(1, 0, 0, Duration::from_secs(5)));
+
+        let permit = controller.admit("alice", "app").await.unwrap();
+        let queued = {
+            let controller = Arc::clone(&controller);
+            tokio::spawn(async move { controller.admit("bob", "web").await.is_ok() })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(permit);
+        assert!(queued.await.unwrap());
+    }
+
+    #[test]
+    fn idle_keys_are_dropped() {
+        let limit = KeyedLimit::new(1);
+        let held = limit.semaphore("alice");
+        let _ = limit.semaphore("bob");
+        let _ = limit.semaphore("carol");
+
+        let semaphores = limit.semaphores.lock().unwrap();
+        assert_eq!(semaphores.len(), 2);
+        assert!(semaphores.contains_key("alice"));
+        drop(semaphores);
+        drop(held);
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Alignment of the results of a query over the current window with the results of the same
+//! query over an earlier comparison window.
+
+use std::collections::{HashMap, VecDeque};
+
+use chrono::Duration;
+use datafusion::arrow::array::{Array, ArrayRef, AsArray};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Int64Type, TimeUnit};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use datafusion::arrow::record_batch::RecordBatch;
+use serde_json::{Map, Value};
+
+/// Suffix of the columns holding the values of the comparison window
+pub const PREVIOUS_SUFFIX: &str = "_previous";
+
+/// Move every timestamp column of `batch` forward by `offset`, so that the rows of the
+/// comparison window carry the times of the current window.
+pub fn shift_timestamps(batch: &RecordBatch, offset: Duration) -> Result<RecordBatch, ArrowError> {
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| match column.data_type() {
+            DataType::Timestamp(unit, _) => shift_column(column, unit, offset),
+            _ => Ok(column.clone()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    RecordBatch::try_new(batch.schema(), columns)
+}
+
+fn shift_column(
+    column: &ArrayRef,
+    unit: &TimeUnit,
+    offset: Duration,
+) -> Result<ArrayRef, ArrowError> {
+    let delta = match unit {
+        TimeUnit::Second => Some(offset.num_seconds()),
+        TimeUnit::Millisecond => Some(offset.num_milliseconds()),
+        TimeUnit::Microsecond => offset.num_microseconds(),
+        TimeUnit::Nanosecond => offset.num_nanoseconds(),
+    }
+    .ok_or_else(|| ArrowError::ComputeError("comparison offset is too large".to_string()))?;
+
+    let values = cast(column, &DataType::Int64)?;
+    let shifted = values
+        .as_primitive::<Int64Type>()
+        .unary::<_, Int64Type>(|value| value + delta);
+    cast(&shifted, column.data_type())
+}
+
+/// Join the rows of the current and the comparison window on their non numeric columns, such as
+/// the time bucket or a group key. Every numeric column of the comparison window is added to the
+/// joined row with the `_previous` suffix, rows found in only one window get nulls for the other.
+///
+/// Returns the names of the columns along with the rows.
+#[allow(clippy::type_complexity)]
+pub fn align(
+    current: &[RecordBatch],
+    previous: &[RecordBatch],
+) -> Result<(Vec<String>, Vec<Map<String, Value>>), ArrowError> {
+    let Some(schema) = current
+        .first()
+        .or(previous.first())
+        .map(|batch| batch.schema())
+    else {
+        return Ok((Vec::new(), Vec::new()));
+    };
+    let (values, keys): (Vec<_>, Vec<_>) = schema
+        .fields()
+        .iter()
+        .partition(|field| field.data_type().is_numeric());
+    let keys: Vec<&String> = keys.into_iter().map(|field| field.name()).collect();
+    let values: Vec<&String> = values.into_iter().map(|field| field.name()).collect();
+
+    let mut fields: Vec<String> = schema
+        .fields()
+        .iter()
+        .map(|field| field.name().clone())
+        .collect();
+    fields.extend(values.iter().map(|name| format!("{name}{PREVIOUS_SUFFIX}")));
+
+    let key_of = |row: &Map<String, Value>| {
+        let key: Vec<&Value> = keys
+            .iter()
+            .map(|name| row.get(*name).unwrap_or(&Value::Null))
+            .collect();
+        serde_json::to_string(&key).expect("json values serialize")
+    };
+
+    let current = record_batches_to_json_rows(&current.iter().collect::<Vec<_>>())?;
+    let previous = record_batches_to_json_rows(&previous.iter().collect::<Vec<_>>())?;
+
+    // rows sharing a key are paired in order
+    let mut unmatched: HashMap<String, VecDeque<usize>> = HashMap::new();
+    for (index, row) in previous.iter().enumerate() {
+        unmatched.entry(key_of(row)).or_default().push_back(index);
+    }
+    let mut matched = vec![false; previous.len()];
+
+    let mut rows = Vec::with_capacity(current.len());
+    for mut row in current {
+        let pair = unmatched
+            .get_mut(&key_of(&row))
+            .and_then(|indices| indices.pop_front());
+        for name in &values {
+            let value = pair
+                .and_then(|index| previous[index].get(*name).cloned())
+                .unwrap_or(Value::Null);
+            row.insert(format!("{name}{PREVIOUS_SUFFIX}"), value);
+        }
+        if let Some(index) = pair {
+            matched[index] = true;
+        }
+        rows.push(row);
+    }
+
+    for (row, _) in previous
+        .into_iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+    {
+        let mut aligned = Map::new();
+        for name in &keys {
+            if let Some(value) = row.get(*name) {
+                aligned.insert(name.to_string(), value.clone());
+            }
+        }
+        for name in &values {
+            aligned.insert(name.to_string(), Value::Null);
+            aligned.insert(
+                format!("{name}{PREVIOUS_SUFFIX}"),
+                row.get(*name).cloned().unwrap_or(Value::Null),
+            );
+        }
+        rows.push(aligned);
+    }
+
+    Ok((fields, rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Duration;
+    use datafusion::arrow::array::{Array, Int64Array, StringArray, TimestampMillisecondArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use serde_json::json;
+
+    use super::{align, shift_timestamps};
+
+    fn batch(times: &[i64], levels: &[&str], counts: &[i64]) -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new(
+                "time",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+            Field::new("level", DataType::Utf8, false),
+            Field::new("count", DataType::Int64, false),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(TimestampMillisecondArray::from(times.to_vec())),
+                Arc::new(StringArray::from(levels.to_vec())),
+                Arc::new(Int64Array::from(counts.to_vec())),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn timestamps_are_shifted() {
+        let shifted =
+            shift_timestamps(&batch(&[0, 1000], &["a", "b"], &[1, 2]), Duration::days(1)).unwrap();
+        let times = shifted
+            .column(0)
+            .as_any()
+            .downcast_ref::<TimestampMillisecondArray>()
+            .unwrap();
+        assert_eq!(times.values(), &[86_400_000, 86_401_000]);
+        assert_eq!(shifted.column(2).len(), 2);
+    }
+
+    #[test]
+    fn rows_are_aligned_on_keys() {
+        let current = batch(&[0, 0], &["error", "warn"], &[5, 3]);
+        let previous = batch(&[0, 0], &["error", "info"], &[2, 7]);
+
+        let (fields, rows) = align(&[current], &[previous]).unwrap();
+        assert_eq!(fields, ["time", "level", "count", "count_previous"]);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["level"], json!("error"));
+        assert_eq!(rows[0]["count"], json!(5));
+        assert_eq!(rows[0]["count_previous"], json!(2));
+        assert_eq!(rows[1]["count_previous"], json!(null));
+        assert_eq!(rows[2]["level"], json!("info"));
+        assert_eq!(rows[2]["count"], json!(null));
+        assert_eq!(rows[2]["count_previous"], json!(7));
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Predicates set on streams, e.g. to leave out health checks. Every scan of the stream is
+//! filtered with it, unless the query asks for raw events. It saves readers of the stream from
+//! repeating the predicate, it does not restrict access as any reader can ask for raw events.
+//!
+//! A default projection, to leave out columns, is out of scope: a filter only drops events,
+//! while a projection would change the columns of `SELECT *` and of every saved query.
+
+use std::collections::HashMap;
+
+use datafusion::common::tree_node::{Transformed, TreeNode, VisitRecursion};
+use datafusion::common::Column;
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::SessionState;
+use datafusion::logical_expr::{Expr, LogicalPlan};
+
+use super::referenced_tables;
+use crate::metadata::STREAM_INFO;
+
+/// Plan the default filter of a stream, a SQL predicate as in the `WHERE` clause of a query of
+/// the stream. Its columns are unqualified so that it applies to any scan of the stream.
+pub async fn plan(
+    state: &SessionState,
+    stream_name: &str,
+    filter: &str,
+) -> Result<Expr, DataFusionError> {
+    let sql = format!("SELECT * FROM \"{stream_name}\" WHERE {filter}");
+    let plan = state.create_logical_plan(&sql).await?;
+
+    // anything past the predicate, e.g. a union or a limit, plans to another shape
+    let LogicalPlan::Projection(projection) = plan else {
+        return Err(not_a_predicate());
+    };
+    let LogicalPlan::Filter(filter) = projection.input.as_ref() else {
+        return Err(not_a_predicate());
+    };
+    if !matches!(filter.input.as_ref(), LogicalPlan::TableScan(_)) {
+        return Err(not_a_predicate());
+    }
+    if has_subquery(&filter.predicate) {
+        return Err(DataFusionError::Plan(
+            "default filter can not have subqueries".to_string(),
+        ));
+    }
+
+    filter.predicate.clone().transform(&|expr| match expr {
+        Expr::Column(column) => Ok(Transformed::Yes(Expr::Column(Column::from_name(
+            column.name,
+        )))),
+        expr => Ok(Transformed::No(expr)),
+    })
+}
+
+/// Default filters of the streams the plan reads, by stream. A filter which does not plan
+/// anymore is skipped rather than failing every query of its stream.
+pub async fn of_plan(state: &SessionState, plan: &LogicalPlan) -> HashMap<String, Expr> {
+    let mut filters = HashMap::new();
+    for table in referenced_tables(plan) {
+        let Ok(Some(filter)) = STREAM_INFO.get_default_filter(&table) else {
+            continue;
+        };
+        match self::plan(state, &table, &filter).await {
+            Ok(predicate) => {
+                filters.insert(table, predicate);
+            }
+            Err(err) => log::warn!("default filter of log stream {table} is skipped: {err}"),
+        }
+    }
+    filters
+}
+
+fn not_a_predicate() -> DataFusionError {
+    DataFusionError::Plan("default filter has to be a predicate".to_string())
+}
+
+fn has_subquery(predicate: &Expr) -> bool {
+    let mut found = false;
+    let _ = predicate.apply(&mut |expr| {
+        if matches!(
+            expr,
+            Expr::Exists(_) | Expr::InSubquery(_) | Expr::ScalarSubquery(_)
+        ) {
+            found = true;
+            return Ok(VisitRecursion::Stop);
+        }
+        Ok(VisitRecursion::Continue)
+    });
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow_schema::{DataType, Field, Schema};
+    use datafusion::datasource::MemTable;
+    use datafusion::logical_expr::{col, lit};
+    use datafusion::prelude::SessionContext;
+
+    use super::plan;
+
+    fn session() -> SessionContext {
+        let ctx = SessionContext::new();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("path", DataType::Utf8, true),
+            Field::new("status", DataType::Int64, true),
+        ]));
+        let provider = MemTable::try_new(schema, vec![vec![]]).unwrap();
+        ctx.register_table("app", Arc::new(provider)).unwrap();
+        ctx
+    }
+
+    #[tokio::test]
+    async fn predicate_is_planned_with_unqualified_columns() {
+        let state = session().state();
+        let predicate = plan(&state, "app", "path <> '/health' AND status >= 200")
+            .await
+            .unwrap();
+        assert_eq!(
+            predicate,
+            col("path")
+                .not_eq(lit("/health"))
+                .and(col("status").gt_eq(lit(200i64)))
+        );
+    }
+
+    #[tokio::test]
+    async fn anything_but_a_predicate_is_refused() {
+        let state = session().state();
+        for filter in [
+            "status = 200 LIMIT 1",
+            "status = 200 UNION SELECT * FROM app",
+            "status IN (SELECT status FROM app)",
+            "missing = 1",
+        ] {
+            assert!(
+                plan(&state, "app", filter).await.is_err(),
+                "{filter} was planned"
+            );
+        }
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::memory_pool::{MemoryConsumer, MemoryPool, MemoryReservation};
+
+/// Memory pool that caps the memory a single query can reserve.
+/// Every reservation is also made against the server wide pool so
+/// the global budget keeps being enforced across concurrent queries.
+#[derive(Debug)]
+pub struct QueryMemoryPool {
+    inner: Arc<dyn MemoryPool>,
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl QueryMemoryPool {
+    pub fn new(inner: Arc<dyn MemoryPool>, limit: usize) -> Self {
+        Self {
+            inner,
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MemoryPool for QueryMemoryPool {
+    fn register(&self, consumer: &MemoryConsumer) {
+        self.inner.register(consumer)
+    }
+
+    fn unregister(&self, consumer: &MemoryConsumer) {
+        self.inner.unregister(consumer)
+    }
+
+    fn grow(&self, reservation: &MemoryReservation, additional: usize) {
+        self.inner.grow(reservation, additional);
+        self.used.fetch_add(additional, Ordering::Relaxed);
+    }
+
+    fn shrink(&self, reservation: &MemoryReservation, shrink: usize) {
+        self.inner.shrink(reservation, shrink);
+        self.used.fetch_sub(shrink, Ordering::Relaxed);
+    }
+
+    fn try_grow(&self, reservation: &MemoryReservation, additional: usize) -> Result<()> {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let new_used = used + additional;
+                (new_used <= self.limit).then_some(new_used)
+            })
+            .map_err(|used| {
+                DataFusionError::ResourcesExhausted(format!(
+                    "Failed to allocate additional {} bytes with {} bytes already allocated - query memory limit of {} bytes reached",
+                    additional,
+                    used,
+                    self.limit
+                ))
+            })?;
+
+        // release the query level reservation if the server wide pool is exhausted
+        if let Err(err) = self.inner.try_grow(reservation, additional) {
+            self.used.fetch_sub(additional, Ordering::Relaxed);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn reserved(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::execution::memory_pool::{GreedyMemoryPool, MemoryConsumer, MemoryPool};
+
+    use super::QueryMemoryPool;
+
+    #[test]
+    fn query_limit_is_enforced() {
+        let global: Arc<dyn MemoryPool> = Arc::new(GreedyMemoryPool::new(1024));
+        let pool: Arc<dyn MemoryPool> = Arc::new(QueryMemoryPool::new(global.clone(), 100));
+
+        let mut reservation = MemoryConsumer::new("test").register(&pool);
+        reservation.try_grow(100).unwrap();
+        assert!(reservation.try_grow(1).is_err());
+        assert_eq!(global.reserved(), 100);
+
+        reservation.shrink(50);
+        reservation.try_grow(50).unwrap();
+        assert_eq!(pool.reserved(), 100);
+    }
+
+    #[test]
+    fn global_limit_is_enforced() {
+        let global: Arc<dyn MemoryPool> = Arc::new(GreedyMemoryPool::new(64));
+        let pool: Arc<dyn MemoryPool> = Arc::new(QueryMemoryPool::new(global.clone(), 100));
+
+        let mut reservation = MemoryConsumer::new("test").register(&pool);
+        assert!(reservation.try_grow(80).is_err());
+        assert_eq!(pool.reserved(), 0);
+        assert_eq!(global.reserved(), 0);
+    }
+}
@@ -0,0 +1,192 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Clustering of log lines by message template, following the Drain algorithm
+//! (He et al., "Drain: An Online Log Parsing Approach with Fixed Depth Tree").
+
+use std::collections::HashMap;
+
+/// Token standing for the variable parts of a template
+pub const WILDCARD: &str = "<*>";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Pattern {
+    pub template: String,
+    pub count: u64,
+    pub samples: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Cluster {
+    tokens: Vec<String>,
+    count: u64,
+    samples: Vec<String>,
+}
+
+impl Cluster {
+    // share of the template's constant tokens found at the same position in `tokens`
+    fn similarity(&self, tokens: &[String]) -> (f64, usize) {
+        let mut equal = 0;
+        let mut wildcards = 0;
+        for (template, token) in self.tokens.iter().zip(tokens) {
+            if template == WILDCARD {
+                wildcards += 1;
+            } else if template == token {
+                equal += 1;
+            }
+        }
+        (equal as f64 / self.tokens.len() as f64, wildcards)
+    }
+
+    fn merge(&mut self, tokens: Vec<String>) {
+        for (template, token) in self.tokens.iter_mut().zip(tokens) {
+            if *template != token {
+                *template = WILDCARD.to_string();
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Drain {
+    similarity_threshold: f64,
+    max_samples: usize,
+    // clusters grouped by token count and first token
+    groups: HashMap<(usize, String), Vec<Cluster>>,
+}
+
+impl Drain {
+    pub fn new(similarity_threshold: f64, max_samples: usize) -> Self {
+        Self {
+            similarity_threshold,
+            max_samples,
+            groups: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, line: &str) {
+        let tokens = tokenize(line);
+        let Some(first) = tokens.first() else {
+            return;
+        };
+
+        let clusters = self
+            .groups
+            .entry((tokens.len(), first.clone()))
+            .or_default();
+
+        // most similar cluster, ties go to the more general template
+        let best = clusters
+            .iter()
+            .enumerate()
+            .map(|(index, cluster)| (cluster.similarity(&tokens), index))
+            .filter(|((similarity, _), _)| *similarity >= self.similarity_threshold)
+            .max_by(|((a, a_wildcards), _), ((b, b_wildcards), _)| {
+                a.total_cmp(b).then(a_wildcards.cmp(b_wildcards))
+            })
+            .map(|(_, index)| index);
+
+        let cluster = match best {
+            Some(index) => {
+                let cluster = &mut clusters[index];
+                cluster.merge(tokens);
+                cluster
+            }
+            None => {
+                clusters.push(Cluster {
+                    tokens,
+                    count: 0,
+                    samples: Vec::new(),
+                });
+                clusters.last_mut().expect("cluster was just added")
+            }
+        };
+
+        cluster.count += 1;
+        if cluster.samples.len() < self.max_samples {
+            cluster.samples.push(line.to_owned());
+        }
+    }
+
+    /// Patterns found so far, most frequent first
+    pub fn into_patterns(self) -> Vec<Pattern> {
+        let mut patterns: Vec<_> = self
+            .groups
+            .into_values()
+            .flatten()
+            .map(|cluster| Pattern {
+                template: cluster.tokens.join(" "),
+                count: cluster.count,
+                samples: cluster.samples,
+            })
+            .collect();
+        patterns.sort_by(|a, b| b.count.cmp(&a.count).then(a.template.cmp(&b.template)));
+        patterns
+    }
+}
+
+// tokens containing digits are almost always variables such as ids, sizes or timestamps
+fn tokenize(line: &str) -> Vec<String> {
+    line.split_whitespace()
+        .map(|token| {
+            if token.chars().any(|c| c.is_ascii_digit()) {
+                WILDCARD.to_string()
+            } else {
+                token.to_string()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Drain;
+
+    #[test]
+    fn lines_are_clustered_by_template() {
+        let mut drain = Drain::new(0.5, 2);
+        for line in [
+            "user alice logged in from 10.0.0.1",
+            "user bob logged in from 10.0.0.2",
+            "user carol logged in from 10.0.0.3",
+            "connection to db-1 timed out",
+            "connection to cache timed out",
+            "",
+        ] {
+            drain.add(line);
+        }
+
+        let patterns = drain.into_patterns();
+        assert_eq!(patterns.len(), 2);
+        assert_eq!(patterns[0].template, "user <*> logged in from <*>");
+        assert_eq!(patterns[0].count, 3);
+        assert_eq!(patterns[0].samples.len(), 2);
+        assert_eq!(patterns[1].template, "connection to <*> timed out");
+        assert_eq!(patterns[1].count, 2);
+    }
+
+    #[test]
+    fn dissimilar_lines_are_not_merged() {
+        let mut drain = Drain::new(0.5, 1);
+        drain.add("disk full on node");
+        drain.add("disk quota exceeded for user");
+        drain.add("disk check passed ok now");
+
+        assert_eq!(drain.into_patterns().len(), 3);
+    }
+}
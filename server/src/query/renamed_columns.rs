@@ -0,0 +1,626 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Columns renamed in the settings of a stream. Queries which reference a column by its old
+//! name are rewritten before they are planned, so that saved queries keep working while
+//! applications move to the new field name. Every rewrite is logged, to find the queries which
+//! still have to be updated.
+//!
+//! A reference is resolved to the stream of its query block, by its qualifier or as the only
+//! stream which has the column. While the stream still has a column of an old name, events
+//! ingested before the rename have their value there, so the reference reads the newest of the
+//! names which has a value, as `COALESCE("new", "old")`. Selected columns keep the name the
+//! query gave them.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+
+use arrow_schema::Schema;
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::SessionState;
+use datafusion::logical_expr::LogicalPlan;
+use datafusion::sql::parser::Statement as DFStatement;
+use datafusion::sql::sqlparser::ast::{
+    Expr, Function, FunctionArg, FunctionArgExpr, Ident, JoinConstraint, JoinOperator, ObjectName,
+    Query, Select, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, VisitMut,
+    VisitorMut,
+};
+
+use crate::metadata::STREAM_INFO;
+
+/// Plan a SQL statement, with old names of renamed columns replaced by their current names
+pub async fn create_logical_plan(
+    state: &SessionState,
+    sql: &str,
+) -> Result<LogicalPlan, DataFusionError> {
+    let dialect = state.config().options().sql_parser.dialect.clone();
+    let mut statement = state.sql_to_statement(sql, &dialect)?;
+    rewrite_statement(&mut statement);
+    state.statement_to_plan(statement).await
+}
+
+/// Replace old names of renamed columns of the streams the statement reads
+pub fn rewrite_statement(statement: &mut DFStatement) {
+    let DFStatement::Statement(statement) = statement else {
+        return;
+    };
+    let rewritten = rewrite(statement, &stream_columns);
+    if !rewritten.is_empty() {
+        log::warn!(
+            "query references renamed columns {}, they were rewritten to their current names",
+            rewritten.join(", ")
+        );
+    }
+}
+
+/// Check renames of a stream, an old name may be renamed again but not back to itself
+pub fn validate(renamed: &BTreeMap<String, String>) -> Result<(), String> {
+    for (old, new) in renamed {
+        if old.is_empty() || new.is_empty() {
+            return Err("column names can not be empty".to_string());
+        }
+        if current_name(renamed, old).is_none() {
+            return Err(format!("renames of column {old} form a cycle"));
+        }
+    }
+    Ok(())
+}
+
+// name a column is known by after following its renames, none if they form a cycle
+fn current_name<'a>(renamed: &'a BTreeMap<String, String>, column: &'a str) -> Option<&'a str> {
+    let mut name = column;
+    for _ in 0..=renamed.len() {
+        match renamed.get(name) {
+            Some(new) => name = new,
+            None => return Some(name),
+        }
+    }
+    None
+}
+
+/// Renames and schema of a stream
+struct StreamColumns {
+    renamed: BTreeMap<String, String>,
+    schema: Arc<Schema>,
+}
+
+type Lookup<'a> = &'a dyn Fn(&str) -> Option<StreamColumns>;
+
+fn stream_columns(stream_name: &str) -> Option<StreamColumns> {
+    Some(StreamColumns {
+        renamed: STREAM_INFO.get_renamed_columns(stream_name).ok()?,
+        schema: STREAM_INFO.schema(stream_name).ok()?,
+    })
+}
+
+impl StreamColumns {
+    fn has_column(&self, name: &str) -> bool {
+        self.schema.field_with_name(name).is_ok()
+    }
+
+    // names a reference to a column reads, newest first, none if it was not renamed
+    fn names_of(&self, column: &str) -> Option<Vec<String>> {
+        let current = current_name(&self.renamed, column)?;
+        if current == column {
+            return None;
+        }
+        let mut names = vec![column.to_owned()];
+        while let Some(new) = self.renamed.get(names.last().expect("names are not empty")) {
+            names.push(new.clone());
+        }
+        names.reverse();
+        // a name no event has a value for can not be planned, the current name stands in
+        let with_values: Vec<_> = names
+            .iter()
+            .filter(|name| self.has_column(name))
+            .cloned()
+            .collect();
+        if with_values.is_empty() {
+            Some(vec![current.to_owned()])
+        } else {
+            Some(with_values)
+        }
+    }
+}
+
+// a table of a query block, none for the columns of subqueries and tables which are no stream
+struct Relation {
+    reference: Ident,
+    columns: Option<StreamColumns>,
+}
+
+// rewrite column references of the statement, returns the old names which were rewritten
+fn rewrite(statement: &mut Statement, lookup: Lookup) -> Vec<String> {
+    let mut rewritten = HashSet::new();
+    if let Statement::Query(query) = statement {
+        rewrite_query(query, lookup, &mut rewritten);
+    }
+    let mut rewritten: Vec<_> = rewritten.into_iter().collect();
+    rewritten.sort();
+    rewritten
+}
+
+fn rewrite_query(query: &mut Query, lookup: Lookup, rewritten: &mut HashSet<String>) {
+    if let Some(with) = &mut query.with {
+        for cte in &mut with.cte_tables {
+            rewrite_query(&mut cte.query, lookup, rewritten);
+        }
+    }
+    let scope = rewrite_set_expr(&mut query.body, lookup, rewritten);
+    let mut rewriter = Rewriter {
+        scope: &scope,
+        lookup,
+        rewritten,
+        subqueries: 0,
+    };
+    for order_by in &mut query.order_by {
+        let _ = order_by.expr.visit(&mut rewriter);
+    }
+}
+
+// the relations of a single query block, which its ordering refers to
+fn rewrite_set_expr(
+    body: &mut SetExpr,
+    lookup: Lookup,
+    rewritten: &mut HashSet<String>,
+) -> Vec<Relation> {
+    match body {
+        SetExpr::Select(select) => rewrite_select(select, lookup, rewritten),
+        SetExpr::Query(query) => {
+            rewrite_query(query, lookup, rewritten);
+            Vec::new()
+        }
+        SetExpr::SetOperation { left, right, .. } => {
+            rewrite_set_expr(left, lookup, rewritten);
+            rewrite_set_expr(right, lookup, rewritten);
+            Vec::new()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn rewrite_select(
+    select: &mut Select,
+    lookup: Lookup,
+    rewritten: &mut HashSet<String>,
+) -> Vec<Relation> {
+    let mut from = std::mem::take(&mut select.from);
+    let mut scope = Vec::new();
+    for table in &mut from {
+        add_relations(table, lookup, rewritten, &mut scope);
+    }
+
+    let selected: Vec<_> = select
+        .projection
+        .iter()
+        .map(|item| match item {
+            SelectItem::UnnamedExpr(expr) => column_name(expr),
+            _ => None,
+        })
+        .collect();
+
+    let mut rewriter = Rewriter {
+        scope: &scope,
+        lookup,
+        rewritten,
+        subqueries: 0,
+    };
+    let _ = select.visit(&mut rewriter);
+    for join in from.iter_mut().flat_map(|table| &mut table.joins) {
+        if let Some(JoinConstraint::On(expr)) = join_constraint(&mut join.join_operator) {
+            let _ = expr.visit(&mut rewriter);
+        }
+    }
+    select.from = from;
+
+    // a selected column keeps its name when it reads other columns now
+    for (item, name) in select.projection.iter_mut().zip(selected) {
+        if let (SelectItem::UnnamedExpr(expr), Some(name)) = (&*item, name) {
+            if column_name(expr).as_ref() != Some(&name) {
+                *item = SelectItem::ExprWithAlias {
+                    expr: expr.clone(),
+                    alias: Ident::with_quote('"', name),
+                };
+            }
+        }
+    }
+    scope
+}
+
+// name of a column reference, as it is planned
+fn column_name(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Identifier(ident) => Some(normalized(ident)),
+        Expr::CompoundIdentifier(idents) => idents.last().map(normalized),
+        _ => None,
+    }
+}
+
+fn join_constraint(operator: &mut JoinOperator) -> Option<&mut JoinConstraint> {
+    match operator {
+        JoinOperator::Inner(constraint)
+        | JoinOperator::LeftOuter(constraint)
+        | JoinOperator::RightOuter(constraint)
+        | JoinOperator::FullOuter(constraint)
+        | JoinOperator::LeftSemi(constraint)
+        | JoinOperator::RightSemi(constraint)
+        | JoinOperator::LeftAnti(constraint)
+        | JoinOperator::RightAnti(constraint) => Some(constraint),
+        _ => None,
+    }
+}
+
+fn add_relations(
+    table: &mut TableWithJoins,
+    lookup: Lookup,
+    rewritten: &mut HashSet<String>,
+    scope: &mut Vec<Relation>,
+) {
+    add_relation(&mut table.relation, lookup, rewritten, scope);
+    for join in &mut table.joins {
+        add_relation(&mut join.relation, lookup, rewritten, scope);
+    }
+}
+
+fn add_relation(
+    factor: &mut TableFactor,
+    lookup: Lookup,
+    rewritten: &mut HashSet<String>,
+    scope: &mut Vec<Relation>,
+) {
+    match factor {
+        TableFactor::Table { name, alias, .. } => {
+            let Some(table) = name.0.last() else {
+                return;
+            };
+            scope.push(Relation {
+                reference: alias.as_ref().map_or(table, |alias| &alias.name).clone(),
+                columns: lookup(&table.value),
+            });
+        }
+        TableFactor::Derived {
+            subquery, alias, ..
+        } => {
+            rewrite_query(subquery, lookup, rewritten);
+            scope.push(Relation {
+                reference: alias
+                    .as_ref()
+                    .map_or_else(|| Ident::new(""), |alias| alias.name.clone()),
+                columns: None,
+            });
+        }
+        TableFactor::NestedJoin {
+            table_with_joins, ..
+        } => add_relations(table_with_joins, lookup, rewritten, scope),
+        _ => scope.push(Relation {
+            reference: Ident::new(""),
+            columns: None,
+        }),
+    }
+}
+
+// unquoted identifiers are planned in lower case
+fn normalized(ident: &Ident) -> String {
+    match ident.quote_style {
+        Some(_) => ident.value.clone(),
+        None => ident.value.to_lowercase(),
+    }
+}
+
+// rewrites the column references of a query block, subqueries are rewritten in their own scope
+struct Rewriter<'a> {
+    scope: &'a [Relation],
+    lookup: Lookup<'a>,
+    rewritten: &'a mut HashSet<String>,
+    // depth of the subqueries being visited, their columns are already rewritten
+    subqueries: usize,
+}
+
+impl Rewriter<'_> {
+    // relation an unqualified column belongs to, the only stream which has it
+    fn relation_of(&self, column: &str) -> Option<&Relation> {
+        if let [relation] = self.scope {
+            return Some(relation);
+        }
+        let mut owners = self.scope.iter().filter(|relation| {
+            relation.columns.as_ref().map_or(true, |columns| {
+                columns.has_column(column) || columns.renamed.contains_key(column)
+            })
+        });
+        match (owners.next(), owners.next()) {
+            (Some(relation), None) => Some(relation),
+            _ => None,
+        }
+    }
+
+    fn replacement(&mut self, expr: &Expr) -> Option<Expr> {
+        let (qualifier, column, relation) = match expr {
+            Expr::Identifier(ident) => {
+                let column = normalized(ident);
+                let relation = self.relation_of(&column)?;
+                // a column of a join is qualified, the new name may be a column of another table
+                let qualifier = if self.scope.len() > 1 {
+                    vec![relation.reference.clone()]
+                } else {
+                    Vec::new()
+                };
+                (qualifier, column, relation)
+            }
+            Expr::CompoundIdentifier(idents) if idents.len() > 1 => {
+                let (column, qualifier) = idents.split_last()?;
+                let reference = normalized(qualifier.last()?);
+                let relation = self
+                    .scope
+                    .iter()
+                    .find(|relation| normalized(&relation.reference) == reference)?;
+                (qualifier.to_vec(), normalized(column), relation)
+            }
+            _ => return None,
+        };
+        let names = relation.columns.as_ref()?.names_of(&column)?;
+        self.rewritten.insert(column);
+
+        let mut references = names.into_iter().map(|name| {
+            let name = Ident::with_quote('"', name);
+            if qualifier.is_empty() {
+                Expr::Identifier(name)
+            } else {
+                Expr::CompoundIdentifier(qualifier.iter().cloned().chain([name]).collect())
+            }
+        });
+        if references.len() == 1 {
+            return references.next();
+        }
+        Some(Expr::Function(Function {
+            name: ObjectName(vec![Ident::new("coalesce")]),
+            args: references
+                .map(|reference| FunctionArg::Unnamed(FunctionArgExpr::Expr(reference)))
+                .collect(),
+            over: None,
+            distinct: false,
+            special: false,
+            order_by: Vec::new(),
+        }))
+    }
+}
+
+impl VisitorMut for Rewriter<'_> {
+    type Break = ();
+
+    fn pre_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::InSubquery { expr, subquery, .. } => {
+                let _ = expr.visit(self);
+                rewrite_query(subquery, self.lookup, self.rewritten);
+                self.subqueries += 1;
+            }
+            Expr::Exists { subquery, .. }
+            | Expr::Subquery(subquery)
+            | Expr::ArraySubquery(subquery) => {
+                rewrite_query(subquery, self.lookup, self.rewritten);
+                self.subqueries += 1;
+            }
+            _ => (),
+        }
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<()> {
+        match expr {
+            Expr::InSubquery { .. }
+            | Expr::Exists { .. }
+            | Expr::Subquery(_)
+            | Expr::ArraySubquery(_) => self.subqueries -= 1,
+            _ if self.subqueries == 0 => {
+                if let Some(replacement) = self.replacement(expr) {
+                    *expr = replacement;
+                }
+            }
+            _ => (),
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use arrow_array::{Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema};
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::SessionContext;
+    use datafusion::sql::parser::Statement as DFStatement;
+    use datafusion::sql::sqlparser::dialect::GenericDialect;
+    use datafusion::sql::sqlparser::parser::Parser;
+
+    use super::{current_name, rewrite, validate, StreamColumns};
+
+    fn schema(columns: &[&str]) -> Arc<Schema> {
+        Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|name| Field::new(*name, DataType::Utf8, true))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    // app renamed msg to message and lvl to level, other never did and has a msg column
+    fn lookup(app_columns: &'static [&'static str]) -> impl Fn(&str) -> Option<StreamColumns> {
+        move |stream| match stream {
+            "app" => Some(StreamColumns {
+                renamed: BTreeMap::from([
+                    ("msg".to_string(), "message".to_string()),
+                    ("lvl".to_string(), "level".to_string()),
+                ]),
+                schema: schema(app_columns),
+            }),
+            "other" => Some(StreamColumns {
+                renamed: BTreeMap::new(),
+                schema: schema(&["msg", "host"]),
+            }),
+            _ => None,
+        }
+    }
+
+    fn rewritten(sql: &str, app_columns: &'static [&'static str]) -> (String, Vec<String>) {
+        let mut statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        let rewritten = rewrite(&mut statement, &lookup(app_columns));
+        (statement.to_string(), rewritten)
+    }
+
+    #[test]
+    fn old_names_are_rewritten() {
+        assert_eq!(
+            rewritten(
+                r#"SELECT msg, app.MSG, level FROM app WHERE "msg" = 'a' AND "Msg" = 'b'"#,
+                &["message", "level"],
+            ),
+            (
+                r#"SELECT "message" AS "msg", app."message" AS "msg", level FROM app WHERE "message" = 'a' AND "Msg" = 'b'"#
+                    .to_string(),
+                vec!["msg".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn old_columns_are_read_while_the_stream_has_them() {
+        assert_eq!(
+            rewritten("SELECT msg FROM app ORDER BY msg", &["msg", "message"]).0,
+            r#"SELECT coalesce("message", "msg") AS "msg" FROM app ORDER BY coalesce("message", "msg")"#
+        );
+        // the current name is never rewritten
+        assert_eq!(
+            rewritten("SELECT message FROM app", &["msg", "message"]),
+            ("SELECT message FROM app".to_string(), vec![])
+        );
+    }
+
+    #[test]
+    fn columns_are_rewritten_per_relation() {
+        assert_eq!(
+            rewritten(
+                "SELECT a.msg, b.msg FROM app AS a JOIN other AS b ON a.msg = b.msg",
+                &["message"],
+            )
+            .0,
+            r#"SELECT a."message" AS "msg", b.msg FROM app AS a JOIN other AS b ON a."message" = b.msg"#
+        );
+        // an unqualified column of two streams is left to the planner
+        assert_eq!(
+            rewritten("SELECT msg FROM app JOIN other ON true", &["message"]).1,
+            Vec::<String>::new()
+        );
+        // of one stream, it is qualified so that it does not read a column of the other
+        assert_eq!(
+            rewritten(
+                "SELECT host FROM other JOIN app ON true WHERE lvl = 'a'",
+                &["level", "message"]
+            )
+            .0,
+            r#"SELECT host FROM other JOIN app ON true WHERE app."level" = 'a'"#
+        );
+        // subqueries are rewritten in their own scope
+        assert_eq!(
+            rewritten(
+                "SELECT msg FROM other WHERE msg IN (SELECT msg FROM app)",
+                &["message"],
+            )
+            .0,
+            r#"SELECT msg FROM other WHERE msg IN (SELECT "message" AS "msg" FROM app)"#
+        );
+    }
+
+    #[tokio::test]
+    async fn events_from_before_and_after_a_rename_are_read() {
+        let schema = schema(&["msg", "message"]);
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec![Some("old"), None])),
+                Arc::new(StringArray::from(vec![None, Some("new")])),
+            ],
+        )
+        .unwrap();
+        let ctx = SessionContext::new();
+        ctx.register_table(
+            "app",
+            Arc::new(MemTable::try_new(schema, vec![vec![batch]]).unwrap()),
+        )
+        .unwrap();
+
+        let state = ctx.state();
+        let mut statement = state
+            .sql_to_statement(
+                "SELECT msg FROM app WHERE msg IS NOT NULL ORDER BY msg",
+                "generic",
+            )
+            .unwrap();
+        let DFStatement::Statement(inner) = &mut statement else {
+            unreachable!("statement is a query")
+        };
+        rewrite(inner, &lookup(&["msg", "message"]));
+        let plan = state.statement_to_plan(statement).await.unwrap();
+        let batches = ctx
+            .execute_logical_plan(plan)
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+
+        assert_eq!(batches[0].schema().field(0).name(), "msg");
+        let messages: Vec<_> = batches
+            .iter()
+            .flat_map(|batch| {
+                let column = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .unwrap()
+                    .clone();
+                (0..column.len())
+                    .map(|i| column.value(i).to_owned())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(messages, ["new", "old"]);
+    }
+
+    #[test]
+    fn renames_are_followed() {
+        let renamed = BTreeMap::from([
+            ("a".to_string(), "b".to_string()),
+            ("b".to_string(), "c".to_string()),
+        ]);
+        assert_eq!(current_name(&renamed, "a"), Some("c"));
+        assert!(validate(&renamed).is_ok());
+
+        let cycle = BTreeMap::from([
+            ("a".to_string(), "b".to_string()),
+            ("b".to_string(), "a".to_string()),
+        ]);
+        assert_eq!(current_name(&cycle, "a"), None);
+        assert!(validate(&cycle).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::handlers::http::ingest::push_internal_events;
+use crate::metrics::{
+    self, QUERIES_EXECUTED, QUERY_EXECUTE_TIME, QUERY_RESULT_ROWS, QUERY_SCANNED_BYTES,
+    QUERY_SCANNED_FILES,
+};
+use crate::option::CONFIG;
+
+/// Internal stream queries running longer than `P_QUERY_SLOW_THRESHOLD` are written to
+pub const SLOW_QUERY_STREAM_NAME: &str = "pslowquery";
+
+/// An executed query, as written to the slow query stream
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct ExecutedQuery {
+    pub query: String,
+    pub stream: String,
+    pub user: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub scanned_files: u64,
+    pub scanned_bytes: u64,
+    pub result_rows: u64,
+}
+
+impl ExecutedQuery {
+    fn is_slow(&self, threshold: Duration) -> bool {
+        // queries on the slow query stream are left out, looking into slow queries is not one
+        self.stream != SLOW_QUERY_STREAM_NAME && self.duration_ms >= threshold.as_millis() as u64
+    }
+}
+
+/// Record the metrics of an executed query and log it if it was slow
+pub fn record(executed: ExecutedQuery) {
+    let stream = metrics::stream_counter_label(&executed.stream);
+    let stream = stream.as_str();
+    QUERY_EXECUTE_TIME
+        .with_label_values(&[stream])
+        .observe(executed.duration_ms as f64 / 1000.);
+    QUERY_SCANNED_BYTES
+        .with_label_values(&[stream])
+        .inc_by(executed.scanned_bytes);
+    QUERY_SCANNED_FILES
+        .with_label_values(&[stream])
+        .inc_by(executed.scanned_files);
+    QUERY_RESULT_ROWS
+        .with_label_values(&[stream])
+        .inc_by(executed.result_rows);
+    QUERIES_EXECUTED
+        .with_label_values(&[&metrics::user_label(&executed.user)])
+        .inc();
+
+    if !executed.is_slow(CONFIG.parseable.query_slow_threshold) {
+        return;
+    }
+
+    log::info!(
+        "slow query on {} by {} took {}ms",
+        executed.stream,
+        executed.user,
+        executed.duration_ms
+    );
+    tokio::spawn(async move {
+        if let Err(err) = push_internal_events(SLOW_QUERY_STREAM_NAME, json!([executed])).await {
+            log::warn!("could not write to {SLOW_QUERY_STREAM_NAME}: {err}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::{ExecutedQuery, SLOW_QUERY_STREAM_NAME};
+
+    fn executed(stream: &str, duration_ms: u64) -> ExecutedQuery {
+        ExecutedQuery {
+            query: "select * from app".to_owned(),
+            stream: stream.to_owned(),
+            user: "admin".to_owned(),
+            start_time: Utc::now(),
+            end_time: Utc::now(),
+            duration_ms,
+            scanned_files: 0,
+            scanned_bytes: 0,
+            result_rows: 0,
+        }
+    }
+
+    #[test]
+    fn slow_above_threshold() {
+        let threshold = Duration::from_secs(10);
+        assert!(!executed("app", 9_999).is_slow(threshold));
+        assert!(executed("app", 10_000).is_slow(threshold));
+        assert!(!executed(SLOW_QUERY_STREAM_NAME, 60_000).is_slow(threshold));
+    }
+}
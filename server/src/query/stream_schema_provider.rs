@@ -16,11 +16,8 @@
  *
  */
 
+use crate::catalog::snapshot::{self, Snapshot, SnapshotAt};
 use crate::Mode;
-use crate::{
-    catalog::snapshot::{self, Snapshot},
-    storage::{ObjectStoreFormat, STREAM_ROOT_DIRECTORY},
-};
 use arrow_array::RecordBatch;
 use arrow_schema::{Schema, SchemaRef, SortOptions};
 use bytes::Bytes;
@@ -39,30 +36,42 @@ use datafusion::{
     },
     error::DataFusionError,
     execution::{context::SessionState, object_store::ObjectStoreUrl},
-    logical_expr::{BinaryExpr, Operator, TableProviderFilterPushDown, TableType},
+    logical_expr::{
+        expr::{Between, InList},
+        BinaryExpr, Operator, TableProviderFilterPushDown, TableType,
+    },
     optimizer::utils::conjunction,
     physical_expr::{create_physical_expr, PhysicalSortExpr},
-    physical_plan::{self, empty::EmptyExec, union::UnionExec, ExecutionPlan, Statistics},
+    physical_plan::{
+        self, empty::EmptyExec, sorts::sort::SortExec, union::UnionExec, ExecutionPlan, Statistics,
+    },
     prelude::Expr,
     scalar::ScalarValue,
 };
 use futures_util::{stream::FuturesOrdered, StreamExt, TryFutureExt, TryStreamExt};
 use itertools::Itertools;
 use object_store::{path::Path, ObjectStore};
-use relative_path::RelativePathBuf;
 use std::{any::Any, collections::HashMap, ops::Bound, sync::Arc};
 use url::Url;
 
 use crate::{
     catalog::{
-        self, column::TypedStatistics, manifest::Manifest, snapshot::ManifestItem, ManifestFile,
+        self,
+        column::TypedStatistics,
+        manifest::{Manifest, SortOrder},
+        snapshot::ManifestItem,
+        ManifestFile,
     },
     event::{self, DEFAULT_TIMESTAMP_KEY},
+    external_tables,
+    handlers::http::cluster::sharding,
     localcache::LocalCacheManager,
     metadata::STREAM_INFO,
-    metrics::QUERY_CACHE_HIT,
+    metering,
+    metrics::{self, QUERY_CACHE_HIT},
     option::CONFIG,
-    storage::ObjectStorage,
+    storage::{compression, ObjectStorage},
+    utils,
 };
 
 use super::listing_table_builder::ListingTableBuilder;
@@ -80,26 +89,45 @@ impl SchemaProvider for GlobalSchemaProvider {
     }
 
     fn table_names(&self) -> Vec<String> {
-        STREAM_INFO.list_streams()
+        let mut names = STREAM_INFO.list_streams();
+        names.extend(external_tables::list().into_iter().map(|table| table.name));
+        names
     }
 
     async fn table(&self, name: &str) -> Option<Arc<dyn TableProvider>> {
-        if self.table_exist(name) {
-            Some(Arc::new(StandardTableProvider {
+        if STREAM_INFO.stream_exists(name) {
+            return Some(Arc::new(StandardTableProvider {
                 schema: STREAM_INFO.schema(name).unwrap(),
                 stream: name.to_owned(),
                 url: self.storage.store_url(),
-            }))
-        } else {
-            None
+            }));
+        }
+
+        // the table may have been created on another query server
+        let table = match external_tables::get(name) {
+            Some(table) => table,
+            None => external_tables::fetch(name).await.ok()??,
+        };
+        match table.table_provider().await {
+            Ok(provider) => Some(provider),
+            Err(err) => {
+                log::warn!("could not read external table {name}: {err}");
+                None
+            }
         }
     }
 
     fn table_exist(&self, name: &str) -> bool {
-        STREAM_INFO.stream_exists(name)
+        STREAM_INFO.stream_exists(name) || external_tables::exists(name)
     }
 }
 
+/// Session extension of queries sorted by the time column of the stream, newest first. Their
+/// scans read parquet files in groups ordered by time and sort the staged events, so that the
+/// sources are merged in order instead of being sorted as a whole.
+#[derive(Debug)]
+pub struct TimeOrdered;
+
 #[derive(Debug)]
 struct StandardTableProvider {
     schema: SchemaRef,
@@ -119,7 +147,7 @@ async fn create_parquet_physical_plan(
     filters: &[Expr],
     limit: Option<usize>,
     state: &SessionState,
-    time_partition: Option<String>,
+    ordered_by: Option<&str>,
 ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
     let filters = if let Some(expr) = conjunction(filters.to_vec()) {
         let table_df_schema = schema.as_ref().clone().to_dfschema()?;
@@ -130,16 +158,10 @@ async fn create_parquet_physical_plan(
         None
     };
 
-    let sort_expr = PhysicalSortExpr {
-        expr: if let Some(time_partition) = time_partition {
-            physical_plan::expressions::col(&time_partition, &schema)?
-        } else {
-            physical_plan::expressions::col(DEFAULT_TIMESTAMP_KEY, &schema)?
-        },
-        options: SortOptions {
-            descending: true,
-            nulls_first: true,
-        },
+    // only groups of files which are ordered as a whole can be merged instead of sorted
+    let output_ordering = match ordered_by {
+        Some(column) => vec![vec![time_sort_expr(column, &schema)?]],
+        None => Vec::new(),
     };
     let file_format = ParquetFormat::default().with_enable_pruning(Some(true));
 
@@ -154,7 +176,7 @@ async fn create_parquet_physical_plan(
                 statistics,
                 projection: projection.cloned(),
                 limit,
-                output_ordering: vec![vec![sort_expr]],
+                output_ordering,
                 table_partition_cols: Vec::new(),
                 infinite_source: false,
             },
@@ -164,6 +186,31 @@ async fn create_parquet_physical_plan(
     Ok(plan)
 }
 
+// time column, newest first, the order events are staged and parquet files are written in
+fn time_sort_expr(column: &str, schema: &Schema) -> Result<PhysicalSortExpr, DataFusionError> {
+    Ok(PhysicalSortExpr {
+        expr: physical_plan::expressions::col(column, schema)?,
+        options: SortOptions {
+            descending: true,
+            nulls_first: true,
+        },
+    })
+}
+
+// Staged events are sorted in memory to be merged with the parquet files. Their order is
+// left as is if the time column is not projected.
+fn sort_by_time(
+    plan: Arc<dyn ExecutionPlan>,
+    column: &str,
+) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+    let Ok(sort_expr) = time_sort_expr(column, &plan.schema()) else {
+        return Ok(plan);
+    };
+    Ok(Arc::new(
+        SortExec::new(vec![sort_expr], plan).with_preserve_partitioning(true),
+    ))
+}
+
 async fn collect_from_snapshot(
     snapshot: &catalog::snapshot::Snapshot,
     time_filters: &[PartialTimeFilter],
@@ -171,20 +218,26 @@ async fn collect_from_snapshot(
     filters: &[Expr],
     limit: Option<usize>,
 ) -> Result<Vec<catalog::manifest::File>, DataFusionError> {
-    let items = snapshot.manifests(time_filters);
+    // manifests none of whose files can match are not read
+    let items = snapshot.manifests(time_filters).into_iter().filter(|item| {
+        !filters
+            .iter()
+            .any(|filter| can_be_pruned(filter, &|name| item.column_stats.get(name)))
+    });
     let manifest_files = collect_manifest_files(
         object_store,
         items
-            .into_iter()
             .sorted_by_key(|file| file.time_lower_bound)
             .map(|item| item.manifest_path)
             .collect(),
     )
     .await?;
+    // a file committed again may be listed by two manifests of a day until they are merged
     let mut manifest_files: Vec<_> = manifest_files
         .into_iter()
         .flat_map(|file| file.files)
         .rev()
+        .unique_by(|file| file.file_path.clone())
         .collect();
     for filter in filters {
         manifest_files.retain(|file| !file.can_be_pruned(filter))
@@ -210,38 +263,143 @@ async fn collect_from_snapshot(
     Ok(manifest_files)
 }
 
+/// Number of scan groups to split `file_count` files of `total_size` bytes into.
+/// Small scans are coalesced so that every group reads at least `min_group_size`
+/// bytes, large scans are spread across `target_partitions` groups.
+fn scan_group_count(
+    file_count: usize,
+    total_size: u64,
+    target_partitions: usize,
+    min_group_size: u64,
+) -> usize {
+    let by_size = if min_group_size == 0 {
+        file_count
+    } else {
+        total_size.div_ceil(min_group_size) as usize
+    };
+
+    by_size.min(file_count).min(target_partitions).max(1)
+}
+
 fn partitioned_files(
     manifest_files: Vec<catalog::manifest::File>,
     table_schema: &Schema,
-    target_partition: usize,
+    target_partitions: usize,
+) -> (Vec<Vec<PartitionedFile>>, datafusion::common::Statistics) {
+    let total_size = manifest_files.iter().map(|file| file.file_size).sum();
+    let group_count = scan_group_count(
+        manifest_files.len(),
+        total_size,
+        target_partitions,
+        CONFIG.parseable.query_min_scan_group_size,
+    );
+    let mut groups = Vec::from_iter((0..group_count).map(|_| Vec::new()));
+    let mut group_sizes = vec![0u64; group_count];
+    for file in manifest_files {
+        // assign each file to the least loaded group, files keep their relative
+        // order within a group
+        let (index, _) = group_sizes
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, size)| **size)
+            .expect("atleast one scan group");
+        group_sizes[index] += file.file_size;
+        groups[index].push(file);
+    }
+    file_groups(groups, table_schema)
+}
+
+/// Groups of files in which every file only has events older than those of the files before
+/// it, so that each group is ordered by `time_column`, newest first. A file is put after the
+/// group with the closest newer events, files overlapping all groups start a new one.
+/// The files are given back if one of them is not sorted by the column or has no statistics
+/// of it.
+fn time_ordered_groups(
+    manifest_files: Vec<catalog::manifest::File>,
+    time_column: &str,
+) -> Result<Vec<Vec<catalog::manifest::File>>, Vec<catalog::manifest::File>> {
+    let Some(bounds) = manifest_files
+        .iter()
+        .map(|file| time_bounds(file, time_column))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Err(manifest_files);
+    };
+
+    let files = bounds
+        .into_iter()
+        .zip(manifest_files)
+        .sorted_by_key(|((_, max), _)| std::cmp::Reverse(*max));
+    // oldest event of each group, with its files
+    let mut groups: Vec<(i64, Vec<catalog::manifest::File>)> = Vec::new();
+    for ((min, max), file) in files {
+        match groups
+            .iter_mut()
+            .filter(|(oldest, _)| *oldest >= max)
+            .min_by_key(|(oldest, _)| *oldest)
+        {
+            Some((oldest, group)) => {
+                *oldest = min;
+                group.push(file);
+            }
+            None => groups.push((min, vec![file])),
+        }
+    }
+    Ok(groups.into_iter().map(|(_, files)| files).collect())
+}
+
+// oldest and newest value of the time column of a file sorted by it, newest first
+fn time_bounds(file: &catalog::manifest::File, time_column: &str) -> Option<(i64, i64)> {
+    match file.sort_order_id.first() {
+        Some((column, SortOrder::DescNullsFirst)) if column == time_column => (),
+        _ => return None,
+    }
+    let column = file
+        .columns
+        .iter()
+        .find(|column| column.name == time_column)?;
+    match &column.stats {
+        Some(TypedStatistics::Int(stats)) => Some((stats.min, stats.max)),
+        _ => None,
+    }
+}
+
+fn file_groups(
+    groups: Vec<Vec<catalog::manifest::File>>,
+    table_schema: &Schema,
 ) -> (Vec<Vec<PartitionedFile>>, datafusion::common::Statistics) {
-    let mut partitioned_files = Vec::from_iter((0..target_partition).map(|_| Vec::new()));
     let mut column_statistics = HashMap::<String, Option<catalog::column::TypedStatistics>>::new();
     let mut count = 0;
-    for (index, file) in manifest_files
+    let partitioned_files = groups
         .into_iter()
-        .enumerate()
-        .map(|(x, y)| (x % target_partition, y))
-    {
-        let catalog::manifest::File {
-            file_path,
-            num_rows,
-            columns,
-            ..
-        } = file;
-        partitioned_files[index].push(PartitionedFile::new(file_path, file.file_size));
-        columns.into_iter().for_each(|col| {
-            column_statistics
-                .entry(col.name)
-                .and_modify(|x| {
-                    if let Some((stats, col_stats)) = x.as_ref().cloned().zip(col.stats.clone()) {
-                        *x = Some(stats.update(col_stats));
-                    }
+        .map(|group| {
+            group
+                .into_iter()
+                .map(|file| {
+                    let catalog::manifest::File {
+                        file_path,
+                        num_rows,
+                        columns,
+                        ..
+                    } = file;
+                    columns.into_iter().for_each(|col| {
+                        column_statistics
+                            .entry(col.name)
+                            .and_modify(|x| {
+                                if let Some((stats, col_stats)) =
+                                    x.as_ref().cloned().zip(col.stats.clone())
+                                {
+                                    *x = Some(stats.update(col_stats));
+                                }
+                            })
+                            .or_insert_with(|| col.stats.as_ref().cloned());
+                    });
+                    count += num_rows;
+                    PartitionedFile::new(file_path, file.file_size)
                 })
-                .or_insert_with(|| col.stats.as_ref().cloned());
-        });
-        count += num_rows;
-    }
+                .collect()
+        })
+        .collect();
     let statistics = table_schema
         .fields()
         .iter()
@@ -270,6 +428,33 @@ fn partitioned_files(
     (partitioned_files, statistics)
 }
 
+// Scan groups of the files and the column each group is ordered by. Files of queries sorted
+// by the time column are grouped by time if they can be, other files by size.
+fn scan_groups<'a>(
+    manifest_files: Vec<catalog::manifest::File>,
+    table_schema: &Schema,
+    target_partitions: usize,
+    time_column: Option<&'a str>,
+) -> (
+    Vec<Vec<PartitionedFile>>,
+    datafusion::common::Statistics,
+    Option<&'a str>,
+) {
+    let manifest_files = match time_column {
+        Some(time_column) => match time_ordered_groups(manifest_files, time_column) {
+            Ok(groups) => {
+                let (partitioned_files, statistics) = file_groups(groups, table_schema);
+                return (partitioned_files, statistics, Some(time_column));
+            }
+            Err(manifest_files) => manifest_files,
+        },
+        None => manifest_files,
+    };
+    let (partitioned_files, statistics) =
+        partitioned_files(manifest_files, table_schema, target_partitions);
+    (partitioned_files, statistics, None)
+}
+
 #[async_trait::async_trait]
 impl TableProvider for StandardTableProvider {
     fn as_any(&self) -> &dyn std::any::Any {
@@ -293,6 +478,7 @@ impl TableProvider for StandardTableProvider {
     ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
         let mut memory_exec = None;
         let mut cache_exec = None;
+        let target_partitions = state.config_options().execution.target_partitions;
         let object_store = state
             .runtime_env()
             .object_store_registry
@@ -305,51 +491,58 @@ impl TableProvider for StandardTableProvider {
             .await
             .map_err(|err| DataFusionError::Plan(err.to_string()))?;
         let time_partition = object_store_format.time_partition;
+        let custom_partition = object_store_format.custom_partition;
         let time_filters = extract_primary_filter(filters, time_partition.clone());
         if time_filters.is_empty() {
             return Err(DataFusionError::Plan("potentially unbounded query on time range. Table scanning requires atleast one time bound".to_string()));
         }
-
-        if include_now(filters, time_partition.clone()) {
-            if let Some(records) =
+        // files are grouped by time to be merged when the query is sorted by time
+        let time_column = time_partition
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TIMESTAMP_KEY.to_owned());
+        let ordered_by = state
+            .config()
+            .get_extension::<TimeOrdered>()
+            .map(|_| time_column.as_str());
+
+        // a past state of the stream only has the events committed to its snapshot
+        let at = state.config().get_extension::<SnapshotAt>();
+        if at.is_none() && include_now(filters, time_partition.clone()) {
+            let records = if CONFIG.parseable.mode == Mode::Query {
+                // events not uploaded yet are only in the staging of the ingesters
+                let records = sharding::staging_records(&self.stream, &self.schema).await;
+                (!records.is_empty()).then_some(records)
+            } else {
                 event::STREAM_WRITERS.recordbatches_cloned(&self.stream, &self.schema)
-            {
+            };
+            if let Some(records) = records {
                 let reversed_mem_table = reversed_mem_table(records, self.schema.clone())?;
-                memory_exec = Some(
-                    reversed_mem_table
-                        .scan(state, projection, filters, limit)
-                        .await?,
-                );
+                let exec = reversed_mem_table
+                    .scan(state, projection, filters, limit)
+                    .await?;
+                memory_exec = Some(match ordered_by {
+                    Some(column) => sort_by_time(exec, column)?,
+                    None => exec,
+                });
             }
         };
         let mut merged_snapshot: snapshot::Snapshot = Snapshot::default();
-        if CONFIG.parseable.mode == Mode::Query {
-            let path = RelativePathBuf::from_iter([&self.stream, STREAM_ROOT_DIRECTORY]);
-            let obs = glob_storage
-                .get_objects(
-                    Some(&path),
-                    Box::new(|file_name| file_name.starts_with(".ingester")),
-                )
-                .await;
-
-            if let Ok(obs) = obs {
-                for ob in obs {
-                    if let Ok(object_store_format) =
-                        serde_json::from_slice::<ObjectStoreFormat>(&ob)
-                    {
-                        let snapshot = object_store_format.snapshot;
-                        for manifest in snapshot.manifest_list {
-                            merged_snapshot.manifest_list.push(manifest);
-                        }
-                    }
-                }
-            }
+        if let Some(at) = &at {
+            merged_snapshot = catalog::snapshot_at(&*glob_storage, &self.stream, **at)
+                .await
+                .map_err(|err| DataFusionError::Plan(err.to_string()))?;
+        } else if CONFIG.parseable.mode == Mode::Query {
+            merged_snapshot.manifest_list =
+                catalog::ingester_manifests(&*glob_storage, &self.stream)
+                    .await
+                    .map_err(|err| DataFusionError::External(Box::new(err)))?;
         } else {
             merged_snapshot = object_store_format.snapshot;
         }
 
         // Is query timerange is overlapping with older data.
-        if is_overlapping_query(&merged_snapshot.manifest_list, &time_filters) {
+        // Older data is listed as it is now, so it is not part of past states.
+        if at.is_none() && is_overlapping_query(&merged_snapshot.manifest_list, &time_filters) {
             return legacy_listing_table(
                 self.stream.clone(),
                 memory_exec,
@@ -375,10 +568,25 @@ impl TableProvider for StandardTableProvider {
         )
         .await?;
 
+        if let Some(column) = custom_partition {
+            for prefixes in filters
+                .iter()
+                .filter_map(|expr| custom_partition_prefixes(expr, &column))
+            {
+                manifest_files
+                    .retain(|file| in_custom_partitions(&file.file_path, &column, &prefixes));
+            }
+        }
+
         if manifest_files.is_empty() {
             return final_plan(vec![memory_exec], projection, self.schema.clone());
         }
 
+        metering::record_scan(
+            &self.stream,
+            manifest_files.iter().map(|file| file.file_size).sum(),
+        );
+
         // Based on entries in the manifest files, find them in the cache and create a physical plan.
         if let Some(cache_manager) = LocalCacheManager::global() {
             let (cached, remainder) = cache_manager
@@ -400,7 +608,8 @@ impl TableProvider for StandardTableProvider {
                 })
                 .collect();
 
-            let (partitioned_files, statistics) = partitioned_files(cached, &self.schema, 1);
+            let (partitioned_files, statistics, cache_ordered_by) =
+                scan_groups(cached, &self.schema, target_partitions, ordered_by);
             let plan = create_parquet_physical_plan(
                 ObjectStoreUrl::parse("file:///").unwrap(),
                 partitioned_files,
@@ -410,7 +619,7 @@ impl TableProvider for StandardTableProvider {
                 filters,
                 limit,
                 state,
-                time_partition.clone(),
+                cache_ordered_by,
             )
             .await?;
 
@@ -418,7 +627,9 @@ impl TableProvider for StandardTableProvider {
         }
 
         if manifest_files.is_empty() {
-            QUERY_CACHE_HIT.with_label_values(&[&self.stream]).inc();
+            QUERY_CACHE_HIT
+                .with_label_values(&[&metrics::stream_counter_label(&self.stream)])
+                .inc();
             return final_plan(
                 vec![memory_exec, cache_exec],
                 projection,
@@ -426,7 +637,8 @@ impl TableProvider for StandardTableProvider {
             );
         }
 
-        let (partitioned_files, statistics) = partitioned_files(manifest_files, &self.schema, 1);
+        let (partitioned_files, statistics, remote_ordered_by) =
+            scan_groups(manifest_files, &self.schema, target_partitions, ordered_by);
         let remote_exec = create_parquet_physical_plan(
             ObjectStoreUrl::parse(&glob_storage.store_url()).unwrap(),
             partitioned_files,
@@ -436,7 +648,7 @@ impl TableProvider for StandardTableProvider {
             filters,
             limit,
             state,
-            time_partition.clone(),
+            remote_ordered_by,
         )
         .await?;
 
@@ -720,11 +932,16 @@ async fn collect_manifest_files(
         .collect::<Vec<object_store::Result<Bytes>>>()
         .await;
 
-    Ok(resp
-        .into_iter()
+    resp.into_iter()
         .flat_map(|res| res.ok())
-        .map(|bytes| serde_json::from_slice(&bytes).unwrap())
-        .collect())
+        .map(|bytes| {
+            let bytes = compression::decode(bytes).map_err(|err| object_store::Error::Generic {
+                store: "zstd",
+                source: Box::new(err),
+            })?;
+            Ok(serde_json::from_slice(&bytes).unwrap())
+        })
+        .collect()
 }
 
 // extract start time and end time from filter preficate
@@ -747,56 +964,143 @@ fn extract_primary_filter(
     time_filters
 }
 
-trait ManifestExt: ManifestFile {
-    fn find_matching_column(&self, partial_filter: &Expr) -> Option<&catalog::column::Column> {
-        let name = match partial_filter {
-            Expr::BinaryExpr(binary_expr) => {
-                let Expr::Column(col) = binary_expr.left.as_ref() else {
-                    return None;
-                };
-                &col.name
-            }
-            _ => {
-                return None;
+// prefixes of the custom partitions a filter restricts the query to, none if the filter is not
+// an equality on the partition column
+fn custom_partition_prefixes(expr: &Expr, column: &str) -> Option<Vec<String>> {
+    match expr {
+        Expr::BinaryExpr(BinaryExpr {
+            left,
+            op: Operator::Eq,
+            right,
+        }) => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(col), Expr::Literal(value))
+            | (Expr::Literal(value), Expr::Column(col))
+                if col.name == column =>
+            {
+                Some(vec![custom_partition_prefix(column, value)?])
             }
-        };
+            _ => None,
+        },
+        Expr::BinaryExpr(BinaryExpr {
+            left,
+            op: Operator::Or,
+            right,
+        }) => {
+            let mut prefixes = custom_partition_prefixes(left, column)?;
+            prefixes.extend(custom_partition_prefixes(right, column)?);
+            Some(prefixes)
+        }
+        Expr::InList(InList {
+            expr,
+            list,
+            negated: false,
+        }) => match expr.as_ref() {
+            Expr::Column(col) if col.name == column => list
+                .iter()
+                .map(|item| match item {
+                    Expr::Literal(value) => custom_partition_prefix(column, value),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
-        self.columns().iter().find(|col| &col.name == name)
+// values are written to the partition of their display string, other types are not pruned
+fn custom_partition_prefix(column: &str, value: &ScalarValue) -> Option<String> {
+    let value = match value {
+        ScalarValue::Utf8(Some(value)) | ScalarValue::LargeUtf8(Some(value)) => value.clone(),
+        ScalarValue::Boolean(Some(_))
+        | ScalarValue::Int8(Some(_))
+        | ScalarValue::Int16(Some(_))
+        | ScalarValue::Int32(Some(_))
+        | ScalarValue::Int64(Some(_))
+        | ScalarValue::UInt8(Some(_))
+        | ScalarValue::UInt16(Some(_))
+        | ScalarValue::UInt32(Some(_))
+        | ScalarValue::UInt64(Some(_)) => value.to_string(),
+        _ => return None,
+    };
+    Some(utils::custom_partition_to_prefix(column, Some(&value)))
+}
+
+fn in_custom_partitions(file_path: &str, column: &str, prefixes: &[String]) -> bool {
+    // files uploaded before the stream was partitioned hold every value
+    if !file_path.contains(&format!("/{column}=")) {
+        return true;
     }
+    prefixes
+        .iter()
+        .any(|prefix| file_path.contains(&format!("/{prefix}")))
+}
 
+trait ManifestExt: ManifestFile {
     fn can_be_pruned(&self, partial_filter: &Expr) -> bool {
-        fn extract_op_scalar(expr: &Expr) -> Option<(Operator, &ScalarValue)> {
-            let Expr::BinaryExpr(expr) = expr else {
-                return None;
-            };
-            let Expr::Literal(value) = &*expr.right else {
-                return None;
-            };
-            Some((expr.op, value))
-        }
-
-        let Some(col) = self.find_matching_column(partial_filter) else {
-            return false;
-        };
+        // a file without rows matches no filter
+        self.num_rows() == 0
+            || can_be_pruned(partial_filter, &|name| {
+                self.columns()
+                    .iter()
+                    .find(|col| col.name == name)?
+                    .stats
+                    .as_ref()
+            })
+    }
+}
 
-        let Some((op, value)) = extract_op_scalar(partial_filter) else {
-            return false;
-        };
+impl<T: ManifestFile> ManifestExt for T {}
 
-        let Some(value) = cast_or_none(value) else {
+// true if no row with values within the statistics of its columns can satisfy the filter,
+// columns without statistics never prune
+fn can_be_pruned<'a>(
+    filter: &Expr,
+    stats_of: &dyn Fn(&str) -> Option<&'a TypedStatistics>,
+) -> bool {
+    let compare = |column: &Expr, op: Operator, value: &Expr| {
+        let (Expr::Column(col), Expr::Literal(value)) = (column, value) else {
             return false;
         };
-
-        let Some(stats) = &col.stats else {
+        let (Some(stats), Some(value)) = (stats_of(&col.name), cast_or_none(value)) else {
             return false;
         };
-
         !satisfy_constraints(value, op, stats).unwrap_or(true)
+    };
+
+    match filter {
+        Expr::BinaryExpr(BinaryExpr {
+            left,
+            op: Operator::And,
+            right,
+        }) => can_be_pruned(left, stats_of) || can_be_pruned(right, stats_of),
+        Expr::BinaryExpr(BinaryExpr {
+            left,
+            op: Operator::Or,
+            right,
+        }) => can_be_pruned(left, stats_of) && can_be_pruned(right, stats_of),
+        Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+            if let (Expr::Literal(_), Expr::Column(_)) = (left.as_ref(), right.as_ref()) {
+                op.swap().is_some_and(|op| compare(right, op, left))
+            } else {
+                compare(left, *op, right)
+            }
+        }
+        Expr::InList(InList {
+            expr,
+            list,
+            negated: false,
+        }) => list.iter().all(|item| compare(expr, Operator::Eq, item)),
+        Expr::Between(Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        }) => compare(expr, Operator::GtEq, low) || compare(expr, Operator::LtEq, high),
+        _ => false,
     }
 }
 
-impl<T: ManifestFile> ManifestExt for T {}
-
 enum CastRes<'a> {
     Bool(bool),
     Int(i64),
@@ -828,7 +1132,7 @@ fn satisfy_constraints(value: CastRes, op: Operator, stats: &TypedStatistics) ->
     fn matches<T: std::cmp::PartialOrd>(value: T, min: T, max: T, op: Operator) -> Option<bool> {
         let val = match op {
             Operator::Eq | Operator::IsNotDistinctFrom => value >= min && value <= max,
-            Operator::NotEq => value < min && value > max,
+            Operator::NotEq => !(value == min && value == max),
             Operator::Lt => value > min,
             Operator::LtEq => value >= min,
             Operator::Gt => value < max,
@@ -855,13 +1159,22 @@ fn satisfy_constraints(value: CastRes, op: Operator, stats: &TypedStatistics) ->
 
 #[cfg(test)]
 mod tests {
-    use std::ops::Add;
+    use std::{collections::BTreeMap, ops::Add};
 
     use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Utc};
 
-    use crate::catalog::snapshot::ManifestItem;
+    use crate::catalog::{
+        column::{Column, Int64Type, TypedStatistics, Utf8Type},
+        manifest::{File, SortOrder},
+        snapshot::ManifestItem,
+    };
 
-    use super::{is_overlapping_query, PartialTimeFilter};
+    use datafusion::prelude::{col, lit};
+
+    use super::{
+        custom_partition_prefixes, in_custom_partitions, is_overlapping_query, scan_group_count,
+        time_ordered_groups, ManifestExt, PartialTimeFilter,
+    };
 
     fn datetime_min(year: i32, month: u32, day: u32) -> DateTime<Utc> {
         NaiveDate::from_ymd_opt(year, month, day)
@@ -884,16 +1197,22 @@ mod tests {
                 manifest_path: "1".to_string(),
                 time_lower_bound: datetime_min(2023, 12, 15),
                 time_upper_bound: datetime_max(2023, 12, 15),
+                column_stats: BTreeMap::new(),
+                num_files: 0,
             },
             ManifestItem {
                 manifest_path: "2".to_string(),
                 time_lower_bound: datetime_min(2023, 12, 16),
                 time_upper_bound: datetime_max(2023, 12, 16),
+                column_stats: BTreeMap::new(),
+                num_files: 0,
             },
             ManifestItem {
                 manifest_path: "3".to_string(),
                 time_lower_bound: datetime_min(2023, 12, 17),
                 time_upper_bound: datetime_max(2023, 12, 17),
+                column_stats: BTreeMap::new(),
+                num_files: 0,
             },
         ]
     }
@@ -935,4 +1254,129 @@ mod tests {
 
         assert!(!res)
     }
+
+    #[test]
+    fn small_scans_are_coalesced() {
+        // 10 files of 1 MiB each with 8 MiB minimum group size
+        assert_eq!(scan_group_count(10, 10 << 20, 16, 8 << 20), 2);
+        assert_eq!(scan_group_count(1, 1 << 10, 16, 8 << 20), 1);
+    }
+
+    #[test]
+    fn large_scans_use_target_partitions() {
+        assert_eq!(scan_group_count(100, 100 << 30, 16, 8 << 20), 16);
+        assert_eq!(scan_group_count(4, 100 << 30, 16, 8 << 20), 4);
+        assert_eq!(scan_group_count(0, 0, 16, 8 << 20), 1);
+    }
+
+    #[test]
+    fn custom_partitions_are_pruned_on_equality() {
+        let filter = col("tenant")
+            .eq(lit("acme"))
+            .or(col("tenant").eq(lit("globex")));
+        let prefixes = custom_partition_prefixes(&filter, "tenant").unwrap();
+        assert_eq!(prefixes, vec!["tenant=acme/", "tenant=globex/"]);
+
+        let file = |prefix: &str| {
+            format!("s3://bucket/app/date=2024-01-01/hour=10/minute=05/{prefix}10.0.0.1.8000.data.parquet")
+        };
+        assert!(in_custom_partitions(
+            &file("tenant=acme/"),
+            "tenant",
+            &prefixes
+        ));
+        assert!(!in_custom_partitions(
+            &file("tenant=initech/"),
+            "tenant",
+            &prefixes
+        ));
+        assert!(in_custom_partitions(&file(""), "tenant", &prefixes));
+
+        let filter = col("tenant").not_eq(lit("acme"));
+        assert!(custom_partition_prefixes(&filter, "tenant").is_none());
+    }
+
+    fn time_sorted_file(path: &str, min: i64, max: i64) -> File {
+        File {
+            file_path: path.to_string(),
+            columns: vec![Column {
+                name: "p_timestamp".to_string(),
+                stats: Some(TypedStatistics::Int(Int64Type { min, max })),
+                uncompressed_size: 0,
+                compressed_size: 0,
+            }],
+            sort_order_id: vec![("p_timestamp".to_string(), SortOrder::DescNullsFirst)],
+            ..File::default()
+        }
+    }
+
+    #[test]
+    fn files_are_grouped_in_time_order() {
+        // two ingesters uploading files of overlapping time ranges
+        let files = vec![
+            time_sorted_file("a1", 0, 10),
+            time_sorted_file("b1", 5, 15),
+            time_sorted_file("a2", 10, 20),
+            time_sorted_file("b2", 15, 25),
+        ];
+        let groups: Vec<Vec<String>> = time_ordered_groups(files, "p_timestamp")
+            .unwrap()
+            .into_iter()
+            .map(|group| group.into_iter().map(|file| file.file_path).collect())
+            .collect();
+        assert_eq!(groups, [vec!["b2", "b1"], vec!["a2", "a1"]]);
+
+        let mut unsorted = time_sorted_file("c", 0, 10);
+        unsorted.sort_order_id = vec![("status".to_string(), SortOrder::AscNullsFirst)];
+        let files = vec![time_sorted_file("a", 10, 20), unsorted];
+        assert_eq!(
+            time_ordered_groups(files, "p_timestamp").unwrap_err().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn files_are_pruned_on_statistics() {
+        let file = File {
+            file_path: "a".to_string(),
+            num_rows: 10,
+            columns: vec![
+                Column {
+                    name: "status".to_string(),
+                    stats: Some(TypedStatistics::Int(Int64Type { min: 200, max: 299 })),
+                    uncompressed_size: 0,
+                    compressed_size: 0,
+                },
+                Column {
+                    name: "host".to_string(),
+                    stats: Some(TypedStatistics::String(Utf8Type {
+                        min: "a".to_string(),
+                        max: "a".to_string(),
+                    })),
+                    uncompressed_size: 0,
+                    compressed_size: 0,
+                },
+            ],
+            ..File::default()
+        };
+
+        assert!(file.can_be_pruned(&col("status").gt_eq(lit(500))));
+        assert!(file.can_be_pruned(&lit(500).lt_eq(col("status"))));
+        assert!(file.can_be_pruned(
+            &col("status").in_list(vec![404, 500].into_iter().map(lit).collect(), false)
+        ));
+        assert!(file.can_be_pruned(&col("status").between(lit(400), lit(499))));
+        assert!(file.can_be_pruned(&col("status").eq(lit(500)).and(col("host").eq(lit("b")))));
+        assert!(file.can_be_pruned(&col("host").not_eq(lit("a"))));
+
+        assert!(!file.can_be_pruned(&col("status").eq(lit(500)).or(col("host").eq(lit("a")))));
+        assert!(!file.can_be_pruned(&col("status").not_eq(lit(200))));
+        assert!(!file.can_be_pruned(&col("level").eq(lit("error"))));
+
+        let empty = File {
+            num_rows: 0,
+            ..file
+        };
+        assert!(empty.can_be_pruned(&col("status").eq(lit(200))));
+    }
 }
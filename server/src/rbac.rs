@@ -16,6 +16,7 @@
  *
  */
 
+pub mod cluster_token;
 pub mod map;
 pub mod role;
 pub mod user;
@@ -100,6 +101,17 @@ impl Users {
         sessions().get(session).cloned().unwrap_or_default()
     }
 
+    // permissions granted to the user through their roles, independent of any session
+    pub fn get_user_permissions(&self, username: &str) -> Vec<Permission> {
+        self.get_user(username)
+            .map(|user| roles_to_permission(user.roles()))
+            .unwrap_or_default()
+    }
+
+    pub fn get_username_from_session(&self, session: &SessionKey) -> Option<String> {
+        sessions().get_username(session).cloned()
+    }
+
     pub fn session_exists(&self, session: &SessionKey) -> bool {
         sessions().get(session).is_some()
     }
@@ -0,0 +1,135 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Short lived tokens authenticating requests between the nodes of a cluster.
+//!
+//! With `P_CLUSTER_SECRET` set, nodes sign their requests to each other instead of sending the
+//! root credentials kept in the ingester metadata. A token names the node which issued it and
+//! when it expires, signed with HMAC-SHA256: `Cluster {issuer}.{expires}.{hex signature}`.
+//! Tokens are valid for `P_CLUSTER_TOKEN_TTL`, a node issues a new one once half of it has passed.
+
+use std::sync::Mutex;
+
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+
+use crate::option::CONFIG;
+
+/// Authorization scheme of cluster tokens
+pub const SCHEME: &str = "Cluster";
+// tolerated difference between the clocks of two nodes, in seconds
+const CLOCK_SKEW_SECS: i64 = 30;
+
+struct Issued {
+    token: String,
+    renew_at: i64,
+}
+
+// token this node currently sends with its requests
+static ISSUED: Lazy<Mutex<Option<Issued>>> = Lazy::new(Mutex::default);
+
+fn mac(secret: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+fn issue(secret: &str, issuer: &str, expires: i64) -> String {
+    let payload = format!("{issuer}.{expires}");
+    let signature = hex::encode(mac(secret, &payload).finalize().into_bytes());
+    format!("{payload}.{signature}")
+}
+
+/// Whether a token is signed with the secret and unexpired at `now`. Tokens expiring later than
+/// a freshly issued one would are rejected, so a leaked token can not be valid for long.
+fn verify(secret: &str, token: &str, now: i64, ttl: i64) -> bool {
+    let mut parts = token.rsplitn(3, '.');
+    let (Some(signature), Some(expires), Some(issuer)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let Ok(expires_at) = expires.parse::<i64>() else {
+        return false;
+    };
+    if expires_at < now - CLOCK_SKEW_SECS || expires_at > now + ttl + CLOCK_SKEW_SECS {
+        return false;
+    }
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    mac(secret, &format!("{issuer}.{expires}"))
+        .verify_slice(&signature)
+        .is_ok()
+}
+
+fn ttl_secs() -> i64 {
+    CONFIG.parseable.cluster_token_ttl.as_secs() as i64
+}
+
+/// Authorization header for a request to another node, `None` without a cluster secret
+pub fn authorization() -> Option<String> {
+    let secret = CONFIG.parseable.cluster_secret.as_ref()?;
+    let now = chrono::Utc::now().timestamp();
+    let mut issued = ISSUED.lock().unwrap();
+    let token = match &*issued {
+        Some(current) if current.renew_at > now => current.token.clone(),
+        _ => {
+            let ttl = ttl_secs();
+            let token = issue(secret, &CONFIG.parseable.address, now + ttl);
+            *issued = Some(Issued {
+                token: token.clone(),
+                renew_at: now + ttl / 2,
+            });
+            token
+        }
+    };
+    Some(format!("{SCHEME} {token}"))
+}
+
+/// Check a token another node sent, tokens are rejected without a cluster secret
+pub fn verify_now(token: &str) -> bool {
+    let Some(secret) = &CONFIG.parseable.cluster_secret else {
+        return false;
+    };
+    verify(secret, token, chrono::Utc::now().timestamp(), ttl_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{issue, verify};
+
+    #[test]
+    fn signed_unexpired_tokens_only() {
+        let now = 1_700_000_000;
+        let ttl = 300;
+        let token = issue("secret", "10.0.0.7:8000", now + ttl);
+
+        assert!(verify("secret", &token, now, ttl));
+        assert!(verify("secret", &token, now + ttl, ttl));
+        assert!(!verify("secret", &token, now + ttl + 60, ttl));
+        assert!(!verify("other", &token, now, ttl));
+        // expiry can not be pushed out without the secret
+        let forged = token.replacen(&(now + ttl).to_string(), &(now + 3600).to_string(), 1);
+        assert!(!verify("secret", &forged, now, ttl));
+        // nor can a token be issued to live longer than the ttl
+        let long_lived = issue("secret", "10.0.0.7:8000", now + 3600);
+        assert!(!verify("secret", &long_lived, now, ttl));
+    }
+}
@@ -187,6 +187,11 @@ impl Sessions {
         sessions.retain(|(_, expiry)| expiry < &now);
     }
 
+    // get the user related to this session
+    pub fn get_username(&self, key: &SessionKey) -> Option<&String> {
+        self.active_sessions.get(key).map(|(username, _)| username)
+    }
+
     // get permission related to this session
     pub fn get(&self, key: &SessionKey) -> Option<&Vec<Permission>> {
         self.active_sessions.get(key).map(|(_, perms)| perms)
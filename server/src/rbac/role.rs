@@ -28,6 +28,66 @@ pub enum Action {
     GetSchema,
     GetStats,
     DeleteStream,
+    FlushStream,
+    GetFlushInterval,
+    PutFlushInterval,
+    GetSortKeys,
+    PutSortKeys,
+    GetFieldMapping,
+    PutFieldMapping,
+    GetAllowedTags,
+    PutAllowedTags,
+    GetMaxColumns,
+    PutMaxColumns,
+    GetSchemaCompatibility,
+    PutSchemaCompatibility,
+    GetRenamedColumns,
+    PutRenamedColumns,
+    GetDefaultFilter,
+    PutDefaultFilter,
+    GetArchive,
+    PutArchive,
+    GetIcebergExport,
+    PutIcebergExport,
+    PutLegalHold,
+    DeleteLegalHold,
+    Purge,
+    CheckConsistency,
+    GetClusterCapacity,
+    ExportMetering,
+    ListDashboard,
+    GetDashboard,
+    CreateDashboard,
+    UpdateDashboard,
+    DeleteDashboard,
+    ListFilter,
+    GetFilter,
+    CreateFilter,
+    UpdateFilter,
+    DeleteFilter,
+    ListReport,
+    GetReport,
+    CreateReport,
+    UpdateReport,
+    DeleteReport,
+    ListDetection,
+    GetDetection,
+    CreateDetection,
+    UpdateDetection,
+    DeleteDetection,
+    ListSilence,
+    GetSilence,
+    GetAlertHistory,
+    CreateSilence,
+    DeleteSilence,
+    ListExternalTable,
+    GetExternalTable,
+    CreateExternalTable,
+    DeleteExternalTable,
+    ReloadConfig,
+    Profile,
+    ReplicateEvents,
+    PutStreamSettings,
     GetRetention,
     PutRetention,
     GetCacheEnabled,
@@ -47,7 +107,17 @@ pub enum Action {
     QueryLLM,
     ListCluster,
     ListClusterMetrics,
+    Metrics,
     DeleteIngester,
+    AssignShards,
+    Backup,
+    Restore,
+    ListStreamTemplate,
+    GetStreamTemplate,
+    PutStreamTemplate,
+    DeleteStreamTemplate,
+    ListActiveQueries,
+    KillQuery,
     All,
     GetAnalytics,
 }
@@ -106,9 +176,76 @@ impl RoleBuilder {
                 | Action::ListStream
                 | Action::ListCluster
                 | Action::ListClusterMetrics
+                | Action::Metrics
                 | Action::DeleteIngester
+                | Action::AssignShards
+                | Action::Backup
+                | Action::Restore
+                | Action::ListStreamTemplate
+                | Action::GetStreamTemplate
+                | Action::PutStreamTemplate
+                | Action::DeleteStreamTemplate
+                | Action::ListActiveQueries
+                | Action::KillQuery
+                | Action::PutLegalHold
+                | Action::DeleteLegalHold
+                | Action::CheckConsistency
+                | Action::GetClusterCapacity
+                | Action::ExportMetering
+                | Action::ListDashboard
+                | Action::GetDashboard
+                | Action::CreateDashboard
+                | Action::UpdateDashboard
+                | Action::DeleteDashboard
+                | Action::ListFilter
+                | Action::GetFilter
+                | Action::CreateFilter
+                | Action::UpdateFilter
+                | Action::DeleteFilter
+                | Action::ListReport
+                | Action::GetReport
+                | Action::CreateReport
+                | Action::UpdateReport
+                | Action::DeleteReport
+                | Action::ListDetection
+                | Action::GetDetection
+                | Action::CreateDetection
+                | Action::UpdateDetection
+                | Action::DeleteDetection
+                | Action::ListSilence
+                | Action::GetSilence
+                | Action::GetAlertHistory
+                | Action::ListExternalTable
+                | Action::CreateExternalTable
+                | Action::DeleteExternalTable
+                | Action::ReloadConfig
+                | Action::Profile
+                | Action::ReplicateEvents
+                | Action::PutStreamSettings
                 | Action::GetAnalytics => Permission::Unit(action),
                 Action::Ingest
+                | Action::FlushStream
+                | Action::GetFlushInterval
+                | Action::PutFlushInterval
+                | Action::GetSortKeys
+                | Action::PutSortKeys
+                | Action::GetFieldMapping
+                | Action::PutFieldMapping
+                | Action::GetAllowedTags
+                | Action::PutAllowedTags
+                | Action::GetMaxColumns
+                | Action::PutMaxColumns
+                | Action::GetSchemaCompatibility
+                | Action::PutSchemaCompatibility
+                | Action::GetRenamedColumns
+                | Action::PutRenamedColumns
+                | Action::GetDefaultFilter
+                | Action::PutDefaultFilter
+                | Action::GetArchive
+                | Action::PutArchive
+                | Action::GetIcebergExport
+                | Action::PutIcebergExport
+                | Action::Purge
                 | Action::GetSchema
                 | Action::GetStats
                 | Action::GetRetention
@@ -117,6 +254,9 @@ impl RoleBuilder {
                 | Action::PutCacheEnabled
                 | Action::PutAlert
                 | Action::GetAlert
+                | Action::GetExternalTable
+                | Action::CreateSilence
+                | Action::DeleteSilence
                 | Action::All => Permission::Stream(action, self.stream.clone().unwrap()),
             };
             perms.push(perm);
@@ -176,6 +316,7 @@ pub mod model {
         RoleBuilder {
             actions: vec![
                 Action::Ingest,
+                Action::FlushStream,
                 Action::Query,
                 Action::CreateStream,
                 Action::ListStream,
@@ -184,12 +325,65 @@ pub mod model {
                 Action::GetStats,
                 Action::GetRetention,
                 Action::PutRetention,
+                Action::GetFlushInterval,
+                Action::PutFlushInterval,
+                Action::GetSortKeys,
+                Action::PutSortKeys,
+                Action::GetFieldMapping,
+                Action::PutFieldMapping,
+                Action::GetAllowedTags,
+                Action::PutAllowedTags,
+                Action::GetMaxColumns,
+                Action::PutMaxColumns,
+                Action::GetSchemaCompatibility,
+                Action::PutSchemaCompatibility,
+                Action::GetRenamedColumns,
+                Action::PutRenamedColumns,
+                Action::GetDefaultFilter,
+                Action::PutDefaultFilter,
+                Action::GetArchive,
+                Action::GetIcebergExport,
+                Action::PutIcebergExport,
+                Action::Purge,
                 Action::PutCacheEnabled,
                 Action::GetCacheEnabled,
                 Action::PutAlert,
                 Action::GetAlert,
+                Action::ListSilence,
+                Action::GetSilence,
+                Action::GetAlertHistory,
+                Action::CreateSilence,
+                Action::DeleteSilence,
                 Action::GetAbout,
                 Action::QueryLLM,
+                Action::ListDashboard,
+                Action::GetDashboard,
+                Action::CreateDashboard,
+                Action::UpdateDashboard,
+                Action::DeleteDashboard,
+                Action::ListFilter,
+                Action::GetFilter,
+                Action::CreateFilter,
+                Action::UpdateFilter,
+                Action::DeleteFilter,
+                Action::ListReport,
+                Action::GetReport,
+                Action::CreateReport,
+                Action::UpdateReport,
+                Action::DeleteReport,
+                Action::ListDetection,
+                Action::GetDetection,
+                Action::CreateDetection,
+                Action::UpdateDetection,
+                Action::DeleteDetection,
+                Action::ListExternalTable,
+                Action::GetExternalTable,
+                Action::CreateExternalTable,
+                Action::DeleteExternalTable,
+                Action::ListStreamTemplate,
+                Action::GetStreamTemplate,
+                Action::PutStreamTemplate,
+                Action::DeleteStreamTemplate,
             ],
             stream: Some("*".to_string()),
             tag: None,
@@ -200,16 +394,52 @@ pub mod model {
         RoleBuilder {
             actions: vec![
                 Action::Ingest,
+                Action::FlushStream,
                 Action::Query,
                 Action::ListStream,
                 Action::GetStream,
                 Action::GetSchema,
                 Action::GetStats,
                 Action::GetRetention,
+                Action::GetFlushInterval,
+                Action::GetSortKeys,
+                Action::GetFieldMapping,
+                Action::GetAllowedTags,
+                Action::GetMaxColumns,
+                Action::GetSchemaCompatibility,
+                Action::GetRenamedColumns,
+                Action::GetDefaultFilter,
+                Action::GetIcebergExport,
                 Action::PutAlert,
                 Action::GetAlert,
+                Action::ListSilence,
+                Action::GetSilence,
+                Action::GetAlertHistory,
+                Action::CreateSilence,
+                Action::DeleteSilence,
                 Action::GetAbout,
                 Action::QueryLLM,
+                Action::ListDashboard,
+                Action::GetDashboard,
+                Action::CreateDashboard,
+                Action::UpdateDashboard,
+                Action::DeleteDashboard,
+                Action::ListFilter,
+                Action::GetFilter,
+                Action::CreateFilter,
+                Action::UpdateFilter,
+                Action::DeleteFilter,
+                Action::ListReport,
+                Action::GetReport,
+                Action::CreateReport,
+                Action::UpdateReport,
+                Action::DeleteReport,
+                Action::ListDetection,
+                Action::GetDetection,
+                Action::ListExternalTable,
+                Action::GetExternalTable,
+                Action::ListStreamTemplate,
+                Action::GetStreamTemplate,
             ],
             stream: None,
             tag: None,
@@ -225,10 +455,38 @@ pub mod model {
                 Action::GetSchema,
                 Action::GetStats,
                 Action::GetRetention,
+                Action::GetFlushInterval,
+                Action::GetSortKeys,
+                Action::GetFieldMapping,
+                Action::GetAllowedTags,
+                Action::GetMaxColumns,
+                Action::GetSchemaCompatibility,
+                Action::GetRenamedColumns,
+                Action::GetDefaultFilter,
+                Action::GetIcebergExport,
                 Action::GetAlert,
+                Action::ListSilence,
+                Action::GetSilence,
+                Action::GetAlertHistory,
                 Action::GetAbout,
                 Action::QueryLLM,
                 Action::ListCluster,
+                Action::ListDashboard,
+                Action::GetDashboard,
+                Action::ListFilter,
+                Action::GetFilter,
+                Action::CreateFilter,
+                Action::UpdateFilter,
+                Action::DeleteFilter,
+                Action::ListReport,
+                Action::GetReport,
+                Action::CreateReport,
+                Action::UpdateReport,
+                Action::DeleteReport,
+                Action::ListDetection,
+                Action::GetDetection,
+                Action::ListExternalTable,
+                Action::GetExternalTable,
             ],
             stream: None,
             tag: None,
@@ -0,0 +1,323 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Reload of the settings which can change without a restart.
+//!
+//! Settings are read from the file set with `P_CONFIG_FILE`, settings missing from the file fall
+//! back to the values the server was started with. Alerts of every stream are read again from
+//! object storage.
+
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use actix_web::{web, Responder};
+use env_logger::filter::Filter;
+use http::StatusCode;
+use once_cell::sync::Lazy;
+use url::Url;
+
+use crate::alerts::Alerts;
+use crate::handlers::http::middleware::current_request_id;
+use crate::handlers::http::oidc::set_oidc_config;
+use crate::handlers::http::problem::Problem;
+use crate::metadata::STREAM_INFO;
+use crate::oidc::OpenidConfig;
+use crate::option::CONFIG;
+use crate::query::admission::QUERY_ADMISSION;
+use crate::storage::ObjectStorageError;
+
+#[derive(Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Settings {
+    /// filter in the `RUST_LOG` format, e.g. `info` or `warn,parseable=debug`
+    #[serde(default)]
+    log_level: Option<String>,
+    #[serde(default)]
+    query_max_concurrent: Option<usize>,
+    #[serde(default)]
+    query_max_concurrent_per_user: Option<usize>,
+    #[serde(default)]
+    query_max_concurrent_per_stream: Option<usize>,
+    #[serde(default, with = "humantime_serde")]
+    query_queue_timeout: Option<Duration>,
+    #[serde(default)]
+    oidc: Option<OidcSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct OidcSettings {
+    client_id: String,
+    client_secret: String,
+    issuer: Url,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadSummary {
+    log_level: String,
+    query_max_concurrent: usize,
+    query_max_concurrent_per_user: usize,
+    query_max_concurrent_per_stream: usize,
+    #[serde(with = "humantime_serde")]
+    query_queue_timeout: Duration,
+    oidc_reloaded: bool,
+    alert_streams: usize,
+}
+
+/// Logger whose filter can be replaced while the server runs
+struct ReloadableLogger {
+    writer: env_logger::Logger,
+    filter: RwLock<Filter>,
+}
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.filter.read().unwrap().matches(record) {
+            return;
+        }
+        match current_request_id() {
+            // records logged while a request is handled carry its id
+            Some(id) => self.writer.log(
+                &log::Record::builder()
+                    .args(format_args!("{} request_id={id}", record.args()))
+                    .level(record.level())
+                    .target(record.target())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.writer.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.writer.flush()
+    }
+}
+
+// the writer lets everything through, records are filtered by the reloadable filter
+static LOGGER: Lazy<ReloadableLogger> = Lazy::new(|| ReloadableLogger {
+    writer: env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Trace)
+        .build(),
+    filter: RwLock::new(log_filter(&default_log_level())),
+});
+
+// oidc settings applied by the last reload, to only rediscover the provider on a change
+static APPLIED_OIDC: Lazy<Mutex<Option<OidcSettings>>> = Lazy::new(|| {
+    Mutex::new(CONFIG.parseable.openid.as_ref().map(|config| OidcSettings {
+        client_id: config.id.clone(),
+        client_secret: config.secret.clone(),
+        issuer: config.issuer.clone(),
+    }))
+});
+
+fn default_log_level() -> String {
+    std::env::var(env_logger::DEFAULT_FILTER_ENV).unwrap_or_default()
+}
+
+fn log_filter(spec: &str) -> Filter {
+    env_logger::filter::Builder::new().parse(spec).build()
+}
+
+/// Install the logger, filtered by `RUST_LOG` till a reload sets another level
+pub fn init_logger() {
+    log::set_logger(&*LOGGER).expect("logger is only set once");
+    log::set_max_level(LOGGER.filter.read().unwrap().filter());
+}
+
+fn set_log_level(spec: &str) {
+    let filter = log_filter(spec);
+    log::set_max_level(filter.filter());
+    *LOGGER.filter.write().unwrap() = filter;
+}
+
+async fn read_settings() -> Result<Settings, ReloadError> {
+    let Some(path) = &CONFIG.parseable.config_file else {
+        return Ok(Settings::default());
+    };
+    let bytes = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Read the settings again and apply them. Everything which can fail is done before any
+/// setting is applied, a failed reload leaves the settings as they were.
+pub async fn reload() -> Result<ReloadSummary, ReloadError> {
+    let settings = read_settings().await?;
+    let parseable = &CONFIG.parseable;
+    let alerts = load_alerts().await?;
+
+    // a changed provider is discovered last, it is applied along with everything else
+    let oidc = settings.oidc.clone().or_else(|| {
+        parseable.openid.as_ref().map(|config| OidcSettings {
+            client_id: config.id.clone(),
+            client_secret: config.secret.clone(),
+            issuer: config.issuer.clone(),
+        })
+    });
+    let oidc_reloaded = *APPLIED_OIDC.lock().unwrap() != oidc;
+    if oidc_reloaded {
+        set_oidc_config(oidc.clone().map(|oidc| OpenidConfig {
+            id: oidc.client_id,
+            secret: oidc.client_secret,
+            issuer: oidc.issuer,
+            origin: parseable.openid_origin(),
+        }))
+        .await?;
+        *APPLIED_OIDC.lock().unwrap() = oidc;
+    }
+
+    let log_level = settings.log_level.unwrap_or_else(default_log_level);
+    set_log_level(&log_level);
+
+    let summary = ReloadSummary {
+        log_level,
+        query_max_concurrent: settings
+            .query_max_concurrent
+            .unwrap_or(parseable.query_max_concurrent),
+        query_max_concurrent_per_user: settings
+            .query_max_concurrent_per_user
+            .unwrap_or(parseable.query_max_concurrent_per_user),
+        query_max_concurrent_per_stream: settings
+            .query_max_concurrent_per_stream
+            .unwrap_or(parseable.query_max_concurrent_per_stream),
+        query_queue_timeout: settings
+            .query_queue_timeout
+            .unwrap_or(parseable.query_queue_timeout),
+        oidc_reloaded,
+        alert_streams: alerts.len(),
+    };
+    for (stream, alerts) in alerts {
+        // the stream may have been deleted meanwhile
+        let _ = STREAM_INFO.set_alert(&stream, alerts);
+    }
+    QUERY_ADMISSION.set_limits(
+        summary.query_max_concurrent,
+        summary.query_max_concurrent_per_user,
+        summary.query_max_concurrent_per_stream,
+        summary.query_queue_timeout,
+    );
+
+    log::info!("configuration reloaded: {summary:?}");
+    Ok(summary)
+}
+
+// alerts, including their targets, as currently stored for every stream
+async fn load_alerts() -> Result<Vec<(String, Alerts)>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let mut alerts = Vec::new();
+    for stream in STREAM_INFO.list_streams() {
+        let stream_alerts = store.get_alerts(&stream).await?;
+        alerts.push((stream, stream_alerts));
+    }
+    Ok(alerts)
+}
+
+/// Reload the configuration whenever the process receives SIGHUP
+#[cfg(unix)]
+pub fn init_sighup_handler() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            log::warn!("could not listen for SIGHUP, configuration can only be reloaded through the api: {err}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(err) = reload().await {
+                log::error!("configuration reload failed: {err}");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn init_sighup_handler() {}
+
+// POST "/config/reload" ==> apply the settings of the config file and reload alerts
+pub async fn reload_config() -> Result<impl Responder, ReloadError> {
+    Ok(web::Json(reload().await?))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("Could not read the config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid config file: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Could not connect to the OIDC provider: {0}")]
+    Oidc(#[from] openid::error::Error),
+    #[error("Failed to connect to storage: {0}")]
+    ObjectStorage(#[from] ObjectStorageError),
+}
+
+impl actix_web::ResponseError for ReloadError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Serde(_) => StatusCode::BAD_REQUEST,
+            Self::Oidc(_) => StatusCode::BAD_GATEWAY,
+            Self::ObjectStorage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Settings;
+
+    #[test]
+    fn settings_file_format() {
+        let settings: Settings = serde_json::from_str(
+            r#"{
+                "logLevel": "warn,parseable=debug",
+                "queryMaxConcurrentPerUser": 2,
+                "queryQueueTimeout": "45s",
+                "oidc": {
+                    "clientId": "parseable",
+                    "clientSecret": "secret",
+                    "issuer": "https://accounts.example.com"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(settings.log_level.as_deref(), Some("warn,parseable=debug"));
+        assert_eq!(settings.query_max_concurrent, None);
+        assert_eq!(settings.query_max_concurrent_per_user, Some(2));
+        assert_eq!(settings.query_queue_timeout, Some(Duration::from_secs(45)));
+        assert!(settings.oidc.is_some());
+
+        // misspelled settings are not silently ignored
+        assert!(serde_json::from_str::<Settings>(r#"{"loglevel": "debug"}"#).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Replication of staged events to peer ingesters.
+//!
+//! With `P_REPLICATION_FACTOR` set, every batch is copied to that many peers before it is
+//! acknowledged. Peers are the ingesters following this one in the order of their ids, a peer
+//! which can not be reached is replaced by the next one. Peers keep the copies in
+//! `staging/.replicas/{origin}/{stream}` till the origin uploads the events and trims them.
+//! Copies of an ingester which went stale are staged and uploaded by every peer holding them,
+//! so events of a lost ingester may show up more than once but are not lost.
+
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use arrow_array::RecordBatch;
+use arrow_ipc::writer::StreamWriter;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use futures::future::join_all;
+use http::StatusCode;
+use once_cell::sync::{Lazy, OnceCell};
+
+use crate::event::{self, format};
+use crate::handlers::http::base_path_without_preceding_slash;
+use crate::handlers::http::cluster::node_client::CLIENT;
+use crate::handlers::http::cluster::{get_ingester_info, is_stale};
+use crate::handlers::http::ingest::{create_stream_if_not_exists, PostError};
+use crate::handlers::http::modal::IngesterMetadata;
+use crate::handlers::http::problem::Problem;
+use crate::metadata::STREAM_INFO;
+use crate::option::{Mode, CONFIG};
+use crate::shutdown;
+use crate::storage::{staging::encryption, LOCAL_SYNC_INTERVAL};
+
+const REPLICA_DIR: &str = ".replicas";
+const REPLICA_EXTENSION: &str = "arrows";
+// time the origin took the batch, in milliseconds since the epoch
+const TIMESTAMP_HEADER: &str = "x-p-replica-timestamp";
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(10);
+const PEER_REFRESH_INTERVAL_MINUTES: u32 = 1;
+
+// files still open when a sync starts are uploaded by the next one,
+// so only copies taken this long before the sync are trimmed
+const TRIM_MARGIN: Duration = Duration::from_secs(2 * LOCAL_SYNC_INTERVAL);
+
+// id of this ingester, set when replication is initialized
+static LOCAL_ID: OnceCell<String> = OnceCell::new();
+// registered ingesters, refreshed every minute
+static INGESTERS: Lazy<RwLock<Vec<IngesterMetadata>>> = Lazy::new(RwLock::default);
+
+/// Whether events are copied to peers before they are acknowledged
+pub fn is_enabled() -> bool {
+    CONFIG.parseable.mode == Mode::Ingest && CONFIG.parseable.replication_factor > 0
+}
+
+fn replica_dir() -> PathBuf {
+    CONFIG.staging_dir().join(REPLICA_DIR)
+}
+
+/// Remove the copies of a deleted stream kept for every peer
+pub fn drop_stream(stream_name: &str) {
+    let Ok(origins) = std::fs::read_dir(replica_dir()) else {
+        return;
+    };
+    for origin in origins.flatten() {
+        let dir = origin.path().join(stream_name);
+        if dir.exists() {
+            if let Err(err) = std::fs::remove_dir_all(&dir) {
+                log::warn!("failed to remove replicas in {}: {err}", dir.display());
+            }
+        }
+    }
+}
+
+// path segments sent by peers end up in the file system
+fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty() && segment != "." && segment != ".." && !segment.contains(['/', '\\'])
+}
+
+/// Ingesters other than `origin` in the order copies of its events are placed,
+/// starting with the one following it by id and wrapping around
+fn successors<'a>(ingesters: &'a [IngesterMetadata], origin: &str) -> Vec<&'a IngesterMetadata> {
+    let mut peers: Vec<&IngesterMetadata> = ingesters
+        .iter()
+        .filter(|ingester| !ingester.ingester_id.is_empty() && ingester.ingester_id != origin)
+        .collect();
+    peers.sort_by(|a, b| a.ingester_id.cmp(&b.ingester_id));
+    let split = peers.partition_point(|peer| peer.ingester_id.as_str() < origin);
+    peers.rotate_left(split);
+    peers
+}
+
+/// Start refreshing the list of peers and staging copies of stale ingesters
+pub async fn init(ingester_id: String) {
+    let _ = LOCAL_ID.set(ingester_id);
+    refresh_ingesters().await;
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler
+        .every(PEER_REFRESH_INTERVAL_MINUTES.minutes())
+        .run(|| async {
+            refresh_ingesters().await;
+            promote_orphaned_replicas().await;
+        });
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+async fn refresh_ingesters() {
+    match get_ingester_info().await {
+        Ok(ingesters) => *INGESTERS.write().unwrap() = ingesters,
+        Err(err) => log::warn!("could not refresh the ingesters to replicate to: {err}"),
+    }
+}
+
+// registered ingesters other than this one which have a recent heartbeat
+fn live_peers() -> Vec<IngesterMetadata> {
+    let now = Utc::now();
+    let ingesters = INGESTERS.read().unwrap();
+    successors(&ingesters, LOCAL_ID.get().map_or("", String::as_str))
+        .into_iter()
+        .filter(|peer| !is_stale(peer, now, CONFIG.parseable.ingester_stale_after))
+        .cloned()
+        .collect()
+}
+
+fn encode(rb: &RecordBatch) -> Result<Bytes, arrow_schema::ArrowError> {
+    let mut buf = Vec::new();
+    {
+        let mut writer = StreamWriter::try_new(&mut buf, &rb.schema())?;
+        writer.write(rb)?;
+        writer.finish()?;
+    }
+    Ok(buf.into())
+}
+
+async fn send_replica(
+    peer: &IngesterMetadata,
+    origin: &str,
+    stream_name: &str,
+    timestamp: i64,
+    body: Bytes,
+) -> Result<(), reqwest::Error> {
+    CLIENT
+        .post(format!(
+            "{}{}/replicas/{origin}/{stream_name}",
+            peer.domain_name,
+            base_path_without_preceding_slash()
+        ))
+        .header(header::AUTHORIZATION, peer.authorization())
+        .header(TIMESTAMP_HEADER, timestamp)
+        .timeout(REPLICATION_TIMEOUT)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Copy a batch to as many peers as the replication factor asks for
+pub async fn replicate(stream_name: &str, rb: &RecordBatch) -> Result<(), ReplicationError> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let Some(origin) = LOCAL_ID.get() else {
+        return Ok(());
+    };
+
+    let required = CONFIG.parseable.replication_factor;
+    let body = encode(rb)?;
+    let timestamp = Utc::now().timestamp_millis();
+    let mut candidates = live_peers().into_iter();
+    let mut acked = 0;
+
+    while acked < required {
+        let peers: Vec<IngesterMetadata> = candidates.by_ref().take(required - acked).collect();
+        if peers.is_empty() {
+            return Err(ReplicationError::NotEnoughPeers { required, acked });
+        }
+        let results = join_all(
+            peers
+                .iter()
+                .map(|peer| send_replica(peer, origin, stream_name, timestamp, body.clone())),
+        )
+        .await;
+        for (peer, result) in peers.iter().zip(results) {
+            match result {
+                Ok(()) => acked += 1,
+                Err(err) => log::warn!("could not replicate to {}: {err}", peer.domain_name),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ask the peers to drop copies of events of the given streams which are in object storage now
+pub async fn trim_peers(stream_names: &[String], uploaded_before: DateTime<Utc>) {
+    if !is_enabled() {
+        return;
+    }
+    let Some(origin) = LOCAL_ID.get() else {
+        return;
+    };
+
+    // copies may be left on peers which are no longer the first ones, so every peer is asked
+    let peers = live_peers();
+    let requests = peers.iter().flat_map(|peer| {
+        stream_names.iter().map(move |stream_name| async move {
+            let res = CLIENT
+                .delete(format!(
+                    "{}{}/replicas/{origin}/{stream_name}",
+                    peer.domain_name,
+                    base_path_without_preceding_slash()
+                ))
+                .query(&[("before", uploaded_before.timestamp_millis())])
+                .header(header::AUTHORIZATION, peer.authorization())
+                .timeout(REPLICATION_TIMEOUT)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status());
+            if let Err(err) = res {
+                // copies left behind are trimmed with the next sync of the stream
+                log::warn!("could not trim replicas on {}: {err}", peer.domain_name);
+            }
+        })
+    });
+    join_all(requests).await;
+}
+
+fn replica_timestamp(path: &std::path::Path) -> Option<i64> {
+    path.file_name()?.to_str()?.split('.').next()?.parse().ok()
+}
+
+// stage copies of ingesters which went stale or were removed, so their events are uploaded
+async fn promote_orphaned_replicas() {
+    if shutdown::is_draining() {
+        return;
+    }
+    let Ok(origins) = std::fs::read_dir(replica_dir()) else {
+        return;
+    };
+
+    let now = Utc::now();
+    let ingesters = INGESTERS.read().unwrap().clone();
+    for origin in origins.flatten() {
+        let origin_id = origin.file_name().to_string_lossy().into_owned();
+        let alive = ingesters.iter().any(|ingester| {
+            ingester.ingester_id == origin_id
+                && !is_stale(ingester, now, CONFIG.parseable.ingester_stale_after)
+        });
+        if alive {
+            continue;
+        }
+
+        log::warn!("ingester {origin_id} is gone, staging the events it replicated here");
+        let Ok(streams) = std::fs::read_dir(origin.path()) else {
+            continue;
+        };
+        for stream in streams.flatten() {
+            let stream_name = stream.file_name().to_string_lossy().into_owned();
+            if let Err(err) = promote_stream(&stream_name, stream.path()).await {
+                log::error!(
+                    "could not stage replicas of {origin_id} for stream {stream_name}: {err}"
+                );
+            }
+        }
+        let _ = std::fs::remove_dir(origin.path());
+    }
+}
+
+async fn promote_stream(stream_name: &str, dir: PathBuf) -> Result<(), PostError> {
+    create_stream_if_not_exists(stream_name).await?;
+
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .map_err(|err| PostError::CustomError(err.to_string()))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    files.sort();
+
+    for file in files {
+        let body = tokio::fs::read(&file)
+            .await
+            .and_then(encryption::decrypt)
+            .map_err(|err| PostError::CustomError(err.to_string()))?;
+        let size = body.len();
+        let rb = format::arrow::Event::read_ipc_stream(body.into())?;
+        let stream_schema = STREAM_INFO
+            .schema(stream_name)
+            .map_err(anyhow::Error::from)?;
+        let is_first_event = rb
+            .schema()
+            .fields()
+            .iter()
+            .any(|field| stream_schema.field_with_name(field.name()).is_err());
+
+        event::Event {
+            rb,
+            stream_name: stream_name.to_owned(),
+            origin_format: "json",
+            origin_size: size as u64,
+            is_first_event,
+        }
+        .process()
+        .await?;
+        let _ = std::fs::remove_file(&file);
+    }
+    let _ = std::fs::remove_dir(&dir);
+    Ok(())
+}
+
+fn path_segments(req: &HttpRequest) -> Result<(String, String), ReplicationError> {
+    let origin = req.match_info().get("origin").unwrap_or_default();
+    let stream_name = req.match_info().get("logstream").unwrap_or_default();
+    if !is_valid_segment(origin) || !is_valid_segment(stream_name) {
+        return Err(ReplicationError::InvalidPath);
+    }
+    Ok((origin.to_owned(), stream_name.to_owned()))
+}
+
+// POST "/replicas/{origin}/{logstream}" ==> Keep a copy of a batch staged on the origin ingester
+pub async fn put_replica(req: HttpRequest, body: Bytes) -> Result<HttpResponse, ReplicationError> {
+    // a draining ingester would leave the copy behind, the origin picks another peer
+    if shutdown::is_draining() {
+        return Err(ReplicationError::ShuttingDown);
+    }
+    let (origin, stream_name) = path_segments(&req)?;
+    let timestamp = req
+        .headers()
+        .get(TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or(ReplicationError::MissingTimestamp)?;
+
+    let dir = replica_dir().join(origin).join(stream_name);
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!(
+        "{timestamp}.{}.{REPLICA_EXTENSION}",
+        ulid::Ulid::new()
+    ));
+    tokio::fs::write(path, encryption::encrypt(&body)?).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TrimQuery {
+    /// milliseconds since the epoch
+    before: i64,
+}
+
+// DELETE "/replicas/{origin}/{logstream}?before={millis}" ==> Drop copies the origin has uploaded
+pub async fn trim_replicas(
+    req: HttpRequest,
+    query: web::Query<TrimQuery>,
+) -> Result<HttpResponse, ReplicationError> {
+    let (origin, stream_name) = path_segments(&req)?;
+    let dir = replica_dir().join(origin).join(stream_name);
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Ok(HttpResponse::Ok().finish());
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if replica_timestamp(&path).is_some_and(|timestamp| timestamp < query.before) {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    let _ = std::fs::remove_dir(&dir);
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Time before which events are in object storage, for a sync which started at `started`
+pub fn uploaded_before(started: DateTime<Utc>) -> DateTime<Utc> {
+    started - chrono::Duration::from_std(TRIM_MARGIN).expect("trim margin fits")
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReplicationError {
+    #[error(
+        "Events were copied to {acked} of the {required} peer ingesters required, send them again"
+    )]
+    NotEnoughPeers { required: usize, acked: usize },
+    #[error("Could not encode the events: {0}")]
+    Arrow(#[from] arrow_schema::ArrowError),
+    #[error("Could not store the replica: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid origin or stream name")]
+    InvalidPath,
+    #[error("Missing replica timestamp")]
+    MissingTimestamp,
+    #[error("Server is shutting down, replicate to another ingester")]
+    ShuttingDown,
+}
+
+impl actix_web::ResponseError for ReplicationError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            Self::NotEnoughPeers { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Arrow(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidPath => StatusCode::BAD_REQUEST,
+            Self::MissingTimestamp => StatusCode::BAD_REQUEST,
+            Self::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::successors;
+    use crate::handlers::http::modal::IngesterMetadata;
+
+    fn ingester(id: &str) -> IngesterMetadata {
+        IngesterMetadata {
+            ingester_id: id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn peers_follow_origin_by_id() {
+        let ingesters = vec![ingester("c"), ingester("a"), ingester("d"), ingester("b")];
+        let order = |origin| -> Vec<String> {
+            successors(&ingesters, origin)
+                .into_iter()
+                .map(|peer| peer.ingester_id.clone())
+                .collect()
+        };
+
+        assert_eq!(order("b"), ["c", "d", "a"]);
+        assert_eq!(order("d"), ["a", "b", "c"]);
+        // an ingester which is no longer registered keeps its place in the ring
+        assert_eq!(order("bb"), ["c", "d", "a", "b"]);
+    }
+}
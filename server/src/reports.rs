@@ -0,0 +1,544 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Reports run a saved query on a cron schedule and mail the results to their recipients.
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use clokwerk::{AsyncScheduler, TimeUnits};
+use datafusion::arrow::json::writer::record_batches_to_json_rows;
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use relative_path::RelativePathBuf;
+use serde_json::{Map, Value};
+use ulid::Ulid;
+
+use crate::handlers::http::query::authorize_query;
+use crate::option::CONFIG;
+use crate::query::{renamed_columns, QUERY_SESSION};
+use crate::rbac::Users;
+use crate::storage::{ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
+
+const REPORTS_DIRECTORY: &str = "reports";
+// rows shown in the mail body, the attachment has all of them
+const REPORT_TABLE_ROWS: usize = 50;
+// a failed run is retried after this long, doubling after every further failure
+const REPORT_RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// a run failing this many times in a row is given up until the next scheduled time
+const REPORT_MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub id: Ulid,
+    pub name: String,
+    /// sql of the query the report runs
+    pub query: String,
+    /// window the query covers, ending at the time the report runs, e.g. `1d`
+    pub time_range: String,
+    /// cron expression, either with or without the leading seconds field
+    pub schedule: String,
+    pub recipients: Vec<String>,
+    pub owner: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<DateTime<Utc>>,
+    /// time of the last failed run since the report last ran
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// failed attempts of the current run
+    #[serde(default)]
+    pub failures: u32,
+}
+
+impl Report {
+    /// Whether the schedule fired since the report last ran, a failed run is retried once its
+    /// backoff passed
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        let Ok(schedule) = parse_schedule(&self.schedule) else {
+            return false;
+        };
+        if let Some(failed) = self.last_failure.filter(|_| self.failures > 0) {
+            if now < failed + self.retry_backoff() {
+                return false;
+            }
+        }
+        schedule
+            .after(&self.last_run.unwrap_or(self.created_at))
+            .next()
+            .is_some_and(|next| next <= now)
+    }
+
+    fn retry_backoff(&self) -> chrono::Duration {
+        let interval = chrono::Duration::from_std(REPORT_RETRY_INTERVAL).unwrap();
+        interval * 2i32.pow(self.failures.saturating_sub(1).min(REPORT_MAX_ATTEMPTS))
+    }
+
+    /// Record the outcome of a run. A run failing [`REPORT_MAX_ATTEMPTS`] times in a row is
+    /// given up, the report runs again at its next scheduled time.
+    pub fn record_run(&mut self, now: DateTime<Utc>, res: Result<(), String>) {
+        match res {
+            Ok(()) => {
+                self.last_run = Some(now);
+                self.last_failure = None;
+                self.last_error = None;
+                self.failures = 0;
+            }
+            Err(err) => {
+                self.last_failure = Some(now);
+                self.last_error = Some(err);
+                self.failures += 1;
+                if self.failures >= REPORT_MAX_ATTEMPTS {
+                    log::warn!(
+                        "report {} ({}) failed {} times, it is skipped until it is next scheduled",
+                        self.name,
+                        self.id,
+                        self.failures
+                    );
+                    self.last_run = Some(now);
+                    self.failures = 0;
+                }
+            }
+        }
+    }
+
+    // the state of the runs of `ran`, kept on a report which may have been changed meanwhile
+    fn with_runs_of(self, ran: &Report) -> Self {
+        Self {
+            last_run: ran.last_run,
+            last_failure: ran.last_failure,
+            last_error: ran.last_error.clone(),
+            failures: ran.failures,
+            ..self
+        }
+    }
+}
+
+/// Parse a cron expression. The usual five fields are accepted along with the six or seven
+/// fields form which starts with seconds.
+pub fn parse_schedule(expression: &str) -> Result<cron::Schedule, cron::error::Error> {
+    let expression = expression.trim();
+    if expression.split_whitespace().count() == 5 {
+        cron::Schedule::from_str(&format!("0 {expression}"))
+    } else {
+        cron::Schedule::from_str(expression)
+    }
+}
+
+pub fn reports_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, REPORTS_DIRECTORY])
+}
+
+pub fn report_path(id: Ulid) -> RelativePathBuf {
+    reports_path().join(format!("{id}.json"))
+}
+
+pub async fn list_reports() -> Result<Vec<Report>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = match store
+        .get_objects(
+            Some(&reports_path()),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await
+    {
+        Ok(objects) => objects,
+        // nothing was saved yet
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    objects
+        .iter()
+        .map(|bytes| {
+            serde_json::from_slice(bytes)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))
+        })
+        .collect()
+}
+
+pub async fn get_report(id: Ulid) -> Result<Option<Report>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    match store.get_object(&report_path(id)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err))),
+        Err(ObjectStorageError::NoSuchKey(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub async fn put_report(report: &Report) -> Result<(), ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let body = serde_json::to_vec(report)
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    store.put_object(&report_path(report.id), body.into()).await
+}
+
+pub fn smtp_configured() -> bool {
+    CONFIG.parseable.smtp_host.is_some()
+}
+
+pub fn init_report_scheduler() {
+    if !smtp_configured() {
+        return;
+    }
+    log::info!("Setting up schedular for reports");
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler.every(1.minutes()).run(run_due_reports);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+async fn run_due_reports() {
+    let reports = match list_reports().await {
+        Ok(reports) => reports,
+        Err(err) => {
+            log::warn!("could not load reports: {err}");
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    for mut report in reports.into_iter().filter(|report| report.is_due(now)) {
+        let res = run_report(&report, now).await.map_err(|err| {
+            log::warn!("report {} ({}) failed: {err}", report.name, report.id);
+            err.to_string()
+        });
+        report.record_run(now, res);
+
+        // the report is read again so that changes made while it ran are not overwritten
+        let current = match get_report(report.id).await {
+            Ok(Some(current)) => current,
+            // deleted while it ran
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!("could not update report {}: {err}", report.id);
+                continue;
+            }
+        };
+        if let Err(err) = put_report(&current.with_runs_of(&report)).await {
+            log::warn!("could not update report {}: {err}", report.id);
+        }
+    }
+}
+
+/// Run the report query over the window ending at `now` and mail the results
+pub async fn run_report(report: &Report, now: DateTime<Utc>) -> anyhow::Result<()> {
+    let range = chrono::Duration::from_std(humantime::parse_duration(&report.time_range)?)?;
+    let mut query = crate::query::Query {
+        raw_logical_plan: renamed_columns::create_logical_plan(
+            &QUERY_SESSION.state(),
+            &report.query,
+        )
+        .await?,
+        start: now - range,
+        end: now,
+        filter_tag: None,
+        at: None,
+        raw: false,
+    };
+    let table_name = query
+        .table_name()
+        .ok_or_else(|| anyhow!("query does not read from a stream"))?;
+
+    // the query runs with the permissions the owner has at this time
+    let tags = authorize_query(&Users.get_user_permissions(&report.owner), &table_name)?;
+    if !tags.is_empty() {
+        query.filter_tag = Some(tags);
+    }
+
+    let (records, fields) = query.execute(table_name).await?;
+    let rows = record_batches_to_json_rows(&records.iter().collect::<Vec<_>>())?;
+
+    send_report(report, now, &fields, &rows).await
+}
+
+async fn send_report(
+    report: &Report,
+    now: DateTime<Utc>,
+    fields: &[String],
+    rows: &[Map<String, Value>],
+) -> anyhow::Result<()> {
+    let host = CONFIG
+        .parseable
+        .smtp_host
+        .as_deref()
+        .ok_or_else(|| anyhow!("SMTP is not configured"))?;
+    let from: Mailbox = CONFIG
+        .parseable
+        .smtp_from
+        .as_deref()
+        .ok_or_else(|| anyhow!("SMTP sender is not configured"))?
+        .parse()?;
+
+    let mut message = Message::builder()
+        .from(from)
+        .subject(format!("Parseable report: {}", report.name));
+    for recipient in &report.recipients {
+        message = message.to(recipient.parse()?);
+    }
+    let message = message.multipart(
+        MultiPart::mixed()
+            .singlepart(SinglePart::html(to_html(report, now, fields, rows)))
+            .singlepart(
+                Attachment::new(format!("{}-{}.csv", report.name, now.format("%Y%m%d%H%M")))
+                    .body(to_csv(fields, rows), ContentType::parse("text/csv")?),
+            ),
+    )?;
+
+    let mut transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+        .port(CONFIG.parseable.smtp_port);
+    if let (Some(username), Some(password)) = (
+        &CONFIG.parseable.smtp_username,
+        &CONFIG.parseable.smtp_password,
+    ) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport.build().send(message).await?;
+
+    Ok(())
+}
+
+fn cell(row: &Map<String, Value>, field: &str) -> String {
+    match row.get(field) {
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn to_csv(fields: &[String], rows: &[Map<String, Value>]) -> String {
+    let escape = |value: &str| {
+        if value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_owned()
+        }
+    };
+
+    let mut csv = fields
+        .iter()
+        .map(|field| escape(field))
+        .collect::<Vec<_>>()
+        .join(",");
+    csv.push('\n');
+    for row in rows {
+        let line: Vec<String> = fields
+            .iter()
+            .map(|field| escape(&cell(row, field)))
+            .collect();
+        csv.push_str(&line.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn to_html(
+    report: &Report,
+    now: DateTime<Utc>,
+    fields: &[String],
+    rows: &[Map<String, Value>],
+) -> String {
+    let mut html = format!(
+        "<h3>{}</h3><p>{} rows for the {} up to {}</p>",
+        escape_html(&report.name),
+        rows.len(),
+        escape_html(&report.time_range),
+        now.to_rfc3339()
+    );
+    html.push_str("<table border=\"1\" cellpadding=\"4\" cellspacing=\"0\"><tr>");
+    for field in fields {
+        html.push_str(&format!("<th>{}</th>", escape_html(field)));
+    }
+    html.push_str("</tr>");
+    for row in rows.iter().take(REPORT_TABLE_ROWS) {
+        html.push_str("<tr>");
+        for field in fields {
+            html.push_str(&format!("<td>{}</td>", escape_html(&cell(row, field))));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+    if rows.len() > REPORT_TABLE_ROWS {
+        html.push_str(&format!(
+            "<p>First {REPORT_TABLE_ROWS} rows are shown, all rows are in the attached csv</p>"
+        ));
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use serde_json::json;
+    use ulid::Ulid;
+
+    use super::{parse_schedule, to_csv, Report};
+
+    #[test]
+    fn report_is_due_after_scheduled_time() {
+        assert!(parse_schedule("0 8 * * *").is_ok());
+        assert!(parse_schedule("0 0 8 * * *").is_ok());
+        assert!(parse_schedule("every day").is_err());
+
+        let created_at = Utc.with_ymd_and_hms(2024, 3, 5, 7, 0, 0).unwrap();
+        let mut report = Report {
+            id: Ulid::new(),
+            name: "errors".to_string(),
+            query: "select * from app".to_string(),
+            time_range: "1d".to_string(),
+            schedule: "0 8 * * *".to_string(),
+            recipients: vec!["team@example.com".to_string()],
+            owner: "alice".to_string(),
+            created_at,
+            updated_at: created_at,
+            last_run: None,
+            last_failure: None,
+            last_error: None,
+            failures: 0,
+        };
+        assert!(!report.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 7, 59, 0).unwrap()));
+        assert!(report.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 30).unwrap()));
+
+        // a failed run is retried later on
+        report.record_run(
+            Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 30).unwrap(),
+            Err("smtp unavailable".to_string()),
+        );
+        assert!(!report.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 8, 5, 0).unwrap()));
+        assert!(report.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 8, 10, 30).unwrap()));
+        report.record_run(Utc.with_ymd_and_hms(2024, 3, 5, 8, 10, 30).unwrap(), Ok(()));
+        assert_eq!(report.failures, 0);
+        assert!(report.last_error.is_none());
+
+        report.last_run = Some(Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 30).unwrap());
+        assert!(!report.is_due(Utc.with_ymd_and_hms(2024, 3, 5, 8, 1, 0).unwrap()));
+    }
+
+    #[test]
+    fn failed_runs_back_off_and_are_given_up() {
+        let created_at = Utc.with_ymd_and_hms(2024, 3, 5, 7, 0, 0).unwrap();
+        let mut report = Report {
+            id: Ulid::new(),
+            name: "errors".to_string(),
+            query: "select * from app".to_string(),
+            time_range: "1d".to_string(),
+            schedule: "0 8 * * *".to_string(),
+            recipients: vec!["team@example.com".to_string()],
+            owner: "alice".to_string(),
+            created_at,
+            updated_at: created_at,
+            last_run: None,
+            last_failure: None,
+            last_error: None,
+            failures: 0,
+        };
+
+        let mut now = Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 0).unwrap();
+        for backoff in [10, 20, 40, 80] {
+            assert!(report.is_due(now));
+            report.record_run(now, Err("smtp unavailable".to_string()));
+            assert!(!report.is_due(now + Duration::minutes(backoff - 1)));
+            now += Duration::minutes(backoff);
+        }
+
+        // the fifth failure gives up until the next day
+        assert!(report.is_due(now));
+        report.record_run(now, Err("smtp unavailable".to_string()));
+        assert_eq!(report.failures, 0);
+        assert_eq!(report.last_error.as_deref(), Some("smtp unavailable"));
+        assert!(!report.is_due(now + Duration::hours(12)));
+        assert!(report.is_due(Utc.with_ymd_and_hms(2024, 3, 6, 8, 0, 30).unwrap()));
+    }
+
+    #[test]
+    fn runs_are_recorded_on_the_current_report() {
+        let created_at = Utc.with_ymd_and_hms(2024, 3, 5, 7, 0, 0).unwrap();
+        let mut ran = Report {
+            id: Ulid::new(),
+            name: "errors".to_string(),
+            query: "select * from app".to_string(),
+            time_range: "1d".to_string(),
+            schedule: "0 8 * * *".to_string(),
+            recipients: vec!["team@example.com".to_string()],
+            owner: "alice".to_string(),
+            created_at,
+            updated_at: created_at,
+            last_run: None,
+            last_failure: None,
+            last_error: None,
+            failures: 0,
+        };
+        // changed through the api while the report ran
+        let current = Report {
+            recipients: vec!["oncall@example.com".to_string()],
+            schedule: "0 9 * * *".to_string(),
+            ..ran.clone()
+        };
+        let now = Utc.with_ymd_and_hms(2024, 3, 5, 8, 0, 30).unwrap();
+        ran.record_run(now, Ok(()));
+
+        let updated = current.with_runs_of(&ran);
+        assert_eq!(updated.recipients, vec!["oncall@example.com"]);
+        assert_eq!(updated.schedule, "0 9 * * *");
+        assert_eq!(updated.last_run, Some(now));
+    }
+
+    #[test]
+    fn csv_rendering() {
+        let row = json!({"level": "error", "message": "a \"quoted\", message", "count": 3});
+        let csv = to_csv(
+            &[
+                "level".to_string(),
+                "message".to_string(),
+                "count".to_string(),
+                "host".to_string(),
+            ],
+            &[row.as_object().unwrap().clone()],
+        );
+        assert_eq!(
+            csv,
+            "level,message,count,host\nerror,\"a \"\"quoted\"\", message\",3,\n"
+        );
+    }
+}
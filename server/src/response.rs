@@ -16,7 +16,6 @@
  *
  */
 
-use actix_web::{web, Responder};
 use datafusion::arrow::json::writer::record_batches_to_json_rows;
 use datafusion::arrow::record_batch::RecordBatch;
 use itertools::Itertools;
@@ -30,7 +29,7 @@ pub struct QueryResponse {
 }
 
 impl QueryResponse {
-    pub fn to_http(&self) -> impl Responder {
+    pub fn to_json(&self) -> Value {
         log::info!("{}", "Returning query results");
         let records: Vec<&RecordBatch> = self.records.iter().collect();
         let mut json_records = record_batches_to_json_rows(&records).unwrap();
@@ -45,15 +44,13 @@ impl QueryResponse {
         }
         let values = json_records.into_iter().map(Value::Object).collect_vec();
 
-        let response = if self.with_fields {
+        if self.with_fields {
             json!({
                 "fields": self.fields,
                 "records": values
             })
         } else {
             Value::Array(values)
-        };
-
-        web::Json(response)
+        }
     }
 }
@@ -0,0 +1,260 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Import of log files AWS services write to S3, such as ALB access logs, CloudTrail and VPC
+//! flow logs.
+//!
+//! S3 event notifications for new objects are read from the SQS queue in `P_S3_IMPORT_QUEUE_URL`,
+//! sent there directly or through SNS. Objects are mapped to a stream and format by the bucket
+//! and prefix they are under, as set in `P_S3_IMPORT_MAPPINGS`, and read with the credentials of
+//! the s3 storage. A notification is deleted from the queue once all its objects are ingested, a
+//! failed one is received again after the visibility timeout of the queue and its objects are
+//! ingested from the start.
+
+mod formats;
+mod sqs;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use object_store::path::Path;
+use serde_json::Value;
+
+pub use self::formats::ImportFormat;
+use crate::handlers::http::ingest;
+use crate::option::CONFIG;
+use crate::shutdown;
+
+// events pushed at once from a single object
+const IMPORT_BATCH_SIZE: usize = 10_000;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Objects under a prefix of a bucket, imported into a stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportMapping {
+    pub bucket: String,
+    pub prefix: String,
+    pub stream: String,
+    pub format: ImportFormat,
+}
+
+impl FromStr for ImportMapping {
+    type Err = String;
+
+    // s3://<bucket>/<prefix>=<stream>:<format>
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{s} is not of the form s3://<bucket>/<prefix>=<stream>:<format>");
+        let (location, target) = s.trim().rsplit_once('=').ok_or_else(invalid)?;
+        let (stream, format) = target.split_once(':').ok_or_else(invalid)?;
+        let (bucket, prefix) = location
+            .strip_prefix("s3://")
+            .ok_or_else(invalid)?
+            .split_once('/')
+            .unwrap_or((location.trim_start_matches("s3://"), ""));
+        if bucket.is_empty() || stream.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            bucket: bucket.to_owned(),
+            prefix: prefix.to_owned(),
+            stream: stream.to_owned(),
+            format: format.parse()?,
+        })
+    }
+}
+
+/// Start importing the objects announced on the queue, if one is configured
+pub fn init() {
+    let Some(queue_url) = CONFIG.parseable.s3_import_queue_url.clone() else {
+        return;
+    };
+    let Some((region, credentials)) = CONFIG.storage().get_aws_credentials() else {
+        log::error!("S3 import needs s3 storage with an access key to read from the queue");
+        return;
+    };
+    let region = sqs::queue_region(&queue_url).unwrap_or(region);
+    let queue = sqs::Queue::new(queue_url, region, credentials);
+
+    log::info!("Importing objects announced on {}", queue.url());
+    tokio::spawn(run(queue));
+}
+
+async fn run(queue: sqs::Queue) {
+    let mut backoff = MIN_BACKOFF;
+    while !shutdown::is_draining() {
+        let messages = match queue.receive().await {
+            Ok(messages) => {
+                backoff = MIN_BACKOFF;
+                messages
+            }
+            Err(err) => {
+                log::warn!("could not receive s3 event notifications: {err}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                continue;
+            }
+        };
+
+        for message in messages {
+            if let Err(err) = import_notification(&message.body).await {
+                // left on the queue, it is received again once its visibility timeout runs out
+                log::warn!("could not import objects of s3 event notification: {err}");
+                continue;
+            }
+            if let Err(err) = queue.delete(&message.receipt_handle).await {
+                log::warn!("could not delete s3 event notification: {err}");
+            }
+        }
+    }
+}
+
+async fn import_notification(body: &str) -> anyhow::Result<()> {
+    for (bucket, key) in created_objects(body)? {
+        let Some(mapping) = mapping_for(&bucket, &key) else {
+            log::debug!("no import mapping for s3://{bucket}/{key}");
+            continue;
+        };
+        import_object(mapping, &key).await?;
+    }
+    Ok(())
+}
+
+fn mapping_for(bucket: &str, key: &str) -> Option<&'static ImportMapping> {
+    CONFIG
+        .parseable
+        .s3_import_mappings
+        .iter()
+        .find(|mapping| mapping.bucket == bucket && key.starts_with(&mapping.prefix))
+}
+
+async fn import_object(mapping: &ImportMapping, key: &str) -> anyhow::Result<()> {
+    let store = CONFIG
+        .storage()
+        .get_bucket_store(&mapping.bucket)
+        .ok_or_else(|| anyhow!("objects can only be imported with s3 storage"))?;
+    let data = store.get(&Path::from(key)).await?.bytes().await?;
+
+    // size of the whole object is accounted with the first batch
+    let mut size = data.len();
+    let format = mapping.format;
+    let events = tokio::task::spawn_blocking(move || formats::parse(format, &data)).await??;
+    let count = events.len();
+
+    let mut events = events.into_iter().peekable();
+    while events.peek().is_some() {
+        let batch: Vec<_> = events.by_ref().take(IMPORT_BATCH_SIZE).collect();
+        ingest::push_events(
+            &mapping.stream,
+            Value::Array(batch),
+            std::mem::take(&mut size),
+        )
+        .await?;
+    }
+
+    log::info!(
+        "imported {count} events of s3://{}/{key} into {}",
+        mapping.bucket,
+        mapping.stream
+    );
+    Ok(())
+}
+
+// bucket and key of the objects created, from a notification sent by S3 directly or through SNS.
+// The test event S3 sends when notifications are set up has no records.
+fn created_objects(body: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut notification: Value = serde_json::from_str(body)?;
+    if let Some(message) = notification.get("Message").and_then(Value::as_str) {
+        notification = serde_json::from_str(message)?;
+    }
+
+    let Some(records) = notification.get("Records").and_then(Value::as_array) else {
+        return Ok(Vec::new());
+    };
+    let objects = records
+        .iter()
+        .filter(|record| {
+            record
+                .get("eventName")
+                .and_then(Value::as_str)
+                .is_some_and(|name| name.starts_with("ObjectCreated:"))
+        })
+        .filter_map(|record| {
+            let bucket = record.pointer("/s3/bucket/name")?.as_str()?;
+            let key = record.pointer("/s3/object/key")?.as_str()?;
+            Some((bucket.to_owned(), decode_key(key)))
+        })
+        .collect();
+    Ok(objects)
+}
+
+// keys in notifications are form encoded, with spaces as '+'
+fn decode_key(key: &str) -> String {
+    url::form_urlencoded::parse(key.as_bytes())
+        .next()
+        .map(|(key, _)| key.into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{created_objects, ImportFormat, ImportMapping};
+
+    #[test]
+    fn mapping_is_parsed() {
+        let mapping: ImportMapping = "s3://logs/AWSLogs/year=2024/=alb_logs:alb".parse().unwrap();
+        assert_eq!(mapping.bucket, "logs");
+        assert_eq!(mapping.prefix, "AWSLogs/year=2024/");
+        assert_eq!(mapping.stream, "alb_logs");
+        assert_eq!(mapping.format, ImportFormat::Alb);
+
+        assert!("logs/AWSLogs=alb_logs:alb"
+            .parse::<ImportMapping>()
+            .is_err());
+        assert!("s3://logs/=alb_logs:xml".parse::<ImportMapping>().is_err());
+    }
+
+    #[test]
+    fn created_objects_are_read_through_sns() {
+        let event = json!({
+            "Records": [
+                {
+                    "eventName": "ObjectCreated:Put",
+                    "s3": { "bucket": { "name": "logs" }, "object": { "key": "alb/a+b%3D1.log.gz" } }
+                },
+                {
+                    "eventName": "ObjectRemoved:Delete",
+                    "s3": { "bucket": { "name": "logs" }, "object": { "key": "alb/old.log.gz" } }
+                }
+            ]
+        });
+        let body = json!({ "Type": "Notification", "Message": event.to_string() }).to_string();
+
+        assert_eq!(
+            created_objects(&body).unwrap(),
+            vec![("logs".to_owned(), "alb/a b=1.log.gz".to_owned())]
+        );
+        assert!(created_objects(r#"{"Event": "s3:TestEvent"}"#)
+            .unwrap()
+            .is_empty());
+    }
+}
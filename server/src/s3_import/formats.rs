@@ -0,0 +1,270 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde_json::{Map, Number, Value};
+
+use crate::handlers::http::cloudwatch::decompress;
+
+// fields of an ALB access log entry, in the order they are written
+const ALB_FIELDS: &[&str] = &[
+    "type",
+    "time",
+    "elb",
+    "client_port",
+    "target_port",
+    "request_processing_time",
+    "target_processing_time",
+    "response_processing_time",
+    "elb_status_code",
+    "target_status_code",
+    "received_bytes",
+    "sent_bytes",
+    "request",
+    "user_agent",
+    "ssl_cipher",
+    "ssl_protocol",
+    "target_group_arn",
+    "trace_id",
+    "domain_name",
+    "chosen_cert_arn",
+    "matched_rule_priority",
+    "request_creation_time",
+    "actions_executed",
+    "redirect_url",
+    "error_reason",
+    "target_port_list",
+    "target_status_code_list",
+    "classification",
+    "classification_reason",
+    "conn_trace_id",
+];
+const ALB_NUMERIC_FIELDS: &[&str] = &[
+    "request_processing_time",
+    "target_processing_time",
+    "response_processing_time",
+    "elb_status_code",
+    "target_status_code",
+    "received_bytes",
+    "sent_bytes",
+    "matched_rule_priority",
+];
+
+/// Format of the objects of an import mapping, which may be gzipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// access logs of application load balancers
+    Alb,
+    /// `{"Records": [..]}` files of CloudTrail
+    CloudTrail,
+    /// VPC flow logs, with the header line naming the fields
+    VpcFlow,
+    /// json array, object or newline delimited objects
+    Json,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alb" => Ok(Self::Alb),
+            "cloudtrail" => Ok(Self::CloudTrail),
+            "vpcflow" => Ok(Self::VpcFlow),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown format {s}, supported are alb, cloudtrail, vpcflow and json"
+            )),
+        }
+    }
+}
+
+/// Events of an object in the given format
+pub fn parse(format: ImportFormat, data: &[u8]) -> anyhow::Result<Vec<Value>> {
+    let data = decompress(data)?;
+    match format {
+        ImportFormat::Alb => Ok(lines(&data)?.map(alb_event).collect()),
+        ImportFormat::CloudTrail => cloudtrail_events(&data),
+        ImportFormat::VpcFlow => vpc_flow_events(&data),
+        ImportFormat::Json => json_events(&data),
+    }
+}
+
+fn lines(data: &[u8]) -> anyhow::Result<impl Iterator<Item = &str>> {
+    let text = std::str::from_utf8(data).map_err(|_| anyhow!("object is not utf-8 text"))?;
+    Ok(text.lines().filter(|line| !line.trim().is_empty()))
+}
+
+fn alb_event(line: &str) -> Value {
+    let event: Map<String, Value> = ALB_FIELDS
+        .iter()
+        .zip(split_quoted(line))
+        .map(|(&name, value)| {
+            let value = if ALB_NUMERIC_FIELDS.contains(&name) {
+                typed_value(value)
+            } else if value == "-" {
+                Value::Null
+            } else {
+                Value::String(value)
+            };
+            (name.to_owned(), value)
+        })
+        .collect();
+    Value::Object(event)
+}
+
+// fields separated by spaces, double quoted ones may contain spaces and escaped quotes
+fn split_quoted(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+            continue;
+        }
+
+        let mut field = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => field.extend(chars.next()),
+                    '"' => break,
+                    c => field.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ' ') {
+                field.push(c);
+            }
+        }
+        fields.push(field);
+    }
+    fields
+}
+
+// numbers are kept as such, '-' marks a missing value
+fn typed_value(value: String) -> Value {
+    if value == "-" {
+        return Value::Null;
+    }
+    if let Ok(number) = value.parse::<i64>() {
+        return Value::Number(number.into());
+    }
+    match value.parse::<f64>().ok().and_then(Number::from_f64) {
+        Some(number) => Value::Number(number),
+        None => Value::String(value),
+    }
+}
+
+// digest files of CloudTrail have no records and are skipped
+fn cloudtrail_events(data: &[u8]) -> anyhow::Result<Vec<Value>> {
+    let mut file: Value = serde_json::from_slice(data)?;
+    match file.get_mut("Records").map(Value::take) {
+        Some(Value::Array(records)) => Ok(records),
+        Some(_) => Err(anyhow!("Records of CloudTrail file is not an array")),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn vpc_flow_events(data: &[u8]) -> anyhow::Result<Vec<Value>> {
+    let mut lines = lines(data)?;
+    let header: Vec<_> = lines
+        .next()
+        .map(|header| {
+            header
+                .split_whitespace()
+                .map(|name| name.replace('-', "_"))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let events = lines
+        .map(|line| {
+            let event: Map<String, Value> = header
+                .iter()
+                .cloned()
+                .zip(
+                    line.split_whitespace()
+                        .map(|value| typed_value(value.to_owned())),
+                )
+                .collect();
+            Value::Object(event)
+        })
+        .collect();
+    Ok(events)
+}
+
+fn json_events(data: &[u8]) -> anyhow::Result<Vec<Value>> {
+    match serde_json::from_slice(data) {
+        Ok(Value::Array(events)) => Ok(events),
+        Ok(event) => Ok(vec![event]),
+        Err(_) => lines(data)?
+            .map(|line| serde_json::from_str(line).map_err(Into::into))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{parse, ImportFormat};
+
+    #[test]
+    fn alb_entry_is_parsed() {
+        let line = r#"https 2024-01-11T09:08:34.290318Z app/web/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 "GET https://www.example.com:443/ HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2 arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/web/73e2d6bc24d8a067 "Root=1-58337281-1d84f3d73c47ec4e58577259" "www.example.com" "-" 1 2024-01-11T09:08:34.290000Z "forward" "-" "-" "10.0.0.1:80" "200" "-" "-""#;
+
+        let events = parse(ImportFormat::Alb, line.as_bytes()).unwrap();
+
+        assert_eq!(
+            events[0]["request"],
+            "GET https://www.example.com:443/ HTTP/1.1"
+        );
+        assert_eq!(events[0]["elb_status_code"], 200);
+        assert_eq!(events[0]["target_processing_time"], json!(0.001));
+        assert_eq!(events[0]["chosen_cert_arn"], json!(null));
+        assert_eq!(events[0]["actions_executed"], "forward");
+    }
+
+    #[test]
+    fn vpc_flow_log_is_parsed_by_its_header() {
+        let data = "version account-id srcaddr dstport action\n2 123456789010 172.31.16.139 22 ACCEPT\n2 123456789010 172.31.16.21 - REJECT\n";
+
+        let events = parse(ImportFormat::VpcFlow, data.as_bytes()).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["account_id"], 123456789010u64);
+        assert_eq!(events[0]["srcaddr"], "172.31.16.139");
+        assert_eq!(events[1]["dstport"], json!(null));
+    }
+
+    #[test]
+    fn cloudtrail_records_are_events() {
+        let data = json!({ "Records": [{ "eventName": "ConsoleLogin" }] }).to_string();
+        let events = parse(ImportFormat::CloudTrail, data.as_bytes()).unwrap();
+        assert_eq!(events, vec![json!({ "eventName": "ConsoleLogin" })]);
+
+        let digest = json!({ "digestStartTime": "2024-01-11T09:00:00Z" }).to_string();
+        assert!(parse(ImportFormat::CloudTrail, digest.as_bytes())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Client for the SQS queue notifications are read from, using the json protocol of SQS.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use bytes::Bytes;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::tls;
+use crate::utils::sigv4::{self, Credentials};
+
+// messages are long polled, the request has to outlive the wait
+const MAX_MESSAGES: usize = 10;
+const WAIT_TIME_SECS: u64 = 20;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(WAIT_TIME_SECS + 10);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Message {
+    pub receipt_handle: String,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReceiveMessageResult {
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+pub struct Queue {
+    url: Url,
+    endpoint: Url,
+    region: String,
+    credentials: Credentials,
+    client: reqwest::Client,
+}
+
+/// Region of a queue url of the form https://sqs.<region>.amazonaws.com/<account>/<queue>
+pub fn queue_region(url: &Url) -> Option<String> {
+    let mut labels = url.host_str()?.split('.');
+    match (labels.next(), labels.next()) {
+        (Some("sqs"), Some(region)) => Some(region.to_owned()),
+        _ => None,
+    }
+}
+
+impl Queue {
+    pub fn new(url: Url, region: String, credentials: Credentials) -> Self {
+        // requests of the json protocol go to the root of the host of the queue
+        let mut endpoint = url.clone();
+        endpoint.set_path("/");
+        endpoint.set_query(None);
+
+        Self {
+            url,
+            endpoint,
+            region,
+            credentials,
+            client: tls::client_builder(None)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("client can be built on this system"),
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Wait for the next messages on the queue
+    pub async fn receive(&self) -> anyhow::Result<Vec<Message>> {
+        let response = self
+            .call(
+                "ReceiveMessage",
+                json!({
+                    "QueueUrl": self.url.as_str(),
+                    "MaxNumberOfMessages": MAX_MESSAGES,
+                    "WaitTimeSeconds": WAIT_TIME_SECS,
+                }),
+            )
+            .await?;
+        let result: ReceiveMessageResult = serde_json::from_slice(&response)?;
+        Ok(result.messages)
+    }
+
+    pub async fn delete(&self, receipt_handle: &str) -> anyhow::Result<()> {
+        self.call(
+            "DeleteMessage",
+            json!({
+                "QueueUrl": self.url.as_str(),
+                "ReceiptHandle": receipt_handle,
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn call(&self, action: &str, body: Value) -> anyhow::Result<Bytes> {
+        let mut request = self
+            .client
+            .post(self.endpoint.clone())
+            .header("x-amz-target", format!("AmazonSQS.{action}"))
+            .header(reqwest::header::CONTENT_TYPE, "application/x-amz-json-1.0")
+            .body(serde_json::to_vec(&body)?)
+            .build()?;
+        sigv4::sign(&mut request, &self.credentials, &self.region, "sqs");
+
+        let response = self.client.execute(request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("{action} failed with {status}: {}", response.text().await?);
+        }
+        Ok(response.bytes().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::queue_region;
+
+    #[test]
+    fn region_is_read_from_queue_url() {
+        let url = Url::parse("https://sqs.eu-west-1.amazonaws.com/123456789012/s3-events").unwrap();
+        assert_eq!(queue_region(&url).as_deref(), Some("eu-west-1"));
+        assert_eq!(
+            queue_region(&Url::parse("http://localhost:9324/q").unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,379 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Versions of the schemas of log streams. A version is recorded every time events add
+//! fields to a stream, each server records the versions it sees in an object of its own and
+//! they are merged when read. Streams are tracked from the first change of their schema.
+//!
+//! The compatibility setting of a stream decides which changes events may make:
+//! - `backward`: queries of the new schema can read older events, added fields are nullable
+//! - `forward`: queries of the old schema can read newer events, removed fields were nullable
+//! - `full`: both
+//!
+//! Fields never change their type, whatever the setting.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use arrow_schema::{DataType, Field, Fields, Schema};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use relative_path::RelativePathBuf;
+use tokio::sync::Mutex;
+
+use crate::handlers::http::modal::ingest_server::get_ingester_id;
+use crate::metadata::STREAM_INFO;
+use crate::option::{Mode, CONFIG};
+use crate::storage::ObjectStorageError;
+
+const SCHEMA_VERSIONS_DIRECTORY: &str = ".schema_versions";
+// name the query server, or a standalone one, records its versions under
+const SERVER_NODE: &str = "server";
+
+/// Changes of the schema of a stream which are accepted at ingest
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Compatibility {
+    /// fields are added as events have them
+    #[default]
+    None,
+    Backward,
+    Forward,
+    Full,
+}
+
+impl Compatibility {
+    pub fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+
+    fn backward(self) -> bool {
+        matches!(self, Self::Backward | Self::Full)
+    }
+
+    fn forward(self) -> bool {
+        matches!(self, Self::Forward | Self::Full)
+    }
+
+    /// Check the evolution from `old` to `new`
+    pub fn check(self, old: &Schema, new: &Schema) -> Result<(), IncompatibleSchema> {
+        if self.is_none() {
+            return Ok(());
+        }
+        for field in new.fields() {
+            match old.field_with_name(field.name()) {
+                Ok(old_field) if old_field.data_type() != field.data_type() => {
+                    return Err(IncompatibleSchema::TypeChanged(
+                        field.name().clone(),
+                        old_field.data_type().clone(),
+                        field.data_type().clone(),
+                    ))
+                }
+                Err(_) if self.backward() && !field.is_nullable() => {
+                    return Err(IncompatibleSchema::RequiredFieldAdded(field.name().clone()))
+                }
+                _ => (),
+            }
+        }
+        if self.forward() {
+            if let Some(field) = old
+                .fields()
+                .iter()
+                .find(|field| !field.is_nullable() && new.field_with_name(field.name()).is_err())
+            {
+                return Err(IncompatibleSchema::RequiredFieldRemoved(
+                    field.name().clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the schema of a stream with the fields of an event, fields of the event replace
+    /// those of the same name
+    pub fn check_event(
+        self,
+        stream_schema: &HashMap<String, Arc<Field>>,
+        event_schema: &Schema,
+    ) -> Result<(), IncompatibleSchema> {
+        // the first event of a stream sets its schema
+        if self.is_none() || stream_schema.is_empty() {
+            return Ok(());
+        }
+        let old = Schema::new(stream_schema.values().cloned().collect::<Fields>());
+        let mut fields: Vec<Arc<Field>> = stream_schema
+            .values()
+            .filter(|field| event_schema.field_with_name(field.name()).is_err())
+            .cloned()
+            .collect();
+        fields.extend(event_schema.fields().iter().cloned());
+        self.check(&old, &Schema::new(fields))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IncompatibleSchema {
+    #[error("field {0} would change its type from {1} to {2}")]
+    TypeChanged(String, DataType, DataType),
+    #[error("field {0} is not nullable, adding it is not backward compatible")]
+    RequiredFieldAdded(String),
+    #[error("field {0} is not nullable, removing it is not forward compatible")]
+    RequiredFieldRemoved(String),
+}
+
+/// A version of the schema of a stream
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SchemaVersion {
+    pub version: usize,
+    pub created_at: DateTime<Utc>,
+    /// fields which are new in this version
+    pub added: Vec<String>,
+    pub schema: Schema,
+}
+
+// schema of a stream as a server saw it, in the object of the server
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordedSchema {
+    created_at: DateTime<Utc>,
+    schema: Schema,
+}
+
+// schemas recorded by this server, by stream, loaded from storage on first use
+static RECORDED: Lazy<Mutex<HashMap<String, Vec<RecordedSchema>>>> = Lazy::new(Mutex::default);
+
+// kept apart from the stream metadata, whose listings expect only the files of the stream
+fn versions_directory(stream_name: &str) -> RelativePathBuf {
+    RelativePathBuf::from_iter([stream_name, SCHEMA_VERSIONS_DIRECTORY])
+}
+
+// versions recorded by a node, by the id of the ingester or as the server
+fn versions_path(stream_name: &str, node: &str) -> RelativePathBuf {
+    versions_directory(stream_name).join(format!("{node}.json"))
+}
+
+fn own_node() -> Result<String, ObjectStorageError> {
+    match CONFIG.parseable.mode {
+        Mode::Ingest => Ok(get_ingester_id()?),
+        Mode::All | Mode::Query => Ok(SERVER_NODE.to_owned()),
+    }
+}
+
+/// Record the current schema of the stream if it has fields the last recorded one does not
+/// have. Failing to record is logged, the events were ingested regardless.
+pub async fn record_version(stream_name: &str) {
+    if let Err(err) = try_record_version(stream_name).await {
+        log::warn!("could not record the schema version of log stream {stream_name}: {err}");
+    }
+}
+
+async fn try_record_version(stream_name: &str) -> Result<(), ObjectStorageError> {
+    let schema = STREAM_INFO
+        .schema(stream_name)
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    let store = CONFIG.storage().get_object_store();
+    let path = versions_path(stream_name, &own_node()?);
+
+    let mut recorded = RECORDED.lock().await;
+    if !recorded.contains_key(stream_name) {
+        let versions = match store.get_object(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?,
+            Err(ObjectStorageError::NoSuchKey(_)) => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        recorded.insert(stream_name.to_owned(), versions);
+    }
+    let versions = recorded
+        .get_mut(stream_name)
+        .expect("versions are loaded above");
+
+    let grown = versions.last().map_or(true, |last| {
+        schema
+            .fields()
+            .iter()
+            .any(|field| last.schema.field_with_name(field.name()).is_err())
+    });
+    if !grown {
+        return Ok(());
+    }
+    versions.push(RecordedSchema {
+        created_at: Utc::now(),
+        schema: schema.as_ref().clone(),
+    });
+    let bytes = serde_json::to_vec(versions).expect("schemas can be serialized");
+    store.put_object(&path, bytes.into()).await
+}
+
+/// Versions of the schema of a stream, as recorded by every server
+pub async fn versions(stream_name: &str) -> Result<Vec<SchemaVersion>, ObjectStorageError> {
+    let store = CONFIG.storage().get_object_store();
+    let objects = store
+        .get_objects(
+            Some(&versions_directory(stream_name)),
+            Box::new(|file_name| file_name.ends_with(".json")),
+        )
+        .await;
+    let objects = match objects {
+        Ok(objects) => objects,
+        // listing a prefix which was never written to fails on local storage
+        Err(ObjectStorageError::IoError(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+    let recorded = objects
+        .iter()
+        .map(|bytes| serde_json::from_slice::<Vec<RecordedSchema>>(bytes))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+    Ok(merge_versions(recorded.into_iter().flatten().collect()))
+}
+
+// In the order they were recorded, every schema which adds fields to those before it is a
+// version. Fields keep the type they were first recorded with.
+fn merge_versions(mut recorded: Vec<RecordedSchema>) -> Vec<SchemaVersion> {
+    recorded.sort_by_key(|recorded| recorded.created_at);
+    let mut fields: BTreeMap<String, Arc<Field>> = BTreeMap::new();
+    let mut versions = Vec::new();
+    for RecordedSchema { created_at, schema } in recorded {
+        let mut added = Vec::new();
+        for field in schema.fields() {
+            if !fields.contains_key(field.name()) {
+                fields.insert(field.name().clone(), field.clone());
+                added.push(field.name().clone());
+            }
+        }
+        if added.is_empty() {
+            continue;
+        }
+        versions.push(SchemaVersion {
+            version: versions.len() + 1,
+            created_at,
+            added,
+            schema: Schema::new(fields.values().cloned().collect::<Fields>()),
+        });
+    }
+    versions
+}
+
+/// Schemas recorded of a deleted stream are dropped, a stream created with its name starts over
+pub async fn forget(stream_name: &str) {
+    RECORDED.lock().await.remove(stream_name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use arrow_schema::{DataType, Field, Schema};
+    use chrono::{TimeZone, Utc};
+
+    use super::{merge_versions, versions_path, Compatibility, IncompatibleSchema, RecordedSchema};
+
+    fn schema(fields: &[(&str, DataType, bool)]) -> Schema {
+        Schema::new(
+            fields
+                .iter()
+                .map(|(name, data_type, nullable)| Field::new(*name, data_type.clone(), *nullable))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn compatibility_rules() {
+        let old = schema(&[("a", DataType::Utf8, false), ("b", DataType::Int64, true)]);
+        let required_added = schema(&[
+            ("a", DataType::Utf8, false),
+            ("b", DataType::Int64, true),
+            ("c", DataType::Utf8, false),
+        ]);
+        let required_removed = schema(&[("b", DataType::Int64, true)]);
+
+        assert!(Compatibility::Backward
+            .check(&old, &required_removed)
+            .is_ok());
+        assert!(matches!(
+            Compatibility::Backward.check(&old, &required_added),
+            Err(IncompatibleSchema::RequiredFieldAdded(name)) if name == "c"
+        ));
+        assert!(Compatibility::Forward.check(&old, &required_added).is_ok());
+        assert!(matches!(
+            Compatibility::Forward.check(&old, &required_removed),
+            Err(IncompatibleSchema::RequiredFieldRemoved(name)) if name == "a"
+        ));
+        assert!(Compatibility::None.check(&old, &required_added).is_ok());
+
+        let stream: HashMap<String, Arc<Field>> = old
+            .fields()
+            .iter()
+            .map(|field| (field.name().clone(), field.clone()))
+            .collect();
+        let retyped = schema(&[("b", DataType::Utf8, true)]);
+        assert!(matches!(
+            Compatibility::Full.check_event(&stream, &retyped),
+            Err(IncompatibleSchema::TypeChanged(..))
+        ));
+        let added = schema(&[("c", DataType::Utf8, true)]);
+        assert!(Compatibility::Full.check_event(&stream, &added).is_ok());
+    }
+
+    #[test]
+    fn versions_of_all_servers_are_merged() {
+        let recorded = |secs, fields: &[&str]| RecordedSchema {
+            created_at: Utc.timestamp_opt(secs, 0).unwrap(),
+            schema: schema(
+                &fields
+                    .iter()
+                    .map(|name| (*name, DataType::Utf8, true))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        let versions = merge_versions(vec![
+            recorded(30, &["a", "b", "c"]),
+            recorded(10, &["a"]),
+            recorded(20, &["a", "b"]),
+            recorded(25, &["a", "b"]),
+        ]);
+
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[1].version, 2);
+        assert_eq!(versions[1].added, ["b"]);
+        assert_eq!(versions[2].schema.fields().len(), 3);
+    }
+
+    #[test]
+    fn versions_are_kept_apart_from_the_stream_metadata() {
+        assert_eq!(
+            versions_path("app", "01HQ").as_str(),
+            "app/.schema_versions/01HQ.json"
+        );
+    }
+}
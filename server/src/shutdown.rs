@@ -0,0 +1,214 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Graceful shutdown of the servers which stage events.
+//!
+//! On SIGTERM or SIGINT events are no longer accepted and readiness fails, requests in flight are
+//! given time to finish and staging is flushed to object storage before the process exits. The
+//! whole drain is bounded by `P_DRAIN_TIMEOUT`, staging data left behind is uploaded on the next
+//! start.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::ServerHandle;
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+
+use crate::event::STREAM_WRITERS;
+use crate::metadata::STREAM_INFO;
+use crate::monitor::{self, ServerEvent};
+use crate::option::CONFIG;
+use crate::{alerts, metering, replication};
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+const IN_FLIGHT_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+// requests in flight, by kind
+static IN_FLIGHT: [AtomicUsize; 4] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+// end of the drain, set when the shutdown signal is received
+static DEADLINE: OnceCell<Instant> = OnceCell::new();
+
+/// Whether the server is shutting down and should no longer accept events
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Acquire)
+}
+
+/// Kinds of requests told apart when counting the requests in flight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+    Ingest,
+    Query,
+    Cluster,
+    Other,
+}
+
+impl RequestKind {
+    const ALL: [RequestKind; 4] = [
+        RequestKind::Ingest,
+        RequestKind::Query,
+        RequestKind::Cluster,
+        RequestKind::Other,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            RequestKind::Ingest => "ingest",
+            RequestKind::Query => "query",
+            RequestKind::Cluster => "cluster",
+            RequestKind::Other => "other",
+        }
+    }
+}
+
+/// A request in flight, counted till it is dropped
+pub struct InFlight(RequestKind);
+
+impl InFlight {
+    pub fn start(kind: RequestKind) -> Self {
+        IN_FLIGHT[kind as usize].fetch_add(1, Ordering::AcqRel);
+        InFlight(kind)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT[self.0 as usize].fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Number of requests in flight of each kind
+pub fn in_flight() -> Vec<(RequestKind, usize)> {
+    RequestKind::ALL
+        .into_iter()
+        .map(|kind| (kind, IN_FLIGHT[kind as usize].load(Ordering::Acquire)))
+        .collect()
+}
+
+fn describe_in_flight(in_flight: &[(RequestKind, usize)]) -> String {
+    in_flight
+        .iter()
+        .map(|(kind, count)| format!("{} {count}", kind.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn remaining() -> Duration {
+    DEADLINE
+        .get()
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .unwrap_or(CONFIG.parseable.drain_timeout)
+}
+
+/// Start draining on SIGTERM or SIGINT and stop the http server gracefully. The http server
+/// should be built with signals disabled and the drain timeout as its shutdown timeout.
+pub fn init_signal_handler(server: ServerHandle) {
+    tokio::spawn(async move {
+        let signal = wait_for_signal().await;
+        log::info!(
+            "received {signal}, draining for at most {}",
+            humantime::format_duration(CONFIG.parseable.drain_timeout)
+        );
+        let requests = in_flight();
+        log::info!("requests in flight: {}", describe_in_flight(&requests));
+        monitor::record(ServerEvent::Shutdown {
+            signal: signal.to_owned(),
+            in_flight: requests.iter().map(|(_, count)| count).sum(),
+        });
+        let _ = DEADLINE.set(Instant::now() + CONFIG.parseable.drain_timeout);
+        DRAINING.store(true, Ordering::Release);
+
+        // report the requests left every few seconds till they finished
+        let progress = tokio::spawn(async {
+            loop {
+                tokio::time::sleep(IN_FLIGHT_REPORT_INTERVAL).await;
+                let requests = in_flight();
+                if requests.iter().all(|(_, count)| *count == 0) {
+                    break;
+                }
+                log::info!(
+                    "waiting for requests in flight: {}",
+                    describe_in_flight(&requests)
+                );
+            }
+        });
+        server.stop(true).await;
+        progress.abort();
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::warn!(
+                "could not listen for SIGTERM, only SIGINT starts a graceful shutdown: {err}"
+            );
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+/// Upload everything in staging, including the files of the current minute which the regular
+/// sync leaves to be written to. Returns whether staging was flushed completely.
+pub async fn flush_staging() -> bool {
+    // usage, server events and alert evaluations are written as events of internal streams, so
+    // they go to staging first
+    metering::flush().await;
+    monitor::flush().await;
+    alerts::history::flush().await;
+    STREAM_WRITERS.unset_all();
+
+    let timeout = remaining();
+    let started = Utc::now();
+    let store = CONFIG.storage().get_object_store();
+    match tokio::time::timeout(timeout, store.sync()).await {
+        Ok(Ok(())) => {
+            log::info!("staging flushed to object storage");
+            // the current minute is flushed as well, peers can drop every copy taken before
+            replication::trim_peers(&STREAM_INFO.list_streams(), started).await;
+            true
+        }
+        Ok(Err(err)) => {
+            log::error!("could not flush staging, it is uploaded on the next start: {err}");
+            false
+        }
+        Err(_) => {
+            log::error!("drain timeout elapsed before staging was flushed, it is uploaded on the next start");
+            false
+        }
+    }
+}
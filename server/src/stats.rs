@@ -64,6 +64,24 @@ pub fn delete_stats(stream_name: &str, format: &'static str) -> prometheus::Resu
     Ok(())
 }
 
+/// Subtract the events and sizes of data removed from the stream, e.g. by a purge
+pub fn remove_stats(stream_name: &str, format: &'static str, removed: Stats) {
+    let event_labels = event_labels(stream_name, format);
+    let storage_size_labels = storage_size_labels(stream_name);
+
+    // the counter can only be reset, it is set again to the events left
+    let events = EVENTS_INGESTED.with_label_values(&event_labels);
+    let remaining = events.get().saturating_sub(removed.events);
+    events.reset();
+    events.inc_by(remaining);
+    EVENTS_INGESTED_SIZE
+        .with_label_values(&event_labels)
+        .sub(removed.ingestion as i64);
+    STORAGE_SIZE
+        .with_label_values(&storage_size_labels)
+        .sub(removed.storage as i64);
+}
+
 fn event_labels<'a>(stream_name: &'a str, format: &'static str) -> [&'a str; 2] {
     [stream_name, format]
 }
@@ -16,29 +16,46 @@
  *
  */
 
-use crate::{catalog::snapshot::Snapshot, stats::Stats};
+use crate::schema_registry::Compatibility;
+use crate::{catalog::snapshot::Snapshot, stats::Stats, utils::json};
 
-use chrono::Local;
+use chrono::{DateTime, Local, Utc};
+use serde_json::Value;
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::time::Duration;
 
+pub mod archive;
+pub mod compression;
+pub mod consistency;
+pub mod iceberg;
 mod localfs;
 mod metrics_layer;
 pub(crate) mod object_storage;
+pub mod purge;
+pub mod quirks;
+mod read_layer;
 pub mod retention;
 mod s3;
 pub mod staging;
 mod store_metadata;
+mod throttle;
 
 pub use localfs::FSConfig;
 pub use object_storage::{ObjectStorage, ObjectStorageProvider};
+pub use read_layer::ReadLayerRegistry;
 pub use s3::S3Config;
 pub use store_metadata::{
     put_remote_metadata, put_staging_metadata, resolve_parseable_metadata, StorageMetadata,
+    CURRENT_STORAGE_METADATA_VERSION,
 };
 
+use self::archive::Archive;
 use self::retention::Retention;
 pub use self::staging::StorageDir;
+// listed as they are read by the client
+pub use parseable_client::types::LogStream;
 
 // metadata file names in a Stream prefix
 pub const STREAM_METADATA_FILE_NAME: &str = ".stream.json";
@@ -48,6 +65,7 @@ pub const PARSEABLE_ROOT_DIRECTORY: &str = ".parseable";
 pub const SCHEMA_FILE_NAME: &str = ".schema";
 pub const ALERT_FILE_NAME: &str = ".alert.json";
 pub const MANIFEST_FILE: &str = "manifest.json";
+pub const SHARD_MAP_FILE_NAME: &str = "stream_shards.json";
 
 /// local sync interval to move data.records to /tmp dir of that stream.
 /// 60 sec is a reasonable value.
@@ -70,7 +88,7 @@ const ACCESS_ALL: &str = "all";
 pub const CURRENT_OBJECT_STORE_VERSION: &str = "v3";
 pub const CURRENT_SCHEMA_VERSION: &str = "v3";
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct ObjectStoreFormat {
     /// Version of schema registry
     pub version: String,
@@ -93,11 +111,132 @@ pub struct ObjectStoreFormat {
     pub retention: Option<Retention>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub time_partition: Option<String>,
+    #[serde(
+        rename = "custom-partition",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub custom_partition: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub static_schema_flag: Option<String>,
+    #[serde(
+        rename = "flush-interval",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    pub flush_interval: Option<Duration>,
+    #[serde(rename = "sort-keys", default, skip_serializing_if = "Vec::is_empty")]
+    pub sort_keys: Vec<SortKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<Archive>,
+    #[serde(
+        rename = "legal-hold",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub legal_hold: Option<LegalHold>,
+    #[serde(rename = "iceberg-export", default)]
+    pub iceberg_export: bool,
+    #[serde(
+        rename = "field-mapping",
+        default,
+        skip_serializing_if = "FieldMapping::is_none"
+    )]
+    pub field_mapping: FieldMapping,
+    /// names of the tags events can be sent with in the `X-P-Tags` header
+    #[serde(
+        rename = "allowed-tags",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allowed_tags: Vec<String>,
+    /// columns the schema can grow to, new fields beyond are packed into the `_extra` column
+    #[serde(
+        rename = "max-columns",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_columns: Option<usize>,
+    /// changes of the schema events are allowed to make
+    #[serde(
+        rename = "schema-compatibility",
+        default,
+        skip_serializing_if = "Compatibility::is_none"
+    )]
+    pub schema_compatibility: Compatibility,
+    /// current names of renamed columns by their old names, queries of the old names are
+    /// rewritten to them
+    #[serde(
+        rename = "renamed-columns",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub renamed_columns: BTreeMap<String, String>,
+    /// predicate queries of the stream are filtered with unless they ask for raw events
+    #[serde(
+        rename = "default-filter",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_filter: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+/// Column the rows of a parquet file are sorted by when staging is converted
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct SortKey {
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+/// Names and types incoming fields of a stream are mapped to
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldMapping {
+    /// fields are kept as they are sent
+    #[default]
+    None,
+    /// fields are mapped to the Elastic Common Schema
+    Ecs,
+}
+
+impl FieldMapping {
+    pub fn is_none(&self) -> bool {
+        *self == FieldMapping::None
+    }
+
+    /// Fields of an event, or of an array of events, in this mapping
+    pub fn apply(self, value: Value) -> Value {
+        match self {
+            FieldMapping::None => value,
+            FieldMapping::Ecs => json::ecs::normalize(value),
+        }
+    }
+}
+
+/// While a legal hold is placed, data of the stream can not be deleted or rewritten
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct LegalHold {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(rename = "placed-by")]
+    pub placed_by: String,
+    #[serde(rename = "placed-at")]
+    pub placed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
 pub struct StreamInfo {
     #[serde(rename = "created-at")]
     pub created_at: String,
@@ -108,8 +247,67 @@ pub struct StreamInfo {
     pub cache_enabled: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub time_partition: Option<String>,
+    #[serde(
+        rename = "custom-partition",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub custom_partition: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub static_schema_flag: Option<String>,
+    #[serde(
+        rename = "flush-interval",
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    #[schema(value_type = Option<String>, example = "1m")]
+    pub flush_interval: Option<Duration>,
+    #[serde(rename = "sort-keys", default, skip_serializing_if = "Vec::is_empty")]
+    pub sort_keys: Vec<SortKey>,
+    #[serde(rename = "legal-hold", skip_serializing_if = "Option::is_none")]
+    pub legal_hold: Option<LegalHold>,
+    #[serde(
+        rename = "field-mapping",
+        default,
+        skip_serializing_if = "FieldMapping::is_none"
+    )]
+    pub field_mapping: FieldMapping,
+    /// names of the tags events can be sent with in the `X-P-Tags` header
+    #[serde(
+        rename = "allowed-tags",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allowed_tags: Vec<String>,
+    /// columns the schema can grow to, new fields beyond are packed into the `_extra` column
+    #[serde(
+        rename = "max-columns",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_columns: Option<usize>,
+    /// changes of the schema events are allowed to make
+    #[serde(
+        rename = "schema-compatibility",
+        default,
+        skip_serializing_if = "Compatibility::is_none"
+    )]
+    pub schema_compatibility: Compatibility,
+    /// current names of renamed columns by their old names, queries of the old names are
+    /// rewritten to them
+    #[serde(
+        rename = "renamed-columns",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub renamed_columns: BTreeMap<String, String>,
+    /// predicate queries of the stream are filtered with unless they ask for raw events
+    #[serde(
+        rename = "default-filter",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_filter: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -155,7 +353,19 @@ impl Default for ObjectStoreFormat {
             cache_enabled: false,
             retention: None,
             time_partition: None,
+            custom_partition: None,
             static_schema_flag: None,
+            flush_interval: None,
+            sort_keys: Vec::new(),
+            archive: None,
+            legal_hold: None,
+            iceberg_export: false,
+            field_mapping: FieldMapping::None,
+            allowed_tags: Vec::new(),
+            max_columns: None,
+            schema_compatibility: Compatibility::None,
+            renamed_columns: BTreeMap::new(),
+            default_filter: None,
         }
     }
 }
@@ -167,9 +377,100 @@ impl ObjectStoreFormat {
     }
 }
 
-#[derive(serde::Serialize, PartialEq)]
-pub struct LogStream {
-    pub name: String,
+/// Settings of a stream every ingester keeps in its own copy of the stream metadata.
+/// Streams have no ingestion pipelines in this version, so there are none to propagate.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StreamSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    #[serde(default)]
+    pub cache_enabled: bool,
+    #[serde(
+        rename = "flush-interval",
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "humantime_serde"
+    )]
+    pub flush_interval: Option<Duration>,
+    #[serde(rename = "sort-keys", default, skip_serializing_if = "Vec::is_empty")]
+    pub sort_keys: Vec<SortKey>,
+    #[serde(
+        rename = "field-mapping",
+        default,
+        skip_serializing_if = "FieldMapping::is_none"
+    )]
+    pub field_mapping: FieldMapping,
+    /// names of the tags events can be sent with in the `X-P-Tags` header
+    #[serde(
+        rename = "allowed-tags",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub allowed_tags: Vec<String>,
+    /// columns the schema can grow to, new fields beyond are packed into the `_extra` column
+    #[serde(
+        rename = "max-columns",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_columns: Option<usize>,
+    /// changes of the schema events are allowed to make
+    #[serde(
+        rename = "schema-compatibility",
+        default,
+        skip_serializing_if = "Compatibility::is_none"
+    )]
+    pub schema_compatibility: Compatibility,
+    /// current names of renamed columns by their old names, queries of the old names are
+    /// rewritten to them
+    #[serde(
+        rename = "renamed-columns",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub renamed_columns: BTreeMap<String, String>,
+    /// predicate queries of the stream are filtered with unless they ask for raw events
+    #[serde(
+        rename = "default-filter",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default_filter: Option<String>,
+}
+
+impl StreamSettings {
+    pub fn of(meta: &ObjectStoreFormat) -> Self {
+        Self {
+            retention: meta.retention.clone(),
+            cache_enabled: meta.cache_enabled,
+            flush_interval: meta.flush_interval,
+            sort_keys: meta.sort_keys.clone(),
+            field_mapping: meta.field_mapping,
+            allowed_tags: meta.allowed_tags.clone(),
+            max_columns: meta.max_columns,
+            schema_compatibility: meta.schema_compatibility,
+            renamed_columns: meta.renamed_columns.clone(),
+            default_filter: meta.default_filter.clone(),
+        }
+    }
+
+    /// Overwrite the settings in `meta`, returns whether any of them changed
+    pub fn apply_to(&self, meta: &mut ObjectStoreFormat) -> bool {
+        if *self == Self::of(meta) {
+            return false;
+        }
+        meta.retention.clone_from(&self.retention);
+        meta.cache_enabled = self.cache_enabled;
+        meta.flush_interval = self.flush_interval;
+        meta.sort_keys.clone_from(&self.sort_keys);
+        meta.field_mapping = self.field_mapping;
+        meta.allowed_tags.clone_from(&self.allowed_tags);
+        meta.max_columns = self.max_columns;
+        meta.schema_compatibility = self.schema_compatibility;
+        meta.renamed_columns.clone_from(&self.renamed_columns);
+        meta.default_filter.clone_from(&self.default_filter);
+        true
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -203,3 +504,52 @@ pub enum ObjectStorageError {
     #[error("Authentication Error: {0}")]
     AuthenticationError(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::{ObjectStoreFormat, StreamSettings};
+
+    #[test]
+    fn settings_are_applied_to_the_copy_of_an_ingester() {
+        let settings: StreamSettings = serde_json::from_value(json!({
+            "retention": [{"description": "cleanup", "action": "delete", "duration": "30d"}],
+            "cache_enabled": true,
+            "flush-interval": "30s",
+            "allowed-tags": ["env"]
+        }))
+        .unwrap();
+        assert_eq!(settings.flush_interval, Some(Duration::from_secs(30)));
+
+        let mut meta = ObjectStoreFormat::default();
+        assert!(settings.apply_to(&mut meta));
+        assert_eq!(StreamSettings::of(&meta), settings);
+        assert_eq!(
+            meta.retention.as_ref().unwrap().delete_after_days(),
+            Some(30)
+        );
+        assert!(meta.cache_enabled);
+        assert_eq!(meta.allowed_tags, vec!["env".to_owned()]);
+
+        // nothing to write when the copy is up to date
+        assert!(!settings.apply_to(&mut meta));
+    }
+
+    #[test]
+    fn settings_cleared_on_the_query_server_are_cleared_on_ingesters() {
+        let mut meta = ObjectStoreFormat {
+            cache_enabled: true,
+            flush_interval: Some(Duration::from_secs(30)),
+            max_columns: Some(10),
+            ..ObjectStoreFormat::default()
+        };
+
+        assert!(StreamSettings::default().apply_to(&mut meta));
+        assert!(!meta.cache_enabled);
+        assert_eq!(meta.flush_interval, None);
+        assert_eq!(meta.max_columns, None);
+    }
+}
@@ -0,0 +1,205 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use object_store::{path::Path, ObjectStore};
+use relative_path::RelativePath;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::option::CONFIG;
+
+const ARCHIVE_REPORT_FILE_NAME: &str = ".archive-report.json";
+
+/// Destination where the data of a stream is copied to before the stream is deleted
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Archive {
+    /// url of the bucket and prefix, e.g. s3://archive-bucket/parseable
+    pub destination: String,
+}
+
+// schemes of destinations in the kind of object store the server runs on, data is not archived
+// to the local disk of the server or to stores it was not configured for
+fn allowed_schemes(storage_name: &str) -> &'static [&'static str] {
+    match storage_name {
+        "s3" => &["s3", "s3a"],
+        _ => &[],
+    }
+}
+
+fn check_scheme(url: &Url, storage_name: &str) -> Result<(), ArchiveError> {
+    let allowed = allowed_schemes(storage_name);
+    if allowed.contains(&url.scheme()) {
+        return Ok(());
+    }
+    let reason = if allowed.is_empty() {
+        format!("streams cannot be archived with {storage_name} storage")
+    } else {
+        format!(
+            "scheme {} is not allowed, use one of {}",
+            url.scheme(),
+            allowed.join(", ")
+        )
+    };
+    Err(ArchiveError::InvalidDestination(reason))
+}
+
+impl Archive {
+    // credentials for the destination are read from the environment (AWS_*)
+    fn object_store(&self) -> Result<(Box<dyn ObjectStore>, Path), ArchiveError> {
+        let url = Url::parse(&self.destination)
+            .map_err(|err| ArchiveError::InvalidDestination(err.to_string()))?;
+        check_scheme(&url, CONFIG.storage_name)?;
+        let options = std::env::vars()
+            .filter(|(key, _)| key.starts_with("AWS_"))
+            .map(|(key, value)| (key.to_ascii_lowercase(), value));
+
+        object_store::parse_url_opts(&url, options)
+            .map_err(|err| ArchiveError::InvalidDestination(err.to_string()))
+    }
+
+    pub fn validate(&self) -> Result<(), ArchiveError> {
+        self.object_store().map(|_| ())
+    }
+}
+
+/// Summary of a completed archival, also written next to the archived data
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ArchiveReport {
+    pub stream: String,
+    pub destination: String,
+    pub files: usize,
+    pub bytes: u64,
+    pub started_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+}
+
+/// Copy every object of the stream to the archive destination under `<destination>/<stream>/`
+pub async fn archive_stream(
+    stream_name: &str,
+    archive: &Archive,
+) -> Result<ArchiveReport, ArchiveError> {
+    let started_at = Utc::now();
+    let (destination, dest_prefix) = archive.object_store()?;
+    let dest_prefix = dest_prefix.child(stream_name);
+
+    let storage = CONFIG.storage();
+    let store = storage.get_object_store();
+    let source = storage.get_datafusion_object_store()?;
+    let prefix = store.absolute_url(RelativePath::new(stream_name));
+
+    let objects: Vec<_> = source.list(Some(&prefix)).await?.try_collect().await?;
+
+    let mut files = 0;
+    let mut bytes = 0;
+    for object in objects {
+        let Some(parts) = object.location.prefix_match(&prefix) else {
+            continue;
+        };
+        let target = parts.fold(dest_prefix.clone(), |path, part| path.child(part));
+
+        copy_object(&*source, &object.location, &*destination, &target).await?;
+        files += 1;
+        bytes += object.size as u64;
+    }
+
+    let report = ArchiveReport {
+        stream: stream_name.to_owned(),
+        destination: archive.destination.clone(),
+        files,
+        bytes,
+        started_at,
+        completed_at: Utc::now(),
+    };
+
+    let report_bytes = serde_json::to_vec(&report).expect("report is serializable");
+    destination
+        .put(
+            &dest_prefix.child(ARCHIVE_REPORT_FILE_NAME),
+            report_bytes.into(),
+        )
+        .await?;
+
+    Ok(report)
+}
+
+// streams the object to the destination instead of holding it in memory, a failed copy is
+// aborted so that no partial upload is left at the destination
+async fn copy_object(
+    source: &dyn ObjectStore,
+    from: &Path,
+    destination: &dyn ObjectStore,
+    to: &Path,
+) -> Result<(), ArchiveError> {
+    let mut chunks = source.get(from).await?.into_stream();
+    let (multipart_id, mut writer) = destination.put_multipart(to).await?;
+
+    let res = async {
+        while let Some(chunk) = chunks.next().await {
+            writer.write_all(&chunk?).await?;
+        }
+        writer.shutdown().await?;
+        Ok::<_, ArchiveError>(())
+    }
+    .await;
+
+    if res.is_err() {
+        if let Err(err) = destination.abort_multipart(to, &multipart_id).await {
+            log::warn!("failed to abort archive upload of {to}: {err}");
+        }
+    }
+    res
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("Invalid archive destination: {0}")]
+    InvalidDestination(String),
+    #[error("Failed to archive stream: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[error("Failed to archive stream: {0}")]
+    DataFusion(#[from] datafusion::error::DataFusionError),
+    #[error("Failed to archive stream: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::{check_scheme, Archive};
+
+    #[test]
+    fn rejects_invalid_destination() {
+        let archive = Archive {
+            destination: "not a url".to_string(),
+        };
+        assert!(archive.validate().is_err());
+    }
+
+    #[test]
+    fn only_schemes_of_the_configured_store_are_allowed() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert!(check_scheme(&url("s3://archive/parseable"), "s3").is_ok());
+        assert!(check_scheme(&url("file:///tmp/archive"), "s3").is_err());
+        assert!(check_scheme(&url("http://169.254.169.254/latest"), "s3").is_err());
+        assert!(check_scheme(&url("s3://archive/parseable"), "drive").is_err());
+        assert!(check_scheme(&url("file:///tmp/archive"), "drive").is_err());
+    }
+}
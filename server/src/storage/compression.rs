@@ -0,0 +1,94 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Compression of the metadata objects of streams. Stream metadata, which holds the stats of
+//! the stream, and manifests are written as zstd frames when `P_COMPRESS_METADATA` is set.
+//! The zstd magic number marks a compressed object, no json document starts with it, so
+//! objects are read the same whether they were written compressed or not.
+
+use bytes::Bytes;
+use object_store::{path::Path, ObjectStore};
+use relative_path::RelativePath;
+
+use super::{MANIFEST_FILE, STREAM_METADATA_FILE_NAME};
+use crate::option::CONFIG;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compress the object if it is stream metadata or a manifest and compression is enabled
+pub fn encode(path: &RelativePath, bytes: Bytes) -> Bytes {
+    if !CONFIG.parseable.compress_metadata || !is_metadata(path.as_str()) {
+        return bytes;
+    }
+    match zstd::bulk::compress(&bytes, ZSTD_LEVEL) {
+        Ok(compressed) => compressed.into(),
+        Err(err) => {
+            log::warn!("failed to compress {path}, it is written uncompressed: {err}");
+            bytes
+        }
+    }
+}
+
+/// Decompress the object if it was written compressed
+pub fn decode(bytes: Bytes) -> Result<Bytes, std::io::Error> {
+    if !bytes.starts_with(&ZSTD_MAGIC) {
+        return Ok(bytes);
+    }
+    zstd::stream::decode_all(bytes.as_ref()).map(Into::into)
+}
+
+/// Read an object directly from the object store, decompressed if it was written compressed
+pub async fn read(store: &dyn ObjectStore, path: &Path) -> Result<Bytes, object_store::Error> {
+    let bytes = store.get(path).await?.bytes().await?;
+    decode(bytes).map_err(|err| object_store::Error::Generic {
+        store: "zstd",
+        source: Box::new(err),
+    })
+}
+
+fn is_metadata(path: &str) -> bool {
+    path.ends_with(STREAM_METADATA_FILE_NAME) || path.ends_with(MANIFEST_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{decode, is_metadata, ZSTD_LEVEL};
+
+    #[test]
+    fn objects_are_decoded_either_way() {
+        let json = Bytes::from_static(br#"{"version":"v1","files":[]}"#);
+        let compressed: Bytes = zstd::bulk::compress(&json, ZSTD_LEVEL).unwrap().into();
+
+        assert_ne!(compressed, json);
+        assert_eq!(decode(compressed).unwrap(), json);
+        assert_eq!(decode(json.clone()).unwrap(), json);
+    }
+
+    #[test]
+    fn only_metadata_is_compressed() {
+        assert!(is_metadata("app/.stream/.stream.json"));
+        assert!(is_metadata(
+            "app/date=2024-01-01/10.0.0.1.8000.00000000000000000001.manifest.json"
+        ));
+        assert!(!is_metadata(".parseable/.parseable.json"));
+        assert!(!is_metadata("app/.stream/.schema"));
+    }
+}
@@ -0,0 +1,527 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use object_store::{path::Path, ObjectStore};
+use relative_path::RelativePath;
+use ulid::Ulid;
+
+use crate::catalog::column::TypedStatistics;
+use crate::catalog::manifest::{self, Manifest};
+use crate::option::CONFIG;
+
+use super::{
+    compression, quirks, ObjectStoreFormat, MANIFEST_FILE, STREAM_METADATA_FILE_NAME,
+    STREAM_ROOT_DIRECTORY,
+};
+
+// parquet files younger than this may still be waiting for their manifest entry
+pub(crate) const ORPHAN_GRACE_PERIOD_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SizeMismatch {
+    pub path: String,
+    pub manifest_size: u64,
+    pub actual_size: u64,
+}
+
+/// Result of cross checking the metadata of a stream with the objects in storage
+#[derive(Debug, Default, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyReport {
+    pub stream: String,
+    pub manifests: usize,
+    pub files: usize,
+    /// manifests listed in a snapshot which do not exist
+    pub missing_manifests: Vec<String>,
+    /// files listed in a manifest which do not exist
+    pub missing_files: Vec<String>,
+    pub size_mismatches: Vec<SizeMismatch>,
+    /// files whose content does not match the checksum recorded at upload
+    pub checksum_mismatches: Vec<String>,
+    pub checksums_verified: usize,
+    /// parquet files not referenced by any manifest
+    pub orphaned_files: Vec<String>,
+    pub manifest_events: u64,
+    pub manifest_storage: u64,
+    pub recorded_events: u64,
+    pub recorded_storage: u64,
+    pub repaired: bool,
+}
+
+impl ConsistencyReport {
+    fn has_metadata_errors(&self) -> bool {
+        !(self.missing_manifests.is_empty()
+            && self.missing_files.is_empty()
+            && self.size_mismatches.is_empty())
+    }
+}
+
+/// Walk all objects of the stream and compare them with its snapshots and manifests.
+/// With `verify_checksums` every file is downloaded and compared with its recorded checksum.
+/// With `repair`, snapshot and manifest entries pointing to missing objects are dropped
+/// and file sizes are corrected. Data objects are never modified.
+pub async fn check_stream(
+    stream_name: &str,
+    verify_checksums: bool,
+    repair: bool,
+) -> Result<ConsistencyReport, ConsistencyError> {
+    let storage = CONFIG.storage();
+    let store = storage.get_datafusion_object_store()?;
+    let prefix = storage
+        .get_object_store()
+        .absolute_url(RelativePath::new(stream_name));
+
+    check_objects(
+        &*store,
+        &prefix,
+        stream_name,
+        quirks::get().list_after_write_delay,
+        verify_checksums,
+        repair,
+    )
+    .await
+}
+
+async fn check_objects(
+    store: &dyn ObjectStore,
+    prefix: &Path,
+    stream_name: &str,
+    list_after_write_delay: std::time::Duration,
+    verify_checksums: bool,
+    repair: bool,
+) -> Result<ConsistencyReport, ConsistencyError> {
+    let objects: Vec<_> = store.list(Some(prefix)).await?.try_collect().await?;
+    let mut sizes: HashMap<String, (u64, DateTime<Utc>)> = objects
+        .iter()
+        .map(|meta| {
+            (
+                meta.location.to_string(),
+                (meta.size as u64, meta.last_modified),
+            )
+        })
+        .collect();
+
+    let mut report = ConsistencyReport {
+        stream: stream_name.to_owned(),
+        ..ConsistencyReport::default()
+    };
+    // objects missing from a listing which lags behind writes are looked up before they are
+    // reported, repair would drop them from the metadata otherwise
+    let lagging = !list_after_write_delay.is_zero();
+
+    // main and ingester stream metadata files
+    let mut stream_jsons = Vec::new();
+    for meta in &objects {
+        let is_stream_json = meta
+            .location
+            .filename()
+            .is_some_and(|name| name.ends_with(STREAM_METADATA_FILE_NAME))
+            && meta
+                .location
+                .parts()
+                .any(|part| part.as_ref() == STREAM_ROOT_DIRECTORY);
+        if is_stream_json {
+            let bytes = compression::read(store, &meta.location).await?;
+            let format: ObjectStoreFormat = serde_json::from_slice(&bytes)?;
+            report.recorded_events += format.stats.events;
+            report.recorded_storage += format.stats.storage;
+            stream_jsons.push((meta.location.clone(), format));
+        }
+    }
+
+    let manifest_paths: HashSet<String> = stream_jsons
+        .iter()
+        .flat_map(|(_, format)| &format.snapshot.manifest_list)
+        .map(|item| item.manifest_path.clone())
+        .collect();
+
+    let mut referenced = HashSet::new();
+    let mut manifests = Vec::new();
+    for manifest_path in manifest_paths {
+        let present = sizes.contains_key(&manifest_path)
+            || (lagging && head_unlisted(store, &manifest_path, &mut sizes).await?);
+        if !present {
+            report.missing_manifests.push(manifest_path);
+            continue;
+        }
+        let path = Path::parse(&manifest_path)?;
+        let manifest: Manifest = serde_json::from_slice(&compression::read(store, &path).await?)?;
+        report.manifests += 1;
+
+        let mut needs_repair = false;
+        for file in &manifest.files {
+            report.files += 1;
+            report.manifest_events += file.num_rows;
+            report.manifest_storage += file.file_size;
+            referenced.insert(file.file_path.clone());
+
+            if lagging && !sizes.contains_key(&file.file_path) {
+                head_unlisted(store, &file.file_path, &mut sizes).await?;
+            }
+            match sizes.get(&file.file_path) {
+                None => {
+                    report.missing_files.push(file.file_path.clone());
+                    needs_repair = true;
+                }
+                Some((size, _)) if *size != file.file_size => {
+                    report.size_mismatches.push(SizeMismatch {
+                        path: file.file_path.clone(),
+                        manifest_size: file.file_size,
+                        actual_size: *size,
+                    });
+                    needs_repair = true;
+                }
+                _ => (),
+            }
+
+            if let (true, Some(checksum), Some(_)) =
+                (verify_checksums, &file.checksum, sizes.get(&file.file_path))
+            {
+                let data = store
+                    .get(&Path::parse(&file.file_path)?)
+                    .await?
+                    .bytes()
+                    .await?;
+                if manifest::checksum(&data) != *checksum {
+                    report.checksum_mismatches.push(file.file_path.clone());
+                }
+                report.checksums_verified += 1;
+            }
+        }
+
+        if needs_repair {
+            manifests.push((path, manifest));
+        }
+    }
+
+    // manifests written since the listing may be missing from it as well
+    let grace_cutoff = Utc::now()
+        - Duration::minutes(ORPHAN_GRACE_PERIOD_MINUTES)
+        - Duration::from_std(list_after_write_delay).unwrap_or_else(|_| Duration::zero());
+    report.orphaned_files = sizes
+        .iter()
+        .filter(|(path, (_, last_modified))| {
+            path.ends_with(".parquet")
+                && !referenced.contains(*path)
+                && *last_modified < grace_cutoff
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+
+    if repair && report.has_metadata_errors() {
+        // repaired manifests are written under new names and the snapshots are switched over
+        // to them afterwards, a reader sees either the old or the repaired manifest and never a
+        // snapshot referring to a manifest which is not written yet
+        let mut repaired: HashMap<String, (String, BTreeMap<String, TypedStatistics>)> =
+            HashMap::new();
+        for (path, mut manifest) in manifests {
+            manifest
+                .files
+                .retain(|file| sizes.contains_key(&file.file_path));
+            for file in manifest.files.iter_mut() {
+                if let Some((size, _)) = sizes.get(&file.file_path) {
+                    file.file_size = *size;
+                }
+            }
+            let repaired_path = repaired_manifest_path(&path);
+            store
+                .put(&repaired_path, serde_json::to_vec(&manifest)?.into())
+                .await?;
+            repaired.insert(
+                path.to_string(),
+                (repaired_path.to_string(), manifest.column_stats()),
+            );
+        }
+
+        for (path, _) in stream_jsons {
+            // read again, snapshots committed while the stream was checked are kept
+            let mut format: ObjectStoreFormat =
+                serde_json::from_slice(&compression::read(store, &path).await?)?;
+            let count = format.snapshot.manifest_list.len();
+            format
+                .snapshot
+                .manifest_list
+                .retain(|item| !report.missing_manifests.contains(&item.manifest_path));
+            let mut changed = format.snapshot.manifest_list.len() != count;
+            for item in format.snapshot.manifest_list.iter_mut() {
+                if let Some((repaired_path, column_stats)) = repaired.get(&item.manifest_path) {
+                    item.manifest_path = repaired_path.clone();
+                    item.column_stats = column_stats.clone();
+                    changed = true;
+                }
+            }
+            if changed {
+                store
+                    .put(&path, serde_json::to_vec(&format)?.into())
+                    .await?;
+            }
+        }
+        report.repaired = true;
+    }
+
+    Ok(report)
+}
+
+// the repaired copy of a manifest, next to it
+fn repaired_manifest_path(path: &Path) -> Path {
+    let file_name = path.filename().unwrap_or(MANIFEST_FILE);
+    let stem = file_name
+        .strip_suffix(MANIFEST_FILE)
+        .unwrap_or(file_name)
+        .trim_end_matches('.');
+    let parent: Path = path.parts().take(path.parts().count() - 1).collect();
+    parent.child(format!("{stem}.{}.{MANIFEST_FILE}", Ulid::new()))
+}
+
+// look up an object missing from the listing, true if it exists
+async fn head_unlisted(
+    store: &dyn ObjectStore,
+    path: &str,
+    sizes: &mut HashMap<String, (u64, DateTime<Utc>)>,
+) -> Result<bool, ConsistencyError> {
+    match store.head(&Path::parse(path)?).await {
+        Ok(meta) => {
+            sizes.insert(path.to_owned(), (meta.size as u64, meta.last_modified));
+            Ok(true)
+        }
+        Err(object_store::Error::NotFound { .. }) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConsistencyError {
+    #[error("Object store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[error("Invalid object path: {0}")]
+    Path(#[from] object_store::path::Error),
+    #[error("DataFusion error: {0}")]
+    DataFusion(#[from] datafusion::error::DataFusionError),
+    #[error("Invalid metadata: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+
+    use super::check_objects;
+    use crate::catalog::manifest::{self, File, Manifest};
+    use crate::catalog::snapshot::ManifestItem;
+    use crate::storage::ObjectStoreFormat;
+
+    const STREAM_JSON: &str = "app/.stream/.stream.json";
+    const MANIFEST: &str = "app/date=2024-01-01/10.0.0.1.8000.00000000000000000001.manifest.json";
+    const PRESENT: &str = "app/date=2024-01-01/hour=10/minute=05/a.data.parquet";
+    const MISSING: &str = "app/date=2024-01-01/hour=10/minute=05/b.data.parquet";
+
+    fn file(path: &str, size: u64) -> File {
+        File {
+            file_path: path.to_string(),
+            num_rows: 1,
+            file_size: size,
+            ..File::default()
+        }
+    }
+
+    async fn put_json(store: &InMemory, path: &str, value: &impl serde::Serialize) {
+        store
+            .put(&Path::from(path), serde_json::to_vec(value).unwrap().into())
+            .await
+            .unwrap();
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(store: &InMemory, path: &str) -> T {
+        let bytes = store
+            .get(&Path::from(path))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    // a stream whose manifest lists a file which does not exist and one with a wrong size, and
+    // whose snapshot lists a manifest which does not exist
+    async fn inconsistent_stream() -> InMemory {
+        let store = InMemory::new();
+        store
+            .put(&Path::from(PRESENT), vec![0u8; 10].into())
+            .await
+            .unwrap();
+        let manifest = Manifest {
+            files: vec![file(PRESENT, 7), file(MISSING, 5)],
+            ..Manifest::default()
+        };
+        put_json(&store, MANIFEST, &manifest).await;
+
+        let mut format = ObjectStoreFormat::default();
+        for manifest_path in [MANIFEST, "app/date=2024-01-02/gone.manifest.json"] {
+            format.snapshot.manifest_list.push(ManifestItem {
+                manifest_path: manifest_path.to_string(),
+                time_lower_bound: Utc::now(),
+                time_upper_bound: Utc::now(),
+                column_stats: Default::default(),
+                num_files: 0,
+            });
+        }
+        put_json(&store, STREAM_JSON, &format).await;
+        store
+    }
+
+    #[tokio::test]
+    async fn inconsistencies_are_detected_without_changes() {
+        let store = inconsistent_stream().await;
+        let report = check_objects(
+            &store,
+            &Path::from("app"),
+            "app",
+            std::time::Duration::ZERO,
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.manifests, 1);
+        assert_eq!(report.files, 2);
+        assert_eq!(
+            report.missing_manifests,
+            vec!["app/date=2024-01-02/gone.manifest.json"]
+        );
+        assert_eq!(report.missing_files, vec![MISSING]);
+        assert_eq!(report.size_mismatches.len(), 1);
+        assert_eq!(report.size_mismatches[0].actual_size, 10);
+        assert!(!report.repaired);
+
+        let format: ObjectStoreFormat = get_json(&store, STREAM_JSON).await;
+        assert_eq!(format.snapshot.manifest_list.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn repaired_manifests_are_written_before_the_snapshot_is_switched() {
+        let store = inconsistent_stream().await;
+        let report = check_objects(
+            &store,
+            &Path::from("app"),
+            "app",
+            std::time::Duration::ZERO,
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(report.repaired);
+
+        let format: ObjectStoreFormat = get_json(&store, STREAM_JSON).await;
+        assert_eq!(format.snapshot.manifest_list.len(), 1);
+        let repaired_path = &format.snapshot.manifest_list[0].manifest_path;
+        assert_ne!(repaired_path, MANIFEST);
+        assert!(repaired_path.ends_with(".manifest.json"));
+
+        let repaired: Manifest = get_json(&store, repaired_path).await;
+        assert_eq!(repaired.files.len(), 1);
+        assert_eq!(repaired.files[0].file_path, PRESENT);
+        assert_eq!(repaired.files[0].file_size, 10);
+
+        // the original manifest is left as it was for readers of the previous snapshot
+        let original: Manifest = get_json(&store, MANIFEST).await;
+        assert_eq!(original.files.len(), 2);
+
+        let report = check_objects(
+            &store,
+            &Path::from("app"),
+            "app",
+            std::time::Duration::ZERO,
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(report.missing_manifests.is_empty());
+        assert!(report.missing_files.is_empty());
+        assert!(report.size_mismatches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn files_not_matching_their_checksum_are_reported() {
+        const CORRUPTED: &str = "app/date=2024-01-01/hour=10/minute=05/c.data.parquet";
+        let store = InMemory::new();
+        let intact = vec![1u8; 10];
+        let corrupted = vec![2u8; 10];
+        store
+            .put(&Path::from(PRESENT), intact.clone().into())
+            .await
+            .unwrap();
+        store
+            .put(&Path::from(CORRUPTED), corrupted.into())
+            .await
+            .unwrap();
+        let manifest = Manifest {
+            files: vec![
+                File {
+                    checksum: Some(manifest::checksum(&intact)),
+                    ..file(PRESENT, 10)
+                },
+                // the file was changed after its checksum was recorded
+                File {
+                    checksum: Some(manifest::checksum(&[3u8; 10])),
+                    ..file(CORRUPTED, 10)
+                },
+            ],
+            ..Manifest::default()
+        };
+        put_json(&store, MANIFEST, &manifest).await;
+        let mut format = ObjectStoreFormat::default();
+        format.snapshot.manifest_list.push(ManifestItem {
+            manifest_path: MANIFEST.to_string(),
+            time_lower_bound: Utc::now(),
+            time_upper_bound: Utc::now(),
+            column_stats: Default::default(),
+            num_files: 0,
+        });
+        put_json(&store, STREAM_JSON, &format).await;
+
+        let prefix = Path::from("app");
+        let check = |verify_checksums| {
+            check_objects(
+                &store,
+                &prefix,
+                "app",
+                std::time::Duration::ZERO,
+                verify_checksums,
+                false,
+            )
+        };
+        let report = check(false).await.unwrap();
+        assert_eq!(report.checksums_verified, 0);
+        assert!(report.checksum_mismatches.is_empty());
+
+        let report = check(true).await.unwrap();
+        assert_eq!(report.checksums_verified, 2);
+        assert_eq!(report.checksum_mismatches, vec![CORRUPTED]);
+        assert!(report.missing_files.is_empty());
+    }
+}
@@ -0,0 +1,906 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Iceberg metadata for the parquet files of a stream, so that engines such as Spark and Trino
+//! can read the stream in place. The table is kept at `{stream}/.iceberg`, every export writes
+//! a new metadata version whose snapshot holds the files in the manifests of the stream. Each
+//! manifest of the stream becomes an iceberg manifest, which is reused by later exports for as
+//! long as the manifest is not changed.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use apache_avro::{Schema as AvroSchema, Writer};
+use arrow_schema::{DataType, Field, Fields};
+use bytes::Bytes;
+use chrono::Utc;
+use clokwerk::{AsyncScheduler, TimeUnits};
+use futures::TryStreamExt;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use once_cell::sync::Lazy;
+use relative_path::RelativePathBuf;
+use serde_json::{json, Value};
+
+use crate::catalog::{self, manifest::Manifest};
+use crate::metadata::STREAM_INFO;
+use crate::option::CONFIG;
+
+use super::ObjectStorageError;
+
+const ICEBERG_DIRECTORY: &str = ".iceberg";
+const METADATA_DIRECTORY: &str = "metadata";
+const VERSION_HINT_FILE: &str = "version-hint.text";
+const EXPORT_INTERVAL_MINUTES: u32 = 5;
+const FORMAT_VERSION: u8 = 2;
+// partition field ids start after this one, iceberg uses it for unpartitioned tables
+const LAST_PARTITION_ID: i32 = 999;
+const STATUS_ADDED: i32 = 1;
+const CONTENT_DATA: i32 = 0;
+
+static MANIFEST_SCHEMA: Lazy<AvroSchema> = Lazy::new(|| {
+    AvroSchema::parse_str(
+        r#"{
+  "type": "record",
+  "name": "manifest_entry",
+  "fields": [
+    {"name": "status", "type": "int", "field-id": 0},
+    {"name": "snapshot_id", "type": ["null", "long"], "default": null, "field-id": 1},
+    {"name": "sequence_number", "type": ["null", "long"], "default": null, "field-id": 3},
+    {"name": "file_sequence_number", "type": ["null", "long"], "default": null, "field-id": 4},
+    {"name": "data_file", "field-id": 2, "type": {
+      "type": "record",
+      "name": "r2",
+      "fields": [
+        {"name": "content", "type": "int", "field-id": 134},
+        {"name": "file_path", "type": "string", "field-id": 100},
+        {"name": "file_format", "type": "string", "field-id": 101},
+        {"name": "partition", "type": {"type": "record", "name": "r102", "fields": []}, "field-id": 102},
+        {"name": "record_count", "type": "long", "field-id": 103},
+        {"name": "file_size_in_bytes", "type": "long", "field-id": 104}
+      ]
+    }}
+  ]
+}"#,
+    )
+    .expect("manifest schema is valid")
+});
+
+static MANIFEST_LIST_SCHEMA: Lazy<AvroSchema> = Lazy::new(|| {
+    AvroSchema::parse_str(
+        r#"{
+  "type": "record",
+  "name": "manifest_file",
+  "fields": [
+    {"name": "manifest_path", "type": "string", "field-id": 500},
+    {"name": "manifest_length", "type": "long", "field-id": 501},
+    {"name": "partition_spec_id", "type": "int", "field-id": 502},
+    {"name": "content", "type": "int", "field-id": 517},
+    {"name": "sequence_number", "type": "long", "field-id": 515},
+    {"name": "min_sequence_number", "type": "long", "field-id": 516},
+    {"name": "added_snapshot_id", "type": "long", "field-id": 503},
+    {"name": "added_files_count", "type": "int", "field-id": 504},
+    {"name": "existing_files_count", "type": "int", "field-id": 505},
+    {"name": "deleted_files_count", "type": "int", "field-id": 506},
+    {"name": "added_rows_count", "type": "long", "field-id": 512},
+    {"name": "existing_rows_count", "type": "long", "field-id": 513},
+    {"name": "deleted_rows_count", "type": "long", "field-id": 514}
+  ]
+}"#,
+    )
+    .expect("manifest list schema is valid")
+});
+
+#[derive(Debug, serde::Serialize)]
+struct ManifestEntry {
+    status: i32,
+    snapshot_id: Option<i64>,
+    sequence_number: Option<i64>,
+    file_sequence_number: Option<i64>,
+    data_file: DataFile,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DataFile {
+    content: i32,
+    file_path: String,
+    file_format: String,
+    partition: Partition,
+    record_count: i64,
+    file_size_in_bytes: i64,
+}
+
+// the table is not partitioned
+#[derive(Debug, serde::Serialize)]
+struct Partition {}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct ManifestFile {
+    manifest_path: String,
+    manifest_length: i64,
+    partition_spec_id: i32,
+    content: i32,
+    sequence_number: i64,
+    min_sequence_number: i64,
+    added_snapshot_id: i64,
+    added_files_count: i32,
+    existing_files_count: i32,
+    deleted_files_count: i32,
+    added_rows_count: i64,
+    existing_rows_count: i64,
+    deleted_rows_count: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TableMetadata {
+    format_version: u8,
+    table_uuid: String,
+    location: String,
+    last_sequence_number: i64,
+    last_updated_ms: i64,
+    last_column_id: i32,
+    schemas: Vec<IcebergSchema>,
+    current_schema_id: i32,
+    partition_specs: Vec<Value>,
+    default_spec_id: i32,
+    last_partition_id: i32,
+    sort_orders: Vec<Value>,
+    default_sort_order_id: i32,
+    properties: HashMap<String, String>,
+    current_snapshot_id: i64,
+    snapshots: Vec<Snapshot>,
+    snapshot_log: Vec<Value>,
+    metadata_log: Vec<Value>,
+    refs: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct IcebergSchema {
+    #[serde(rename = "type")]
+    schema_type: String,
+    schema_id: i32,
+    fields: Vec<Value>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Snapshot {
+    snapshot_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_snapshot_id: Option<i64>,
+    sequence_number: i64,
+    timestamp_ms: i64,
+    manifest_list: String,
+    summary: HashMap<String, String>,
+    schema_id: i32,
+}
+
+/// Whether iceberg metadata is kept for the stream, and where the table is
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IcebergExport {
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+pub fn init_export_scheduler() {
+    log::info!("Setting up schedular for iceberg export");
+
+    let mut scheduler = AsyncScheduler::new();
+    scheduler
+        .every(EXPORT_INTERVAL_MINUTES.minutes())
+        .run(export_streams);
+
+    tokio::spawn(async move {
+        loop {
+            scheduler.run_pending().await;
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+    });
+}
+
+async fn export_streams() {
+    let storage = CONFIG.storage().get_object_store();
+    for stream in STREAM_INFO.list_streams() {
+        match storage.get_object_store_format(&stream).await {
+            Ok(format) if format.iceberg_export => {}
+            Ok(_) => continue,
+            Err(err) => {
+                log::warn!("failed to read metadata of stream {stream}: {err}");
+                continue;
+            }
+        }
+        if let Err(err) = export_stream(&stream).await {
+            log::warn!("failed to export stream {stream} as an iceberg table: {err}");
+        }
+    }
+}
+
+// iceberg refers to files with urls of the object store
+fn file_url(path: &str) -> String {
+    let store_url = CONFIG.storage().get_object_store().store_url();
+    format!(
+        "{}/{}",
+        store_url.as_str().trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Location of the iceberg table of the stream, to register it with a catalog
+pub fn table_location(stream: &str) -> String {
+    file_url(table_path(stream).as_ref())
+}
+
+fn table_path(stream: &str) -> Path {
+    CONFIG
+        .storage()
+        .get_object_store()
+        .absolute_url(&RelativePathBuf::from_iter([stream, ICEBERG_DIRECTORY]))
+}
+
+/// Write a new version of the iceberg metadata of the stream if its files or schema changed
+/// since the last export. Returns whether a version was written.
+pub async fn export_stream(stream: &str) -> Result<bool, IcebergError> {
+    let storage = CONFIG.storage();
+    let store = storage
+        .get_datafusion_object_store()
+        .map_err(|err| IcebergError::Storage(ObjectStorageError::UnhandledError(Box::new(err))))?;
+    let metadata_dir = table_path(stream).child(METADATA_DIRECTORY);
+
+    let previous = read_current_metadata(&*store, &metadata_dir).await?;
+    let previous_manifests = match previous
+        .as_ref()
+        .and_then(|(_, metadata)| current_snapshot(metadata))
+    {
+        Some(snapshot) => read_manifest_list(&*store, &snapshot.manifest_list).await?,
+        None => Vec::new(),
+    };
+
+    // field ids of columns which were exported before are kept
+    let mut ids = FieldIds::default();
+    if let Some((_, metadata)) = &previous {
+        ids.last_id = metadata.last_column_id;
+        for schema in &metadata.schemas {
+            for field in &schema.fields {
+                ids.collect(field, "");
+            }
+        }
+    }
+    let arrow_schema = STREAM_INFO
+        .schema(stream)
+        .map_err(|err| IcebergError::Schema(err.to_string()))?;
+    let fields = ids.fields(arrow_schema.fields(), "");
+    let (schema_id, schema_changed) = match &previous {
+        Some((_, metadata)) => match metadata.schemas.iter().find(|s| s.fields == fields) {
+            Some(schema) => (
+                schema.schema_id,
+                schema.schema_id != metadata.current_schema_id,
+            ),
+            None => (
+                metadata
+                    .schemas
+                    .iter()
+                    .map(|s| s.schema_id)
+                    .max()
+                    .unwrap_or(-1)
+                    + 1,
+                true,
+            ),
+        },
+        None => (0, true),
+    };
+
+    let sequence_number = previous
+        .as_ref()
+        .map_or(1, |(_, metadata)| metadata.last_sequence_number + 1);
+    let snapshot_id = Utc::now().timestamp_millis();
+
+    let manifest_items = catalog::get_manifest_list(storage.get_object_store(), stream).await?;
+    let mut manifests = Vec::with_capacity(manifest_items.len());
+    let mut added_files = 0;
+    for item in manifest_items {
+        let manifest_path = Path::parse(&item.manifest_path)
+            .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+        let meta = store
+            .head(&manifest_path)
+            .await
+            .map_err(ObjectStorageError::from)?;
+        // manifests rewritten in place, e.g. by a purge, are exported again
+        let key = xxhash_rust::xxh3::xxh3_64(
+            format!(
+                "{}:{}:{}",
+                item.manifest_path, meta.size, meta.last_modified
+            )
+            .as_bytes(),
+        );
+        let iceberg_path = metadata_dir.child(format!("{key:016x}-m0.avro"));
+        let iceberg_url = file_url(iceberg_path.as_ref());
+        if let Some(existing) = previous_manifests
+            .iter()
+            .find(|manifest| manifest.manifest_path == iceberg_url)
+        {
+            manifests.push(existing.clone());
+            continue;
+        }
+
+        let Some(manifest) = catalog::read_manifest(&item.manifest_path).await? else {
+            continue;
+        };
+        let (bytes, files, rows) =
+            write_manifest(&manifest, snapshot_id, sequence_number, &fields, schema_id)?;
+        let length = bytes.len() as i64;
+        store
+            .put(&iceberg_path, bytes)
+            .await
+            .map_err(ObjectStorageError::from)?;
+        added_files += files;
+        manifests.push(ManifestFile {
+            manifest_path: iceberg_url,
+            manifest_length: length,
+            partition_spec_id: 0,
+            content: CONTENT_DATA,
+            sequence_number,
+            min_sequence_number: sequence_number,
+            added_snapshot_id: snapshot_id,
+            added_files_count: files,
+            existing_files_count: 0,
+            deleted_files_count: 0,
+            added_rows_count: rows,
+            existing_rows_count: 0,
+            deleted_rows_count: 0,
+        });
+    }
+
+    let current: HashSet<_> = manifests.iter().map(|m| &m.manifest_path).collect();
+    let removed = previous_manifests
+        .iter()
+        .any(|manifest| !current.contains(&manifest.manifest_path));
+    if previous.is_some() && added_files == 0 && !removed && !schema_changed {
+        return Ok(false);
+    }
+
+    let manifest_list_path = metadata_dir.child(format!("snap-{snapshot_id}.avro"));
+    store
+        .put(
+            &manifest_list_path,
+            write_manifest_list(&manifests, snapshot_id, sequence_number)?,
+        )
+        .await
+        .map_err(ObjectStorageError::from)?;
+
+    let now = Utc::now().timestamp_millis();
+    let (version, metadata) = next_metadata(
+        stream,
+        previous.clone(),
+        fields,
+        ids.last_id,
+        Snapshot {
+            snapshot_id,
+            parent_snapshot_id: previous.as_ref().map(|(_, m)| m.current_snapshot_id),
+            sequence_number,
+            timestamp_ms: now,
+            manifest_list: file_url(manifest_list_path.as_ref()),
+            summary: HashMap::from([(
+                "operation".to_string(),
+                if removed { "overwrite" } else { "append" }.to_string(),
+            )]),
+            schema_id,
+        },
+        &metadata_dir,
+    );
+    store
+        .put(
+            &metadata_dir.child(format!("v{version}.metadata.json")),
+            serde_json::to_vec(&metadata)?.into(),
+        )
+        .await
+        .map_err(ObjectStorageError::from)?;
+    store
+        .put(
+            &metadata_dir.child(VERSION_HINT_FILE),
+            version.to_string().into(),
+        )
+        .await
+        .map_err(ObjectStorageError::from)?;
+
+    // files of versions before the previous one are removed, readers of the
+    // previous version may still be reading it
+    let mut referenced: HashSet<String> = manifests.into_iter().map(|m| m.manifest_path).collect();
+    referenced.extend(previous_manifests.into_iter().map(|m| m.manifest_path));
+    referenced.insert(file_url(manifest_list_path.as_ref()));
+    if let Some((_, previous)) = &previous {
+        referenced.extend(current_snapshot(previous).map(|s| s.manifest_list.clone()));
+    }
+    remove_unreferenced(&*store, &metadata_dir, version, &referenced).await?;
+
+    Ok(true)
+}
+
+async fn read_current_metadata(
+    store: &dyn ObjectStore,
+    metadata_dir: &Path,
+) -> Result<Option<(u64, TableMetadata)>, IcebergError> {
+    let hint = match store.get(&metadata_dir.child(VERSION_HINT_FILE)).await {
+        Ok(hint) => hint.bytes().await.map_err(ObjectStorageError::from)?,
+        Err(object_store::Error::NotFound { .. }) => return Ok(None),
+        Err(err) => return Err(ObjectStorageError::from(err).into()),
+    };
+    let version: u64 = String::from_utf8_lossy(&hint)
+        .trim()
+        .parse()
+        .map_err(|_| IcebergError::Schema("invalid version hint".to_string()))?;
+    let bytes = store
+        .get(&metadata_dir.child(format!("v{version}.metadata.json")))
+        .await
+        .map_err(ObjectStorageError::from)?
+        .bytes()
+        .await
+        .map_err(ObjectStorageError::from)?;
+    Ok(Some((version, serde_json::from_slice(&bytes)?)))
+}
+
+fn current_snapshot(metadata: &TableMetadata) -> Option<&Snapshot> {
+    metadata
+        .snapshots
+        .iter()
+        .find(|snapshot| snapshot.snapshot_id == metadata.current_snapshot_id)
+}
+
+async fn read_manifest_list(
+    store: &dyn ObjectStore,
+    url: &str,
+) -> Result<Vec<ManifestFile>, IcebergError> {
+    let path = url_path(url)?;
+    let bytes = store
+        .get(&path)
+        .await
+        .map_err(ObjectStorageError::from)?
+        .bytes()
+        .await
+        .map_err(ObjectStorageError::from)?;
+    let reader = apache_avro::Reader::new(&bytes[..])?;
+    let mut manifests = Vec::new();
+    for value in reader {
+        manifests.push(apache_avro::from_value(&value?)?);
+    }
+    Ok(manifests)
+}
+
+// path in the object store of a url written to the metadata
+fn url_path(url: &str) -> Result<Path, IcebergError> {
+    let url = url::Url::parse(url).map_err(|err| IcebergError::Schema(err.to_string()))?;
+    Path::from_url_path(url.path()).map_err(|err| IcebergError::Schema(err.to_string()))
+}
+
+fn write_manifest(
+    manifest: &Manifest,
+    snapshot_id: i64,
+    sequence_number: i64,
+    fields: &[Value],
+    schema_id: i32,
+) -> Result<(Bytes, i32, i64), IcebergError> {
+    let mut writer = Writer::new(&MANIFEST_SCHEMA, Vec::new());
+    let schema = json!({"type": "struct", "schema-id": schema_id, "fields": fields});
+    writer.add_user_metadata("schema".to_string(), schema.to_string())?;
+    writer.add_user_metadata("schema-id".to_string(), schema_id.to_string())?;
+    writer.add_user_metadata("partition-spec".to_string(), "[]")?;
+    writer.add_user_metadata("partition-spec-id".to_string(), "0")?;
+    writer.add_user_metadata("format-version".to_string(), FORMAT_VERSION.to_string())?;
+    writer.add_user_metadata("content".to_string(), "data")?;
+
+    let mut rows = 0;
+    for file in &manifest.files {
+        rows += file.num_rows as i64;
+        writer.append_ser(ManifestEntry {
+            status: STATUS_ADDED,
+            snapshot_id: Some(snapshot_id),
+            sequence_number: Some(sequence_number),
+            file_sequence_number: Some(sequence_number),
+            data_file: DataFile {
+                content: CONTENT_DATA,
+                file_path: file_url(&file.file_path),
+                file_format: "PARQUET".to_string(),
+                partition: Partition {},
+                record_count: file.num_rows as i64,
+                file_size_in_bytes: file.file_size as i64,
+            },
+        })?;
+    }
+    Ok((
+        writer.into_inner()?.into(),
+        manifest.files.len() as i32,
+        rows,
+    ))
+}
+
+fn write_manifest_list(
+    manifests: &[ManifestFile],
+    snapshot_id: i64,
+    sequence_number: i64,
+) -> Result<Bytes, IcebergError> {
+    let mut writer = Writer::new(&MANIFEST_LIST_SCHEMA, Vec::new());
+    writer.add_user_metadata("snapshot-id".to_string(), snapshot_id.to_string())?;
+    writer.add_user_metadata("sequence-number".to_string(), sequence_number.to_string())?;
+    writer.add_user_metadata("format-version".to_string(), FORMAT_VERSION.to_string())?;
+    for manifest in manifests {
+        writer.append_ser(manifest)?;
+    }
+    Ok(writer.into_inner()?.into())
+}
+
+fn next_metadata(
+    stream: &str,
+    previous: Option<(u64, TableMetadata)>,
+    fields: Vec<Value>,
+    last_column_id: i32,
+    snapshot: Snapshot,
+    metadata_dir: &Path,
+) -> (u64, TableMetadata) {
+    let (version, mut metadata) = match previous {
+        Some((version, metadata)) => (version + 1, metadata),
+        None => (
+            0,
+            TableMetadata {
+                format_version: FORMAT_VERSION,
+                table_uuid: random_uuid(),
+                location: table_location(stream),
+                last_sequence_number: 0,
+                last_updated_ms: 0,
+                last_column_id: 0,
+                schemas: Vec::new(),
+                current_schema_id: -1,
+                partition_specs: vec![json!({"spec-id": 0, "fields": []})],
+                default_spec_id: 0,
+                last_partition_id: LAST_PARTITION_ID,
+                sort_orders: vec![json!({"order-id": 0, "fields": []})],
+                default_sort_order_id: 0,
+                properties: HashMap::new(),
+                current_snapshot_id: -1,
+                snapshots: Vec::new(),
+                snapshot_log: Vec::new(),
+                metadata_log: Vec::new(),
+                refs: json!({}),
+            },
+        ),
+    };
+    if version > 0 {
+        metadata.metadata_log.push(json!({
+            "timestamp-ms": metadata.last_updated_ms,
+            "metadata-file": file_url(metadata_dir.child(format!("v{}.metadata.json", version - 1)).as_ref()),
+        }));
+    }
+
+    let schema_id = snapshot.schema_id;
+    if metadata
+        .schemas
+        .iter()
+        .all(|schema| schema.schema_id != schema_id)
+    {
+        metadata.schemas.push(IcebergSchema {
+            schema_type: "struct".to_string(),
+            schema_id,
+            fields: fields.clone(),
+        });
+    }
+    // files written by parseable have no field ids, columns are matched by name
+    metadata.properties.insert(
+        "schema.name-mapping.default".to_string(),
+        Value::Array(name_mapping(&fields)).to_string(),
+    );
+
+    metadata.current_schema_id = schema_id;
+    metadata.last_column_id = last_column_id;
+    metadata.last_sequence_number = snapshot.sequence_number;
+    metadata.last_updated_ms = snapshot.timestamp_ms;
+    metadata.current_snapshot_id = snapshot.snapshot_id;
+    metadata.refs = json!({"main": {"snapshot-id": snapshot.snapshot_id, "type": "branch"}});
+    metadata.snapshot_log = vec![json!({
+        "timestamp-ms": snapshot.timestamp_ms,
+        "snapshot-id": snapshot.snapshot_id,
+    })];
+    // only the current snapshot is kept, the files of older ones are removed
+    metadata.snapshots = vec![snapshot];
+
+    (version, metadata)
+}
+
+async fn remove_unreferenced(
+    store: &dyn ObjectStore,
+    metadata_dir: &Path,
+    version: u64,
+    referenced: &HashSet<String>,
+) -> Result<(), IcebergError> {
+    let objects: Vec<_> = store
+        .list(Some(metadata_dir))
+        .await
+        .map_err(ObjectStorageError::from)?
+        .try_collect()
+        .await
+        .map_err(ObjectStorageError::from)?;
+    for object in objects {
+        let Some(name) = object.location.filename() else {
+            continue;
+        };
+        let unreferenced = match name
+            .strip_prefix('v')
+            .and_then(|name| name.strip_suffix(".metadata.json"))
+        {
+            Some(file_version) => file_version
+                .parse::<u64>()
+                .is_ok_and(|file_version| file_version + 1 < version),
+            None => {
+                name.ends_with(".avro") && !referenced.contains(&file_url(object.location.as_ref()))
+            }
+        };
+        if unreferenced {
+            store
+                .delete(&object.location)
+                .await
+                .map_err(ObjectStorageError::from)?;
+        }
+    }
+    Ok(())
+}
+
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    // version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+// ids of the fields of the iceberg schema, by their path in the schema
+#[derive(Default)]
+struct FieldIds {
+    ids: HashMap<String, i32>,
+    last_id: i32,
+}
+
+impl FieldIds {
+    fn id(&mut self, path: &str) -> i32 {
+        if let Some(id) = self.ids.get(path) {
+            return *id;
+        }
+        self.last_id += 1;
+        self.ids.insert(path.to_string(), self.last_id);
+        self.last_id
+    }
+
+    // record the ids of a field of a previously exported schema
+    fn collect(&mut self, field: &Value, prefix: &str) {
+        let (Some(name), Some(id)) = (
+            field.get("name").and_then(Value::as_str),
+            field.get("id").and_then(Value::as_i64),
+        ) else {
+            return;
+        };
+        let path = format!("{prefix}{name}");
+        self.ids.insert(path.clone(), id as i32);
+        self.collect_type(&field["type"], &path);
+    }
+
+    fn collect_type(&mut self, field_type: &Value, path: &str) {
+        match field_type.get("type").and_then(Value::as_str) {
+            Some("struct") => {
+                for field in field_type["fields"].as_array().into_iter().flatten() {
+                    self.collect(field, &format!("{path}."));
+                }
+            }
+            Some("list") => {
+                let element = format!("{path}.element");
+                if let Some(id) = field_type.get("element-id").and_then(Value::as_i64) {
+                    self.ids.insert(element.clone(), id as i32);
+                }
+                self.collect_type(&field_type["element"], &element);
+            }
+            Some("map") => {
+                for part in ["key", "value"] {
+                    let child = format!("{path}.{part}");
+                    if let Some(id) = field_type.get(format!("{part}-id")).and_then(Value::as_i64) {
+                        self.ids.insert(child.clone(), id as i32);
+                    }
+                    self.collect_type(&field_type[part], &child);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // columns of types iceberg has no equivalent for are left out
+    fn fields(&mut self, fields: &Fields, prefix: &str) -> Vec<Value> {
+        fields
+            .iter()
+            .filter_map(|field| self.field(field, prefix))
+            .collect()
+    }
+
+    fn field(&mut self, field: &Field, prefix: &str) -> Option<Value> {
+        let path = format!("{prefix}{}", field.name());
+        let field_type = self.data_type(field.data_type(), &path)?;
+        Some(json!({
+            "id": self.id(&path),
+            "name": field.name(),
+            "required": !field.is_nullable(),
+            "type": field_type,
+        }))
+    }
+
+    fn data_type(&mut self, data_type: &DataType, path: &str) -> Option<Value> {
+        let primitive = match data_type {
+            DataType::Boolean => "boolean".to_string(),
+            DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::UInt8
+            | DataType::UInt16 => "int".to_string(),
+            DataType::Int64 | DataType::UInt32 | DataType::UInt64 => "long".to_string(),
+            DataType::Float16 | DataType::Float32 => "float".to_string(),
+            DataType::Float64 => "double".to_string(),
+            DataType::Utf8 | DataType::LargeUtf8 => "string".to_string(),
+            DataType::Binary | DataType::LargeBinary => "binary".to_string(),
+            DataType::FixedSizeBinary(length) => format!("fixed[{length}]"),
+            DataType::Date32 | DataType::Date64 => "date".to_string(),
+            DataType::Timestamp(_, None) => "timestamp".to_string(),
+            DataType::Timestamp(_, Some(_)) => "timestamptz".to_string(),
+            DataType::Decimal128(precision, scale) => format!("decimal({precision}, {scale})"),
+            DataType::List(element) | DataType::LargeList(element) => {
+                let element_path = format!("{path}.element");
+                let element_type = self.data_type(element.data_type(), &element_path)?;
+                return Some(json!({
+                    "type": "list",
+                    "element-id": self.id(&element_path),
+                    "element": element_type,
+                    "element-required": !element.is_nullable(),
+                }));
+            }
+            DataType::Struct(fields) => {
+                let fields = self.fields(fields, &format!("{path}."));
+                return Some(json!({"type": "struct", "fields": fields}));
+            }
+            DataType::Map(entries, _) => {
+                let DataType::Struct(entry_fields) = entries.data_type() else {
+                    return None;
+                };
+                let (key, value) = (entry_fields.first()?, entry_fields.get(1)?);
+                let key_path = format!("{path}.key");
+                let value_path = format!("{path}.value");
+                let key_type = self.data_type(key.data_type(), &key_path)?;
+                let value_type = self.data_type(value.data_type(), &value_path)?;
+                return Some(json!({
+                    "type": "map",
+                    "key-id": self.id(&key_path),
+                    "key": key_type,
+                    "value-id": self.id(&value_path),
+                    "value": value_type,
+                    "value-required": !value.is_nullable(),
+                }));
+            }
+            _ => return None,
+        };
+        Some(Value::String(primitive))
+    }
+}
+
+// maps the column names of the parquet files to the field ids
+fn name_mapping(fields: &[Value]) -> Vec<Value> {
+    fields
+        .iter()
+        .map(|field| {
+            let mut mapping = json!({
+                "field-id": field["id"],
+                "names": [field["name"]],
+            });
+            let nested = nested_mapping(&field["type"]);
+            if !nested.is_empty() {
+                mapping["fields"] = Value::Array(nested);
+            }
+            mapping
+        })
+        .collect()
+}
+
+fn nested_mapping(field_type: &Value) -> Vec<Value> {
+    let child = |id: &Value, name: &str, child_type: &Value| {
+        let mut mapping = json!({"field-id": id, "names": [name]});
+        let nested = nested_mapping(child_type);
+        if !nested.is_empty() {
+            mapping["fields"] = Value::Array(nested);
+        }
+        mapping
+    };
+    match field_type.get("type").and_then(Value::as_str) {
+        Some("struct") => name_mapping(
+            field_type["fields"]
+                .as_array()
+                .map_or(&[][..], Vec::as_slice),
+        ),
+        Some("list") => vec![child(
+            &field_type["element-id"],
+            "element",
+            &field_type["element"],
+        )],
+        Some("map") => vec![
+            child(&field_type["key-id"], "key", &field_type["key"]),
+            child(&field_type["value-id"], "value", &field_type["value"]),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IcebergError {
+    #[error("{0}")]
+    Storage(#[from] ObjectStorageError),
+    #[error("Invalid iceberg metadata: {0}")]
+    Schema(String),
+    #[error("Avro error: {0}")]
+    Avro(#[from] apache_avro::Error),
+    #[error("Serde error: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_schema::{DataType, Field, Fields, TimeUnit};
+    use serde_json::json;
+
+    use super::FieldIds;
+
+    #[test]
+    fn field_ids_are_kept_across_exports() {
+        let mut ids = FieldIds::default();
+        let fields = Fields::from(vec![
+            Field::new(
+                "p_timestamp",
+                DataType::Timestamp(TimeUnit::Millisecond, None),
+                false,
+            ),
+            Field::new("level", DataType::Utf8, true),
+        ]);
+        let exported = ids.fields(&fields, "");
+        assert_eq!(exported[0]["id"], json!(1));
+        assert_eq!(exported[0]["type"], json!("timestamp"));
+
+        // a later export of the schema with a new column
+        let mut next = FieldIds {
+            last_id: ids.last_id,
+            ..Default::default()
+        };
+        for field in &exported {
+            next.collect(field, "");
+        }
+        let fields = Fields::from(vec![
+            Field::new("host", DataType::Utf8, true),
+            Field::new("level", DataType::Utf8, true),
+        ]);
+        let exported = next.fields(&fields, "");
+        assert_eq!(exported[0]["id"], json!(3));
+        assert_eq!(exported[1]["id"], json!(2));
+    }
+}
@@ -25,20 +25,29 @@ use std::{
 use async_trait::async_trait;
 use bytes::Bytes;
 use datafusion::{datasource::listing::ListingTableUrl, execution::runtime_env::RuntimeConfig};
-use fs_extra::file::CopyOptions;
-use futures::{stream::FuturesUnordered, TryStreamExt};
+use futures::{
+    stream::{BoxStream, FuturesUnordered},
+    StreamExt, TryStreamExt,
+};
 use relative_path::{RelativePath, RelativePathBuf};
 use tokio::fs::{self, DirEntry};
 use tokio_stream::wrappers::ReadDirStream;
+use ulid::Ulid;
 
 use crate::metrics::storage::{localfs::REQUEST_RESPONSE_TIME, StorageMetrics};
 use crate::option::validation;
 
+use super::compression;
+use super::object_storage::LIST_FETCH_CONCURRENCY;
+use super::staging::encryption;
 use super::{
     LogStream, ObjectStorage, ObjectStorageError, ObjectStorageProvider, PARSEABLE_ROOT_DIRECTORY,
     SCHEMA_FILE_NAME, STREAM_METADATA_FILE_NAME, STREAM_ROOT_DIRECTORY,
 };
 
+// suffix of the files objects are written to before they are renamed into place
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+
 #[derive(Debug, Clone, clap::Args)]
 #[command(
     name = "Local filesystem config",
@@ -56,6 +65,37 @@ pub struct FSConfig {
         value_parser = validation::canonicalize_path
     )]
     pub root: PathBuf,
+
+    /// Writes flushed to the drive before they replace an object: never, metadata or always
+    #[arg(
+        long = "fs-fsync",
+        env = "P_FS_FSYNC",
+        value_name = "policy",
+        default_value = "metadata",
+        value_enum
+    )]
+    pub fsync: FsyncPolicy,
+}
+
+/// Writes flushed to the drive before they replace an object. Objects are always written to a
+/// temporary file which is renamed into place, so that a crash never leaves a truncated object,
+/// flushing makes sure the renamed object survives a power loss as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    Never,
+    /// stream metadata, manifests and other json objects, not parquet files
+    Metadata,
+    Always,
+}
+
+impl FsyncPolicy {
+    fn syncs(self, is_data: bool) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Metadata => !is_data,
+            Self::Always => true,
+        }
+    }
 }
 
 impl ObjectStorageProvider for FSConfig {
@@ -64,7 +104,7 @@ impl ObjectStorageProvider for FSConfig {
     }
 
     fn get_object_store(&self) -> Arc<dyn ObjectStorage + Send> {
-        Arc::new(LocalFS::new(self.root.clone()))
+        Arc::new(LocalFS::new(self.root.clone(), self.fsync))
     }
 
     fn get_endpoint(&self) -> String {
@@ -79,11 +119,12 @@ impl ObjectStorageProvider for FSConfig {
 pub struct LocalFS {
     // absolute path of the data directory
     root: PathBuf,
+    fsync: FsyncPolicy,
 }
 
 impl LocalFS {
-    pub fn new(root: PathBuf) -> Self {
-        Self { root }
+    pub fn new(root: PathBuf, fsync: FsyncPolicy) -> Self {
+        Self { root, fsync }
     }
 
     pub fn path_in_root(&self, path: &RelativePath) -> PathBuf {
@@ -97,7 +138,7 @@ impl ObjectStorage for LocalFS {
         let time = Instant::now();
         let file_path = self.path_in_root(path);
         let res: Result<Bytes, ObjectStorageError> = match fs::read(file_path).await {
-            Ok(x) => Ok(x.into()),
+            Ok(x) => compression::decode(x.into()).map_err(Into::into),
             Err(e) => match e.kind() {
                 std::io::ErrorKind::NotFound => {
                     Err(ObjectStorageError::NoSuchKey(path.to_string()))
@@ -123,13 +164,9 @@ impl ObjectStorage for LocalFS {
         let mut entries = fs::read_dir(&self.root).await?;
 
         while let Some(entry) = entries.next_entry().await? {
-            let flag = entry
-                .path()
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default()
-                .contains("ingester");
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+            let flag = name.contains("ingester") && !is_temp_file(name);
 
             if flag {
                 path_arr.push(
@@ -159,13 +196,9 @@ impl ObjectStorage for LocalFS {
         let mut entries = fs::read_dir(&stream_dir_path).await?;
 
         while let Some(entry) = entries.next_entry().await? {
-            let flag = entry
-                .path()
-                .file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default()
-                .contains("ingester");
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+            let flag = name.contains("ingester") && !is_temp_file(name);
 
             if flag {
                 path_arr.push(RelativePathBuf::from_iter([
@@ -189,48 +222,36 @@ impl ObjectStorage for LocalFS {
         Ok(path_arr)
     }
 
-    /// currently it is not using the starts_with_pattern
-    async fn get_objects(
+    fn stream_objects(
         &self,
-        base_path: Option<&RelativePath>,
-        filter_func: Box<(dyn Fn(String) -> bool + std::marker::Send + 'static)>,
-    ) -> Result<Vec<Bytes>, ObjectStorageError> {
-        let time = Instant::now();
-
-        let prefix = if let Some(path) = base_path {
-            path.to_path(&self.root)
-        } else {
-            self.root.clone()
-        };
-
-        let mut entries = fs::read_dir(&prefix).await?;
-        let mut res = Vec::new();
-        while let Some(entry) = entries.next_entry().await? {
-            let path = entry
-                .path()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
-            let ingester_file = filter_func(path);
-
-            if !ingester_file {
-                continue;
-            }
-
-            let file = fs::read(entry.path()).await?;
-            res.push(file.into());
-        }
-
-        // maybe change the return code
-        let status = if res.is_empty() { "200" } else { "400" };
-        let time = time.elapsed().as_secs_f64();
-        REQUEST_RESPONSE_TIME
-            .with_label_values(&["GET", status])
-            .observe(time);
-
-        Ok(res)
+        base_path: Option<RelativePathBuf>,
+        filter_func: Box<dyn Fn(String) -> bool + Send>,
+    ) -> BoxStream<'_, Result<(RelativePathBuf, Bytes), ObjectStorageError>> {
+        let base_path = base_path.unwrap_or_default();
+        let prefix = base_path.to_path(&self.root);
+
+        futures::stream::once(fs::read_dir(prefix))
+            .map_ok(ReadDirStream::new)
+            .try_flatten()
+            .map_err(ObjectStorageError::from)
+            .try_filter_map(move |entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let path = base_path.join(&name);
+                let matches = !is_temp_file(&name) && filter_func(name);
+                async move { Ok(matches.then(|| (path, entry.path()))) }
+            })
+            .map_ok(|(path, file_path)| async move {
+                let time = Instant::now();
+                let res = fs::read(file_path).await;
+                let status = if res.is_ok() { "200" } else { "400" };
+                REQUEST_RESPONSE_TIME
+                    .with_label_values(&["GET", status])
+                    .observe(time.elapsed().as_secs_f64());
+                let bytes = compression::decode(res?.into())?;
+                Ok::<_, ObjectStorageError>((path, bytes))
+            })
+            .try_buffered(LIST_FETCH_CONCURRENCY)
+            .boxed()
     }
 
     async fn put_object(
@@ -240,11 +261,15 @@ impl ObjectStorage for LocalFS {
     ) -> Result<(), ObjectStorageError> {
         let time = Instant::now();
 
+        let resource = compression::encode(path, resource);
         let path = self.path_in_root(path);
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent).await?;
-        }
-        let res = fs::write(path, resource).await;
+        let sync = self.fsync.syncs(false);
+        let res = tokio::task::spawn_blocking(move || {
+            write_atomic(&path, sync, |temp| std::fs::write(temp, &resource))
+        })
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|res| res);
 
         let status = if res.is_ok() { "200" } else { "400" };
         let time = time.elapsed().as_secs_f64();
@@ -354,16 +379,31 @@ impl ObjectStorage for LocalFS {
     }
 
     async fn upload_file(&self, key: &str, path: &Path) -> Result<(), ObjectStorageError> {
-        let op = CopyOptions {
-            overwrite: true,
-            skip_exist: true,
-            ..CopyOptions::default()
-        };
         let to_path = self.root.join(key);
-        if let Some(path) = to_path.parent() {
-            fs::create_dir_all(path).await?;
+        let from_path = path.to_owned();
+        let sync = self.fsync.syncs(true);
+        tokio::task::spawn_blocking(move || {
+            write_atomic(&to_path, sync, |temp| {
+                if encryption::enabled() {
+                    std::fs::write(temp, encryption::read(&from_path)?)
+                } else {
+                    std::fs::copy(&from_path, temp).map(|_| ())
+                }
+            })
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        Ok(())
+    }
+
+    async fn abort_abandoned_uploads(&self) -> Result<(), ObjectStorageError> {
+        let root = self.root.clone();
+        let removed = tokio::task::spawn_blocking(move || remove_temp_files(&root))
+            .await
+            .map_err(std::io::Error::other)??;
+        if removed > 0 {
+            log::warn!("removed {removed} temporary files of writes interrupted by a previous run");
         }
-        let _ = fs_extra::file::copy(path, to_path, &op)?;
         Ok(())
     }
 
@@ -482,8 +522,201 @@ async fn dir_name(entry: DirEntry) -> Result<Option<String>, ObjectStorageError>
     }
 }
 
-impl From<fs_extra::error::Error> for ObjectStorageError {
-    fn from(e: fs_extra::error::Error) -> Self {
-        ObjectStorageError::UnhandledError(Box::new(e))
+// writes an object through a temporary file in its directory which is renamed into place, a
+// crash leaves either the previous object or the complete new one
+fn write_atomic(
+    path: &Path,
+    sync: bool,
+    write: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let parent = path.parent().expect("objects are below the root directory");
+    std::fs::create_dir_all(parent)?;
+    // named unlike any object, listings filter temporary files out by name
+    let temp = parent.join(format!(".{}{TEMP_FILE_SUFFIX}", Ulid::new()));
+
+    let res = write(&temp).and_then(|()| {
+        if sync {
+            std::fs::File::open(&temp)?.sync_all()?;
+        }
+        std::fs::rename(&temp, path)?;
+        // the rename itself is durable once the directory is flushed
+        #[cfg(unix)]
+        if sync {
+            std::fs::File::open(parent)?.sync_all()?;
+        }
+        Ok(())
+    });
+    if res.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    res
+}
+
+fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(TEMP_FILE_SUFFIX)
+}
+
+// removes the temporary files below `dir` left behind by writes interrupted by a crash, they
+// are never renamed into place
+fn remove_temp_files(dir: &Path) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            removed += remove_temp_files(&entry.path())?;
+        } else if file_type.is_file() && is_temp_file(&entry.file_name().to_string_lossy()) {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use chrono::Utc;
+    use futures::TryStreamExt;
+    use relative_path::RelativePathBuf;
+
+    use crate::catalog::{ingester_manifests, snapshot::ManifestItem};
+    use crate::storage::{ObjectStorage, ObjectStoreFormat};
+
+    use super::{
+        is_temp_file, remove_temp_files, write_atomic, FsyncPolicy, LocalFS, LIST_FETCH_CONCURRENCY,
+    };
+
+    #[test]
+    fn failed_writes_keep_the_object() {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        let path = dir.join("stream").join(".stream.json");
+
+        write_atomic(&path, true, |temp| std::fs::write(temp, "{}")).unwrap();
+        let failed = write_atomic(&path, false, |temp| {
+            std::fs::write(temp, "{\"trunc")?;
+            Err(std::io::Error::other("crash"))
+        });
+
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+        // no temporary file is left behind
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_temporary_files_are_removed() {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        let stream = dir.join("stream").join(".stream");
+        std::fs::create_dir_all(&stream).unwrap();
+        std::fs::write(stream.join(".stream.json"), "{}").unwrap();
+        std::fs::write(stream.join(".01HQ.tmp"), "{\"trunc").unwrap();
+        std::fs::write(dir.join(".01HR.tmp"), "").unwrap();
+
+        assert!(is_temp_file(".01HQ.tmp"));
+        assert!(!is_temp_file(".stream.json"));
+        assert_eq!(remove_temp_files(&dir).unwrap(), 2);
+        assert_eq!(std::fs::read_dir(&stream).unwrap().count(), 1);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    fn ingester_metadata(manifests: &[u32]) -> Vec<u8> {
+        let mut meta = ObjectStoreFormat::default();
+        meta.snapshot.manifest_list = manifests
+            .iter()
+            .map(|date| ManifestItem {
+                manifest_path: format!("app/date=2024-01-{date:02}/manifest.json"),
+                time_lower_bound: Utc::now(),
+                time_upper_bound: Utc::now(),
+                column_stats: Default::default(),
+                num_files: 0,
+            })
+            .collect();
+        serde_json::to_vec(&meta).unwrap()
+    }
+
+    #[tokio::test]
+    async fn manifests_of_every_ingester_are_listed() {
+        let root = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        let dir = root.join("app").join(".stream");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(".ingester.01HQ.stream.json"),
+            ingester_metadata(&[1, 2]),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join(".ingester.01HR.stream.json"),
+            ingester_metadata(&[3]),
+        )
+        .unwrap();
+        // schemas of the ingesters and the metadata of the stream are not theirs
+        std::fs::write(dir.join(".ingester.01HQ.schema"), "{\"fields\": []}").unwrap();
+        std::fs::write(dir.join(".stream.json"), ingester_metadata(&[4])).unwrap();
+        let storage = LocalFS::new(root.clone(), FsyncPolicy::Never);
+
+        let mut paths: Vec<_> = ingester_manifests(&storage, "app")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.manifest_path)
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "app/date=2024-01-01/manifest.json",
+                "app/date=2024-01-02/manifest.json",
+                "app/date=2024-01-03/manifest.json",
+            ]
+        );
+
+        // an unreadable object fails the listing rather than dropping its manifests
+        std::fs::write(dir.join(".ingester.01HS.stream.json"), "{\"trunc").unwrap();
+        assert!(ingester_manifests(&storage, "app").await.is_err());
+        // so does a failed listing
+        assert!(ingester_manifests(&storage, "missing").await.is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn objects_passing_the_filter_are_listed() {
+        let root = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        let dir = root.join("app").join(".stream");
+        std::fs::create_dir_all(&dir).unwrap();
+        // more objects than are fetched at once
+        for i in 0..3 * LIST_FETCH_CONCURRENCY {
+            std::fs::write(dir.join(format!("{i}.json")), i.to_string()).unwrap();
+        }
+        std::fs::write(dir.join("other.txt"), "").unwrap();
+        std::fs::write(dir.join(".01HQ.tmp"), "").unwrap();
+        let storage = LocalFS::new(root.clone(), FsyncPolicy::Never);
+
+        let mut objects: Vec<_> = storage
+            .stream_objects(
+                Some(RelativePathBuf::from("app/.stream")),
+                Box::new(|name| !name.ends_with(".txt")),
+            )
+            .map_ok(|(path, bytes)| (path.to_string(), bytes))
+            .try_collect()
+            .await
+            .unwrap();
+        objects.sort();
+        let mut expected: Vec<_> = (0..3 * LIST_FETCH_CONCURRENCY)
+            .map(|i| (format!("app/.stream/{i}.json"), Bytes::from(i.to_string())))
+            .collect();
+        expected.sort();
+        assert_eq!(objects, expected);
+
+        let listed = storage
+            .stream_objects(Some(RelativePathBuf::from("missing")), Box::new(|_| true))
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(listed.is_err());
+        std::fs::remove_dir_all(root).unwrap();
     }
 }
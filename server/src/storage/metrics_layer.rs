@@ -1,4 +1,5 @@
 use std::{
+    error::Error as StdError,
     ops::Range,
     task::{Context, Poll},
     time,
@@ -12,16 +13,142 @@ use object_store::{
 };
 use tokio::io::AsyncWrite;
 
-use crate::metrics::storage::s3::QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME;
+use crate::metrics::storage::s3::{
+    QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME, STORAGE_BYTES, STORAGE_REQUESTS,
+    STORAGE_REQUEST_TIME,
+};
+
+/// Requests of the server itself, e.g. uploads and metadata
+pub const SERVER_LAYER: &str = "server";
+/// Requests of query scans
+pub const QUERY_LAYER: &str = "query";
+
+/// Class of a failed request, to tell problems of the storage backend from those of Parseable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    NotFound,
+    /// the object exists already or a precondition of the request failed
+    Conflict,
+    /// 429, or 503 which S3 answers with when it slows requests down
+    Throttled,
+    ServerError,
+    ClientError,
+    /// the request did not get a response, e.g. connection failures and timeouts
+    Network,
+    Other,
+}
+
+impl ErrorClass {
+    /// Class of an error of the object store, or of an error caused by one
+    pub fn of(err: &(dyn StdError + 'static)) -> Self {
+        let mut source = Some(err);
+        while let Some(err) = source {
+            match err.downcast_ref::<object_store::Error>() {
+                Some(object_store::Error::NotFound { .. }) => return Self::NotFound,
+                Some(
+                    object_store::Error::AlreadyExists { .. }
+                    | object_store::Error::Precondition { .. }
+                    | object_store::Error::NotModified { .. },
+                ) => return Self::Conflict,
+                _ => (),
+            }
+            if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+                if let Some(status) = err.status() {
+                    return Self::of_status(status.as_u16());
+                }
+                if err.is_timeout() || err.is_connect() || err.is_request() {
+                    return Self::Network;
+                }
+            }
+            // the source of an io error is that of the error it wraps
+            source = match err.downcast_ref::<std::io::Error>() {
+                Some(err) => err.get_ref().map(|err| err as &(dyn StdError + 'static)),
+                None => err.source(),
+            };
+        }
+
+        // statuses of errors without a response error are only in their message
+        let message = err.to_string();
+        if ["SlowDown", "Too Many Requests", "Service Unavailable"]
+            .iter()
+            .any(|reason| message.contains(reason))
+        {
+            Self::Throttled
+        } else if ["Internal Server Error", "Bad Gateway", "Gateway Timeout"]
+            .iter()
+            .any(|reason| message.contains(reason))
+        {
+            Self::ServerError
+        } else {
+            Self::Other
+        }
+    }
+
+    fn of_status(status: u16) -> Self {
+        match status {
+            404 => Self::NotFound,
+            409 | 412 => Self::Conflict,
+            429 | 503 => Self::Throttled,
+            500..=599 => Self::ServerError,
+            _ => Self::ClientError,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Conflict => "conflict",
+            Self::Throttled => "throttled",
+            Self::ServerError => "server_error",
+            Self::ClientError => "client_error",
+            Self::Network => "network",
+            Self::Other => "other",
+        }
+    }
+}
+
+fn outcome<R>(res: &object_store::Result<R>) -> &'static str {
+    match res {
+        Ok(_) => "ok",
+        Err(err) => ErrorClass::of(err).as_str(),
+    }
+}
 
 #[derive(Debug)]
 pub struct MetricLayer<T: ObjectStore> {
     inner: T,
+    layer: &'static str,
 }
 
 impl<T: ObjectStore> MetricLayer<T> {
-    pub fn new(inner: T) -> Self {
-        Self { inner }
+    /// Store whose requests are observed under the given layer, [`SERVER_LAYER`] or [`QUERY_LAYER`]
+    pub fn new(inner: T, layer: &'static str) -> Self {
+        Self { inner, layer }
+    }
+
+    fn observe<R>(&self, method: &'static str, time: time::Instant, res: &object_store::Result<R>) {
+        observe(self.layer, method, time, outcome(res));
+    }
+
+    fn count_bytes(&self, direction: &'static str, bytes: usize) {
+        STORAGE_BYTES
+            .with_label_values(&[self.layer, direction])
+            .inc_by(bytes as u64);
+    }
+}
+
+fn observe(layer: &'static str, method: &'static str, time: time::Instant, outcome: &str) {
+    let elapsed = time.elapsed().as_secs_f64();
+    STORAGE_REQUESTS
+        .with_label_values(&[layer, method, outcome])
+        .inc();
+    STORAGE_REQUEST_TIME
+        .with_label_values(&[layer, method, outcome])
+        .observe(elapsed);
+    if layer == QUERY_LAYER && outcome == "ok" {
+        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
+            .with_label_values(&[method, "200"])
+            .observe(elapsed);
     }
 }
 
@@ -35,12 +162,13 @@ impl<T: ObjectStore> std::fmt::Display for MetricLayer<T> {
 impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
     async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<()> {
         let time = time::Instant::now();
-        self.inner.put(location, bytes).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["PUT", "200"])
-            .observe(elapsed);
-        return Ok(());
+        let len = bytes.len();
+        let res = self.inner.put(location, bytes).await;
+        self.observe("PUT", time, &res);
+        if res.is_ok() {
+            self.count_bytes("written", len);
+        }
+        res
     }
 
     // todo completly tracking multipart upload
@@ -49,13 +177,9 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         location: &Path,
     ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
         let time = time::Instant::now();
-        let (id, write) = self.inner.put_multipart(location).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["PUT_MULTIPART", "200"])
-            .observe(elapsed);
-
-        Ok((id, write))
+        let res = self.inner.put_multipart(location).await;
+        self.observe("PUT_MULTIPART", time, &res);
+        res
     }
 
     async fn abort_multipart(
@@ -64,12 +188,9 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         multipart_id: &MultipartId,
     ) -> object_store::Result<()> {
         let time = time::Instant::now();
-        let elapsed = time.elapsed().as_secs_f64();
-        self.inner.abort_multipart(location, multipart_id).await?;
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["PUT_MULTIPART_ABORT", "200"])
-            .observe(elapsed);
-        Ok(())
+        let res = self.inner.abort_multipart(location, multipart_id).await;
+        self.observe("PUT_MULTIPART_ABORT", time, &res);
+        res
     }
 
     async fn append(
@@ -77,23 +198,19 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         location: &Path,
     ) -> object_store::Result<Box<dyn AsyncWrite + Unpin + Send>> {
         let time = time::Instant::now();
-        let write = self.inner.append(location).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["APPEND", "200"])
-            .observe(elapsed);
-
-        Ok(write)
+        let res = self.inner.append(location).await;
+        self.observe("APPEND", time, &res);
+        res
     }
 
     async fn get(&self, location: &Path) -> object_store::Result<GetResult> {
         let time = time::Instant::now();
-        let res = self.inner.get(location).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["GET", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.get(location).await;
+        self.observe("GET", time, &res);
+        if let Ok(res) = &res {
+            self.count_bytes("read", res.range.len());
+        }
+        res
     }
 
     async fn get_opts(
@@ -102,22 +219,22 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         options: GetOptions,
     ) -> object_store::Result<GetResult> {
         let time = time::Instant::now();
-        let res = self.inner.get_opts(location, options).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["GET_OPTS", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.get_opts(location, options).await;
+        self.observe("GET_OPTS", time, &res);
+        if let Ok(res) = &res {
+            self.count_bytes("read", res.range.len());
+        }
+        res
     }
 
     async fn get_range(&self, location: &Path, range: Range<usize>) -> object_store::Result<Bytes> {
         let time = time::Instant::now();
-        let res = self.inner.get_range(location, range).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["GET_RANGE", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.get_range(location, range).await;
+        self.observe("GET_RANGE", time, &res);
+        if let Ok(bytes) = &res {
+            self.count_bytes("read", bytes.len());
+        }
+        res
     }
 
     async fn get_ranges(
@@ -126,32 +243,26 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         ranges: &[Range<usize>],
     ) -> object_store::Result<Vec<Bytes>> {
         let time = time::Instant::now();
-        let res = self.inner.get_ranges(location, ranges).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["GET_RANGES", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.get_ranges(location, ranges).await;
+        self.observe("GET_RANGES", time, &res);
+        if let Ok(ranges) = &res {
+            self.count_bytes("read", ranges.iter().map(Bytes::len).sum());
+        }
+        res
     }
 
     async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
         let time = time::Instant::now();
-        let res = self.inner.head(location).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["HEAD", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.head(location).await;
+        self.observe("HEAD", time, &res);
+        res
     }
 
     async fn delete(&self, location: &Path) -> object_store::Result<()> {
         let time = time::Instant::now();
-        let res = self.inner.delete(location).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["DELETE", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.delete(location).await;
+        self.observe("DELETE", time, &res);
+        res
     }
 
     fn delete_stream<'a>(
@@ -166,13 +277,17 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         prefix: Option<&Path>,
     ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
         let time = time::Instant::now();
-        let inner = self.inner.list(prefix).await?;
-        let res = StreamMetricWrapper {
+        let inner = self.inner.list(prefix).await;
+        if inner.is_err() {
+            self.observe("LIST", time, &inner);
+        }
+        Ok(Box::pin(StreamMetricWrapper {
             time,
-            labels: ["LIST", "200"],
-            inner,
-        };
-        Ok(Box::pin(res))
+            layer: self.layer,
+            method: "LIST",
+            outcome: "ok",
+            inner: inner?,
+        }))
     }
 
     async fn list_with_offset(
@@ -181,74 +296,66 @@ impl<T: ObjectStore> ObjectStore for MetricLayer<T> {
         offset: &Path,
     ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
         let time = time::Instant::now();
-        let inner = self.inner.list_with_offset(prefix, offset).await?;
-        let res = StreamMetricWrapper {
+        let inner = self.inner.list_with_offset(prefix, offset).await;
+        if inner.is_err() {
+            self.observe("LIST_OFFSET", time, &inner);
+        }
+        Ok(Box::pin(StreamMetricWrapper {
             time,
-            labels: ["LIST_OFFSET", "200"],
-            inner,
-        };
-        Ok(Box::pin(res))
+            layer: self.layer,
+            method: "LIST_OFFSET",
+            outcome: "ok",
+            inner: inner?,
+        }))
     }
 
     async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
         let time = time::Instant::now();
-        let res = self.inner.list_with_delimiter(prefix).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["LIST_DELIM", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.list_with_delimiter(prefix).await;
+        self.observe("LIST_DELIM", time, &res);
+        res
     }
 
     async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
         let time = time::Instant::now();
-        let res = self.inner.copy(from, to).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["COPY", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.copy(from, to).await;
+        self.observe("COPY", time, &res);
+        res
     }
 
     async fn rename(&self, from: &Path, to: &Path) -> object_store::Result<()> {
         let time = time::Instant::now();
-        let res = self.inner.rename(from, to).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["RENAME", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.rename(from, to).await;
+        self.observe("RENAME", time, &res);
+        res
     }
 
     async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
         let time = time::Instant::now();
-        let res = self.inner.copy_if_not_exists(from, to).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["COPY_IF", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.copy_if_not_exists(from, to).await;
+        self.observe("COPY_IF", time, &res);
+        res
     }
 
     async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
         let time = time::Instant::now();
-        let res = self.inner.rename_if_not_exists(from, to).await?;
-        let elapsed = time.elapsed().as_secs_f64();
-        QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-            .with_label_values(&["RENAME_IF", "200"])
-            .observe(elapsed);
-        Ok(res)
+        let res = self.inner.rename_if_not_exists(from, to).await;
+        self.observe("RENAME_IF", time, &res);
+        res
     }
 }
 
-struct StreamMetricWrapper<'a, const N: usize, T> {
+// observes a listing once it is exhausted, as failed if any page of it failed
+struct StreamMetricWrapper<'a> {
     time: time::Instant,
-    labels: [&'static str; N],
-    inner: BoxStream<'a, T>,
+    layer: &'static str,
+    method: &'static str,
+    outcome: &'static str,
+    inner: BoxStream<'a, object_store::Result<ObjectMeta>>,
 }
 
-impl<T, const N: usize> Stream for StreamMetricWrapper<'_, N, T> {
-    type Item = T;
+impl Stream for StreamMetricWrapper<'_> {
+    type Item = object_store::Result<ObjectMeta>;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
@@ -256,12 +363,43 @@ impl<T, const N: usize> Stream for StreamMetricWrapper<'_, N, T> {
     ) -> Poll<Option<Self::Item>> {
         match self.inner.poll_next_unpin(cx) {
             t @ Poll::Ready(None) => {
-                QUERY_LAYER_STORAGE_REQUEST_RESPONSE_TIME
-                    .with_label_values(&self.labels)
-                    .observe(self.time.elapsed().as_secs_f64());
+                observe(self.layer, self.method, self.time, self.outcome);
                 t
             }
+            Poll::Ready(Some(Err(err))) => {
+                self.outcome = ErrorClass::of(&err).as_str();
+                Poll::Ready(Some(Err(err)))
+            }
             t => t,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorClass;
+
+    #[test]
+    fn errors_are_classified() {
+        let not_found = object_store::Error::NotFound {
+            path: "app/.stream/.stream.json".to_string(),
+            source: "not found".into(),
+        };
+        assert_eq!(ErrorClass::of(&not_found), ErrorClass::NotFound);
+
+        let slow_down = object_store::Error::Generic {
+            store: "S3",
+            source: "Received <Code>SlowDown</Code> after 10 retries".into(),
+        };
+        assert_eq!(ErrorClass::of(&slow_down), ErrorClass::Throttled);
+
+        let bad_gateway = object_store::Error::Generic {
+            store: "S3",
+            source: "Server returned 502 Bad Gateway".into(),
+        };
+        assert_eq!(ErrorClass::of(&bad_gateway), ErrorClass::ServerError);
+
+        assert_eq!(ErrorClass::of_status(503), ErrorClass::Throttled);
+        assert_eq!(ErrorClass::of_status(403), ErrorClass::ClientError);
+    }
+}
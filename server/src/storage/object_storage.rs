@@ -17,19 +17,24 @@
  */
 
 use super::{
-    retention::Retention, staging::convert_disk_files_to_parquet, LogStream, ObjectStorageError,
-    ObjectStoreFormat, Permisssion, StorageDir, StorageMetadata,
+    quirks::Quirks,
+    retention::Retention,
+    staging::{convert_streams_to_parquet, encryption, Conversion},
+    throttle::{retry_throttled, UPLOAD_LIMIT, UPLOAD_THROTTLE},
+    LogStream, ObjectStorageError, ObjectStoreFormat, Permisssion, StorageDir, StorageMetadata,
+    StreamSettings,
 };
 use super::{
-    ALERT_FILE_NAME, MANIFEST_FILE, PARSEABLE_METADATA_FILE_NAME, PARSEABLE_ROOT_DIRECTORY,
-    SCHEMA_FILE_NAME, STREAM_METADATA_FILE_NAME, STREAM_ROOT_DIRECTORY,
+    ALERT_FILE_NAME, PARSEABLE_METADATA_FILE_NAME, PARSEABLE_ROOT_DIRECTORY, SCHEMA_FILE_NAME,
+    SHARD_MAP_FILE_NAME, STREAM_METADATA_FILE_NAME, STREAM_ROOT_DIRECTORY,
 };
 
+use crate::handlers::http::modal::ingest_server;
 use crate::option::Mode;
-use crate::utils::get_address;
+use crate::utils::sigv4;
 use crate::{
     alerts::Alerts,
-    catalog::{self, manifest::Manifest, snapshot::Snapshot},
+    catalog::{self, snapshot::Snapshot},
     localcache::LocalCacheManager,
     metadata::STREAM_INFO,
     metrics::{storage::StorageMetrics, STORAGE_SIZE},
@@ -41,8 +46,14 @@ use actix_web_prometheus::PrometheusMetrics;
 use arrow_schema::Schema;
 use async_trait::async_trait;
 use bytes::Bytes;
+use datafusion::error::DataFusionError;
+use datafusion::execution::runtime_env::RuntimeEnv;
 use datafusion::{datasource::listing::ListingTableUrl, execution::runtime_env::RuntimeConfig};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
 use itertools::Itertools;
+use object_store::ObjectStore;
+use once_cell::sync::Lazy;
 use relative_path::RelativePath;
 use relative_path::RelativePathBuf;
 use serde_json::Value;
@@ -55,22 +66,82 @@ use std::{
     time::{Duration, Instant},
 };
 
+/// Objects fetched at once while a prefix is listed
+pub(super) const LIST_FETCH_CONCURRENCY: usize = 16;
+/// Prefixes listed at once
+const LIST_PREFIX_CONCURRENCY: usize = 8;
+
+static SYNC_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
 pub trait ObjectStorageProvider: StorageMetrics + std::fmt::Debug {
     fn get_datafusion_runtime(&self) -> RuntimeConfig;
     fn get_object_store(&self) -> Arc<dyn ObjectStorage + Send>;
     fn get_endpoint(&self) -> String;
     fn register_store_metrics(&self, handler: &PrometheusMetrics);
+
+    /// Store for another bucket of the same object storage, accessed with the same credentials
+    fn get_bucket_store(&self, _bucket: &str) -> Option<Arc<dyn ObjectStore>> {
+        None
+    }
+
+    /// Quirks of the object storage known from its configuration, see [`super::quirks::get`]
+    /// for those probed at start
+    fn quirks(&self) -> Quirks {
+        Quirks::default()
+    }
+
+    /// Region and access key of the object storage, to sign requests to other services of the
+    /// same cloud with
+    fn get_aws_credentials(&self) -> Option<(String, sigv4::Credentials)> {
+        None
+    }
+
+    /// Object store registered with datafusion, addressed with the paths used in manifests
+    fn get_datafusion_object_store(&self) -> Result<Arc<dyn ObjectStore>, DataFusionError> {
+        let runtime = RuntimeEnv::new(self.get_datafusion_runtime())?;
+        runtime
+            .object_store_registry
+            .get_store(&self.get_object_store().store_url())
+    }
 }
 
 #[async_trait]
 pub trait ObjectStorage: Sync + 'static {
     async fn get_object(&self, path: &RelativePath) -> Result<Bytes, ObjectStorageError>;
+    /// Objects below the prefix whose file name passes the filter, in listing order. The prefix
+    /// is listed a page at a time and matching objects are fetched concurrently as the stream
+    /// is polled, so that callers do not hold every object of a large prefix at once.
+    fn stream_objects(
+        &self,
+        base_path: Option<RelativePathBuf>,
+        filter_fun: Box<dyn Fn(String) -> bool + Send>,
+    ) -> BoxStream<'_, Result<(RelativePathBuf, Bytes), ObjectStorageError>>;
+    /// Objects below any of the prefixes whose file name passes the filter. Prefixes are listed
+    /// concurrently and their objects are interleaved.
+    fn stream_objects_in(
+        &self,
+        prefixes: Vec<RelativePathBuf>,
+        filter_fun: Arc<dyn Fn(String) -> bool + Send + Sync>,
+    ) -> BoxStream<'_, Result<(RelativePathBuf, Bytes), ObjectStorageError>> {
+        futures::stream::iter(prefixes)
+            .map(move |prefix| {
+                let filter_fun = filter_fun.clone();
+                self.stream_objects(Some(prefix), Box::new(move |name| filter_fun(name)))
+            })
+            .flatten_unordered(LIST_PREFIX_CONCURRENCY)
+            .boxed()
+    }
     // want to make it more generic with a filter function
     async fn get_objects(
         &self,
         base_path: Option<&RelativePath>,
         filter_fun: Box<dyn Fn(String) -> bool + Send>,
-    ) -> Result<Vec<Bytes>, ObjectStorageError>;
+    ) -> Result<Vec<Bytes>, ObjectStorageError> {
+        self.stream_objects(base_path.map(ToOwned::to_owned), filter_fun)
+            .map_ok(|(_, bytes)| bytes)
+            .try_collect()
+            .await
+    }
     async fn put_object(
         &self,
         path: &RelativePath,
@@ -84,6 +155,10 @@ pub trait ObjectStorage: Sync + 'static {
     async fn list_dirs(&self) -> Result<Vec<String>, ObjectStorageError>;
     async fn list_dates(&self, stream_name: &str) -> Result<Vec<String>, ObjectStorageError>;
     async fn upload_file(&self, key: &str, path: &Path) -> Result<(), ObjectStorageError>;
+    /// Abort uploads left incomplete by a previous run, multipart uploads or temporary files
+    async fn abort_abandoned_uploads(&self) -> Result<(), ObjectStorageError> {
+        Ok(())
+    }
     async fn delete_object(&self, path: &RelativePath) -> Result<(), ObjectStorageError>;
     async fn get_ingester_meta_file_paths(
         &self,
@@ -127,6 +202,7 @@ pub trait ObjectStorage: Sync + 'static {
         &self,
         stream_name: &str,
         time_partition: &str,
+        custom_partition: &str,
         static_schema_flag: &str,
         schema: Arc<Schema>,
     ) -> Result<(), ObjectStorageError> {
@@ -139,6 +215,9 @@ pub trait ObjectStorage: Sync + 'static {
         } else {
             format.time_partition = Some(time_partition.to_string());
         }
+        if !custom_partition.is_empty() {
+            format.custom_partition = Some(custom_partition.to_string());
+        }
         if static_schema_flag != "true" {
             format.static_schema_flag = None;
         } else {
@@ -283,6 +362,24 @@ pub trait ObjectStorage: Sync + 'static {
         Ok(serde_json::from_slice(&stream_metadata).expect("parseable config is valid json"))
     }
 
+    /// settings of a stream as last set through the query server, from the metadata shared by
+    /// all nodes rather than the copy of this ingester
+    async fn get_stream_settings(
+        &self,
+        stream_name: &str,
+    ) -> Result<StreamSettings, ObjectStorageError> {
+        let bytes = self
+            .get_object(&RelativePathBuf::from_iter([
+                stream_name,
+                STREAM_ROOT_DIRECTORY,
+                STREAM_METADATA_FILE_NAME,
+            ]))
+            .await?;
+        let meta = serde_json::from_slice::<ObjectStoreFormat>(&bytes)
+            .expect("parseable config is valid json");
+        Ok(StreamSettings::of(&meta))
+    }
+
     async fn put_stream_manifest(
         &self,
         stream_name: &str,
@@ -308,18 +405,6 @@ pub trait ObjectStorage: Sync + 'static {
         Ok(stats)
     }
 
-    async fn get_stats(&self, stream_name: &str) -> Result<Stats, ObjectStorageError> {
-        let stream_metadata = self.get_object(&stream_json_path(stream_name)).await?;
-        let stream_metadata: Value =
-            serde_json::from_slice(&stream_metadata).expect("parseable config is valid json");
-
-        let stats = &stream_metadata["stats"];
-
-        let stats = serde_json::from_value(stats.clone()).unwrap_or_default();
-
-        Ok(stats)
-    }
-
     async fn get_retention(&self, stream_name: &str) -> Result<Retention, ObjectStorageError> {
         let stream_metadata = self.get_object(&stream_json_path(stream_name)).await?;
         let stream_metadata: Value =
@@ -364,35 +449,6 @@ pub trait ObjectStorage: Sync + 'static {
         }
     }
 
-    // get the manifest info
-    async fn get_manifest(
-        &self,
-        path: &RelativePath,
-    ) -> Result<Option<Manifest>, ObjectStorageError> {
-        let path = manifest_path(path.as_str());
-        match self.get_object(&path).await {
-            Ok(bytes) => Ok(Some(
-                serde_json::from_slice(&bytes).expect("manifest is valid json"),
-            )),
-            Err(err) => {
-                if matches!(err, ObjectStorageError::NoSuchKey(_)) {
-                    Ok(None)
-                } else {
-                    Err(err)
-                }
-            }
-        }
-    }
-
-    async fn put_manifest(
-        &self,
-        path: &RelativePath,
-        manifest: Manifest,
-    ) -> Result<(), ObjectStorageError> {
-        let path = manifest_path(path.as_str());
-        self.put_object(&path, to_bytes(&manifest)).await
-    }
-
     // gets the snapshot of the stream
     async fn get_object_store_format(
         &self,
@@ -420,22 +476,53 @@ pub trait ObjectStorage: Sync + 'static {
         }
 
         let streams = STREAM_INFO.list_streams();
+        self.sync_streams(&streams).await
+    }
+
+    // convert staging files of given streams to parquet and upload them
+    async fn sync_streams(&self, streams: &[String]) -> Result<(), ObjectStorageError> {
+        // scheduled sync and manual flush should not convert the same files at once
+        let _guard = SYNC_LOCK.lock().await;
+
         let mut stream_stats = HashMap::new();
 
         let cache_manager = LocalCacheManager::global();
         let mut cache_updates: HashMap<&String, Vec<_>> = HashMap::new();
 
-        for stream in &streams {
-            let cache_enabled = STREAM_INFO
-                .cache_enabled(stream)
-                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+        let mut conversions = Vec::with_capacity(streams.len());
+        for stream in streams {
             let time_partition = STREAM_INFO
                 .get_time_partition(stream)
                 .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
-            let dir = StorageDir::new(stream);
-            let schema = convert_disk_files_to_parquet(stream, &dir, time_partition)
+            let sort_keys = STREAM_INFO
+                .get_sort_keys(stream)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+            let custom_partition = STREAM_INFO
+                .get_custom_partition(stream)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+            conversions.push(Conversion {
+                stream: stream.to_owned(),
+                time_partition,
+                custom_partition,
+                sort_keys,
+            });
+        }
+        let mut schemas =
+            tokio::task::spawn_blocking(move || convert_streams_to_parquet(&conversions))
+                .await
                 .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
 
+        for stream in streams {
+            let cache_enabled = STREAM_INFO
+                .cache_enabled(stream)
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?;
+            let dir = StorageDir::new(stream);
+            let schema = schemas
+                .remove(stream)
+                .transpose()
+                .map_err(|err| ObjectStorageError::UnhandledError(Box::new(err)))?
+                .flatten();
+
             if let Some(schema) = schema {
                 let static_schema_flag = STREAM_INFO
                     .get_static_schema_flag(stream)
@@ -454,23 +541,58 @@ pub trait ObjectStorage: Sync + 'static {
                     .or_insert_with(|| compressed_size);
             });
 
-            for file in parquet_files {
+            let uploads = parquet_files.into_iter().map(|file| async move {
                 let filename = file
                     .file_name()
                     .expect("only parquet files are returned by iterator")
                     .to_str()
                     .expect("filename is valid string");
-                let file_suffix = str::replacen(filename, ".", "/", 3);
-                let stream_relative_path = format!("{stream}/{file_suffix}");
-                self.upload_file(&stream_relative_path, &file).await?;
+                let stream_relative_path =
+                    format!("{stream}/{}", StorageDir::parquet_object_path(filename));
+                UPLOAD_THROTTLE
+                    .acquire(file.metadata().map_or(0, |meta| meta.len()))
+                    .await;
+                // throttled uploads are retried at a lower concurrency rather than failing the sync
+                retry_throttled(&UPLOAD_LIMIT, || {
+                    self.upload_file(&stream_relative_path, &file)
+                })
+                .await?;
+                Ok::<_, ObjectStorageError>((stream_relative_path, file))
+            });
+            let uploaded: Vec<_> = futures::stream::iter(uploads)
+                .buffer_unordered(CONFIG.parseable.upload_concurrency.max(1))
+                .collect()
+                .await;
+
+            // files that did get uploaded are still committed before failing
+            let mut upload_error = None;
+            let mut committed = Vec::new();
+            let mut changes = Vec::new();
+            for res in uploaded {
+                let (stream_relative_path, file) = match res {
+                    Ok(uploaded) => uploaded,
+                    Err(err) => {
+                        upload_error.get_or_insert(err);
+                        continue;
+                    }
+                };
                 let absolute_path = self
                     .absolute_url(RelativePath::from_path(&stream_relative_path).unwrap())
                     .to_string();
-                let store = CONFIG.storage().get_object_store();
                 let manifest =
                     catalog::create_from_parquet_file(absolute_path.clone(), &file).unwrap();
-                catalog::update_snapshot(store, stream, manifest).await?;
-                if cache_enabled && cache_manager.is_some() {
+                changes.push(manifest);
+                committed.push((absolute_path, file));
+            }
+
+            // all files uploaded by this sync become visible in a single snapshot, staged files
+            // are kept until then so that a failed commit is retried on the next sync
+            let store = CONFIG.storage().get_object_store();
+            catalog::update_snapshot(store, stream, changes).await?;
+            // the cache is read by queries as it is, encrypted staging files are not kept there
+            let cache_files = cache_enabled && cache_manager.is_some() && !encryption::enabled();
+            for (absolute_path, file) in committed {
+                if cache_files {
                     cache_updates
                         .entry(stream)
                         .or_default()
@@ -479,6 +601,10 @@ pub trait ObjectStorage: Sync + 'static {
                     let _ = fs::remove_file(file);
                 }
             }
+
+            if let Some(err) = upload_error {
+                return Err(err);
+            }
         }
 
         for (stream, compressed_size) in stream_stats {
@@ -539,8 +665,11 @@ fn to_bytes(any: &(impl ?Sized + serde::Serialize)) -> Bytes {
 fn schema_path(stream_name: &str) -> RelativePathBuf {
     match CONFIG.parseable.mode {
         Mode::Ingest => {
-            let (ip, port) = get_address();
-            let file_name = format!(".ingester.{}.{}{}", ip, port, SCHEMA_FILE_NAME);
+            let file_name = format!(
+                "{}{}",
+                ingester_file_prefix(&own_ingester_id()),
+                SCHEMA_FILE_NAME
+            );
 
             RelativePathBuf::from_iter([stream_name, STREAM_ROOT_DIRECTORY, &file_name])
         }
@@ -552,20 +681,31 @@ fn schema_path(stream_name: &str) -> RelativePathBuf {
 
 #[inline(always)]
 pub fn stream_json_path(stream_name: &str) -> RelativePathBuf {
+    RelativePathBuf::from_iter([stream_name, STREAM_ROOT_DIRECTORY, &stream_json_file_name()])
+}
+
+/// file name of the stream metadata this node writes, ingesters write their own copy
+pub fn stream_json_file_name() -> String {
     match &CONFIG.parseable.mode {
-        Mode::Ingest => {
-            let (ip, port) = get_address();
-            let file_name = format!(".ingester.{}.{}{}", ip, port, STREAM_METADATA_FILE_NAME);
-            RelativePathBuf::from_iter([stream_name, STREAM_ROOT_DIRECTORY, &file_name])
-        }
-        Mode::Query | Mode::All => RelativePathBuf::from_iter([
-            stream_name,
-            STREAM_ROOT_DIRECTORY,
-            STREAM_METADATA_FILE_NAME,
-        ]),
+        Mode::Ingest => format!(
+            "{}{}",
+            ingester_file_prefix(&own_ingester_id()),
+            STREAM_METADATA_FILE_NAME
+        ),
+        Mode::Query | Mode::All => STREAM_METADATA_FILE_NAME.to_string(),
     }
 }
 
+/// prefix of the schema and stream metadata an ingester writes next to those of the stream
+pub fn ingester_file_prefix(ingester_id: &str) -> String {
+    format!(".ingester.{ingester_id}")
+}
+
+// the id is read from staging before anything is written to the object store
+fn own_ingester_id() -> String {
+    ingest_server::get_ingester_id().expect("ingester id is set up at start")
+}
+
 /// path will be ".parseable/.parsable.json"
 #[inline(always)]
 pub fn parseable_json_path() -> RelativePathBuf {
@@ -573,21 +713,26 @@ pub fn parseable_json_path() -> RelativePathBuf {
 }
 
 #[inline(always)]
-fn alert_json_path(stream_name: &str) -> RelativePathBuf {
+pub fn alert_json_path(stream_name: &str) -> RelativePathBuf {
     RelativePathBuf::from_iter([stream_name, ALERT_FILE_NAME])
 }
 
 #[inline(always)]
-fn manifest_path(prefix: &str) -> RelativePathBuf {
-    let addr = get_address();
-    let mainfest_file_name = format!("{}.{}.{}", addr.0, addr.1, MANIFEST_FILE);
-    RelativePathBuf::from_iter([prefix, &mainfest_file_name])
-}
-
-#[inline(always)]
-pub fn ingester_metadata_path(ip: String, port: String) -> RelativePathBuf {
+pub fn ingester_metadata_path(ingester_id: &str) -> RelativePathBuf {
     RelativePathBuf::from_iter([
         PARSEABLE_ROOT_DIRECTORY,
-        &format!("ingester.{}.{}.json", ip, port),
+        &format!("ingester.{ingester_id}.json"),
     ])
 }
+
+/// path will be ".parseable/stream_shards.json"
+#[inline(always)]
+pub fn shard_map_path() -> RelativePathBuf {
+    RelativePathBuf::from_iter([PARSEABLE_ROOT_DIRECTORY, SHARD_MAP_FILE_NAME])
+}
+
+/// id under which metadata written before ingesters had an id is stored
+#[inline(always)]
+pub fn legacy_ingester_id(host: &str, port: &str) -> String {
+    format!("{host}.{port}")
+}
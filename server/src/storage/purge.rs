@@ -0,0 +1,443 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use datafusion::arrow::record_batch::{RecordBatch, RecordBatchReader};
+use datafusion::common::SchemaError;
+use datafusion::datasource::MemTable;
+use datafusion::error::DataFusionError;
+use datafusion::prelude::{SessionConfig, SessionContext};
+use datafusion::sql::sqlparser::dialect::GenericDialect;
+use datafusion::sql::sqlparser::parser::Parser;
+use datafusion::sql::sqlparser::tokenizer::Token;
+use object_store::path::Path;
+use once_cell::sync::Lazy;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::file::properties::{WriterProperties, WriterPropertiesBuilder};
+use ulid::Ulid;
+
+use crate::catalog::{
+    self,
+    manifest::{self, Manifest},
+};
+use crate::localcache::{CacheError, LocalCacheManager};
+use crate::option::CONFIG;
+use crate::stats::{self, Stats};
+use crate::storage::{compression, ObjectStorageError};
+
+const PURGE_TABLE_NAME: &str = "purge";
+
+static PURGE_JOBS: Lazy<RwLock<HashMap<Ulid, PurgeStatus>>> = Lazy::new(RwLock::default);
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PurgeState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of a purge job, polled through the api
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeStatus {
+    pub id: Ulid,
+    pub stream: String,
+    pub predicate: String,
+    pub state: PurgeState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub total_files: usize,
+    pub scanned_files: usize,
+    pub rewritten_files: usize,
+    pub deleted_rows: u64,
+    pub started_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completed_at: Option<DateTime<Utc>>,
+}
+
+pub fn purge_status(id: Ulid) -> Option<PurgeStatus> {
+    PURGE_JOBS.read().unwrap().get(&id).cloned()
+}
+
+fn update_status(id: Ulid, f: impl FnOnce(&mut PurgeStatus)) {
+    if let Some(status) = PURGE_JOBS.write().unwrap().get_mut(&id) {
+        f(status)
+    }
+}
+
+/// Check that the predicate is a single sql expression
+pub fn validate_predicate(predicate: &str) -> Result<(), PurgeError> {
+    let mut parser = Parser::new(&GenericDialect {})
+        .try_with_sql(predicate)
+        .map_err(|err| PurgeError::InvalidPredicate(err.to_string()))?;
+    parser
+        .parse_expr()
+        .map_err(|err| PurgeError::InvalidPredicate(err.to_string()))?;
+
+    if parser.peek_token().token != Token::EOF {
+        return Err(PurgeError::InvalidPredicate(
+            "predicate must be a single expression".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Start a purge of the rows matching `predicate` from the stream.
+/// Only one purge can run for a stream at a time.
+pub fn start_purge(
+    stream_name: &str,
+    predicate: String,
+    manifest_paths: Vec<String>,
+) -> Result<Ulid, PurgeError> {
+    let id = Ulid::new();
+    {
+        let mut jobs = PURGE_JOBS.write().unwrap();
+        if jobs
+            .values()
+            .any(|job| job.stream == stream_name && job.state == PurgeState::Running)
+        {
+            return Err(PurgeError::AlreadyRunning(stream_name.to_owned()));
+        }
+
+        jobs.insert(
+            id,
+            PurgeStatus {
+                id,
+                stream: stream_name.to_owned(),
+                predicate: predicate.clone(),
+                state: PurgeState::Running,
+                error: None,
+                total_files: 0,
+                scanned_files: 0,
+                rewritten_files: 0,
+                deleted_rows: 0,
+                started_at: Utc::now(),
+                completed_at: None,
+            },
+        );
+    }
+
+    let stream_name = stream_name.to_owned();
+    tokio::spawn(async move {
+        let res = run_purge(id, &stream_name, &predicate, manifest_paths).await;
+        if let Err(err) = &res {
+            log::error!("purge {id} failed: {err}");
+        }
+        update_status(id, |status| {
+            match res {
+                Ok(()) => status.state = PurgeState::Completed,
+                Err(err) => {
+                    status.state = PurgeState::Failed;
+                    status.error = Some(err.to_string());
+                }
+            }
+            status.completed_at = Some(Utc::now());
+        });
+    });
+
+    Ok(id)
+}
+
+// Files are not changed once a snapshot refers to them, the rewritten files are written under
+// new names and replace the originals in a new snapshot. The originals are removed once the
+// snapshots referring to them expire.
+async fn run_purge(
+    id: Ulid,
+    stream_name: &str,
+    predicate: &str,
+    manifest_paths: Vec<String>,
+) -> Result<(), PurgeError> {
+    let store = CONFIG.storage().get_datafusion_object_store()?;
+
+    let mut manifests = Vec::with_capacity(manifest_paths.len());
+    for manifest_path in manifest_paths {
+        let path = Path::parse(manifest_path)?;
+        let manifest: Manifest = serde_json::from_slice(&compression::read(&*store, &path).await?)?;
+        manifests.push(manifest);
+    }
+
+    let total_files = manifests.iter().map(|m| m.files.len()).sum();
+    update_status(id, |status| status.total_files = total_files);
+
+    let mut replacements = HashMap::new();
+    let mut removed = Stats::default();
+    for file in manifests.into_iter().flat_map(|manifest| manifest.files) {
+        let path = Path::parse(&file.file_path)?;
+        let data = store.get(&path).await?.bytes().await?;
+
+        if let Some((bytes, num_rows)) = purge_rows(data, predicate, writer_properties()).await? {
+            let deleted_rows = file.num_rows.saturating_sub(num_rows);
+            let replacement = match bytes {
+                Some(bytes) => {
+                    let rewritten_path = rewritten_path(&path);
+                    store.put(&rewritten_path, bytes.clone()).await?;
+                    Some(manifest::create_from_parquet_bytes(
+                        rewritten_path.to_string(),
+                        bytes,
+                    )?)
+                }
+                // every row matched, the file is dropped
+                None => None,
+            };
+            let (ingestion, storage) = replacement
+                .as_ref()
+                .map_or((0, 0), |file| (file.ingestion_size, file.file_size));
+            removed.events += deleted_rows;
+            removed.ingestion += file.ingestion_size.saturating_sub(ingestion);
+            removed.storage += file.file_size.saturating_sub(storage);
+            replacements.insert(file.file_path, replacement);
+            update_status(id, |status| {
+                status.rewritten_files += 1;
+                status.deleted_rows += deleted_rows;
+            });
+        }
+        update_status(id, |status| status.scanned_files += 1);
+    }
+
+    let replaced_files: Vec<String> = replacements.keys().cloned().collect();
+    let storage = CONFIG.storage().get_object_store();
+    catalog::replace_files(storage.clone(), stream_name, replacements).await?;
+
+    stats::remove_stats(stream_name, "json", removed);
+    if let Some(stats) = stats::get_current_stats(stream_name, "json") {
+        storage.put_stats(stream_name, &stats).await?;
+    }
+    // the purged rows must not be served from a copy of the replaced files
+    if let Some(cache_manager) = LocalCacheManager::global() {
+        cache_manager
+            .remove_from_cache(stream_name, &replaced_files)
+            .await?;
+    }
+    Ok(())
+}
+
+fn writer_properties() -> WriterPropertiesBuilder {
+    WriterProperties::builder()
+        .set_max_row_group_size(CONFIG.parseable.row_group_size)
+        .set_compression(CONFIG.parseable.parquet_compression.into())
+}
+
+// path of the file rewritten from the one at `path`, next to it
+fn rewritten_path(path: &Path) -> Path {
+    let file_name = path.filename().unwrap_or_default();
+    let stem = file_name.strip_suffix(".parquet").unwrap_or(file_name);
+    let parent: Path = path.parts().take(path.parts().count() - 1).collect();
+    parent.child(format!("{stem}.{}.parquet", Ulid::new()))
+}
+
+/// Remove the rows matching `predicate` from a parquet file. Returns None if no row matched,
+/// otherwise the rewritten file (None if every row matched) and its row count.
+async fn purge_rows(
+    data: Bytes,
+    predicate: &str,
+    props: WriterPropertiesBuilder,
+) -> Result<Option<(Option<Bytes>, u64)>, PurgeError> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(data)?;
+    // rows keep their order, so the rewritten file is sorted as the original is
+    let sorting_columns = builder
+        .metadata()
+        .row_groups()
+        .first()
+        .and_then(|row_group| row_group.sorting_columns().cloned());
+    let reader = builder.build()?;
+    let schema = reader.schema();
+    let batches = reader.collect::<Result<Vec<_>, _>>()?;
+    let num_rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+
+    let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(1));
+    let table = MemTable::try_new(schema.clone(), vec![batches])?;
+    ctx.register_table(PURGE_TABLE_NAME, Arc::new(table))?;
+
+    // IS NOT TRUE keeps rows for which the predicate evaluates to null
+    let query = format!("SELECT * FROM {PURGE_TABLE_NAME} WHERE ({predicate}) IS NOT TRUE");
+    let retained = match ctx.sql(&query).await {
+        Ok(df) => df.collect().await?,
+        // predicate refers to a column this file does not have
+        Err(DataFusionError::SchemaError(SchemaError::FieldNotFound { .. })) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let retained_rows: usize = retained.iter().map(RecordBatch::num_rows).sum();
+    if retained_rows == num_rows {
+        return Ok(None);
+    }
+    if retained_rows == 0 {
+        return Ok(Some((None, 0)));
+    }
+
+    let props = props.set_sorting_columns(sorting_columns).build();
+    let mut buffer = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut buffer, schema, Some(props))?;
+    for batch in &retained {
+        writer.write(batch)?;
+    }
+    writer.close()?;
+
+    Ok(Some((Some(buffer.into()), retained_rows as u64)))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PurgeError {
+    #[error("Invalid purge predicate: {0}")]
+    InvalidPredicate(String),
+    #[error("A purge is already running for log stream {0}")]
+    AlreadyRunning(String),
+    #[error("Object store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+    #[error("Invalid object path: {0}")]
+    Path(#[from] object_store::path::Error),
+    #[error("DataFusion error: {0}")]
+    DataFusion(#[from] DataFusionError),
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] datafusion::arrow::error::ArrowError),
+    #[error("Invalid manifest: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("Storage error: {0}")]
+    Storage(#[from] ObjectStorageError),
+    #[error("Cache error: {0}")]
+    Cache(#[from] CacheError),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use datafusion::arrow::array::{Array, Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use object_store::path::Path;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+
+    use super::{purge_rows, rewritten_path, validate_predicate};
+
+    fn parquet_file() -> Bytes {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("level", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3, 4])),
+                Arc::new(StringArray::from(vec![
+                    Some("debug"),
+                    Some("info"),
+                    None,
+                    Some("debug"),
+                ])),
+            ],
+        )
+        .unwrap();
+        let mut buffer = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut buffer, schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+        buffer.into()
+    }
+
+    fn ids(data: Bytes) -> Vec<i64> {
+        ParquetRecordBatchReaderBuilder::try_new(data)
+            .unwrap()
+            .build()
+            .unwrap()
+            .flat_map(|batch| {
+                let batch = batch.unwrap();
+                let ids = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<Int64Array>()
+                    .unwrap()
+                    .clone();
+                ids.values().to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn predicate_must_be_single_expression() {
+        assert!(validate_predicate("user_id = 'x'").is_ok());
+        assert!(validate_predicate("user_id = 'x' AND level IN ('info', 'debug')").is_ok());
+        assert!(validate_predicate("user_id = 'x'; DROP TABLE t").is_err());
+        assert!(validate_predicate("").is_err());
+    }
+
+    #[test]
+    fn rewritten_files_are_named_anew() {
+        let path = Path::from("app/date=2024-01-01/hour=10/minute=05/10.0.0.1.8000.data.parquet");
+        let rewritten = rewritten_path(&path);
+        let name = rewritten.filename().unwrap();
+
+        assert_ne!(rewritten, path);
+        assert!(rewritten
+            .as_ref()
+            .starts_with("app/date=2024-01-01/hour=10/minute=05/10.0.0.1.8000.data."));
+        assert!(name.ends_with(".parquet"));
+    }
+
+    #[tokio::test]
+    async fn matching_rows_are_removed_from_the_rewritten_file() {
+        let (rewritten, num_rows) = purge_rows(
+            parquet_file(),
+            "level = 'debug'",
+            WriterProperties::builder(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        // the row with a null level is kept, the predicate is not true for it
+        assert_eq!(num_rows, 2);
+        assert_eq!(ids(rewritten.unwrap()), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn files_without_matches_are_left_alone() {
+        let purged = purge_rows(
+            parquet_file(),
+            "level = 'error'",
+            WriterProperties::builder(),
+        )
+        .await
+        .unwrap();
+        assert!(purged.is_none());
+
+        let purged = purge_rows(parquet_file(), "user_id = 'x'", WriterProperties::builder())
+            .await
+            .unwrap();
+        assert!(purged.is_none());
+    }
+
+    #[tokio::test]
+    async fn files_with_every_row_matched_are_dropped() {
+        let purged = purge_rows(parquet_file(), "id > 0", WriterProperties::builder())
+            .await
+            .unwrap();
+        assert!(matches!(purged, Some((None, 0))));
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Behavior of S3 compatible object storage which differs from AWS S3. The provider, set with
+//! `P_S3_PROVIDER` or told from the endpoint, gives the quirks assumed at start. A probe run
+//! against the bucket at start, and by `parseable check-config`, refines them with what the
+//! store actually does.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use object_store::{path::Path, BackoffConfig, ObjectStore};
+use once_cell::sync::OnceCell;
+use relative_path::RelativePathBuf;
+use ulid::Ulid;
+
+use super::{ObjectStorage, PARSEABLE_ROOT_DIRECTORY};
+use crate::option::CONFIG;
+
+// how long the probe waits for a new object to be listed
+const PROBE_LIST_TIMEOUT: Duration = Duration::from_secs(10);
+const PROBE_LIST_INTERVAL: Duration = Duration::from_millis(250);
+// assumed of stores which were not probed yet, nor are known to list new objects right away
+const UNKNOWN_LIST_AFTER_WRITE_DELAY: Duration = Duration::from_secs(5);
+
+static QUIRKS: OnceCell<Quirks> = OnceCell::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum S3Provider {
+    /// told from the endpoint
+    Auto,
+    Aws,
+    Minio,
+    Ceph,
+    Wasabi,
+    R2,
+    Other,
+}
+
+impl S3Provider {
+    /// The provider, told from the host of the endpoint if it is `auto`. Self hosted stores,
+    /// e.g. MinIO or Ceph, can not be told apart and are `other`.
+    pub fn resolve(self, endpoint_url: &str) -> Self {
+        if self != Self::Auto {
+            return self;
+        }
+        let host = url::Url::parse(endpoint_url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+            .unwrap_or_default();
+        if host.ends_with("amazonaws.com") {
+            Self::Aws
+        } else if host.ends_with("r2.cloudflarestorage.com") {
+            Self::R2
+        } else if host.ends_with("wasabisys.com") {
+            Self::Wasabi
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Quirks assumed of the provider until the store is probed
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Self::Aws | Self::Minio | Self::Wasabi | Self::R2 => Quirks::default(),
+            // buckets of Ceph replicated across zones list objects once the bucket index caught
+            // up, other stores are not known to list them right away
+            Self::Ceph | Self::Auto | Self::Other => Quirks {
+                list_after_write_delay: UNKNOWN_LIST_AFTER_WRITE_DELAY,
+            },
+        }
+    }
+
+    /// Backoff of retried requests. Hosted providers which throttle bursts of requests are
+    /// retried slower, so that retries do not add to the burst.
+    pub fn backoff(self) -> BackoffConfig {
+        match self {
+            Self::Wasabi | Self::R2 => BackoffConfig {
+                init_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(30),
+                base: 2.,
+            },
+            _ => BackoffConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// new objects may be missing from listings for this long after they are written
+    pub list_after_write_delay: Duration,
+}
+
+impl Quirks {
+    /// Quirks with what the probe observed. A single probe which listed the object right away
+    /// does not prove the listing never lags, so a delay assumed of the provider is kept.
+    pub fn refine(self, observed: &Observed) -> Self {
+        Self {
+            list_after_write_delay: self.list_after_write_delay.max(
+                observed
+                    .list_after_write_delay
+                    .unwrap_or(PROBE_LIST_TIMEOUT),
+            ),
+        }
+    }
+}
+
+/// What the probe observed of the store
+#[derive(Debug, Clone, Copy)]
+pub struct Observed {
+    /// none if the object was not listed within the timeout of the probe
+    pub list_after_write_delay: Option<Duration>,
+}
+
+/// Quirks of the object storage of the server, probed at start
+pub fn get() -> Quirks {
+    QUIRKS
+        .get()
+        .copied()
+        .unwrap_or_else(|| CONFIG.storage().quirks())
+}
+
+/// Probe the object storage and use the quirks found from then on. Only S3 compatible stores
+/// are probed, the probe takes as long as the listing lags.
+pub async fn init() {
+    if CONFIG.storage_name != "s3" {
+        return;
+    }
+    let storage = CONFIG.storage();
+    let assumed = storage.quirks();
+    let path = probe_path(&*storage.get_object_store());
+    let observed = match storage.get_datafusion_object_store() {
+        Ok(store) => probe(&*store, &path).await.map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    let quirks = match observed {
+        Ok(observed) => assumed.refine(&observed),
+        Err(err) => {
+            log::warn!(
+                "could not probe the object storage, quirks of its provider are assumed: {err}"
+            );
+            assumed
+        }
+    };
+    if !quirks.list_after_write_delay.is_zero() {
+        log::info!(
+            "object storage may list new objects up to {} late",
+            humantime::format_duration(quirks.list_after_write_delay)
+        );
+    }
+    let _ = QUIRKS.set(quirks);
+}
+
+/// Path of a probe object, unique so that servers sharing the bucket can probe at once
+pub fn probe_path(store: &dyn ObjectStorage) -> Path {
+    let name = format!(".quirks.{}.probe", Ulid::new());
+    store.absolute_url(&RelativePathBuf::from_iter([
+        PARSEABLE_ROOT_DIRECTORY,
+        name.as_str(),
+    ]))
+}
+
+/// Write, list and delete an object at `path` to observe how the store behaves
+pub async fn probe(store: &dyn ObjectStore, path: &Path) -> Result<Observed, object_store::Error> {
+    store.put(path, Bytes::from_static(b"probe")).await?;
+    let result = observe(store, path).await;
+    store.delete(path).await?;
+    result
+}
+
+async fn observe(store: &dyn ObjectStore, path: &Path) -> Result<Observed, object_store::Error> {
+    let prefix: Path = path.parts().take(path.parts().count() - 1).collect();
+    let written = Instant::now();
+    let mut list_after_write_delay = None;
+    loop {
+        let listed: Vec<_> = store.list(Some(&prefix)).await?.try_collect().await?;
+        if listed.iter().any(|meta| meta.location == *path) {
+            // listed on the first try is no delay at all, else allow for the polling interval
+            let elapsed = written.elapsed();
+            list_after_write_delay = Some(if elapsed < PROBE_LIST_INTERVAL {
+                Duration::ZERO
+            } else {
+                elapsed + PROBE_LIST_INTERVAL
+            });
+            break;
+        }
+        if written.elapsed() > PROBE_LIST_TIMEOUT {
+            break;
+        }
+        tokio::time::sleep(PROBE_LIST_INTERVAL).await;
+    }
+
+    Ok(Observed {
+        list_after_write_delay,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use object_store::{memory::InMemory, path::Path, ObjectStore};
+
+    use super::{probe, Observed, Quirks, S3Provider};
+
+    #[test]
+    fn providers_are_told_from_the_endpoint() {
+        let resolve = |url| S3Provider::Auto.resolve(url);
+        assert_eq!(
+            resolve("https://s3.us-east-1.amazonaws.com"),
+            S3Provider::Aws
+        );
+        assert_eq!(
+            resolve("https://abc.r2.cloudflarestorage.com"),
+            S3Provider::R2
+        );
+        assert_eq!(
+            resolve("https://s3.eu-central-1.wasabisys.com"),
+            S3Provider::Wasabi
+        );
+        assert_eq!(resolve("http://minio:9000"), S3Provider::Other);
+        assert_eq!(
+            S3Provider::Minio.resolve("http://minio:9000"),
+            S3Provider::Minio
+        );
+    }
+
+    #[test]
+    fn probes_keep_assumed_delays() {
+        let observed = Observed {
+            list_after_write_delay: Some(Duration::ZERO),
+        };
+        let quirks = S3Provider::Ceph.quirks().refine(&observed);
+        assert_eq!(
+            quirks.list_after_write_delay,
+            S3Provider::Ceph.quirks().list_after_write_delay
+        );
+
+        let quirks = Quirks::default().refine(&Observed {
+            list_after_write_delay: None,
+        });
+        assert!(!quirks.list_after_write_delay.is_zero());
+    }
+
+    #[tokio::test]
+    async fn probe_cleans_up() {
+        let store = InMemory::new();
+        let path = Path::from(".parseable/.quirks.probe");
+        let observed = probe(&store, &path).await.unwrap();
+
+        assert_eq!(observed.list_after_write_delay, Some(Duration::ZERO));
+        assert!(store.head(&path).await.is_err());
+    }
+}
@@ -0,0 +1,243 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::{ops::Range, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::execution::object_store::ObjectStoreRegistry;
+use futures_util::{future::try_join_all, stream::BoxStream};
+use object_store::{
+    path::Path, GetOptions, GetResult, ListResult, MultipartId, ObjectMeta, ObjectStore,
+};
+use tokio::{
+    io::AsyncWrite,
+    sync::{Semaphore, SemaphorePermit},
+};
+use url::Url;
+
+use super::throttle::{limited, retry_throttled, SCAN_LIMIT};
+
+/// Object store layer used by queries for reading parquet files.
+/// Byte ranges that are close to each other are merged into a single GET
+/// and all column chunks requested together are fetched concurrently,
+/// while the number of in flight GET requests is capped by a semaphore. Reads the store
+/// throttles are retried at a lower concurrency, shared by all queries.
+#[derive(Debug)]
+pub struct ReadLayer<T: ObjectStore> {
+    inner: T,
+    semaphore: Option<Arc<Semaphore>>,
+    coalesce: usize,
+}
+
+impl<T: ObjectStore> ReadLayer<T> {
+    pub fn new(inner: T, semaphore: Option<Arc<Semaphore>>, coalesce: usize) -> Self {
+        Self {
+            inner,
+            semaphore,
+            coalesce,
+        }
+    }
+
+    async fn permit(&self) -> Option<SemaphorePermit<'_>> {
+        match &self.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+}
+
+impl<T: ObjectStore> std::fmt::Display for ReadLayer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Read({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl<T: ObjectStore> ObjectStore for ReadLayer<T> {
+    async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<()> {
+        self.inner.put(location, bytes).await
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &Path,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        self.inner.put_multipart(location).await
+    }
+
+    async fn abort_multipart(
+        &self,
+        location: &Path,
+        multipart_id: &MultipartId,
+    ) -> object_store::Result<()> {
+        self.inner.abort_multipart(location, multipart_id).await
+    }
+
+    async fn get_opts(
+        &self,
+        location: &Path,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        let _permit = self.permit().await;
+        // options are consumed by the request, so it is sent once
+        limited(&SCAN_LIMIT, self.inner.get_opts(location, options)).await
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<usize>) -> object_store::Result<Bytes> {
+        let _permit = self.permit().await;
+        retry_throttled(&SCAN_LIMIT, || {
+            self.inner.get_range(location, range.clone())
+        })
+        .await
+    }
+
+    async fn get_ranges(
+        &self,
+        location: &Path,
+        ranges: &[Range<usize>],
+    ) -> object_store::Result<Vec<Bytes>> {
+        let fetch_ranges = coalesce_ranges(ranges, self.coalesce);
+        let fetched = try_join_all(
+            fetch_ranges
+                .iter()
+                .map(|range| self.get_range(location, range.clone())),
+        )
+        .await?;
+
+        let res = ranges
+            .iter()
+            .map(|range| {
+                // coalesced ranges are sorted and do not overlap
+                let idx = fetch_ranges.partition_point(|fetched| fetched.start <= range.start) - 1;
+                let start = range.start - fetch_ranges[idx].start;
+                fetched[idx].slice(start..start + range.len())
+            })
+            .collect();
+
+        Ok(res)
+    }
+
+    async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+        let _permit = self.permit().await;
+        retry_throttled(&SCAN_LIMIT, || self.inner.head(location)).await
+    }
+
+    async fn delete(&self, location: &Path) -> object_store::Result<()> {
+        self.inner.delete(location).await
+    }
+
+    async fn list(
+        &self,
+        prefix: Option<&Path>,
+    ) -> object_store::Result<BoxStream<'life0, object_store::Result<ObjectMeta>>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
+
+/// Merge byte ranges that are at most `coalesce` bytes apart.
+/// Returned ranges are sorted and never overlap.
+fn coalesce_ranges(ranges: &[Range<usize>], coalesce: usize) -> Vec<Range<usize>> {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_unstable_by_key(|range| range.start);
+
+    let mut res: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match res.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(coalesce) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => res.push(range),
+        }
+    }
+    res
+}
+
+/// Object store registry for a single query. Every store handed out to the
+/// query is wrapped in a [`ReadLayer`] sharing the same request limit.
+#[derive(Debug)]
+pub struct ReadLayerRegistry {
+    inner: Arc<dyn ObjectStoreRegistry>,
+    semaphore: Option<Arc<Semaphore>>,
+    coalesce: usize,
+}
+
+impl ReadLayerRegistry {
+    /// a `max_requests` of 0 disables the request limit
+    pub fn new(inner: Arc<dyn ObjectStoreRegistry>, max_requests: usize, coalesce: usize) -> Self {
+        Self {
+            inner,
+            semaphore: (max_requests > 0).then(|| Arc::new(Semaphore::new(max_requests))),
+            coalesce,
+        }
+    }
+}
+
+impl ObjectStoreRegistry for ReadLayerRegistry {
+    fn register_store(
+        &self,
+        url: &Url,
+        store: Arc<dyn ObjectStore>,
+    ) -> Option<Arc<dyn ObjectStore>> {
+        self.inner.register_store(url, store)
+    }
+
+    fn get_store(&self, url: &Url) -> datafusion::error::Result<Arc<dyn ObjectStore>> {
+        let store = self.inner.get_store(url)?;
+        Ok(Arc::new(ReadLayer::new(
+            store,
+            self.semaphore.clone(),
+            self.coalesce,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::coalesce_ranges;
+
+    #[test]
+    fn close_ranges_are_merged() {
+        let ranges = [20..30, 0..10, 12..15, 100..110];
+        assert_eq!(coalesce_ranges(&ranges, 5), vec![0..30, 100..110]);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        let ranges = [0..50, 10..20, 40..60];
+        assert_eq!(coalesce_ranges(&ranges, 0), vec![0..60]);
+        assert!(coalesce_ranges(&[], 10).is_empty());
+    }
+}
@@ -112,6 +112,16 @@ pub struct Retention {
     tasks: Vec<Task>,
 }
 
+impl Retention {
+    /// days after which data of the stream is deleted
+    pub fn delete_after_days(&self) -> Option<u32> {
+        self.tasks
+            .iter()
+            .find(|task| task.action == Action::Delete)
+            .map(|task| task.days.get())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Task {
     description: String,
@@ -197,10 +207,15 @@ mod action {
         catalog::{self, remove_manifest_from_snapshot},
         metadata,
         option::CONFIG,
+        webhooks::{self, LifecycleEvent},
     };
 
     pub(super) async fn delete(stream_name: String, days: u32) {
         log::info!("running retention task - delete for stream={stream_name}");
+        if under_legal_hold(&stream_name) {
+            return;
+        }
+
         let retain_until = get_retain_until(Utc::now().date_naive(), days as u64);
 
         let Ok(dates) = CONFIG
@@ -239,7 +254,7 @@ mod action {
         }
 
         let store = CONFIG.storage().get_object_store();
-        let res = remove_manifest_from_snapshot(store.clone(), &stream_name, dates).await;
+        let res = remove_manifest_from_snapshot(store.clone(), &stream_name, dates.clone()).await;
         if let Err(err) = res {
             log::error!("Failed to update manifest list in the snapshot {err:?}")
         }
@@ -254,6 +269,24 @@ mod action {
                 );
             }
         }
+
+        // the runtime of this task is dropped once it returns, so delivery is awaited
+        webhooks::send(LifecycleEvent::RetentionExecuted {
+            stream: stream_name,
+            deleted_dates: dates,
+        })
+        .await;
+    }
+
+    fn under_legal_hold(stream_name: &str) -> bool {
+        let Ok(Some(legal_hold)) = metadata::STREAM_INFO.legal_hold(stream_name) else {
+            return false;
+        };
+        log::info!(
+            "skipping retention for stream={stream_name}, under legal hold since {}",
+            legal_hold.placed_at
+        );
+        true
     }
 
     fn get_retain_until(current_date: NaiveDate, days: u64) -> NaiveDate {
@@ -270,10 +303,13 @@ mod action {
 
     #[cfg(test)]
     mod tests {
-        use chrono::{Datelike, NaiveDate};
+        use chrono::{Datelike, NaiveDate, Utc};
 
         use super::get_retain_until;
         use super::string_to_date;
+        use super::under_legal_hold;
+        use crate::metadata::{LogStreamMetadata, STREAM_INFO};
+        use crate::storage::LegalHold;
 
         #[test]
         fn test_time_from_string() {
@@ -287,5 +323,25 @@ mod action {
             let date = get_retain_until(current_date, 1);
             assert_eq!(date.day(), 1)
         }
+
+        #[test]
+        fn legal_hold_skips_retention() {
+            let stream_name = "retention_legal_hold";
+            STREAM_INFO
+                .write()
+                .unwrap()
+                .insert(stream_name.to_string(), LogStreamMetadata::default());
+            assert!(!under_legal_hold(stream_name));
+
+            let hold = LegalHold {
+                reason: None,
+                placed_by: "admin".to_string(),
+                placed_at: Utc::now(),
+            };
+            STREAM_INFO.set_legal_hold(stream_name, Some(hold)).unwrap();
+            assert!(under_legal_hold(stream_name));
+
+            STREAM_INFO.delete_stream(stream_name);
+        }
     }
 }
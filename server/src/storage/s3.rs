@@ -23,25 +23,34 @@ use datafusion::datasource::object_store::{
     DefaultObjectStoreRegistry, ObjectStoreRegistry, ObjectStoreUrl,
 };
 use datafusion::execution::runtime_env::RuntimeConfig;
-use futures::stream::FuturesUnordered;
+use futures::stream::{BoxStream, FuturesUnordered};
 use futures::{StreamExt, TryStreamExt};
 use object_store::aws::{AmazonS3, AmazonS3Builder, AmazonS3ConfigKey, Checksum};
 use object_store::limit::LimitStore;
 use object_store::path::Path as StorePath;
-use object_store::{ClientOptions, ObjectStore};
+use object_store::{ClientOptions, ObjectStore, RetryConfig};
+use once_cell::sync::OnceCell;
 use relative_path::{RelativePath, RelativePathBuf};
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 
+use std::io::Read;
 use std::iter::Iterator;
-use std::path::Path as StdPath;
+use std::net::SocketAddr;
+use std::path::{Path as StdPath, PathBuf};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crate::metrics::storage::{s3::REQUEST_RESPONSE_TIME, StorageMetrics};
+use crate::option::CONFIG;
 use crate::storage::{LogStream, ObjectStorage, ObjectStorageError, PARSEABLE_ROOT_DIRECTORY};
-
-use super::metrics_layer::MetricLayer;
+use crate::tls::{self, upgrade_proxy};
+use crate::utils::sigv4;
+
+use super::compression;
+use super::metrics_layer::{MetricLayer, QUERY_LAYER, SERVER_LAYER};
+use super::object_storage::LIST_FETCH_CONCURRENCY;
+use super::quirks::{Quirks, S3Provider};
+use super::staging::encryption;
 use super::{
     ObjectStorageProvider, PARSEABLE_METADATA_FILE_NAME, SCHEMA_FILE_NAME,
     STREAM_METADATA_FILE_NAME, STREAM_ROOT_DIRECTORY,
@@ -50,6 +59,14 @@ use super::{
 // in bytes
 const MULTIPART_UPLOAD_SIZE: usize = 1024 * 1024 * 100;
 const CONNECT_TIMEOUT_SECS: u64 = 5;
+// hosts of the instance and container credential endpoints, which are not proxied
+const METADATA_HOSTS: &str = "169.254.169.254,169.254.170.2";
+
+// address of the proxy which sends object store requests with the restricted cipher suites
+static UPGRADE_PROXY: OnceCell<SocketAddr> = OnceCell::new();
+// in progress multipart uploads are journaled here so that uploads abandoned
+// by a crash can be aborted on the next start
+const MULTIPART_JOURNAL_DIR: &str = ".multipart";
 const AWS_CONTAINER_CREDENTIALS_RELATIVE_URI: &str = "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI";
 
 #[derive(Debug, Clone, clap::Args)]
@@ -126,9 +143,42 @@ pub struct S3Config {
         required = false
     )]
     pub metadata_endpoint: Option<String>,
+
+    /// Number of times a failed request, including every part of a multipart upload, is retried
+    #[arg(
+        long,
+        env = "P_S3_MAX_RETRIES",
+        value_name = "count",
+        default_value = "10"
+    )]
+    pub max_retries: usize,
+
+    /// Maximum time spent retrying a single request
+    #[arg(
+        long,
+        env = "P_S3_RETRY_TIMEOUT",
+        value_name = "duration",
+        default_value = "3m",
+        value_parser = humantime::parse_duration
+    )]
+    pub retry_timeout: Duration,
+
+    /// Provider of the S3 compatible object storage, whose quirks are accounted for
+    #[arg(
+        long,
+        env = "P_S3_PROVIDER",
+        value_name = "provider",
+        default_value = "auto",
+        value_enum
+    )]
+    pub provider: S3Provider,
 }
 
 impl S3Config {
+    fn provider(&self) -> S3Provider {
+        self.provider.resolve(&self.endpoint_url)
+    }
+
     fn get_default_builder(&self) -> AmazonS3Builder {
         let mut client_options = ClientOptions::default()
             .with_allow_http(true)
@@ -138,9 +188,36 @@ impl S3Config {
             client_options = client_options.with_allow_invalid_certificates(true)
         }
 
+        let mut endpoint_url = self.endpoint_url.clone();
+        // the tls of the object store client cannot be configured, with restricted cipher suites
+        // it talks plain http to a local proxy which sends the requests on over https
+        if let Some(endpoint) = self
+            .endpoint_url
+            .strip_prefix("https://")
+            .filter(|_| tls::restricted())
+        {
+            let authority = endpoint.split('/').next().unwrap_or_default();
+            let proxy = UPGRADE_PROXY
+                .get_or_try_init(|| upgrade_proxy::start(authority))
+                .expect("object store proxy can be started");
+            let mut excludes = METADATA_HOSTS.to_owned();
+            if let Some(host) = self
+                .metadata_endpoint
+                .as_deref()
+                .and_then(|endpoint| endpoint.parse::<http::Uri>().ok())
+                .and_then(|uri| uri.host().map(str::to_owned))
+            {
+                excludes = format!("{excludes},{host}");
+            }
+            client_options = client_options
+                .with_proxy_url(format!("http://{proxy}"))
+                .with_proxy_excludes(excludes);
+            endpoint_url = format!("http://{endpoint}");
+        }
+
         let mut builder = AmazonS3Builder::new()
             .with_region(&self.region)
-            .with_endpoint(&self.endpoint_url)
+            .with_endpoint(&endpoint_url)
             .with_bucket_name(&self.bucket_name)
             .with_virtual_hosted_style_request(!self.use_path_style)
             .with_allow_http(true);
@@ -172,7 +249,13 @@ impl S3Config {
             builder = builder.with_metadata_endpoint(metadata_endpoint)
         }
 
-        builder.with_client_options(client_options)
+        builder
+            .with_client_options(client_options)
+            .with_retry(RetryConfig {
+                backoff: self.provider().backoff(),
+                max_retries: self.max_retries,
+                retry_timeout: self.retry_timeout,
+            })
     }
 }
 
@@ -182,7 +265,7 @@ impl ObjectStorageProvider for S3Config {
 
         // limit objectstore to a concurrent request limit
         let s3 = LimitStore::new(s3, super::MAX_OBJECT_STORE_REQUESTS);
-        let s3 = MetricLayer::new(s3);
+        let s3 = MetricLayer::new(s3, QUERY_LAYER);
 
         let object_store_registry: DefaultObjectStoreRegistry = DefaultObjectStoreRegistry::new();
         let url = ObjectStoreUrl::parse(format!("s3://{}", &self.bucket_name)).unwrap();
@@ -196,6 +279,7 @@ impl ObjectStorageProvider for S3Config {
 
         // limit objectstore to a concurrent request limit
         let s3 = LimitStore::new(s3, super::MAX_OBJECT_STORE_REQUESTS);
+        let s3 = MetricLayer::new(s3, SERVER_LAYER);
 
         Arc::new(S3 {
             client: s3,
@@ -208,6 +292,32 @@ impl ObjectStorageProvider for S3Config {
         format!("{}/{}", self.endpoint_url, self.bucket_name)
     }
 
+    fn quirks(&self) -> Quirks {
+        self.provider().quirks()
+    }
+
+    fn get_bucket_store(&self, bucket: &str) -> Option<Arc<dyn ObjectStore>> {
+        let s3 = self
+            .get_default_builder()
+            .with_bucket_name(bucket)
+            .build()
+            .ok()?;
+        let s3 = LimitStore::new(s3, super::MAX_OBJECT_STORE_REQUESTS);
+        Some(Arc::new(MetricLayer::new(s3, QUERY_LAYER)))
+    }
+
+    fn get_aws_credentials(&self) -> Option<(String, sigv4::Credentials)> {
+        let (access_key_id, secret_access_key) =
+            self.access_key_id.clone().zip(self.secret_key.clone())?;
+        Some((
+            self.region.clone(),
+            sigv4::Credentials {
+                access_key_id,
+                secret_access_key,
+            },
+        ))
+    }
+
     fn register_store_metrics(&self, handler: &actix_web_prometheus::PrometheusMetrics) {
         self.register_metrics(handler)
     }
@@ -218,7 +328,7 @@ fn to_object_store_path(path: &RelativePath) -> StorePath {
 }
 
 pub struct S3 {
-    client: LimitStore<AmazonS3>,
+    client: MetricLayer<LimitStore<AmazonS3>>,
     bucket: String,
     root: StorePath,
 }
@@ -236,7 +346,7 @@ impl S3 {
                     .with_label_values(&["GET", "200"])
                     .observe(time);
                 let body = resp.bytes().await.unwrap();
-                Ok(body)
+                Ok(compression::decode(body)?)
             }
             Err(err) => {
                 let time = instant.elapsed().as_secs_f64();
@@ -254,6 +364,7 @@ impl S3 {
         resource: Bytes,
     ) -> Result<(), ObjectStorageError> {
         let time = Instant::now();
+        let resource = compression::encode(path, resource);
         let resp = self.client.put(&to_object_store_path(path), resource).await;
         let status = if resp.is_ok() { "200" } else { "400" };
         let time = time.elapsed().as_secs_f64();
@@ -349,7 +460,7 @@ impl S3 {
         let res = if should_multipart {
             self._upload_multipart(key, path).await
         } else {
-            let bytes = tokio::fs::read(path).await?;
+            let bytes = encryption::read(path)?;
             self.client
                 .put(&key.into(), bytes.into())
                 .await
@@ -366,43 +477,129 @@ impl S3 {
     }
 
     async fn _upload_multipart(&self, key: &str, path: &StdPath) -> Result<(), ObjectStorageError> {
-        let mut buf = vec![0u8; MULTIPART_UPLOAD_SIZE / 2];
-        let mut file = OpenOptions::new().read(true).open(path).await?;
+        let file = encryption::open(path)?;
+        self.upload_parts(&MultipartJournal::dir(), key, file).await
+    }
 
+    // every part is retried by the client according to the configured retry policy,
+    // the upload is aborted once a part runs out of retries
+    async fn upload_parts(
+        &self,
+        journal_dir: &StdPath,
+        key: &str,
+        mut file: impl Read + Send,
+    ) -> Result<(), ObjectStorageError> {
         let (multipart_id, mut async_writer) = self.client.put_multipart(&key.into()).await?;
+        let journal = MultipartJournal::create(journal_dir, key, &multipart_id).await?;
 
-        let close_multipart = |err| async move {
-            log::error!("multipart upload failed. {:?}", err);
+        let res = async {
+            let mut buf = vec![0u8; MULTIPART_UPLOAD_SIZE / 2];
+            loop {
+                let len = file.read(&mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                async_writer.write_all(&buf[0..len]).await?;
+                async_writer.flush().await?;
+            }
+            async_writer.shutdown().await
+        }
+        .await;
+
+        if let Err(err) = res {
+            log::error!("multipart upload of {key} failed. {:?}", err);
             self.client
                 .abort_multipart(&key.into(), &multipart_id)
+                .await?;
+            journal.remove().await;
+            return Err(err.into());
+        }
+
+        journal.remove().await;
+        Ok(())
+    }
+
+    async fn _abort_abandoned_uploads(
+        &self,
+        journal_dir: &StdPath,
+    ) -> Result<(), ObjectStorageError> {
+        for (journal, upload) in MultipartJournal::list(journal_dir).await? {
+            log::warn!(
+                "aborting multipart upload of {} abandoned by a previous run",
+                upload.key
+            );
+            match self
+                .client
+                .abort_multipart(&upload.key.as_str().into(), &upload.multipart_id)
                 .await
+            {
+                Ok(()) => journal.remove().await,
+                Err(err) => log::error!(
+                    "failed to abort multipart upload of {}. {:?}",
+                    upload.key,
+                    err
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct MultipartUpload {
+    key: String,
+    multipart_id: String,
+}
+
+struct MultipartJournal {
+    path: PathBuf,
+}
+
+impl MultipartJournal {
+    fn dir() -> PathBuf {
+        CONFIG.staging_dir().join(MULTIPART_JOURNAL_DIR)
+    }
+
+    async fn create(
+        dir: &StdPath,
+        key: &str,
+        multipart_id: &str,
+    ) -> Result<Self, ObjectStorageError> {
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.json", ulid::Ulid::new()));
+        let upload = MultipartUpload {
+            key: key.to_owned(),
+            multipart_id: multipart_id.to_owned(),
         };
+        tokio::fs::write(&path, serde_json::to_vec(&upload).expect("serializable")).await?;
+        Ok(Self { path })
+    }
 
-        loop {
-            match file.read(&mut buf).await {
-                Ok(len) => {
-                    if len == 0 {
-                        break;
-                    }
-                    if let Err(err) = async_writer.write_all(&buf[0..len]).await {
-                        close_multipart(err).await?;
-                        break;
-                    }
-                    if let Err(err) = async_writer.flush().await {
-                        close_multipart(err).await?;
-                        break;
-                    }
-                }
-                Err(err) => {
-                    close_multipart(err).await?;
-                    break;
-                }
+    async fn list(dir: &StdPath) -> Result<Vec<(Self, MultipartUpload)>, ObjectStorageError> {
+        let mut uploads = Vec::new();
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(uploads),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let journal = Self { path: entry.path() };
+            match serde_json::from_slice(&tokio::fs::read(entry.path()).await?) {
+                Ok(upload) => uploads.push((journal, upload)),
+                Err(_) => journal.remove().await,
             }
         }
+        Ok(uploads)
+    }
 
-        async_writer.shutdown().await?;
-
-        Ok(())
+    async fn remove(self) {
+        if let Err(err) = tokio::fs::remove_file(&self.path).await {
+            log::warn!(
+                "failed to remove multipart journal {}. {:?}",
+                self.path.display(),
+                err
+            );
+        }
     }
 }
 
@@ -412,46 +609,34 @@ impl ObjectStorage for S3 {
         Ok(self._get_object(path).await?)
     }
 
-    async fn get_objects(
+    fn stream_objects(
         &self,
-        base_path: Option<&RelativePath>,
+        base_path: Option<RelativePathBuf>,
         filter_func: Box<dyn Fn(String) -> bool + Send>,
-    ) -> Result<Vec<Bytes>, ObjectStorageError> {
-        let instant = Instant::now();
-
-        let prefix = if let Some(base_path) = base_path {
-            to_object_store_path(base_path)
-        } else {
-            self.root.clone()
+    ) -> BoxStream<'_, Result<(RelativePathBuf, Bytes), ObjectStorageError>> {
+        let prefix = match base_path {
+            Some(base_path) => to_object_store_path(&base_path),
+            None => self.root.clone(),
         };
 
-        let mut list_stream = self.client.list(Some(&prefix)).await?;
-
-        let mut res = vec![];
-
-        while let Some(meta) = list_stream.next().await.transpose()? {
-            let ingester_file = filter_func(meta.location.filename().unwrap().to_string());
-
-            if !ingester_file {
-                continue;
-            }
-
-            let byts = self
-                .get_object(
-                    RelativePath::from_path(meta.location.as_ref())
-                        .map_err(ObjectStorageError::PathError)?,
-                )
-                .await?;
-
-            res.push(byts);
-        }
-
-        let instant = instant.elapsed().as_secs_f64();
-        REQUEST_RESPONSE_TIME
-            .with_label_values(&["GET", "200"])
-            .observe(instant);
-
-        Ok(res)
+        // the listing is paginated by the client, pages are requested as the stream is polled
+        futures::stream::once(async move { self.client.list(Some(&prefix)).await })
+            .try_flatten()
+            .map_err(ObjectStorageError::from)
+            .try_filter_map(move |meta| {
+                let matches = meta
+                    .location
+                    .filename()
+                    .is_some_and(|name| filter_func(name.to_string()));
+                async move { Ok(matches.then_some(meta.location)) }
+            })
+            .map_ok(move |location| async move {
+                let path = RelativePathBuf::from(location.as_ref());
+                let bytes = self.get_object(&path).await?;
+                Ok::<_, ObjectStorageError>((path, bytes))
+            })
+            .try_buffered(LIST_FETCH_CONCURRENCY)
+            .boxed()
     }
 
     async fn get_ingester_meta_file_paths(
@@ -603,6 +788,11 @@ impl ObjectStorage for S3 {
         Ok(streams)
     }
 
+    async fn abort_abandoned_uploads(&self) -> Result<(), ObjectStorageError> {
+        self._abort_abandoned_uploads(&MultipartJournal::dir())
+            .await
+    }
+
     async fn upload_file(&self, key: &str, path: &StdPath) -> Result<(), ObjectStorageError> {
         self._upload_file(key, path).await?;
 
@@ -661,3 +851,161 @@ impl From<serde_json::Error> for ObjectStorageError {
         ObjectStorageError::UnhandledError(Box::new(error))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use actix_web::{http::Method, web, App, HttpRequest, HttpResponse, HttpServer};
+    use object_store::limit::LimitStore;
+    use object_store::path::Path as StorePath;
+
+    use super::{MultipartJournal, S3Config, S3};
+    use crate::storage::metrics_layer::{MetricLayer, SERVER_LAYER};
+    use crate::storage::quirks::S3Provider;
+
+    const UPLOAD_ID: &str = "upload-1";
+
+    // S3 multipart api failing the first `failing_parts` part uploads, records the requests
+    #[derive(Default)]
+    struct MockS3 {
+        failing_parts: usize,
+        parts: usize,
+        completed: usize,
+        aborted: Vec<String>,
+    }
+
+    async fn handle(req: HttpRequest, mock: web::Data<Mutex<MockS3>>) -> HttpResponse {
+        let mut mock = mock.lock().unwrap();
+        let query = req.query_string();
+        match *req.method() {
+            Method::POST if query.starts_with("uploads") => HttpResponse::Ok().body(format!(
+                "<InitiateMultipartUploadResult><UploadId>{UPLOAD_ID}</UploadId></InitiateMultipartUploadResult>"
+            )),
+            Method::PUT if query.contains("partNumber") => {
+                mock.parts += 1;
+                if mock.parts <= mock.failing_parts {
+                    HttpResponse::ServiceUnavailable().finish()
+                } else {
+                    HttpResponse::Ok()
+                        .insert_header(("ETag", format!("\"{}\"", mock.parts)))
+                        .finish()
+                }
+            }
+            Method::POST => {
+                mock.completed += 1;
+                HttpResponse::Ok().finish()
+            }
+            Method::DELETE => {
+                mock.aborted.push(query.to_string());
+                HttpResponse::NoContent().finish()
+            }
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    fn mock_s3(failing_parts: usize) -> (String, Arc<Mutex<MockS3>>) {
+        let mock = web::Data::new(Mutex::new(MockS3 {
+            failing_parts,
+            ..MockS3::default()
+        }));
+        let state = mock.clone().into_inner();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(mock.clone())
+                .default_service(web::to(handle))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let endpoint = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        (endpoint, state)
+    }
+
+    fn s3(endpoint_url: String, max_retries: usize) -> S3 {
+        let config = S3Config {
+            endpoint_url,
+            access_key_id: Some("access".to_string()),
+            secret_key: Some("secret".to_string()),
+            region: "us-east-1".to_string(),
+            bucket_name: "bucket".to_string(),
+            set_checksum: false,
+            use_path_style: true,
+            skip_tls: false,
+            imdsv1_fallback: false,
+            metadata_endpoint: None,
+            max_retries,
+            retry_timeout: Duration::from_secs(10),
+            provider: S3Provider::Other,
+        };
+        let client = config.get_default_builder().build().unwrap();
+        S3 {
+            client: MetricLayer::new(LimitStore::new(client, 10), SERVER_LAYER),
+            bucket: "bucket".to_string(),
+            root: StorePath::from(""),
+        }
+    }
+
+    fn journal_dir() -> PathBuf {
+        std::env::temp_dir().join(ulid::Ulid::new().to_string())
+    }
+
+    fn journaled(dir: &Path) -> usize {
+        std::fs::read_dir(dir).map_or(0, |entries| entries.count())
+    }
+
+    #[actix_web::test]
+    async fn failed_parts_are_retried() {
+        let (endpoint, mock) = mock_s3(2);
+        let dir = journal_dir();
+
+        s3(endpoint, 3)
+            .upload_parts(&dir, "app/file.parquet", &[7u8; 1024][..])
+            .await
+            .unwrap();
+
+        let mock = mock.lock().unwrap();
+        assert_eq!(mock.parts, 3);
+        assert_eq!(mock.completed, 1);
+        assert!(mock.aborted.is_empty());
+        assert_eq!(journaled(&dir), 0);
+    }
+
+    #[actix_web::test]
+    async fn upload_is_aborted_once_a_part_runs_out_of_retries() {
+        let (endpoint, mock) = mock_s3(usize::MAX);
+        let dir = journal_dir();
+
+        let res = s3(endpoint, 1)
+            .upload_parts(&dir, "app/file.parquet", &[7u8; 1024][..])
+            .await;
+
+        assert!(res.is_err());
+        let mock = mock.lock().unwrap();
+        assert_eq!(mock.parts, 2);
+        assert_eq!(mock.completed, 0);
+        assert_eq!(mock.aborted, vec![format!("uploadId={UPLOAD_ID}")]);
+        assert_eq!(journaled(&dir), 0);
+    }
+
+    #[actix_web::test]
+    async fn uploads_abandoned_by_a_previous_run_are_aborted() {
+        let (endpoint, mock) = mock_s3(0);
+        let dir = journal_dir();
+        MultipartJournal::create(&dir, "app/file.parquet", "abandoned")
+            .await
+            .unwrap();
+        assert_eq!(journaled(&dir), 1);
+
+        s3(endpoint, 0)
+            ._abort_abandoned_uploads(&dir)
+            .await
+            .unwrap();
+
+        assert_eq!(mock.lock().unwrap().aborted, vec!["uploadId=abandoned"]);
+        assert_eq!(journaled(&dir), 0);
+    }
+}
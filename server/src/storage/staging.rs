@@ -17,16 +17,29 @@
  *
  */
 
+pub mod encryption;
+
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     fs,
     path::{Path, PathBuf},
     process,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
 };
 
+use arrow_array::{Array, RecordBatch, UInt32Array};
 use arrow_schema::{ArrowError, Schema};
+use arrow_select::concat::concat_batches;
+use arrow_select::take::take;
 use chrono::{NaiveDateTime, Timelike, Utc};
+use datafusion::arrow::{
+    compute::{lexsort_to_indices, SortColumn, SortOptions},
+    util::display::array_value_to_string,
+};
 use parquet::{
     arrow::ArrowWriter,
     basic::Encoding,
@@ -35,18 +48,24 @@ use parquet::{
     format::SortingColumn,
     schema::types::ColumnPath,
 };
+use thread_priority::ThreadPriority;
+use ulid::Ulid;
 
+use self::encryption::StagingFile;
 use super::super::handlers::http::modal::server::Server;
 use crate::{
     event::DEFAULT_TIMESTAMP_KEY,
     metrics,
-    option::CONFIG,
-    storage::OBJECT_STORE_DATA_GRANULARITY,
+    option::{ConversionPriority, CONFIG},
+    shutdown,
+    storage::{SortKey, OBJECT_STORE_DATA_GRANULARITY},
     utils::{self, arrow::merged_reader::MergedReverseRecordReader},
 };
 
 const ARROW_FILE_EXTENSION: &str = "data.arrows";
 const PARQUET_FILE_EXTENSION: &str = "data.parquet";
+// marks staging files of the current minute set aside by a flush, after the stream hash
+const SEALED_FILE_MARKER: &str = "-sealed-";
 
 #[derive(Debug)]
 pub struct StorageDir {
@@ -103,7 +122,6 @@ impl StorageDir {
         paths
     }
 
-    #[allow(dead_code)]
     pub fn arrow_files_grouped_by_time(&self) -> HashMap<PathBuf, Vec<PathBuf>> {
         // hashmap <time, vec[paths]>
         let mut grouped_arrow_file: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
@@ -129,12 +147,8 @@ impl StorageDir {
         let mut arrow_files = self.arrow_files();
 
         arrow_files.retain(|path| {
-            !path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .ends_with(&hot_filename)
+            let filename = path.file_name().unwrap().to_str().unwrap();
+            !filename.ends_with(&hot_filename) || Self::is_sealed(filename)
         });
 
         for arrow_file_path in arrow_files {
@@ -148,6 +162,34 @@ impl StorageDir {
         grouped_arrow_file
     }
 
+    /// Set the files of the current minute aside under new names, so that the next conversion
+    /// picks them up while new events of the minute are written to new files. The writers of
+    /// the stream must be closed and kept from opening files again until this returns.
+    pub fn seal_current_files(&self) {
+        let hot_filename = Self::file_time_suffix(Utc::now().naive_utc(), ARROW_FILE_EXTENSION);
+        for path in self.arrow_files() {
+            let filename = path.file_name().unwrap().to_str().unwrap();
+            if !filename.ends_with(&hot_filename) || Self::is_sealed(filename) {
+                continue;
+            }
+            // the stream hash is dropped from the parquet name, so sealed files keep their group
+            let (stream_hash, rest) = filename.split_once('.').unwrap();
+            let sealed = path.with_file_name(format!(
+                "{stream_hash}{SEALED_FILE_MARKER}{}.{rest}",
+                Ulid::new()
+            ));
+            if let Err(err) = fs::rename(&path, &sealed) {
+                log::warn!("could not seal staging file {}: {err}", path.display());
+            }
+        }
+    }
+
+    fn is_sealed(filename: &str) -> bool {
+        filename
+            .split_once('.')
+            .is_some_and(|(stream_hash, _)| stream_hash.contains(SEALED_FILE_MARKER))
+    }
+
     pub fn parquet_files(&self) -> Vec<PathBuf> {
         let Ok(dir) = self.data_path.read_dir() else {
             return vec![];
@@ -159,6 +201,38 @@ impl StorageDir {
             .collect()
     }
 
+    /// Path of the parquet file holding the rows of one custom partition, the partition prefix
+    /// is placed after the minute of the file
+    fn partitioned_parquet_path(path: &Path, partition_prefix: &str) -> PathBuf {
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        let (time, rest) = filename
+            .match_indices('.')
+            .nth(2)
+            .map(|(index, _)| filename.split_at(index + 1))
+            .unwrap();
+        let partition = partition_prefix.replace('/', ".");
+        path.with_file_name(format!("{time}{partition}{rest}"))
+    }
+
+    /// Object store path of a staged parquet file, relative to its stream
+    pub fn parquet_object_path(filename: &str) -> String {
+        // date, hour and minute are directories, followed by the custom partition if any
+        let directories = match filename.split('.').nth(3) {
+            Some(part) if part.contains('=') => 4,
+            _ => 3,
+        };
+        str::replacen(filename, ".", "/", directories)
+    }
+
+    /// Parquet file a group of staging files is converted to. Every conversion writes a file of
+    /// its own, so files of the same minute converted apart, e.g. set aside by a flush or staged
+    /// again after a restart, never replace each other in staging or in the object store.
+    fn unique_parquet_path(group: &Path) -> PathBuf {
+        let filename = group.file_name().unwrap().to_str().unwrap();
+        let stem = filename.strip_suffix(PARQUET_FILE_EXTENSION).unwrap();
+        group.with_file_name(format!("{stem}{}.{PARQUET_FILE_EXTENSION}", Ulid::new()))
+    }
+
     fn arrow_path_to_parquet(path: &Path) -> PathBuf {
         let filename = path.file_name().unwrap().to_str().unwrap();
         let (_, filename) = filename.split_once('.').unwrap();
@@ -188,37 +262,57 @@ pub fn to_parquet_path(stream_name: &str, time: NaiveDateTime) -> PathBuf {
     data_path.join(dir)
 }
 
+/// Stream to convert from staging to parquet, with the layout of its parquet files
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    pub stream: String,
+    pub time_partition: Option<String>,
+    pub custom_partition: Option<String>,
+    pub sort_keys: Vec<SortKey>,
+}
+
 pub fn convert_disk_files_to_parquet(
     stream: &str,
     dir: &StorageDir,
     time_partition: Option<String>,
+    custom_partition: Option<String>,
+    sort_keys: &[SortKey],
 ) -> Result<Option<Schema>, MoveDataError> {
     let mut schemas = Vec::new();
 
     let time = chrono::Utc::now().naive_utc();
-    let staging_files = dir.arrow_files_grouped_exclude_time(time);
-    if staging_files.is_empty() {
-        metrics::STAGING_FILES.with_label_values(&[stream]).set(0);
+    // no more events are written once the server drains, so the current minute is converted too
+    let staging_files = if shutdown::is_draining() {
+        dir.arrow_files_grouped_by_time()
+    } else {
+        dir.arrow_files_grouped_exclude_time(time)
+    };
+    let label = metrics::stream_label(stream);
+    if let (true, Some(label)) = (staging_files.is_empty(), &label) {
+        metrics::STAGING_FILES.with_label_values(&[label]).set(0);
         metrics::STORAGE_SIZE
-            .with_label_values(&["staging", stream, "arrows"])
+            .with_label_values(&["staging", label, "arrows"])
             .set(0);
         metrics::STORAGE_SIZE
-            .with_label_values(&["staging", stream, "parquet"])
+            .with_label_values(&["staging", label, "parquet"])
             .set(0);
     }
 
-    for (parquet_path, files) in staging_files {
-        metrics::STAGING_FILES
-            .with_label_values(&[stream])
-            .set(files.len() as i64);
+    for (group, files) in staging_files {
+        let parquet_path = StorageDir::unique_parquet_path(&group);
+        if let Some(label) = &label {
+            metrics::STAGING_FILES
+                .with_label_values(&[label])
+                .set(files.len() as i64);
 
-        for file in &files {
-            let file_size = file.metadata().unwrap().len();
-            let file_type = file.extension().unwrap().to_str().unwrap();
+            for file in &files {
+                let file_size = file.metadata().unwrap().len();
+                let file_type = file.extension().unwrap().to_str().unwrap();
 
-            metrics::STORAGE_SIZE
-                .with_label_values(&["staging", stream, file_type])
-                .add(file_size as i64);
+                metrics::STORAGE_SIZE
+                    .with_label_values(&["staging", label, file_type])
+                    .add(file_size as i64);
+            }
         }
 
         let record_reader = MergedReverseRecordReader::try_new(&files).unwrap();
@@ -227,19 +321,46 @@ pub fn convert_disk_files_to_parquet(
         if let Some(time_partition) = time_partition.as_ref() {
             index_time_partition = merged_schema.index_of(time_partition).unwrap();
         }
-        let parquet_file = fs::File::create(&parquet_path).map_err(|_| MoveDataError::Create)?;
-        let props = parquet_writer_props(time_partition.clone(), index_time_partition).build();
+        let sort_columns = sort_columns(&merged_schema, sort_keys);
+        let props =
+            parquet_writer_props(time_partition.clone(), index_time_partition, &sort_columns)
+                .build();
 
         schemas.push(merged_schema.clone());
         let schema = Arc::new(merged_schema);
-        let mut writer = ArrowWriter::try_new(parquet_file, schema.clone(), Some(props))?;
 
-        for ref record in record_reader.merged_iter(schema) {
-            writer.write(record)?;
+        if sort_columns.is_empty() && custom_partition.is_none() {
+            let parquet_file =
+                StagingFile::create(&parquet_path).map_err(|_| MoveDataError::Create)?;
+            let mut writer = ArrowWriter::try_new(parquet_file, schema.clone(), Some(props))?;
+            for ref record in record_reader.merged_iter(schema) {
+                writer.write(record)?;
+            }
+            writer.close()?;
+        } else {
+            let partition = custom_partition
+                .as_deref()
+                .map(|column| (column, schema.index_of(column).ok()));
+            let mut writers = PartitionWriters::new(parquet_path, schema.clone(), props);
+            // rows are sorted a row group at a time, each row group is closed once written so
+            // that rows of two sorted chunks never share a row group
+            let mut chunk = Vec::new();
+            let mut rows = 0;
+            for record in record_reader.merged_iter(schema) {
+                rows += record.num_rows();
+                chunk.push(record);
+                if rows >= CONFIG.parseable.row_group_size {
+                    writers.write(&chunk, partition, &sort_columns)?;
+                    chunk.clear();
+                    rows = 0;
+                }
+            }
+            if !chunk.is_empty() {
+                writers.write(&chunk, partition, &sort_columns)?;
+            }
+            writers.close()?;
         }
 
-        writer.close()?;
-
         for file in files {
             if fs::remove_file(file).is_err() {
                 log::error!("Failed to delete file. Unstable state");
@@ -248,6 +369,20 @@ pub fn convert_disk_files_to_parquet(
         }
     }
 
+    // events of the current minute and parquet files not uploaded yet stay in staging
+    if let Some(label) = metrics::stream_label(stream) {
+        let staged: u64 = dir
+            .arrow_files()
+            .iter()
+            .chain(dir.parquet_files().iter())
+            .filter_map(|file| file.metadata().ok())
+            .map(|meta| meta.len())
+            .sum();
+        metrics::STAGING_SIZE
+            .with_label_values(&[&label])
+            .set(staged as i64);
+    }
+
     if !schemas.is_empty() {
         Ok(Some(Schema::try_merge(schemas).unwrap()))
     } else {
@@ -255,11 +390,216 @@ pub fn convert_disk_files_to_parquet(
     }
 }
 
+/// Convert staging files of multiple streams to parquet. Streams are converted in parallel on
+/// at most `conversion_concurrency` threads running at the configured conversion priority.
+/// Takes a list of streams along with their time partition.
+pub fn convert_streams_to_parquet(
+    streams: &[Conversion],
+) -> HashMap<String, Result<Option<Schema>, MoveDataError>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(HashMap::with_capacity(streams.len()));
+    let workers = CONFIG
+        .parseable
+        .conversion_concurrency
+        .clamp(1, streams.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                set_conversion_priority();
+                // pick up the next stream till every stream is converted
+                while let Some(conversion) = streams.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let dir = StorageDir::new(&conversion.stream);
+                    let res = convert_disk_files_to_parquet(
+                        &conversion.stream,
+                        &dir,
+                        conversion.time_partition.clone(),
+                        conversion.custom_partition.clone(),
+                        &conversion.sort_keys,
+                    );
+                    results
+                        .lock()
+                        .unwrap()
+                        .insert(conversion.stream.clone(), res);
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+fn set_conversion_priority() {
+    let priority = match CONFIG.parseable.conversion_priority {
+        ConversionPriority::Low => ThreadPriority::Min,
+        ConversionPriority::Normal => return,
+        ConversionPriority::High => ThreadPriority::Max,
+    };
+    if thread_priority::set_current_thread_priority(priority).is_err() {
+        log::warn!("Conversion priority cannot be set for conversion thread. Make sure that user/program is allowed to set thread priority.")
+    }
+}
+
+/// Index and direction of the sort key columns present in `schema`. Keys on columns the
+/// staged events do not have yet are skipped.
+fn sort_columns(schema: &Schema, sort_keys: &[SortKey]) -> Vec<(usize, bool)> {
+    sort_keys
+        .iter()
+        .filter_map(|key| {
+            let index = schema.index_of(&key.column).ok()?;
+            Some((index, key.descending))
+        })
+        .collect()
+}
+
+/// Parquet files written from one group of staging files, one per custom partition value
+struct PartitionWriters {
+    parquet_path: PathBuf,
+    schema: Arc<Schema>,
+    props: WriterProperties,
+    writers: HashMap<String, ArrowWriter<StagingFile>>,
+}
+
+impl PartitionWriters {
+    fn new(parquet_path: PathBuf, schema: Arc<Schema>, props: WriterProperties) -> Self {
+        Self {
+            parquet_path,
+            schema,
+            props,
+            writers: HashMap::new(),
+        }
+    }
+
+    fn write(
+        &mut self,
+        chunk: &[RecordBatch],
+        partition: Option<(&str, Option<usize>)>,
+        sort_columns: &[(usize, bool)],
+    ) -> Result<(), MoveDataError> {
+        let batch = concat_batches(&self.schema, chunk)?;
+        let parts = match partition {
+            Some((column, index)) => partition_batch(&batch, column, index)?,
+            None => vec![(String::new(), batch)],
+        };
+
+        for (prefix, batch) in parts {
+            let writer = match self.writers.entry(prefix) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let path = if entry.key().is_empty() {
+                        self.parquet_path.clone()
+                    } else {
+                        StorageDir::partitioned_parquet_path(&self.parquet_path, entry.key())
+                    };
+                    let file = StagingFile::create(path).map_err(|_| MoveDataError::Create)?;
+                    entry.insert(ArrowWriter::try_new(
+                        file,
+                        self.schema.clone(),
+                        Some(self.props.clone()),
+                    )?)
+                }
+            };
+            if sort_columns.is_empty() {
+                writer.write(&batch)?;
+            } else {
+                writer.write(&sort_batch(&batch, sort_columns)?)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn close(self) -> Result<(), MoveDataError> {
+        for writer in self.writers.into_values() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+// splits rows by the prefix of their custom partition value, keeping their order
+fn partition_batch(
+    batch: &RecordBatch,
+    column: &str,
+    index: Option<usize>,
+) -> Result<Vec<(String, RecordBatch)>, ArrowError> {
+    let Some(index) = index else {
+        let prefix = utils::custom_partition_to_prefix(column, None);
+        return Ok(vec![(prefix, batch.clone())]);
+    };
+
+    let values = batch.column(index);
+    let mut rows: HashMap<String, Vec<u32>> = HashMap::new();
+    for row in 0..batch.num_rows() {
+        let value = if values.is_null(row) {
+            None
+        } else {
+            Some(array_value_to_string(values, row)?)
+        };
+        rows.entry(utils::custom_partition_to_prefix(column, value.as_deref()))
+            .or_default()
+            .push(row as u32);
+    }
+
+    rows.into_iter()
+        .map(|(prefix, rows)| {
+            let indices = UInt32Array::from(rows);
+            let columns = batch
+                .columns()
+                .iter()
+                .map(|column| take(column.as_ref(), &indices, None))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((prefix, RecordBatch::try_new(batch.schema(), columns)?))
+        })
+        .collect()
+}
+
+fn sort_batch(
+    batch: &RecordBatch,
+    sort_columns: &[(usize, bool)],
+) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<SortColumn> = sort_columns
+        .iter()
+        .map(|&(index, descending)| SortColumn {
+            values: batch.column(index).clone(),
+            options: Some(SortOptions {
+                descending,
+                nulls_first: true,
+            }),
+        })
+        .collect();
+    let indices = lexsort_to_indices(&columns, None)?;
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| take(column.as_ref(), &indices, None))
+        .collect::<Result<Vec<_>, _>>()?;
+    RecordBatch::try_new(batch.schema(), columns)
+}
+
 fn parquet_writer_props(
     time_partition: Option<String>,
     index_time_partition: usize,
+    sort_columns: &[(usize, bool)],
 ) -> WriterPropertiesBuilder {
     let index_time_partition: i32 = index_time_partition as i32;
+    // without sort keys rows are ordered by time, newest first
+    let sorting_columns = if sort_columns.is_empty() {
+        vec![SortingColumn {
+            column_idx: index_time_partition,
+            descending: true,
+            nulls_first: true,
+        }]
+    } else {
+        sort_columns
+            .iter()
+            .map(|&(index, descending)| SortingColumn {
+                column_idx: index as i32,
+                descending,
+                nulls_first: true,
+            })
+            .collect()
+    };
 
     if let Some(time_partition) = time_partition {
         WriterProperties::builder()
@@ -269,11 +609,7 @@ fn parquet_writer_props(
                 ColumnPath::new(vec![time_partition]),
                 Encoding::DELTA_BYTE_ARRAY,
             )
-            .set_sorting_columns(Some(vec![SortingColumn {
-                column_idx: index_time_partition,
-                descending: true,
-                nulls_first: true,
-            }]))
+            .set_sorting_columns(Some(sorting_columns))
     } else {
         WriterProperties::builder()
             .set_max_row_group_size(CONFIG.parseable.row_group_size)
@@ -282,11 +618,7 @@ fn parquet_writer_props(
                 ColumnPath::new(vec![DEFAULT_TIMESTAMP_KEY.to_string()]),
                 Encoding::DELTA_BINARY_PACKED,
             )
-            .set_sorting_columns(Some(vec![SortingColumn {
-                column_idx: index_time_partition,
-                descending: true,
-                nulls_first: true,
-            }]))
+            .set_sorting_columns(Some(sorting_columns))
     }
 }
 
@@ -301,3 +633,149 @@ pub enum MoveDataError {
     #[error("Could not generate parquet file")]
     Create,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use arrow_array::{Array, Int64Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema};
+
+    use super::{partition_batch, sort_batch, sort_columns, StorageDir};
+    use crate::storage::SortKey;
+
+    #[test]
+    fn sorts_across_batches_by_keys() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("service", DataType::Utf8, true),
+            Field::new("latency", DataType::Int64, true),
+        ]));
+        let batch = |services: Vec<&str>, latencies: Vec<i64>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(StringArray::from(services)),
+                    Arc::new(Int64Array::from(latencies)),
+                ],
+            )
+            .unwrap()
+        };
+        let batches = [batch(vec!["b", "a"], vec![1, 2]), batch(vec!["a"], vec![5])];
+        let batch = arrow_select::concat::concat_batches(&schema, &batches).unwrap();
+        let keys = [
+            SortKey {
+                column: "service".to_string(),
+                descending: false,
+            },
+            SortKey {
+                column: "missing".to_string(),
+                descending: false,
+            },
+            SortKey {
+                column: "latency".to_string(),
+                descending: true,
+            },
+        ];
+
+        let columns = sort_columns(&schema, &keys);
+        assert_eq!(columns, vec![(0, false), (1, true)]);
+
+        let sorted = sort_batch(&batch, &columns).unwrap();
+        let latency = sorted
+            .column(1)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(latency.values(), &[5, 2, 1]);
+    }
+
+    #[test]
+    fn splits_rows_by_partition_value() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "tenant",
+            DataType::Utf8,
+            true,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(StringArray::from(vec![
+                Some("acme"),
+                None,
+                Some("acme"),
+            ]))],
+        )
+        .unwrap();
+
+        let mut parts = partition_batch(&batch, "tenant", Some(0)).unwrap();
+        parts.sort_by(|a, b| a.0.cmp(&b.0));
+        let parts: Vec<_> = parts
+            .iter()
+            .map(|(prefix, batch)| (prefix.as_str(), batch.num_rows()))
+            .collect();
+        assert_eq!(parts, vec![("tenant=/", 1), ("tenant=acme/", 2)]);
+    }
+
+    #[test]
+    fn partitioned_object_path() {
+        let path = StorageDir::partitioned_parquet_path(
+            Path::new("/staging/app/date=2024-01-01.hour=10.minute=05.10.0.0.1.8000.data.parquet"),
+            "tenant=acme/",
+        );
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            StorageDir::parquet_object_path(filename),
+            "date=2024-01-01/hour=10/minute=05/tenant=acme/10.0.0.1.8000.data.parquet"
+        );
+        assert_eq!(
+            StorageDir::parquet_object_path(
+                "date=2024-01-01.hour=10.minute=05.10.0.0.1.8000.data.parquet"
+            ),
+            "date=2024-01-01/hour=10/minute=05/10.0.0.1.8000.data.parquet"
+        );
+    }
+
+    #[test]
+    fn sealed_and_later_files_of_a_minute_are_uploaded_apart() {
+        let open = "abc.date=2024-01-01.hour=10.minute=05.10.0.0.1.8000.data.arrows";
+        let sealed = "abc-sealed-01HQ.date=2024-01-01.hour=10.minute=05.10.0.0.1.8000.data.arrows";
+        assert!(!StorageDir::is_sealed(open));
+        assert!(StorageDir::is_sealed(sealed));
+
+        // the sealed files are converted on flush, the rest of the minute once it is over
+        let mut objects = HashSet::new();
+        for arrow_file in [sealed, open] {
+            let group = StorageDir::arrow_path_to_parquet(Path::new(arrow_file));
+            let parquet_path = StorageDir::unique_parquet_path(&group);
+            let filename = parquet_path.file_name().unwrap().to_str().unwrap();
+            let object = StorageDir::parquet_object_path(filename);
+            assert!(object.starts_with("date=2024-01-01/hour=10/minute=05/10.0.0.1.8000."));
+            assert!(object.ends_with(".data.parquet"));
+            objects.insert(object);
+        }
+        assert_eq!(objects.len(), 2);
+    }
+
+    #[test]
+    fn minute_drained_on_shutdown_is_not_replaced_after_a_restart() {
+        // staged before the shutdown and converted by the drain, then staged again by the
+        // restarted server within the same minute
+        let before = "abc.date=2024-01-01.hour=10.minute=05.10.0.0.1.8000.data.arrows";
+        let after = "def.date=2024-01-01.hour=10.minute=05.10.0.0.1.8000.data.arrows";
+
+        let drained = StorageDir::arrow_path_to_parquet(Path::new(before));
+        let restarted = StorageDir::arrow_path_to_parquet(Path::new(after));
+        assert_eq!(drained, restarted);
+
+        let objects: HashSet<_> = [drained, restarted]
+            .iter()
+            .map(|group| {
+                let parquet_path = StorageDir::unique_parquet_path(group);
+                let filename = parquet_path.file_name().unwrap().to_str().unwrap();
+                StorageDir::parquet_object_path(filename)
+            })
+            .collect();
+        assert_eq!(objects.len(), 2);
+    }
+}
@@ -0,0 +1,637 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Encryption of the files kept on the local disk of a node: staging arrow files, the parquet
+//! files converted from them, spooled request bodies and the replicas of events of other
+//! ingesters. Files are sealed in chunks with AES-256-GCM, so that they can be appended to and
+//! read from any offset while a changed chunk fails to decrypt. Every chunk is prefixed with
+//! its length and uses the nonce of the file followed by its index. Files without the header
+//! are read as they are, staging written before encryption was enabled stays readable.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::option::CONFIG;
+
+const MAGIC: &[u8; 4] = b"PSE2";
+const KEY_ID_LEN: usize = 4;
+const NONCE_PREFIX_LEN: usize = 8;
+/// Magic, id of the key and nonce prefix at the start of every encrypted file
+pub const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + NONCE_PREFIX_LEN;
+// buffered data is sealed as a chunk once it is this long, or when the writer is flushed
+const CHUNK_LEN: usize = 64 * 1024;
+const LEN_PREFIX_LEN: usize = 4;
+const TAG_LEN: usize = 16;
+
+/// 256 bit key, given as 64 hex characters or in base64
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+impl EncryptionKey {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let bytes = match hex::decode(s) {
+            Ok(bytes) => bytes,
+            Err(_) => base64::engine::general_purpose::STANDARD
+                .decode(s)
+                .map_err(|_| "key is neither hex nor base64".to_string())?,
+        };
+        let key: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| "key has to be 32 bytes long".to_string())?;
+        Ok(Self(key))
+    }
+
+    // files name the key they were written with, so that a changed key is reported as such
+    fn id(&self) -> [u8; KEY_ID_LEN] {
+        let digest = Sha256::digest(self.0);
+        let mut id = [0; KEY_ID_LEN];
+        id.copy_from_slice(&digest[..KEY_ID_LEN]);
+        id
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+fn key() -> Option<&'static EncryptionKey> {
+    CONFIG.parseable.staging_encryption_key.as_ref()
+}
+
+pub fn enabled() -> bool {
+    key().is_some()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Seals and opens the chunks of a file
+struct ChunkCipher {
+    cipher: Aes256Gcm,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    // index of the next chunk sealed
+    next_chunk: u32,
+}
+
+impl ChunkCipher {
+    fn nonce(&self, index: u32) -> [u8; NONCE_PREFIX_LEN + 4] {
+        let mut nonce = [0; NONCE_PREFIX_LEN + 4];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..].copy_from_slice(&index.to_be_bytes());
+        nonce
+    }
+
+    // the chunk with its length prefix, as written to the file
+    fn seal(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let index = self.next_chunk;
+        self.next_chunk = index
+            .checked_add(1)
+            .ok_or_else(|| invalid_data("staging file has too many chunks"))?;
+        let sealed = self
+            .cipher
+            .encrypt(Nonce::from_slice(&self.nonce(index)), data)
+            .map_err(|_| invalid_data("failed to encrypt staging data"))?;
+        let mut chunk = Vec::with_capacity(LEN_PREFIX_LEN + sealed.len());
+        chunk.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&sealed);
+        Ok(chunk)
+    }
+
+    fn open(&self, index: u32, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher
+            .decrypt(Nonce::from_slice(&self.nonce(index)), sealed)
+            .map_err(|_| invalid_data("staging file was changed or is corrupted"))
+    }
+}
+
+fn new_header(key: &EncryptionKey) -> ([u8; HEADER_LEN], ChunkCipher) {
+    let nonce_prefix: [u8; NONCE_PREFIX_LEN] = rand::random();
+    let mut header = [0; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()..MAGIC.len() + KEY_ID_LEN].copy_from_slice(&key.id());
+    header[MAGIC.len() + KEY_ID_LEN..].copy_from_slice(&nonce_prefix);
+    let cipher = ChunkCipher {
+        cipher: key.cipher(),
+        nonce_prefix,
+        next_chunk: 0,
+    };
+    (header, cipher)
+}
+
+// cipher of an encrypted file with the given header, None for a file that is not encrypted.
+// The key is only looked up for encrypted files.
+fn read_header<'a>(
+    header: &[u8],
+    key: impl FnOnce() -> Option<&'a EncryptionKey>,
+) -> io::Result<Option<ChunkCipher>> {
+    if header.len() < HEADER_LEN || !header.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let key = key().ok_or_else(|| {
+        invalid_data("staging file is encrypted but no staging encryption key is set")
+    })?;
+    if header[MAGIC.len()..MAGIC.len() + KEY_ID_LEN] != key.id() {
+        return Err(invalid_data("staging file was encrypted with another key"));
+    }
+    let mut nonce_prefix = [0; NONCE_PREFIX_LEN];
+    nonce_prefix.copy_from_slice(&header[MAGIC.len() + KEY_ID_LEN..HEADER_LEN]);
+    Ok(Some(ChunkCipher {
+        cipher: key.cipher(),
+        nonce_prefix,
+        next_chunk: 0,
+    }))
+}
+
+// Chunk of an encrypted file
+struct Chunk {
+    // offset of the sealed data in the file
+    offset: u64,
+    sealed_len: u32,
+    // offset of the data in the decrypted file
+    start: u64,
+}
+
+// Chunks of an encrypted file after its header. A chunk cut short by a crash while it was
+// written ends the file, along with the data of the chunk.
+fn read_chunks<R: Read + Seek>(file: &mut R) -> io::Result<Vec<Chunk>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut offset = file.seek(SeekFrom::Start(HEADER_LEN as u64))?;
+    let mut start = 0;
+    let mut chunks = Vec::new();
+    while offset + LEN_PREFIX_LEN as u64 <= len {
+        let mut prefix = [0; LEN_PREFIX_LEN];
+        file.read_exact(&mut prefix)?;
+        let sealed_len = u32::from_le_bytes(prefix);
+        let data_offset = offset + LEN_PREFIX_LEN as u64;
+        if (sealed_len as usize) < TAG_LEN || data_offset + sealed_len as u64 > len {
+            break;
+        }
+        chunks.push(Chunk {
+            offset: data_offset,
+            sealed_len,
+            start,
+        });
+        start += (sealed_len as usize - TAG_LEN) as u64;
+        offset = file.seek(SeekFrom::Start(data_offset + sealed_len as u64))?;
+    }
+    Ok(chunks)
+}
+
+/// File in staging, written encrypted if a staging encryption key is set. Written data is
+/// buffered until a chunk is full or the file is flushed, and flushed when the file is dropped.
+pub struct StagingFile {
+    file: File,
+    cipher: Option<ChunkCipher>,
+    buffer: Vec<u8>,
+}
+
+impl StagingFile {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::init(File::create(path)?, key())
+    }
+
+    /// Append to the file, in the format it was written in if it exists
+    pub fn append(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::append_with(path, key())
+    }
+
+    fn append_with(path: impl AsRef<Path>, key: Option<&EncryptionKey>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        if len == 0 {
+            return Self::init(file, key);
+        }
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        (&mut file)
+            .take(HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+        let mut cipher = read_header(&header, || key)?;
+        if let Some(cipher) = &mut cipher {
+            let chunks = read_chunks(&mut file)?;
+            let end = chunks.last().map_or(HEADER_LEN as u64, |chunk| {
+                chunk.offset + chunk.sealed_len as u64
+            });
+            // drop a chunk cut short, so that the appended chunks can be read
+            if end < len {
+                file.set_len(end)?;
+            }
+            cipher.next_chunk = chunks.len() as u32;
+        }
+        Ok(Self {
+            file,
+            cipher,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn init(mut file: File, key: Option<&EncryptionKey>) -> io::Result<Self> {
+        let cipher = match key {
+            Some(key) => {
+                let (header, cipher) = new_header(key);
+                file.write_all(&header)?;
+                Some(cipher)
+            }
+            None => None,
+        };
+        Ok(Self {
+            file,
+            cipher,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn seal_buffer(&mut self) -> io::Result<()> {
+        let Some(cipher) = &mut self.cipher else {
+            return Ok(());
+        };
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = cipher.seal(&self.buffer)?;
+        self.file.write_all(&chunk)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl fmt::Debug for StagingFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StagingFile")
+            .field("file", &self.file)
+            .field("encrypted", &self.cipher.is_some())
+            .finish()
+    }
+}
+
+impl Write for StagingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cipher.is_none() {
+            return self.file.write(buf);
+        }
+        let len = buf.len().min(CHUNK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == CHUNK_LEN {
+            self.seal_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.seal_buffer()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for StagingFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.seal_buffer() {
+            log::error!("failed to write buffered staging data: {err}");
+        }
+    }
+}
+
+/// Encrypts data written in parts to a file in staging from async code, which can not use a
+/// [`StagingFile`]. Every part is sealed as a chunk of its own.
+pub struct Encryptor {
+    cipher: Option<ChunkCipher>,
+}
+
+impl Encryptor {
+    /// Encryptor and the header to write first, empty if no staging encryption key is set
+    pub fn new() -> (Self, Vec<u8>) {
+        match key() {
+            Some(key) => {
+                let (header, cipher) = new_header(key);
+                (
+                    Self {
+                        cipher: Some(cipher),
+                    },
+                    header.to_vec(),
+                )
+            }
+            None => (Self { cipher: None }, Vec::new()),
+        }
+    }
+
+    pub fn encrypt<'a>(&mut self, data: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        match &mut self.cipher {
+            Some(cipher) => Ok(Cow::Owned(cipher.seal(data)?)),
+            None => Ok(Cow::Borrowed(data)),
+        }
+    }
+}
+
+/// Reader of a file in staging, decrypting it if it is encrypted
+pub struct StagingReader<R = File> {
+    file: R,
+    encrypted: Option<EncryptedFile>,
+}
+
+struct EncryptedFile {
+    cipher: ChunkCipher,
+    chunks: Vec<Chunk>,
+    len: u64,
+    // position in the decrypted file, with the last chunk read
+    pos: u64,
+    current: Option<(usize, Vec<u8>)>,
+}
+
+pub fn open(path: impl AsRef<Path>) -> io::Result<StagingReader> {
+    StagingReader::new(File::open(path)?, key)
+}
+
+impl<R: Read + Seek> StagingReader<R> {
+    fn new<'a>(mut file: R, key: impl FnOnce() -> Option<&'a EncryptionKey>) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        (&mut file)
+            .take(HEADER_LEN as u64)
+            .read_to_end(&mut header)?;
+        let Some(cipher) = read_header(&header, key)? else {
+            file.rewind()?;
+            return Ok(Self {
+                file,
+                encrypted: None,
+            });
+        };
+        let chunks = read_chunks(&mut file)?;
+        let len = chunks.last().map_or(0, |chunk| {
+            chunk.start + (chunk.sealed_len as usize - TAG_LEN) as u64
+        });
+        Ok(Self {
+            file,
+            encrypted: Some(EncryptedFile {
+                cipher,
+                chunks,
+                len,
+                pos: 0,
+                current: None,
+            }),
+        })
+    }
+}
+
+/// Contents of a file in staging
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+impl<R> fmt::Debug for StagingReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StagingReader")
+            .field("encrypted", &self.encrypted.is_some())
+            .finish()
+    }
+}
+
+impl<R: Read + Seek> Read for StagingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(encrypted) = &mut self.encrypted else {
+            return self.file.read(buf);
+        };
+        if encrypted.pos >= encrypted.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = encrypted
+            .chunks
+            .partition_point(|chunk| chunk.start <= encrypted.pos)
+            - 1;
+        if encrypted.current.as_ref().map(|(current, _)| *current) != Some(index) {
+            let chunk = &encrypted.chunks[index];
+            let mut sealed = vec![0; chunk.sealed_len as usize];
+            self.file.seek(SeekFrom::Start(chunk.offset))?;
+            self.file.read_exact(&mut sealed)?;
+            let data = encrypted.cipher.open(index as u32, &sealed)?;
+            encrypted.current = Some((index, data));
+        }
+        let (_, data) = encrypted.current.as_ref().expect("chunk is read");
+        let offset = (encrypted.pos - encrypted.chunks[index].start) as usize;
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        encrypted.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for StagingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let Some(encrypted) = &mut self.encrypted else {
+            return self.file.seek(pos);
+        };
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => encrypted.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => encrypted.len.checked_add_signed(offset),
+        };
+        encrypted.pos =
+            pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+        Ok(encrypted.pos)
+    }
+}
+
+/// Data to write to a file in staging, encrypted if a staging encryption key is set
+pub fn encrypt(data: &[u8]) -> io::Result<Vec<u8>> {
+    encrypt_with(data, key())
+}
+
+fn encrypt_with(data: &[u8], key: Option<&EncryptionKey>) -> io::Result<Vec<u8>> {
+    let Some(key) = key else {
+        return Ok(data.to_vec());
+    };
+    let (header, mut cipher) = new_header(key);
+    let mut encrypted = header.to_vec();
+    for chunk in data.chunks(CHUNK_LEN) {
+        encrypted.extend_from_slice(&cipher.seal(chunk)?);
+    }
+    Ok(encrypted)
+}
+
+/// Data read from a file in staging
+pub fn decrypt(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    decrypt_with(data, key)
+}
+
+fn decrypt_with<'a>(
+    data: Vec<u8>,
+    key: impl FnOnce() -> Option<&'a EncryptionKey>,
+) -> io::Result<Vec<u8>> {
+    if !data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+    let mut decrypted = Vec::new();
+    StagingReader::new(Cursor::new(data), key)?.read_to_end(&mut decrypted)?;
+    Ok(decrypted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+
+    use super::{
+        decrypt_with, encrypt_with, EncryptionKey, StagingFile, StagingReader, CHUNK_LEN,
+        HEADER_LEN,
+    };
+
+    const KEY: EncryptionKey = EncryptionKey([7; 32]);
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(ulid::Ulid::new().to_string())
+    }
+
+    fn read(path: &PathBuf, key: Option<&EncryptionKey>) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        StagingReader::new(std::fs::File::open(path)?, || key)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn parse_key() {
+        let hex = "00".repeat(32);
+        assert!(EncryptionKey::parse(&hex).is_ok());
+        assert!(EncryptionKey::parse("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=").is_ok());
+        assert!(EncryptionKey::parse("abcd").is_err());
+    }
+
+    #[test]
+    fn appended_data_is_read_back_from_any_offset() {
+        let path = temp_path();
+        let large: Vec<u8> = (0..CHUNK_LEN * 2 + 100).map(|i| i as u8).collect();
+        let mut file = StagingFile::append_with(&path, Some(&KEY)).unwrap();
+        file.write_all(b"first write ").unwrap();
+        file.flush().unwrap();
+        file.write_all(&large).unwrap();
+        drop(file);
+        let mut file = StagingFile::append_with(&path, Some(&KEY)).unwrap();
+        file.write_all(b"and an append").unwrap();
+        drop(file);
+
+        let expected = [b"first write ".as_slice(), &large, b"and an append"].concat();
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(12).any(|window| window == b"first write "));
+        assert_eq!(read(&path, Some(&KEY)).unwrap(), expected);
+
+        let mut reader =
+            StagingReader::new(std::fs::File::open(&path).unwrap(), || Some(&KEY)).unwrap();
+        for offset in [5, CHUNK_LEN as u64, expected.len() as u64 - 13] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = [0; 13];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, expected[offset as usize..offset as usize + 13]);
+        }
+        reader.seek(SeekFrom::End(-6)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, b"append");
+
+        // files written without a key are read as they are
+        let plain = temp_path();
+        let mut file = StagingFile::append_with(&plain, None).unwrap();
+        file.write_all(b"plain").unwrap();
+        drop(file);
+        assert_eq!(read(&plain, Some(&KEY)).unwrap(), b"plain");
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(plain).unwrap();
+    }
+
+    #[test]
+    fn changed_data_and_other_keys_are_rejected() {
+        let path = temp_path();
+        let mut file = StagingFile::append_with(&path, Some(&KEY)).unwrap();
+        file.write_all(b"some events").unwrap();
+        drop(file);
+
+        let other = EncryptionKey([8; 32]);
+        assert_eq!(
+            read(&path, Some(&other)).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read(&path, None).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        let mut data = std::fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, data).unwrap();
+        assert_eq!(
+            read(&path, Some(&KEY)).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chunk_cut_short_is_dropped() {
+        let path = temp_path();
+        let mut file = StagingFile::append_with(&path, Some(&KEY)).unwrap();
+        file.write_all(b"complete").unwrap();
+        file.flush().unwrap();
+        file.write_all(b"cut short").unwrap();
+        drop(file);
+
+        // a crash while the second chunk was written
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        assert_eq!(read(&path, Some(&KEY)).unwrap(), b"complete");
+
+        let mut file = StagingFile::append_with(&path, Some(&KEY)).unwrap();
+        file.write_all(b" and appended").unwrap();
+        drop(file);
+        assert_eq!(read(&path, Some(&KEY)).unwrap(), b"complete and appended");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn encrypted_data_is_decrypted() {
+        let data: Vec<u8> = (0..CHUNK_LEN * 3).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt_with(&data, Some(&KEY)).unwrap();
+        assert!(encrypted.len() > HEADER_LEN + data.len());
+        assert_eq!(decrypt_with(encrypted, || Some(&KEY)).unwrap(), data);
+        assert_eq!(encrypt_with(&data, None).unwrap(), data);
+        assert_eq!(decrypt_with(data.clone(), || None).unwrap(), data);
+    }
+}
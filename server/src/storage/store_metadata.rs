@@ -35,6 +35,8 @@ use crate::{
 
 use super::{object_storage::parseable_json_path, PARSEABLE_METADATA_FILE_NAME};
 
+pub const CURRENT_STORAGE_METADATA_VERSION: &str = "v3";
+
 // Expose some static variables for internal usage
 pub static STORAGE_METADATA: OnceCell<StaticStorageMetadata> = OnceCell::new();
 
@@ -66,7 +68,7 @@ pub struct StorageMetadata {
 impl StorageMetadata {
     pub fn new() -> Self {
         Self {
-            version: "v3".to_string(),
+            version: CURRENT_STORAGE_METADATA_VERSION.to_string(),
             mode: CONFIG.storage_name.to_owned(),
             staging: CONFIG.staging_dir().to_path_buf(),
             storage: CONFIG.storage().get_endpoint(),
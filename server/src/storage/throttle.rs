@@ -0,0 +1,298 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::metrics_layer::ErrorClass;
+use crate::option::CONFIG;
+
+// throttled requests are retried for this long before they fail
+const THROTTLE_RETRY_TIMEOUT: Duration = Duration::from_secs(120);
+const MIN_THROTTLE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_THROTTLE_BACKOFF: Duration = Duration::from_secs(30);
+
+pub static UPLOAD_THROTTLE: Lazy<Throttle> =
+    Lazy::new(|| Throttle::new(CONFIG.parseable.upload_bandwidth_limit));
+
+/// Uploads of parquet files by the sync
+pub static UPLOAD_LIMIT: Lazy<AdaptiveLimit> =
+    Lazy::new(|| AdaptiveLimit::new("uploads", CONFIG.parseable.upload_concurrency));
+
+/// Reads of query scans, across all queries of the server
+pub static SCAN_LIMIT: Lazy<AdaptiveLimit> =
+    Lazy::new(|| AdaptiveLimit::new("query scans", super::MAX_OBJECT_STORE_REQUESTS));
+
+/// Paces transfers to an average rate. Every transfer reserves the time its bytes take
+/// at the configured rate and waits until the transfers reserved before it are done.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: Option<u64>,
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec,
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    /// Wait till `bytes` can be sent without exceeding the rate
+    pub async fn acquire(&self, bytes: u64) {
+        if let Some(start) = self.reserve(bytes, Instant::now()) {
+            tokio::time::sleep_until(start).await;
+        }
+    }
+
+    fn reserve(&self, bytes: u64, now: Instant) -> Option<Instant> {
+        let bytes_per_sec = self.bytes_per_sec?;
+        let mut next_slot = self.next_slot.lock().expect("not poisoned");
+        let start = next_slot.map_or(now, |slot| slot.max(now));
+        *next_slot = Some(start + Duration::from_secs_f64(bytes as f64 / bytes_per_sec as f64));
+        Some(start)
+    }
+}
+
+/// Limit of requests in flight which adapts to throttling of the object store. A throttled
+/// request halves the limit and holds back new requests for a backoff, which doubles while the
+/// store keeps throttling. The limit grows back by a quarter after as many successful requests,
+/// the backoff is reset once the limit is back at its maximum.
+#[derive(Debug)]
+pub struct AdaptiveLimit {
+    name: &'static str,
+    max: usize,
+    state: Mutex<LimitState>,
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct LimitState {
+    limit: usize,
+    in_flight: usize,
+    successes: usize,
+    backoff: Duration,
+    resume_at: Option<Instant>,
+}
+
+pub struct LimitPermit<'a>(&'a AdaptiveLimit);
+
+impl Drop for LimitPermit<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().expect("not poisoned").in_flight -= 1;
+        self.0.released.notify_waiters();
+    }
+}
+
+impl AdaptiveLimit {
+    pub fn new(name: &'static str, max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            name,
+            max,
+            state: Mutex::new(LimitState {
+                limit: max,
+                in_flight: 0,
+                successes: 0,
+                backoff: MIN_THROTTLE_BACKOFF,
+                resume_at: None,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    /// Wait till a request can be sent within the limit
+    pub async fn acquire(&self) -> LimitPermit<'_> {
+        loop {
+            // registered before the limit is checked, so that no release is missed
+            let released = self.released.notified();
+            let resume_at = {
+                let mut state = self.state.lock().expect("not poisoned");
+                match state.resume_at {
+                    Some(resume_at) if resume_at > Instant::now() => Some(resume_at),
+                    _ if state.in_flight < state.limit => {
+                        state.in_flight += 1;
+                        return LimitPermit(self);
+                    }
+                    _ => None,
+                }
+            };
+            match resume_at {
+                Some(resume_at) => tokio::time::sleep_until(resume_at).await,
+                None => released.await,
+            }
+        }
+    }
+
+    fn throttled(&self, now: Instant) {
+        let mut state = self.state.lock().expect("not poisoned");
+        // requests sent before the backoff started do not reduce the limit again
+        if state.resume_at.is_some_and(|resume_at| resume_at > now) {
+            return;
+        }
+        state.limit = (state.limit / 2).max(1);
+        state.successes = 0;
+        state.resume_at = Some(now + state.backoff);
+        log::warn!(
+            "object storage throttles {}, backing off for {} with at most {} requests in flight",
+            self.name,
+            humantime::format_duration(state.backoff),
+            state.limit
+        );
+        state.backoff = (state.backoff * 2).min(MAX_THROTTLE_BACKOFF);
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.state.lock().expect("not poisoned");
+        if state.limit < self.max {
+            state.successes += 1;
+            if state.successes >= state.limit {
+                state.limit = (state.limit + state.limit / 4 + 1).min(self.max);
+                state.successes = 0;
+            }
+        }
+        // a store which throttles every other request keeps backing off longer
+        if state.limit == self.max {
+            state.backoff = MIN_THROTTLE_BACKOFF;
+        }
+    }
+
+    #[cfg(test)]
+    fn limit(&self) -> usize {
+        self.state.lock().expect("not poisoned").limit
+    }
+
+    #[cfg(test)]
+    fn backoff(&self) -> Duration {
+        self.state.lock().expect("not poisoned").backoff
+    }
+}
+
+/// Send a request within the limit. A throttled request is retried once the limit allows,
+/// until the requests have been throttled for [`THROTTLE_RETRY_TIMEOUT`].
+pub async fn retry_throttled<T, E, F, Fut>(limit: &AdaptiveLimit, mut request: F) -> Result<T, E>
+where
+    E: std::error::Error + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let deadline = Instant::now() + THROTTLE_RETRY_TIMEOUT;
+    loop {
+        let res = limited(limit, request()).await;
+        if matches!(&res, Err(err) if ErrorClass::of(err) == ErrorClass::Throttled)
+            && Instant::now() < deadline
+        {
+            continue;
+        }
+        return res;
+    }
+}
+
+/// Send a request once within the limit, a throttled request lowers the limit
+pub async fn limited<T, E>(
+    limit: &AdaptiveLimit,
+    request: impl Future<Output = Result<T, E>>,
+) -> Result<T, E>
+where
+    E: std::error::Error + 'static,
+{
+    let _permit = limit.acquire().await;
+    let res = request.await;
+    match &res {
+        Ok(_) => limit.succeeded(),
+        Err(err) if ErrorClass::of(err) == ErrorClass::Throttled => limit.throttled(Instant::now()),
+        Err(_) => (),
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{AdaptiveLimit, Throttle, MIN_THROTTLE_BACKOFF};
+
+    #[test]
+    fn transfers_are_paced() {
+        let throttle = Throttle::new(Some(1024));
+        let now = Instant::now();
+
+        assert_eq!(throttle.reserve(2048, now), Some(now));
+        assert_eq!(
+            throttle.reserve(512, now),
+            Some(now + Duration::from_secs(2))
+        );
+        // idle time is not accumulated as credit
+        let later = now + Duration::from_secs(10);
+        assert_eq!(throttle.reserve(512, later), Some(later));
+
+        assert_eq!(Throttle::new(None).reserve(2048, now), None);
+    }
+
+    #[test]
+    fn limits_adapt_to_throttling() {
+        let limit = AdaptiveLimit::new("test", 8);
+        let now = Instant::now();
+
+        limit.throttled(now);
+        assert_eq!(limit.limit(), 4);
+        // throttled requests sent before the backoff count once
+        limit.throttled(now);
+        assert_eq!(limit.limit(), 4);
+
+        for _ in 0..4 {
+            limit.succeeded();
+        }
+        assert_eq!(limit.limit(), 6);
+
+        limit.throttled(now + Duration::from_secs(1));
+        assert_eq!(limit.limit(), 3);
+        limit.throttled(now + Duration::from_millis(1200));
+        assert_eq!(limit.limit(), 3);
+        limit.throttled(now + Duration::from_secs(2));
+        assert_eq!(limit.limit(), 1);
+    }
+
+    #[test]
+    fn backoff_grows_while_successes_alternate_with_throttling() {
+        let limit = AdaptiveLimit::new("test", 8);
+        let mut now = Instant::now();
+
+        for backoff in [1, 2, 4] {
+            limit.throttled(now);
+            limit.succeeded();
+            assert_eq!(limit.backoff(), Duration::from_secs(backoff));
+            now += limit.backoff();
+        }
+        assert_eq!(limit.limit(), 2);
+
+        while limit.limit() < 8 {
+            assert_eq!(limit.backoff(), Duration::from_secs(4));
+            limit.succeeded();
+        }
+        assert_eq!(limit.backoff(), MIN_THROTTLE_BACKOFF);
+    }
+}
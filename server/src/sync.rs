@@ -16,17 +16,34 @@
  *
  */
 
-use clokwerk::{AsyncScheduler, Job, Scheduler, TimeUnits};
+use chrono::Utc;
+use clokwerk::{AsyncScheduler, Scheduler, TimeUnits};
 use thread_priority::{ThreadBuilder, ThreadPriority};
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::TryRecvError;
 
+use std::collections::HashMap;
 use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crate::metadata::STREAM_INFO;
+use crate::monitor::{self, ServerEvent};
 use crate::option::CONFIG;
-use crate::{storage, STORAGE_UPLOAD_INTERVAL};
+use crate::{catalog, replication, storage, STORAGE_UPLOAD_INTERVAL};
+
+/// A stream's flush interval can not be set lower than this. Staging files are converted a
+/// minute at a time and the current minute is left to its writers, so flushing more often
+/// would not upload events any sooner.
+pub const MIN_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Streams are checked for a due flush at this interval, well below the minimum flush interval
+// so that flushes are not late by much.
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Extra time interval is added so that this schedular does not race with local sync.
+pub const DEFAULT_FLUSH_INTERVAL: Duration =
+    Duration::from_secs(STORAGE_UPLOAD_INTERVAL as u64 + 5);
 
 pub(crate) fn object_store_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, oneshot::Sender<()>) {
     let (outbox_tx, outbox_rx) = oneshot::channel::<()>();
@@ -37,15 +54,48 @@ pub(crate) fn object_store_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, one
             let rt = actix_web::rt::System::new();
             rt.block_on(async {
                 let mut scheduler = AsyncScheduler::new();
+                let mut last_flush = HashMap::new();
                 scheduler
-                    .every(STORAGE_UPLOAD_INTERVAL.seconds())
-                    // Extra time interval is added so that this schedular does not race with local sync.
-                    .plus(5u32.seconds())
-                    .run(|| async {
-                        if let Err(e) = CONFIG.storage().get_object_store().sync().await {
-                            log::warn!("failed to sync local data with object store. {:?}", e);
+                    .every((FLUSH_CHECK_INTERVAL.as_secs() as u32).seconds())
+                    .run(move || {
+                        let due = due_streams(
+                            STREAM_INFO.flush_intervals(),
+                            &mut last_flush,
+                            Instant::now(),
+                            DEFAULT_FLUSH_INTERVAL,
+                        );
+                        async move {
+                            if due.is_empty() {
+                                return;
+                            }
+                            let started = Utc::now();
+                            let timer = Instant::now();
+                            match CONFIG.storage().get_object_store().sync_streams(&due).await {
+                                Ok(()) => {
+                                    monitor::record(ServerEvent::Flush {
+                                        streams: due.len(),
+                                        duration_ms: timer.elapsed().as_millis() as u64,
+                                    });
+                                    replication::trim_peers(
+                                        &due,
+                                        replication::uploaded_before(started),
+                                    )
+                                    .await
+                                }
+                                Err(e) => {
+                                    log::warn!(
+                                        "failed to sync local data with object store. {:?}",
+                                        e
+                                    );
+                                    monitor::record(ServerEvent::FlushFailed {
+                                        streams: due.len(),
+                                        error: e.to_string(),
+                                    });
+                                }
+                            }
                         }
                     });
+                scheduler.every(1.hour()).run(expire_snapshots);
 
                 loop {
                     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -70,6 +120,39 @@ pub(crate) fn object_store_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, one
     (handle, outbox_rx, inbox_tx)
 }
 
+// snapshots are expired by the node which committed them, next to the uploads committing them
+async fn expire_snapshots() {
+    for stream in STREAM_INFO.list_streams() {
+        let store = CONFIG.storage().get_object_store();
+        match catalog::expire_snapshots(store, &stream, CONFIG.parseable.snapshot_retention).await {
+            Ok(0) => {}
+            Ok(expired) => log::info!("expired {expired} snapshots of stream {stream}"),
+            Err(err) => log::warn!("failed to expire snapshots of stream {stream}: {err}"),
+        }
+    }
+}
+
+// streams whose flush interval has elapsed since their last flush.
+// Streams seen for the first time are considered flushed at `now`.
+fn due_streams(
+    streams: Vec<(String, Option<Duration>)>,
+    last_flush: &mut HashMap<String, Instant>,
+    now: Instant,
+    default: Duration,
+) -> Vec<String> {
+    last_flush.retain(|stream, _| streams.iter().any(|(name, _)| name == stream));
+
+    let mut due = Vec::new();
+    for (stream, interval) in streams {
+        let last = last_flush.entry(stream.clone()).or_insert(now);
+        if now.duration_since(*last) >= interval.unwrap_or(default) {
+            *last = now;
+            due.push(stream);
+        }
+    }
+    due
+}
+
 pub(crate) fn run_local_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, oneshot::Sender<()>) {
     let (outbox_tx, outbox_rx) = oneshot::channel::<()>();
     let (inbox_tx, inbox_rx) = oneshot::channel::<()>();
@@ -91,6 +174,7 @@ pub(crate) fn run_local_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, onesho
                 loop {
                     thread::sleep(Duration::from_millis(50));
                     scheduler.run_pending();
+                    crate::event::STREAM_WRITERS.flush_pending();
                     match AssertUnwindSafe(|| inbox_rx.try_recv())() {
                         Ok(_) => break,
                         Err(TryRecvError::Empty) => continue,
@@ -110,3 +194,40 @@ pub(crate) fn run_local_sync() -> (JoinHandle<()>, oneshot::Receiver<()>, onesho
 
     (handle, outbox_rx, inbox_tx)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use super::due_streams;
+
+    #[test]
+    fn flush_interval_override() {
+        let start = Instant::now();
+        let default = Duration::from_secs(65);
+        let streams = || {
+            vec![
+                ("fast".to_string(), Some(Duration::from_secs(10))),
+                ("default".to_string(), None),
+            ]
+        };
+
+        let mut last_flush = HashMap::new();
+        assert!(due_streams(streams(), &mut last_flush, start, default).is_empty());
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(
+            due_streams(streams(), &mut last_flush, now, default),
+            ["fast"]
+        );
+
+        let now = start + Duration::from_secs(65);
+        let mut due = due_streams(streams(), &mut last_flush, now, default);
+        due.sort();
+        assert_eq!(due, ["default", "fast"]);
+
+        due_streams(vec![], &mut last_flush, now, default);
+        assert!(last_flush.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! TLS settings shared by the HTTP server and the clients for outgoing requests.
+//!
+//! `P_TLS_CIPHERS` limits the cipher suites offered on both sides. With `P_FIPS_MODE` only the
+//! FIPS 140 approved AES-GCM suites, with SHA-256 or SHA-384, and the NIST P-256 and P-384 key
+//! exchange groups are used. The server and reqwest share one rustls, so both configs are built
+//! from the same selection. The object store client cannot be configured, see [`upgrade_proxy`].
+
+pub mod upgrade_proxy;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use rustls::{ConfigBuilder, ServerConfig, WantsVerifier};
+
+use crate::option::CONFIG;
+
+/// Cipher suites allowed in FIPS mode
+pub const FIPS_CIPHER_SUITES: &[&str] = &[
+    "TLS13_AES_256_GCM_SHA384",
+    "TLS13_AES_128_GCM_SHA256",
+    "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+    "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+    "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+    "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+];
+
+// key exchange groups allowed in FIPS mode, for the server and the clients
+static FIPS_KX_GROUPS: &[&rustls::SupportedKxGroup] =
+    &[&rustls::kx_group::SECP384R1, &rustls::kx_group::SECP256R1];
+
+/// Names of the cipher suites that can be configured
+pub fn cipher_suite_names() -> impl Iterator<Item = String> {
+    rustls::ALL_CIPHER_SUITES
+        .iter()
+        .map(|suite| format!("{:?}", suite.suite()))
+}
+
+// suites are matched by their IANA name
+fn allowed(suite: rustls::CipherSuite) -> bool {
+    let name = format!("{suite:?}");
+    let ciphers = &CONFIG.parseable.tls_ciphers;
+    (ciphers.is_empty() || ciphers.contains(&name))
+        && (!CONFIG.parseable.fips_mode || FIPS_CIPHER_SUITES.contains(&name.as_str()))
+}
+
+/// Whether cipher suites are restricted, clients keep the defaults of reqwest otherwise
+pub fn restricted() -> bool {
+    CONFIG.parseable.fips_mode || !CONFIG.parseable.tls_ciphers.is_empty()
+}
+
+/// Builder of the server config, with the configured cipher suites and key exchange groups
+pub fn server_config_builder() -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, rustls::Error>
+{
+    let suites: Vec<_> = rustls::ALL_CIPHER_SUITES
+        .iter()
+        .filter(|suite| allowed(suite.suite()))
+        .copied()
+        .collect();
+    let kx_groups: &[_] = if CONFIG.parseable.fips_mode {
+        FIPS_KX_GROUPS
+    } else {
+        &rustls::ALL_KX_GROUPS
+    };
+
+    ServerConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_kx_groups(kx_groups)
+        .with_safe_default_protocol_versions()
+}
+
+// DER encoded certificates in the PEM file at `path`, none if it cannot be read
+fn trusted_certs(path: &Path) -> Vec<Vec<u8>> {
+    let certs = File::open(path)
+        .map(BufReader::new)
+        .and_then(|mut reader| rustls_pemfile::certs(&mut reader));
+    match certs {
+        Ok(certs) => certs,
+        Err(err) => {
+            log::warn!(
+                "cannot trust {} for outgoing requests: {err}",
+                path.display()
+            );
+            Vec::new()
+        }
+    }
+}
+
+fn client_config(trusted: &[Vec<u8>]) -> rustls::ClientConfig {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    for cert in trusted {
+        if let Err(err) = roots.add(&rustls::Certificate(cert.clone())) {
+            log::warn!("cannot trust certificate for outgoing requests: {err}");
+        }
+    }
+
+    let suites: Vec<_> = rustls::ALL_CIPHER_SUITES
+        .iter()
+        .filter(|suite| allowed(suite.suite()))
+        .copied()
+        .collect();
+    let kx_groups: &[_] = if CONFIG.parseable.fips_mode {
+        FIPS_KX_GROUPS
+    } else {
+        &rustls::ALL_KX_GROUPS
+    };
+
+    rustls::ClientConfig::builder()
+        .with_cipher_suites(&suites)
+        .with_kx_groups(kx_groups)
+        .with_safe_default_protocol_versions()
+        .expect("cipher suites are validated at startup")
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+}
+
+/// Builder of clients for outgoing requests, with the cipher suites allowed for the server.
+/// The certificates at `trusted` are trusted besides the usual roots.
+pub fn client_builder(trusted: Option<&Path>) -> reqwest::ClientBuilder {
+    let trusted = trusted.map(trusted_certs).unwrap_or_default();
+    let builder = reqwest::Client::builder().use_rustls_tls();
+
+    if restricted() {
+        return builder.use_preconfigured_tls(client_config(&trusted));
+    }
+
+    trusted.iter().fold(
+        builder,
+        |builder, cert| match reqwest::Certificate::from_der(cert) {
+            Ok(cert) => builder.add_root_certificate(cert),
+            Err(err) => {
+                log::warn!("cannot trust certificate for outgoing requests: {err}");
+                builder
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cipher_suite_names, FIPS_CIPHER_SUITES};
+
+    #[test]
+    fn fips_suites_are_known() {
+        let names: Vec<_> = cipher_suite_names().collect();
+        for suite in FIPS_CIPHER_SUITES {
+            assert!(names.iter().any(|name| name == suite), "{suite}");
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Proxy on the loopback interface through which the object store client reaches an https
+//! endpoint with the restricted cipher suites.
+//!
+//! object_store builds its own reqwest client and offers no way to configure its TLS. When cipher
+//! suites are restricted it sends plain http to this proxy instead, which sends every request on
+//! over https with a client from [`client_builder`]. Host, path and headers are kept, so request
+//! signatures stay valid. Requests for other hosts and CONNECT tunnels, whose TLS could not be
+//! restricted, are refused.
+
+use std::convert::Infallible;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use http::header::{self, HeaderMap};
+use http::{Method, Request, Response, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+
+use super::client_builder;
+
+// headers which only apply to a single connection and are not forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Start the proxy for the endpoint with the given authority, returns the address it listens on
+pub fn start(endpoint: &str) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+
+    // responses are passed on as they are
+    let client = client_builder(None)
+        .redirect(reqwest::redirect::Policy::none())
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        .build()
+        .map_err(io::Error::other)?;
+    let endpoint: Arc<str> = endpoint.to_ascii_lowercase().into();
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("object-store-proxy")
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name("object-store-proxy".to_owned())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let client = client.clone();
+                    let endpoint = endpoint.clone();
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |req| {
+                            forward(client.clone(), endpoint.clone(), req)
+                        }))
+                    }
+                });
+                let server = Server::from_tcp(listener)
+                    .expect("listener is bound")
+                    .serve(make_service);
+                if let Err(err) = server.await {
+                    log::error!("object store proxy stopped: {err}");
+                }
+            })
+        })?;
+
+    Ok(addr)
+}
+
+// https url for a request to the proxy, none unless it is a plain http request for the endpoint
+fn upstream_url(method: &Method, uri: &Uri, endpoint: &str) -> Option<String> {
+    if method == Method::CONNECT || uri.scheme_str() != Some("http") {
+        return None;
+    }
+    let authority = uri.authority()?.as_str().to_ascii_lowercase();
+    // bucket names are prepended to the endpoint for virtual hosted style requests
+    let is_endpoint = authority == endpoint
+        || authority
+            .strip_suffix(endpoint)
+            .is_some_and(|bucket| bucket.ends_with('.'));
+    if !is_endpoint {
+        return None;
+    }
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    Some(format!("https://{authority}{path}"))
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+    // the host is set from the url, which has the same authority
+    headers.remove(header::HOST);
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+async fn forward(
+    client: reqwest::Client,
+    endpoint: Arc<str>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let Some(url) = upstream_url(req.method(), req.uri(), &endpoint) else {
+        log::warn!(
+            "refused to proxy {} {} for the object store",
+            req.method(),
+            req.uri()
+        );
+        return Ok(status(StatusCode::FORBIDDEN));
+    };
+
+    let (mut parts, body) = req.into_parts();
+    remove_hop_by_hop(&mut parts.headers);
+    let mut request = client.request(parts.method, url).headers(parts.headers);
+    // bodies are streamed, the length is kept in the content-length header
+    if !body.is_end_stream() {
+        request = request.body(reqwest::Body::wrap_stream(body));
+    }
+
+    match request.send().await {
+        Ok(upstream) => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = upstream.status();
+            *response.headers_mut() = upstream.headers().clone();
+            remove_hop_by_hop(response.headers_mut());
+            *response.body_mut() = Body::wrap_stream(upstream.bytes_stream());
+            Ok(response)
+        }
+        Err(err) => {
+            log::warn!("object store request through the proxy failed: {err}");
+            Ok(status(StatusCode::BAD_GATEWAY))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, Uri};
+
+    use super::upstream_url;
+
+    fn url(method: Method, uri: &str) -> Option<String> {
+        upstream_url(&method, &uri.parse::<Uri>().unwrap(), "s3.example.com:9000")
+    }
+
+    #[test]
+    fn requests_for_the_endpoint_are_sent_over_https() {
+        assert_eq!(
+            url(
+                Method::GET,
+                "http://s3.example.com:9000/bucket/key?list-type=2"
+            )
+            .as_deref(),
+            Some("https://s3.example.com:9000/bucket/key?list-type=2")
+        );
+        assert_eq!(
+            url(Method::PUT, "http://Bucket.S3.example.com:9000/key").as_deref(),
+            Some("https://bucket.s3.example.com:9000/key")
+        );
+    }
+
+    #[test]
+    fn other_requests_are_refused() {
+        assert_eq!(
+            url(Method::GET, "http://evil.com/s3.example.com:9000"),
+            None
+        );
+        assert_eq!(url(Method::GET, "http://xs3.example.com:9000/key"), None);
+        assert_eq!(url(Method::GET, "http://s3.example.com/key"), None);
+        assert_eq!(url(Method::GET, "https://s3.example.com:9000/key"), None);
+        assert_eq!(url(Method::CONNECT, "s3.example.com:9000"), None);
+        assert_eq!(url(Method::GET, "/bucket/key"), None);
+    }
+}
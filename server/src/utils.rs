@@ -20,6 +20,7 @@ pub mod actix;
 pub mod arrow;
 pub mod header_parsing;
 pub mod json;
+pub mod sigv4;
 pub mod uid;
 pub mod update;
 
@@ -78,6 +79,29 @@ pub fn minute_to_prefix(minute: u32, data_granularity: u32) -> Option<String> {
     ))
 }
 
+// longer values are cut, partitions are only a coarse filter
+const CUSTOM_PARTITION_VALUE_LEN: usize = 64;
+
+/// Prefix of a custom partition value below the minute prefix. Characters other than ascii
+/// alphanumerics, `-` and `_` are replaced by `_` so that the value is safe in object keys and
+/// staging file names. Values mapping to the same prefix share a partition, which only makes
+/// pruning less selective. Events without the column are in the partition with an empty value.
+pub fn custom_partition_to_prefix(column: &str, value: Option<&str>) -> String {
+    let value: String = value
+        .unwrap_or_default()
+        .chars()
+        .take(CUSTOM_PARTITION_VALUE_LEN)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{column}={value}/")
+}
+
 pub struct TimePeriod {
     start: DateTime<Utc>,
     end: DateTime<Utc>,
@@ -226,6 +250,11 @@ impl TimePeriod {
     }
 }
 
+/// Compare secrets in time independent of where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[inline(always)]
 pub fn get_address() -> (IpAddr, u16) {
     let addr = CONFIG.parseable.ingestor_url.parse::<SocketAddr>().unwrap();
@@ -313,4 +342,21 @@ mod tests {
         let left = prefixes.iter().map(String::as_str).collect::<Vec<&str>>();
         assert_eq!(left.as_slice(), right);
     }
+
+    #[rstest]
+    #[case::plain(Some("acme"), "tenant=acme/")]
+    #[case::replaced(Some("eu.west/1"), "tenant=eu_west_1/")]
+    #[case::missing(None, "tenant=/")]
+    fn custom_partition_prefix(#[case] value: Option<&str>, #[case] prefix: &str) {
+        assert_eq!(super::custom_partition_to_prefix("tenant", value), prefix);
+    }
+
+    #[rstest]
+    #[case::equal("secret", "secret", true)]
+    #[case::differs("secret", "secreT", false)]
+    #[case::prefix("secret", "secretx", false)]
+    #[case::empty("", "", true)]
+    fn constant_time_comparison(#[case] a: &str, #[case] b: &str, #[case] equal: bool) {
+        assert_eq!(super::constant_time_eq(a.as_bytes(), b.as_bytes()), equal);
+    }
 }
@@ -20,13 +20,37 @@
 use actix_web::{
     dev::ServiceRequest,
     error::{ErrorUnauthorized, ErrorUnprocessableEntity},
+    http::header::{HeaderMap, AUTHORIZATION},
     Error, FromRequest, HttpRequest,
 };
 use actix_web_httpauth::extractors::basic::BasicAuth;
 
+use crate::option::CONFIG;
 use crate::rbac::map::SessionKey;
+use crate::rbac::{cluster_token, Users};
+
+// requests signed by another node of the cluster act as the root user,
+// whose credentials were sent before cluster tokens
+fn cluster_session_key(headers: &HeaderMap) -> Option<Result<SessionKey, Error>> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let token = value
+        .strip_prefix(cluster_token::SCHEME)?
+        .strip_prefix(' ')?;
+    if !cluster_token::verify_now(token) {
+        return Some(Err(ErrorUnauthorized(
+            "Cluster token is invalid or expired",
+        )));
+    }
+    Some(Ok(SessionKey::BasicAuth {
+        username: CONFIG.parseable.username.clone(),
+        password: CONFIG.parseable.password.clone(),
+    }))
+}
 
 pub fn extract_session_key(req: &mut ServiceRequest) -> Result<SessionKey, Error> {
+    if let Some(key) = cluster_session_key(req.headers()) {
+        return key;
+    }
     // Extract username and password from the request using basic auth extractor.
     let creds = req.extract::<BasicAuth>().into_inner();
     let basic = creds.map(|creds| {
@@ -49,6 +73,9 @@ pub fn extract_session_key(req: &mut ServiceRequest) -> Result<SessionKey, Error
 }
 
 pub fn extract_session_key_from_req(req: &HttpRequest) -> Result<SessionKey, Error> {
+    if let Some(key) = cluster_session_key(req.headers()) {
+        return key;
+    }
     // Extract username and password from the request using basic auth extractor.
     let creds = BasicAuth::extract(req).into_inner();
     let basic = creds.map(|creds| {
@@ -69,3 +96,11 @@ pub fn extract_session_key_from_req(req: &HttpRequest) -> Result<SessionKey, Err
         Err(ErrorUnauthorized("No authentication method supplied"))
     }
 }
+
+/// Name of the user making the request, empty if it can not be resolved
+pub fn request_username(req: &HttpRequest) -> String {
+    extract_session_key_from_req(req)
+        .ok()
+        .and_then(|key| Users.get_username_from_session(&key))
+        .unwrap_or_default()
+}
@@ -21,17 +21,21 @@ use arrow_array::{RecordBatch, TimestampMillisecondArray};
 use arrow_ipc::reader::StreamReader;
 use arrow_schema::Schema;
 use itertools::kmerge_by;
-use std::{fs::File, io::BufReader, path::PathBuf, sync::Arc};
+use std::{io::BufReader, path::PathBuf, sync::Arc};
 
 use super::{
     adapt_batch,
     reverse_reader::{reverse, OffsetReader},
 };
-use crate::{event::DEFAULT_TIMESTAMP_KEY, utils};
+use crate::{
+    event::DEFAULT_TIMESTAMP_KEY,
+    storage::staging::encryption::{self, StagingReader},
+    utils,
+};
 
 #[derive(Debug)]
 pub struct MergedRecordReader {
-    pub readers: Vec<StreamReader<BufReader<File>>>,
+    pub readers: Vec<StreamReader<BufReader<StagingReader>>>,
 }
 
 impl MergedRecordReader {
@@ -39,7 +43,8 @@ impl MergedRecordReader {
         let mut readers = Vec::with_capacity(files.len());
 
         for file in files {
-            let reader = StreamReader::try_new(File::open(file).unwrap(), None).map_err(|_| ())?;
+            let reader =
+                StreamReader::try_new(encryption::open(file).unwrap(), None).map_err(|_| ())?;
             readers.push(reader);
         }
 
@@ -58,7 +63,7 @@ impl MergedRecordReader {
 
 #[derive(Debug)]
 pub struct MergedReverseRecordReader {
-    pub readers: Vec<StreamReader<BufReader<OffsetReader<File>>>>,
+    pub readers: Vec<StreamReader<BufReader<OffsetReader<StagingReader>>>>,
 }
 
 impl MergedReverseRecordReader {
@@ -66,7 +71,7 @@ impl MergedReverseRecordReader {
         let mut readers = Vec::with_capacity(files.len());
         for file in files {
             let reader =
-                utils::arrow::reverse_reader::get_reverse_reader(File::open(file).unwrap())
+                utils::arrow::reverse_reader::get_reverse_reader(encryption::open(file).unwrap())
                     .map_err(|_| ())?;
             readers.push(reader);
         }
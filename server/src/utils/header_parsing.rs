@@ -17,8 +17,12 @@
  */
 
 const MAX_HEADERS_ALLOWED: usize = 10;
+use std::collections::BTreeMap;
+
 use actix_web::{HttpRequest, HttpResponse, ResponseError};
 
+use crate::handlers::http::problem::Problem;
+
 pub fn collect_labelled_headers(
     req: &HttpRequest,
     prefix: &str,
@@ -54,6 +58,58 @@ pub fn collect_labelled_headers(
     Ok(labels.join(&kv_separator.to_string()))
 }
 
+/// Tags of the `key` headers, comma separated `name=value` pairs. Each tag becomes a
+/// column of every event of the request.
+pub fn collect_tags(
+    req: &HttpRequest,
+    key: &str,
+) -> Result<BTreeMap<String, String>, ParseHeaderError> {
+    let mut tags = BTreeMap::new();
+    for value in req.headers().get_all(key) {
+        let value = value.to_str().map_err(|_| ParseHeaderError::InvalidValue)?;
+        for pair in value
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (name, value) = pair.split_once('=').ok_or_else(|| {
+                ParseHeaderError::InvalidTag(format!("{pair} is not of the form name=value"))
+            })?;
+            let name = name.trim();
+            validate_tag_name(name)?;
+            if tags
+                .insert(name.to_owned(), value.trim().to_owned())
+                .is_some()
+            {
+                return Err(ParseHeaderError::InvalidTag(format!(
+                    "{name} is set more than once"
+                )));
+            }
+        }
+    }
+
+    if tags.len() > MAX_HEADERS_ALLOWED {
+        return Err(ParseHeaderError::MaxHeadersLimitExceeded);
+    }
+    Ok(tags)
+}
+
+/// Tags are columns, so their names are plain column names
+pub fn validate_tag_name(name: &str) -> Result<(), ParseHeaderError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(ParseHeaderError::InvalidTag(format!(
+            "{name:?} is not a valid tag name, use letters, digits and underscores"
+        )));
+    }
+    // columns such as p_timestamp and p_tags are set by the server
+    if name.starts_with("p_") {
+        return Err(ParseHeaderError::InvalidTag(format!(
+            "{name} is reserved, names starting with p_ are used by the server"
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ParseHeaderError {
     #[error("Too many headers received. Limit is of 5 headers")]
@@ -68,6 +124,10 @@ pub enum ParseHeaderError {
     SeperatorInValue(char),
     #[error("Stream name not found in header [x-p-stream]")]
     MissingStreamName,
+    #[error("Invalid tag in header [x-p-tags]: {0}")]
+    InvalidTag(String),
+    #[error("Tag {0} is not allowed on log stream {1}")]
+    TagNotAllowed(String, String),
 }
 
 impl ResponseError for ParseHeaderError {
@@ -76,6 +136,32 @@ impl ResponseError for ParseHeaderError {
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).body(self.to_string())
+        Problem::new(self.status_code(), self).response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::collect_tags;
+
+    #[test]
+    fn tags_are_collected_from_all_headers() {
+        let req = TestRequest::default()
+            .append_header(("x-p-tags", "environment=prod, datacenter = eu-1"))
+            .append_header(("x-p-tags", "team=payments"))
+            .to_http_request();
+        let tags = collect_tags(&req, "x-p-tags").unwrap();
+        assert_eq!(tags["environment"], "prod");
+        assert_eq!(tags["datacenter"], "eu-1");
+        assert_eq!(tags["team"], "payments");
+
+        for invalid in ["environment", "p_timestamp=now", "env-name=prod", "a=1,a=2"] {
+            let req = TestRequest::default()
+                .append_header(("x-p-tags", invalid))
+                .to_http_request();
+            assert!(collect_tags(&req, "x-p-tags").is_err(), "{invalid}");
+        }
     }
 }
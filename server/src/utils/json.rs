@@ -16,11 +16,38 @@
  *
  */
 
+use std::cell::RefCell;
+
+use serde::de::Error;
 use serde_json;
 use serde_json::Value;
 
+pub mod ecs;
 pub mod flatten;
 
+// buffers larger than this are not kept around after parsing
+const MAX_RETAINED_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+thread_local! {
+    static SIMD_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+// Parse json with simd-json. simd-json parses in place so the input is copied
+// into a buffer which is reused across requests handled by the same thread.
+pub fn from_slice_simd(body: &[u8]) -> Result<Value, serde_json::Error> {
+    SIMD_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.clear();
+        buffer.extend_from_slice(body);
+        let value =
+            simd_json::serde::from_slice(&mut buffer[..]).map_err(serde_json::Error::custom);
+        if buffer.capacity() > MAX_RETAINED_BUFFER_SIZE {
+            *buffer = Vec::new();
+        }
+        value
+    })
+}
+
 pub fn flatten_json_body(
     body: serde_json::Value,
     time_partition: Option<String>,
@@ -47,3 +74,21 @@ pub fn convert_to_string(value: &Value) -> Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::from_slice_simd;
+
+    #[test]
+    fn simd_parses_same_as_serde() {
+        let value = json!([{"a": 1, "b": "x", "c": [1.5, null]}, {"a": -2, "d": {"e": true}}]);
+        let body = serde_json::to_vec(&value).unwrap();
+
+        assert_eq!(from_slice_simd(&body).unwrap(), value);
+        // buffer is reused for the next parse
+        assert_eq!(from_slice_simd(br#"{"a":1}"#).unwrap(), json!({"a": 1}));
+        assert!(from_slice_simd(br#"{"a":"#).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Mapping of event fields to the names and types of the Elastic Common Schema.
+//!
+//! Nested objects are flattened with `.`, as ECS names fields, so `{"log": {"level": "info"}}`
+//! is the column `log.level`. Fields commonly used for an ECS field, such as `msg` or `status`,
+//! are renamed to it unless the event has the ECS field already. Known ECS fields are converted
+//! to their type, numbers of keyword fields become strings and numeric strings of long fields
+//! become numbers. Other fields are kept as they are.
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::{Map, Number, Value};
+
+const SEPARATOR: &str = ".";
+const TIMESTAMP: &str = "@timestamp";
+
+// field names, in lower case, mapped to the ECS field they are commonly used for
+const ALIASES: &[(&str, &str)] = &[
+    ("msg", "message"),
+    ("log", "message"),
+    ("level", "log.level"),
+    ("lvl", "log.level"),
+    ("loglevel", "log.level"),
+    ("log_level", "log.level"),
+    ("severity", "log.level"),
+    ("logger", "log.logger"),
+    ("logger_name", "log.logger"),
+    ("time", TIMESTAMP),
+    ("ts", TIMESTAMP),
+    ("timestamp", TIMESTAMP),
+    ("datetime", TIMESTAMP),
+    ("host", "host.name"),
+    ("hostname", "host.name"),
+    ("host_name", "host.name"),
+    ("ip", "source.ip"),
+    ("client_ip", "source.ip"),
+    ("clientip", "source.ip"),
+    ("remote_addr", "source.ip"),
+    ("remote_ip", "source.ip"),
+    ("src_ip", "source.ip"),
+    ("source_ip", "source.ip"),
+    ("src_port", "source.port"),
+    ("source_port", "source.port"),
+    ("dst_ip", "destination.ip"),
+    ("dest_ip", "destination.ip"),
+    ("destination_ip", "destination.ip"),
+    ("dst_port", "destination.port"),
+    ("dest_port", "destination.port"),
+    ("destination_port", "destination.port"),
+    ("method", "http.request.method"),
+    ("http_method", "http.request.method"),
+    ("request_method", "http.request.method"),
+    ("status", "http.response.status_code"),
+    ("status_code", "http.response.status_code"),
+    ("http_status", "http.response.status_code"),
+    ("response_code", "http.response.status_code"),
+    ("bytes", "http.response.body.bytes"),
+    ("bytes_sent", "http.response.body.bytes"),
+    ("body_bytes_sent", "http.response.body.bytes"),
+    ("response_size", "http.response.body.bytes"),
+    ("referer", "http.request.referrer"),
+    ("referrer", "http.request.referrer"),
+    ("http_referer", "http.request.referrer"),
+    ("url", "url.original"),
+    ("uri", "url.original"),
+    ("request_uri", "url.original"),
+    ("path", "url.path"),
+    ("user_agent", "user_agent.original"),
+    ("useragent", "user_agent.original"),
+    ("http_user_agent", "user_agent.original"),
+    ("user", "user.name"),
+    ("username", "user.name"),
+    ("user_name", "user.name"),
+    ("user_id", "user.id"),
+    ("uid", "user.id"),
+    ("pid", "process.pid"),
+    ("process_id", "process.pid"),
+    ("process", "process.name"),
+    ("process_name", "process.name"),
+    ("program", "process.name"),
+    ("service", "service.name"),
+    ("service_name", "service.name"),
+    ("app", "service.name"),
+    ("env", "service.environment"),
+    ("environment", "service.environment"),
+    ("error", "error.message"),
+    ("err", "error.message"),
+    ("error_message", "error.message"),
+    ("stack", "error.stack_trace"),
+    ("stacktrace", "error.stack_trace"),
+    ("stack_trace", "error.stack_trace"),
+    ("trace_id", "trace.id"),
+    ("traceid", "trace.id"),
+    ("span_id", "span.id"),
+    ("spanid", "span.id"),
+    ("container_id", "container.id"),
+    ("container_name", "container.name"),
+    ("namespace", "orchestrator.namespace"),
+    ("pod", "orchestrator.resource.name"),
+    ("pod_name", "orchestrator.resource.name"),
+];
+
+// ECS fields of type long
+const LONG_FIELDS: &[&str] = &[
+    "source.port",
+    "destination.port",
+    "client.port",
+    "server.port",
+    "http.response.status_code",
+    "http.response.body.bytes",
+    "http.request.body.bytes",
+    "process.pid",
+    "process.parent.pid",
+    "event.severity",
+    "event.duration",
+];
+
+// ECS fields of type keyword which are often sent as numbers
+const KEYWORD_FIELDS: &[&str] = &[
+    "log.level",
+    "user.id",
+    "user.effective.id",
+    "user.audit.id",
+    "group.id",
+    "event.id",
+    "event.code",
+    "error.code",
+    "host.name",
+];
+
+/// Fields of an event, or of an array of events, mapped to ECS
+pub fn normalize(value: Value) -> Value {
+    match value {
+        Value::Object(event) => Value::Object(normalize_event(event)),
+        Value::Array(events) => Value::Array(events.into_iter().map(normalize).collect()),
+        value => value,
+    }
+}
+
+fn normalize_event(event: Map<String, Value>) -> Map<String, Value> {
+    let mut fields = Map::new();
+    flatten(None, event, &mut fields);
+
+    let mut mapped = Map::new();
+    for (name, value) in fields.iter() {
+        let target = ALIASES
+            .iter()
+            .find(|(alias, _)| name.eq_ignore_ascii_case(alias))
+            .map(|(_, target)| *target)
+            // fields of the ECS name take precedence, as does the first alias of it
+            .filter(|target| {
+                !value.is_array() && !fields.contains_key(*target) && !mapped.contains_key(*target)
+            })
+            .unwrap_or(name.as_str());
+        mapped.insert(target.to_owned(), typed(target, value.clone()));
+    }
+    mapped
+}
+
+/// Fields of an event with ECS nesting converted to their ECS type, as [`normalize`] converts them
+/// once they are flattened
+pub fn with_field_types(event: Value) -> Value {
+    fn convert(prefix: Option<&str>, value: Value) -> Value {
+        match value {
+            Value::Object(object) => Value::Object(
+                object
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let name = match prefix {
+                            Some(prefix) => format!("{prefix}{SEPARATOR}{key}"),
+                            None => key.clone(),
+                        };
+                        (key, convert(Some(&name), value))
+                    })
+                    .collect(),
+            ),
+            value => match prefix {
+                Some(name) => typed(name, value),
+                None => value,
+            },
+        }
+    }
+    convert(None, event)
+}
+
+fn flatten(prefix: Option<&str>, object: Map<String, Value>, fields: &mut Map<String, Value>) {
+    for (key, value) in object {
+        let name = match prefix {
+            Some(prefix) => format!("{prefix}{SEPARATOR}{key}"),
+            None => key,
+        };
+        match value {
+            Value::Object(object) if !object.is_empty() => flatten(Some(&name), object, fields),
+            value => {
+                fields.insert(name, value);
+            }
+        }
+    }
+}
+
+fn typed(field: &str, value: Value) -> Value {
+    match value {
+        Value::String(s) if LONG_FIELDS.contains(&field) => match s.trim().parse::<i64>() {
+            Ok(number) => Value::Number(number.into()),
+            Err(_) => Value::String(s),
+        },
+        Value::Number(number) if KEYWORD_FIELDS.contains(&field) => {
+            Value::String(number.to_string())
+        }
+        Value::Number(number) if field == TIMESTAMP => epoch_timestamp(&number)
+            .map(Value::String)
+            .unwrap_or(Value::Number(number)),
+        value => value,
+    }
+}
+
+// seconds or milliseconds since the epoch, told apart by their magnitude
+fn epoch_timestamp(number: &Number) -> Option<String> {
+    let value = number.as_f64()?;
+    let millis = if value.abs() >= 1e12 {
+        value
+    } else {
+        value * 1000.0
+    } as i64;
+    let time = NaiveDateTime::from_timestamp_millis(millis)?;
+    Some(
+        DateTime::<Utc>::from_naive_utc_and_offset(time, Utc)
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{normalize, with_field_types};
+
+    #[test]
+    fn common_names_are_mapped() {
+        let event = json!({
+            "msg": "GET /health",
+            "level": 30,
+            "status": "200",
+            "client_ip": "10.0.0.7",
+            "time": 1705026780,
+            "custom": "kept",
+        });
+
+        assert_eq!(
+            normalize(event),
+            json!({
+                "message": "GET /health",
+                "log.level": "30",
+                "http.response.status_code": 200,
+                "source.ip": "10.0.0.7",
+                "@timestamp": "2024-01-12T02:33:00.000Z",
+                "custom": "kept",
+            })
+        );
+    }
+
+    #[test]
+    fn nested_fields_are_named_with_dots() {
+        let event = json!([{
+            "log": { "level": "info" },
+            "level": "debug",
+            "http": { "response": { "status_code": "503" } },
+        }]);
+
+        assert_eq!(
+            normalize(event),
+            json!([{
+                "log.level": "info",
+                "level": "debug",
+                "http.response.status_code": 503,
+            }])
+        );
+    }
+
+    #[test]
+    fn nested_fields_get_their_type() {
+        let event = json!({
+            "process": { "pid": "4242", "parent": { "pid": 1 } },
+            "user": { "id": 1000, "name": "alice" },
+            "tags": ["a"],
+        });
+
+        assert_eq!(
+            with_field_types(event),
+            json!({
+                "process": { "pid": 4242, "parent": { "pid": 1 } },
+                "user": { "id": "1000", "name": "alice" },
+                "tags": ["a"],
+            })
+        );
+    }
+}
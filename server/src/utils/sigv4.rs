@@ -0,0 +1,506 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! AWS Signature Version 4. Requests to the ingest endpoint of AWS services are verified with the
+//! access key set in `P_AWS_INGEST_ACCESS_KEY_ID` and `P_AWS_INGEST_SECRET_ACCESS_KEY`, requests
+//! Parseable makes to AWS services itself are signed.
+//!
+//! Kinesis Firehose does not sign deliveries to HTTP endpoints but sends the configured access key
+//! in `X-Amz-Firehose-Access-Key`, which is accepted when it matches `P_AWS_FIREHOSE_ACCESS_KEY`.
+//! Signed requests have to sign their payload, `UNSIGNED-PAYLOAD` is rejected.
+
+use actix_web::{http::header, HttpRequest};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::HeaderValue;
+use sha2::{Digest, Sha256};
+
+use crate::option::CONFIG;
+use crate::utils::constant_time_eq;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const DATE_HEADER: &str = "x-amz-date";
+const CONTENT_SHA256_HEADER: &str = "x-amz-content-sha256";
+const FIREHOSE_ACCESS_KEY_HEADER: &str = "x-amz-firehose-access-key";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+// signed requests are accepted this long before and after they were signed, as AWS does
+const MAX_SKEW_SECS: i64 = 15 * 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SigV4Error {
+    #[error("Ingestion from AWS services is not configured")]
+    NotConfigured,
+    #[error("Malformed AWS signature: {0}")]
+    Malformed(&'static str),
+    #[error("Unknown access key id")]
+    UnknownAccessKey,
+    #[error("Request was signed more than 15 minutes from now")]
+    Expired,
+    #[error("Signature does not match")]
+    Mismatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+// Authorization: AWS4-HMAC-SHA256 Credential=<key>/<date>/<region>/<service>/aws4_request,
+// SignedHeaders=<header;..>, Signature=<hex>
+struct Authorization<'a> {
+    access_key_id: &'a str,
+    scope: &'a str,
+    date: &'a str,
+    signed_headers: Vec<&'a str>,
+    signature: &'a str,
+}
+
+impl<'a> Authorization<'a> {
+    fn parse(value: &'a str) -> Result<Self, SigV4Error> {
+        let params = value
+            .strip_prefix(ALGORITHM)
+            .ok_or(SigV4Error::Malformed("unsupported algorithm"))?;
+
+        let (mut credential, mut signed_headers, mut signature) = (None, None, None);
+        for param in params.split(',') {
+            match param.trim().split_once('=') {
+                Some(("Credential", value)) => credential = Some(value),
+                Some(("SignedHeaders", value)) => signed_headers = Some(value),
+                Some(("Signature", value)) => signature = Some(value),
+                _ => {}
+            }
+        }
+
+        let credential = credential.ok_or(SigV4Error::Malformed("missing credential"))?;
+        let (access_key_id, scope) = credential
+            .split_once('/')
+            .ok_or(SigV4Error::Malformed("invalid credential"))?;
+        let date = match scope.split('/').collect::<Vec<_>>()[..] {
+            [date, _region, _service, "aws4_request"] => date,
+            _ => return Err(SigV4Error::Malformed("invalid credential scope")),
+        };
+
+        Ok(Self {
+            access_key_id,
+            scope,
+            date,
+            signed_headers: signed_headers
+                .ok_or(SigV4Error::Malformed("missing signed headers"))?
+                .split(';')
+                .collect(),
+            signature: signature.ok_or(SigV4Error::Malformed("missing signature"))?,
+        })
+    }
+}
+
+/// Check that the request was signed, or sent by Kinesis Firehose, with the configured key
+pub fn verify(req: &HttpRequest, body: &[u8]) -> Result<(), SigV4Error> {
+    let credentials = CONFIG
+        .parseable
+        .aws_ingest_access_key_id
+        .clone()
+        .zip(CONFIG.parseable.aws_ingest_secret_access_key.clone())
+        .map(|(access_key_id, secret_access_key)| Credentials {
+            access_key_id,
+            secret_access_key,
+        });
+    verify_at(
+        req,
+        body,
+        credentials.as_ref(),
+        CONFIG.parseable.aws_firehose_access_key.as_deref(),
+        Utc::now(),
+    )
+}
+
+fn verify_at(
+    req: &HttpRequest,
+    body: &[u8],
+    credentials: Option<&Credentials>,
+    firehose_access_key: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<(), SigV4Error> {
+    let headers = req.headers();
+
+    if let Some(key) = headers.get(FIREHOSE_ACCESS_KEY_HEADER) {
+        let expected = firehose_access_key.ok_or(SigV4Error::NotConfigured)?;
+        return if constant_time_eq(key.as_bytes(), expected.as_bytes()) {
+            Ok(())
+        } else {
+            Err(SigV4Error::Mismatch)
+        };
+    }
+
+    let credentials = credentials.ok_or(SigV4Error::NotConfigured)?;
+
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(SigV4Error::Malformed("missing authorization"))?;
+    let authorization = Authorization::parse(authorization)?;
+    if authorization.access_key_id != credentials.access_key_id {
+        return Err(SigV4Error::UnknownAccessKey);
+    }
+    if !authorization.signed_headers.contains(&"host") {
+        return Err(SigV4Error::Malformed("host is not signed"));
+    }
+
+    let amz_date = headers
+        .get(DATE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or(SigV4Error::Malformed("missing x-amz-date"))?;
+    let signed_at = NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ")
+        .map_err(|_| SigV4Error::Malformed("invalid x-amz-date"))?;
+    let signed_at = DateTime::<Utc>::from_naive_utc_and_offset(signed_at, Utc);
+    if !amz_date.starts_with(authorization.date) {
+        return Err(SigV4Error::Malformed(
+            "date does not match credential scope",
+        ));
+    }
+    if (now - signed_at).num_seconds().abs() > MAX_SKEW_SECS {
+        return Err(SigV4Error::Expired);
+    }
+
+    let body_hash = hex::encode(Sha256::digest(body));
+    // the signature has to cover the body, otherwise it could be replaced
+    match headers
+        .get(CONTENT_SHA256_HEADER)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(UNSIGNED_PAYLOAD) => return Err(SigV4Error::Malformed("payload is not signed")),
+        Some(hash) if hash != body_hash => return Err(SigV4Error::Mismatch),
+        _ => {}
+    }
+
+    let mut canonical_headers = String::new();
+    for name in &authorization.signed_headers {
+        let values: Vec<_> = match *name {
+            // http/2 requests carry the host in the uri instead of a header
+            "host" if !headers.contains_key(header::HOST) => req
+                .uri()
+                .authority()
+                .map(|host| host.to_string())
+                .into_iter()
+                .collect(),
+            name => headers
+                .get_all(name)
+                .filter_map(|value| value.to_str().ok())
+                .map(|value| value.split_whitespace().collect::<Vec<_>>().join(" "))
+                .collect(),
+        };
+        canonical_headers.push_str(&format!("{name}:{}\n", values.join(",")));
+    }
+
+    let path = match req.uri().path() {
+        "" => "/",
+        path => path,
+    };
+    let canonical_request = [
+        req.method().as_str(),
+        path,
+        &canonical_query(req.query_string()),
+        &canonical_headers,
+        &authorization.signed_headers.join(";"),
+        &body_hash,
+    ]
+    .join("\n");
+
+    let signature = hex::decode(authorization.signature)
+        .map_err(|_| SigV4Error::Malformed("invalid signature"))?;
+    signing_mac(&credentials.secret_access_key, authorization.scope)
+        .chain_update(string_to_sign(
+            amz_date,
+            authorization.scope,
+            &canonical_request,
+        ))
+        .verify_slice(&signature)
+        .map_err(|_| SigV4Error::Mismatch)
+}
+
+/// Sign a request to the AWS service in the given region, its headers are signed along with the
+/// host and the date
+pub fn sign(
+    request: &mut reqwest::Request,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+) {
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let scope = format!("{}/{region}/{service}/aws4_request", &amz_date[..8]);
+    request.headers_mut().insert(
+        DATE_HEADER,
+        HeaderValue::from_str(&amz_date).expect("date is a valid header"),
+    );
+
+    let url = request.url();
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (host, None) => host.unwrap_or_default().to_owned(),
+        (None, Some(_)) => String::new(),
+    };
+    let mut headers: Vec<_> = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?.trim())))
+        .chain([("host", host.as_str())])
+        .collect();
+    headers.sort();
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{value}\n"))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .unwrap_or_default();
+    let canonical_request = [
+        request.method().as_str(),
+        url.path(),
+        &canonical_query(url.query().unwrap_or_default()),
+        &canonical_headers,
+        &signed_headers,
+        &hex::encode(Sha256::digest(body)),
+    ]
+    .join("\n");
+
+    let signature = signing_mac(&credentials.secret_access_key, &scope)
+        .chain_update(string_to_sign(&amz_date, &scope, &canonical_request))
+        .finalize()
+        .into_bytes();
+    let authorization = format!(
+        "{ALGORITHM} Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={}",
+        credentials.access_key_id,
+        hex::encode(signature)
+    );
+    request.headers_mut().insert(
+        reqwest::header::AUTHORIZATION,
+        HeaderValue::from_str(&authorization).expect("authorization is a valid header"),
+    );
+}
+
+fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    [
+        ALGORITHM,
+        amz_date,
+        scope,
+        &hex::encode(Sha256::digest(canonical_request.as_bytes())),
+    ]
+    .join("\n")
+}
+
+// mac keyed with the signing key derived from the secret for the date, region and service
+fn signing_mac(secret_access_key: &str, scope: &str) -> HmacSha256 {
+    let key = scope.split('/').fold(
+        format!("AWS4{secret_access_key}").into_bytes(),
+        |key, part| {
+            HmacSha256::new_from_slice(&key)
+                .expect("hmac takes keys of any size")
+                .chain_update(part.as_bytes())
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        },
+    );
+    HmacSha256::new_from_slice(&key).expect("hmac takes keys of any size")
+}
+
+// query parameters uri encoded and sorted by name, then by value
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<_> = url::form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| (uri_encode(&name), uri_encode(&value)))
+        .collect();
+    params.sort();
+    params
+        .into_iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use chrono::{TimeZone, Utc};
+
+    use super::{sign, verify_at, Credentials, SigV4Error};
+
+    // get-vanilla of the AWS Signature Version 4 test suite
+    fn credentials() -> Credentials {
+        Credentials {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+        }
+    }
+    const AUTHORIZATION: &str = "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+
+    fn request(authorization: &str) -> actix_web::HttpRequest {
+        TestRequest::get()
+            .uri("/")
+            .insert_header(("host", "example.amazonaws.com"))
+            .insert_header(("x-amz-date", "20150830T123600Z"))
+            .insert_header(("authorization", authorization))
+            .to_http_request()
+    }
+
+    #[test]
+    fn vanilla_request_is_verified() {
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 40, 0).unwrap();
+        assert!(verify_at(
+            &request(AUTHORIZATION),
+            b"",
+            Some(&credentials()),
+            None,
+            now
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 40, 0).unwrap();
+        let authorization = AUTHORIZATION.replace("5fa00", "5fa01");
+        assert!(matches!(
+            verify_at(
+                &request(&authorization),
+                b"",
+                Some(&credentials()),
+                None,
+                now
+            ),
+            Err(SigV4Error::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn old_request_is_rejected() {
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 13, 0, 0).unwrap();
+        assert!(matches!(
+            verify_at(
+                &request(AUTHORIZATION),
+                b"",
+                Some(&credentials()),
+                None,
+                now
+            ),
+            Err(SigV4Error::Expired)
+        ));
+    }
+
+    // request signed with the given headers, as it is received
+    fn signed_request(headers: &[(&'static str, &str)], body: &[u8]) -> actix_web::HttpRequest {
+        let mut request = reqwest::Request::new(
+            reqwest::Method::POST,
+            "https://sqs.us-east-1.amazonaws.com/?a=1".parse().unwrap(),
+        );
+        for (name, value) in headers {
+            request.headers_mut().insert(*name, value.parse().unwrap());
+        }
+        *request.body_mut() = Some(body.to_vec().into());
+        sign(&mut request, &credentials(), "us-east-1", "sqs");
+
+        let mut received = TestRequest::post()
+            .uri("/?a=1")
+            .insert_header(("host", "sqs.us-east-1.amazonaws.com"));
+        for (name, value) in request.headers() {
+            received = received.insert_header((name.as_str(), value.to_str().unwrap()));
+        }
+        received.to_http_request()
+    }
+
+    #[test]
+    fn signed_request_is_verified() {
+        let body = br#"{"QueueUrl": "q"}"#;
+        let request = signed_request(&[("x-amz-target", "AmazonSQS.ReceiveMessage")], body);
+        assert!(verify_at(&request, body, Some(&credentials()), None, Utc::now()).is_ok());
+
+        // the body is covered by the signature
+        assert!(matches!(
+            verify_at(&request, b"{}", Some(&credentials()), None, Utc::now()),
+            Err(SigV4Error::Mismatch)
+        ));
+    }
+
+    #[test]
+    fn unsigned_payload_is_rejected() {
+        let body = br#"{"QueueUrl": "q"}"#;
+        let request = signed_request(&[("x-amz-content-sha256", "UNSIGNED-PAYLOAD")], body);
+        assert!(matches!(
+            verify_at(&request, body, Some(&credentials()), None, Utc::now()),
+            Err(SigV4Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn firehose_deliveries_need_the_firehose_access_key() {
+        let request = |key: &str| {
+            TestRequest::post()
+                .insert_header(("x-amz-firehose-access-key", key))
+                .to_http_request()
+        };
+        let secret = credentials().secret_access_key;
+
+        assert!(verify_at(
+            &request("firehose"),
+            b"",
+            None,
+            Some("firehose"),
+            Utc::now()
+        )
+        .is_ok());
+        assert!(matches!(
+            verify_at(
+                &request(&secret),
+                b"",
+                Some(&credentials()),
+                Some("firehose"),
+                Utc::now()
+            ),
+            Err(SigV4Error::Mismatch)
+        ));
+        assert!(matches!(
+            verify_at(
+                &request(&secret),
+                b"",
+                Some(&credentials()),
+                None,
+                Utc::now()
+            ),
+            Err(SigV4Error::NotConfigured)
+        ));
+    }
+}
@@ -22,6 +22,7 @@ use anyhow::anyhow;
 use chrono::{DateTime, Utc};
 
 use crate::about;
+use crate::tls;
 
 use super::uid;
 
@@ -32,7 +33,7 @@ pub struct LatestRelease {
 }
 
 pub async fn get_latest(deployment_id: &uid::Uid) -> Result<LatestRelease, anyhow::Error> {
-    let agent = reqwest::ClientBuilder::new()
+    let agent = tls::client_builder(None)
         .user_agent(about::user_agent(deployment_id))
         .timeout(Duration::from_secs(8))
         .build()
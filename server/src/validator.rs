@@ -16,9 +16,10 @@
  *
  */
 
+use crate::alerts::burn_rate::ServiceLevelObjective;
 use crate::alerts::rule::base::{NumericRule, StringRule};
 use crate::alerts::rule::{ColumnRule, ConsecutiveNumericRule, ConsecutiveStringRule};
-use crate::alerts::{Alerts, Rule};
+use crate::alerts::{template, Alerts, Rule};
 
 use self::error::{AlertValidationError, StreamNameValidationError, UsernameValidationError};
 
@@ -38,6 +39,14 @@ pub fn alert(alerts: &Alerts) -> Result<(), AlertValidationError> {
         if alert.targets.is_empty() {
             return Err(AlertValidationError::NoTarget);
         }
+        for template in alert
+            .targets
+            .iter()
+            .filter_map(|target| target.target.template())
+        {
+            template::validate(template)
+                .map_err(|err| AlertValidationError::InvalidTemplate(err.to_string()))?;
+        }
 
         if let Rule::Column(ref column_rule) = alert.rule {
             match column_rule {
@@ -58,6 +67,40 @@ pub fn alert(alerts: &Alerts) -> Result<(), AlertValidationError> {
                 }
             }
         }
+
+        if let Rule::BurnRate(ref rule) = alert.rule {
+            burn_rate(&rule.objective)?;
+        }
+    }
+    Ok(())
+}
+
+fn burn_rate(objective: &ServiceLevelObjective) -> Result<(), AlertValidationError> {
+    if objective.error_condition.trim().is_empty() {
+        return Err(AlertValidationError::EmptyErrorCondition);
+    }
+    // conditions are put into a query on the stream, they may not read other streams
+    let conditions = [
+        Some(&objective.error_condition),
+        objective.total_condition.as_ref(),
+    ];
+    if conditions.into_iter().flatten().any(|condition| {
+        condition
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .any(|word| word.eq_ignore_ascii_case("select"))
+    }) {
+        return Err(AlertValidationError::SubqueryInCondition);
+    }
+    if !(objective.objective > 0.0 && objective.objective < 100.0) {
+        return Err(AlertValidationError::InvalidObjective);
+    }
+    if objective.windows.is_empty() {
+        return Err(AlertValidationError::NoBurnRateWindow);
+    }
+    for window in &objective.windows {
+        if window.short.is_zero() || window.short >= window.long || window.burn_rate <= 0.0 {
+            return Err(AlertValidationError::InvalidBurnRateWindow);
+        }
     }
     Ok(())
 }
@@ -143,6 +186,18 @@ pub mod error {
         InvalidRuleRepeat,
         #[error("Alert must have at least one target")]
         NoTarget,
+        #[error("Alert's rule.errorCondition cannot be empty")]
+        EmptyErrorCondition,
+        #[error("Alert's rule conditions cannot contain subqueries")]
+        SubqueryInCondition,
+        #[error("Alert's rule.objective must be a percentage between 0 and 100")]
+        InvalidObjective,
+        #[error("Alert's rule.windows cannot be empty")]
+        NoBurnRateWindow,
+        #[error("Alert's rule.windows need a short window shorter than the long one and a positive burnRate")]
+        InvalidBurnRateWindow,
+        #[error("Alert target has an invalid template: {0}")]
+        InvalidTemplate(String),
     }
 
     #[derive(Debug, thiserror::Error)]
@@ -0,0 +1,201 @@
+/*
+ * Parseable Server (C) 2022 - 2024 Parseable, Inc.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Webhook notifications for changes to streams and to the cluster.
+//!
+//! Every configured url receives a POST with the json encoded [`Notification`]. When a secret is
+//! configured the body is signed with HMAC-SHA256, sent as `X-P-Signature: sha256=<hex digest>`.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use ulid::Ulid;
+use url::Url;
+
+use crate::option::CONFIG;
+use crate::tls;
+
+const SIGNATURE_HEADER: &str = "X-P-Signature";
+const EVENT_HEADER: &str = "X-P-Event";
+const DELIVERY_HEADER: &str = "X-P-Delivery";
+// attempts per url, with a doubling delay in between
+const DELIVERY_ATTEMPTS: u32 = 3;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    StreamCreated {
+        stream: String,
+    },
+    StreamDeleted {
+        stream: String,
+    },
+    RetentionExecuted {
+        stream: String,
+        deleted_dates: Vec<String>,
+    },
+    IngesterJoined {
+        domain_name: String,
+    },
+    IngesterLeft {
+        domain_name: String,
+    },
+}
+
+impl LifecycleEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::StreamCreated { .. } => "stream_created",
+            Self::StreamDeleted { .. } => "stream_deleted",
+            Self::RetentionExecuted { .. } => "retention_executed",
+            Self::IngesterJoined { .. } => "ingester_joined",
+            Self::IngesterLeft { .. } => "ingester_left",
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Notification {
+    id: Ulid,
+    timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    event: LifecycleEvent,
+}
+
+/// Post the event to every configured webhook in the background
+pub fn notify(event: LifecycleEvent) {
+    if CONFIG.parseable.webhook_urls.is_empty() {
+        return;
+    }
+    tokio::spawn(send(event));
+}
+
+/// Post the event to every configured webhook, for callers on a runtime which may be
+/// shut down before a background delivery would finish
+pub async fn send(event: LifecycleEvent) {
+    if CONFIG.parseable.webhook_urls.is_empty() {
+        return;
+    }
+
+    let notification = Notification {
+        id: Ulid::new(),
+        timestamp: Utc::now(),
+        event,
+    };
+    let body = match serde_json::to_vec(&notification) {
+        Ok(body) => body,
+        Err(err) => {
+            log::error!("could not serialize webhook notification: {err}");
+            return;
+        }
+    };
+    let signature = CONFIG
+        .parseable
+        .webhook_secret
+        .as_deref()
+        .map(|secret| sign(secret, &body));
+
+    for url in &CONFIG.parseable.webhook_urls {
+        deliver(url, &notification, &body, signature.as_deref()).await;
+    }
+}
+
+async fn deliver(url: &Url, notification: &Notification, body: &[u8], signature: Option<&str>) {
+    let client = tls::client_builder(None)
+        .build()
+        .expect("client can be built on this system");
+    let mut delay = Duration::from_secs(1);
+
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let mut request = client
+            .post(url.clone())
+            .timeout(DELIVERY_TIMEOUT)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, notification.event.name())
+            .header(DELIVERY_HEADER, notification.id.to_string())
+            .body(body.to_vec());
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+
+        match request.send().await {
+            Ok(res) if res.status().is_success() => return,
+            Ok(res) => log::warn!(
+                "webhook {url} rejected {} (attempt {attempt}): {}",
+                notification.event.name(),
+                res.status()
+            ),
+            Err(err) => log::warn!(
+                "webhook {url} unreachable for {} (attempt {attempt}): {err}",
+                notification.event.name()
+            ),
+        }
+
+        if attempt < DELIVERY_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use ulid::Ulid;
+
+    use super::{sign, LifecycleEvent, Notification};
+
+    #[test]
+    fn payload_is_signed() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn notification_format() {
+        let notification = Notification {
+            id: Ulid::nil(),
+            timestamp: "2024-03-05T14:00:00Z".parse().unwrap(),
+            event: LifecycleEvent::StreamCreated {
+                stream: "app".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&notification).unwrap(),
+            json!({
+                "id": "00000000000000000000000000",
+                "timestamp": "2024-03-05T14:00:00Z",
+                "event": "stream_created",
+                "stream": "app"
+            })
+        );
+    }
+}